@@ -0,0 +1,171 @@
+// Where the MIDI bytes the guest sends go. The raw dump needs nothing from
+// the host; a real synthesizer, through midir, needs the `midi` feature.
+use log::warn;
+use std::fs::File;
+use std::io::Write;
+
+/// Takes the byte stream from an emulated MIDI interface.
+pub trait MidiOut {
+    fn send(&mut self, bytes: &[u8]);
+}
+
+/// Writes the bytes to a file exactly as the guest sent them.
+pub struct MidiDump {
+    file: File,
+    path: String,
+}
+
+impl MidiDump {
+    pub fn create(path: &str) -> Result<MidiDump, String> {
+        let file = File::create(path).map_err(|err| format!("{}: {}", path, err))?;
+        Ok(MidiDump {
+            file,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl MidiOut for MidiDump {
+    fn send(&mut self, bytes: &[u8]) {
+        if let Err(err) = self.file.write_all(bytes) {
+            warn!("{}: {}", self.path, err);
+        }
+    }
+}
+
+/// Cuts a byte stream into whole messages, since host MIDI APIs take one
+/// message at a time. Running status is filled back in, and real time
+/// bytes come out on their own wherever they turn up.
+#[derive(Debug, Clone, Default)]
+pub struct MidiParser {
+    /// The status that data bytes belong to.
+    status: Option<u8>,
+    message: Vec<u8>,
+}
+
+/// Data bytes after each status.
+fn data_length(status: u8) -> usize {
+    match status & 0xf0 {
+        0xc0 | 0xd0 => 1,
+        0xf0 => match status {
+            0xf1 | 0xf3 => 1,
+            0xf2 => 2,
+            _ => 0,
+        },
+        _ => 2,
+    }
+}
+
+impl MidiParser {
+    pub fn new() -> MidiParser {
+        MidiParser::default()
+    }
+
+    /// Takes the next byte, returning a message once one is complete.
+    pub fn feed(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match byte {
+            0xf8..=0xff => return Some(vec![byte]),
+            0xf7 if self.status == Some(0xf0) => {
+                self.message.push(byte);
+                self.status = None;
+                return Some(std::mem::take(&mut self.message));
+            }
+            0x80..=0xf6 => {
+                self.message = vec![byte];
+                // System messages cancel running status.
+                self.status = match byte {
+                    0xf4..=0xf6 => None,
+                    _ => Some(byte),
+                };
+                if byte == 0xf6 {
+                    return Some(std::mem::take(&mut self.message));
+                }
+                return None;
+            }
+            0xf7 => return None,
+            _ => {}
+        }
+        let status = self.status?;
+        if self.message.is_empty() {
+            self.message.push(status);
+        }
+        self.message.push(byte);
+        if status == 0xf0 || self.message.len() <= data_length(status) {
+            return None;
+        }
+        if status >= 0xf0 {
+            self.status = None;
+        }
+        Some(std::mem::take(&mut self.message))
+    }
+}
+
+/// A MIDI output port on the host.
+#[cfg(feature = "midi")]
+pub struct HostMidiOut {
+    connection: midir::MidiOutputConnection,
+    parser: MidiParser,
+}
+
+#[cfg(feature = "midi")]
+impl HostMidiOut {
+    /// Connects to the first output port whose name contains `name`, or to
+    /// the first port of all if `name` is empty.
+    pub fn open(name: &str) -> Result<HostMidiOut, String> {
+        let output = midir::MidiOutput::new("emupc-rs").map_err(|err| err.to_string())?;
+        let port = output
+            .ports()
+            .into_iter()
+            .find(|port| {
+                output
+                    .port_name(port)
+                    .is_ok_and(|port_name| port_name.contains(name))
+            })
+            .ok_or_else(|| format!("no MIDI output port matching \"{}\"", name))?;
+        let connection = output
+            .connect(&port, "emupc-rs MPU-401")
+            .map_err(|err| err.to_string())?;
+        Ok(HostMidiOut {
+            connection,
+            parser: MidiParser::new(),
+        })
+    }
+}
+
+#[cfg(feature = "midi")]
+impl MidiOut for HostMidiOut {
+    fn send(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if let Some(message) = self.parser.feed(byte) {
+                if let Err(err) = self.connection.send(&message) {
+                    warn!("MIDI output: {}", err);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_midi_parser_messages() {
+    let mut parser = MidiParser::new();
+    let stream = [
+        0x90, 0x3c, 0x40, 0x3e, 0x40, // note on, then another by running status
+        0xc1, 0xf8, 0x05, // a program change with a clock in the middle
+        0xf0, 0x7e, 0x7f, 0x09, 0x01, 0xf7, // GM on
+        0x40, // a data byte with no status
+    ];
+    let messages: Vec<Vec<u8>> = stream
+        .iter()
+        .filter_map(|&byte| parser.feed(byte))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            vec![0x90, 0x3c, 0x40],
+            vec![0x90, 0x3e, 0x40],
+            vec![0xf8],
+            vec![0xc1, 0x05],
+            vec![0xf0, 0x7e, 0x7f, 0x09, 0x01, 0xf7],
+        ]
+    );
+}
@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+
+/// Samples it takes to fade back in after a discontinuity, about 5 ms at
+/// 48 kHz.
+pub const RAMP_SAMPLES: u32 = 256;
+
+/// Queues mono samples between the emulation thread and the audio backend.
+///
+/// Loading a savestate changes every device's output at once. Whatever was
+/// still queued from before the load would be followed by a jump to the
+/// new waveform, which is heard as a pop, so `resume_after_load` drops the
+/// queue and the output fades back in from silence.
+#[derive(Clone, Debug)]
+pub struct Mixer {
+    queue: VecDeque<f32>,
+    /// Samples left in the current fade in.
+    ramp_left: u32,
+    /// The most samples to queue before dropping the oldest, so a stalled
+    /// backend can't make latency grow without bound.
+    capacity: usize,
+}
+
+impl Default for Mixer {
+    fn default() -> Mixer {
+        Mixer::new(8192)
+    }
+}
+
+impl Mixer {
+    pub fn new(capacity: usize) -> Mixer {
+        Mixer {
+            queue: VecDeque::with_capacity(capacity),
+            ramp_left: 0,
+            capacity,
+        }
+    }
+
+    /// Adds the sum of all device outputs for one sample period.
+    pub fn push(&mut self, sample: f32) {
+        let gain = if self.ramp_left > 0 {
+            self.ramp_left -= 1;
+            1.0 - self.ramp_left as f32 / RAMP_SAMPLES as f32
+        } else {
+            1.0
+        };
+        if self.queue.len() == self.capacity {
+            self.queue.pop_front();
+        }
+        self.queue.push_back((sample * gain).clamp(-1.0, 1.0));
+    }
+
+    /// Fills `out` for the backend, padding with silence if the emulation
+    /// fell behind. Returns how many real samples were available.
+    pub fn pull(&mut self, out: &mut [f32]) -> usize {
+        let available = self.queue.len().min(out.len());
+        for (dst, src) in out.iter_mut().zip(self.queue.drain(..available)) {
+            *dst = src;
+        }
+        for dst in out[available..].iter_mut() {
+            *dst = 0.0;
+        }
+        available
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Call after loading a savestate, before any new samples are pushed.
+    pub fn resume_after_load(&mut self) {
+        self.queue.clear();
+        self.ramp_left = RAMP_SAMPLES;
+    }
+}
+
+#[test]
+fn test_mixer_ramps_after_load() {
+    let mut mixer = Mixer::new(1024);
+    for _ in 0..100 {
+        mixer.push(0.5);
+    }
+    mixer.resume_after_load();
+    assert_eq!(mixer.queued(), 0);
+    for _ in 0..RAMP_SAMPLES + 10 {
+        mixer.push(1.0);
+    }
+    let mut out = vec![0.0; RAMP_SAMPLES as usize + 20];
+    assert_eq!(mixer.pull(&mut out), RAMP_SAMPLES as usize + 10);
+    assert!(out[0] < 0.01);
+    assert!(out
+        .windows(2)
+        .take(RAMP_SAMPLES as usize)
+        .all(|w| w[1] >= w[0]));
+    assert_eq!(out[RAMP_SAMPLES as usize], 1.0);
+    assert_eq!(out[RAMP_SAMPLES as usize + 15], 0.0);
+}
@@ -0,0 +1,5 @@
+// Sound output shared by the emulated sound devices. Devices produce
+// samples at the host rate and the mixer hands them to the audio backend.
+pub mod midi;
+pub mod mixer;
+pub mod output;
@@ -0,0 +1,246 @@
+// Playing the machine's sound on the host. The machine makes its mixed
+// samples at the host's rate, but in bursts of a frame at a time, timed
+// by a clock that is never quite the sound card's, and at a fraction of
+// the rate when slowed down. So the samples go through a resampler into a
+// queue the output stream takes from, and the resampler's ratio follows
+// the speed and is nudged by how full the queue is: a little faster when
+// it's filling up, a little slower when it's running dry, so latency stays
+// put without the pitch wandering far enough to hear. The cpal stream
+// itself needs the `audio` feature.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// The most the ratio is nudged either way to keep the queue level, as a
+/// fraction. 0.5% is under a tenth of a semitone.
+const MAX_CORRECTION: f64 = 0.005;
+
+/// How much of each new measurement of the queue goes into the average the
+/// correction works from. Measurements are taken a frame apart and the
+/// callback empties the queue in bursts, so one on its own is noisy.
+const FILL_SMOOTHING: f64 = 0.05;
+
+/// Resamples a mono stream by linear interpolation, at a ratio that can
+/// change from one block to the next without a seam.
+#[derive(Clone, Debug, Default)]
+pub struct Resampler {
+    /// How far the next output sample is from `last` towards the next
+    /// input sample.
+    position: f64,
+    last: f32,
+}
+
+impl Resampler {
+    pub fn new() -> Resampler {
+        Resampler::default()
+    }
+
+    /// Resamples `input` onto the end of `out`, advancing `step` input
+    /// samples for each output sample.
+    pub fn process(&mut self, input: &[f32], step: f64, out: &mut Vec<f32>) {
+        for &sample in input {
+            while self.position < 1.0 {
+                out.push(self.last + (sample - self.last) * self.position as f32);
+                self.position += step;
+            }
+            self.position -= 1.0;
+            self.last = sample;
+        }
+    }
+}
+
+/// Plays the machine's sound, a frame's worth at a time.
+pub trait AudioOut {
+    /// The rate the machine should make samples at.
+    fn sample_rate(&self) -> u32;
+    /// Plays samples made at `speed` times real time.
+    fn push(&mut self, samples: &[f32], speed: f64);
+}
+
+/// The queue between the emulation and the output stream.
+pub type SampleQueue = Arc<Mutex<VecDeque<f32>>>;
+
+/// Takes the machine's samples a frame at a time and keeps the queue to
+/// the output stream at `target` samples.
+#[derive(Debug)]
+pub struct AudioSink {
+    queue: SampleQueue,
+    resampler: Resampler,
+    target: usize,
+    /// The queue's length, averaged.
+    fill: f64,
+    scratch: Vec<f32>,
+}
+
+impl AudioSink {
+    pub fn new(target: usize) -> AudioSink {
+        AudioSink {
+            queue: Arc::new(Mutex::new(VecDeque::with_capacity(target * 4))),
+            resampler: Resampler::new(),
+            target: target.max(1),
+            fill: target as f64,
+            scratch: vec![],
+        }
+    }
+
+    /// The queue, for the output stream to take from.
+    pub fn queue(&self) -> SampleQueue {
+        self.queue.clone()
+    }
+
+    /// Input samples for each output sample: the speed, corrected for the
+    /// queue drifting from its target.
+    pub fn step(&self, speed: f64) -> f64 {
+        let error = (self.fill - self.target as f64) / self.target as f64;
+        speed * (1.0 + (error * MAX_CORRECTION).clamp(-MAX_CORRECTION, MAX_CORRECTION))
+    }
+
+    /// Queues a frame's samples, made at `speed` times real time.
+    pub fn push(&mut self, samples: &[f32], speed: f64) {
+        let step = self.step(speed);
+        self.scratch.clear();
+        self.resampler.process(samples, step, &mut self.scratch);
+        let mut queue = self.queue.lock().unwrap();
+        self.fill += (queue.len() as f64 - self.fill) * FILL_SMOOTHING;
+        queue.extend(self.scratch.drain(..));
+        // Far behind, after a stall: drop the oldest rather than keep the
+        // latency.
+        let excess = queue.len().saturating_sub(self.target * 4);
+        queue.drain(..excess);
+    }
+}
+
+/// Fills an interleaved output buffer from the queue, the same sample on
+/// every channel, with silence once it runs dry.
+pub fn fill_interleaved(queue: &SampleQueue, channels: usize, out: &mut [f32]) {
+    let mut queue = queue.lock().unwrap();
+    for frame in out.chunks_mut(channels.max(1)) {
+        let sample = queue.pop_front().unwrap_or(0.0);
+        frame.iter_mut().for_each(|out| *out = sample);
+    }
+}
+
+/// The host's default audio output, through cpal.
+#[cfg(feature = "audio")]
+pub struct HostAudio {
+    sink: AudioSink,
+    sample_rate: u32,
+    _stream: cpal::Stream,
+}
+
+#[cfg(feature = "audio")]
+impl HostAudio {
+    /// Opens the default output device with about `latency_ms` of sound
+    /// queued ahead of it.
+    pub fn open(latency_ms: u32) -> Result<HostAudio, String> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+        use cpal::SampleFormat;
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| "no audio output device".to_string())?;
+        let config = device
+            .default_output_config()
+            .map_err(|err| err.to_string())?;
+        let format = config.sample_format();
+        let config: cpal::StreamConfig = config.into();
+        let sample_rate = config.sample_rate.0;
+        let sink = AudioSink::new((sample_rate * latency_ms / 1000) as usize);
+        let stream = match format {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, sink.queue()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, sink.queue()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, sink.queue()),
+            format => return Err(format!("unsupported sample format {}", format)),
+        }?;
+        stream.play().map_err(|err| err.to_string())?;
+        Ok(HostAudio {
+            sink,
+            sample_rate,
+            _stream: stream,
+        })
+    }
+}
+
+#[cfg(feature = "audio")]
+impl AudioOut for HostAudio {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn push(&mut self, samples: &[f32], speed: f64) {
+        self.sink.push(samples, speed);
+    }
+}
+
+#[cfg(feature = "audio")]
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    queue: SampleQueue,
+) -> Result<cpal::Stream, String>
+where
+    T: cpal::SizedSample + cpal::FromSample<f32>,
+{
+    use cpal::traits::DeviceTrait;
+
+    let channels = config.channels as usize;
+    let mut buffer = vec![];
+    device
+        .build_output_stream(
+            config,
+            move |out: &mut [T], _| {
+                buffer.resize(out.len(), 0.0);
+                fill_interleaved(&queue, channels, &mut buffer);
+                for (out, &sample) in out.iter_mut().zip(buffer.iter()) {
+                    *out = T::from_sample(sample);
+                }
+            },
+            |err| log::warn!("Audio output: {}", err),
+            None,
+        )
+        .map_err(|err| err.to_string())
+}
+
+#[test]
+fn test_resampler() {
+    let mut resampler = Resampler::new();
+    let mut out = vec![];
+    resampler.process(&[1.0, 1.0, 1.0, 1.0], 1.0, &mut out);
+    assert_eq!(out, [0.0, 1.0, 1.0, 1.0]);
+
+    // Half speed doubles the samples, filling in between.
+    let mut out = vec![];
+    resampler.process(&[0.0, 1.0], 0.5, &mut out);
+    assert_eq!(out, [1.0, 0.5, 0.0, 0.5]);
+
+    // The ratio can change between blocks without losing the place.
+    let mut out = vec![];
+    resampler.process(&[0.0; 100], 2.0, &mut out);
+    resampler.process(&[0.0; 100], 1.0, &mut out);
+    assert_eq!(out.len(), 150);
+}
+
+#[test]
+fn test_sink_drift() {
+    let mut sink = AudioSink::new(1000);
+    assert_eq!(sink.step(1.0), 1.0);
+    assert_eq!(sink.step(0.5), 0.5);
+
+    // A machine making 1% more than the output takes: the queue grows,
+    // and the step goes up to drain it, but no more than the limit.
+    let queue = sink.queue();
+    let mut out = vec![0.0; 800];
+    for _ in 0..200 {
+        sink.push(&[0.25; 808], 1.0);
+        fill_interleaved(&queue, 1, &mut out);
+    }
+    let step = sink.step(1.0);
+    assert!(step > 1.0 && step <= 1.0 + MAX_CORRECTION);
+    assert!(queue.lock().unwrap().len() <= 4000);
+
+    // Running dry pads with silence on every channel.
+    queue.lock().unwrap().clear();
+    queue.lock().unwrap().push_back(0.5);
+    let mut out = vec![1.0; 4];
+    fill_interleaved(&queue, 2, &mut out);
+    assert_eq!(out, [0.5, 0.5, 0.0, 0.0]);
+}
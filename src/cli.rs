@@ -0,0 +1,341 @@
+// The frontend's command line. Everything that describes the machine goes
+// through here and is checked before the machine is built; what can only
+// be checked against the machine itself, like whether it has a COM3, is
+// reported as a usage error once it is.
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use emupc_rs::hardware::ems::PageFrame;
+use emupc_rs::hardware::rom::BiosImage;
+use emupc_rs::hardware::soundblaster::SbModel;
+use emupc_rs::headless::ExitCondition;
+use emupc_rs::input::keymap::KeyMap;
+use emupc_rs::trace::TraceFilter;
+use emupc_rs::VideoCard;
+use std::path::PathBuf;
+
+/// An IBM PC, XT and AT emulator.
+#[derive(Debug, Parser)]
+#[command(version, about, args_override_self = true)]
+pub struct Args {
+    /// Reads machine profiles from a TOML file.
+    #[arg(long, value_name = "PATH", env = "EMUPC_CONFIG")]
+    pub config: Option<String>,
+    /// The profile to use from the config file.
+    #[arg(long, value_name = "NAME", requires = "config")]
+    pub profile: Option<String>,
+    /// The machine to emulate, by id, alias or name; `list` shows them.
+    #[arg(long, default_value = "ibm5150")]
+    pub machine: String,
+    /// Conventional memory in place of the machine's own.
+    #[arg(long, value_name = "KB")]
+    pub ram: Option<usize>,
+    /// Memory above 1 MB, on an AT.
+    #[arg(long = "ext-mem", value_name = "KB")]
+    pub ext_mem: Option<usize>,
+    /// The video adapter in place of the machine's own: cga, hercules or
+    /// ega.
+    #[arg(long, value_parser = parse_video)]
+    pub video: Option<VideoCard>,
+    /// Fits an MDA beside the colour card, as a second monitor.
+    #[arg(long)]
+    pub mda: bool,
+    /// The CPU clock.
+    #[arg(long, value_name = "MHZ", value_parser = parse_mhz)]
+    pub clock: Option<u32>,
+    /// Fits a turbo switch, with this clock when it's off.
+    #[arg(long = "slow-clock", value_name = "MHZ", value_parser = parse_mhz)]
+    pub slow_clock: Option<u32>,
+    /// Starts with the turbo switch on or off.
+    #[arg(long, value_name = "on|off", value_parser = parse_switch)]
+    pub turbo: Option<bool>,
+
+    /// The system BIOS, or EVEN,ODD for a split pair.
+    #[arg(long, value_name = "PATH", value_parser = parse_bios)]
+    pub bios: Option<BiosImage>,
+    /// An option ROM, at ADDR in hex or wherever POST will find it.
+    #[arg(long = "option-rom", value_name = "PATH[@ADDR]", value_parser = parse_option_rom)]
+    pub option_roms: Vec<(String, Option<u32>)>,
+    /// Copies the ROMs in a range of segments into RAM, e.g. C000-C7FF.
+    #[arg(long, value_name = "RANGE", value_parser = parse_segments)]
+    pub shadow: Vec<(u32, u32)>,
+    /// Opens upper memory blocks in a range of segments, e.g. D000-EFFF.
+    #[arg(long, value_name = "RANGE", value_parser = parse_segments)]
+    pub umb: Vec<(u32, u32)>,
+    /// Keeps the AT's CMOS settings in a file.
+    #[arg(long, value_name = "PATH")]
+    pub cmos: Option<String>,
+
+    /// A floppy image for drive A:.
+    #[arg(long, value_name = "IMAGE")]
+    pub fda: Option<String>,
+    /// A floppy image for drive B:.
+    #[arg(long, value_name = "IMAGE")]
+    pub fdb: Option<String>,
+    /// Another image Ctrl+F1 changes drive A:'s disk to, after --fda's and
+    /// in the order given, for software that comes on several disks.
+    #[arg(long = "disk-set", value_name = "IMAGE")]
+    pub disk_set: Vec<String>,
+    /// Never writes to the floppy images.
+    #[arg(long = "floppy-read-only")]
+    pub floppy_read_only: bool,
+    /// A hard disk image for the first drive.
+    #[arg(long, value_name = "IMAGE")]
+    pub hda: Option<String>,
+    /// A hard disk image for the second drive.
+    #[arg(long, value_name = "IMAGE")]
+    pub hdb: Option<String>,
+    /// Keeps hard disk writes in IMAGE.snapshot, leaving the image alone.
+    #[arg(long)]
+    pub snapshot: bool,
+    /// A CD-ROM image, on the AT's IDE channel.
+    #[arg(long, value_name = "IMAGE")]
+    pub cdrom: Option<String>,
+    /// Another image Ctrl+F2 changes the CD-ROM's disc to, after --cdrom's
+    /// and in the order given.
+    #[arg(long = "cd-set", value_name = "IMAGE")]
+    pub cd_set: Vec<String>,
+    /// Loads the boot sector from drive A: and jumps to it, skipping
+    /// POST. The PC only.
+    #[arg(long = "direct-boot", requires = "fda")]
+    pub direct_boot: bool,
+
+    /// Fits an AdLib.
+    #[arg(long)]
+    pub adlib: bool,
+    /// Fits a Sound Blaster: 2 or pro.
+    #[arg(long, value_name = "MODEL", value_parser = parse_sb)]
+    pub sb: Option<SbModel>,
+    /// Fits an EMS board with this much memory.
+    #[arg(long, value_name = "KB")]
+    pub ems: Option<usize>,
+    /// Where the EMS board's page frame goes: d000 or e000.
+    #[arg(long = "ems-frame", requires = "ems", value_parser = parse_frame)]
+    pub ems_frame: Option<PageFrame>,
+    /// The EMS board's I/O port, in hex.
+    #[arg(long = "ems-port", requires = "ems", value_name = "PORT", value_parser = parse_port)]
+    pub ems_port: Option<u16>,
+    /// Fits a performance counter card.
+    #[arg(long = "perf-counter")]
+    pub perf_counter: bool,
+    /// Fits an MPU-401 and sends its MIDI to a host output.
+    #[arg(long, value_name = "NAME", conflicts_with = "midi_dump")]
+    pub midi: Option<String>,
+    /// Fits an MPU-401 and writes its MIDI to a file.
+    #[arg(long = "midi-dump", value_name = "PATH")]
+    pub midi_dump: Option<String>,
+    /// Connects COM1: tcp-listen:[ADDRESS:]PORT, tcp:HOST:PORT, pty,
+    /// serial:PATH, mouse or mouse-systems.
+    #[arg(long, value_name = "BACKEND")]
+    pub com1: Option<String>,
+    /// Connects COM2, as --com1.
+    #[arg(long, value_name = "BACKEND")]
+    pub com2: Option<String>,
+    /// Connects COM3, as --com1.
+    #[arg(long, value_name = "BACKEND")]
+    pub com3: Option<String>,
+    /// Connects COM4, as --com1.
+    #[arg(long, value_name = "BACKEND")]
+    pub com4: Option<String>,
+    /// Connects LPT1: file:PATH, text:PATH, loopback, printer:PATH or
+    /// parport:PATH.
+    #[arg(long, value_name = "BACKEND")]
+    pub lpt1: Option<String>,
+    /// Connects LPT2, as --lpt1.
+    #[arg(long, value_name = "BACKEND")]
+    pub lpt2: Option<String>,
+    /// Connects LPT3, as --lpt1.
+    #[arg(long, value_name = "BACKEND")]
+    pub lpt3: Option<String>,
+    /// Fits an NE2000 on a network: slirp, tap:NAME or pcap:INTERFACE.
+    #[arg(long, value_name = "BACKEND")]
+    pub nic: Option<String>,
+    /// The NE2000's I/O port, in hex.
+    #[arg(long = "nic-port", requires = "nic", value_name = "PORT", value_parser = parse_port)]
+    pub nic_port: Option<u16>,
+    /// The NE2000's IRQ.
+    #[arg(long = "nic-irq", requires = "nic", value_parser = clap::value_parser!(u8).range(2..=15))]
+    pub nic_irq: Option<u8>,
+    /// Fits a game port, for the host's gamepads or keys[:MAP] for a
+    /// joystick on the keyboard.
+    #[arg(long, value_name = "gamepad|keys[:MAP]")]
+    pub joystick: Option<String>,
+    /// Sends host keys to the machine as other keys, e.g.
+    /// capslock=leftctrl,leftwin=none.
+    #[arg(long, value_name = "MAP", value_parser = KeyMap::parse)]
+    pub keymap: Option<KeyMap>,
+    /// Mickeys for each pixel the host's mouse moves.
+    #[arg(long = "mouse-sensitivity", value_name = "FACTOR", default_value_t = 1.0,
+          value_parser = parse_sensitivity)]
+    pub mouse_sensitivity: f64,
+    /// Fits a PS/2 mouse, on an AT.
+    #[arg(long = "ps2-mouse")]
+    pub ps2_mouse: bool,
+    /// Shows the snow the original CGA makes in 80-column text.
+    #[arg(long = "cga-snow")]
+    pub cga_snow: bool,
+    /// Shows the CGA's composite output.
+    #[arg(long)]
+    pub composite: bool,
+
+    /// Plays no sound.
+    #[arg(long = "no-audio")]
+    pub no_audio: bool,
+    /// How much sound to keep queued ahead of the host's output.
+    #[arg(long = "audio-latency", value_name = "MS", default_value_t = 60,
+          value_parser = clap::value_parser!(u32).range(10..=1000))]
+    pub audio_latency: u32,
+
+    /// Runs slower or faster than real time, as a multiple of it, from
+    /// 0.1 to 10.
+    #[arg(long, default_value_t = 1.0)]
+    pub speed: f64,
+    /// Starts running as fast as the host can.
+    #[arg(long)]
+    pub unthrottled: bool,
+    /// Log levels, e.g. info,fdc=trace.
+    #[arg(long, value_name = "SPEC", env = "EMUPC_LOG")]
+    pub log: Option<String>,
+    /// Host scheduling, e.g. cpu=2,priority,low-latency.
+    #[arg(long, value_name = "SPEC", env = "EMUPC_LATENCY")]
+    pub latency: Option<String>,
+    /// Runs with no display, as fast as the host can.
+    #[arg(long)]
+    pub headless: bool,
+    /// Ends a --headless run once a condition is met, printing the
+    /// machine's state and exiting with STATUS, or 0: instructions=N,
+    /// at=CS:IP, port=PORT[:VALUE] or text=STRING, in hex but for N.
+    #[arg(long = "exit-when", value_name = "[STATUS:]CONDITION",
+          value_parser = ExitCondition::parse, requires = "headless")]
+    pub exit_when: Vec<ExitCondition>,
+    /// Starts in the debugger on the terminal, before the first
+    /// instruction. From then on breakpoints and watchpoints stop into it,
+    /// as after Ctrl+F3.
+    #[arg(long, conflicts_with = "exit_when")]
+    pub debug: bool,
+    /// Opens the window at this many times 640x480.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=8))]
+    pub scale: u32,
+    /// Scales the picture by whole pixels only, as near 4:3 as that gets.
+    #[arg(long = "integer-scale")]
+    pub integer_scale: bool,
+    /// Shows the frame rate, the emulated clock and the drive lights along
+    /// the bottom of the window. Ctrl+F4 turns it on and off.
+    #[arg(long = "status-bar")]
+    pub status_bar: bool,
+    /// Where Ctrl+F5 saves screenshots and Ctrl+F6 recordings.
+    #[arg(long = "screenshot-dir", value_name = "DIR", default_value = ".")]
+    pub screenshot_dir: PathBuf,
+    /// Records every frame as a PNG and the sound as a WAV into DIR, from
+    /// the start.
+    #[arg(long, value_name = "DIR")]
+    pub record: Option<PathBuf>,
+    /// Writes a line to PATH for every instruction run, with its address,
+    /// bytes and disassembly and the registers it changed.
+    #[arg(long, value_name = "PATH")]
+    pub trace: Option<PathBuf>,
+    /// Traces only the code that passes these, comma separated and in hex:
+    /// cs=SEG for code in a segment, range=START-END for code in a range
+    /// of physical memory.
+    #[arg(long = "trace-filter", value_name = "FILTER",
+          value_parser = TraceFilter::parse, requires = "trace")]
+    pub trace_filter: Option<TraceFilter>,
+    /// Keeps only the last N instructions of the trace, and writes them
+    /// when the emulator exits, for seeing what led up to a crash.
+    #[arg(long = "trace-ring", value_name = "N", requires = "trace",
+          value_parser = clap::value_parser!(u32).range(1..))]
+    pub trace_ring: Option<u32>,
+}
+
+/// Exits with a usage error about `option`, for what couldn't be checked
+/// until the machine was built.
+pub fn fail(option: &str, err: impl std::fmt::Display) -> ! {
+    Args::command()
+        .error(ErrorKind::ValueValidation, format!("{}: {}", option, err))
+        .exit()
+}
+
+fn parse_mhz(mhz: &str) -> Result<u32, String> {
+    match mhz.parse::<f64>() {
+        Ok(mhz) if mhz > 0.0 => Ok((mhz * 1_000_000.0) as u32),
+        _ => Err("expected a clock in MHz".to_string()),
+    }
+}
+
+fn parse_sensitivity(factor: &str) -> Result<f64, String> {
+    match factor.parse::<f64>() {
+        Ok(factor) if factor > 0.0 && factor <= 16.0 => Ok(factor),
+        _ => Err("expected a factor above 0, up to 16".to_string()),
+    }
+}
+
+fn parse_switch(switch: &str) -> Result<bool, String> {
+    match switch {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("expected on or off".to_string()),
+    }
+}
+
+fn parse_video(card: &str) -> Result<VideoCard, String> {
+    match card.to_ascii_lowercase().as_str() {
+        "cga" => Ok(VideoCard::Cga),
+        "hercules" | "mda" => Ok(VideoCard::Hercules),
+        "ega" => Ok(VideoCard::Ega),
+        "vga" => Err("there's no VGA yet; expected cga, hercules or ega".to_string()),
+        _ => Err("expected cga, hercules or ega".to_string()),
+    }
+}
+
+fn parse_bios(spec: &str) -> Result<BiosImage, String> {
+    Ok(BiosImage::parse(spec))
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+fn parse_option_rom(spec: &str) -> Result<(String, Option<u32>), String> {
+    match spec.split_once('@') {
+        Some((path, addr)) => match parse_hex(addr) {
+            Some(addr) => Ok((path.to_string(), Some(addr))),
+            None => Err(format!("bad address '{}'", addr)),
+        },
+        None => Ok((spec.to_string(), None)),
+    }
+}
+
+/// A range of segments, inclusive, as the addresses they cover.
+fn parse_segments(spec: &str) -> Result<(u32, u32), String> {
+    let (start, end) = spec
+        .split_once('-')
+        .ok_or_else(|| "expected a range of segments like D000-EFFF".to_string())?;
+    match (parse_hex(start), parse_hex(end)) {
+        (Some(start), Some(end)) if start <= 0xffff && end <= 0xffff => {
+            Ok((start << 4, end << 4 | 0xf))
+        }
+        _ => Err("expected a range of segments like D000-EFFF".to_string()),
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    match parse_hex(port) {
+        Some(port) if port <= 0x3ff => Ok(port as u16),
+        _ => Err("expected an I/O port in hex, up to 3FF".to_string()),
+    }
+}
+
+fn parse_sb(model: &str) -> Result<SbModel, String> {
+    match model {
+        "2" => Ok(SbModel::Sb2),
+        "pro" => Ok(SbModel::Pro),
+        _ => Err("expected 2 or pro".to_string()),
+    }
+}
+
+fn parse_frame(frame: &str) -> Result<PageFrame, String> {
+    match frame.to_ascii_lowercase().as_str() {
+        "d000" => Ok(PageFrame::D000),
+        "e000" => Ok(PageFrame::E000),
+        _ => Err("expected d000 or e000".to_string()),
+    }
+}
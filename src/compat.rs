@@ -0,0 +1,170 @@
+// Recognises boot disks and looks them up in a local database of titles
+// with known requirements. Fingerprinting is always available; reading the
+// TOML database needs the `compat-db` feature.
+//
+// A database entry looks like:
+//
+//     [[title]]
+//     name = "Some Game"
+//     boot_crc32 = 0x1a2b3c4d
+//     volume_serial = "1234-ABCD"
+//     machine = "ibm5150"
+//     min_ram_kb = 256
+//     warnings = ["Needs a joystick"]
+#[cfg(feature = "compat-db")]
+use serde::Deserialize;
+use std::fmt;
+
+/// CRC-32 as used by zip and most ROM databases.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    pub boot_crc32: u32,
+    /// From the extended BIOS parameter block, which DOS 4 and later write.
+    pub volume_serial: Option<u32>,
+}
+
+impl Fingerprint {
+    pub fn of(image: &[u8]) -> Option<Fingerprint> {
+        let boot = image.get(..512)?;
+        let volume_serial = if boot[0x26] == 0x29 {
+            Some(u32::from_le_bytes([
+                boot[0x27], boot[0x28], boot[0x29], boot[0x2a],
+            ]))
+        } else {
+            None
+        };
+        Some(Fingerprint {
+            boot_crc32: crc32(boot),
+            volume_serial,
+        })
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "boot sector CRC {:08x}", self.boot_crc32)?;
+        if let Some(serial) = self.volume_serial {
+            write!(f, ", volume {:04X}-{:04X}", serial >> 16, serial & 0xffff)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "compat-db")]
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CompatEntry {
+    pub name: String,
+    pub boot_crc32: Option<u32>,
+    /// Written the way DOS shows it, e.g. `1234-ABCD`.
+    pub volume_serial: Option<String>,
+    /// Id of the machine template the title works best on.
+    pub machine: Option<String>,
+    pub min_ram_kb: Option<usize>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+#[cfg(feature = "compat-db")]
+impl CompatEntry {
+    fn matches(&self, fingerprint: &Fingerprint) -> bool {
+        let serial = self
+            .volume_serial
+            .as_ref()
+            .and_then(|s| u32::from_str_radix(&s.replace('-', ""), 16).ok());
+        match (self.boot_crc32, serial) {
+            (None, None) => false,
+            (crc, serial) => {
+                crc.is_none_or(|crc| crc == fingerprint.boot_crc32)
+                    && serial.is_none_or(|serial| Some(serial) == fingerprint.volume_serial)
+            }
+        }
+    }
+
+    /// Configuration changes to suggest when running on `template`.
+    pub fn suggestions(
+        &self,
+        template: &crate::hardware::templates::MachineTemplate,
+    ) -> Vec<String> {
+        let mut suggestions = vec![];
+        if let Some(machine) = &self.machine {
+            if !machine.eq_ignore_ascii_case(template.id) {
+                suggestions.push(format!(
+                    "{} is known to work with --machine {}",
+                    self.name, machine
+                ));
+            }
+        }
+        if let Some(min_ram_kb) = self.min_ram_kb {
+            if template.ram_kb < min_ram_kb {
+                suggestions.push(format!(
+                    "{} needs at least {}K of RAM, this machine has {}K",
+                    self.name, min_ram_kb, template.ram_kb
+                ));
+            }
+        }
+        for warning in self.warnings.iter() {
+            suggestions.push(format!("{}: {}", self.name, warning));
+        }
+        suggestions
+    }
+}
+
+#[cfg(feature = "compat-db")]
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CompatDatabase {
+    #[serde(default, rename = "title")]
+    pub titles: Vec<CompatEntry>,
+}
+
+#[cfg(feature = "compat-db")]
+impl CompatDatabase {
+    pub fn parse(text: &str) -> Result<CompatDatabase, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    pub fn load(path: &str) -> Result<CompatDatabase, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        CompatDatabase::parse(&text).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    pub fn lookup(&self, fingerprint: &Fingerprint) -> Option<&CompatEntry> {
+        self.titles.iter().find(|entry| entry.matches(fingerprint))
+    }
+}
+
+#[test]
+fn test_fingerprint() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    let mut image = vec![0u8; 1024];
+    image[0x26] = 0x29;
+    image[0x27..0x2b].copy_from_slice(&0x1234_abcdu32.to_le_bytes());
+    let fingerprint = Fingerprint::of(&image).unwrap();
+    assert_eq!(fingerprint.volume_serial, Some(0x1234_abcd));
+    assert!(Fingerprint::of(&image[..100]).is_none());
+
+    #[cfg(feature = "compat-db")]
+    {
+        let db = CompatDatabase::parse(
+            "[[title]]\nname = \"Demo\"\nvolume_serial = \"1234-ABCD\"\nmin_ram_kb = 256\n",
+        )
+        .unwrap();
+        let entry = db.lookup(&fingerprint).unwrap();
+        let template = crate::hardware::templates::find_template("ibm5150").unwrap();
+        assert_eq!(entry.suggestions(template).len(), 1);
+    }
+}
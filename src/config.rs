@@ -0,0 +1,165 @@
+// Machine profiles in a TOML file, so a setup doesn't have to be typed out
+// every run. Settings are the command line's long options without the
+// dashes, at the top level for every profile or in a `[profile.NAME]`
+// table for one:
+//
+//     default-profile = "xt"    # when --profile isn't given
+//     floppy-read-only = true
+//
+//     [profile.xt]
+//     machine = "ibm5160"
+//     fda = "disks/dos33.img"
+//     option-rom = ["roms/xtide.bin@c8000"]
+//
+//     [profile.at]
+//     machine = "ibm5170"
+//     hda = "disks/at.img"
+//     ext-mem = 1024
+//
+// A profile is turned back into options and parsed ahead of the command
+// line, so it's checked the same way, and options given on the command
+// line win.
+use crate::cli::Args;
+use clap::CommandFactory;
+use std::fs;
+use toml::{Table, Value};
+
+/// Options that choose the file and profile, which make no sense in one.
+const RESERVED: [&str; 2] = ["config", "profile"];
+
+/// The options `path` sets for `profile`, or for the one the file names
+/// as its default.
+pub fn load(path: &str, profile: Option<&str>) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    profile_args(&text, profile).map_err(|err| format!("{}: {}", path, err))
+}
+
+fn profile_args(text: &str, profile: Option<&str>) -> Result<Vec<String>, String> {
+    let mut table: Table = text
+        .parse()
+        .map_err(|err: toml::de::Error| err.to_string())?;
+    let profiles = match table.remove("profile") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => {
+            return Err("profile is for [profile.NAME] tables, \
+                        name the default one with default-profile"
+                .to_string())
+        }
+        None => Table::new(),
+    };
+    let default = match table.remove("default-profile") {
+        Some(Value::String(name)) => Some(name),
+        Some(_) => return Err("default-profile should be a profile's name".to_string()),
+        None => None,
+    };
+    let mut args = to_args(&table)?;
+    match profile.or(default.as_deref()) {
+        Some(name) => match profiles.get(name) {
+            Some(Value::Table(settings)) => {
+                args.extend(to_args(settings).map_err(|err| format!("[profile.{}] {}", name, err))?)
+            }
+            _ => return Err(format!("no profile named '{}'", name)),
+        },
+        None if !profiles.is_empty() => {
+            return Err("no profile chosen, pick one with --profile or default-profile".to_string())
+        }
+        None => {}
+    }
+    Ok(args)
+}
+
+/// Turns settings into the options they stand for. A flag is set with
+/// `true`, and an option that can be given more than once takes a list.
+fn to_args(settings: &Table) -> Result<Vec<String>, String> {
+    let command = Args::command();
+    let mut args = vec![];
+    for (key, value) in settings {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .filter(|_| !RESERVED.contains(&key.as_str()))
+            .ok_or_else(|| format!("unknown setting '{}'", key))?;
+        let option = format!("--{}", key);
+        if !arg.get_action().takes_values() {
+            match value {
+                Value::Boolean(true) => args.push(option),
+                Value::Boolean(false) => {}
+                _ => return Err(format!("{} should be true or false", key)),
+            }
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Integer(value) => value.to_string(),
+                Value::Float(value) => value.to_string(),
+                _ => return Err(format!("{} should be a string or a number", key)),
+            };
+            args.push(option.clone());
+            args.push(value);
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+use clap::Parser;
+
+#[test]
+fn test_profiles() {
+    let text = r#"
+        default-profile = "xt"
+        floppy-read-only = true
+        adlib = false
+
+        [profile.xt]
+        machine = "ibm5160"
+        option-rom = ["a.bin", "b.bin@c8000"]
+
+        [profile.at]
+        machine = "ibm5170"
+        ext-mem = 1024
+        clock = 8.5
+    "#;
+    assert_eq!(
+        profile_args(text, None).unwrap(),
+        vec![
+            "--floppy-read-only",
+            "--machine",
+            "ibm5160",
+            "--option-rom",
+            "a.bin",
+            "--option-rom",
+            "b.bin@c8000",
+        ]
+    );
+    assert_eq!(
+        profile_args(text, Some("at")).unwrap()[1..],
+        [
+            "--clock",
+            "8.5",
+            "--ext-mem",
+            "1024",
+            "--machine",
+            "ibm5170"
+        ]
+    );
+    assert!(profile_args(text, Some("ps2")).is_err());
+    assert!(profile_args("[profile.a]\n[profile.b]\n", None).is_err());
+    assert!(profile_args("turbo-button = true", None).is_err());
+    assert!(profile_args("profile = \"xt\"", None).is_err());
+    assert!(profile_args("adlib = \"yes\"", None).is_err());
+    assert!(profile_args("ram = [true]", None).is_err());
+
+    // The command line comes after the profile, so it wins.
+    let mut argv = vec!["emupc-rs".to_string()];
+    argv.extend(profile_args(text, Some("at")).unwrap());
+    argv.extend(["--machine".to_string(), "generic286".to_string()]);
+    let args = Args::try_parse_from(argv).unwrap();
+    assert_eq!(args.machine, "generic286");
+    assert_eq!(args.ext_mem, Some(1024));
+}
@@ -0,0 +1,314 @@
+// What a machine needs from its processor, whichever model it is. The
+// machine code in `hardware` only talks to the CPU through this trait, so a
+// board works the same with an 8088 or a 286 plugged in.
+use crate::cpu286::{Cpu286, Cpu286Context};
+use crate::cpu386::{Cpu386, Cpu386Context};
+use crate::cpu8086::{Cpu8086, Cpu8086Context};
+use std::fmt;
+
+/// The register state every model has, in the usual encoding order.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CpuState {
+    /// AX, CX, DX, BX, SP, BP, SI, DI.
+    pub gprs: [u16; 8],
+    /// ES, CS, SS, DS. Selectors only on the 286.
+    pub seg_regs: [u16; 4],
+    pub ip: u16,
+    pub flags: u16,
+    pub halted: bool,
+}
+
+impl CpuState {
+    pub fn cs(&self) -> u16 {
+        self.seg_regs[1]
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.gprs[4]
+    }
+}
+
+/// The registers on two lines, general then segment, for dumps and the
+/// debugger.
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [ax, cx, dx, bx, sp, bp, si, di] = self.gprs;
+        let [es, cs, ss, ds] = self.seg_regs;
+        writeln!(
+            f,
+            "AX={:04X} CX={:04X} DX={:04X} BX={:04X} SP={:04X} BP={:04X} SI={:04X} DI={:04X}",
+            ax, cx, dx, bx, sp, bp, si, di
+        )?;
+        write!(
+            f,
+            "ES={:04X} CS={:04X} SS={:04X} DS={:04X} IP={:04X} FLAGS={:04X}{}",
+            es,
+            cs,
+            ss,
+            ds,
+            self.ip,
+            self.flags,
+            if self.halted { " halted" } else { "" }
+        )
+    }
+}
+
+/// A processor driven by the machine's run loop. `B` is the bus the board
+/// puts behind it.
+pub trait Cpu<B> {
+    /// Executes one instruction and returns the cycles it took.
+    fn tick(&mut self, bus: &mut B) -> usize;
+    fn reset(&mut self);
+    fn halted(&self) -> bool;
+    /// Whether the CPU has stopped after a triple fault. Only the 286 can.
+    fn shutdown(&self) -> bool {
+        false
+    }
+    /// CS:IP of the next instruction, for breakpoints.
+    fn program_counter(&self) -> (u16, u16);
+    /// Where CS starts, for reading the code at CS:IP. A linear address on
+    /// the 386, which may be paged.
+    fn code_base(&self) -> u32;
+    fn interrupts_enabled(&self) -> bool;
+    /// Takes a maskable interrupt. The caller checks `interrupts_enabled`
+    /// first and gets `vector` from the interrupt controller.
+    fn irq(&mut self, bus: &mut B, vector: u8);
+    fn nmi(&mut self, bus: &mut B);
+    fn snapshot(&self) -> CpuState;
+    /// Puts back registers a debugger has changed. A segment register
+    /// given a new value gets the base a real mode load would give it.
+    fn restore(&mut self, state: &CpuState);
+}
+
+impl<B: Cpu8086Context> Cpu<B> for Cpu8086 {
+    fn tick(&mut self, bus: &mut B) -> usize {
+        Cpu8086::tick(self, bus)
+    }
+
+    fn reset(&mut self) {
+        Cpu8086::reset(self)
+    }
+
+    fn halted(&self) -> bool {
+        self.halted
+    }
+
+    fn program_counter(&self) -> (u16, u16) {
+        (
+            self.regs.readseg16(crate::cpu8086::registers::SegReg::CS),
+            self.regs.ip,
+        )
+    }
+
+    fn code_base(&self) -> u32 {
+        (self.regs.readseg16(crate::cpu8086::registers::SegReg::CS) as u32) << 4
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.regs
+            .flags
+            .contains(crate::cpu8086::registers::Flags::INTERRUPT)
+    }
+
+    fn irq(&mut self, bus: &mut B, vector: u8) {
+        self.interrupt(bus, vector);
+    }
+
+    fn nmi(&mut self, bus: &mut B) {
+        self.interrupt(bus, 2);
+    }
+
+    fn snapshot(&self) -> CpuState {
+        CpuState {
+            gprs: self.regs.gprs,
+            seg_regs: self.regs.seg_regs,
+            ip: self.regs.ip,
+            flags: self.regs.flags.bits(),
+            halted: self.halted,
+        }
+    }
+
+    fn restore(&mut self, state: &CpuState) {
+        self.regs.gprs = state.gprs;
+        self.regs.seg_regs = state.seg_regs;
+        self.regs.ip = state.ip;
+        self.regs.flags = crate::cpu8086::registers::Flags::from_bits_truncate(state.flags);
+        self.halted = state.halted;
+    }
+}
+
+impl<B: Cpu286Context> Cpu<B> for Cpu286 {
+    fn tick(&mut self, bus: &mut B) -> usize {
+        Cpu286::tick(self, bus)
+    }
+
+    fn reset(&mut self) {
+        Cpu286::reset(self)
+    }
+
+    fn halted(&self) -> bool {
+        self.halted
+    }
+
+    fn shutdown(&self) -> bool {
+        self.shutdown
+    }
+
+    fn program_counter(&self) -> (u16, u16) {
+        (self.regs.seg_regs[1].selector, self.regs.ip)
+    }
+
+    fn code_base(&self) -> u32 {
+        self.regs.seg_regs[1].base
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        Cpu286::interrupts_enabled(self)
+    }
+
+    fn irq(&mut self, bus: &mut B, vector: u8) {
+        self.raise_interrupt(bus, vector);
+    }
+
+    fn nmi(&mut self, bus: &mut B) {
+        self.raise_interrupt(bus, 2);
+    }
+
+    fn snapshot(&self) -> CpuState {
+        let mut seg_regs = [0; 4];
+        for (selector, seg) in seg_regs.iter_mut().zip(self.regs.seg_regs.iter()) {
+            *selector = seg.selector;
+        }
+        CpuState {
+            gprs: self.regs.gprs,
+            seg_regs,
+            ip: self.regs.ip,
+            flags: self.regs.flags.bits(),
+            halted: self.halted,
+        }
+    }
+
+    fn restore(&mut self, state: &CpuState) {
+        self.regs.gprs = state.gprs;
+        for (seg, &selector) in self.regs.seg_regs.iter_mut().zip(&state.seg_regs) {
+            if seg.selector != selector {
+                seg.selector = selector;
+                seg.base = (selector as u32) << 4;
+            }
+        }
+        self.regs.ip = state.ip;
+        self.regs.flags = crate::cpu286::registers::Flags::from_bits_truncate(state.flags);
+        self.halted = state.halted;
+    }
+}
+
+impl<B: Cpu386Context> Cpu<B> for Cpu386 {
+    fn tick(&mut self, bus: &mut B) -> usize {
+        Cpu386::tick(self, bus)
+    }
+
+    fn reset(&mut self) {
+        Cpu386::reset(self)
+    }
+
+    fn halted(&self) -> bool {
+        self.halted
+    }
+
+    fn shutdown(&self) -> bool {
+        self.shutdown
+    }
+
+    fn program_counter(&self) -> (u16, u16) {
+        (self.regs.seg_regs[1].selector, self.regs.eip as u16)
+    }
+
+    fn code_base(&self) -> u32 {
+        self.regs.seg_regs[1].base
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        Cpu386::interrupts_enabled(self)
+    }
+
+    fn irq(&mut self, bus: &mut B, vector: u8) {
+        self.raise_interrupt(bus, vector);
+    }
+
+    fn nmi(&mut self, bus: &mut B) {
+        self.raise_interrupt(bus, 2);
+    }
+
+    /// Only the low words of the 32-bit registers.
+    fn snapshot(&self) -> CpuState {
+        let mut state = CpuState {
+            ip: self.regs.eip as u16,
+            flags: self.regs.eflags.bits() as u16,
+            halted: self.halted,
+            ..Default::default()
+        };
+        for (reg, value) in state.gprs.iter_mut().zip(self.regs.gprs.iter()) {
+            *reg = *value as u16;
+        }
+        for (selector, seg) in state.seg_regs.iter_mut().zip(self.regs.seg_regs.iter()) {
+            *selector = seg.selector;
+        }
+        state
+    }
+
+    /// Only the low words, as `snapshot` has them.
+    fn restore(&mut self, state: &CpuState) {
+        let low = |reg: &mut u32, value: u16| *reg = (*reg & 0xffff_0000) | value as u32;
+        for (reg, &value) in self.regs.gprs.iter_mut().zip(&state.gprs) {
+            low(reg, value);
+        }
+        for (seg, &selector) in self.regs.seg_regs.iter_mut().zip(&state.seg_regs) {
+            if seg.selector != selector {
+                seg.selector = selector;
+                seg.base = (selector as u32) << 4;
+            }
+        }
+        low(&mut self.regs.eip, state.ip);
+        let mut eflags = self.regs.eflags.bits();
+        low(&mut eflags, state.flags);
+        self.regs.eflags = crate::cpu386::registers::Flags::from_bits_truncate(eflags);
+        self.halted = state.halted;
+    }
+}
+
+#[test]
+fn test_irq_through_cpu_trait() {
+    use crate::hardware::ibmpc5150machine::IbmPc5150Hardware;
+    use crate::hardware::ibmpcatmachine::IbmPcAtHardware;
+
+    fn check<B, C: Cpu<B>>(cpu: &mut C, bus: &mut B) {
+        cpu.irq(bus, 8);
+        let state = cpu.snapshot();
+        assert_eq!((state.cs(), state.ip), (0x1234, 0x0010));
+        assert_eq!(cpu.program_counter(), (0x1234, 0x0010));
+        assert_eq!(state.sp(), 0x0ffa);
+        assert!(!cpu.interrupts_enabled());
+    }
+
+    let mut pc = IbmPc5150Hardware::new();
+    pc.memory.ram[0x20..0x24].copy_from_slice(&[0x10, 0x00, 0x34, 0x12]);
+    let mut cpu8086 = Cpu8086::new();
+    cpu8086.regs.gprs[4] = 0x1000;
+    cpu8086
+        .regs
+        .flags
+        .insert(crate::cpu8086::registers::Flags::INTERRUPT);
+    check(&mut cpu8086, &mut pc);
+    assert_eq!(pc.memory.ram[0xffc..0xffe], [0xff, 0xff]);
+
+    let mut at = IbmPcAtHardware::new();
+    at.memory.ram[0x20..0x24].copy_from_slice(&[0x10, 0x00, 0x34, 0x12]);
+    let mut cpu286 = Cpu286::new();
+    cpu286.regs.gprs[4] = 0x1000;
+    cpu286
+        .regs
+        .flags
+        .insert(crate::cpu286::registers::Flags::INTERRUPT);
+    check(&mut cpu286, &mut at);
+    assert_eq!(at.memory.ram[0xffa..0xffc], [0xf0, 0xff]);
+}
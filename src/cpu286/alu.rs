@@ -0,0 +1,448 @@
+use crate::cpu286::registers::*;
+use crate::cpu286::Cpu286;
+use crate::cpu286::Exception;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AluOp {
+    Add,
+    Or,
+    Adc,
+    Sbb,
+    And,
+    Sub,
+    Xor,
+    Cmp,
+}
+
+impl AluOp {
+    /// The operation selected by bits 3-5 of the opcode or of the ModR/M
+    /// reg field, which use the same ordering.
+    pub fn from_num(num: u8) -> AluOp {
+        match num & 7 {
+            0 => AluOp::Add,
+            1 => AluOp::Or,
+            2 => AluOp::Adc,
+            3 => AluOp::Sbb,
+            4 => AluOp::And,
+            5 => AluOp::Sub,
+            6 => AluOp::Xor,
+            _ => AluOp::Cmp,
+        }
+    }
+}
+
+fn mask(word: bool) -> u32 {
+    if word {
+        0xffff
+    } else {
+        0xff
+    }
+}
+
+fn sign(word: bool) -> u32 {
+    if word {
+        0x8000
+    } else {
+        0x80
+    }
+}
+
+impl Cpu286 {
+    pub fn set_pzs(&mut self, value: u16, word: bool) {
+        let value = value as u32 & mask(word);
+        self.regs.flags.set(Flags::ZERO, value == 0);
+        self.regs.flags.set(Flags::SIGN, (value & sign(word)) != 0);
+        self.regs
+            .flags
+            .set(Flags::PARITY, (value as u8).count_ones().is_multiple_of(2));
+    }
+
+    /// Runs one of the eight basic ALU operations and sets the flags.
+    /// The caller decides whether to write the result back, since CMP
+    /// doesn't.
+    pub fn alu(&mut self, op: AluOp, dst: u16, src: u16, word: bool) -> u16 {
+        let dst = dst as u32 & mask(word);
+        let src = src as u32 & mask(word);
+        let carry = self.regs.flags.contains(Flags::CARRY) as u32;
+        let result = match op {
+            AluOp::Add | AluOp::Adc => {
+                let carry = if op == AluOp::Adc { carry } else { 0 };
+                let result = dst + src + carry;
+                self.regs.flags.set(Flags::CARRY, result > mask(word));
+                self.regs.flags.set(
+                    Flags::OVERFLOW,
+                    ((dst ^ result) & (src ^ result) & sign(word)) != 0,
+                );
+                self.regs
+                    .flags
+                    .set(Flags::ADJUST, ((dst ^ src ^ result) & 0x10) != 0);
+                result
+            }
+            AluOp::Sub | AluOp::Sbb | AluOp::Cmp => {
+                let carry = if op == AluOp::Sbb { carry } else { 0 };
+                let result = dst.wrapping_sub(src).wrapping_sub(carry);
+                self.regs.flags.set(Flags::CARRY, dst < src + carry);
+                self.regs.flags.set(
+                    Flags::OVERFLOW,
+                    ((dst ^ src) & (dst ^ result) & sign(word)) != 0,
+                );
+                self.regs
+                    .flags
+                    .set(Flags::ADJUST, ((dst ^ src ^ result) & 0x10) != 0);
+                result
+            }
+            AluOp::Or | AluOp::And | AluOp::Xor => {
+                self.regs
+                    .flags
+                    .remove(Flags::CARRY | Flags::OVERFLOW | Flags::ADJUST);
+                match op {
+                    AluOp::Or => dst | src,
+                    AluOp::And => dst & src,
+                    _ => dst ^ src,
+                }
+            }
+        };
+        let result = (result & mask(word)) as u16;
+        self.set_pzs(result, word);
+        result
+    }
+
+    /// INC and DEC leave the carry flag alone.
+    pub fn inc(&mut self, value: u16, word: bool) -> u16 {
+        let carry = self.regs.flags.contains(Flags::CARRY);
+        let result = self.alu(AluOp::Add, value, 1, word);
+        self.regs.flags.set(Flags::CARRY, carry);
+        result
+    }
+
+    pub fn dec(&mut self, value: u16, word: bool) -> u16 {
+        let carry = self.regs.flags.contains(Flags::CARRY);
+        let result = self.alu(AluOp::Sub, value, 1, word);
+        self.regs.flags.set(Flags::CARRY, carry);
+        result
+    }
+
+    /// The group 2 shifts and rotates, selected by the ModR/M reg field.
+    /// Unlike the 8086, the 286 masks the count to 5 bits.
+    pub fn shift(&mut self, op: u8, value: u16, count: u8, word: bool) -> u16 {
+        let count = count & 0x1f;
+        if count == 0 {
+            return value;
+        }
+        let msb = sign(word);
+        let mut value = value as u32 & mask(word);
+        let mut carry = self.regs.flags.contains(Flags::CARRY);
+        match op & 7 {
+            0 => {
+                for _ in 0..count {
+                    carry = (value & msb) != 0;
+                    value = ((value << 1) | carry as u32) & mask(word);
+                }
+                self.regs
+                    .flags
+                    .set(Flags::OVERFLOW, ((value & msb) != 0) != carry);
+            }
+            1 => {
+                for _ in 0..count {
+                    carry = (value & 1) != 0;
+                    value = (value >> 1) | if carry { msb } else { 0 };
+                }
+                self.regs
+                    .flags
+                    .set(Flags::OVERFLOW, ((value ^ (value << 1)) & msb) != 0);
+            }
+            2 => {
+                for _ in 0..count {
+                    let out = (value & msb) != 0;
+                    value = ((value << 1) | carry as u32) & mask(word);
+                    carry = out;
+                }
+                self.regs
+                    .flags
+                    .set(Flags::OVERFLOW, ((value & msb) != 0) != carry);
+            }
+            3 => {
+                for _ in 0..count {
+                    let out = (value & 1) != 0;
+                    value = (value >> 1) | if carry { msb } else { 0 };
+                    carry = out;
+                }
+                self.regs
+                    .flags
+                    .set(Flags::OVERFLOW, ((value ^ (value << 1)) & msb) != 0);
+            }
+            4 | 6 => {
+                for _ in 0..count {
+                    carry = (value & msb) != 0;
+                    value = (value << 1) & mask(word);
+                }
+                self.regs
+                    .flags
+                    .set(Flags::OVERFLOW, ((value & msb) != 0) != carry);
+                self.set_pzs(value as u16, word);
+            }
+            5 => {
+                self.regs.flags.set(Flags::OVERFLOW, (value & msb) != 0);
+                for _ in 0..count {
+                    carry = (value & 1) != 0;
+                    value >>= 1;
+                }
+                self.set_pzs(value as u16, word);
+            }
+            _ => {
+                for _ in 0..count {
+                    carry = (value & 1) != 0;
+                    value = (value >> 1) | (value & msb);
+                }
+                self.regs.flags.remove(Flags::OVERFLOW);
+                self.set_pzs(value as u16, word);
+            }
+        }
+        self.regs.flags.set(Flags::CARRY, carry);
+        value as u16
+    }
+
+    pub fn mul8(&mut self, src: u8, signed: bool) {
+        let al = self.regs.read8(Reg8::AL);
+        let (result, overflow) = if signed {
+            let result = (al as i8 as i16) * (src as i8 as i16);
+            (result as u16, result != (result as i8 as i16))
+        } else {
+            let result = al as u16 * src as u16;
+            (result, result > 0xff)
+        };
+        self.regs.write16(Reg16::AX, result);
+        self.regs.flags.set(Flags::CARRY, overflow);
+        self.regs.flags.set(Flags::OVERFLOW, overflow);
+    }
+
+    pub fn mul16(&mut self, src: u16, signed: bool) {
+        let ax = self.regs.read16(Reg16::AX);
+        let (result, overflow) = if signed {
+            let result = (ax as i16 as i32) * (src as i16 as i32);
+            (result as u32, result != (result as i16 as i32))
+        } else {
+            let result = ax as u32 * src as u32;
+            (result, result > 0xffff)
+        };
+        self.regs.write16(Reg16::AX, result as u16);
+        self.regs.write16(Reg16::DX, (result >> 16) as u16);
+        self.regs.flags.set(Flags::CARRY, overflow);
+        self.regs.flags.set(Flags::OVERFLOW, overflow);
+    }
+
+    /// The three operand IMUL added by the 186. Only the low word is kept.
+    pub fn imul_word(&mut self, a: u16, b: u16) -> u16 {
+        let result = (a as i16 as i32) * (b as i16 as i32);
+        let overflow = result != (result as i16 as i32);
+        self.regs.flags.set(Flags::CARRY, overflow);
+        self.regs.flags.set(Flags::OVERFLOW, overflow);
+        result as u16
+    }
+
+    pub fn div8(&mut self, src: u8, signed: bool) -> Result<(), Exception> {
+        if src == 0 {
+            return Err(Exception::DivideError);
+        }
+        let ax = self.regs.read16(Reg16::AX);
+        let (quotient, remainder) = if signed {
+            let dividend = ax as i16 as i32;
+            let divisor = src as i8 as i32;
+            let quotient = dividend / divisor;
+            if quotient < i8::MIN as i32 || quotient > i8::MAX as i32 {
+                return Err(Exception::DivideError);
+            }
+            (quotient as u8, (dividend % divisor) as u8)
+        } else {
+            let quotient = ax / src as u16;
+            if quotient > 0xff {
+                return Err(Exception::DivideError);
+            }
+            (quotient as u8, (ax % src as u16) as u8)
+        };
+        self.regs.write8(Reg8::AL, quotient);
+        self.regs.write8(Reg8::AH, remainder);
+        Ok(())
+    }
+
+    pub fn div16(&mut self, src: u16, signed: bool) -> Result<(), Exception> {
+        if src == 0 {
+            return Err(Exception::DivideError);
+        }
+        let dividend =
+            ((self.regs.read16(Reg16::DX) as u32) << 16) | self.regs.read16(Reg16::AX) as u32;
+        let (quotient, remainder) = if signed {
+            let dividend = dividend as i32 as i64;
+            let divisor = src as i16 as i64;
+            let quotient = dividend / divisor;
+            if quotient < i16::MIN as i64 || quotient > i16::MAX as i64 {
+                return Err(Exception::DivideError);
+            }
+            (quotient as u16, (dividend % divisor) as u16)
+        } else {
+            let quotient = dividend / src as u32;
+            if quotient > 0xffff {
+                return Err(Exception::DivideError);
+            }
+            (quotient as u16, (dividend % src as u32) as u16)
+        };
+        self.regs.write16(Reg16::AX, quotient);
+        self.regs.write16(Reg16::DX, remainder);
+        Ok(())
+    }
+
+    pub fn daa(&mut self, subtract: bool) {
+        let old_al = self.regs.read8(Reg8::AL);
+        let old_carry = self.regs.flags.contains(Flags::CARRY);
+        let mut al = old_al;
+        let adjust = |al: u8, value: u8| {
+            if subtract {
+                al.wrapping_sub(value)
+            } else {
+                al.wrapping_add(value)
+            }
+        };
+        if (old_al & 0xf) > 9 || self.regs.flags.contains(Flags::ADJUST) {
+            al = adjust(al, 6);
+            self.regs.flags.insert(Flags::ADJUST);
+        } else {
+            self.regs.flags.remove(Flags::ADJUST);
+        }
+        if old_al > 0x99 || old_carry {
+            al = adjust(al, 0x60);
+            self.regs.flags.insert(Flags::CARRY);
+        } else {
+            self.regs.flags.remove(Flags::CARRY);
+        }
+        self.regs.write8(Reg8::AL, al);
+        self.set_pzs(al as u16, false);
+    }
+
+    pub fn aaa(&mut self, subtract: bool) {
+        let al = self.regs.read8(Reg8::AL);
+        if (al & 0xf) > 9 || self.regs.flags.contains(Flags::ADJUST) {
+            let ax = self.regs.read16(Reg16::AX);
+            let ax = if subtract {
+                ax.wrapping_sub(6).wrapping_sub(0x100)
+            } else {
+                ax.wrapping_add(0x106)
+            };
+            self.regs.write16(Reg16::AX, ax);
+            self.regs.flags.insert(Flags::ADJUST | Flags::CARRY);
+        } else {
+            self.regs.flags.remove(Flags::ADJUST | Flags::CARRY);
+        }
+        let al = self.regs.read8(Reg8::AL) & 0xf;
+        self.regs.write8(Reg8::AL, al);
+    }
+
+    pub fn aam(&mut self, base: u8) -> Result<(), Exception> {
+        if base == 0 {
+            return Err(Exception::DivideError);
+        }
+        let al = self.regs.read8(Reg8::AL);
+        self.regs.write8(Reg8::AH, al / base);
+        self.regs.write8(Reg8::AL, al % base);
+        self.set_pzs((al % base) as u16, false);
+        Ok(())
+    }
+
+    pub fn aad(&mut self, base: u8) {
+        let al = self.regs.read8(Reg8::AL);
+        let ah = self.regs.read8(Reg8::AH);
+        let result = al.wrapping_add(ah.wrapping_mul(base));
+        self.regs.write16(Reg16::AX, result as u16);
+        self.set_pzs(result as u16, false);
+    }
+}
+
+#[test]
+fn test_alu_flags() {
+    let mut cpu = Cpu286::new();
+    let flags = |cpu: &Cpu286| {
+        let f = cpu.regs.flags;
+        [
+            f.contains(Flags::CARRY),
+            f.contains(Flags::PARITY),
+            f.contains(Flags::ADJUST),
+            f.contains(Flags::ZERO),
+            f.contains(Flags::SIGN),
+            f.contains(Flags::OVERFLOW),
+        ]
+    };
+    //                                                  C      P      A      Z      S      O
+    assert_eq!(cpu.alu(AluOp::Add, 0x7f, 0x01, false), 0x80);
+    assert_eq!(flags(&cpu), [false, false, true, false, true, true]);
+    assert_eq!(cpu.alu(AluOp::Add, 0xff, 0x01, false), 0x00);
+    assert_eq!(flags(&cpu), [true, true, true, true, false, false]);
+    // ADC takes the carry that just came out.
+    assert_eq!(cpu.alu(AluOp::Adc, 0xfffe, 0x0001, true), 0x0000);
+    assert_eq!(flags(&cpu), [true, true, true, true, false, false]);
+    assert_eq!(cpu.alu(AluOp::Sub, 0x8000, 0x0001, true), 0x7fff);
+    assert_eq!(flags(&cpu), [false, true, true, false, false, true]);
+    assert_eq!(cpu.alu(AluOp::Cmp, 0x0001, 0x0002, true), 0xffff);
+    assert_eq!(flags(&cpu), [true, true, true, false, true, false]);
+    assert_eq!(cpu.alu(AluOp::Sbb, 0x00, 0x00, false), 0xff);
+    assert_eq!(flags(&cpu), [true, true, true, false, true, false]);
+    // The logical operations clear carry, overflow and adjust.
+    assert_eq!(cpu.alu(AluOp::And, 0xf0f0, 0x0ff0, true), 0x00f0);
+    assert_eq!(flags(&cpu), [false, true, false, false, false, false]);
+    assert_eq!(cpu.alu(AluOp::Xor, 0x55, 0x55, false), 0x00);
+    assert_eq!(flags(&cpu), [false, true, false, true, false, false]);
+    assert_eq!(cpu.alu(AluOp::Or, 0x8000, 0x0001, true), 0x8001);
+    assert_eq!(flags(&cpu), [false, false, false, false, true, false]);
+
+    // INC and DEC keep whatever carry there was.
+    cpu.regs.flags.insert(Flags::CARRY);
+    assert_eq!(cpu.inc(0xff, false), 0x00);
+    assert_eq!(flags(&cpu), [true, true, true, true, false, false]);
+    cpu.regs.flags.remove(Flags::CARRY);
+    assert_eq!(cpu.dec(0x8000, true), 0x7fff);
+    assert_eq!(flags(&cpu), [false, true, true, false, false, true]);
+}
+
+#[test]
+fn test_shifts_and_rotates() {
+    let mut cpu = Cpu286::new();
+    let carry = |cpu: &Cpu286| cpu.regs.flags.contains(Flags::CARRY);
+    let overflow = |cpu: &Cpu286| cpu.regs.flags.contains(Flags::OVERFLOW);
+
+    // rol, ror
+    assert_eq!(cpu.shift(0, 0x81, 1, false), 0x03);
+    assert!(carry(&cpu) && overflow(&cpu));
+    assert_eq!(cpu.shift(1, 0x0001, 1, true), 0x8000);
+    assert!(carry(&cpu) && overflow(&cpu));
+    assert_eq!(cpu.shift(0, 0x1234, 4, true), 0x2341);
+    assert!(carry(&cpu));
+
+    // rcl, rcr go through the carry flag.
+    cpu.regs.flags.remove(Flags::CARRY);
+    assert_eq!(cpu.shift(2, 0x8000, 1, true), 0x0000);
+    assert!(carry(&cpu) && overflow(&cpu));
+    assert_eq!(cpu.shift(3, 0x01, 1, false), 0x80);
+    assert!(carry(&cpu) && overflow(&cpu));
+    // Eight bits and the carry rotate as nine, so the carry ends up in
+    // bit 7 after eight.
+    assert_eq!(cpu.shift(2, 0x00, 8, false), 0x80);
+    assert!(!carry(&cpu));
+
+    // shl, shr, sar
+    assert_eq!(cpu.shift(4, 0x4000, 1, true), 0x8000);
+    assert!(!carry(&cpu) && overflow(&cpu));
+    assert!(cpu.regs.flags.contains(Flags::SIGN));
+    assert_eq!(cpu.shift(5, 0x81, 1, false), 0x40);
+    assert!(carry(&cpu) && overflow(&cpu));
+    assert_eq!(cpu.shift(7, 0x81, 1, false), 0xc0);
+    assert!(carry(&cpu) && !overflow(&cpu));
+    assert_eq!(cpu.shift(7, 0x8000, 15, true), 0xffff);
+    assert!(cpu.regs.flags.contains(Flags::SIGN));
+    assert_eq!(cpu.shift(5, 0x8000, 16, true), 0x0000);
+    assert!(carry(&cpu) && cpu.regs.flags.contains(Flags::ZERO));
+
+    // The count is masked to five bits, so 21h shifts once and 20h
+    // doesn't shift or touch the flags at all.
+    assert_eq!(cpu.shift(4, 0x01, 0x21, false), 0x02);
+    cpu.regs.flags.insert(Flags::CARRY);
+    assert_eq!(cpu.shift(4, 0x01, 0x20, false), 0x01);
+    assert!(carry(&cpu));
+}
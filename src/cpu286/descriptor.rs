@@ -0,0 +1,404 @@
+use crate::cpu286::registers::*;
+use crate::cpu286::Cpu286;
+use crate::cpu286::Cpu286Context;
+use crate::cpu286::Exception;
+
+/// An 8-byte descriptor from the GDT, LDT or IDT. Gates use the same
+/// layout with the offset in the limit field and the selector in the low
+/// word of the base.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Descriptor {
+    pub limit: u16,
+    pub base: u32,
+    pub rights: u8,
+}
+
+impl Descriptor {
+    pub fn from_bytes(bytes: [u8; 6]) -> Descriptor {
+        Descriptor {
+            limit: u16::from_le_bytes([bytes[0], bytes[1]]),
+            base: u32::from_le_bytes([bytes[2], bytes[3], bytes[4], 0]),
+            rights: bytes[5],
+        }
+    }
+
+    pub fn present(&self) -> bool {
+        (self.rights & 0x80) != 0
+    }
+
+    pub fn dpl(&self) -> u16 {
+        ((self.rights >> 5) & 3) as u16
+    }
+
+    /// Code or data, as opposed to a system descriptor or gate.
+    pub fn is_segment(&self) -> bool {
+        (self.rights & 0x10) != 0
+    }
+
+    pub fn is_code(&self) -> bool {
+        self.is_segment() && (self.rights & 0x08) != 0
+    }
+
+    pub fn is_data(&self) -> bool {
+        self.is_segment() && (self.rights & 0x08) == 0
+    }
+
+    pub fn is_conforming(&self) -> bool {
+        self.is_code() && (self.rights & 0x04) != 0
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.is_data() || (self.rights & 0x02) != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.is_data() && (self.rights & 0x02) != 0
+    }
+
+    /// The type field of a system descriptor: 1 and 3 are TSSs, 2 is an
+    /// LDT, 4-7 are call, task, interrupt and trap gates.
+    pub fn system_type(&self) -> u8 {
+        self.rights & 0x0f
+    }
+
+    pub fn gate_offset(&self) -> u16 {
+        self.limit
+    }
+
+    pub fn gate_selector(&self) -> u16 {
+        self.base as u16
+    }
+
+    pub fn gate_word_count(&self) -> u16 {
+        ((self.base >> 16) & 0x1f) as u16
+    }
+
+    pub fn to_cache(self, selector: u16) -> SegmentRegister {
+        SegmentRegister {
+            selector,
+            base: self.base,
+            limit: self.limit,
+            rights: self.rights,
+            valid: true,
+        }
+    }
+}
+
+impl Cpu286 {
+    fn descriptor_addr(&self, selector: u16) -> Result<u32, Exception> {
+        let index = (selector & 0xfff8) as u32;
+        let (base, limit) = if (selector & 4) != 0 {
+            (self.regs.ldtr.base, self.regs.ldtr.limit)
+        } else {
+            (self.regs.gdtr.base, self.regs.gdtr.limit)
+        };
+        if index + 7 > limit as u32 {
+            return Err(Exception::GeneralProtection(selector & 0xfffc));
+        }
+        Ok(base + index)
+    }
+
+    pub fn read_descriptor<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Result<Descriptor, Exception> {
+        let addr = self.descriptor_addr(selector)?;
+        let mut bytes = [0u8; 6];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.mem_read_byte(ctx, addr + i as u32);
+        }
+        Ok(Descriptor::from_bytes(bytes))
+    }
+
+    pub fn set_accessed<T: Cpu286Context>(&mut self, ctx: &mut T, selector: u16) {
+        if let Ok(addr) = self.descriptor_addr(selector) {
+            let rights = self.mem_read_byte(ctx, addr + 5);
+            self.mem_write_byte(ctx, addr + 5, rights | 1);
+        }
+    }
+
+    /// Loads a segment register in protected mode, checking the descriptor
+    /// the selector points at and filling in the hidden descriptor cache.
+    pub fn load_protected_segment<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        selector: u16,
+    ) -> Result<(), Exception> {
+        let error_code = selector & 0xfffc;
+        if error_code == 0 {
+            // A null selector can sit in DS or ES until it is used.
+            if seg == SegReg::CS || seg == SegReg::SS {
+                return Err(Exception::GeneralProtection(0));
+            }
+            self.regs.setseg(
+                seg,
+                SegmentRegister {
+                    selector,
+                    valid: false,
+                    ..Default::default()
+                },
+            );
+            return Ok(());
+        }
+        let descriptor = self.read_descriptor(ctx, selector)?;
+        let cpl = self.regs.cpl();
+        let rpl = selector & 3;
+        let selector = match seg {
+            SegReg::SS => {
+                if !descriptor.is_writable() || rpl != cpl || descriptor.dpl() != cpl {
+                    return Err(Exception::GeneralProtection(error_code));
+                }
+                if !descriptor.present() {
+                    return Err(Exception::StackFault(error_code));
+                }
+                selector
+            }
+            SegReg::CS => {
+                if !descriptor.is_code() {
+                    return Err(Exception::GeneralProtection(error_code));
+                }
+                let allowed = if descriptor.is_conforming() {
+                    descriptor.dpl() <= cpl
+                } else {
+                    descriptor.dpl() == cpl && rpl <= cpl
+                };
+                if !allowed {
+                    return Err(Exception::GeneralProtection(error_code));
+                }
+                if !descriptor.present() {
+                    return Err(Exception::SegmentNotPresent(error_code));
+                }
+                // Control transfers through here never change privilege.
+                (selector & 0xfffc) | cpl
+            }
+            _ => {
+                if !descriptor.is_readable() {
+                    return Err(Exception::GeneralProtection(error_code));
+                }
+                if !descriptor.is_conforming() && descriptor.dpl() < cpl.max(rpl) {
+                    return Err(Exception::GeneralProtection(error_code));
+                }
+                if !descriptor.present() {
+                    return Err(Exception::SegmentNotPresent(error_code));
+                }
+                selector
+            }
+        };
+        self.set_accessed(ctx, selector);
+        self.regs.setseg(seg, descriptor.to_cache(selector));
+        Ok(())
+    }
+
+    /// The descriptor LAR, LSL, VERR and VERW look at, if the current
+    /// privilege level can see it. None of these instructions fault on a
+    /// bad selector; they just clear ZF.
+    fn visible_descriptor<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Option<Descriptor> {
+        if (selector & 0xfffc) == 0 {
+            return None;
+        }
+        let descriptor = self.read_descriptor(ctx, selector).ok()?;
+        let level = self.regs.cpl().max(selector & 3);
+        if descriptor.is_conforming() || descriptor.dpl() >= level {
+            Some(descriptor)
+        } else {
+            None
+        }
+    }
+
+    /// LAR. Returns the access rights byte in the high byte, as the 286
+    /// does, for any segment, TSS, LDT, call gate or task gate.
+    pub fn load_access_rights<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Option<u16> {
+        let descriptor = self.visible_descriptor(ctx, selector)?;
+        if descriptor.is_segment() || matches!(descriptor.system_type(), 1..=5) {
+            Some((descriptor.rights as u16) << 8)
+        } else {
+            None
+        }
+    }
+
+    /// LSL. Gates have no limit, so only segments, TSSs and LDTs count.
+    pub fn load_segment_limit<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Option<u16> {
+        let descriptor = self.visible_descriptor(ctx, selector)?;
+        if descriptor.is_segment() || matches!(descriptor.system_type(), 1..=3) {
+            Some(descriptor.limit)
+        } else {
+            None
+        }
+    }
+
+    /// VERR and VERW: whether the segment could be read or written at the
+    /// current privilege level.
+    pub fn verify_segment<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+        write: bool,
+    ) -> bool {
+        match self.visible_descriptor(ctx, selector) {
+            Some(descriptor) if write => descriptor.is_writable(),
+            Some(descriptor) => descriptor.is_segment() && descriptor.is_readable(),
+            None => false,
+        }
+    }
+
+    /// LLDT. The selector must point at an LDT descriptor in the GDT; a
+    /// null selector leaves the LDT unusable.
+    pub fn load_ldt<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Result<(), Exception> {
+        let error_code = selector & 0xfffc;
+        if error_code == 0 {
+            self.regs.ldtr = LDTRTR {
+                selector,
+                ..Default::default()
+            };
+            return Ok(());
+        }
+        if (selector & 4) != 0 {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        let descriptor = self.read_descriptor(ctx, selector)?;
+        if descriptor.is_segment() || descriptor.system_type() != 2 {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        if !descriptor.present() {
+            return Err(Exception::SegmentNotPresent(error_code));
+        }
+        self.regs.ldtr = LDTRTR {
+            selector,
+            base: descriptor.base,
+            limit: descriptor.limit,
+            rights: descriptor.rights,
+        };
+        Ok(())
+    }
+}
+
+#[test]
+fn test_protected_mode_segment_loads() {
+    let mut bus = crate::cpu286::TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.ip = 0x100;
+    cpu.regs.gdtr = GDTRIDTR {
+        base: 0x800,
+        limit: 0x1f,
+    };
+    // code, writable data and read-only data, all at base 0
+    bus.ram[0x808..0x80e].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0x9a]);
+    bus.ram[0x810..0x816].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0x92]);
+    bus.ram[0x818..0x81e].copy_from_slice(&[0xff, 0x00, 0, 0, 0, 0x90]);
+    bus.ram[0x100..0x10b].copy_from_slice(&[
+        0xb8, 0x01, 0x00, // mov ax, 1
+        0x0f, 0x01, 0xf0, // lmsw ax
+        0xea, 0x20, 0x01, 0x08, 0x00, // jmp 0008:0120
+    ]);
+    for _ in 0..3 {
+        cpu.tick(&mut bus);
+    }
+    assert!(cpu.regs.protected_mode());
+    assert_eq!(cpu.regs.readseg16(SegReg::CS).selector, 0x08);
+    assert_eq!(cpu.regs.ip, 0x120);
+    assert_eq!(bus.ram[0x80d], 0x9b);
+
+    cpu.load_segment(&mut bus, SegReg::DS, 0x18).unwrap();
+    assert_eq!(
+        cpu.write8(&mut bus, SegReg::DS, 0, 0),
+        Err(Exception::GeneralProtection(0))
+    );
+    assert_eq!(
+        cpu.read8(&mut bus, SegReg::DS, 0x100),
+        Err(Exception::GeneralProtection(0))
+    );
+    assert_eq!(
+        cpu.load_segment(&mut bus, SegReg::SS, 0x18),
+        Err(Exception::GeneralProtection(0x18))
+    );
+    assert_eq!(
+        cpu.load_segment(&mut bus, SegReg::DS, 0x20),
+        Err(Exception::GeneralProtection(0x20))
+    );
+    cpu.load_segment(&mut bus, SegReg::ES, 0).unwrap();
+    assert_eq!(
+        cpu.read8(&mut bus, SegReg::ES, 0),
+        Err(Exception::GeneralProtection(0))
+    );
+}
+
+#[test]
+fn test_descriptor_inspection() {
+    let mut bus = crate::cpu286::TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.ip = 0x100;
+    bus.ram[0x100..0x105].copy_from_slice(&[0x0f, 0x02, 0xc3, 0x63, 0xd0]);
+    assert_eq!(cpu.execute(&mut bus), Err(Exception::InvalidOpcode));
+    cpu.regs.ip = 0x103;
+    assert_eq!(cpu.execute(&mut bus), Err(Exception::InvalidOpcode));
+
+    cpu.regs.gdtr = GDTRIDTR {
+        base: 0x800,
+        limit: 0x27,
+    };
+    // code, ring 3 data, read-only data with a small limit, interrupt gate
+    bus.ram[0x808..0x80e].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0x9a]);
+    bus.ram[0x810..0x816].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0xf2]);
+    bus.ram[0x818..0x81e].copy_from_slice(&[0xff, 0x00, 0, 0, 0, 0x90]);
+    bus.ram[0x820..0x826].copy_from_slice(&[0x00, 0x01, 0x08, 0, 0, 0x86]);
+    cpu.regs.msw.insert(Msw::PROTECTION_ENABLE);
+    cpu.load_segment(&mut bus, SegReg::CS, 0x08).unwrap();
+    bus.ram[0x200..0x215].copy_from_slice(&[
+        0x0f, 0x02, 0xc3, // lar ax, bx
+        0x0f, 0x03, 0xcb, // lsl cx, bx
+        0x0f, 0x00, 0xe3, // verr bx
+        0x0f, 0x00, 0xeb, // verw bx
+        0x63, 0xd0, // arpl ax, dx
+        0x0f, 0x02, 0xc3, // lar ax, bx
+        0x0f, 0x00, 0xeb, // verw bx
+        0x90,
+    ]);
+    cpu.regs.ip = 0x200;
+    cpu.regs.write16(Reg16::BX, 0x18);
+    cpu.execute(&mut bus).unwrap();
+    assert_eq!(cpu.regs.read16(Reg16::AX), 0x9000);
+    cpu.execute(&mut bus).unwrap();
+    assert_eq!(cpu.regs.read16(Reg16::CX), 0x00ff);
+    cpu.execute(&mut bus).unwrap();
+    assert!(cpu.regs.flags.contains(Flags::ZERO));
+    cpu.execute(&mut bus).unwrap();
+    assert!(!cpu.regs.flags.contains(Flags::ZERO));
+
+    cpu.regs.write16(Reg16::AX, 0x10);
+    cpu.regs.write16(Reg16::DX, 3);
+    cpu.execute(&mut bus).unwrap();
+    assert_eq!(cpu.regs.read16(Reg16::AX), 0x13);
+    assert!(cpu.regs.flags.contains(Flags::ZERO));
+
+    cpu.regs.write16(Reg16::BX, 0x20);
+    cpu.execute(&mut bus).unwrap();
+    assert!(!cpu.regs.flags.contains(Flags::ZERO));
+    assert_eq!(cpu.regs.read16(Reg16::AX), 0x13);
+    cpu.regs.write16(Reg16::BX, 0x13);
+    cpu.execute(&mut bus).unwrap();
+    assert!(cpu.regs.flags.contains(Flags::ZERO));
+}
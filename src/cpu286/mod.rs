@@ -1,295 +1,2050 @@
+use crate::cpu286::alu::AluOp;
+use crate::cpu286::operand::*;
 use crate::cpu286::registers::*;
+use crate::cpu286::task::TaskSwitch;
+use log::{debug, error, trace};
 
+pub mod alu;
+pub mod descriptor;
+pub mod operand;
+pub mod privilege;
 pub mod registers;
+pub mod task;
+pub mod timing;
 
 pub trait Cpu286Context {
     fn mem_read_byte(&mut self, addr: u32) -> u8;
     fn mem_write_byte(&mut self, addr: u32, value: u8);
     fn io_read_byte(&mut self, addr: u16) -> u8;
     fn io_write_byte(&mut self, addr: u16, value: u8);
+    fn io_read_word(&mut self, addr: u16) -> u16 {
+        let lo = self.io_read_byte(addr);
+        let hi = self.io_read_byte(addr.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+    fn io_write_word(&mut self, addr: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.io_write_byte(addr, lo);
+        self.io_write_byte(addr.wrapping_add(1), hi);
+    }
+    /// The address lines the board lets through. On the AT, A20 is gated by
+    /// the keyboard controller so real mode software sees memory wrap at
+    /// 1 MB like on an 8086.
+    fn a20_mask(&self) -> u32 {
+        0xff_ffff
+    }
+    /// Extra clocks the board adds to a byte access at `addr`, for slow
+    /// 8-bit cards and memory that needs wait states.
+    fn mem_wait_states(&self, _addr: u32) -> usize {
+        0
+    }
+    /// The same for a byte access to an I/O port.
+    fn io_wait_states(&self, _addr: u16) -> usize {
+        0
+    }
+}
+
+/// Faults raised while executing an instruction. They abort the
+/// instruction and are delivered through the interrupt table with CS:IP
+/// pointing back at the faulting instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exception {
+    DivideError,
+    BoundRange,
+    InvalidOpcode,
+    DeviceNotAvailable,
+    DoubleFault,
+    InvalidTss(u16),
+    SegmentNotPresent(u16),
+    StackFault(u16),
+    GeneralProtection(u16),
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        match self {
+            Exception::DivideError => 0,
+            Exception::BoundRange => 5,
+            Exception::InvalidOpcode => 6,
+            Exception::DeviceNotAvailable => 7,
+            Exception::DoubleFault => 8,
+            Exception::InvalidTss(_) => 10,
+            Exception::SegmentNotPresent(_) => 11,
+            Exception::StackFault(_) => 12,
+            Exception::GeneralProtection(_) => 13,
+        }
+    }
+
+    /// Protected mode pushes an error code for these, usually the selector
+    /// that caused the fault.
+    pub fn error_code(self) -> Option<u16> {
+        match self {
+            Exception::DoubleFault => Some(0),
+            Exception::InvalidTss(code)
+            | Exception::SegmentNotPresent(code)
+            | Exception::StackFault(code)
+            | Exception::GeneralProtection(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Two contributory faults in a row make a double fault. Anything
+    /// else is handled one after the other.
+    pub fn is_contributory(self) -> bool {
+        matches!(
+            self,
+            Exception::DivideError
+                | Exception::InvalidTss(_)
+                | Exception::SegmentNotPresent(_)
+                | Exception::StackFault(_)
+                | Exception::GeneralProtection(_)
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SegAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RepType {
+    REPE,
+    REPNE,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Cpu286 {
     pub regs: Registers,
     pub opcode: u8,
+    pub halted: bool,
+    /// A fault while delivering a double fault stops the CPU until it is
+    /// reset. The AT decodes this bus cycle and resets the CPU itself.
+    pub shutdown: bool,
+    pub seg_override: Option<SegReg>,
+    pub rep_state: Option<RepType>,
+    /// Set by instructions that hold off interrupts until after the next
+    /// instruction (STI, MOV SS and POP SS).
+    pub interrupt_shadow: bool,
+    pub instr_ip: u16,
+    pub instr_sp: u16,
+    /// CS and SS as they were at the start of the instruction, so a fault
+    /// halfway through a far transfer leaves nothing half loaded.
+    pub instr_cs: SegmentRegister,
+    pub instr_ss: SegmentRegister,
+    /// Clocks taken by the current instruction so far, wait states
+    /// included.
+    pub cycles: usize,
 }
 
 impl Cpu286 {
     pub fn new() -> Cpu286 {
         Cpu286 {
             regs: Registers::new(),
-            opcode: 0,
+            ..Default::default()
         }
     }
+
+    pub fn reset(&mut self) {
+        *self = Cpu286::new();
+    }
     pub fn mem_read_byte<T: Cpu286Context>(&mut self, ctx: &mut T, addr: u32) -> u8 {
-        let masked_addr = addr & 0xff_ffff;
+        let masked_addr = addr & 0xff_ffff & ctx.a20_mask();
+        self.cycles += ctx.mem_wait_states(masked_addr);
         ctx.mem_read_byte(masked_addr)
     }
     pub fn mem_write_byte<T: Cpu286Context>(&mut self, ctx: &mut T, addr: u32, value: u8) {
-        let masked_addr = addr & 0xff_ffff;
+        let masked_addr = addr & 0xff_ffff & ctx.a20_mask();
+        self.cycles += ctx.mem_wait_states(masked_addr);
         ctx.mem_write_byte(masked_addr, value)
     }
 
+    fn io_read8<T: Cpu286Context>(&mut self, ctx: &mut T, port: u16) -> u8 {
+        self.cycles += ctx.io_wait_states(port);
+        ctx.io_read_byte(port)
+    }
+
+    fn io_read16<T: Cpu286Context>(&mut self, ctx: &mut T, port: u16) -> u16 {
+        self.cycles += ctx.io_wait_states(port) + ctx.io_wait_states(port.wrapping_add(1));
+        ctx.io_read_word(port)
+    }
+
+    fn io_write8<T: Cpu286Context>(&mut self, ctx: &mut T, port: u16, value: u8) {
+        self.cycles += ctx.io_wait_states(port);
+        ctx.io_write_byte(port, value)
+    }
+
+    fn io_write16<T: Cpu286Context>(&mut self, ctx: &mut T, port: u16, value: u16) {
+        self.cycles += ctx.io_wait_states(port) + ctx.io_wait_states(port.wrapping_add(1));
+        ctx.io_write_word(port, value)
+    }
+
     pub fn mem_read_word<T: Cpu286Context>(&mut self, ctx: &mut T, addr: u32) -> u16 {
-        let masked_addr = addr & 0xff_ffff;
-        let lo = ctx.mem_read_byte(masked_addr);
-        let hi = ctx.mem_read_byte(masked_addr.wrapping_add(1) & 0xff_ffff);
+        let lo = self.mem_read_byte(ctx, addr);
+        let hi = self.mem_read_byte(ctx, addr.wrapping_add(1));
         u16::from_le_bytes([lo, hi])
     }
 
-    pub fn tick<T: Cpu286Context>(&mut self, ctx: &mut T) {
-        self.opcode = self.mem_read_byte(
-            ctx,
-            self.regs.readseg16(SegReg::CS).base + self.regs.ip as u32,
-        );
-        println!(
-            "Opcode {:#02x} CS base {:#06x} IP {:#04x}",
-            self.opcode,
-            self.regs.readseg16(SegReg::CS).base,
-            self.regs.ip
-        );
-        match self.opcode {
-            0x70 => {
-                println!("jo");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if self.regs.flags.contains(Flags::OVERFLOW) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
-                }
-            }
-            0x71 => {
-                println!("jno");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if !self.regs.flags.contains(Flags::OVERFLOW) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
-                }
-            }
-            0x72 => {
-                println!("jc");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if self.regs.flags.contains(Flags::CARRY) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
-                }
-            }
-            0x73 => {
-                println!("jnc");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if !self.regs.flags.contains(Flags::CARRY) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
-                }
-            }
-            0x74 => {
-                println!("jz");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if self.regs.flags.contains(Flags::ZERO) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
-                }
-            }
-            0x75 => {
-                println!("jnz");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if !self.regs.flags.contains(Flags::ZERO) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
-                }
-            }
-            0x78 => {
-                println!("js");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if self.regs.flags.contains(Flags::SIGN) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
-                }
-            }
-            0x79 => {
-                println!("jns");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if !self.regs.flags.contains(Flags::SIGN) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
-                }
-            }
-            0x7a => {
-                println!("jp");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if self.regs.flags.contains(Flags::PARITY) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
-                }
-            }
-            0x7b => {
-                println!("jnp");
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                let offset: i16 = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                ) as i8 as i16;
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                if !self.regs.flags.contains(Flags::PARITY) {
-                    self.regs.ip = self.regs.ip.wrapping_add(offset as u16);
+    pub fn mem_write_word<T: Cpu286Context>(&mut self, ctx: &mut T, addr: u32, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.mem_write_byte(ctx, addr, lo);
+        self.mem_write_byte(ctx, addr.wrapping_add(1), hi);
+    }
+
+    /// Checks that `size` bytes at `offset` fit in the segment and returns
+    /// the 24-bit linear address. A word at offset FFFF doesn't wrap on the
+    /// 286 like it does on the 8086, it faults. In protected mode the
+    /// access rights in the descriptor cache are checked too.
+    fn linear_addr(
+        &self,
+        seg: SegReg,
+        offset: u16,
+        size: u16,
+        access: SegAccess,
+    ) -> Result<u32, Exception> {
+        let segment = self.regs.readseg16(seg);
+        let fault = if seg == SegReg::SS {
+            Exception::StackFault(0)
+        } else {
+            Exception::GeneralProtection(0)
+        };
+        let last = offset as u32 + size as u32 - 1;
+        let expand_down = (segment.rights & 0x1c) == 0x14;
+        let in_limit = if expand_down {
+            offset > segment.limit && last <= 0xffff
+        } else {
+            last <= segment.limit as u32
+        };
+        if !in_limit {
+            return Err(fault);
+        }
+        if self.regs.protected_mode() && access != SegAccess::Execute {
+            if !segment.valid {
+                return Err(fault);
+            }
+            let code = (segment.rights & 0x08) != 0;
+            let rw = (segment.rights & 0x02) != 0;
+            let allowed = match access {
+                SegAccess::Write => !code && rw,
+                _ => !code || rw,
+            };
+            if !allowed {
+                return Err(fault);
+            }
+        }
+        Ok((segment.base + offset as u32) & 0xff_ffff)
+    }
+
+    pub fn read8<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        offset: u16,
+    ) -> Result<u8, Exception> {
+        let addr = self.linear_addr(seg, offset, 1, SegAccess::Read)?;
+        Ok(self.mem_read_byte(ctx, addr))
+    }
+
+    pub fn read16<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        offset: u16,
+    ) -> Result<u16, Exception> {
+        let addr = self.linear_addr(seg, offset, 2, SegAccess::Read)?;
+        Ok(self.mem_read_word(ctx, addr))
+    }
+
+    pub fn write8<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        offset: u16,
+        value: u8,
+    ) -> Result<(), Exception> {
+        let addr = self.linear_addr(seg, offset, 1, SegAccess::Write)?;
+        self.mem_write_byte(ctx, addr, value);
+        Ok(())
+    }
+
+    pub fn write16<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        offset: u16,
+        value: u16,
+    ) -> Result<(), Exception> {
+        let addr = self.linear_addr(seg, offset, 2, SegAccess::Write)?;
+        self.mem_write_word(ctx, addr, value);
+        Ok(())
+    }
+
+    pub fn fetch8<T: Cpu286Context>(&mut self, ctx: &mut T) -> Result<u8, Exception> {
+        let addr = self.linear_addr(SegReg::CS, self.regs.ip, 1, SegAccess::Execute)?;
+        let value = self.mem_read_byte(ctx, addr);
+        self.regs.ip = self.regs.ip.wrapping_add(1);
+        Ok(value)
+    }
+
+    pub fn fetch16<T: Cpu286Context>(&mut self, ctx: &mut T) -> Result<u16, Exception> {
+        let addr = self.linear_addr(SegReg::CS, self.regs.ip, 2, SegAccess::Execute)?;
+        let value = self.mem_read_word(ctx, addr);
+        self.regs.ip = self.regs.ip.wrapping_add(2);
+        Ok(value)
+    }
+
+    pub fn push<T: Cpu286Context>(&mut self, ctx: &mut T, value: u16) -> Result<(), Exception> {
+        let sp = self.regs.read16(Reg16::SP).wrapping_sub(2);
+        self.write16(ctx, SegReg::SS, sp, value)?;
+        self.regs.write16(Reg16::SP, sp);
+        Ok(())
+    }
+
+    pub fn pop<T: Cpu286Context>(&mut self, ctx: &mut T) -> Result<u16, Exception> {
+        let sp = self.regs.read16(Reg16::SP);
+        let value = self.read16(ctx, SegReg::SS, sp)?;
+        self.regs.write16(Reg16::SP, sp.wrapping_add(2));
+        Ok(value)
+    }
+
+    pub fn load_segment<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        selector: u16,
+    ) -> Result<(), Exception> {
+        if self.regs.protected_mode() {
+            self.load_protected_segment(ctx, seg, selector)?;
+        } else {
+            self.regs.writeseg16(seg, selector);
+        }
+        if seg == SegReg::SS {
+            self.interrupt_shadow = true;
+        }
+        Ok(())
+    }
+
+    /// Real mode POPF and IRET can't touch IOPL or NT. In protected mode
+    /// IOPL can only be changed at CPL 0, and IF only when CPL <= IOPL.
+    fn write_flags(&mut self, value: u16) {
+        if !self.regs.protected_mode() {
+            self.regs.write16(Reg16::FLAGS, value & 0x0fff);
+            return;
+        }
+        let mut mask = 0x7fff;
+        if self.regs.cpl() > 0 {
+            mask &= !Flags::IOPL.bits();
+        }
+        if self.regs.cpl() > self.regs.iopl() {
+            mask &= !Flags::INTERRUPT.bits();
+        }
+        let current = self.regs.read16(Reg16::FLAGS);
+        self.regs
+            .write16(Reg16::FLAGS, (value & mask) | (current & !mask & 0x7fff));
+    }
+
+    /// Transfers control through the interrupt vector table, which in real
+    /// mode is found through the IDTR just like in protected mode.
+    pub fn interrupt<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        vector: u8,
+    ) -> Result<(), Exception> {
+        self.interrupt_with_error(ctx, vector, None)
+    }
+
+    fn interrupt_with_error<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        vector: u8,
+        error_code: Option<u16>,
+    ) -> Result<(), Exception> {
+        if self.regs.protected_mode() {
+            return self.protected_interrupt(ctx, vector, error_code);
+        }
+        let entry = vector as u32 * 4;
+        if entry + 3 > self.regs.idtr.limit as u32 {
+            return Err(Exception::GeneralProtection(vector as u16 * 8 + 2));
+        }
+        let flags = self.regs.read16(Reg16::FLAGS);
+        let cs = self.regs.readseg16(SegReg::CS).selector;
+        let ip = self.regs.ip;
+        self.push(ctx, flags)?;
+        self.push(ctx, cs)?;
+        self.push(ctx, ip)?;
+        self.regs.flags.remove(Flags::INTERRUPT | Flags::TRAP);
+        let addr = self.regs.idtr.base + entry;
+        let offset = self.mem_read_word(ctx, addr);
+        let segment = self.mem_read_word(ctx, addr + 2);
+        self.load_segment(ctx, SegReg::CS, segment)?;
+        self.regs.ip = offset;
+        Ok(())
+    }
+
+    /// INT n, INT3 and INTO. In protected mode the gate's DPL must allow
+    /// the current privilege level to use it.
+    fn software_interrupt<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        vector: u8,
+    ) -> Result<(), Exception> {
+        if self.regs.protected_mode() {
+            let gate = self.read_idt_gate(ctx, vector)?;
+            if gate.dpl() < self.regs.cpl() {
+                return Err(Exception::GeneralProtection(vector as u16 * 8 + 2));
+            }
+        }
+        self.interrupt(ctx, vector)
+    }
+
+    fn read_idt_gate<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        vector: u8,
+    ) -> Result<descriptor::Descriptor, Exception> {
+        let entry = vector as u32 * 8;
+        if entry + 7 > self.regs.idtr.limit as u32 {
+            return Err(Exception::GeneralProtection(vector as u16 * 8 + 2));
+        }
+        let mut bytes = [0u8; 6];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.mem_read_byte(ctx, self.regs.idtr.base + entry + i as u32);
+        }
+        Ok(descriptor::Descriptor::from_bytes(bytes))
+    }
+
+    /// Protected mode interrupts go through an interrupt, trap or task gate
+    /// in the IDT. A handler in a more privileged non-conforming segment
+    /// runs on that level's stack, with the old SS:SP pushed first.
+    fn protected_interrupt<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        vector: u8,
+        error_code: Option<u16>,
+    ) -> Result<(), Exception> {
+        let gate_error = vector as u16 * 8 + 2;
+        let gate = self.read_idt_gate(ctx, vector)?;
+        if gate.is_segment() || !matches!(gate.system_type(), 5..=7) {
+            return Err(Exception::GeneralProtection(gate_error));
+        }
+        if !gate.present() {
+            return Err(Exception::SegmentNotPresent(gate_error));
+        }
+        if gate.system_type() == 5 {
+            self.task_switch(ctx, gate.gate_selector(), TaskSwitch::Interrupt)?;
+            if let Some(code) = error_code {
+                self.push(ctx, code)?;
+            }
+            return Ok(());
+        }
+        let target = gate.gate_selector();
+        let target_error = target & 0xfffc;
+        if target_error == 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let code = self.read_descriptor(ctx, target)?;
+        let cpl = self.regs.cpl();
+        if !code.is_code() || code.dpl() > cpl {
+            return Err(Exception::GeneralProtection(target_error));
+        }
+        if !code.present() {
+            return Err(Exception::SegmentNotPresent(target_error));
+        }
+        let flags = self.regs.read16(Reg16::FLAGS);
+        let cs = self.regs.readseg16(SegReg::CS).selector;
+        let ip = self.regs.ip;
+        let new_cpl = if !code.is_conforming() && code.dpl() < cpl {
+            let old_ss = self.regs.readseg16(SegReg::SS).selector;
+            let old_sp = self.regs.read16(Reg16::SP);
+            self.switch_to_inner_stack(ctx, code.dpl())?;
+            self.push(ctx, old_ss)?;
+            self.push(ctx, old_sp)?;
+            code.dpl()
+        } else {
+            cpl
+        };
+        self.push(ctx, flags)?;
+        self.push(ctx, cs)?;
+        self.push(ctx, ip)?;
+        if let Some(code) = error_code {
+            self.push(ctx, code)?;
+        }
+        self.enter_code_segment(ctx, target, code, new_cpl);
+        self.regs.ip = gate.gate_offset();
+        self.regs.flags.remove(Flags::TRAP | Flags::NESTED_TASK);
+        if gate.system_type() == 6 {
+            self.regs.flags.remove(Flags::INTERRUPT);
+        }
+        Ok(())
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.regs.flags.contains(Flags::INTERRUPT) && !self.interrupt_shadow
+    }
+
+    /// Delivers a hardware interrupt between instructions. The caller is
+    /// responsible for checking `interrupts_enabled` first.
+    pub fn raise_interrupt<T: Cpu286Context>(&mut self, ctx: &mut T, vector: u8) {
+        self.halted = false;
+        if let Err(exception) = self.interrupt(ctx, vector) {
+            self.deliver_exception(ctx, exception);
+        }
+    }
+
+    fn deliver_exception<T: Cpu286Context>(&mut self, ctx: &mut T, exception: Exception) {
+        trace!(target: "cpu", "Exception {:?}", exception);
+        let mut current = exception;
+        loop {
+            let fault = match self.interrupt_with_error(ctx, current.vector(), current.error_code())
+            {
+                Ok(()) => return,
+                Err(fault) => fault,
+            };
+            if current == Exception::DoubleFault {
+                error!(
+                    target: "cpu",
+                    "{:?} while delivering a double fault, shutting down",
+                    fault
+                );
+                self.shutdown = true;
+                return;
+            }
+            debug!(target: "cpu", "{:?} while delivering {:?}", fault, current);
+            current = if current.is_contributory() && fault.is_contributory() {
+                Exception::DoubleFault
+            } else {
+                fault
+            };
+        }
+    }
+
+    /// Executes one instruction and returns the clocks it took.
+    pub fn tick<T: Cpu286Context>(&mut self, ctx: &mut T) -> usize {
+        if self.halted || self.shutdown {
+            return 2;
+        }
+        self.cycles = 0;
+        self.instr_ip = self.regs.ip;
+        self.instr_sp = self.regs.read16(Reg16::SP);
+        self.instr_cs = self.regs.readseg16(SegReg::CS);
+        self.instr_ss = self.regs.readseg16(SegReg::SS);
+        self.seg_override = None;
+        self.rep_state = None;
+        self.interrupt_shadow = false;
+        let trap = self.regs.flags.contains(Flags::TRAP);
+        match self.execute(ctx) {
+            Ok(()) => {
+                if trap {
+                    self.raise_interrupt(ctx, 1);
                 }
             }
+            Err(exception) => {
+                self.regs.ip = self.instr_ip;
+                self.regs.write16(Reg16::SP, self.instr_sp);
+                self.regs.setseg(SegReg::CS, self.instr_cs);
+                self.regs.setseg(SegReg::SS, self.instr_ss);
+                self.deliver_exception(ctx, exception);
+                self.cycles += timing::INTERRUPT_CYCLES;
+            }
+        }
+        self.cycles
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        let flags = self.regs.flags;
+        let result = match (cc >> 1) & 7 {
+            0 => flags.contains(Flags::OVERFLOW),
+            1 => flags.contains(Flags::CARRY),
+            2 => flags.contains(Flags::ZERO),
+            3 => flags.contains(Flags::CARRY) || flags.contains(Flags::ZERO),
+            4 => flags.contains(Flags::SIGN),
+            5 => flags.contains(Flags::PARITY),
+            6 => flags.contains(Flags::SIGN) != flags.contains(Flags::OVERFLOW),
+            _ => {
+                flags.contains(Flags::ZERO)
+                    || (flags.contains(Flags::SIGN) != flags.contains(Flags::OVERFLOW))
+            }
+        };
+        result != ((cc & 1) != 0)
+    }
+
+    fn jump_relative(&mut self, offset: u16) {
+        self.cycles += timing::BRANCH_TAKEN_CYCLES;
+        self.regs.ip = self.regs.ip.wrapping_add(offset);
+    }
+
+    fn far_jump<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        segment: u16,
+        offset: u16,
+    ) -> Result<(), Exception> {
+        if let Some(descriptor) = self.system_target(ctx, segment)? {
+            if descriptor.system_type() == 4 {
+                return self.far_transfer_through_gate(ctx, segment, descriptor, false);
+            }
+            return self.far_transfer_to_task(ctx, segment, descriptor, TaskSwitch::Jump);
+        }
+        self.load_segment(ctx, SegReg::CS, segment)?;
+        self.regs.ip = offset;
+        Ok(())
+    }
+
+    /// Returns the descriptor when a far JMP or CALL in protected mode
+    /// targets a gate or TSS rather than a code segment.
+    fn system_target<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Result<Option<descriptor::Descriptor>, Exception> {
+        if !self.regs.protected_mode() || (selector & 0xfffc) == 0 {
+            return Ok(None);
+        }
+        let descriptor = self.read_descriptor(ctx, selector)?;
+        if descriptor.is_segment() {
+            Ok(None)
+        } else {
+            Ok(Some(descriptor))
+        }
+    }
+
+    fn far_call<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        segment: u16,
+        offset: u16,
+    ) -> Result<(), Exception> {
+        if let Some(descriptor) = self.system_target(ctx, segment)? {
+            if descriptor.system_type() == 4 {
+                return self.far_transfer_through_gate(ctx, segment, descriptor, true);
+            }
+            return self.far_transfer_to_task(ctx, segment, descriptor, TaskSwitch::Call);
+        }
+        let cs = self.regs.readseg16(SegReg::CS).selector;
+        let ip = self.regs.ip;
+        self.push(ctx, cs)?;
+        self.push(ctx, ip)?;
+        self.far_jump(ctx, segment, offset)
+    }
+
+    fn data_seg(&self) -> SegReg {
+        self.seg_override.unwrap_or(SegReg::DS)
+    }
+
+    fn string_step(&self, word: bool) -> u16 {
+        let size = if word { 2 } else { 1 };
+        if self.regs.flags.contains(Flags::DIRECTION) {
+            0u16.wrapping_sub(size)
+        } else {
+            size
+        }
+    }
+
+    fn advance_index(&mut self, reg: Reg16, word: bool) {
+        let value = self.regs.read16(reg).wrapping_add(self.string_step(word));
+        self.regs.write16(reg, value);
+    }
+
+    /// Runs one iteration of a string instruction. With a REP prefix IP is
+    /// moved back to the prefix until the count runs out, so interrupts
+    /// can be taken between iterations.
+    fn string_op<T: Cpu286Context>(&mut self, ctx: &mut T, opcode: u8) -> Result<(), Exception> {
+        if self.rep_state.is_some() && self.regs.read16(Reg16::CX) == 0 {
+            return Ok(());
+        }
+        let word = (opcode & 1) != 0;
+        let si = self.regs.read16(Reg16::SI);
+        let di = self.regs.read16(Reg16::DI);
+        let dx = self.regs.read16(Reg16::DX);
+        let src_seg = self.data_seg();
+        let compares = matches!(opcode, 0xa6 | 0xa7 | 0xae | 0xaf);
+        if opcode < 0x70 {
+            self.check_io_privilege()?;
+        }
+        match opcode {
+            0x6c => {
+                trace!(target: "cpu", "insb");
+                let value = self.io_read8(ctx, dx);
+                self.write8(ctx, SegReg::ES, di, value)?;
+                self.advance_index(Reg16::DI, word);
+            }
+            0x6d => {
+                trace!(target: "cpu", "insw");
+                let value = self.io_read16(ctx, dx);
+                self.write16(ctx, SegReg::ES, di, value)?;
+                self.advance_index(Reg16::DI, word);
+            }
+            0x6e => {
+                trace!(target: "cpu", "outsb");
+                let value = self.read8(ctx, src_seg, si)?;
+                self.io_write8(ctx, dx, value);
+                self.advance_index(Reg16::SI, word);
+            }
+            0x6f => {
+                trace!(target: "cpu", "outsw");
+                let value = self.read16(ctx, src_seg, si)?;
+                self.io_write16(ctx, dx, value);
+                self.advance_index(Reg16::SI, word);
+            }
+            0xa4 | 0xa5 => {
+                trace!(target: "cpu", "movs");
+                if word {
+                    let value = self.read16(ctx, src_seg, si)?;
+                    self.write16(ctx, SegReg::ES, di, value)?;
+                } else {
+                    let value = self.read8(ctx, src_seg, si)?;
+                    self.write8(ctx, SegReg::ES, di, value)?;
+                }
+                self.advance_index(Reg16::SI, word);
+                self.advance_index(Reg16::DI, word);
+            }
+            0xa6 | 0xa7 => {
+                trace!(target: "cpu", "cmps");
+                let (src, dst) = if word {
+                    (
+                        self.read16(ctx, src_seg, si)?,
+                        self.read16(ctx, SegReg::ES, di)?,
+                    )
+                } else {
+                    (
+                        self.read8(ctx, src_seg, si)? as u16,
+                        self.read8(ctx, SegReg::ES, di)? as u16,
+                    )
+                };
+                self.alu(AluOp::Cmp, src, dst, word);
+                self.advance_index(Reg16::SI, word);
+                self.advance_index(Reg16::DI, word);
+            }
+            0xaa | 0xab => {
+                trace!(target: "cpu", "stos");
+                if word {
+                    let ax = self.regs.read16(Reg16::AX);
+                    self.write16(ctx, SegReg::ES, di, ax)?;
+                } else {
+                    let al = self.regs.read8(Reg8::AL);
+                    self.write8(ctx, SegReg::ES, di, al)?;
+                }
+                self.advance_index(Reg16::DI, word);
+            }
+            0xac | 0xad => {
+                trace!(target: "cpu", "lods");
+                if word {
+                    let value = self.read16(ctx, src_seg, si)?;
+                    self.regs.write16(Reg16::AX, value);
+                } else {
+                    let value = self.read8(ctx, src_seg, si)?;
+                    self.regs.write8(Reg8::AL, value);
+                }
+                self.advance_index(Reg16::SI, word);
+            }
+            _ => {
+                trace!(target: "cpu", "scas");
+                let (acc, value) = if word {
+                    (
+                        self.regs.read16(Reg16::AX),
+                        self.read16(ctx, SegReg::ES, di)?,
+                    )
+                } else {
+                    (
+                        self.regs.read8(Reg8::AL) as u16,
+                        self.read8(ctx, SegReg::ES, di)? as u16,
+                    )
+                };
+                self.alu(AluOp::Cmp, acc, value, word);
+                self.advance_index(Reg16::DI, word);
+            }
+        }
+        if let Some(rep) = self.rep_state {
+            let cx = self.regs.read16(Reg16::CX).wrapping_sub(1);
+            self.regs.write16(Reg16::CX, cx);
+            let zero = self.regs.flags.contains(Flags::ZERO);
+            let done = cx == 0
+                || (compares
+                    && match rep {
+                        RepType::REPE => !zero,
+                        RepType::REPNE => zero,
+                    });
+            if !done {
+                self.regs.ip = self.instr_ip;
+            }
+        }
+        Ok(())
+    }
+
+    /// LOADALL. Loads every register, including the hidden descriptor
+    /// caches, from the table at physical address 0x800. HIMEM.SYS uses it
+    /// to reach extended memory without leaving real mode.
+    fn loadall<T: Cpu286Context>(&mut self, ctx: &mut T) -> Result<(), Exception> {
+        if self.regs.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let word =
+            |cpu: &mut Cpu286, ctx: &mut T, offset: u32| cpu.mem_read_word(ctx, 0x800 + offset);
+        // Caches are stored as a 24-bit base, the access rights byte and
+        // the limit.
+        let cache = |cpu: &mut Cpu286, ctx: &mut T, offset: u32| {
+            let mut bytes = [0u8; 6];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = cpu.mem_read_byte(ctx, 0x800 + offset + i as u32);
+            }
+            (
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]),
+                bytes[3],
+                u16::from_le_bytes([bytes[4], bytes[5]]),
+            )
+        };
+
+        let msw = word(self, ctx, 0x06);
+        self.regs.write_msw(msw);
+        let flags = word(self, ctx, 0x18);
+        self.regs.write16(Reg16::FLAGS, flags);
+        self.regs.ip = word(self, ctx, 0x1a);
+        for (i, reg) in [
+            Reg16::DI,
+            Reg16::SI,
+            Reg16::BP,
+            Reg16::SP,
+            Reg16::BX,
+            Reg16::DX,
+            Reg16::CX,
+            Reg16::AX,
+        ]
+        .iter()
+        .enumerate()
+        {
+            let value = word(self, ctx, 0x26 + i as u32 * 2);
+            self.regs.write16(*reg, value);
+        }
+        let selectors = [
+            (SegReg::DS, 0x1e, 0x48),
+            (SegReg::SS, 0x20, 0x42),
+            (SegReg::CS, 0x22, 0x3c),
+            (SegReg::ES, 0x24, 0x36),
+        ];
+        for (seg, selector_offset, cache_offset) in selectors.iter() {
+            let selector = word(self, ctx, *selector_offset);
+            let (base, rights, limit) = cache(self, ctx, *cache_offset);
+            self.regs.setseg(
+                *seg,
+                SegmentRegister {
+                    selector,
+                    base,
+                    limit,
+                    rights,
+                    valid: (rights & 0x80) != 0,
+                },
+            );
+        }
+        let (base, _, limit) = cache(self, ctx, 0x4e);
+        self.regs.gdtr = GDTRIDTR { base, limit };
+        let (base, _, limit) = cache(self, ctx, 0x5a);
+        self.regs.idtr = GDTRIDTR { base, limit };
+        let (base, rights, limit) = cache(self, ctx, 0x54);
+        self.regs.ldtr = LDTRTR {
+            selector: word(self, ctx, 0x1c),
+            base,
+            limit,
+            rights,
+        };
+        let (base, rights, limit) = cache(self, ctx, 0x60);
+        self.regs.tr = LDTRTR {
+            selector: word(self, ctx, 0x16),
+            base,
+            limit,
+            rights,
+        };
+        Ok(())
+    }
+
+    /// The two-byte opcodes the 286 added for protected mode and the
+    /// machine status word.
+    fn execute_0f<T: Cpu286Context>(&mut self, ctx: &mut T) -> Result<(), Exception> {
+        let opcode = self.fetch8(ctx)?;
+        self.cycles += timing::base_cycles_0f(opcode);
+        match opcode {
+            0x00 => {
+                if !self.regs.protected_mode() {
+                    return Err(Exception::InvalidOpcode);
+                }
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                match params.reg {
+                    0 => {
+                        trace!(target: "cpu", "sldt");
+                        let selector = self.regs.ldtr.selector;
+                        self.write_rm16(ctx, params.rm, selector)?;
+                    }
+                    1 => {
+                        trace!(target: "cpu", "str");
+                        let selector = self.regs.tr.selector;
+                        self.write_rm16(ctx, params.rm, selector)?;
+                    }
+                    2 => {
+                        trace!(target: "cpu", "lldt");
+                        if self.regs.cpl() != 0 {
+                            return Err(Exception::GeneralProtection(0));
+                        }
+                        let selector = self.read_rm16(ctx, params.rm)?;
+                        self.load_ldt(ctx, selector)?;
+                    }
+                    3 => {
+                        trace!(target: "cpu", "ltr");
+                        if self.regs.cpl() != 0 {
+                            return Err(Exception::GeneralProtection(0));
+                        }
+                        let selector = self.read_rm16(ctx, params.rm)?;
+                        self.load_task_register(ctx, selector)?;
+                    }
+                    4 | 5 => {
+                        trace!(target: "cpu", "verr/verw");
+                        let selector = self.read_rm16(ctx, params.rm)?;
+                        let ok = self.verify_segment(ctx, selector, params.reg == 5);
+                        self.regs.flags.set(Flags::ZERO, ok);
+                    }
+                    _ => return Err(Exception::InvalidOpcode),
+                }
+            }
+            0x01 => {
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                match params.reg {
+                    0 | 1 => {
+                        trace!(target: "cpu", "sgdt/sidt");
+                        let (seg, offset) = match params.rm {
+                            Operand::Address(seg, offset) => (seg, offset),
+                            Operand::Register(_) => return Err(Exception::InvalidOpcode),
+                        };
+                        let table = if params.reg == 0 {
+                            self.regs.gdtr
+                        } else {
+                            self.regs.idtr
+                        };
+                        // The 286 fills the byte above the 24-bit base with ones.
+                        let base = table.base | 0xff00_0000;
+                        self.write16(ctx, seg, offset, table.limit)?;
+                        self.write16(ctx, seg, offset.wrapping_add(2), base as u16)?;
+                        self.write16(ctx, seg, offset.wrapping_add(4), (base >> 16) as u16)?;
+                    }
+                    2 | 3 => {
+                        trace!(target: "cpu", "lgdt/lidt");
+                        if self.regs.cpl() != 0 {
+                            return Err(Exception::GeneralProtection(0));
+                        }
+                        let (seg, offset) = match params.rm {
+                            Operand::Address(seg, offset) => (seg, offset),
+                            Operand::Register(_) => return Err(Exception::InvalidOpcode),
+                        };
+                        let limit = self.read16(ctx, seg, offset)?;
+                        let low = self.read16(ctx, seg, offset.wrapping_add(2))?;
+                        let high = self.read8(ctx, seg, offset.wrapping_add(4))?;
+                        let table = GDTRIDTR {
+                            base: ((high as u32) << 16) | low as u32,
+                            limit,
+                        };
+                        if params.reg == 2 {
+                            self.regs.gdtr = table;
+                        } else {
+                            self.regs.idtr = table;
+                        }
+                    }
+                    4 => {
+                        trace!(target: "cpu", "smsw");
+                        let msw = self.regs.read_msw();
+                        self.write_rm16(ctx, params.rm, msw)?;
+                    }
+                    6 => {
+                        trace!(target: "cpu", "lmsw");
+                        if self.regs.cpl() != 0 {
+                            return Err(Exception::GeneralProtection(0));
+                        }
+                        let value = self.read_rm16(ctx, params.rm)?;
+                        self.regs.write_msw(value);
+                    }
+                    _ => return Err(Exception::InvalidOpcode),
+                }
+            }
+            0x02 | 0x03 => {
+                trace!(target: "cpu", "lar/lsl");
+                if !self.regs.protected_mode() {
+                    return Err(Exception::InvalidOpcode);
+                }
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let selector = self.read_rm16(ctx, params.rm)?;
+                let result = if opcode == 0x02 {
+                    self.load_access_rights(ctx, selector)
+                } else {
+                    self.load_segment_limit(ctx, selector)
+                };
+                self.regs.flags.set(Flags::ZERO, result.is_some());
+                if let Some(value) = result {
+                    self.regs
+                        .write16(Reg16::from_num(params.reg).unwrap(), value);
+                }
+            }
+            0x05 => {
+                trace!(target: "cpu", "loadall");
+                self.loadall(ctx)?;
+            }
+            0x06 => {
+                trace!(target: "cpu", "clts");
+                if self.regs.cpl() != 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.regs.msw.remove(Msw::TASK_SWITCHED);
+            }
+            _ => return Err(Exception::InvalidOpcode),
+        }
+        Ok(())
+    }
+
+    fn execute<T: Cpu286Context>(&mut self, ctx: &mut T) -> Result<(), Exception> {
+        let mut opcode = self.fetch8(ctx)?;
+        loop {
+            match opcode {
+                0x26 => self.seg_override = Some(SegReg::ES),
+                0x2e => self.seg_override = Some(SegReg::CS),
+                0x36 => self.seg_override = Some(SegReg::SS),
+                0x3e => self.seg_override = Some(SegReg::DS),
+                0xf0 => trace!(target: "cpu", "lock"),
+                0xf2 => self.rep_state = Some(RepType::REPNE),
+                0xf3 => self.rep_state = Some(RepType::REPE),
+                _ => break,
+            }
+            opcode = self.fetch8(ctx)?;
+        }
+        self.opcode = opcode;
+        self.cycles += timing::base_cycles(opcode);
+        match opcode {
+            0x00..=0x3f if (opcode & 7) < 6 => {
+                let op = AluOp::from_num(opcode >> 3);
+                trace!(target: "cpu", "{:?}", op);
+                let word = (opcode & 1) != 0;
+                match opcode & 7 {
+                    0..=3 => {
+                        let modrm = self.fetch8(ctx)?;
+                        let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                        let reg = Operand::Register(params.reg);
+                        let (dst, src) = if (opcode & 2) != 0 {
+                            (reg, params.rm)
+                        } else {
+                            (params.rm, reg)
+                        };
+                        if word {
+                            let a = self.read_rm16(ctx, dst)?;
+                            let b = self.read_rm16(ctx, src)?;
+                            let result = self.alu(op, a, b, true);
+                            if op != AluOp::Cmp {
+                                self.write_rm16(ctx, dst, result)?;
+                            }
+                        } else {
+                            let a = self.read_rm8(ctx, dst)? as u16;
+                            let b = self.read_rm8(ctx, src)? as u16;
+                            let result = self.alu(op, a, b, false);
+                            if op != AluOp::Cmp {
+                                self.write_rm8(ctx, dst, result as u8)?;
+                            }
+                        }
+                    }
+                    4 => {
+                        let imm = self.fetch8(ctx)? as u16;
+                        let al = self.regs.read8(Reg8::AL) as u16;
+                        let result = self.alu(op, al, imm, false);
+                        if op != AluOp::Cmp {
+                            self.regs.write8(Reg8::AL, result as u8);
+                        }
+                    }
+                    _ => {
+                        let imm = self.fetch16(ctx)?;
+                        let ax = self.regs.read16(Reg16::AX);
+                        let result = self.alu(op, ax, imm, true);
+                        if op != AluOp::Cmp {
+                            self.regs.write16(Reg16::AX, result);
+                        }
+                    }
+                }
+            }
+            0x06 | 0x0e | 0x16 | 0x1e => {
+                trace!(target: "cpu", "push sreg");
+                let seg = SegReg::from_num(opcode >> 3).unwrap();
+                let selector = self.regs.readseg16(seg).selector;
+                self.push(ctx, selector)?;
+            }
+            0x07 | 0x17 | 0x1f => {
+                trace!(target: "cpu", "pop sreg");
+                let seg = SegReg::from_num(opcode >> 3).unwrap();
+                let selector = self.pop(ctx)?;
+                self.load_segment(ctx, seg, selector)?;
+            }
+            0x27 => {
+                trace!(target: "cpu", "daa");
+                self.daa(false);
+            }
+            0x2f => {
+                trace!(target: "cpu", "das");
+                self.daa(true);
+            }
+            0x37 => {
+                trace!(target: "cpu", "aaa");
+                self.aaa(false);
+            }
+            0x3f => {
+                trace!(target: "cpu", "aas");
+                self.aaa(true);
+            }
+            0x40..=0x47 => {
+                trace!(target: "cpu", "inc r16");
+                let reg = Reg16::from_num(opcode).unwrap();
+                let value = self.inc(self.regs.read16(reg), true);
+                self.regs.write16(reg, value);
+            }
+            0x48..=0x4f => {
+                trace!(target: "cpu", "dec r16");
+                let reg = Reg16::from_num(opcode).unwrap();
+                let value = self.dec(self.regs.read16(reg), true);
+                self.regs.write16(reg, value);
+            }
+            0x50..=0x57 => {
+                trace!(target: "cpu", "push r16");
+                // PUSH SP stores the value SP had before the push.
+                let value = self.regs.read16(Reg16::from_num(opcode).unwrap());
+                self.push(ctx, value)?;
+            }
+            0x58..=0x5f => {
+                trace!(target: "cpu", "pop r16");
+                let value = self.pop(ctx)?;
+                self.regs.write16(Reg16::from_num(opcode).unwrap(), value);
+            }
+            0x60 => {
+                trace!(target: "cpu", "pusha");
+                let sp = self.regs.read16(Reg16::SP);
+                for num in 0..8 {
+                    let value = if num == 4 {
+                        sp
+                    } else {
+                        self.regs.read16(Reg16::from_num(num).unwrap())
+                    };
+                    self.push(ctx, value)?;
+                }
+            }
+            0x61 => {
+                trace!(target: "cpu", "popa");
+                for num in (0..8).rev() {
+                    let value = self.pop(ctx)?;
+                    if num != 4 {
+                        self.regs.write16(Reg16::from_num(num).unwrap(), value);
+                    }
+                }
+            }
+            0x62 => {
+                trace!(target: "cpu", "bound");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let (seg, offset) = match params.rm {
+                    Operand::Address(seg, offset) => (seg, offset),
+                    Operand::Register(_) => return Err(Exception::InvalidOpcode),
+                };
+                let lower = self.read16(ctx, seg, offset)? as i16;
+                let upper = self.read16(ctx, seg, offset.wrapping_add(2))? as i16;
+                let index = self.regs.read16(Reg16::from_num(params.reg).unwrap()) as i16;
+                if index < lower || index > upper {
+                    return Err(Exception::BoundRange);
+                }
+            }
+            0x63 => {
+                trace!(target: "cpu", "arpl");
+                if !self.regs.protected_mode() {
+                    return Err(Exception::InvalidOpcode);
+                }
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let selector = self.read_rm16(ctx, params.rm)?;
+                let rpl = self.regs.read16(Reg16::from_num(params.reg).unwrap()) & 3;
+                if (selector & 3) < rpl {
+                    self.write_rm16(ctx, params.rm, (selector & 0xfffc) | rpl)?;
+                    self.regs.flags.insert(Flags::ZERO);
+                } else {
+                    self.regs.flags.remove(Flags::ZERO);
+                }
+            }
+            0x68 => {
+                trace!(target: "cpu", "push imm16");
+                let imm = self.fetch16(ctx)?;
+                self.push(ctx, imm)?;
+            }
+            0x69 | 0x6b => {
+                trace!(target: "cpu", "imul r16, r/m16, imm");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let imm = if opcode == 0x69 {
+                    self.fetch16(ctx)?
+                } else {
+                    self.fetch8(ctx)? as i8 as u16
+                };
+                let src = self.read_rm16(ctx, params.rm)?;
+                let result = self.imul_word(src, imm);
+                self.regs
+                    .write16(Reg16::from_num(params.reg).unwrap(), result);
+            }
+            0x6a => {
+                trace!(target: "cpu", "push imm8");
+                let imm = self.fetch8(ctx)? as i8 as u16;
+                self.push(ctx, imm)?;
+            }
+            0x6c..=0x6f | 0xa4..=0xa7 | 0xaa..=0xaf => self.string_op(ctx, opcode)?,
+            0x70..=0x7f => {
+                trace!(target: "cpu", "jcc");
+                let offset = self.fetch8(ctx)? as i8 as u16;
+                if self.condition(opcode) {
+                    self.jump_relative(offset);
+                }
+            }
+            0x80..=0x83 => {
+                let word = (opcode & 1) != 0;
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let op = AluOp::from_num(params.reg);
+                trace!(target: "cpu", "{:?} r/m, imm", op);
+                if word {
+                    let dst = self.read_rm16(ctx, params.rm)?;
+                    let imm = if opcode == 0x83 {
+                        self.fetch8(ctx)? as i8 as u16
+                    } else {
+                        self.fetch16(ctx)?
+                    };
+                    let result = self.alu(op, dst, imm, true);
+                    if op != AluOp::Cmp {
+                        self.write_rm16(ctx, params.rm, result)?;
+                    }
+                } else {
+                    let dst = self.read_rm8(ctx, params.rm)? as u16;
+                    let imm = self.fetch8(ctx)? as u16;
+                    let result = self.alu(op, dst, imm, false);
+                    if op != AluOp::Cmp {
+                        self.write_rm8(ctx, params.rm, result as u8)?;
+                    }
+                }
+            }
+            0x84 | 0x85 => {
+                trace!(target: "cpu", "test r/m, reg");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let reg = Operand::Register(params.reg);
+                if opcode == 0x85 {
+                    let a = self.read_rm16(ctx, params.rm)?;
+                    let b = self.read_rm16(ctx, reg)?;
+                    self.alu(AluOp::And, a, b, true);
+                } else {
+                    let a = self.read_rm8(ctx, params.rm)? as u16;
+                    let b = self.read_rm8(ctx, reg)? as u16;
+                    self.alu(AluOp::And, a, b, false);
+                }
+            }
+            0x86 | 0x87 => {
+                trace!(target: "cpu", "xchg r/m, reg");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let reg = Operand::Register(params.reg);
+                if opcode == 0x87 {
+                    let a = self.read_rm16(ctx, params.rm)?;
+                    let b = self.read_rm16(ctx, reg)?;
+                    self.write_rm16(ctx, params.rm, b)?;
+                    self.write_rm16(ctx, reg, a)?;
+                } else {
+                    let a = self.read_rm8(ctx, params.rm)?;
+                    let b = self.read_rm8(ctx, reg)?;
+                    self.write_rm8(ctx, params.rm, b)?;
+                    self.write_rm8(ctx, reg, a)?;
+                }
+            }
+            0x88..=0x8b => {
+                trace!(target: "cpu", "mov r/m");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let reg = Operand::Register(params.reg);
+                let (dst, src) = if (opcode & 2) != 0 {
+                    (reg, params.rm)
+                } else {
+                    (params.rm, reg)
+                };
+                if (opcode & 1) != 0 {
+                    let value = self.read_rm16(ctx, src)?;
+                    self.write_rm16(ctx, dst, value)?;
+                } else {
+                    let value = self.read_rm8(ctx, src)?;
+                    self.write_rm8(ctx, dst, value)?;
+                }
+            }
+            0x8c => {
+                trace!(target: "cpu", "mov r/m16, sreg");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let seg = SegReg::from_num(params.reg).ok_or(Exception::InvalidOpcode)?;
+                let selector = self.regs.readseg16(seg).selector;
+                self.write_rm16(ctx, params.rm, selector)?;
+            }
+            0x8d => {
+                trace!(target: "cpu", "lea");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                match params.rm {
+                    Operand::Address(_, offset) => self
+                        .regs
+                        .write16(Reg16::from_num(params.reg).unwrap(), offset),
+                    Operand::Register(_) => return Err(Exception::InvalidOpcode),
+                }
+            }
+            0x8e => {
+                trace!(target: "cpu", "mov sreg, r/m16");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let seg = match SegReg::from_num(params.reg) {
+                    Some(SegReg::CS) | None => return Err(Exception::InvalidOpcode),
+                    Some(seg) => seg,
+                };
+                let selector = self.read_rm16(ctx, params.rm)?;
+                self.load_segment(ctx, seg, selector)?;
+            }
+            0x8f => {
+                trace!(target: "cpu", "pop r/m16");
+                let modrm = self.fetch8(ctx)?;
+                let value = self.pop(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                if params.reg != 0 {
+                    return Err(Exception::InvalidOpcode);
+                }
+                self.write_rm16(ctx, params.rm, value)?;
+            }
+            0x90 => trace!(target: "cpu", "nop"),
+            0x91..=0x97 => {
+                trace!(target: "cpu", "xchg ax, r16");
+                let reg = Reg16::from_num(opcode).unwrap();
+                let ax = self.regs.read16(Reg16::AX);
+                let value = self.regs.read16(reg);
+                self.regs.write16(Reg16::AX, value);
+                self.regs.write16(reg, ax);
+            }
+            0x98 => {
+                trace!(target: "cpu", "cbw");
+                let al = self.regs.read8(Reg8::AL);
+                self.regs.write16(Reg16::AX, al as i8 as u16);
+            }
+            0x99 => {
+                trace!(target: "cpu", "cwd");
+                let ax = self.regs.read16(Reg16::AX);
+                let dx = if (ax & 0x8000) != 0 { 0xffff } else { 0 };
+                self.regs.write16(Reg16::DX, dx);
+            }
+            0x9a => {
+                trace!(target: "cpu", "call far");
+                let offset = self.fetch16(ctx)?;
+                let segment = self.fetch16(ctx)?;
+                self.far_call(ctx, segment, offset)?;
+            }
+            0x9b => {
+                trace!(target: "cpu", "wait");
+                if self
+                    .regs
+                    .msw
+                    .contains(Msw::MONITOR_COPROCESSOR | Msw::TASK_SWITCHED)
+                {
+                    return Err(Exception::DeviceNotAvailable);
+                }
+            }
+            0x9c => {
+                trace!(target: "cpu", "pushf");
+                let flags = self.regs.read16(Reg16::FLAGS);
+                self.push(ctx, flags)?;
+            }
+            0x9d => {
+                trace!(target: "cpu", "popf");
+                let flags = self.pop(ctx)?;
+                self.write_flags(flags);
+            }
             0x9e => {
-                println!("sahf");
-                self.regs.flags = Flags::from_bits(
-                    (self.regs.flags.bits() & 0xff02) | (self.regs.read8(Reg8::AH) as u16),
-                )
-                .unwrap();
-                self.regs.ip = self.regs.ip.wrapping_add(1);
+                trace!(target: "cpu", "sahf");
+                let flags = (self.regs.read16(Reg16::FLAGS) & 0xff00)
+                    | (self.regs.read8(Reg8::AH) as u16 & 0xd5);
+                self.regs.write16(Reg16::FLAGS, flags);
             }
             0x9f => {
-                println!("lahf");
-                self.regs.write8(
-                    Reg8::AH,
-                    ((self.regs.flags.bits() & 0xd5) | (0x0002_u16)) as u8,
-                ); //Flags::DEFAULT
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-            }
-            0xb0 => {
-                println!("mov al, imm");
-                let imm_value = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                self.regs.write8(Reg8::AL, imm_value);
-                self.regs.ip = self.regs.ip.wrapping_add(2);
-            }
-            0xb1 => {
-                println!("mov cl, imm");
-                let imm_value = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                self.regs.write8(Reg8::CL, imm_value);
-                self.regs.ip = self.regs.ip.wrapping_add(2);
-            }
-            0xb2 => {
-                println!("mov dl, imm");
-                let imm_value = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                self.regs.write8(Reg8::DL, imm_value);
-                self.regs.ip = self.regs.ip.wrapping_add(2);
-            }
-            0xb3 => {
-                println!("mov bl, imm");
-                let imm_value = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                self.regs.write8(Reg8::BL, imm_value);
-                self.regs.ip = self.regs.ip.wrapping_add(2);
-            }
-            0xb4 => {
-                println!("mov ah, imm");
-                let imm_value = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                self.regs.write8(Reg8::AH, imm_value);
-                self.regs.ip = self.regs.ip.wrapping_add(2);
-            }
-            0xb5 => {
-                println!("mov ch, imm");
-                let imm_value = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                self.regs.write8(Reg8::CH, imm_value);
-                self.regs.ip = self.regs.ip.wrapping_add(2);
-            }
-            0xb6 => {
-                println!("mov dh, imm");
-                let imm_value = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                self.regs.write8(Reg8::DH, imm_value);
-                self.regs.ip = self.regs.ip.wrapping_add(2);
-            }
-            0xb7 => {
-                println!("mov bh, imm");
-                let imm_value = self.mem_read_byte(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                self.regs.write8(Reg8::BH, imm_value);
-                self.regs.ip = self.regs.ip.wrapping_add(2);
+                trace!(target: "cpu", "lahf");
+                let flags = self.regs.read16(Reg16::FLAGS) as u8;
+                self.regs.write8(Reg8::AH, (flags & 0xd5) | 0x02);
+            }
+            0xa0 => {
+                trace!(target: "cpu", "mov al, [imm]");
+                let offset = self.fetch16(ctx)?;
+                let value = self.read8(ctx, self.data_seg(), offset)?;
+                self.regs.write8(Reg8::AL, value);
+            }
+            0xa1 => {
+                trace!(target: "cpu", "mov ax, [imm]");
+                let offset = self.fetch16(ctx)?;
+                let value = self.read16(ctx, self.data_seg(), offset)?;
+                self.regs.write16(Reg16::AX, value);
+            }
+            0xa2 => {
+                trace!(target: "cpu", "mov [imm], al");
+                let offset = self.fetch16(ctx)?;
+                let al = self.regs.read8(Reg8::AL);
+                self.write8(ctx, self.data_seg(), offset, al)?;
+            }
+            0xa3 => {
+                trace!(target: "cpu", "mov [imm], ax");
+                let offset = self.fetch16(ctx)?;
+                let ax = self.regs.read16(Reg16::AX);
+                self.write16(ctx, self.data_seg(), offset, ax)?;
+            }
+            0xa8 => {
+                trace!(target: "cpu", "test al, imm");
+                let imm = self.fetch8(ctx)? as u16;
+                let al = self.regs.read8(Reg8::AL) as u16;
+                self.alu(AluOp::And, al, imm, false);
+            }
+            0xa9 => {
+                trace!(target: "cpu", "test ax, imm");
+                let imm = self.fetch16(ctx)?;
+                let ax = self.regs.read16(Reg16::AX);
+                self.alu(AluOp::And, ax, imm, true);
+            }
+            0xb0..=0xb7 => {
+                trace!(target: "cpu", "mov r8, imm");
+                let imm = self.fetch8(ctx)?;
+                self.regs.write8(Reg8::from_num(opcode).unwrap(), imm);
+            }
+            0xb8..=0xbf => {
+                trace!(target: "cpu", "mov r16, imm");
+                let imm = self.fetch16(ctx)?;
+                self.regs.write16(Reg16::from_num(opcode).unwrap(), imm);
+            }
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                trace!(target: "cpu", "shift/rotate");
+                let word = (opcode & 1) != 0;
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let count = match opcode {
+                    0xc0 | 0xc1 => self.fetch8(ctx)?,
+                    0xd0 | 0xd1 => 1,
+                    _ => self.regs.read8(Reg8::CL),
+                };
+                if word {
+                    let value = self.read_rm16(ctx, params.rm)?;
+                    let result = self.shift(params.reg, value, count, true);
+                    self.write_rm16(ctx, params.rm, result)?;
+                } else {
+                    let value = self.read_rm8(ctx, params.rm)? as u16;
+                    let result = self.shift(params.reg, value, count, false);
+                    self.write_rm8(ctx, params.rm, result as u8)?;
+                }
+            }
+            0xc2 | 0xc3 => {
+                trace!(target: "cpu", "ret near");
+                let release = if opcode == 0xc2 {
+                    self.fetch16(ctx)?
+                } else {
+                    0
+                };
+                self.regs.ip = self.pop(ctx)?;
+                let sp = self.regs.read16(Reg16::SP).wrapping_add(release);
+                self.regs.write16(Reg16::SP, sp);
+            }
+            0xc4 | 0xc5 => {
+                trace!(target: "cpu", "les/lds");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let (seg, offset) = match params.rm {
+                    Operand::Address(seg, offset) => (seg, offset),
+                    Operand::Register(_) => return Err(Exception::InvalidOpcode),
+                };
+                let value = self.read16(ctx, seg, offset)?;
+                let selector = self.read16(ctx, seg, offset.wrapping_add(2))?;
+                let target = if opcode == 0xc4 {
+                    SegReg::ES
+                } else {
+                    SegReg::DS
+                };
+                self.load_segment(ctx, target, selector)?;
+                self.regs
+                    .write16(Reg16::from_num(params.reg).unwrap(), value);
+            }
+            0xc6 | 0xc7 => {
+                trace!(target: "cpu", "mov r/m, imm");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                if opcode == 0xc7 {
+                    let imm = self.fetch16(ctx)?;
+                    self.write_rm16(ctx, params.rm, imm)?;
+                } else {
+                    let imm = self.fetch8(ctx)?;
+                    self.write_rm8(ctx, params.rm, imm)?;
+                }
+            }
+            0xc8 => {
+                trace!(target: "cpu", "enter");
+                let size = self.fetch16(ctx)?;
+                let level = self.fetch8(ctx)? & 0x1f;
+                let bp = self.regs.read16(Reg16::BP);
+                self.push(ctx, bp)?;
+                let frame = self.regs.read16(Reg16::SP);
+                if level > 0 {
+                    let mut bp = bp;
+                    for _ in 1..level {
+                        bp = bp.wrapping_sub(2);
+                        let value = self.read16(ctx, SegReg::SS, bp)?;
+                        self.push(ctx, value)?;
+                    }
+                    self.push(ctx, frame)?;
+                }
+                self.regs.write16(Reg16::BP, frame);
+                let sp = self.regs.read16(Reg16::SP).wrapping_sub(size);
+                self.regs.write16(Reg16::SP, sp);
+            }
+            0xc9 => {
+                trace!(target: "cpu", "leave");
+                let bp = self.regs.read16(Reg16::BP);
+                self.regs.write16(Reg16::SP, bp);
+                let value = self.pop(ctx)?;
+                self.regs.write16(Reg16::BP, value);
+            }
+            0xca | 0xcb => {
+                trace!(target: "cpu", "ret far");
+                let release = if opcode == 0xca {
+                    self.fetch16(ctx)?
+                } else {
+                    0
+                };
+                if self.regs.protected_mode() {
+                    return self.protected_far_return(ctx, release);
+                }
+                let offset = self.pop(ctx)?;
+                let segment = self.pop(ctx)?;
+                self.far_jump(ctx, segment, offset)?;
+                let sp = self.regs.read16(Reg16::SP).wrapping_add(release);
+                self.regs.write16(Reg16::SP, sp);
+            }
+            0xcc => {
+                trace!(target: "cpu", "int3");
+                self.software_interrupt(ctx, 3)?;
+            }
+            0xcd => {
+                trace!(target: "cpu", "int imm");
+                let vector = self.fetch8(ctx)?;
+                self.software_interrupt(ctx, vector)?;
+            }
+            0xce => {
+                trace!(target: "cpu", "into");
+                if self.regs.flags.contains(Flags::OVERFLOW) {
+                    self.software_interrupt(ctx, 4)?;
+                }
+            }
+            0xcf => {
+                trace!(target: "cpu", "iret");
+                if self.regs.protected_mode() {
+                    if self.regs.flags.contains(Flags::NESTED_TASK) {
+                        return self.task_return(ctx);
+                    }
+                    return self.protected_iret(ctx);
+                }
+                let offset = self.pop(ctx)?;
+                let segment = self.pop(ctx)?;
+                let flags = self.pop(ctx)?;
+                self.far_jump(ctx, segment, offset)?;
+                self.write_flags(flags);
+            }
+            0xd4 => {
+                trace!(target: "cpu", "aam");
+                let base = self.fetch8(ctx)?;
+                self.aam(base)?;
+            }
+            0xd5 => {
+                trace!(target: "cpu", "aad");
+                let base = self.fetch8(ctx)?;
+                self.aad(base);
+            }
+            0xd6 => {
+                trace!(target: "cpu", "salc");
+                let al = if self.regs.flags.contains(Flags::CARRY) {
+                    0xff
+                } else {
+                    0
+                };
+                self.regs.write8(Reg8::AL, al);
+            }
+            0xd7 => {
+                trace!(target: "cpu", "xlat");
+                let offset = self
+                    .regs
+                    .read16(Reg16::BX)
+                    .wrapping_add(self.regs.read8(Reg8::AL) as u16);
+                let value = self.read8(ctx, self.data_seg(), offset)?;
+                self.regs.write8(Reg8::AL, value);
+            }
+            0xd8..=0xdf => {
+                trace!(target: "cpu", "esc");
+                // Lets software emulate the coprocessor or save its state
+                // lazily after a task switch.
+                if self
+                    .regs
+                    .msw
+                    .intersects(Msw::EMULATE_COPROCESSOR | Msw::TASK_SWITCHED)
+                {
+                    return Err(Exception::DeviceNotAvailable);
+                }
+                let modrm = self.fetch8(ctx)?;
+                self.get_opcode_params_from_modrm(ctx, modrm)?;
+            }
+            0xe0..=0xe2 => {
+                trace!(target: "cpu", "loop");
+                let offset = self.fetch8(ctx)? as i8 as u16;
+                let cx = self.regs.read16(Reg16::CX).wrapping_sub(1);
+                self.regs.write16(Reg16::CX, cx);
+                let zero = self.regs.flags.contains(Flags::ZERO);
+                let taken = cx != 0
+                    && match opcode {
+                        0xe0 => !zero,
+                        0xe1 => zero,
+                        _ => true,
+                    };
+                if taken {
+                    self.jump_relative(offset);
+                }
+            }
+            0xe3 => {
+                trace!(target: "cpu", "jcxz");
+                let offset = self.fetch8(ctx)? as i8 as u16;
+                if self.regs.read16(Reg16::CX) == 0 {
+                    self.jump_relative(offset);
+                }
+            }
+            0xe4 | 0xe5 | 0xec | 0xed => {
+                trace!(target: "cpu", "in");
+                self.check_io_privilege()?;
+                let port = if opcode < 0xec {
+                    self.fetch8(ctx)? as u16
+                } else {
+                    self.regs.read16(Reg16::DX)
+                };
+                if (opcode & 1) != 0 {
+                    let value = self.io_read16(ctx, port);
+                    self.regs.write16(Reg16::AX, value);
+                } else {
+                    let value = self.io_read8(ctx, port);
+                    self.regs.write8(Reg8::AL, value);
+                }
+            }
+            0xe6 | 0xe7 | 0xee | 0xef => {
+                trace!(target: "cpu", "out");
+                self.check_io_privilege()?;
+                let port = if opcode < 0xee {
+                    self.fetch8(ctx)? as u16
+                } else {
+                    self.regs.read16(Reg16::DX)
+                };
+                if (opcode & 1) != 0 {
+                    self.io_write16(ctx, port, self.regs.read16(Reg16::AX));
+                } else {
+                    self.io_write8(ctx, port, self.regs.read8(Reg8::AL));
+                }
+            }
+            0xe8 => {
+                trace!(target: "cpu", "call near");
+                let offset = self.fetch16(ctx)?;
+                let ip = self.regs.ip;
+                self.push(ctx, ip)?;
+                self.jump_relative(offset);
             }
             0xe9 => {
-                println!("jmp near");
-                let offset = self.mem_read_word(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                self.regs.ip = self.regs.ip.wrapping_add(offset);
+                trace!(target: "cpu", "jmp near");
+                let offset = self.fetch16(ctx)?;
+                self.jump_relative(offset);
             }
             0xea => {
-                println!("jmp far");
-                let offset = self.mem_read_word(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(1) as u32,
-                );
-                let segment = self.mem_read_word(
-                    ctx,
-                    self.regs.readseg16(SegReg::CS).base + self.regs.ip.wrapping_add(3) as u32,
-                );
-                self.regs.writeseg16(SegReg::CS, segment);
-                self.regs.ip = offset;
+                trace!(target: "cpu", "jmp far");
+                let offset = self.fetch16(ctx)?;
+                let segment = self.fetch16(ctx)?;
+                self.far_jump(ctx, segment, offset)?;
             }
+            0xeb => {
+                trace!(target: "cpu", "jmp short");
+                let offset = self.fetch8(ctx)? as i8 as u16;
+                self.jump_relative(offset);
+            }
+            0xf4 => {
+                trace!(target: "cpu", "hlt");
+                self.halted = true;
+            }
+            0xf5 => {
+                trace!(target: "cpu", "cmc");
+                self.regs.flags.toggle(Flags::CARRY);
+            }
+            0xf6 | 0xf7 => {
+                let word = opcode == 0xf7;
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let value = if word {
+                    self.read_rm16(ctx, params.rm)?
+                } else {
+                    self.read_rm8(ctx, params.rm)? as u16
+                };
+                match params.reg {
+                    0 | 1 => {
+                        trace!(target: "cpu", "test r/m, imm");
+                        let imm = if word {
+                            self.fetch16(ctx)?
+                        } else {
+                            self.fetch8(ctx)? as u16
+                        };
+                        self.alu(AluOp::And, value, imm, word);
+                    }
+                    2 | 3 => {
+                        let result = if params.reg == 2 {
+                            trace!(target: "cpu", "not");
+                            !value
+                        } else {
+                            trace!(target: "cpu", "neg");
+                            self.alu(AluOp::Sub, 0, value, word)
+                        };
+                        if word {
+                            self.write_rm16(ctx, params.rm, result)?;
+                        } else {
+                            self.write_rm8(ctx, params.rm, result as u8)?;
+                        }
+                    }
+                    4 | 5 => {
+                        trace!(target: "cpu", "mul/imul");
+                        self.cycles += timing::multiply_divide_cycles(params.reg, word);
+                        if word {
+                            self.mul16(value, params.reg == 5);
+                        } else {
+                            self.mul8(value as u8, params.reg == 5);
+                        }
+                    }
+                    _ => {
+                        trace!(target: "cpu", "div/idiv");
+                        self.cycles += timing::multiply_divide_cycles(params.reg, word);
+                        if word {
+                            self.div16(value, params.reg == 7)?;
+                        } else {
+                            self.div8(value as u8, params.reg == 7)?;
+                        }
+                    }
+                }
+            }
+            0xf8 => self.regs.flags.remove(Flags::CARRY),
+            0xf9 => self.regs.flags.insert(Flags::CARRY),
             0xfa => {
-                println!("cli");
-                self.regs.flags.set(Flags::INTERRUPT, false);
-                self.regs.ip = self.regs.ip.wrapping_add(1);
+                trace!(target: "cpu", "cli");
+                self.check_io_privilege()?;
+                self.regs.flags.remove(Flags::INTERRUPT);
             }
             0xfb => {
-                println!("sti");
-                self.regs.flags.set(Flags::INTERRUPT, true);
-                self.regs.ip = self.regs.ip.wrapping_add(1);
+                trace!(target: "cpu", "sti");
+                self.check_io_privilege()?;
+                if !self.regs.flags.contains(Flags::INTERRUPT) {
+                    self.interrupt_shadow = true;
+                }
+                self.regs.flags.insert(Flags::INTERRUPT);
+            }
+            0xfc => self.regs.flags.remove(Flags::DIRECTION),
+            0xfd => self.regs.flags.insert(Flags::DIRECTION),
+            0xfe => {
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let value = self.read_rm8(ctx, params.rm)? as u16;
+                let result = match params.reg {
+                    0 => {
+                        trace!(target: "cpu", "inc r/m8");
+                        self.inc(value, false)
+                    }
+                    1 => {
+                        trace!(target: "cpu", "dec r/m8");
+                        self.dec(value, false)
+                    }
+                    _ => return Err(Exception::InvalidOpcode),
+                };
+                self.write_rm8(ctx, params.rm, result as u8)?;
+            }
+            0xff => {
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                match params.reg {
+                    0 | 1 => {
+                        trace!(target: "cpu", "inc/dec r/m16");
+                        let value = self.read_rm16(ctx, params.rm)?;
+                        let result = if params.reg == 0 {
+                            self.inc(value, true)
+                        } else {
+                            self.dec(value, true)
+                        };
+                        self.write_rm16(ctx, params.rm, result)?;
+                    }
+                    2 => {
+                        trace!(target: "cpu", "call r/m16");
+                        let target = self.read_rm16(ctx, params.rm)?;
+                        let ip = self.regs.ip;
+                        self.push(ctx, ip)?;
+                        self.regs.ip = target;
+                    }
+                    3 | 5 => {
+                        trace!(target: "cpu", "call/jmp m16:16");
+                        let (seg, offset) = match params.rm {
+                            Operand::Address(seg, offset) => (seg, offset),
+                            Operand::Register(_) => return Err(Exception::InvalidOpcode),
+                        };
+                        let target = self.read16(ctx, seg, offset)?;
+                        let segment = self.read16(ctx, seg, offset.wrapping_add(2))?;
+                        if params.reg == 3 {
+                            self.far_call(ctx, segment, target)?;
+                        } else {
+                            self.far_jump(ctx, segment, target)?;
+                        }
+                    }
+                    4 => {
+                        trace!(target: "cpu", "jmp r/m16");
+                        self.regs.ip = self.read_rm16(ctx, params.rm)?;
+                    }
+                    6 => {
+                        trace!(target: "cpu", "push r/m16");
+                        let value = self.read_rm16(ctx, params.rm)?;
+                        self.push(ctx, value)?;
+                    }
+                    _ => return Err(Exception::InvalidOpcode),
+                }
+            }
+            0x0f => self.execute_0f(ctx)?,
+            _ => {
+                trace!(target: "cpu", "Invalid opcode {:#02x}", opcode);
+                return Err(Exception::InvalidOpcode);
             }
-            _ => panic!("Unhandled opcode!"),
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct TestBus {
+    ram: Vec<u8>,
+}
+
+#[cfg(test)]
+impl Cpu286Context for TestBus {
+    fn mem_read_byte(&mut self, addr: u32) -> u8 {
+        self.ram[(addr & 0xf_ffff) as usize]
+    }
+    fn mem_write_byte(&mut self, addr: u32, value: u8) {
+        self.ram[(addr & 0xf_ffff) as usize] = value;
+    }
+    fn io_read_byte(&mut self, _addr: u16) -> u8 {
+        0xff
+    }
+    fn io_write_byte(&mut self, _addr: u16, _value: u8) {}
+}
+
+#[test]
+fn test_rep_movsb_and_divide_fault() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.ip = 0x100;
+    cpu.regs.write16(Reg16::SP, 0x1000);
+    bus.ram[0..4].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+    bus.ram[0x200] = 0xf4; // hlt
+    bus.ram[0x300..0x303].copy_from_slice(&[1, 2, 3]);
+    bus.ram[0x100..0x112].copy_from_slice(&[
+        0xb8, 0x34, 0x12, // mov ax, 1234h
+        0xb9, 0x03, 0x00, // mov cx, 3
+        0xbe, 0x00, 0x03, // mov si, 300h
+        0xbf, 0x00, 0x04, // mov di, 400h
+        0xf3, 0xa4, // rep movsb
+        0x31, 0xdb, // xor bx, bx
+        0xf6, 0xf3, // div bl
+    ]);
+    for _ in 0..20 {
+        if cpu.halted {
+            break;
+        }
+        cpu.tick(&mut bus);
+    }
+    assert!(cpu.halted);
+    assert_eq!(&bus.ram[0x400..0x403], &[1, 2, 3]);
+    assert_eq!(cpu.regs.read16(Reg16::CX), 0);
+    // The divide error points back at the DIV itself.
+    assert_eq!(cpu.regs.read16(Reg16::SP), 0x0ffa);
+    assert_eq!(&bus.ram[0xffa..0xffc], &[0x10, 0x01]);
+    assert_eq!(
+        cpu.read16(&mut bus, SegReg::DS, 0xffff),
+        Err(Exception::GeneralProtection(0))
+    );
+}
+
+#[test]
+fn test_descriptor_table_registers() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.ip = 0x100;
+    bus.ram[0x200..0x206].copy_from_slice(&[0xff, 0x07, 0x00, 0x10, 0x02, 0x00]);
+    bus.ram[0x100..0x10f].copy_from_slice(&[
+        0x0f, 0x01, 0x16, 0x00, 0x02, // lgdt [200h]
+        0x0f, 0x01, 0x06, 0x10, 0x02, // sgdt [210h]
+        0x0f, 0x01, 0xe0, // smsw ax
+        0x0f, 0x06, // clts
+    ]);
+    for _ in 0..4 {
+        cpu.tick(&mut bus);
+    }
+    assert_eq!(cpu.regs.gdtr.base, 0x02_1000);
+    assert_eq!(cpu.regs.gdtr.limit, 0x07ff);
+    assert_eq!(
+        &bus.ram[0x210..0x216],
+        &[0xff, 0x07, 0x00, 0x10, 0x02, 0xff]
+    );
+    assert_eq!(cpu.regs.read16(Reg16::AX), 0xfff0);
+    assert_eq!(cpu.regs.ip, 0x10f);
+}
+
+#[test]
+fn test_triple_fault_shuts_down() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.ip = 0x100;
+    cpu.regs.write16(Reg16::SP, 0x1000);
+    cpu.regs.idtr.limit = 0;
+    bus.ram[0x100..0x102].copy_from_slice(&[0xcd, 0x21]); // int 21h
+    cpu.tick(&mut bus);
+    assert!(cpu.shutdown);
+    assert_eq!(cpu.regs.ip, 0x100);
+    cpu.reset();
+    assert_eq!(cpu.regs.readseg16(SegReg::CS).base, 0xff_0000);
+    assert!(!cpu.shutdown);
+}
+
+#[test]
+fn test_loadall() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.ip = 0x100;
+    bus.ram[0x100..0x102].copy_from_slice(&[0x0f, 0x05]);
+    let words: [(usize, u16); 4] = [
+        (0x818, 0x0002), // flags
+        (0x81a, 0x0200), // ip
+        (0x822, 0x0020), // cs
+        (0x834, 0x1234), // ax
+    ];
+    for (addr, value) in words.iter() {
+        bus.ram[*addr..*addr + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    // DS points at 1 MB, CS at 0x200 with the usual rights
+    bus.ram[0x848..0x84e].copy_from_slice(&[0x00, 0x00, 0x10, 0x93, 0xff, 0xff]);
+    bus.ram[0x83c..0x842].copy_from_slice(&[0x00, 0x02, 0x00, 0x9b, 0xff, 0xff]);
+    bus.ram[0x85a..0x860].copy_from_slice(&[0x00, 0x00, 0x00, 0x00, 0xff, 0x03]);
+    cpu.tick(&mut bus);
+    assert!(!cpu.regs.protected_mode());
+    assert_eq!(cpu.regs.ip, 0x200);
+    assert_eq!(cpu.regs.read16(Reg16::AX), 0x1234);
+    assert_eq!(cpu.regs.readseg16(SegReg::CS).selector, 0x20);
+    assert_eq!(cpu.regs.readseg16(SegReg::CS).base, 0x200);
+    assert_eq!(cpu.regs.readseg16(SegReg::DS).base, 0x10_0000);
+    assert_eq!(cpu.regs.idtr.limit, 0x3ff);
+}
+
+#[test]
+fn test_string_ops_with_segment_overrides() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.writeseg16(SegReg::DS, 0);
+    cpu.regs.writeseg16(SegReg::ES, 0x100);
+    cpu.regs.writeseg16(SegReg::SS, 0x200);
+    cpu.regs.ip = 0x100;
+    bus.ram[0x10..0x12].copy_from_slice(&[1, 2]);
+    bus.ram[0x1010] = 0xaa;
+    bus.ram[0x2010..0x2012].copy_from_slice(&[5, 6]);
+    bus.ram[0x100..0x119].copy_from_slice(&[
+        0xbe, 0x10, 0x00, // mov si, 10h
+        0x26, 0xac, // es: lodsb
+        0xbe, 0x10, 0x00, // mov si, 10h
+        0xbf, 0x20, 0x00, // mov di, 20h
+        0xb9, 0x02, 0x00, // mov cx, 2
+        0xf3, 0x36, 0xa4, // rep ss: movsb
+        0xfd, // std
+        0xbe, 0x11, 0x00, // mov si, 11h
+        0xbf, 0x31, 0x00, // mov di, 31h
+        0xa5, // movsw
+    ]);
+    while cpu.regs.ip < 0x119 {
+        cpu.tick(&mut bus);
+    }
+    assert_eq!(cpu.regs.read8(Reg8::AL), 0xaa);
+    // The override only moves the source; the destination is always ES.
+    assert_eq!(&bus.ram[0x1020..0x1022], &[5, 6]);
+    assert_eq!(&bus.ram[0x2020..0x2022], &[0, 0]);
+    assert_eq!(cpu.regs.read16(Reg16::CX), 0);
+    // Going down, a word still reads upwards from SI.
+    assert_eq!(&bus.ram[0x1031..0x1033], &[2, 0]);
+    assert_eq!(cpu.regs.read16(Reg16::SI), 0x0f);
+    assert_eq!(cpu.regs.read16(Reg16::DI), 0x2f);
+}
+
+#[test]
+fn test_enter_leave_and_bound() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.ip = 0x100;
+    cpu.regs.write16(Reg16::SP, 0x1000);
+    cpu.regs.write16(Reg16::BP, 0x0800);
+    bus.ram[0x7fe..0x800].copy_from_slice(&[0xef, 0xbe]);
+    bus.ram[0x14..0x18].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+    bus.ram[0x200] = 0xf4; // hlt
+    bus.ram[0x300..0x304].copy_from_slice(&[0x10, 0x00, 0x20, 0x00]);
+    bus.ram[0x100..0x113].copy_from_slice(&[
+        0xc8, 0x08, 0x00, 0x02, // enter 8, 2
+        0xc9, // leave
+        0xb8, 0x15, 0x00, // mov ax, 15h
+        0x62, 0x06, 0x00, 0x03, // bound ax, [300h]
+        0xb8, 0x21, 0x00, // mov ax, 21h
+        0x62, 0x06, 0x00, 0x03, // bound ax, [300h]
+    ]);
+
+    // The old BP, the outer frame's pointer copied from below it, then the
+    // new frame's own.
+    cpu.tick(&mut bus);
+    assert_eq!(cpu.regs.read16(Reg16::BP), 0x0ffe);
+    assert_eq!(cpu.regs.read16(Reg16::SP), 0x0ff2);
+    assert_eq!(
+        &bus.ram[0xffa..0x1000],
+        &[0xfe, 0x0f, 0xef, 0xbe, 0x00, 0x08]
+    );
+    cpu.tick(&mut bus);
+    assert_eq!(cpu.regs.read16(Reg16::BP), 0x0800);
+    assert_eq!(cpu.regs.read16(Reg16::SP), 0x1000);
+
+    for _ in 0..10 {
+        if cpu.halted {
+            break;
+        }
+        cpu.tick(&mut bus);
     }
+    assert!(cpu.halted);
+    // Only the second BOUND faults, and it points back at itself.
+    assert_eq!(cpu.regs.read16(Reg16::SP), 0x0ffa);
+    assert_eq!(&bus.ram[0xffa..0xffc], &[0x0f, 0x01]);
 }
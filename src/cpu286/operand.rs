@@ -1,6 +1,8 @@
 use crate::cpu286::registers::*;
+use crate::cpu286::timing;
 use crate::cpu286::Cpu286;
 use crate::cpu286::Cpu286Context;
+use crate::cpu286::Exception;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum AddrType {
@@ -20,16 +22,13 @@ pub enum DisplacementType {
     Word,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Operand {
     Register(u8),
     Address(SegReg, u16),
 }
 
-pub type ByteOperand = Operand;
-pub type WordOperand = Operand;
-
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub struct OpcodeParams {
     pub reg: u8,
     pub rm: Operand,
@@ -38,8 +37,7 @@ pub struct OpcodeParams {
 impl Cpu286 {
     pub fn get_addr_type_from_modrm(modrm: u8) -> Option<AddrType> {
         let mode = (modrm & 0xc0) >> 6;
-        let rm = modrm & 7;
-        match rm {
+        match modrm & 7 {
             0 => Some(AddrType::BxSi),
             1 => Some(AddrType::BxDi),
             2 => Some(AddrType::BpSi),
@@ -53,16 +51,14 @@ impl Cpu286 {
                     Some(AddrType::Bp)
                 }
             }
-            7 => Some(AddrType::Bx),
-            _ => panic!("Invalid address type!"),
+            _ => Some(AddrType::Bx),
         }
     }
+
     pub fn get_disp_type_from_modrm(modrm: u8) -> Option<DisplacementType> {
-        let mode = (modrm & 0xc0) >> 6;
-        let rm = modrm & 7;
-        match mode {
+        match (modrm & 0xc0) >> 6 {
             0 => {
-                if rm == 6 {
+                if modrm & 7 == 6 {
                     Some(DisplacementType::Word)
                 } else {
                     None
@@ -70,39 +66,34 @@ impl Cpu286 {
             }
             1 => Some(DisplacementType::Byte),
             2 => Some(DisplacementType::Word),
-            _ => panic!("Invalid displacement type!"),
+            _ => None,
         }
     }
+
     pub fn get_offset(&self, addr_type: AddrType, offset: u16) -> u16 {
+        let bx = self.regs.read16(Reg16::BX);
+        let bp = self.regs.read16(Reg16::BP);
+        let si = self.regs.read16(Reg16::SI);
+        let di = self.regs.read16(Reg16::DI);
         let base = match addr_type {
-            AddrType::BxSi => self.regs.read16(Reg16::BX) + self.regs.read16(Reg16::SI),
-            AddrType::BxDi => self.regs.read16(Reg16::BX) + self.regs.read16(Reg16::DI),
-            AddrType::BpSi => self.regs.read16(Reg16::BP) + self.regs.read16(Reg16::SI),
-            AddrType::BpDi => self.regs.read16(Reg16::BP) + self.regs.read16(Reg16::DI),
-            AddrType::Si => self.regs.read16(Reg16::SI),
-            AddrType::Di => self.regs.read16(Reg16::DI),
-            AddrType::Bp => self.regs.read16(Reg16::BP),
-            AddrType::Bx => self.regs.read16(Reg16::BX),
+            AddrType::BxSi => bx.wrapping_add(si),
+            AddrType::BxDi => bx.wrapping_add(di),
+            AddrType::BpSi => bp.wrapping_add(si),
+            AddrType::BpDi => bp.wrapping_add(di),
+            AddrType::Si => si,
+            AddrType::Di => di,
+            AddrType::Bp => bp,
+            AddrType::Bx => bx,
         };
-        base + offset
+        base.wrapping_add(offset)
     }
-    pub fn get_operand_seg(
-        &self,
-        addr_type: Option<AddrType>,
-        disp_type: Option<DisplacementType>,
-    ) -> SegReg {
+
+    /// Every form that uses BP as a base defaults to the stack segment.
+    pub fn get_operand_seg(&self, addr_type: Option<AddrType>) -> SegReg {
         match self.seg_override {
             Some(segment) => segment,
             None => match addr_type {
-                Some(AddrType::BpSi) => SegReg::SS,
-                Some(AddrType::BpDi) => SegReg::SS,
-                Some(AddrType::Bp) => {
-                    if disp_type == Some(DisplacementType::Word) {
-                        SegReg::SS
-                    } else {
-                        SegReg::DS
-                    }
-                }
+                Some(AddrType::BpSi) | Some(AddrType::BpDi) | Some(AddrType::Bp) => SegReg::SS,
                 _ => SegReg::DS,
             },
         }
@@ -112,107 +103,134 @@ impl Cpu286 {
         &mut self,
         ctx: &mut T,
         modrm: u8,
-    ) -> OpcodeParams {
-        let mode = (modrm & 0xc0) >> 6;
+    ) -> Result<OpcodeParams, Exception> {
         let reg = (modrm & 0x38) >> 3;
-        let rm = modrm & 7;
+        if modrm >= 0xc0 {
+            return Ok(OpcodeParams {
+                reg,
+                rm: Operand::Register(modrm & 7),
+            });
+        }
+        self.cycles += timing::MEMORY_OPERAND_CYCLES;
+        let addr_type = Cpu286::get_addr_type_from_modrm(modrm);
+        let displacement = match Cpu286::get_disp_type_from_modrm(modrm) {
+            None => 0,
+            Some(DisplacementType::Byte) => self.fetch8(ctx)? as i8 as u16,
+            Some(DisplacementType::Word) => self.fetch16(ctx)?,
+        };
+        let addr = match addr_type {
+            None => displacement,
+            Some(addr_type) => self.get_offset(addr_type, displacement),
+        };
+        Ok(OpcodeParams {
+            reg,
+            rm: Operand::Address(self.get_operand_seg(addr_type), addr),
+        })
+    }
 
-        match mode {
-            0 => {
-                let addr_type = Cpu286::get_addr_type_from_modrm(modrm);
-                let disp_type = Cpu286::get_disp_type_from_modrm(modrm);
-                let addr: u16;
-                let displacement: u16;
-                let segment: SegReg = self.get_operand_seg(addr_type, disp_type);
-                match disp_type {
-                    None => {
-                        displacement = 0;
-                    }
-                    Some(DisplacementType::Byte) => {
-                        displacement =
-                            self.mem_read_byte(ctx, self.regs.readseg16(SegReg::CS), self.regs.ip)
-                                as u16;
-                        self.regs.ip = self.regs.ip.wrapping_add(1);
-                    }
-                    Some(DisplacementType::Word) => {
-                        displacement =
-                            self.mem_read_word(ctx, self.regs.readseg16(SegReg::CS), self.regs.ip);
-                        self.regs.ip = self.regs.ip.wrapping_add(2);
-                    }
-                }
-                match addr_type {
-                    None => {
-                        addr = displacement;
-                    }
-                    Some(_) => {
-                        addr = self.get_offset(addr_type.unwrap(), displacement);
-                    }
-                }
-                let operand_rm = Operand::Address(segment, addr);
-                let operand_reg = reg;
-                OpcodeParams {
-                    reg: operand_reg,
-                    rm: operand_rm,
-                }
-            }
-            1 => {
-                let addr_type = Cpu286::get_addr_type_from_modrm(modrm);
-                let addr: u16;
-                let displacement: u16 =
-                    self.mem_read_byte(ctx, self.regs.readseg16(SegReg::CS), self.regs.ip) as u16;
-                let segment: SegReg = self.get_operand_seg(addr_type, Some(DisplacementType::Byte));
-                self.regs.ip = self.regs.ip.wrapping_add(1);
-                match addr_type {
-                    None => panic!("Invalid address type for this ModR/M type!"),
-                    Some(_) => {
-                        addr = self.get_offset(addr_type.unwrap(), displacement);
-                    }
-                }
-                let operand_rm = Operand::Address(segment, addr);
-                let operand_reg = reg;
-                OpcodeParams {
-                    reg: operand_reg,
-                    rm: operand_rm,
-                }
-            }
-            2 => {
-                let addr_type = Cpu286::get_addr_type_from_modrm(modrm);
-                let addr: u16;
-                let displacement: u16 =
-                    self.mem_read_word(ctx, self.regs.readseg16(SegReg::CS), self.regs.ip);
-                let segment: SegReg = self.get_operand_seg(addr_type, Some(DisplacementType::Byte));
-                self.regs.ip = self.regs.ip.wrapping_add(2);
-                match addr_type {
-                    None => panic!("Invalid address type for this ModR/M type!"),
-                    Some(_) => {
-                        addr = self.get_offset(addr_type.unwrap(), displacement);
-                    }
-                }
-                let operand_rm = Operand::Address(segment, addr);
-                let operand_reg = reg;
-                OpcodeParams {
-                    reg: operand_reg,
-                    rm: operand_rm,
-                }
+    pub fn read_rm8<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        operand: Operand,
+    ) -> Result<u8, Exception> {
+        match operand {
+            Operand::Register(reg) => Ok(self.regs.read8(Reg8::from_num(reg).unwrap())),
+            Operand::Address(seg, offset) => self.read8(ctx, seg, offset),
+        }
+    }
+
+    pub fn write_rm8<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        operand: Operand,
+        value: u8,
+    ) -> Result<(), Exception> {
+        match operand {
+            Operand::Register(reg) => {
+                self.regs.write8(Reg8::from_num(reg).unwrap(), value);
+                Ok(())
             }
-            3 => {
-                let operand_rm = Operand::Register(rm);
-                let operand_reg = reg;
-                OpcodeParams {
-                    reg: operand_reg,
-                    rm: operand_rm,
-                }
+            Operand::Address(seg, offset) => self.write8(ctx, seg, offset, value),
+        }
+    }
+
+    pub fn read_rm16<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        operand: Operand,
+    ) -> Result<u16, Exception> {
+        match operand {
+            Operand::Register(reg) => Ok(self.regs.read16(Reg16::from_num(reg).unwrap())),
+            Operand::Address(seg, offset) => self.read16(ctx, seg, offset),
+        }
+    }
+
+    pub fn write_rm16<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        operand: Operand,
+        value: u16,
+    ) -> Result<(), Exception> {
+        match operand {
+            Operand::Register(reg) => {
+                self.regs.write16(Reg16::from_num(reg).unwrap(), value);
+                Ok(())
             }
-            _ => panic!("Unimplemented ModR/M mode!"),
+            Operand::Address(seg, offset) => self.write16(ctx, seg, offset, value),
         }
     }
 }
 
+#[cfg(test)]
+use crate::cpu286::TestBus;
+
 #[test]
-fn test_modrm()
-{
-    let mut machine = IbmPc5150Machine::new();
+fn test_modrm() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.write16(Reg16::BX, 0xff00);
+    cpu.regs.write16(Reg16::BP, 0x2000);
+    cpu.regs.write16(Reg16::SI, 0x0130);
+    cpu.regs.write16(Reg16::DI, 0x0004);
+    // Read as a displacement, 80h is -80h and 1280h is itself.
+    bus.ram[0x100..0x102].copy_from_slice(&[0x80, 0x12]);
+    let bases = [
+        0xff00u16.wrapping_add(0x0130),
+        0xff04,
+        0x2130,
+        0x2004,
+        0x0130,
+        0x0004,
+        0x2000,
+        0xff00,
+    ];
     for modrm in 0..=0xffu8 {
-        machine.cpu.get_opcode_params_from_modrm(&mut machine.hardware, modrm);
+        cpu.regs.ip = 0x100;
+        let params = cpu.get_opcode_params_from_modrm(&mut bus, modrm).unwrap();
+        assert_eq!(params.reg, (modrm >> 3) & 7);
+        let rm = (modrm & 7) as usize;
+        let seg = if rm == 2 || rm == 3 || rm == 6 {
+            SegReg::SS
+        } else {
+            SegReg::DS
+        };
+        let (operand, length) = match modrm >> 6 {
+            0 if rm == 6 => (Operand::Address(SegReg::DS, 0x1280), 2),
+            0 => (Operand::Address(seg, bases[rm]), 0),
+            1 => (Operand::Address(seg, bases[rm].wrapping_sub(0x80)), 1),
+            2 => (Operand::Address(seg, bases[rm].wrapping_add(0x1280)), 2),
+            _ => (Operand::Register(modrm & 7), 0),
+        };
+        assert_eq!(params.rm, operand, "modrm {:02x}", modrm);
+        assert_eq!(cpu.regs.ip, 0x100 + length, "modrm {:02x}", modrm);
     }
+
+    // An override beats the stack segment BP brings with it.
+    cpu.regs.ip = 0x100;
+    cpu.seg_override = Some(SegReg::ES);
+    let params = cpu.get_opcode_params_from_modrm(&mut bus, 0x46).unwrap();
+    assert_eq!(params.rm, Operand::Address(SegReg::ES, 0x1f80));
 }
@@ -0,0 +1,347 @@
+use crate::cpu286::descriptor::Descriptor;
+use crate::cpu286::registers::*;
+use crate::cpu286::Cpu286;
+use crate::cpu286::Cpu286Context;
+use crate::cpu286::Exception;
+use log::trace;
+
+const CALL_GATE: u8 = 4;
+
+impl Cpu286 {
+    /// IN, OUT, the string I/O instructions, CLI and STI need CPL <= IOPL
+    /// in protected mode.
+    pub fn check_io_privilege(&self) -> Result<(), Exception> {
+        if self.regs.protected_mode() && self.regs.cpl() > self.regs.iopl() {
+            return Err(Exception::GeneralProtection(0));
+        }
+        Ok(())
+    }
+
+    /// Puts `selector` in CS with its RPL replaced by the new CPL.
+    pub fn enter_code_segment<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+        code: Descriptor,
+        cpl: u16,
+    ) {
+        self.set_accessed(ctx, selector);
+        self.regs
+            .setseg(SegReg::CS, code.to_cache((selector & 0xfffc) | cpl));
+    }
+
+    /// Checks a stack segment for use at privilege level `cpl`. Callers map
+    /// the general protection fault to whatever their instruction raises.
+    fn check_stack_segment<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+        cpl: u16,
+    ) -> Result<Descriptor, Exception> {
+        let error_code = selector & 0xfffc;
+        if error_code == 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let stack = self.read_descriptor(ctx, selector)?;
+        if (selector & 3) != cpl || stack.dpl() != cpl || !stack.is_writable() {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        if !stack.present() {
+            return Err(Exception::StackFault(error_code));
+        }
+        Ok(stack)
+    }
+
+    /// Loads SS:SP for privilege level `dpl` from the current TSS.
+    pub fn switch_to_inner_stack<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        dpl: u16,
+    ) -> Result<(), Exception> {
+        let offset = 2 + dpl as u32 * 4;
+        if offset + 3 > self.regs.tr.limit as u32 {
+            return Err(Exception::InvalidTss(self.regs.tr.selector & 0xfffc));
+        }
+        let sp = self.mem_read_word(ctx, self.regs.tr.base + offset);
+        let ss = self.mem_read_word(ctx, self.regs.tr.base + offset + 2);
+        let stack = self
+            .check_stack_segment(ctx, ss, dpl)
+            .map_err(|fault| match fault {
+                Exception::StackFault(code) => Exception::StackFault(code),
+                _ => Exception::InvalidTss(ss & 0xfffc),
+            })?;
+        trace!(target: "cpu", "Stack switch to {:04x}:{:04x}", ss, sp);
+        self.set_accessed(ctx, ss);
+        self.regs.setseg(SegReg::SS, stack.to_cache(ss));
+        self.regs.write16(Reg16::SP, sp);
+        Ok(())
+    }
+
+    /// Checks the code segment a RET or IRET returns to. Returning to a
+    /// more privileged level is never allowed.
+    fn check_return_segment<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Result<Descriptor, Exception> {
+        let error_code = selector & 0xfffc;
+        let rpl = selector & 3;
+        if error_code == 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        if rpl < self.regs.cpl() {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        let code = self.read_descriptor(ctx, selector)?;
+        let allowed = if code.is_conforming() {
+            code.dpl() <= rpl
+        } else {
+            code.dpl() == rpl
+        };
+        if !code.is_code() || !allowed {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        if !code.present() {
+            return Err(Exception::SegmentNotPresent(error_code));
+        }
+        Ok(code)
+    }
+
+    /// After returning to an outer level, DS and ES can't keep pointing at
+    /// segments only the inner level could load. Those become null.
+    fn invalidate_data_segments(&mut self) {
+        let cpl = self.regs.cpl();
+        for seg in [SegReg::ES, SegReg::DS].iter() {
+            let cache = self.regs.readseg16(*seg);
+            let segment = Descriptor {
+                limit: cache.limit,
+                base: cache.base,
+                rights: cache.rights,
+            };
+            if cache.valid && !segment.is_conforming() && segment.dpl() < cpl {
+                self.regs.setseg(
+                    *seg,
+                    SegmentRegister {
+                        selector: 0,
+                        valid: false,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+    }
+
+    /// Far CALL or JMP through a call gate. A CALL to a more privileged
+    /// non-conforming segment switches to that level's stack from the TSS
+    /// and copies the gate's parameter words across.
+    pub fn far_transfer_through_gate<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+        gate: Descriptor,
+        call: bool,
+    ) -> Result<(), Exception> {
+        let error_code = selector & 0xfffc;
+        let cpl = self.regs.cpl();
+        if gate.system_type() != CALL_GATE || gate.dpl() < cpl.max(selector & 3) {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        if !gate.present() {
+            return Err(Exception::SegmentNotPresent(error_code));
+        }
+        let target = gate.gate_selector();
+        let target_error = target & 0xfffc;
+        if target_error == 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let code = self.read_descriptor(ctx, target)?;
+        if !code.is_code() || code.dpl() > cpl {
+            return Err(Exception::GeneralProtection(target_error));
+        }
+        if !code.present() {
+            return Err(Exception::SegmentNotPresent(target_error));
+        }
+        let cs = self.regs.readseg16(SegReg::CS).selector;
+        let ip = self.regs.ip;
+        if !code.is_conforming() && code.dpl() < cpl {
+            if !call {
+                return Err(Exception::GeneralProtection(target_error));
+            }
+            let new_cpl = code.dpl();
+            let old_ss = self.regs.readseg16(SegReg::SS).selector;
+            let old_sp = self.regs.read16(Reg16::SP);
+            let count = gate.gate_word_count();
+            let mut params = Vec::with_capacity(count as usize);
+            for i in 0..count {
+                params.push(self.read16(ctx, SegReg::SS, old_sp.wrapping_add(i * 2))?);
+            }
+            self.switch_to_inner_stack(ctx, new_cpl)?;
+            self.push(ctx, old_ss)?;
+            self.push(ctx, old_sp)?;
+            for value in params.iter().rev() {
+                self.push(ctx, *value)?;
+            }
+            self.push(ctx, cs)?;
+            self.push(ctx, ip)?;
+            self.enter_code_segment(ctx, target, code, new_cpl);
+        } else {
+            if !code.is_conforming() && code.dpl() != cpl {
+                return Err(Exception::GeneralProtection(target_error));
+            }
+            if call {
+                self.push(ctx, cs)?;
+                self.push(ctx, ip)?;
+            }
+            self.enter_code_segment(ctx, target, code, cpl);
+        }
+        self.regs.ip = gate.gate_offset();
+        Ok(())
+    }
+
+    /// Protected mode far RET, releasing `release` bytes of parameters from
+    /// both stacks when it returns to an outer level.
+    pub fn protected_far_return<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        release: u16,
+    ) -> Result<(), Exception> {
+        let ip = self.pop(ctx)?;
+        let cs = self.pop(ctx)?;
+        let code = self.check_return_segment(ctx, cs)?;
+        let sp = self.regs.read16(Reg16::SP).wrapping_add(release);
+        self.regs.write16(Reg16::SP, sp);
+        let rpl = cs & 3;
+        if rpl == self.regs.cpl() {
+            self.enter_code_segment(ctx, cs, code, rpl);
+        } else {
+            let new_sp = self.pop(ctx)?;
+            let new_ss = self.pop(ctx)?;
+            self.return_to_outer_level(ctx, cs, code, new_ss, new_sp.wrapping_add(release))?;
+        }
+        self.regs.ip = ip;
+        Ok(())
+    }
+
+    /// Protected mode IRET within a task. IOPL is only restored at CPL 0,
+    /// and IF only when CPL <= IOPL.
+    pub fn protected_iret<T: Cpu286Context>(&mut self, ctx: &mut T) -> Result<(), Exception> {
+        let ip = self.pop(ctx)?;
+        let cs = self.pop(ctx)?;
+        let flags = self.pop(ctx)?;
+        let code = self.check_return_segment(ctx, cs)?;
+        let rpl = cs & 3;
+        if rpl == self.regs.cpl() {
+            self.enter_code_segment(ctx, cs, code, rpl);
+            self.write_flags(flags);
+        } else {
+            let new_sp = self.pop(ctx)?;
+            let new_ss = self.pop(ctx)?;
+            // Which flags may change depends on the level IRET runs at.
+            let old_flags = self.regs.flags;
+            self.write_flags(flags);
+            if let Err(fault) = self.return_to_outer_level(ctx, cs, code, new_ss, new_sp) {
+                self.regs.flags = old_flags;
+                return Err(fault);
+            }
+        }
+        self.regs.ip = ip;
+        Ok(())
+    }
+
+    fn return_to_outer_level<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        cs: u16,
+        code: Descriptor,
+        ss: u16,
+        sp: u16,
+    ) -> Result<(), Exception> {
+        let rpl = cs & 3;
+        let stack = self.check_stack_segment(ctx, ss, rpl)?;
+        trace!(target: "cpu", "Return to privilege level {}", rpl);
+        self.enter_code_segment(ctx, cs, code, rpl);
+        self.set_accessed(ctx, ss);
+        self.regs.setseg(SegReg::SS, stack.to_cache(ss));
+        self.regs.write16(Reg16::SP, sp);
+        self.invalidate_data_segments();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_call_gate_to_inner_level_and_back() {
+    let mut bus = crate::cpu286::TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.ip = 0x100;
+    cpu.regs.gdtr = GDTRIDTR {
+        base: 0x800,
+        limit: 0x37,
+    };
+    // ring 0 code and data, a TSS, ring 3 code and data, and a call gate
+    // to 0008:0300 that ring 3 may use, copying one parameter word
+    bus.ram[0x808..0x80e].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0x9a]);
+    bus.ram[0x810..0x816].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0x92]);
+    bus.ram[0x818..0x81e].copy_from_slice(&[0x2b, 0, 0x00, 0x10, 0, 0x81]);
+    bus.ram[0x820..0x826].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0xfa]);
+    bus.ram[0x828..0x82e].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0xf2]);
+    bus.ram[0x830..0x836].copy_from_slice(&[0x00, 0x03, 0x08, 0x00, 0x01, 0xe4]);
+    // SS0:SP0 in the TSS
+    bus.ram[0x1002..0x1006].copy_from_slice(&[0x00, 0x08, 0x10, 0x00]);
+    bus.ram[0x100..0x10b].copy_from_slice(&[
+        0xb8, 0x01, 0x00, // mov ax, 1
+        0x0f, 0x01, 0xf0, // lmsw ax
+        0xea, 0x10, 0x01, 0x08, 0x00, // jmp 0008:0110
+    ]);
+    bus.ram[0x110..0x129].copy_from_slice(&[
+        0xb8, 0x10, 0x00, // mov ax, 0x10
+        0x8e, 0xd0, // mov ss, ax
+        0xbc, 0x00, 0x09, // mov sp, 0x900
+        0xb8, 0x18, 0x00, // mov ax, 0x18
+        0x0f, 0x00, 0xd8, // ltr ax
+        0x6a, 0x2b, // push 0x2b
+        0x68, 0x00, 0x06, // push 0x600
+        0x6a, 0x23, // push 0x23
+        0x68, 0x30, 0x01, // push 0x130
+        0xcb, // retf
+    ]);
+    bus.ram[0x130..0x138].copy_from_slice(&[
+        0x68, 0x34, 0x12, // push 0x1234
+        0x9a, 0x00, 0x00, 0x33, 0x00, // call 0033:0000
+    ]);
+    bus.ram[0x300..0x308].copy_from_slice(&[
+        0x89, 0xe5, // mov bp, sp
+        0x8b, 0x46, 0x04, // mov ax, [bp+4]
+        0xca, 0x02, 0x00, // retf 2
+    ]);
+
+    for _ in 0..13 {
+        cpu.tick(&mut bus);
+    }
+    assert_eq!(cpu.regs.cpl(), 3);
+    assert_eq!(cpu.regs.ip, 0x130);
+    assert_eq!(cpu.regs.readseg16(SegReg::SS).selector, 0x2b);
+    assert!(!cpu.regs.readseg16(SegReg::DS).valid);
+    assert_eq!(
+        cpu.check_io_privilege(),
+        Err(Exception::GeneralProtection(0))
+    );
+
+    cpu.tick(&mut bus);
+    cpu.tick(&mut bus);
+    assert_eq!(cpu.regs.cpl(), 0);
+    assert_eq!(cpu.regs.ip, 0x300);
+    assert_eq!(cpu.regs.readseg16(SegReg::SS).selector, 0x10);
+    assert_eq!(cpu.regs.read16(Reg16::SP), 0x7f6);
+
+    for _ in 0..3 {
+        cpu.tick(&mut bus);
+    }
+    assert_eq!(cpu.regs.read16(Reg16::AX), 0x1234);
+    assert_eq!(cpu.regs.cpl(), 3);
+    assert_eq!(cpu.regs.ip, 0x138);
+    assert_eq!(cpu.regs.readseg16(SegReg::SS).selector, 0x2b);
+    assert_eq!(cpu.regs.read16(Reg16::SP), 0x600);
+}
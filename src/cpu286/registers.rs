@@ -1,5 +1,4 @@
 use bitflags::bitflags;
-#[allow(dead_code)]
 
 bitflags!(
     pub struct Flags: u16
@@ -19,6 +18,24 @@ bitflags!(
     }
 );
 
+bitflags!(
+    /// The machine status word. The other 12 bits are reserved and read
+    /// as ones.
+    pub struct Msw: u16
+    {
+        const PROTECTION_ENABLE = 0x0001;
+        const MONITOR_COPROCESSOR = 0x0002;
+        const EMULATE_COPROCESSOR = 0x0004;
+        const TASK_SWITCHED = 0x0008;
+    }
+);
+
+impl Default for Msw {
+    fn default() -> Msw {
+        Msw::empty()
+    }
+}
+
 impl Default for Flags {
     fn default() -> Flags {
         Flags::DEFAULT
@@ -37,6 +54,22 @@ pub enum Reg8 {
     BH,
 }
 
+impl Reg8 {
+    pub fn from_num(num: u8) -> Option<Reg8> {
+        match num & 7 {
+            0 => Some(Reg8::AL),
+            1 => Some(Reg8::CL),
+            2 => Some(Reg8::DL),
+            3 => Some(Reg8::BL),
+            4 => Some(Reg8::AH),
+            5 => Some(Reg8::CH),
+            6 => Some(Reg8::DH),
+            7 => Some(Reg8::BH),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Reg16 {
     AX,
@@ -50,6 +83,22 @@ pub enum Reg16 {
     FLAGS,
 }
 
+impl Reg16 {
+    pub fn from_num(num: u8) -> Option<Reg16> {
+        match num & 7 {
+            0 => Some(Reg16::AX),
+            1 => Some(Reg16::CX),
+            2 => Some(Reg16::DX),
+            3 => Some(Reg16::BX),
+            4 => Some(Reg16::SP),
+            5 => Some(Reg16::BP),
+            6 => Some(Reg16::SI),
+            7 => Some(Reg16::DI),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SegReg {
     ES,
@@ -58,6 +107,18 @@ pub enum SegReg {
     DS,
 }
 
+impl SegReg {
+    pub fn from_num(num: u8) -> Option<SegReg> {
+        match num {
+            0 => Some(SegReg::ES),
+            1 => Some(SegReg::CS),
+            2 => Some(SegReg::SS),
+            3 => Some(SegReg::DS),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum TableReg {
     GDTR,
@@ -107,7 +168,11 @@ pub struct Registers {
     pub gprs: [u16; 8],
     pub seg_regs: [SegmentRegister; 4],
     pub flags: Flags,
-    pub msw: u16,
+    pub msw: Msw,
+    pub gdtr: GDTRIDTR,
+    pub idtr: GDTRIDTR,
+    pub ldtr: LDTRTR,
+    pub tr: LDTRTR,
 }
 
 impl Registers {
@@ -121,11 +186,46 @@ impl Registers {
                 SegmentRegister::new(SegReg::SS),
                 SegmentRegister::new(SegReg::DS),
             ],
-            flags: Flags::empty(),
-            msw: 0xfff0,
+            flags: Flags::DEFAULT,
+            msw: Msw::empty(),
+            gdtr: GDTRIDTR::default(),
+            idtr: GDTRIDTR {
+                base: 0,
+                limit: 0x3ff,
+            },
+            ldtr: LDTRTR::default(),
+            tr: LDTRTR::default(),
         }
     }
 
+    pub fn protected_mode(&self) -> bool {
+        self.msw.contains(Msw::PROTECTION_ENABLE)
+    }
+
+    pub fn read_msw(&self) -> u16 {
+        self.msw.bits() | 0xfff0
+    }
+
+    /// LMSW can set PE but not clear it; only a reset gets the 286 back
+    /// to real mode.
+    pub fn write_msw(&mut self, value: u16) {
+        let pe = self.msw & Msw::PROTECTION_ENABLE;
+        self.msw = Msw::from_bits_truncate(value) | pe;
+    }
+
+    /// The current privilege level, which is the RPL of the CS selector.
+    pub fn cpl(&self) -> u16 {
+        if self.protected_mode() {
+            self.seg_regs[1].selector & 3
+        } else {
+            0
+        }
+    }
+
+    pub fn iopl(&self) -> u16 {
+        (self.flags & Flags::IOPL).bits() >> 12
+    }
+
     pub fn read8(&self, reg: Reg8) -> u8 {
         use self::Reg8::*;
         match reg {
@@ -189,7 +289,7 @@ impl Registers {
             BP => self.gprs[5],
             SI => self.gprs[6],
             DI => self.gprs[7],
-            FLAGS => (self.flags.bits() as u16) | 0xf002u16,
+            FLAGS => self.flags.bits() | 0x0002,
         }
     }
 
@@ -218,20 +318,16 @@ impl Registers {
         }
     }
 
+    /// Loads a segment register the way real mode does. The limit and
+    /// access rights in the descriptor cache are left alone.
     pub fn writeseg16(&mut self, seg_reg: SegReg, value: u16) {
-        use self::SegReg::*;
-        if (self.msw & 1) != 0 {
-            panic!("Protected mode not implemented yet!");
-        } else {
-            let segment;
-            match seg_reg {
-                ES => segment = 0,
-                CS => segment = 1,
-                SS => segment = 2,
-                DS => segment = 3,
-            }
-            self.seg_regs[segment].selector = value;
-            self.seg_regs[segment].base = (value as u32) << 4;
-        }
+        let segment = &mut self.seg_regs[seg_reg as usize];
+        segment.selector = value;
+        segment.base = (value as u32) << 4;
+        segment.valid = true;
+    }
+
+    pub fn setseg(&mut self, seg_reg: SegReg, segment: SegmentRegister) {
+        self.seg_regs[seg_reg as usize] = segment;
     }
 }
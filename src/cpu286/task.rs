@@ -0,0 +1,315 @@
+use crate::cpu286::descriptor::Descriptor;
+use crate::cpu286::registers::*;
+use crate::cpu286::Cpu286;
+use crate::cpu286::Cpu286Context;
+use crate::cpu286::Exception;
+use log::trace;
+
+// Offsets into a 286 task state segment.
+const TSS_BACK_LINK: u32 = 0x00;
+const TSS_IP: u32 = 0x0e;
+const TSS_FLAGS: u32 = 0x10;
+const TSS_GPRS: u32 = 0x12;
+const TSS_SEGS: u32 = 0x22;
+const TSS_LDT: u32 = 0x2a;
+/// The smallest limit a 286 TSS descriptor can have.
+const TSS_MIN_LIMIT: u16 = 0x2b;
+
+const TSS_AVAILABLE: u8 = 1;
+const TSS_BUSY: u8 = 3;
+const TASK_GATE: u8 = 5;
+
+/// What caused a task switch. It decides what happens to the busy bits,
+/// the back link and the NT flag.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaskSwitch {
+    Jump,
+    Call,
+    Interrupt,
+    Iret,
+}
+
+impl Cpu286 {
+    /// Sets or clears the busy bit of the TSS descriptor at `selector`.
+    fn set_tss_busy<T: Cpu286Context>(&mut self, ctx: &mut T, selector: u16, busy: bool) {
+        let addr = self.regs.gdtr.base + (selector & 0xfff8) as u32 + 5;
+        let rights = self.mem_read_byte(ctx, addr);
+        let rights = if busy { rights | 2 } else { rights & !2 };
+        self.mem_write_byte(ctx, addr, rights);
+    }
+
+    /// LTR. Loads the task register from an available TSS in the GDT and
+    /// marks it busy.
+    pub fn load_task_register<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Result<(), Exception> {
+        let error_code = selector & 0xfffc;
+        if error_code == 0 || (selector & 4) != 0 {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        let descriptor = self.read_descriptor(ctx, selector)?;
+        if descriptor.is_segment() || descriptor.system_type() != TSS_AVAILABLE {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        if !descriptor.present() {
+            return Err(Exception::SegmentNotPresent(error_code));
+        }
+        self.set_tss_busy(ctx, selector, true);
+        self.regs.tr = LDTRTR {
+            selector,
+            base: descriptor.base,
+            limit: descriptor.limit,
+            rights: descriptor.rights | 2,
+        };
+        Ok(())
+    }
+
+    /// Handles a far JMP or CALL whose selector points at a system
+    /// descriptor: either a TSS or a task gate.
+    pub fn far_transfer_to_task<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+        descriptor: Descriptor,
+        kind: TaskSwitch,
+    ) -> Result<(), Exception> {
+        let error_code = selector & 0xfffc;
+        let cpl = self.regs.cpl();
+        if descriptor.dpl() < cpl.max(selector & 3) {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        match descriptor.system_type() {
+            TSS_AVAILABLE => {
+                if !descriptor.present() {
+                    return Err(Exception::SegmentNotPresent(error_code));
+                }
+                self.task_switch(ctx, selector, kind)
+            }
+            TASK_GATE => {
+                if !descriptor.present() {
+                    return Err(Exception::SegmentNotPresent(error_code));
+                }
+                self.task_switch(ctx, descriptor.gate_selector(), kind)
+            }
+            _ => Err(Exception::GeneralProtection(error_code)),
+        }
+    }
+
+    /// Saves the current task in its TSS and loads the task whose TSS
+    /// `selector` points at.
+    pub fn task_switch<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+        kind: TaskSwitch,
+    ) -> Result<(), Exception> {
+        trace!(target: "cpu", "Task switch to {:#06x} ({:?})", selector, kind);
+        let error_code = selector & 0xfffc;
+        if (selector & 4) != 0 {
+            return Err(Exception::GeneralProtection(error_code));
+        }
+        let descriptor = self.read_descriptor(ctx, selector)?;
+        let expected = if kind == TaskSwitch::Iret {
+            TSS_BUSY
+        } else {
+            TSS_AVAILABLE
+        };
+        if descriptor.is_segment() || descriptor.system_type() != expected {
+            return Err(match kind {
+                TaskSwitch::Iret => Exception::InvalidTss(error_code),
+                _ => Exception::GeneralProtection(error_code),
+            });
+        }
+        if !descriptor.present() {
+            return Err(Exception::SegmentNotPresent(error_code));
+        }
+        if descriptor.limit < TSS_MIN_LIMIT {
+            return Err(Exception::InvalidTss(error_code));
+        }
+
+        // Save the outgoing task. IRET clears NT in the saved flags so the
+        // task can be resumed later without returning again.
+        let old = self.regs.tr;
+        let mut flags = self.regs.read16(Reg16::FLAGS);
+        if kind == TaskSwitch::Iret {
+            flags &= !Flags::NESTED_TASK.bits();
+        }
+        self.mem_write_word(ctx, old.base + TSS_IP, self.regs.ip);
+        self.mem_write_word(ctx, old.base + TSS_FLAGS, flags);
+        for i in 0..8 {
+            let value = self.regs.gprs[i];
+            self.mem_write_word(ctx, old.base + TSS_GPRS + i as u32 * 2, value);
+        }
+        for i in 0..4 {
+            let value = self.regs.seg_regs[i].selector;
+            self.mem_write_word(ctx, old.base + TSS_SEGS + i as u32 * 2, value);
+        }
+        if matches!(kind, TaskSwitch::Jump | TaskSwitch::Iret) {
+            self.set_tss_busy(ctx, old.selector, false);
+        }
+
+        // Load the incoming task. From here on the switch has happened, and
+        // any fault is taken in the context of the new task.
+        let base = descriptor.base;
+        if matches!(kind, TaskSwitch::Call | TaskSwitch::Interrupt) {
+            self.mem_write_word(ctx, base + TSS_BACK_LINK, old.selector);
+        }
+        if kind != TaskSwitch::Iret {
+            self.set_tss_busy(ctx, selector, true);
+        }
+        self.regs.tr = LDTRTR {
+            selector,
+            base,
+            limit: descriptor.limit,
+            rights: descriptor.rights | 2,
+        };
+        self.regs.msw.insert(Msw::TASK_SWITCHED);
+
+        self.regs.ip = self.mem_read_word(ctx, base + TSS_IP);
+        let mut flags = self.mem_read_word(ctx, base + TSS_FLAGS);
+        if matches!(kind, TaskSwitch::Call | TaskSwitch::Interrupt) {
+            flags |= Flags::NESTED_TASK.bits();
+        }
+        self.regs.write16(Reg16::FLAGS, flags & 0x7fff);
+        for i in 0..8 {
+            self.regs.gprs[i] = self.mem_read_word(ctx, base + TSS_GPRS + i as u32 * 2);
+        }
+        let mut selectors = [0u16; 4];
+        for (i, selector) in selectors.iter_mut().enumerate() {
+            *selector = self.mem_read_word(ctx, base + TSS_SEGS + i as u32 * 2);
+        }
+        let ldt = self.mem_read_word(ctx, base + TSS_LDT);
+
+        // Start with unusable caches holding the new selectors, so CPL
+        // comes from the new CS while the descriptors are checked.
+        for (i, selector) in selectors.iter().enumerate() {
+            self.regs.seg_regs[i] = SegmentRegister {
+                selector: *selector,
+                valid: false,
+                ..Default::default()
+            };
+        }
+        self.instr_ip = self.regs.ip;
+        self.instr_sp = self.regs.read16(Reg16::SP);
+        self.instr_cs = self.regs.readseg16(SegReg::CS);
+        self.instr_ss = self.regs.readseg16(SegReg::SS);
+
+        self.load_ldt(ctx, ldt).map_err(|fault| match fault {
+            Exception::GeneralProtection(_) => Exception::InvalidTss(ldt & 0xfffc),
+            fault => fault,
+        })?;
+        self.load_task_segments(ctx, selectors)
+    }
+
+    fn load_task_segments<T: Cpu286Context>(
+        &mut self,
+        ctx: &mut T,
+        selectors: [u16; 4],
+    ) -> Result<(), Exception> {
+        let cs = selectors[SegReg::CS as usize];
+        let error_code = cs & 0xfffc;
+        if error_code == 0 {
+            return Err(Exception::InvalidTss(0));
+        }
+        let code = self
+            .read_descriptor(ctx, cs)
+            .map_err(|_| Exception::InvalidTss(error_code))?;
+        let cpl = cs & 3;
+        let allowed = if code.is_conforming() {
+            code.dpl() <= cpl
+        } else {
+            code.dpl() == cpl
+        };
+        if !code.is_code() || !allowed {
+            return Err(Exception::InvalidTss(error_code));
+        }
+        if !code.present() {
+            return Err(Exception::SegmentNotPresent(error_code));
+        }
+        self.set_accessed(ctx, cs);
+        self.regs.setseg(SegReg::CS, code.to_cache(cs));
+
+        for seg in [SegReg::SS, SegReg::DS, SegReg::ES].iter() {
+            let selector = selectors[*seg as usize];
+            self.load_protected_segment(ctx, *seg, selector)
+                .map_err(|fault| match fault {
+                    Exception::GeneralProtection(code) => Exception::InvalidTss(code),
+                    fault => fault,
+                })?;
+        }
+        Ok(())
+    }
+
+    /// IRET with NT set returns to the task in the current TSS's back link.
+    pub fn task_return<T: Cpu286Context>(&mut self, ctx: &mut T) -> Result<(), Exception> {
+        let back_link = self.mem_read_word(ctx, self.regs.tr.base + TSS_BACK_LINK);
+        self.task_switch(ctx, back_link, TaskSwitch::Iret)
+    }
+}
+
+#[test]
+fn test_call_and_iret_through_tss() {
+    let mut bus = crate::cpu286::TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg::CS, 0);
+    cpu.regs.ip = 0x100;
+    cpu.regs.gdtr = GDTRIDTR {
+        base: 0x800,
+        limit: 0x27,
+    };
+    // code, data and two TSSs at 0x1000 and 0x1100
+    bus.ram[0x808..0x80e].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0x9a]);
+    bus.ram[0x810..0x816].copy_from_slice(&[0xff, 0xff, 0, 0, 0, 0x92]);
+    bus.ram[0x818..0x81e].copy_from_slice(&[0x2b, 0, 0x00, 0x10, 0, 0x81]);
+    bus.ram[0x820..0x826].copy_from_slice(&[0x2b, 0, 0x00, 0x11, 0, 0x81]);
+    let task_b: [u16; 22] = [
+        0, 0, 0, 0, 0, 0, 0, // back link and privilege stacks
+        0x200, 0x0002, // ip, flags
+        0x1234, 0, 0, 0, 0x500, 0, 0, 0, // general registers
+        0x10, 0x08, 0x10, 0x10, // es, cs, ss, ds
+        0,    // ldt
+    ];
+    for (i, word) in task_b.iter().enumerate() {
+        bus.ram[0x1100 + i * 2..0x1102 + i * 2].copy_from_slice(&word.to_le_bytes());
+    }
+    bus.ram[0x100..0x124].copy_from_slice(&[
+        0xb8, 0x01, 0x00, // mov ax, 1
+        0x0f, 0x01, 0xf0, // lmsw ax
+        0xea, 0x10, 0x01, 0x08, 0x00, // jmp 0008:0110
+        0, 0, 0, 0, 0, //
+        0xb8, 0x10, 0x00, // mov ax, 0x10
+        0x8e, 0xd0, // mov ss, ax
+        0x8e, 0xd8, // mov ds, ax
+        0x8e, 0xc0, // mov es, ax
+        0xb8, 0x18, 0x00, // mov ax, 0x18
+        0x0f, 0x00, 0xd8, // ltr ax
+        0x9a, 0x00, 0x00, 0x20, 0x00, // call 0020:0000
+    ]);
+    bus.ram[0x200] = 0xcf; // iret
+    for _ in 0..10 {
+        cpu.tick(&mut bus);
+    }
+    assert_eq!(cpu.regs.tr.selector, 0x20);
+    assert_eq!(cpu.regs.ip, 0x200);
+    assert_eq!(cpu.regs.read16(Reg16::AX), 0x1234);
+    assert_eq!(cpu.regs.read16(Reg16::SP), 0x500);
+    assert!(cpu.regs.flags.contains(Flags::NESTED_TASK));
+    assert!(cpu.regs.msw.contains(Msw::TASK_SWITCHED));
+    assert_eq!(bus.ram[0x1100], 0x18);
+    assert_eq!(bus.ram[0x81d], 0x83);
+    assert_eq!(bus.ram[0x825], 0x83);
+    // The outgoing task was saved with AX still holding its TSS selector.
+    assert_eq!(bus.ram[0x1012], 0x18);
+
+    cpu.tick(&mut bus);
+    assert_eq!(cpu.regs.tr.selector, 0x18);
+    assert_eq!(cpu.regs.ip, 0x124);
+    assert_eq!(cpu.regs.read16(Reg16::AX), 0x18);
+    assert!(!cpu.regs.flags.contains(Flags::NESTED_TASK));
+    assert_eq!(bus.ram[0x825], 0x81);
+    assert_eq!(bus.ram[0x81d], 0x83);
+}
@@ -0,0 +1,72 @@
+// Clock counts from the 80286 data sheet, assuming zero wait states and a
+// full prefetch queue. The board adds its own wait states on top for every
+// bus cycle it slows down.
+
+/// Extra clocks for an instruction whose ModR/M operand is in memory. The
+/// data sheet has separate counts for every form; this is about the
+/// average difference from the register form.
+pub const MEMORY_OPERAND_CYCLES: usize = 3;
+
+/// Extra clocks when a conditional jump, loop or near jump is taken and the
+/// prefetch queue has to be refilled.
+pub const BRANCH_TAKEN_CYCLES: usize = 4;
+
+/// Clocks to take an interrupt or exception through the vector table.
+pub const INTERRUPT_CYCLES: usize = 23;
+
+/// One-byte opcodes, in register form. Prefixes are counted as part of
+/// the instruction they prefix; MUL, DIV and the string instructions' REP
+/// iterations add their own.
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+    2,  2,  2,  2,  3,  3,  3,  5,  2,  2,  2,  2,  3,  3,  3,  0, // 0
+    2,  2,  2,  2,  3,  3,  3,  5,  2,  2,  2,  2,  3,  3,  3,  5, // 1
+    2,  2,  2,  2,  3,  3,  0,  3,  2,  2,  2,  2,  3,  3,  0,  3, // 2
+    2,  2,  2,  2,  3,  3,  0,  3,  2,  2,  2,  2,  3,  3,  0,  3, // 3
+    2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2, // 4
+    3,  3,  3,  3,  3,  3,  3,  3,  5,  5,  5,  5,  5,  5,  5,  5, // 5
+   17, 19, 13, 10,  3,  3,  3,  3,  3, 21,  3, 21,  5,  5,  5,  5, // 6
+    3,  3,  3,  3,  3,  3,  3,  3,  3,  3,  3,  3,  3,  3,  3,  3, // 7
+    3,  3,  3,  3,  2,  2,  3,  3,  2,  2,  2,  2,  2,  3,  2,  5, // 8
+    3,  3,  3,  3,  3,  3,  3,  3,  2,  2, 13,  3,  3,  5,  2,  2, // 9
+    5,  5,  3,  3,  5,  5,  8,  8,  3,  3,  3,  3,  5,  5,  7,  7, // a
+    2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2,  2, // b
+    5,  5, 11, 11,  7,  7,  2,  2, 11,  5, 15, 15, 23, 23,  3, 17, // c
+    2,  2,  5,  5, 16, 14,  3,  5,  9,  9,  9,  9,  9,  9,  9,  9, // d
+    4,  4,  4,  4,  5,  5,  3,  3,  3,  3, 11,  3,  5,  5,  3,  3, // e
+    0,  3,  0,  0,  2,  2,  3,  3,  2,  2,  2,  2,  2,  2,  2,  2, // f
+];
+
+/// Clocks for a one-byte opcode, or the 0Fh escape byte itself.
+pub fn base_cycles(opcode: u8) -> usize {
+    CYCLES[opcode as usize] as usize
+}
+
+/// Clocks for the two-byte opcodes, all of them system instructions.
+/// Groups 6 and 7 are charged for their slowest member, the descriptor
+/// and table register loads.
+pub fn base_cycles_0f(opcode: u8) -> usize {
+    match opcode {
+        0x00 => 17,
+        0x01 => 11,
+        0x02 | 0x03 => 14,
+        0x05 => 195,
+        0x06 => 2,
+        _ => 10,
+    }
+}
+
+/// Extra clocks for MUL, IMUL, DIV and IDIV in group 3 (F6h/F7h, reg 4-7).
+pub fn multiply_divide_cycles(reg: u8, word: bool) -> usize {
+    let cycles = match reg {
+        4 | 5 => 11,
+        6 => 12,
+        _ => 15,
+    };
+    if word {
+        cycles + 8
+    } else {
+        cycles
+    }
+}
@@ -0,0 +1,209 @@
+use crate::cpu286::alu::AluOp;
+use crate::cpu386::operand::OpSize;
+use crate::cpu386::registers::*;
+use crate::cpu386::Cpu386;
+
+impl Cpu386 {
+    pub fn set_pzs(&mut self, value: u32, size: OpSize) {
+        let value = value & size.mask();
+        self.regs.eflags.set(Flags::ZERO, value == 0);
+        self.regs
+            .eflags
+            .set(Flags::SIGN, (value & size.sign()) != 0);
+        self.regs
+            .eflags
+            .set(Flags::PARITY, (value as u8).count_ones().is_multiple_of(2));
+    }
+
+    /// The same eight operations as the 286, widened to 32 bits. The
+    /// arithmetic is done in 64 bits so the dword carry falls out the same
+    /// way as the narrower ones.
+    pub fn alu(&mut self, op: AluOp, dst: u32, src: u32, size: OpSize) -> u32 {
+        let mask = size.mask() as u64;
+        let sign = size.sign() as u64;
+        let dst = dst as u64 & mask;
+        let src = src as u64 & mask;
+        let carry = self.regs.eflags.contains(Flags::CARRY) as u64;
+        let result = match op {
+            AluOp::Add | AluOp::Adc => {
+                let carry = if op == AluOp::Adc { carry } else { 0 };
+                let result = dst + src + carry;
+                self.regs.eflags.set(Flags::CARRY, result > mask);
+                self.regs.eflags.set(
+                    Flags::OVERFLOW,
+                    ((dst ^ result) & (src ^ result) & sign) != 0,
+                );
+                self.regs
+                    .eflags
+                    .set(Flags::ADJUST, ((dst ^ src ^ result) & 0x10) != 0);
+                result
+            }
+            AluOp::Sub | AluOp::Sbb | AluOp::Cmp => {
+                let carry = if op == AluOp::Sbb { carry } else { 0 };
+                let result = dst.wrapping_sub(src).wrapping_sub(carry);
+                self.regs.eflags.set(Flags::CARRY, dst < src + carry);
+                self.regs
+                    .eflags
+                    .set(Flags::OVERFLOW, ((dst ^ src) & (dst ^ result) & sign) != 0);
+                self.regs
+                    .eflags
+                    .set(Flags::ADJUST, ((dst ^ src ^ result) & 0x10) != 0);
+                result
+            }
+            AluOp::Or | AluOp::And | AluOp::Xor => {
+                self.regs
+                    .eflags
+                    .remove(Flags::CARRY | Flags::OVERFLOW | Flags::ADJUST);
+                match op {
+                    AluOp::Or => dst | src,
+                    AluOp::And => dst & src,
+                    _ => dst ^ src,
+                }
+            }
+        };
+        let result = (result & mask) as u32;
+        self.set_pzs(result, size);
+        result
+    }
+
+    /// INC and DEC leave the carry flag alone.
+    pub fn inc(&mut self, value: u32, size: OpSize) -> u32 {
+        let carry = self.regs.eflags.contains(Flags::CARRY);
+        let result = self.alu(AluOp::Add, value, 1, size);
+        self.regs.eflags.set(Flags::CARRY, carry);
+        result
+    }
+
+    pub fn dec(&mut self, value: u32, size: OpSize) -> u32 {
+        let carry = self.regs.eflags.contains(Flags::CARRY);
+        let result = self.alu(AluOp::Sub, value, 1, size);
+        self.regs.eflags.set(Flags::CARRY, carry);
+        result
+    }
+
+    /// The group 2 shifts and rotates. Like the 286 the count is masked
+    /// to 5 bits.
+    pub fn shift(&mut self, op: u8, value: u32, count: u8, size: OpSize) -> u32 {
+        let count = count & 0x1f;
+        if count == 0 {
+            return value;
+        }
+        let mask = size.mask() as u64;
+        let msb = size.sign() as u64;
+        let mut value = value as u64 & mask;
+        let mut carry = self.regs.eflags.contains(Flags::CARRY);
+        match op & 7 {
+            0 => {
+                for _ in 0..count {
+                    carry = (value & msb) != 0;
+                    value = ((value << 1) | carry as u64) & mask;
+                }
+                self.regs
+                    .eflags
+                    .set(Flags::OVERFLOW, ((value & msb) != 0) != carry);
+            }
+            1 => {
+                for _ in 0..count {
+                    carry = (value & 1) != 0;
+                    value = (value >> 1) | if carry { msb } else { 0 };
+                }
+                self.regs
+                    .eflags
+                    .set(Flags::OVERFLOW, ((value ^ (value << 1)) & msb) != 0);
+            }
+            2 => {
+                for _ in 0..count {
+                    let out = (value & msb) != 0;
+                    value = ((value << 1) | carry as u64) & mask;
+                    carry = out;
+                }
+                self.regs
+                    .eflags
+                    .set(Flags::OVERFLOW, ((value & msb) != 0) != carry);
+            }
+            3 => {
+                for _ in 0..count {
+                    let out = (value & 1) != 0;
+                    value = (value >> 1) | if carry { msb } else { 0 };
+                    carry = out;
+                }
+                self.regs
+                    .eflags
+                    .set(Flags::OVERFLOW, ((value ^ (value << 1)) & msb) != 0);
+            }
+            4 | 6 => {
+                for _ in 0..count {
+                    carry = (value & msb) != 0;
+                    value = (value << 1) & mask;
+                }
+                self.regs
+                    .eflags
+                    .set(Flags::OVERFLOW, ((value & msb) != 0) != carry);
+                self.set_pzs(value as u32, size);
+            }
+            5 => {
+                self.regs.eflags.set(Flags::OVERFLOW, (value & msb) != 0);
+                for _ in 0..count {
+                    carry = (value & 1) != 0;
+                    value >>= 1;
+                }
+                self.set_pzs(value as u32, size);
+            }
+            _ => {
+                for _ in 0..count {
+                    carry = (value & 1) != 0;
+                    value = (value >> 1) | (value & msb);
+                }
+                self.regs.eflags.remove(Flags::OVERFLOW);
+                self.set_pzs(value as u32, size);
+            }
+        }
+        self.regs.eflags.set(Flags::CARRY, carry);
+        value as u32
+    }
+}
+
+#[test]
+fn test_32bit_alu_flags() {
+    let mut cpu = Cpu386::new();
+    let flags = |cpu: &Cpu386| {
+        let f = cpu.regs.eflags;
+        [
+            f.contains(Flags::CARRY),
+            f.contains(Flags::PARITY),
+            f.contains(Flags::ZERO),
+            f.contains(Flags::SIGN),
+            f.contains(Flags::OVERFLOW),
+        ]
+    };
+    let dword = OpSize::Dword;
+    //                      C      P      Z      S      O
+    assert_eq!(cpu.alu(AluOp::Add, 0x7fff_ffff, 1, dword), 0x8000_0000);
+    assert_eq!(flags(&cpu), [false, true, false, true, true]);
+    assert_eq!(cpu.alu(AluOp::Add, 0xffff_ffff, 1, dword), 0);
+    assert_eq!(flags(&cpu), [true, true, true, false, false]);
+    assert_eq!(cpu.alu(AluOp::Adc, 0xffff_ffff, 0, dword), 0);
+    assert_eq!(flags(&cpu), [true, true, true, false, false]);
+    assert_eq!(cpu.alu(AluOp::Sbb, 0x8000_0000, 0, dword), 0x7fff_ffff);
+    assert_eq!(flags(&cpu), [false, true, false, false, true]);
+    assert_eq!(cpu.alu(AluOp::Sub, 0, 1, dword), 0xffff_ffff);
+    assert_eq!(flags(&cpu), [true, true, false, true, false]);
+    // Parity only looks at the low byte.
+    assert_eq!(cpu.alu(AluOp::Cmp, 0x0001_0100, 0x0001_0000, dword), 0x100);
+    assert_eq!(flags(&cpu), [false, true, false, false, false]);
+    assert_eq!(cpu.alu(AluOp::Xor, 0x8000_0001, 0x8000_0001, dword), 0);
+    assert_eq!(flags(&cpu), [false, true, true, false, false]);
+    cpu.regs.eflags.insert(Flags::CARRY);
+    assert_eq!(cpu.inc(0xffff_ffff, dword), 0);
+    assert_eq!(flags(&cpu), [true, true, true, false, false]);
+    // A word operation on the same value only sees the bottom half.
+    assert_eq!(cpu.alu(AluOp::Add, 0x1234_ffff, 1, OpSize::Word), 0);
+    assert_eq!(flags(&cpu), [true, true, true, false, false]);
+
+    assert_eq!(cpu.shift(4, 0x8000_0001, 1, dword), 2);
+    assert_eq!(flags(&cpu), [true, false, false, false, true]);
+    assert_eq!(cpu.shift(7, 0x8000_0000, 31, dword), 0xffff_ffff);
+    assert_eq!(flags(&cpu), [false, true, false, true, false]);
+    assert_eq!(cpu.shift(1, 0x0000_0001, 1, dword), 0x8000_0000);
+    assert!(cpu.regs.eflags.contains(Flags::CARRY));
+}
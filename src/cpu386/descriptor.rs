@@ -0,0 +1,176 @@
+use crate::cpu386::registers::*;
+use crate::cpu386::Cpu386;
+use crate::cpu386::Cpu386Context;
+use crate::cpu386::Exception;
+
+/// An 8-byte 386 descriptor. The 286 format is the same with the top word
+/// zero, so 286 descriptors load as byte granular 16-bit segments.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Descriptor {
+    pub raw: u64,
+}
+
+impl Descriptor {
+    pub fn from_bytes(bytes: [u8; 8]) -> Descriptor {
+        Descriptor {
+            raw: u64::from_le_bytes(bytes),
+        }
+    }
+
+    pub fn rights(&self) -> u8 {
+        (self.raw >> 40) as u8
+    }
+
+    pub fn present(&self) -> bool {
+        (self.rights() & 0x80) != 0
+    }
+
+    pub fn dpl(&self) -> u16 {
+        ((self.rights() >> 5) & 3) as u16
+    }
+
+    pub fn is_segment(&self) -> bool {
+        (self.rights() & 0x10) != 0
+    }
+
+    pub fn is_code(&self) -> bool {
+        self.is_segment() && (self.rights() & 0x08) != 0
+    }
+
+    pub fn is_writable_data(&self) -> bool {
+        self.is_segment() && (self.rights() & 0x0a) == 0x02
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.is_segment() && ((self.rights() & 0x08) == 0 || (self.rights() & 0x02) != 0)
+    }
+
+    /// 9 is an available 32-bit TSS, 0xe and 0xf are 32-bit interrupt and
+    /// trap gates, 6 and 7 their 286 equivalents.
+    pub fn system_type(&self) -> u8 {
+        self.rights() & 0x0f
+    }
+
+    pub fn base(&self) -> u32 {
+        (((self.raw >> 16) & 0xff_ffff) | ((self.raw >> 32) & 0xff00_0000)) as u32
+    }
+
+    /// The limit in bytes, scaled up when the granularity bit is set.
+    pub fn limit(&self) -> u32 {
+        let limit = ((self.raw & 0xffff) | ((self.raw >> 32) & 0xf_0000)) as u32;
+        if (self.raw & (1 << 55)) != 0 {
+            (limit << 12) | 0xfff
+        } else {
+            limit
+        }
+    }
+
+    pub fn big(&self) -> bool {
+        (self.raw & (1 << 54)) != 0
+    }
+
+    pub fn gate_selector(&self) -> u16 {
+        (self.raw >> 16) as u16
+    }
+
+    /// 286 gates only have the low word of the offset.
+    pub fn gate_offset(&self) -> u32 {
+        let low = (self.raw & 0xffff) as u32;
+        if (self.system_type() & 0x08) != 0 {
+            low | ((self.raw >> 32) as u32 & 0xffff_0000)
+        } else {
+            low
+        }
+    }
+
+    pub fn to_cache(self, selector: u16) -> SegmentRegister {
+        SegmentRegister {
+            selector,
+            base: self.base(),
+            limit: self.limit(),
+            rights: self.rights(),
+            big: self.big(),
+            valid: true,
+        }
+    }
+}
+
+impl Cpu386 {
+    /// Reads a descriptor from the GDT. LDTs aren't supported yet, so
+    /// selectors with the TI bit set fault.
+    pub fn read_descriptor<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Result<Descriptor, Exception> {
+        let index = (selector & 0xfff8) as u32;
+        if (selector & 4) != 0 || index + 7 > self.regs.gdtr.limit as u32 {
+            return Err(Exception::GeneralProtection(selector & 0xfffc));
+        }
+        let mut bytes = [0u8; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read_system8(ctx, self.regs.gdtr.base + index + i as u32)?;
+        }
+        Ok(Descriptor::from_bytes(bytes))
+    }
+
+    /// Loads a segment register from a descriptor in protected mode.
+    pub fn load_protected_segment<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        selector: u16,
+    ) -> Result<(), Exception> {
+        let fault = Exception::GeneralProtection(selector & 0xfffc);
+        if (selector & 0xfffc) == 0 {
+            if seg == SegReg::CS || seg == SegReg::SS {
+                return Err(Exception::GeneralProtection(0));
+            }
+            let mut segment = self.regs.readseg(seg);
+            segment.selector = selector;
+            segment.valid = false;
+            self.regs.setseg(seg, segment);
+            return Ok(());
+        }
+        let descriptor = self.read_descriptor(ctx, selector)?;
+        let usable = match seg {
+            SegReg::CS => descriptor.is_code(),
+            SegReg::SS => descriptor.is_writable_data(),
+            _ => descriptor.is_readable(),
+        };
+        if !usable {
+            return Err(fault);
+        }
+        if !descriptor.present() {
+            return Err(if seg == SegReg::SS {
+                Exception::StackFault(selector & 0xfffc)
+            } else {
+                Exception::SegmentNotPresent(selector & 0xfffc)
+            });
+        }
+        self.regs.setseg(seg, descriptor.to_cache(selector));
+        Ok(())
+    }
+
+    /// LTR. Only 32-bit TSSs are accepted, and only for the ring 0 stack
+    /// pointer used by interrupts; hardware task switches aren't there yet.
+    pub fn load_task_register<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+    ) -> Result<(), Exception> {
+        let descriptor = self.read_descriptor(ctx, selector)?;
+        if descriptor.is_segment() || descriptor.system_type() != 9 {
+            return Err(Exception::GeneralProtection(selector & 0xfffc));
+        }
+        if !descriptor.present() {
+            return Err(Exception::SegmentNotPresent(selector & 0xfffc));
+        }
+        self.regs.tr = TaskRegister {
+            selector,
+            base: descriptor.base(),
+            limit: descriptor.limit(),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+// The instructions the 486 added to the 386 set. Everything else about the
+// 486 (AC, CR0.WP and friends) lives with the 386 code behind a model check.
+use crate::cpu286::alu::AluOp;
+use crate::cpu386::registers::*;
+use crate::cpu386::Cpu386;
+use crate::cpu386::Cpu386Context;
+use crate::cpu386::Exception;
+#[cfg(test)]
+use crate::cpu386::{operand::OpSize, paging::PageAccess, Model, TestBus};
+use log::trace;
+
+impl Cpu386 {
+    /// Two-byte opcodes the 386 doesn't decode. On a 386 these are all
+    /// invalid.
+    pub(super) fn execute_486<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        opcode: u8,
+    ) -> Result<(), Exception> {
+        if !self.model.is_486() || (opcode == 0xa2 && !self.model.has_cpuid()) {
+            trace!(target: "cpu", "Invalid opcode 0f {:#02x}", opcode);
+            return Err(Exception::InvalidOpcode);
+        }
+        match opcode {
+            0x08 | 0x09 => {
+                // There's no cache to write back or throw away.
+                trace!(target: "cpu", "invd/wbinvd");
+                self.privileged()?;
+            }
+            0xa2 => {
+                trace!(target: "cpu", "cpuid");
+                self.cpuid();
+            }
+            0xb0 | 0xb1 => {
+                trace!(target: "cpu", "cmpxchg");
+                let size = self.size_from_opcode(opcode);
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let dst = self.read_rm(ctx, params.rm, size)?;
+                let accumulator = self.read_reg(0, size);
+                self.alu(AluOp::Cmp, accumulator, dst, size);
+                // The destination is written either way, so a read-only
+                // destination faults even when the compare fails.
+                if self.regs.eflags.contains(Flags::ZERO) {
+                    let src = self.read_reg(params.reg, size);
+                    self.write_rm(ctx, params.rm, size, src)?;
+                } else {
+                    self.write_rm(ctx, params.rm, size, dst)?;
+                    self.write_reg(0, size, dst);
+                }
+            }
+            0xc0 | 0xc1 => {
+                trace!(target: "cpu", "xadd");
+                let size = self.size_from_opcode(opcode);
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let dst = self.read_rm(ctx, params.rm, size)?;
+                let src = self.read_reg(params.reg, size);
+                let sum = self.alu(AluOp::Add, dst, src, size);
+                self.write_reg(params.reg, size, dst);
+                self.write_rm(ctx, params.rm, size, sum)?;
+            }
+            0xc8..=0xcf => {
+                // Intel leaves the 16-bit form undefined; real 486s zero
+                // the low word, which is what we do too.
+                trace!(target: "cpu", "bswap");
+                let reg = opcode & 7;
+                if self.op32 {
+                    let value = self.regs.read32(reg);
+                    self.regs.write32(reg, value.swap_bytes());
+                } else {
+                    self.regs.write16(reg, 0);
+                }
+            }
+            _ => {
+                trace!(target: "cpu", "Invalid opcode 0f {:#02x}", opcode);
+                return Err(Exception::InvalidOpcode);
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaf 0 is the vendor string, leaf 1 the signature of a 486DX4 with
+    /// its FPU. Leaves past the highest one return zeros.
+    fn cpuid(&mut self) {
+        let (eax, ebx, ecx, edx) = match self.regs.read32(0) {
+            0 => (1, 0x756e_6547, 0x6c65_746e, 0x4965_6e69),
+            1 => (0x0480, 0, 0, 0x0000_0001),
+            _ => (0, 0, 0, 0),
+        };
+        self.regs.write32(0, eax);
+        self.regs.write32(3, ebx);
+        self.regs.write32(1, ecx);
+        self.regs.write32(2, edx);
+    }
+}
+
+#[test]
+fn test_486_detection_and_instructions() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x1_0000],
+    };
+    let mut cpu = Cpu386::new();
+    cpu.write_flags(Flags::ALIGNMENT_CHECK.bits(), OpSize::Dword);
+    assert!(!cpu.regs.eflags.contains(Flags::ALIGNMENT_CHECK));
+    cpu.regs.writeseg_real(SegReg::CS, 0);
+    cpu.regs.eip = 0x100;
+    bus.ram[0x100..0x103].copy_from_slice(&[0x66, 0x0f, 0xc8]); // bswap eax
+    assert_eq!(cpu.execute(&mut bus), Err(Exception::InvalidOpcode));
+
+    // A 486DX can toggle AC but not ID, and doesn't have CPUID.
+    let mut cpu = Cpu386::with_model(Model::I486DX);
+    assert!(cpu.regs.cr0.contains(Cr0::CACHE_DISABLE));
+    cpu.write_flags((Flags::ALIGNMENT_CHECK | Flags::ID).bits(), OpSize::Dword);
+    assert_eq!(cpu.regs.eflags & Flags::ID, Flags::empty());
+    assert!(cpu.regs.eflags.contains(Flags::ALIGNMENT_CHECK));
+    cpu.regs.writeseg_real(SegReg::CS, 0);
+    cpu.regs.eip = 0x200;
+    bus.ram[0x200..0x202].copy_from_slice(&[0x0f, 0xa2]);
+    assert_eq!(cpu.execute(&mut bus), Err(Exception::InvalidOpcode));
+
+    let mut cpu = Cpu386::with_model(Model::I486DX4);
+    cpu.write_flags(Flags::ID.bits(), OpSize::Dword);
+    assert!(cpu.regs.eflags.contains(Flags::ID));
+    cpu.reset();
+    assert_eq!(cpu.model, Model::I486DX4);
+    cpu.regs.writeseg_real(SegReg::CS, 0);
+    cpu.regs.eip = 0x100;
+    cpu.regs.write32(0, 0x1122_3344);
+    cpu.regs.write32(3, 5);
+    cpu.regs.write32(1, 0x99);
+    bus.ram[0x100..0x114].copy_from_slice(&[
+        0x66, 0x0f, 0xc8, // bswap eax
+        0x66, 0x0f, 0xc1, 0xd8, // xadd eax, ebx
+        0x66, 0x0f, 0xb1, 0xcb, // cmpxchg ebx, ecx
+        0x66, 0x0f, 0xb1, 0xcb, // cmpxchg ebx, ecx
+        0x66, 0x31, 0xc0, // xor eax, eax
+        0x0f, 0xa2, // cpuid
+    ]);
+    cpu.execute(&mut bus).unwrap();
+    assert_eq!(cpu.regs.read32(0), 0x4433_2211);
+    cpu.execute(&mut bus).unwrap();
+    assert_eq!(cpu.regs.read32(0), 0x4433_2216);
+    assert_eq!(cpu.regs.read32(3), 0x4433_2211);
+    cpu.execute(&mut bus).unwrap();
+    assert!(!cpu.regs.eflags.contains(Flags::ZERO));
+    assert_eq!(cpu.regs.read32(0), 0x4433_2211);
+    cpu.execute(&mut bus).unwrap();
+    assert!(cpu.regs.eflags.contains(Flags::ZERO));
+    assert_eq!(cpu.regs.read32(3), 0x99);
+    cpu.execute(&mut bus).unwrap();
+    cpu.execute(&mut bus).unwrap();
+    assert_eq!(cpu.regs.read32(0), 1);
+    assert_eq!(cpu.regs.read32(3), 0x756e_6547);
+
+    // INVLPG drops a stale translation.
+    let put32 = |bus: &mut TestBus, addr: usize, value: u32| {
+        bus.ram[addr..addr + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    put32(&mut bus, 0x1000, 0x2007);
+    put32(&mut bus, 0x2000, 0x0007);
+    put32(&mut bus, 0x2014, 0x7007);
+    cpu.regs.cr3 = 0x1000;
+    cpu.regs.cr0 |= Cr0::PROTECTION_ENABLE | Cr0::PAGING;
+    let translate = |cpu: &mut Cpu386, bus: &mut TestBus| {
+        cpu.translate(bus, 0x5000, PageAccess::Read, false).unwrap()
+    };
+    assert_eq!(translate(&mut cpu, &mut bus), 0x7000);
+    put32(&mut bus, 0x2014, 0x8007);
+    assert_eq!(translate(&mut cpu, &mut bus), 0x7000);
+    cpu.regs.eip = 0x300;
+    bus.ram[0x300..0x305].copy_from_slice(&[0x0f, 0x01, 0x3e, 0x00, 0x50]); // invlpg [5000h]
+    cpu.execute(&mut bus).unwrap();
+    assert_eq!(translate(&mut cpu, &mut bus), 0x8000);
+
+    // Misaligned accesses fault at CPL 3 once CR0.AM is on.
+    cpu.regs.eflags |= Flags::ALIGNMENT_CHECK | Flags::VIRTUAL_8086;
+    assert!(cpu
+        .read(&mut bus, SegReg::DS, 0x5001, OpSize::Dword)
+        .is_ok());
+    cpu.regs.cr0 |= Cr0::ALIGNMENT_MASK;
+    assert_eq!(
+        cpu.read(&mut bus, SegReg::DS, 0x5001, OpSize::Dword),
+        Err(Exception::AlignmentCheck)
+    );
+    assert!(cpu
+        .read(&mut bus, SegReg::DS, 0x5004, OpSize::Dword)
+        .is_ok());
+}
@@ -0,0 +1,1644 @@
+use crate::cpu286::alu::AluOp;
+use crate::cpu286::RepType;
+use crate::cpu386::operand::*;
+use crate::cpu386::paging::{PageAccess, Tlb};
+use crate::cpu386::registers::*;
+use log::{debug, error, trace};
+
+pub mod alu;
+pub mod descriptor;
+pub mod i486;
+pub mod operand;
+pub mod paging;
+pub mod registers;
+pub mod v86;
+
+pub trait Cpu386Context {
+    fn mem_read_byte(&mut self, addr: u32) -> u8;
+    fn mem_write_byte(&mut self, addr: u32, value: u8);
+    fn io_read_byte(&mut self, addr: u16) -> u8;
+    fn io_write_byte(&mut self, addr: u16, value: u8);
+    fn io_read_word(&mut self, addr: u16) -> u16 {
+        let lo = self.io_read_byte(addr);
+        let hi = self.io_read_byte(addr.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+    fn io_write_word(&mut self, addr: u16, value: u16) {
+        let [lo, hi] = value.to_le_bytes();
+        self.io_write_byte(addr, lo);
+        self.io_write_byte(addr.wrapping_add(1), hi);
+    }
+    fn io_read_dword(&mut self, addr: u16) -> u32 {
+        let lo = self.io_read_word(addr);
+        let hi = self.io_read_word(addr.wrapping_add(2));
+        (lo as u32) | ((hi as u32) << 16)
+    }
+    fn io_write_dword(&mut self, addr: u16, value: u32) {
+        self.io_write_word(addr, value as u16);
+        self.io_write_word(addr.wrapping_add(2), (value >> 16) as u16);
+    }
+    /// The address lines the board lets through, for A20 gating and for
+    /// 386SX boards with only 24 of them.
+    fn a20_mask(&self) -> u32 {
+        0xffff_ffff
+    }
+}
+
+/// Faults raised while executing an instruction. As on the 286 they abort
+/// the instruction and restart it after the handler returns.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exception {
+    DivideError,
+    InvalidOpcode,
+    DoubleFault,
+    InvalidTss(u16),
+    SegmentNotPresent(u16),
+    StackFault(u16),
+    GeneralProtection(u16),
+    /// The error code; the faulting address is in CR2.
+    PageFault(u16),
+    AlignmentCheck,
+}
+
+/// Which member of the family to behave as. They share everything but the
+/// instructions, flags and control register bits the later ones added.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Model {
+    #[default]
+    I386DX,
+    I486DX,
+    /// A late 486 with CPUID.
+    I486DX4,
+}
+
+impl Model {
+    pub fn is_486(self) -> bool {
+        self != Model::I386DX
+    }
+
+    pub fn has_cpuid(self) -> bool {
+        self == Model::I486DX4
+    }
+
+    /// The EFLAGS bits POPF can change, ignoring privilege.
+    fn writable_flags(self) -> u32 {
+        let mut mask = 0x0001_7fd5;
+        if self.is_486() {
+            mask |= Flags::ALIGNMENT_CHECK.bits();
+        }
+        if self.has_cpuid() {
+            mask |= Flags::ID.bits();
+        }
+        mask
+    }
+
+    fn writable_cr0(self) -> Cr0 {
+        let cr0 = Cr0::PROTECTION_ENABLE
+            | Cr0::MONITOR_COPROCESSOR
+            | Cr0::EMULATE_COPROCESSOR
+            | Cr0::TASK_SWITCHED
+            | Cr0::PAGING;
+        if self.is_486() {
+            cr0 | Cr0::NUMERIC_ERROR
+                | Cr0::WRITE_PROTECT
+                | Cr0::ALIGNMENT_MASK
+                | Cr0::NOT_WRITE_THROUGH
+                | Cr0::CACHE_DISABLE
+        } else {
+            cr0
+        }
+    }
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        match self {
+            Exception::DivideError => 0,
+            Exception::InvalidOpcode => 6,
+            Exception::DoubleFault => 8,
+            Exception::InvalidTss(_) => 10,
+            Exception::SegmentNotPresent(_) => 11,
+            Exception::StackFault(_) => 12,
+            Exception::GeneralProtection(_) => 13,
+            Exception::PageFault(_) => 14,
+            Exception::AlignmentCheck => 17,
+        }
+    }
+
+    pub fn error_code(self) -> Option<u16> {
+        match self {
+            Exception::DoubleFault | Exception::AlignmentCheck => Some(0),
+            Exception::InvalidTss(code)
+            | Exception::SegmentNotPresent(code)
+            | Exception::StackFault(code)
+            | Exception::GeneralProtection(code)
+            | Exception::PageFault(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    pub fn is_contributory(self) -> bool {
+        matches!(
+            self,
+            Exception::DivideError
+                | Exception::InvalidTss(_)
+                | Exception::SegmentNotPresent(_)
+                | Exception::StackFault(_)
+                | Exception::GeneralProtection(_)
+        )
+    }
+
+    /// Whether `fault` while delivering `self` turns into a double fault.
+    /// The 386 adds page faults as their own class: a page fault followed
+    /// by another page fault or a contributory fault is a double fault.
+    fn double_faults_with(self, fault: Exception) -> bool {
+        let page_fault = matches!(self, Exception::PageFault(_));
+        let fault_is_serious = fault.is_contributory() || matches!(fault, Exception::PageFault(_));
+        (self.is_contributory() && fault.is_contributory()) || (page_fault && fault_is_serious)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cpu386 {
+    pub model: Model,
+    pub regs: Registers,
+    pub opcode: u8,
+    pub halted: bool,
+    /// Set by a fault while delivering a double fault, like the 286.
+    pub shutdown: bool,
+    pub seg_override: Option<SegReg>,
+    pub rep_state: Option<RepType>,
+    /// Operand and address size of the current instruction, after the 66h
+    /// and 67h prefixes have flipped the code segment's default.
+    pub op32: bool,
+    pub addr32: bool,
+    pub interrupt_shadow: bool,
+    pub tlb: Tlb,
+    pub instr_eip: u32,
+    pub instr_esp: u32,
+    pub instr_eflags: Flags,
+    pub instr_cs: SegmentRegister,
+    pub instr_ss: SegmentRegister,
+}
+
+impl Cpu386 {
+    pub fn new() -> Cpu386 {
+        Cpu386::with_model(Model::I386DX)
+    }
+
+    pub fn with_model(model: Model) -> Cpu386 {
+        let mut regs = Registers::new();
+        if model.is_486() {
+            // The 486 comes out of reset with its cache disabled.
+            regs.cr0 |= Cr0::CACHE_DISABLE | Cr0::NOT_WRITE_THROUGH;
+        }
+        Cpu386 {
+            model,
+            regs,
+            ..Default::default()
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Cpu386::with_model(self.model);
+    }
+
+    pub fn phys_read_byte<T: Cpu386Context>(&mut self, ctx: &mut T, addr: u32) -> u8 {
+        ctx.mem_read_byte(addr & ctx.a20_mask())
+    }
+
+    pub fn phys_write_byte<T: Cpu386Context>(&mut self, ctx: &mut T, addr: u32, value: u8) {
+        ctx.mem_write_byte(addr & ctx.a20_mask(), value)
+    }
+
+    /// Reads a byte of a descriptor table or TSS by linear address.
+    pub fn read_system8<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        linear: u32,
+    ) -> Result<u8, Exception> {
+        let addr = self.translate(ctx, linear, PageAccess::Read, false)?;
+        Ok(self.phys_read_byte(ctx, addr))
+    }
+
+    fn read_system32<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        linear: u32,
+    ) -> Result<u32, Exception> {
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read_system8(ctx, linear.wrapping_add(i as u32))?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Checks that `size` bytes at `offset` fit in the segment and returns
+    /// the linear address. Protected mode also checks the access rights;
+    /// virtual-8086 mode segments are always 64K read/write.
+    fn linear_addr(
+        &self,
+        seg: SegReg,
+        offset: u32,
+        size: u32,
+        access: PageAccess,
+    ) -> Result<u32, Exception> {
+        let segment = self.regs.readseg(seg);
+        let fault = if seg == SegReg::SS {
+            Exception::StackFault(0)
+        } else {
+            Exception::GeneralProtection(0)
+        };
+        let last = offset as u64 + size as u64 - 1;
+        let expand_down = (segment.rights & 0x1c) == 0x14;
+        let in_limit = if expand_down {
+            let top = if segment.big { 0xffff_ffff } else { 0xffff };
+            offset > segment.limit && last <= top
+        } else {
+            last <= segment.limit as u64
+        };
+        if !in_limit {
+            return Err(fault);
+        }
+        let protected = self.regs.protected_mode() && !self.regs.v86_mode();
+        if protected && access != PageAccess::Execute {
+            if !segment.valid {
+                return Err(fault);
+            }
+            let code = (segment.rights & 0x08) != 0;
+            let rw = (segment.rights & 0x02) != 0;
+            let allowed = match access {
+                PageAccess::Write => !code && rw,
+                _ => !code || rw,
+            };
+            if !allowed {
+                return Err(fault);
+            }
+        }
+        Ok(segment.base.wrapping_add(offset))
+    }
+
+    fn physical_addrs<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        linear: u32,
+        size: OpSize,
+        access: PageAccess,
+    ) -> Result<[u32; 4], Exception> {
+        let user = self.regs.cpl() == 3;
+        let mut addrs = [0u32; 4];
+        for (i, addr) in addrs.iter_mut().take(size.bytes() as usize).enumerate() {
+            *addr = self.translate(ctx, linear.wrapping_add(i as u32), access, user)?;
+        }
+        Ok(addrs)
+    }
+
+    pub fn read<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        offset: u32,
+        size: OpSize,
+    ) -> Result<u32, Exception> {
+        let linear = self.linear_addr(seg, offset, size.bytes(), PageAccess::Read)?;
+        self.check_alignment(linear, size)?;
+        self.read_linear(ctx, linear, size, PageAccess::Read)
+    }
+
+    /// The 486 faults misaligned data accesses at CPL 3 when both CR0.AM
+    /// and EFLAGS.AC are set. On a 386 AC can never be set.
+    fn check_alignment(&self, linear: u32, size: OpSize) -> Result<(), Exception> {
+        if self.regs.cr0.contains(Cr0::ALIGNMENT_MASK)
+            && self.regs.eflags.contains(Flags::ALIGNMENT_CHECK)
+            && self.regs.cpl() == 3
+            && !linear.is_multiple_of(size.bytes())
+        {
+            return Err(Exception::AlignmentCheck);
+        }
+        Ok(())
+    }
+
+    fn read_linear<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        linear: u32,
+        size: OpSize,
+        access: PageAccess,
+    ) -> Result<u32, Exception> {
+        let addrs = self.physical_addrs(ctx, linear, size, access)?;
+        let mut value = 0;
+        for (i, addr) in addrs.iter().take(size.bytes() as usize).enumerate() {
+            value |= (self.phys_read_byte(ctx, *addr) as u32) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    /// Every byte is translated before any is written, so a page fault
+    /// halfway through leaves memory untouched.
+    pub fn write<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        offset: u32,
+        size: OpSize,
+        value: u32,
+    ) -> Result<(), Exception> {
+        let linear = self.linear_addr(seg, offset, size.bytes(), PageAccess::Write)?;
+        self.check_alignment(linear, size)?;
+        let addrs = self.physical_addrs(ctx, linear, size, PageAccess::Write)?;
+        for (i, addr) in addrs.iter().take(size.bytes() as usize).enumerate() {
+            self.phys_write_byte(ctx, *addr, (value >> (i * 8)) as u8);
+        }
+        Ok(())
+    }
+
+    fn fetch<T: Cpu386Context>(&mut self, ctx: &mut T, size: OpSize) -> Result<u32, Exception> {
+        let linear =
+            self.linear_addr(SegReg::CS, self.regs.eip, size.bytes(), PageAccess::Execute)?;
+        let value = self.read_linear(ctx, linear, size, PageAccess::Execute)?;
+        self.set_eip(self.regs.eip.wrapping_add(size.bytes()));
+        Ok(value)
+    }
+
+    pub fn fetch8<T: Cpu386Context>(&mut self, ctx: &mut T) -> Result<u8, Exception> {
+        Ok(self.fetch(ctx, OpSize::Byte)? as u8)
+    }
+
+    pub fn fetch16<T: Cpu386Context>(&mut self, ctx: &mut T) -> Result<u16, Exception> {
+        Ok(self.fetch(ctx, OpSize::Word)? as u16)
+    }
+
+    pub fn fetch32<T: Cpu386Context>(&mut self, ctx: &mut T) -> Result<u32, Exception> {
+        self.fetch(ctx, OpSize::Dword)
+    }
+
+    /// Immediates are the operand size, except that there are no 32-bit
+    /// immediates for byte operations.
+    fn fetch_imm<T: Cpu386Context>(&mut self, ctx: &mut T, size: OpSize) -> Result<u32, Exception> {
+        self.fetch(ctx, size)
+    }
+
+    /// 16-bit code segments wrap IP at 64K.
+    fn set_eip(&mut self, eip: u32) {
+        self.regs.eip = if self.regs.readseg(SegReg::CS).big {
+            eip
+        } else {
+            eip & 0xffff
+        };
+    }
+
+    fn stack_pointer(&self) -> u32 {
+        if self.regs.readseg(SegReg::SS).big {
+            self.regs.read32(4)
+        } else {
+            self.regs.read16(4) as u32
+        }
+    }
+
+    fn set_stack_pointer(&mut self, value: u32) {
+        if self.regs.readseg(SegReg::SS).big {
+            self.regs.write32(4, value);
+        } else {
+            self.regs.write16(4, value as u16);
+        }
+    }
+
+    pub fn push<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        value: u32,
+        size: OpSize,
+    ) -> Result<(), Exception> {
+        let mut sp = self.stack_pointer().wrapping_sub(size.bytes());
+        if !self.regs.readseg(SegReg::SS).big {
+            sp &= 0xffff;
+        }
+        self.write(ctx, SegReg::SS, sp, size, value)?;
+        self.set_stack_pointer(sp);
+        Ok(())
+    }
+
+    pub fn pop<T: Cpu386Context>(&mut self, ctx: &mut T, size: OpSize) -> Result<u32, Exception> {
+        let sp = self.stack_pointer();
+        let value = self.read(ctx, SegReg::SS, sp, size)?;
+        self.set_stack_pointer(sp.wrapping_add(size.bytes()));
+        Ok(value)
+    }
+
+    pub fn load_segment<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        seg: SegReg,
+        selector: u16,
+    ) -> Result<(), Exception> {
+        if self.regs.protected_mode() && !self.regs.v86_mode() {
+            self.load_protected_segment(ctx, seg, selector)?;
+        } else {
+            self.regs.writeseg_real(seg, selector);
+        }
+        if seg == SegReg::SS {
+            self.interrupt_shadow = true;
+        }
+        Ok(())
+    }
+
+    /// POPF and IRET never change VM. IOPL can only be changed at CPL 0
+    /// and IF only when CPL <= IOPL. A 16-bit write leaves the upper half
+    /// alone.
+    fn write_flags(&mut self, value: u32, size: OpSize) {
+        let mut mask = self.model.writable_flags();
+        if self.regs.cpl() > 0 {
+            mask &= !Flags::IOPL.bits();
+        }
+        if self.regs.protected_mode() && self.regs.cpl() > self.regs.iopl() {
+            mask &= !Flags::INTERRUPT.bits();
+        }
+        if size != OpSize::Dword {
+            mask &= 0xffff;
+        }
+        let current = self.regs.eflags.bits();
+        self.regs.eflags = Flags::from_bits_truncate((value & mask) | (current & !mask));
+    }
+
+    fn check_io_privilege(&self) -> Result<(), Exception> {
+        if self.regs.protected_mode() && self.regs.cpl() > self.regs.iopl() {
+            return Err(Exception::GeneralProtection(0));
+        }
+        Ok(())
+    }
+
+    /// Transfers control through the interrupt vector table in real mode
+    /// or the IDT in protected mode.
+    pub fn interrupt<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        vector: u8,
+        error_code: Option<u16>,
+        software: bool,
+    ) -> Result<(), Exception> {
+        if self.regs.protected_mode() {
+            return self.protected_interrupt(ctx, vector, error_code, software);
+        }
+        let offset = vector as u32 * 4;
+        if offset + 3 > self.regs.idtr.limit as u32 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let ip = self.read_linear(
+            ctx,
+            self.regs.idtr.base + offset,
+            OpSize::Word,
+            PageAccess::Read,
+        )?;
+        let cs = self.read_linear(
+            ctx,
+            self.regs.idtr.base + offset + 2,
+            OpSize::Word,
+            PageAccess::Read,
+        )?;
+        let flags = self.regs.read_flags();
+        let old_cs = self.regs.readseg(SegReg::CS).selector;
+        let old_ip = self.regs.eip;
+        self.push(ctx, flags, OpSize::Word)?;
+        self.push(ctx, old_cs as u32, OpSize::Word)?;
+        self.push(ctx, old_ip, OpSize::Word)?;
+        self.regs.eflags.remove(Flags::INTERRUPT | Flags::TRAP);
+        self.regs.writeseg_real(SegReg::CS, cs as u16);
+        self.regs.eip = ip;
+        Ok(())
+    }
+
+    /// Delivers an interrupt through an interrupt or trap gate. Going to
+    /// a more privileged level, or out of virtual-8086 mode, switches to
+    /// the stack for that level from the TSS.
+    fn protected_interrupt<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        vector: u8,
+        error_code: Option<u16>,
+        software: bool,
+    ) -> Result<(), Exception> {
+        let idt_fault = Exception::GeneralProtection(vector as u16 * 8 + 2);
+        let offset = vector as u32 * 8;
+        if offset + 7 > self.regs.idtr.limit as u32 {
+            return Err(idt_fault);
+        }
+        let mut bytes = [0u8; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read_system8(ctx, self.regs.idtr.base + offset + i as u32)?;
+        }
+        let gate = descriptor::Descriptor::from_bytes(bytes);
+        if gate.is_segment() || !matches!(gate.system_type(), 6 | 7 | 0xe | 0xf) {
+            return Err(idt_fault);
+        }
+        let cpl = self.regs.cpl();
+        if software && gate.dpl() < cpl {
+            return Err(idt_fault);
+        }
+        if !gate.present() {
+            return Err(Exception::SegmentNotPresent(vector as u16 * 8 + 2));
+        }
+        let selector = gate.gate_selector();
+        let code = self.read_descriptor(ctx, selector)?;
+        if !code.is_code() {
+            return Err(Exception::GeneralProtection(selector & 0xfffc));
+        }
+        if !code.present() {
+            return Err(Exception::SegmentNotPresent(selector & 0xfffc));
+        }
+        let from_v86 = self.regs.v86_mode();
+        let dpl = code.dpl();
+        if dpl > cpl || (from_v86 && dpl != 0) {
+            return Err(Exception::GeneralProtection(selector & 0xfffc));
+        }
+        let size = if (gate.system_type() & 8) != 0 {
+            OpSize::Dword
+        } else {
+            OpSize::Word
+        };
+        let flags = self.regs.read_flags();
+        let old_cs = self.regs.readseg(SegReg::CS).selector as u32;
+        let old_eip = self.regs.eip;
+        let old_ss = self.regs.readseg(SegReg::SS).selector as u32;
+        let old_esp = self.regs.read32(4);
+
+        self.regs.eflags.remove(Flags::VIRTUAL_8086);
+        self.regs
+            .setseg(SegReg::CS, code.to_cache((selector & 0xfffc) | dpl));
+        if dpl < cpl {
+            let tss = self.regs.tr.base + 4 + dpl as u32 * 8;
+            let esp = self.read_system32(ctx, tss)?;
+            let ss = self.read_system32(ctx, tss + 4)? as u16;
+            if (ss & 3) != dpl {
+                return Err(Exception::InvalidTss(ss & 0xfffc));
+            }
+            self.load_protected_segment(ctx, SegReg::SS, ss)?;
+            self.regs.write32(4, esp);
+            if from_v86 {
+                self.leave_v86(ctx, size)?;
+            }
+            self.push(ctx, old_ss, size)?;
+            self.push(ctx, old_esp, size)?;
+        }
+        self.push(ctx, flags, size)?;
+        self.push(ctx, old_cs, size)?;
+        self.push(ctx, old_eip, size)?;
+        if let Some(code) = error_code {
+            self.push(ctx, code as u32, size)?;
+        }
+        self.regs.eip = gate.gate_offset();
+        self.regs
+            .eflags
+            .remove(Flags::TRAP | Flags::NESTED_TASK | Flags::RESUME);
+        if (gate.system_type() & 1) == 0 {
+            self.regs.eflags.remove(Flags::INTERRUPT);
+        }
+        Ok(())
+    }
+
+    fn iret<T: Cpu386Context>(&mut self, ctx: &mut T) -> Result<(), Exception> {
+        let size = self.word_size();
+        if self.regs.v86_mode() {
+            self.check_v86_iopl()?;
+        } else if self.regs.protected_mode() {
+            return self.protected_iret(ctx, size);
+        }
+        let eip = self.pop(ctx, size)?;
+        let cs = self.pop(ctx, size)? as u16;
+        let flags = self.pop(ctx, size)?;
+        self.write_flags(flags, size);
+        self.regs.writeseg_real(SegReg::CS, cs);
+        self.set_eip(eip);
+        Ok(())
+    }
+
+    fn protected_iret<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        size: OpSize,
+    ) -> Result<(), Exception> {
+        if self.regs.eflags.contains(Flags::NESTED_TASK) {
+            error!(target: "cpu", "IRET to a nested task isn't supported on the 386 yet");
+            return Err(Exception::GeneralProtection(0));
+        }
+        let cpl = self.regs.cpl();
+        let eip = self.pop(ctx, size)?;
+        let cs = self.pop(ctx, size)? as u16;
+        let flags = self.pop(ctx, size)?;
+        if size == OpSize::Dword && (flags & Flags::VIRTUAL_8086.bits()) != 0 && cpl == 0 {
+            return self.return_to_v86(ctx, eip, cs, flags);
+        }
+        let rpl = cs & 3;
+        if rpl < cpl {
+            return Err(Exception::GeneralProtection(cs & 0xfffc));
+        }
+        let outer = if rpl > cpl {
+            let esp = self.pop(ctx, size)?;
+            let ss = self.pop(ctx, size)? as u16;
+            Some((ss, esp))
+        } else {
+            None
+        };
+        self.write_flags(flags, size);
+        self.load_protected_segment(ctx, SegReg::CS, cs)?;
+        if let Some((ss, esp)) = outer {
+            self.load_protected_segment(ctx, SegReg::SS, ss)?;
+            self.set_stack_pointer(esp);
+        }
+        self.set_eip(eip);
+        Ok(())
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.regs.eflags.contains(Flags::INTERRUPT) && !self.interrupt_shadow
+    }
+
+    /// Delivers a hardware interrupt between instructions. The caller is
+    /// responsible for checking `interrupts_enabled` first.
+    pub fn raise_interrupt<T: Cpu386Context>(&mut self, ctx: &mut T, vector: u8) {
+        self.halted = false;
+        if let Err(exception) = self.interrupt(ctx, vector, None, false) {
+            self.deliver_exception(ctx, exception);
+        }
+    }
+
+    fn deliver_exception<T: Cpu386Context>(&mut self, ctx: &mut T, exception: Exception) {
+        trace!(target: "cpu", "Exception {:?}", exception);
+        let mut current = exception;
+        loop {
+            let fault = match self.interrupt(ctx, current.vector(), current.error_code(), false) {
+                Ok(()) => return,
+                Err(fault) => fault,
+            };
+            if current == Exception::DoubleFault {
+                error!(
+                    target: "cpu",
+                    "{:?} while delivering a double fault, shutting down",
+                    fault
+                );
+                self.shutdown = true;
+                return;
+            }
+            debug!(target: "cpu", "{:?} while delivering {:?}", fault, current);
+            current = if current.double_faults_with(fault) {
+                Exception::DoubleFault
+            } else {
+                fault
+            };
+        }
+    }
+
+    pub fn tick<T: Cpu386Context>(&mut self, ctx: &mut T) -> usize {
+        if self.halted || self.shutdown {
+            return 2;
+        }
+        self.instr_eip = self.regs.eip;
+        self.instr_esp = self.regs.read32(4);
+        self.instr_eflags = self.regs.eflags;
+        self.instr_cs = self.regs.readseg(SegReg::CS);
+        self.instr_ss = self.regs.readseg(SegReg::SS);
+        self.seg_override = None;
+        self.rep_state = None;
+        self.interrupt_shadow = false;
+        let trap = self.regs.eflags.contains(Flags::TRAP);
+        match self.execute(ctx) {
+            Ok(()) => {
+                if trap {
+                    self.raise_interrupt(ctx, 1);
+                }
+            }
+            Err(exception) => {
+                self.regs.eip = self.instr_eip;
+                self.regs.write32(4, self.instr_esp);
+                self.regs.eflags = self.instr_eflags;
+                self.regs.setseg(SegReg::CS, self.instr_cs);
+                self.regs.setseg(SegReg::SS, self.instr_ss);
+                self.deliver_exception(ctx, exception);
+            }
+        }
+        2
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        let flags = self.regs.eflags;
+        let result = match (cc >> 1) & 7 {
+            0 => flags.contains(Flags::OVERFLOW),
+            1 => flags.contains(Flags::CARRY),
+            2 => flags.contains(Flags::ZERO),
+            3 => flags.contains(Flags::CARRY) || flags.contains(Flags::ZERO),
+            4 => flags.contains(Flags::SIGN),
+            5 => flags.contains(Flags::PARITY),
+            6 => flags.contains(Flags::SIGN) != flags.contains(Flags::OVERFLOW),
+            _ => {
+                flags.contains(Flags::ZERO)
+                    || (flags.contains(Flags::SIGN) != flags.contains(Flags::OVERFLOW))
+            }
+        };
+        result != ((cc & 1) != 0)
+    }
+
+    /// Relative jumps truncate to 16 bits with a 16-bit operand size, even
+    /// in a 32-bit code segment.
+    fn jump_relative(&mut self, offset: u32) {
+        let eip = self.regs.eip.wrapping_add(offset);
+        self.regs.eip = if self.op32 { eip } else { eip & 0xffff };
+    }
+
+    fn fetch_rel<T: Cpu386Context>(&mut self, ctx: &mut T) -> Result<u32, Exception> {
+        if self.op32 {
+            self.fetch32(ctx)
+        } else {
+            Ok(self.fetch16(ctx)? as i16 as u32)
+        }
+    }
+
+    fn index_width(&self) -> OpSize {
+        if self.addr32 {
+            OpSize::Dword
+        } else {
+            OpSize::Word
+        }
+    }
+
+    fn advance_index(&mut self, reg: u8, size: OpSize) {
+        let step = if self.regs.eflags.contains(Flags::DIRECTION) {
+            0u32.wrapping_sub(size.bytes())
+        } else {
+            size.bytes()
+        };
+        let width = self.index_width();
+        let value = self.read_reg(reg, width).wrapping_add(step);
+        self.write_reg(reg, width, value);
+    }
+
+    /// MOVS, STOS and LODS. With a REP prefix EIP is moved back to the
+    /// prefix until the count runs out, like on the 286.
+    fn string_op<T: Cpu386Context>(&mut self, ctx: &mut T, opcode: u8) -> Result<(), Exception> {
+        let width = self.index_width();
+        if self.rep_state.is_some() && self.read_reg(1, width) == 0 {
+            return Ok(());
+        }
+        let size = self.size_from_opcode(opcode);
+        let si = self.read_reg(6, width);
+        let di = self.read_reg(7, width);
+        let src_seg = self.seg_override.unwrap_or(SegReg::DS);
+        match opcode {
+            0xa4 | 0xa5 => {
+                trace!(target: "cpu", "movs");
+                let value = self.read(ctx, src_seg, si, size)?;
+                self.write(ctx, SegReg::ES, di, size, value)?;
+                self.advance_index(6, size);
+                self.advance_index(7, size);
+            }
+            0xaa | 0xab => {
+                trace!(target: "cpu", "stos");
+                let value = self.read_reg(0, size);
+                self.write(ctx, SegReg::ES, di, size, value)?;
+                self.advance_index(7, size);
+            }
+            _ => {
+                trace!(target: "cpu", "lods");
+                let value = self.read(ctx, src_seg, si, size)?;
+                self.write_reg(0, size, value);
+                self.advance_index(6, size);
+            }
+        }
+        if self.rep_state.is_some() {
+            let count = self.read_reg(1, width).wrapping_sub(1);
+            self.write_reg(1, width, count);
+            if count != 0 {
+                self.regs.eip = self.instr_eip;
+            }
+        }
+        Ok(())
+    }
+
+    fn privileged(&self) -> Result<(), Exception> {
+        if self.regs.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        Ok(())
+    }
+
+    fn far_jump<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        selector: u16,
+        offset: u32,
+    ) -> Result<(), Exception> {
+        self.load_segment(ctx, SegReg::CS, selector)?;
+        self.set_eip(offset);
+        Ok(())
+    }
+
+    fn write_control_register(&mut self, num: u8, value: u32) -> Result<(), Exception> {
+        match num {
+            0 => {
+                let cr0 = (Cr0::from_bits_truncate(value) & self.model.writable_cr0())
+                    | Cr0::EXTENSION_TYPE;
+                if cr0.contains(Cr0::PAGING) && !cr0.contains(Cr0::PROTECTION_ENABLE) {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                if cr0.contains(Cr0::PAGING) != self.regs.paging() {
+                    self.tlb.flush();
+                }
+                self.regs.cr0 = cr0;
+            }
+            2 => self.regs.cr2 = value,
+            3 => {
+                self.regs.cr3 = value & 0xffff_f000;
+                self.tlb.flush();
+            }
+            _ => return Err(Exception::InvalidOpcode),
+        }
+        Ok(())
+    }
+
+    fn read_control_register(&self, num: u8) -> Result<u32, Exception> {
+        match num {
+            0 => Ok(self.regs.cr0.bits()),
+            2 => Ok(self.regs.cr2),
+            3 => Ok(self.regs.cr3),
+            _ => Err(Exception::InvalidOpcode),
+        }
+    }
+
+    fn execute_0f<T: Cpu386Context>(&mut self, ctx: &mut T) -> Result<(), Exception> {
+        let opcode = self.fetch8(ctx)?;
+        match opcode {
+            0x00 => {
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                if !self.regs.protected_mode() || self.regs.v86_mode() {
+                    return Err(Exception::InvalidOpcode);
+                }
+                match params.reg {
+                    1 => {
+                        trace!(target: "cpu", "str");
+                        let selector = self.regs.tr.selector as u32;
+                        self.write_rm(ctx, params.rm, OpSize::Word, selector)?;
+                    }
+                    3 => {
+                        trace!(target: "cpu", "ltr");
+                        self.privileged()?;
+                        let selector = self.read_rm(ctx, params.rm, OpSize::Word)? as u16;
+                        self.load_task_register(ctx, selector)?;
+                    }
+                    _ => return Err(Exception::InvalidOpcode),
+                }
+            }
+            0x01 => {
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let (seg, offset) = match params.rm {
+                    Operand::Address(seg, offset) => (seg, offset),
+                    Operand::Register(_) => return Err(Exception::InvalidOpcode),
+                };
+                match params.reg {
+                    2 | 3 => {
+                        trace!(target: "cpu", "lgdt/lidt");
+                        self.privileged()?;
+                        let limit = self.read(ctx, seg, offset, OpSize::Word)? as u16;
+                        let mut base =
+                            self.read(ctx, seg, offset.wrapping_add(2), OpSize::Dword)?;
+                        if !self.op32 {
+                            base &= 0xff_ffff;
+                        }
+                        let table = TableRegister { base, limit };
+                        if params.reg == 2 {
+                            self.regs.gdtr = table;
+                        } else {
+                            self.regs.idtr = table;
+                        }
+                    }
+                    7 if self.model.is_486() => {
+                        trace!(target: "cpu", "invlpg");
+                        self.privileged()?;
+                        let linear = self.regs.readseg(seg).base.wrapping_add(offset);
+                        self.tlb.flush_page(linear);
+                    }
+                    _ => return Err(Exception::InvalidOpcode),
+                }
+            }
+            0x06 => {
+                trace!(target: "cpu", "clts");
+                self.privileged()?;
+                self.regs.cr0.remove(Cr0::TASK_SWITCHED);
+            }
+            0x20 | 0x22 => {
+                trace!(target: "cpu", "mov crN");
+                let modrm = self.fetch8(ctx)?;
+                self.privileged()?;
+                let reg = (modrm >> 3) & 7;
+                if opcode == 0x20 {
+                    let value = self.read_control_register(reg)?;
+                    self.regs.write32(modrm & 7, value);
+                } else {
+                    self.write_control_register(reg, self.regs.read32(modrm & 7))?;
+                }
+            }
+            0x80..=0x8f => {
+                trace!(target: "cpu", "jcc near");
+                let offset = self.fetch_rel(ctx)?;
+                if self.condition(opcode & 0xf) {
+                    self.jump_relative(offset);
+                }
+            }
+            0xa0 | 0xa8 => {
+                trace!(target: "cpu", "push fs/gs");
+                let seg = if opcode == 0xa0 {
+                    SegReg::FS
+                } else {
+                    SegReg::GS
+                };
+                let selector = self.regs.readseg(seg).selector as u32;
+                self.push(ctx, selector, self.word_size())?;
+            }
+            0xa1 | 0xa9 => {
+                trace!(target: "cpu", "pop fs/gs");
+                let seg = if opcode == 0xa1 {
+                    SegReg::FS
+                } else {
+                    SegReg::GS
+                };
+                let selector = self.pop(ctx, self.word_size())? as u16;
+                self.load_segment(ctx, seg, selector)?;
+            }
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                trace!(target: "cpu", "movzx/movsx");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let src_size = if (opcode & 1) != 0 {
+                    OpSize::Word
+                } else {
+                    OpSize::Byte
+                };
+                let mut value = self.read_rm(ctx, params.rm, src_size)?;
+                if opcode >= 0xbe && (value & src_size.sign()) != 0 {
+                    value |= !src_size.mask();
+                }
+                self.write_reg(params.reg, self.word_size(), value);
+            }
+            _ => return self.execute_486(ctx, opcode),
+        }
+        Ok(())
+    }
+
+    fn execute<T: Cpu386Context>(&mut self, ctx: &mut T) -> Result<(), Exception> {
+        let big = self.regs.readseg(SegReg::CS).big;
+        self.op32 = big;
+        self.addr32 = big;
+        let opcode = loop {
+            let opcode = self.fetch8(ctx)?;
+            match opcode {
+                0x26 => self.seg_override = Some(SegReg::ES),
+                0x2e => self.seg_override = Some(SegReg::CS),
+                0x36 => self.seg_override = Some(SegReg::SS),
+                0x3e => self.seg_override = Some(SegReg::DS),
+                0x64 => self.seg_override = Some(SegReg::FS),
+                0x65 => self.seg_override = Some(SegReg::GS),
+                0x66 => self.op32 = !big,
+                0x67 => self.addr32 = !big,
+                0xf0 => {}
+                0xf2 => self.rep_state = Some(RepType::REPNE),
+                0xf3 => self.rep_state = Some(RepType::REPE),
+                _ => break opcode,
+            }
+        };
+        self.opcode = opcode;
+        match opcode {
+            0x00..=0x3f if (opcode & 7) < 6 => {
+                trace!(target: "cpu", "alu");
+                let op = AluOp::from_num(opcode >> 3);
+                let size = self.size_from_opcode(opcode);
+                match opcode & 7 {
+                    0..=3 => {
+                        let modrm = self.fetch8(ctx)?;
+                        let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                        let rm = self.read_rm(ctx, params.rm, size)?;
+                        let reg = self.read_reg(params.reg, size);
+                        if (opcode & 2) == 0 {
+                            let result = self.alu(op, rm, reg, size);
+                            if op != AluOp::Cmp {
+                                self.write_rm(ctx, params.rm, size, result)?;
+                            }
+                        } else {
+                            let result = self.alu(op, reg, rm, size);
+                            if op != AluOp::Cmp {
+                                self.write_reg(params.reg, size, result);
+                            }
+                        }
+                    }
+                    _ => {
+                        let imm = self.fetch_imm(ctx, size)?;
+                        let acc = self.read_reg(0, size);
+                        let result = self.alu(op, acc, imm, size);
+                        if op != AluOp::Cmp {
+                            self.write_reg(0, size, result);
+                        }
+                    }
+                }
+            }
+            0x06 | 0x0e | 0x16 | 0x1e => {
+                trace!(target: "cpu", "push seg");
+                let selector = self.regs.readseg(SegReg::from_num(opcode >> 3).unwrap());
+                self.push(ctx, selector.selector as u32, self.word_size())?;
+            }
+            0x07 | 0x17 | 0x1f => {
+                trace!(target: "cpu", "pop seg");
+                let selector = self.pop(ctx, self.word_size())? as u16;
+                self.load_segment(ctx, SegReg::from_num(opcode >> 3).unwrap(), selector)?;
+            }
+            0x0f => self.execute_0f(ctx)?,
+            0x40..=0x47 => {
+                trace!(target: "cpu", "inc reg");
+                let size = self.word_size();
+                let value = self.read_reg(opcode & 7, size);
+                let result = self.inc(value, size);
+                self.write_reg(opcode & 7, size, result);
+            }
+            0x48..=0x4f => {
+                trace!(target: "cpu", "dec reg");
+                let size = self.word_size();
+                let value = self.read_reg(opcode & 7, size);
+                let result = self.dec(value, size);
+                self.write_reg(opcode & 7, size, result);
+            }
+            0x50..=0x57 => {
+                trace!(target: "cpu", "push reg");
+                let size = self.word_size();
+                let value = self.read_reg(opcode & 7, size);
+                self.push(ctx, value, size)?;
+            }
+            0x58..=0x5f => {
+                trace!(target: "cpu", "pop reg");
+                let size = self.word_size();
+                let value = self.pop(ctx, size)?;
+                self.write_reg(opcode & 7, size, value);
+            }
+            0x68 => {
+                trace!(target: "cpu", "push imm");
+                let size = self.word_size();
+                let value = self.fetch_imm(ctx, size)?;
+                self.push(ctx, value, size)?;
+            }
+            0x6a => {
+                trace!(target: "cpu", "push imm8");
+                let value = self.fetch8(ctx)? as i8 as u32;
+                self.push(ctx, value, self.word_size())?;
+            }
+            0x70..=0x7f => {
+                trace!(target: "cpu", "jcc short");
+                let offset = self.fetch8(ctx)? as i8 as u32;
+                if self.condition(opcode & 0xf) {
+                    self.jump_relative(offset);
+                }
+            }
+            0x80 | 0x81 | 0x83 => {
+                trace!(target: "cpu", "group 1");
+                let size = self.size_from_opcode(opcode);
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let imm = if opcode == 0x83 {
+                    self.fetch8(ctx)? as i8 as u32
+                } else {
+                    self.fetch_imm(ctx, size)?
+                };
+                let op = AluOp::from_num(params.reg);
+                let rm = self.read_rm(ctx, params.rm, size)?;
+                let result = self.alu(op, rm, imm, size);
+                if op != AluOp::Cmp {
+                    self.write_rm(ctx, params.rm, size, result)?;
+                }
+            }
+            0x84 | 0x85 => {
+                trace!(target: "cpu", "test rm, reg");
+                let size = self.size_from_opcode(opcode);
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let rm = self.read_rm(ctx, params.rm, size)?;
+                let reg = self.read_reg(params.reg, size);
+                self.alu(AluOp::And, rm, reg, size);
+            }
+            0x86 | 0x87 => {
+                trace!(target: "cpu", "xchg rm, reg");
+                let size = self.size_from_opcode(opcode);
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let rm = self.read_rm(ctx, params.rm, size)?;
+                let reg = self.read_reg(params.reg, size);
+                self.write_rm(ctx, params.rm, size, reg)?;
+                self.write_reg(params.reg, size, rm);
+            }
+            0x88..=0x8b => {
+                trace!(target: "cpu", "mov");
+                let size = self.size_from_opcode(opcode);
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                if (opcode & 2) == 0 {
+                    let value = self.read_reg(params.reg, size);
+                    self.write_rm(ctx, params.rm, size, value)?;
+                } else {
+                    let value = self.read_rm(ctx, params.rm, size)?;
+                    self.write_reg(params.reg, size, value);
+                }
+            }
+            0x8c => {
+                trace!(target: "cpu", "mov rm, sreg");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let seg = SegReg::from_num(params.reg).ok_or(Exception::InvalidOpcode)?;
+                let selector = self.regs.readseg(seg).selector as u32;
+                // Register destinations take the operand size, memory is
+                // always a word.
+                let size = match params.rm {
+                    Operand::Register(_) => self.word_size(),
+                    Operand::Address(..) => OpSize::Word,
+                };
+                self.write_rm(ctx, params.rm, size, selector)?;
+            }
+            0x8d => {
+                trace!(target: "cpu", "lea");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                match params.rm {
+                    Operand::Address(_, offset) => {
+                        self.write_reg(params.reg, self.word_size(), offset)
+                    }
+                    Operand::Register(_) => return Err(Exception::InvalidOpcode),
+                }
+            }
+            0x8e => {
+                trace!(target: "cpu", "mov sreg, rm");
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let seg = SegReg::from_num(params.reg).ok_or(Exception::InvalidOpcode)?;
+                if seg == SegReg::CS {
+                    return Err(Exception::InvalidOpcode);
+                }
+                let selector = self.read_rm(ctx, params.rm, OpSize::Word)? as u16;
+                self.load_segment(ctx, seg, selector)?;
+            }
+            0x8f => {
+                trace!(target: "cpu", "pop rm");
+                let modrm = self.fetch8(ctx)?;
+                let size = self.word_size();
+                let value = self.pop(ctx, size)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                self.write_rm(ctx, params.rm, size, value)?;
+            }
+            0x90 => trace!(target: "cpu", "nop"),
+            0x91..=0x97 => {
+                trace!(target: "cpu", "xchg acc, reg");
+                let size = self.word_size();
+                let acc = self.read_reg(0, size);
+                let reg = self.read_reg(opcode & 7, size);
+                self.write_reg(0, size, reg);
+                self.write_reg(opcode & 7, size, acc);
+            }
+            0x98 => {
+                trace!(target: "cpu", "cbw/cwde");
+                if self.op32 {
+                    let ax = self.regs.read16(0) as i16 as u32;
+                    self.regs.write32(0, ax);
+                } else {
+                    let al = self.regs.read8(0) as i8 as u16;
+                    self.regs.write16(0, al);
+                }
+            }
+            0x99 => {
+                trace!(target: "cpu", "cwd/cdq");
+                let size = self.word_size();
+                let negative = (self.read_reg(0, size) & size.sign()) != 0;
+                self.write_reg(2, size, if negative { 0xffff_ffff } else { 0 });
+            }
+            0x9c => {
+                trace!(target: "cpu", "pushf");
+                self.check_v86_iopl()?;
+                let flags = self.regs.read_flags() & !(Flags::VIRTUAL_8086 | Flags::RESUME).bits();
+                self.push(ctx, flags, self.word_size())?;
+            }
+            0x9d => {
+                trace!(target: "cpu", "popf");
+                self.check_v86_iopl()?;
+                let size = self.word_size();
+                let flags = self.pop(ctx, size)?;
+                self.write_flags(flags, size);
+            }
+            0xa0..=0xa3 => {
+                trace!(target: "cpu", "mov acc, moffs");
+                let size = self.size_from_opcode(opcode);
+                let offset = if self.addr32 {
+                    self.fetch32(ctx)?
+                } else {
+                    self.fetch16(ctx)? as u32
+                };
+                let seg = self.seg_override.unwrap_or(SegReg::DS);
+                if (opcode & 2) == 0 {
+                    let value = self.read(ctx, seg, offset, size)?;
+                    self.write_reg(0, size, value);
+                } else {
+                    let value = self.read_reg(0, size);
+                    self.write(ctx, seg, offset, size, value)?;
+                }
+            }
+            0xa4 | 0xa5 | 0xaa..=0xad => self.string_op(ctx, opcode)?,
+            0xa8 | 0xa9 => {
+                trace!(target: "cpu", "test acc, imm");
+                let size = self.size_from_opcode(opcode);
+                let imm = self.fetch_imm(ctx, size)?;
+                let acc = self.read_reg(0, size);
+                self.alu(AluOp::And, acc, imm, size);
+            }
+            0xb0..=0xb7 => {
+                trace!(target: "cpu", "mov reg8, imm8");
+                let value = self.fetch8(ctx)?;
+                self.regs.write8(opcode & 7, value);
+            }
+            0xb8..=0xbf => {
+                trace!(target: "cpu", "mov reg, imm");
+                let size = self.word_size();
+                let value = self.fetch_imm(ctx, size)?;
+                self.write_reg(opcode & 7, size, value);
+            }
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                trace!(target: "cpu", "group 2");
+                let size = self.size_from_opcode(opcode);
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let count = match opcode {
+                    0xc0 | 0xc1 => self.fetch8(ctx)?,
+                    0xd0 | 0xd1 => 1,
+                    _ => self.regs.read8(1),
+                };
+                let value = self.read_rm(ctx, params.rm, size)?;
+                let result = self.shift(params.reg, value, count, size);
+                self.write_rm(ctx, params.rm, size, result)?;
+            }
+            0xc2 | 0xc3 => {
+                trace!(target: "cpu", "ret near");
+                let release = if opcode == 0xc2 {
+                    self.fetch16(ctx)? as u32
+                } else {
+                    0
+                };
+                let eip = self.pop(ctx, self.word_size())?;
+                let sp = self.stack_pointer().wrapping_add(release);
+                self.set_stack_pointer(sp);
+                self.set_eip(eip);
+            }
+            0xc6 | 0xc7 => {
+                trace!(target: "cpu", "mov rm, imm");
+                let size = self.size_from_opcode(opcode);
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                let value = self.fetch_imm(ctx, size)?;
+                self.write_rm(ctx, params.rm, size, value)?;
+            }
+            0xcc => {
+                trace!(target: "cpu", "int3");
+                self.interrupt(ctx, 3, None, true)?;
+            }
+            0xcd => {
+                let vector = self.fetch8(ctx)?;
+                trace!(target: "cpu", "int {:#04x}", vector);
+                self.check_v86_iopl()?;
+                self.interrupt(ctx, vector, None, true)?;
+            }
+            0xcf => {
+                trace!(target: "cpu", "iret");
+                self.iret(ctx)?;
+            }
+            0xe4 | 0xe5 | 0xec | 0xed => {
+                trace!(target: "cpu", "in");
+                let size = self.size_from_opcode(opcode);
+                let port = if opcode < 0xe8 {
+                    self.fetch8(ctx)? as u16
+                } else {
+                    self.regs.read16(2)
+                };
+                self.check_io_privilege()?;
+                let value = match size {
+                    OpSize::Byte => ctx.io_read_byte(port) as u32,
+                    OpSize::Word => ctx.io_read_word(port) as u32,
+                    OpSize::Dword => ctx.io_read_dword(port),
+                };
+                self.write_reg(0, size, value);
+            }
+            0xe6 | 0xe7 | 0xee | 0xef => {
+                trace!(target: "cpu", "out");
+                let size = self.size_from_opcode(opcode);
+                let port = if opcode < 0xe8 {
+                    self.fetch8(ctx)? as u16
+                } else {
+                    self.regs.read16(2)
+                };
+                self.check_io_privilege()?;
+                let value = self.read_reg(0, size);
+                match size {
+                    OpSize::Byte => ctx.io_write_byte(port, value as u8),
+                    OpSize::Word => ctx.io_write_word(port, value as u16),
+                    OpSize::Dword => ctx.io_write_dword(port, value),
+                }
+            }
+            0xe8 => {
+                trace!(target: "cpu", "call near");
+                let offset = self.fetch_rel(ctx)?;
+                let eip = self.regs.eip;
+                self.push(ctx, eip, self.word_size())?;
+                self.jump_relative(offset);
+            }
+            0xe9 => {
+                trace!(target: "cpu", "jmp near");
+                let offset = self.fetch_rel(ctx)?;
+                self.jump_relative(offset);
+            }
+            0xea => {
+                trace!(target: "cpu", "jmp far");
+                let offset = if self.op32 {
+                    self.fetch32(ctx)?
+                } else {
+                    self.fetch16(ctx)? as u32
+                };
+                let selector = self.fetch16(ctx)?;
+                self.far_jump(ctx, selector, offset)?;
+            }
+            0xeb => {
+                trace!(target: "cpu", "jmp short");
+                let offset = self.fetch8(ctx)? as i8 as u32;
+                self.jump_relative(offset);
+            }
+            0xf4 => {
+                trace!(target: "cpu", "hlt");
+                self.privileged()?;
+                self.halted = true;
+            }
+            0xf5 => {
+                trace!(target: "cpu", "cmc");
+                self.regs.eflags.toggle(Flags::CARRY);
+            }
+            0xf8 => self.regs.eflags.remove(Flags::CARRY),
+            0xf9 => self.regs.eflags.insert(Flags::CARRY),
+            0xfa | 0xfb => {
+                trace!(target: "cpu", "cli/sti");
+                self.check_io_privilege()?;
+                if opcode == 0xfb {
+                    if !self.regs.eflags.contains(Flags::INTERRUPT) {
+                        self.interrupt_shadow = true;
+                    }
+                    self.regs.eflags.insert(Flags::INTERRUPT);
+                } else {
+                    self.regs.eflags.remove(Flags::INTERRUPT);
+                }
+            }
+            0xfc => self.regs.eflags.remove(Flags::DIRECTION),
+            0xfd => self.regs.eflags.insert(Flags::DIRECTION),
+            0xfe | 0xff => {
+                trace!(target: "cpu", "group 4/5");
+                let size = self.size_from_opcode(opcode);
+                let modrm = self.fetch8(ctx)?;
+                let params = self.get_opcode_params_from_modrm(ctx, modrm)?;
+                match params.reg {
+                    0 | 1 => {
+                        let value = self.read_rm(ctx, params.rm, size)?;
+                        let result = if params.reg == 0 {
+                            self.inc(value, size)
+                        } else {
+                            self.dec(value, size)
+                        };
+                        self.write_rm(ctx, params.rm, size, result)?;
+                    }
+                    2 if opcode == 0xff => {
+                        let target = self.read_rm(ctx, params.rm, size)?;
+                        let eip = self.regs.eip;
+                        self.push(ctx, eip, size)?;
+                        self.set_eip(target);
+                    }
+                    4 if opcode == 0xff => {
+                        let target = self.read_rm(ctx, params.rm, size)?;
+                        self.set_eip(target);
+                    }
+                    6 if opcode == 0xff => {
+                        let value = self.read_rm(ctx, params.rm, size)?;
+                        self.push(ctx, value, size)?;
+                    }
+                    _ => return Err(Exception::InvalidOpcode),
+                }
+            }
+            _ => {
+                trace!(target: "cpu", "Invalid opcode {:#02x}", opcode);
+                return Err(Exception::InvalidOpcode);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct TestBus {
+    ram: Vec<u8>,
+}
+
+#[cfg(test)]
+impl Cpu386Context for TestBus {
+    fn mem_read_byte(&mut self, addr: u32) -> u8 {
+        self.ram.get(addr as usize).copied().unwrap_or(0xff)
+    }
+    fn mem_write_byte(&mut self, addr: u32, value: u8) {
+        if let Some(byte) = self.ram.get_mut(addr as usize) {
+            *byte = value;
+        }
+    }
+    fn io_read_byte(&mut self, _addr: u16) -> u8 {
+        0xff
+    }
+    fn io_write_byte(&mut self, _addr: u16, _value: u8) {}
+}
+
+#[test]
+fn test_paging_and_virtual_8086_mode() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu386::new();
+    let put32 = |bus: &mut TestBus, addr: usize, value: u32| {
+        bus.ram[addr..addr + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    // GDT: flat data, flat 32-bit code, a TSS at 6000h.
+    put32(&mut bus, 0x808, 0x0000_ffff);
+    put32(&mut bus, 0x80c, 0x00cf_9200);
+    put32(&mut bus, 0x810, 0x0000_ffff);
+    put32(&mut bus, 0x814, 0x00cf_9a00);
+    put32(&mut bus, 0x818, 0x6000_0067);
+    put32(&mut bus, 0x81c, 0x0000_8900);
+    // The #GP handler is a 32-bit interrupt gate to 10h:3000h.
+    put32(&mut bus, 0x900 + 13 * 8, 0x0010_3000);
+    put32(&mut bus, 0x904 + 13 * 8, 0x0000_8e00);
+    bus.ram[0x3000] = 0xf4; // hlt
+    put32(&mut bus, 0x6004, 0x7000); // esp0
+    put32(&mut bus, 0x6008, 0x08); // ss0
+
+    // Identity map the first 4 MB and put 400000h at physical 5000h.
+    put32(&mut bus, 0x1000, 0x2007);
+    put32(&mut bus, 0x1004, 0x4007);
+    for page in 0..0x100u32 {
+        put32(&mut bus, 0x2000 + page as usize * 4, (page << 12) | 7);
+    }
+    put32(&mut bus, 0x4000, 0x5007);
+    bus.ram[0x800..0x806].copy_from_slice(&[0x1f, 0x00, 0x00, 0x08, 0x00, 0x00]);
+    bus.ram[0x840..0x846].copy_from_slice(&[0xff, 0x00, 0x00, 0x09, 0x00, 0x00]);
+
+    cpu.regs.writeseg_real(SegReg::CS, 0);
+    cpu.regs.eip = 0x100;
+    bus.ram[0x100..0x13f].copy_from_slice(&[
+        0x0f, 0x01, 0x16, 0x00, 0x08, // lgdt [800h]
+        0x0f, 0x01, 0x1e, 0x40, 0x08, // lidt [840h]
+        0x66, 0xb8, 0x00, 0x10, 0x00, 0x00, // mov eax, 1000h
+        0x0f, 0x22, 0xd8, // mov cr3, eax
+        0x66, 0xb8, 0x01, 0x00, 0x00, 0x80, // mov eax, 80000001h
+        0x0f, 0x22, 0xc0, // mov cr0, eax
+        0xb8, 0x08, 0x00, // mov ax, 8
+        0x8e, 0xd8, // mov ds, ax
+        0x66, 0xbb, 0x10, 0x00, 0x40, 0x00, // mov ebx, 400010h
+        0x66, 0xb8, 0x78, 0x56, 0x34, 0x12, // mov eax, 12345678h
+        0x67, 0x66, 0x89, 0x03, // mov [ebx], eax
+        0x67, 0x66, 0x8b, 0x0b, // mov ecx, [ebx]
+        0x66, 0x83, 0xc1, 0x01, // add ecx, 1
+        0xb8, 0x18, 0x00, // mov ax, 18h
+        0x0f, 0x00, 0xd8, // ltr ax
+    ]);
+    for _ in 0..15 {
+        cpu.tick(&mut bus);
+    }
+    assert_eq!(cpu.regs.eip, 0x13f);
+    assert!(cpu.regs.paging());
+    assert_eq!(cpu.regs.readseg(SegReg::DS).limit, 0xffff_ffff);
+    assert_eq!(&bus.ram[0x5010..0x5014], &[0x78, 0x56, 0x34, 0x12]);
+    assert_eq!(cpu.regs.read32(1), 0x1234_5679);
+    // Accessed and dirty are set in the page table entry.
+    assert_eq!(bus.ram[0x4000] & 0x60, 0x60);
+    assert_eq!(cpu.regs.tr.base, 0x6000);
+    assert_eq!(
+        cpu.translate(&mut bus, 0x80_0000, PageAccess::Read, false),
+        Err(Exception::PageFault(0))
+    );
+    assert_eq!(cpu.regs.cr2, 0x80_0000);
+
+    // IRETD into a virtual-8086 task at 0200:0010, which runs CLI with
+    // IOPL 0 and traps back to the monitor.
+    cpu.load_segment(&mut bus, SegReg::SS, 0x08).unwrap();
+    cpu.regs.write32(4, 0x8000 - 36);
+    let frame = [0x10, 0x0200, 0x0002_0002, 0x0ffe, 0x0300, 0, 0, 0, 0];
+    for (i, value) in frame.iter().enumerate() {
+        put32(&mut bus, 0x8000 - 36 + i * 4, *value);
+    }
+    bus.ram[0x2010] = 0xfa; // cli
+    cpu.load_segment(&mut bus, SegReg::CS, 0x10).unwrap();
+    cpu.regs.eip = 0x3100;
+    bus.ram[0x3100] = 0xcf; // iretd
+    cpu.tick(&mut bus);
+    assert!(cpu.regs.v86_mode());
+    assert_eq!(cpu.regs.cpl(), 3);
+    assert_eq!(cpu.regs.readseg(SegReg::CS).base, 0x2000);
+    assert_eq!(cpu.regs.read32(4), 0x0ffe);
+
+    cpu.tick(&mut bus);
+    assert!(!cpu.regs.v86_mode());
+    assert_eq!(cpu.regs.readseg(SegReg::CS).selector, 0x10);
+    assert_eq!(cpu.regs.eip, 0x3000);
+    // Error code, EIP, CS, EFLAGS, ESP, SS, ES, DS, FS, GS.
+    assert_eq!(cpu.regs.read32(4), 0x7000 - 40);
+    assert_eq!(&bus.ram[0x6fd8..0x6fe0], &[0, 0, 0, 0, 0x10, 0, 0, 0]);
+    assert_eq!(bus.ram[0x6fe6] & 0x02, 0x02);
+    assert_eq!(cpu.regs.readseg(SegReg::DS).selector, 0);
+    cpu.tick(&mut bus);
+    assert!(cpu.halted);
+}
+
+#[test]
+fn test_operand_and_address_size_prefixes() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu386::new();
+    cpu.regs.writeseg_real(SegReg::CS, 0);
+    cpu.regs.eip = 0x100;
+    cpu.regs.write32(4, 0x1000);
+    bus.ram[0x300..0x302].copy_from_slice(&[0x11, 0x22]);
+    let code = [
+        0x66, 0xb8, 0x78, 0x56, 0x34, 0x12, // mov eax, 12345678h
+        0xb8, 0xcd, 0xab, // mov ax, 0abcdh
+        0x66, 0x05, 0x01, 0x00, 0x00, 0x00, // add eax, 1
+        0x66, 0xbb, 0x00, 0x03, 0x01, 0x00, // mov ebx, 10300h
+        0x8a, 0x07, // mov al, [bx]
+        0x66, 0xbb, 0x01, 0x03, 0x00, 0x00, // mov ebx, 301h
+        0x67, 0x8a, 0x23, // mov ah, [ebx]
+        0x66, 0x50, // push eax
+        0x5a, // pop dx
+        0x66, 0x98, // cwde
+        0x67, 0x66, 0x8d, 0x44, 0x58, 0x10, // lea eax, [eax+ebx*2+10h]
+    ];
+    bus.ram[0x100..0x100 + code.len()].copy_from_slice(&code);
+
+    cpu.tick(&mut bus);
+    cpu.tick(&mut bus);
+    // A 16-bit write leaves the top of the register alone.
+    assert_eq!(cpu.regs.read32(0), 0x1234_abcd);
+    cpu.tick(&mut bus);
+    assert_eq!(cpu.regs.read32(0), 0x1234_abce);
+    // Without 67h only BX takes part in the address.
+    cpu.tick(&mut bus);
+    cpu.tick(&mut bus);
+    assert_eq!(cpu.regs.read32(0), 0x1234_ab11);
+    cpu.tick(&mut bus);
+    cpu.tick(&mut bus);
+    assert_eq!(cpu.regs.read32(0), 0x1234_2211);
+    // A dword push, half of it popped back.
+    cpu.tick(&mut bus);
+    cpu.tick(&mut bus);
+    assert_eq!(&bus.ram[0xffc..0x1000], &[0x11, 0x22, 0x34, 0x12]);
+    assert_eq!(cpu.regs.read16(2), 0x2211);
+    assert_eq!(cpu.regs.read32(4), 0x0ffe);
+    cpu.tick(&mut bus);
+    assert_eq!(cpu.regs.read32(0), 0x0000_2211);
+    cpu.tick(&mut bus);
+    assert_eq!(cpu.regs.read32(0), 0x2211 + 0x301 * 2 + 0x10);
+    assert_eq!(cpu.regs.eip, 0x100 + code.len() as u32);
+}
+
+#[test]
+fn test_32bit_string_ops() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu386::new();
+    cpu.regs.writeseg_real(SegReg::CS, 0);
+    cpu.regs.writeseg_real(SegReg::ES, 0x100);
+    cpu.regs.eip = 0x100;
+    bus.ram[0x300..0x30c].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    bus.ram[0x1308..0x130c].copy_from_slice(&[0xaa, 0xbb, 0xcc, 0xdd]);
+    let code = [
+        0x66, 0xbe, 0x00, 0x03, 0x00, 0x00, // mov esi, 300h
+        0x66, 0xbf, 0x00, 0x00, 0x00, 0x00, // mov edi, 0
+        0x66, 0xa5, // movsd
+        0x66, 0xb8, 0xef, 0xbe, 0xad, 0xde, // mov eax, 0deadbeefh
+        0x66, 0xb9, 0x03, 0x00, 0x00, 0x00, // mov ecx, 3
+        0x67, 0xf3, 0x66, 0xab, // rep stosd, counting in ECX
+        0x66, 0xad, // lodsd
+        0xfd, // std
+        0x26, 0x66, 0xad, // es: lodsd
+    ];
+    bus.ram[0x100..0x100 + code.len()].copy_from_slice(&code);
+    while cpu.regs.eip < 0x100 + code.len() as u32 {
+        cpu.tick(&mut bus);
+    }
+    assert_eq!(&bus.ram[0x1000..0x1004], &[1, 2, 3, 4]);
+    assert_eq!(
+        &bus.ram[0x1004..0x1010],
+        &[0xef, 0xbe, 0xad, 0xde].repeat(3)[..]
+    );
+    assert_eq!(cpu.regs.read32(1), 0);
+    assert_eq!(cpu.regs.read32(7), 0x10);
+    // The second LODSD reads ES:308h, then steps SI back down.
+    assert_eq!(cpu.regs.read32(0), 0xddcc_bbaa);
+    assert_eq!(cpu.regs.read32(6), 0x304);
+}
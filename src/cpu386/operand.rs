@@ -0,0 +1,284 @@
+use crate::cpu286::operand::{AddrType, DisplacementType};
+use crate::cpu286::Cpu286;
+use crate::cpu386::registers::*;
+use crate::cpu386::Cpu386;
+use crate::cpu386::Cpu386Context;
+use crate::cpu386::Exception;
+
+/// The size of an operand, picked from the opcode's width bit and the
+/// current operand size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpSize {
+    Byte,
+    Word,
+    Dword,
+}
+
+impl OpSize {
+    pub fn mask(self) -> u32 {
+        match self {
+            OpSize::Byte => 0xff,
+            OpSize::Word => 0xffff,
+            OpSize::Dword => 0xffff_ffff,
+        }
+    }
+
+    pub fn sign(self) -> u32 {
+        match self {
+            OpSize::Byte => 0x80,
+            OpSize::Word => 0x8000,
+            OpSize::Dword => 0x8000_0000,
+        }
+    }
+
+    pub fn bytes(self) -> u32 {
+        match self {
+            OpSize::Byte => 1,
+            OpSize::Word => 2,
+            OpSize::Dword => 4,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Operand {
+    Register(u8),
+    Address(SegReg, u32),
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct OpcodeParams {
+    pub reg: u8,
+    pub rm: Operand,
+}
+
+impl Cpu386 {
+    /// The operand size for the current instruction: word or dword from
+    /// the code segment's D bit and the 66h prefix.
+    pub fn word_size(&self) -> OpSize {
+        if self.op32 {
+            OpSize::Dword
+        } else {
+            OpSize::Word
+        }
+    }
+
+    /// Picks byte or the current word size from bit 0 of the opcode.
+    pub fn size_from_opcode(&self, opcode: u8) -> OpSize {
+        if (opcode & 1) != 0 {
+            self.word_size()
+        } else {
+            OpSize::Byte
+        }
+    }
+
+    fn default_seg(&self, stack: bool) -> SegReg {
+        match self.seg_override {
+            Some(segment) => segment,
+            None if stack => SegReg::SS,
+            None => SegReg::DS,
+        }
+    }
+
+    /// The 16-bit addressing forms are the same as on the 8086 and 286, so
+    /// they share the 286 decoder's tables.
+    fn modrm_address16<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        modrm: u8,
+    ) -> Result<Operand, Exception> {
+        let addr_type = Cpu286::get_addr_type_from_modrm(modrm);
+        let displacement = match Cpu286::get_disp_type_from_modrm(modrm) {
+            None => 0,
+            Some(DisplacementType::Byte) => self.fetch8(ctx)? as i8 as u16,
+            Some(DisplacementType::Word) => self.fetch16(ctx)?,
+        };
+        let bx = self.regs.read16(3);
+        let bp = self.regs.read16(5);
+        let si = self.regs.read16(6);
+        let di = self.regs.read16(7);
+        let base = match addr_type {
+            None => 0,
+            Some(AddrType::BxSi) => bx.wrapping_add(si),
+            Some(AddrType::BxDi) => bx.wrapping_add(di),
+            Some(AddrType::BpSi) => bp.wrapping_add(si),
+            Some(AddrType::BpDi) => bp.wrapping_add(di),
+            Some(AddrType::Si) => si,
+            Some(AddrType::Di) => di,
+            Some(AddrType::Bp) => bp,
+            Some(AddrType::Bx) => bx,
+        };
+        let stack = matches!(
+            addr_type,
+            Some(AddrType::BpSi) | Some(AddrType::BpDi) | Some(AddrType::Bp)
+        );
+        Ok(Operand::Address(
+            self.default_seg(stack),
+            base.wrapping_add(displacement) as u32,
+        ))
+    }
+
+    /// The 32-bit forms, with a SIB byte when rm is 4. A base of EBP or
+    /// ESP defaults to the stack segment.
+    fn modrm_address32<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        modrm: u8,
+    ) -> Result<Operand, Exception> {
+        let mode = modrm >> 6;
+        let mut stack = false;
+        let mut addr = if (modrm & 7) == 4 {
+            let sib = self.fetch8(ctx)?;
+            let index = (sib >> 3) & 7;
+            let scaled = if index == 4 {
+                0
+            } else {
+                self.regs.read32(index) << (sib >> 6)
+            };
+            let base = sib & 7;
+            let base_value = if base == 5 && mode == 0 {
+                self.fetch32(ctx)?
+            } else {
+                stack = base == 4 || base == 5;
+                self.regs.read32(base)
+            };
+            base_value.wrapping_add(scaled)
+        } else if mode == 0 && (modrm & 7) == 5 {
+            self.fetch32(ctx)?
+        } else {
+            stack = (modrm & 7) == 5;
+            self.regs.read32(modrm & 7)
+        };
+        addr = match mode {
+            1 => addr.wrapping_add(self.fetch8(ctx)? as i8 as u32),
+            2 => addr.wrapping_add(self.fetch32(ctx)?),
+            _ => addr,
+        };
+        Ok(Operand::Address(self.default_seg(stack), addr))
+    }
+
+    pub fn get_opcode_params_from_modrm<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        modrm: u8,
+    ) -> Result<OpcodeParams, Exception> {
+        let reg = (modrm & 0x38) >> 3;
+        let rm = if modrm >= 0xc0 {
+            Operand::Register(modrm & 7)
+        } else if self.addr32 {
+            self.modrm_address32(ctx, modrm)?
+        } else {
+            self.modrm_address16(ctx, modrm)?
+        };
+        Ok(OpcodeParams { reg, rm })
+    }
+
+    pub fn read_reg(&self, num: u8, size: OpSize) -> u32 {
+        match size {
+            OpSize::Byte => self.regs.read8(num) as u32,
+            OpSize::Word => self.regs.read16(num) as u32,
+            OpSize::Dword => self.regs.read32(num),
+        }
+    }
+
+    pub fn write_reg(&mut self, num: u8, size: OpSize, value: u32) {
+        match size {
+            OpSize::Byte => self.regs.write8(num, value as u8),
+            OpSize::Word => self.regs.write16(num, value as u16),
+            OpSize::Dword => self.regs.write32(num, value),
+        }
+    }
+
+    pub fn read_rm<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        operand: Operand,
+        size: OpSize,
+    ) -> Result<u32, Exception> {
+        match operand {
+            Operand::Register(reg) => Ok(self.read_reg(reg, size)),
+            Operand::Address(seg, offset) => self.read(ctx, seg, offset, size),
+        }
+    }
+
+    pub fn write_rm<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        operand: Operand,
+        size: OpSize,
+        value: u32,
+    ) -> Result<(), Exception> {
+        match operand {
+            Operand::Register(reg) => {
+                self.write_reg(reg, size, value);
+                Ok(())
+            }
+            Operand::Address(seg, offset) => self.write(ctx, seg, offset, size, value),
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::cpu386::TestBus;
+
+#[test]
+fn test_sib_addressing() {
+    let mut bus = TestBus {
+        ram: vec![0; 0x10_0000],
+    };
+    let mut cpu = Cpu386::new();
+    cpu.regs.writeseg_real(SegReg::CS, 0);
+    let values = [0x10, 0x20, 0, 0x1000, 0x2000, 0x3000, 0x4, 0x8];
+    for (reg, value) in values.iter().enumerate() {
+        cpu.regs.write32(reg as u8, *value);
+    }
+    cpu.addr32 = true;
+    let mut decode = |cpu: &mut Cpu386, bytes: &[u8]| {
+        bus.ram[0x100..0x100 + bytes.len()].copy_from_slice(bytes);
+        cpu.regs.eip = 0x101;
+        let params = cpu
+            .get_opcode_params_from_modrm(&mut bus, bytes[0])
+            .unwrap();
+        assert_eq!(cpu.regs.eip, 0x100 + bytes.len() as u32);
+        params.rm
+    };
+    // [ebx+esi*4]
+    assert_eq!(
+        decode(&mut cpu, &[0x04, 0xb3]),
+        Operand::Address(SegReg::DS, 0x1010)
+    );
+    // [esp], with no index
+    assert_eq!(
+        decode(&mut cpu, &[0x04, 0x24]),
+        Operand::Address(SegReg::SS, 0x2000)
+    );
+    // [ebp+edi*8-1]
+    assert_eq!(
+        decode(&mut cpu, &[0x44, 0xfd, 0xff]),
+        Operand::Address(SegReg::SS, 0x303f)
+    );
+    // [ecx*2+12345678h], no base
+    assert_eq!(
+        decode(&mut cpu, &[0x04, 0x4d, 0x78, 0x56, 0x34, 0x12]),
+        Operand::Address(SegReg::DS, 0x1234_5678 + 0x40)
+    );
+    // [eax+eax+disp32]
+    assert_eq!(
+        decode(&mut cpu, &[0x84, 0x00, 0x00, 0x00, 0x01, 0x00]),
+        Operand::Address(SegReg::DS, 0x1_0020)
+    );
+    // [disp32] and [ebp+disp8] without a SIB byte
+    assert_eq!(
+        decode(&mut cpu, &[0x05, 0x00, 0x80, 0x00, 0x00]),
+        Operand::Address(SegReg::DS, 0x8000)
+    );
+    assert_eq!(
+        decode(&mut cpu, &[0x45, 0x04]),
+        Operand::Address(SegReg::SS, 0x3004)
+    );
+    cpu.seg_override = Some(SegReg::ES);
+    assert_eq!(
+        decode(&mut cpu, &[0x04, 0x24]),
+        Operand::Address(SegReg::ES, 0x2000)
+    );
+}
@@ -0,0 +1,158 @@
+use crate::cpu386::registers::Cr0;
+use crate::cpu386::Cpu386;
+use crate::cpu386::Cpu386Context;
+use crate::cpu386::Exception;
+use log::trace;
+
+const PRESENT: u32 = 0x001;
+const WRITABLE: u32 = 0x002;
+const USER: u32 = 0x004;
+const ACCESSED: u32 = 0x020;
+const DIRTY: u32 = 0x040;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct TlbEntry {
+    valid: bool,
+    /// Linear address bits 31-12.
+    page: u32,
+    frame: u32,
+    writable: bool,
+    user: bool,
+    /// Whether the dirty bit is already set in the page table, so writes
+    /// through this entry don't need another walk.
+    dirty: bool,
+}
+
+/// The 386 caches 32 page translations. Ours is direct mapped on the low
+/// bits of the page number, which is close enough for software that only
+/// relies on the TLB being flushed by a CR3 load.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tlb {
+    entries: [TlbEntry; 32],
+}
+
+impl Tlb {
+    pub fn flush(&mut self) {
+        self.entries = [TlbEntry::default(); 32];
+    }
+
+    /// Drops the translation for one page, as INVLPG does on the 486.
+    pub fn flush_page(&mut self, linear: u32) {
+        let page = linear >> 12;
+        let entry = &mut self.entries[(page & 31) as usize];
+        if entry.page == page {
+            entry.valid = false;
+        }
+    }
+
+    fn lookup(&self, page: u32) -> Option<TlbEntry> {
+        let entry = self.entries[(page & 31) as usize];
+        if entry.valid && entry.page == page {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    fn insert(&mut self, entry: TlbEntry) {
+        self.entries[(entry.page & 31) as usize] = entry;
+    }
+}
+
+impl Cpu386 {
+    fn phys_read32<T: Cpu386Context>(&mut self, ctx: &mut T, addr: u32) -> u32 {
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.phys_read_byte(ctx, addr + i as u32);
+        }
+        u32::from_le_bytes(bytes)
+    }
+
+    fn phys_write32<T: Cpu386Context>(&mut self, ctx: &mut T, addr: u32, value: u32) {
+        for (i, byte) in value.to_le_bytes().iter().enumerate() {
+            self.phys_write_byte(ctx, addr + i as u32, *byte);
+        }
+    }
+
+    fn page_fault(
+        &mut self,
+        linear: u32,
+        present: bool,
+        access: PageAccess,
+        user: bool,
+    ) -> Exception {
+        trace!(target: "cpu", "Page fault at {:#010x}", linear);
+        self.regs.cr2 = linear;
+        Exception::PageFault(
+            present as u16 | ((access == PageAccess::Write) as u16) << 1 | (user as u16) << 2,
+        )
+    }
+
+    /// Turns a linear address into a physical one through the page tables
+    /// when paging is on. User mode accesses need the U/S bit in both
+    /// levels and writes from user mode need R/W. The 386 lets supervisor
+    /// code write to read-only pages; the 486 only does when CR0.WP is
+    /// clear. Descriptor table and TSS accesses are always supervisor
+    /// accesses, whatever the CPL.
+    pub fn translate<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        linear: u32,
+        access: PageAccess,
+        user: bool,
+    ) -> Result<u32, Exception> {
+        if !self.regs.paging() {
+            return Ok(linear);
+        }
+        let write = access == PageAccess::Write;
+        let check_writable = write && (user || self.regs.cr0.contains(Cr0::WRITE_PROTECT));
+        let page = linear >> 12;
+        if let Some(entry) = self.tlb.lookup(page) {
+            let allowed = (!user || entry.user) && (!check_writable || entry.writable);
+            if allowed && (!write || entry.dirty) {
+                return Ok(entry.frame | (linear & 0xfff));
+            }
+        }
+
+        let pde_addr = (self.regs.cr3 & 0xffff_f000) | ((linear >> 22) << 2);
+        let pde = self.phys_read32(ctx, pde_addr);
+        if (pde & PRESENT) == 0 {
+            return Err(self.page_fault(linear, false, access, user));
+        }
+        let pte_addr = (pde & 0xffff_f000) | (((linear >> 12) & 0x3ff) << 2);
+        let pte = self.phys_read32(ctx, pte_addr);
+        if (pte & PRESENT) == 0 {
+            return Err(self.page_fault(linear, false, access, user));
+        }
+        let writable = (pde & pte & WRITABLE) != 0;
+        let user_page = (pde & pte & USER) != 0;
+        if (user && !user_page) || (check_writable && !writable) {
+            return Err(self.page_fault(linear, true, access, user));
+        }
+
+        if (pde & ACCESSED) == 0 {
+            self.phys_write32(ctx, pde_addr, pde | ACCESSED);
+        }
+        let new_pte = pte | ACCESSED | if write { DIRTY } else { 0 };
+        if new_pte != pte {
+            self.phys_write32(ctx, pte_addr, new_pte);
+        }
+        let frame = pte & 0xffff_f000;
+        self.tlb.insert(TlbEntry {
+            valid: true,
+            page,
+            frame,
+            writable,
+            user: user_page,
+            dirty: (new_pte & DIRTY) != 0,
+        });
+        Ok(frame | (linear & 0xfff))
+    }
+}
@@ -0,0 +1,258 @@
+use bitflags::bitflags;
+
+bitflags!(
+    pub struct Flags: u32
+    {
+        const CARRY = 0x0000_0001;
+        const PARITY = 0x0000_0004;
+        const ADJUST = 0x0000_0010;
+        const ZERO = 0x0000_0040;
+        const SIGN = 0x0000_0080;
+        const TRAP = 0x0000_0100;
+        const INTERRUPT = 0x0000_0200;
+        const DIRECTION = 0x0000_0400;
+        const OVERFLOW = 0x0000_0800;
+        const IOPL = 0x0000_3000;
+        const NESTED_TASK = 0x0000_4000;
+        const RESUME = 0x0001_0000;
+        const VIRTUAL_8086 = 0x0002_0000;
+        /// 486 only. Whether AC can be toggled is how software tells a 486
+        /// from a 386.
+        const ALIGNMENT_CHECK = 0x0004_0000;
+        /// Toggleable on CPUs that have the CPUID instruction.
+        const ID = 0x0020_0000;
+        const DEFAULT = 0x0000_0002;
+    }
+);
+
+bitflags!(
+    pub struct Cr0: u32
+    {
+        const PROTECTION_ENABLE = 0x0000_0001;
+        const MONITOR_COPROCESSOR = 0x0000_0002;
+        const EMULATE_COPROCESSOR = 0x0000_0004;
+        const TASK_SWITCHED = 0x0000_0008;
+        /// Hardwired on when a 387 is present; we always report one.
+        const EXTENSION_TYPE = 0x0000_0010;
+        /// The rest are 486 only.
+        const NUMERIC_ERROR = 0x0000_0020;
+        const WRITE_PROTECT = 0x0001_0000;
+        const ALIGNMENT_MASK = 0x0004_0000;
+        const NOT_WRITE_THROUGH = 0x2000_0000;
+        const CACHE_DISABLE = 0x4000_0000;
+        const PAGING = 0x8000_0000;
+    }
+);
+
+impl Default for Flags {
+    fn default() -> Flags {
+        Flags::DEFAULT
+    }
+}
+
+impl Default for Cr0 {
+    fn default() -> Cr0 {
+        Cr0::EXTENSION_TYPE
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SegReg {
+    ES,
+    CS,
+    SS,
+    DS,
+    FS,
+    GS,
+}
+
+impl SegReg {
+    pub fn from_num(num: u8) -> Option<SegReg> {
+        match num {
+            0 => Some(SegReg::ES),
+            1 => Some(SegReg::CS),
+            2 => Some(SegReg::SS),
+            3 => Some(SegReg::DS),
+            4 => Some(SegReg::FS),
+            5 => Some(SegReg::GS),
+            _ => None,
+        }
+    }
+}
+
+/// A segment register with its descriptor cache. Unlike the 286 the base
+/// is a full 32 bits and the limit is already scaled by the granularity
+/// bit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SegmentRegister {
+    pub selector: u16,
+    pub base: u32,
+    pub limit: u32,
+    pub rights: u8,
+    /// The D/B bit: 32-bit default operand and address size for code
+    /// segments, ESP instead of SP for stack segments.
+    pub big: bool,
+    pub valid: bool,
+}
+
+impl SegmentRegister {
+    pub fn new(seg: SegReg) -> SegmentRegister {
+        SegmentRegister {
+            selector: if seg == SegReg::CS { 0xf000 } else { 0 },
+            base: if seg == SegReg::CS { 0xffff_0000 } else { 0 },
+            limit: 0xffff,
+            rights: 0x93,
+            big: false,
+            valid: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TableRegister {
+    pub base: u32,
+    pub limit: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TaskRegister {
+    pub selector: u16,
+    pub base: u32,
+    pub limit: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Registers {
+    pub eip: u32,
+    pub gprs: [u32; 8],
+    pub seg_regs: [SegmentRegister; 6],
+    pub eflags: Flags,
+    pub cr0: Cr0,
+    /// The linear address of the last page fault.
+    pub cr2: u32,
+    /// Physical address of the page directory.
+    pub cr3: u32,
+    pub gdtr: TableRegister,
+    pub idtr: TableRegister,
+    pub tr: TaskRegister,
+}
+
+impl Registers {
+    pub fn new() -> Registers {
+        Registers {
+            eip: 0xfff0,
+            gprs: [0; 8],
+            seg_regs: [
+                SegmentRegister::new(SegReg::ES),
+                SegmentRegister::new(SegReg::CS),
+                SegmentRegister::new(SegReg::SS),
+                SegmentRegister::new(SegReg::DS),
+                SegmentRegister::new(SegReg::FS),
+                SegmentRegister::new(SegReg::GS),
+            ],
+            eflags: Flags::DEFAULT,
+            cr0: Cr0::default(),
+            cr2: 0,
+            cr3: 0,
+            gdtr: TableRegister::default(),
+            idtr: TableRegister {
+                base: 0,
+                limit: 0x3ff,
+            },
+            tr: TaskRegister::default(),
+        }
+    }
+
+    pub fn protected_mode(&self) -> bool {
+        self.cr0.contains(Cr0::PROTECTION_ENABLE)
+    }
+
+    pub fn paging(&self) -> bool {
+        self.cr0.contains(Cr0::PAGING)
+    }
+
+    pub fn v86_mode(&self) -> bool {
+        self.eflags.contains(Flags::VIRTUAL_8086)
+    }
+
+    /// Virtual-8086 tasks always run at privilege level 3.
+    pub fn cpl(&self) -> u16 {
+        if self.v86_mode() {
+            3
+        } else if self.protected_mode() {
+            self.seg_regs[1].selector & 3
+        } else {
+            0
+        }
+    }
+
+    pub fn iopl(&self) -> u16 {
+        ((self.eflags & Flags::IOPL).bits() >> 12) as u16
+    }
+
+    /// AL, CL, DL, BL, then AH, CH, DH, BH, as encoded in ModR/M.
+    pub fn read8(&self, num: u8) -> u8 {
+        let reg = self.gprs[(num & 3) as usize];
+        if (num & 4) != 0 {
+            (reg >> 8) as u8
+        } else {
+            reg as u8
+        }
+    }
+
+    pub fn write8(&mut self, num: u8, value: u8) {
+        let reg = &mut self.gprs[(num & 3) as usize];
+        if (num & 4) != 0 {
+            *reg = (*reg & !0xff00) | ((value as u32) << 8);
+        } else {
+            *reg = (*reg & !0xff) | value as u32;
+        }
+    }
+
+    pub fn read16(&self, num: u8) -> u16 {
+        self.gprs[(num & 7) as usize] as u16
+    }
+
+    /// Writing a 16-bit register leaves the upper half of the 32-bit one
+    /// alone.
+    pub fn write16(&mut self, num: u8, value: u16) {
+        let reg = &mut self.gprs[(num & 7) as usize];
+        *reg = (*reg & 0xffff_0000) | value as u32;
+    }
+
+    pub fn read32(&self, num: u8) -> u32 {
+        self.gprs[(num & 7) as usize]
+    }
+
+    pub fn write32(&mut self, num: u8, value: u32) {
+        self.gprs[(num & 7) as usize] = value;
+    }
+
+    pub fn read_flags(&self) -> u32 {
+        self.eflags.bits() | 0x0002
+    }
+
+    pub fn readseg(&self, seg: SegReg) -> SegmentRegister {
+        self.seg_regs[seg as usize]
+    }
+
+    /// Loads a segment register the way real and virtual-8086 mode do.
+    pub fn writeseg_real(&mut self, seg: SegReg, value: u16) {
+        let v86 = self.v86_mode();
+        let segment = &mut self.seg_regs[seg as usize];
+        segment.selector = value;
+        segment.base = (value as u32) << 4;
+        segment.valid = true;
+        if v86 {
+            // Virtual-8086 mode reloads the whole cache, so big real mode
+            // limits left over from before don't leak into the task.
+            segment.limit = 0xffff;
+            segment.rights = if seg == SegReg::CS { 0xfb } else { 0xf3 };
+            segment.big = false;
+        }
+    }
+
+    pub fn setseg(&mut self, seg: SegReg, segment: SegmentRegister) {
+        self.seg_regs[seg as usize] = segment;
+    }
+}
@@ -0,0 +1,69 @@
+// Virtual-8086 mode: real mode code running as a CPL 3 task under a
+// protected mode monitor. Segments are formed the real mode way, and
+// anything that touches IF or the interrupt table traps to the monitor
+// unless IOPL is 3.
+use crate::cpu386::operand::OpSize;
+use crate::cpu386::registers::*;
+use crate::cpu386::Cpu386;
+use crate::cpu386::Cpu386Context;
+use crate::cpu386::Exception;
+use log::trace;
+
+impl Cpu386 {
+    /// CLI, STI, PUSHF, POPF, INT n and IRET are IOPL sensitive in
+    /// virtual-8086 mode.
+    pub fn check_v86_iopl(&self) -> Result<(), Exception> {
+        if self.regs.v86_mode() && self.regs.iopl() < 3 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        Ok(())
+    }
+
+    /// Saves the task's data segments on the monitor's stack when an
+    /// interrupt leaves virtual-8086 mode, then clears them so the
+    /// handler can't accidentally use real mode values as selectors.
+    pub fn leave_v86<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        size: OpSize,
+    ) -> Result<(), Exception> {
+        trace!(target: "cpu", "Leaving virtual-8086 mode");
+        for seg in [SegReg::GS, SegReg::FS, SegReg::DS, SegReg::ES].iter() {
+            let selector = self.regs.readseg(*seg).selector;
+            self.push(ctx, selector as u32, size)?;
+        }
+        for seg in [SegReg::GS, SegReg::FS, SegReg::DS, SegReg::ES].iter() {
+            self.regs.setseg(*seg, SegmentRegister::default());
+        }
+        Ok(())
+    }
+
+    /// The rest of an IRETD at CPL 0 whose EFLAGS image has VM set: the
+    /// task's stack and data segments come off the stack and are loaded as
+    /// real mode segments.
+    pub fn return_to_v86<T: Cpu386Context>(
+        &mut self,
+        ctx: &mut T,
+        eip: u32,
+        cs: u16,
+        eflags: u32,
+    ) -> Result<(), Exception> {
+        trace!(target: "cpu", "Entering virtual-8086 mode");
+        let esp = self.pop(ctx, OpSize::Dword)?;
+        let mut selectors = [0u16; 5];
+        for selector in selectors.iter_mut() {
+            *selector = self.pop(ctx, OpSize::Dword)? as u16;
+        }
+        self.regs.eflags = Flags::from_bits_truncate(eflags) | Flags::DEFAULT;
+        let [ss, es, ds, fs, gs] = selectors;
+        self.regs.writeseg_real(SegReg::CS, cs);
+        self.regs.writeseg_real(SegReg::SS, ss);
+        self.regs.writeseg_real(SegReg::ES, es);
+        self.regs.writeseg_real(SegReg::DS, ds);
+        self.regs.writeseg_real(SegReg::FS, fs);
+        self.regs.writeseg_real(SegReg::GS, gs);
+        self.regs.write32(4, esp);
+        self.regs.eip = eip & 0xffff;
+        Ok(())
+    }
+}
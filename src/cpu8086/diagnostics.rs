@@ -0,0 +1,114 @@
+use crate::cpu8086::registers::*;
+use crate::cpu8086::Cpu8086;
+use crate::cpu8086::Cpu8086Context;
+use crate::cpu8086::RepType;
+use crate::disasm::{self, Isa};
+use log::error;
+use std::fmt;
+
+/// Everything a user needs to file a useful report when the emulator hits
+/// something it doesn't implement yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnimplementedReport {
+    pub what: String,
+    pub cs: u16,
+    pub ip: u16,
+    pub seg_override: Option<SegReg>,
+    pub rep_state: Option<RepType>,
+    /// The 16 bytes starting 4 bytes before the instruction.
+    pub bytes: [u8; 16],
+}
+
+impl UnimplementedReport {
+    /// The instruction itself, prefixes and all.
+    pub fn instruction(&self) -> String {
+        disasm::decode(&self.bytes[4..], self.cs, self.ip, Isa::I8086).text
+    }
+}
+
+impl fmt::Display for UnimplementedReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self
+            .bytes
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| {
+                if i == 4 {
+                    format!("[{:02x}]", byte)
+                } else {
+                    format!("{:02x}", byte)
+                }
+            })
+            .collect();
+        writeln!(
+            f,
+            "Unimplemented {} at {:04x}:{:04x}: {}",
+            self.what,
+            self.cs,
+            self.ip,
+            self.instruction()
+        )?;
+        writeln!(
+            f,
+            "  prefixes: segment {:?}, rep {:?}",
+            self.seg_override, self.rep_state
+        )?;
+        writeln!(f, "  bytes: {}", bytes.join(" "))?;
+        writeln!(f)?;
+        writeln!(f, "To report this, open an issue with the following body:")?;
+        writeln!(f)?;
+        writeln!(f, "### Missing: {}", self.instruction())?;
+        writeln!(f, "- Kind: unimplemented {}", self.what)?;
+        writeln!(f, "- Address: {:04x}:{:04x}", self.cs, self.ip)?;
+        writeln!(f, "- Bytes: `{}`", bytes.join(" "))?;
+        write!(f, "- Program/disk image and what you were doing: ")
+    }
+}
+
+impl Cpu8086 {
+    pub fn unimplemented_report<T: Cpu8086Context>(
+        &self,
+        ctx: &mut T,
+        what: &str,
+    ) -> UnimplementedReport {
+        let base = (self.instr_cs as u32) << 4;
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let offset = self.instr_ip.wrapping_add(i as u16).wrapping_sub(4);
+            *byte = ctx.mem_read_byte((base + offset as u32) & 0xf_ffff);
+        }
+        UnimplementedReport {
+            what: what.to_string(),
+            cs: self.instr_cs,
+            ip: self.instr_ip,
+            seg_override: self.seg_override,
+            rep_state: self.rep_state,
+            bytes,
+        }
+    }
+
+    /// Stops emulation with a report describing the missing feature.
+    pub fn unimplemented<T: Cpu8086Context>(&self, ctx: &mut T, what: &str) -> ! {
+        let report = self.unimplemented_report(ctx, what);
+        error!(target: "cpu", "{}", report);
+        panic!("{}", report);
+    }
+}
+
+#[test]
+fn test_unimplemented_report() {
+    let mut bytes = [0; 16];
+    bytes[4..8].copy_from_slice(&[0x26, 0xff, 0x1e, 0x34]);
+    let report = UnimplementedReport {
+        what: "opcode".to_string(),
+        cs: 0xf000,
+        ip: 0xe05b,
+        seg_override: Some(SegReg::ES),
+        rep_state: None,
+        bytes,
+    };
+    assert_eq!(report.instruction(), "call far es:[0x0034]");
+    assert!(report
+        .to_string()
+        .starts_with("Unimplemented opcode at f000:e05b: call far es:[0x0034]\n"));
+}
@@ -0,0 +1,132 @@
+use crate::cpu8086::registers::*;
+use crate::cpu8086::Cpu8086;
+use crate::cpu8086::RepType;
+use std::fmt;
+
+/// The raw bytes at CS:IP when an instruction starts, along with any
+/// prefixes that were already in effect. The longest 8086 instruction
+/// without prefixes is 6 bytes, so that's all that gets captured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instruction {
+    pub opcode: u8,
+    pub bytes: [u8; 6],
+    pub seg_override: Option<SegReg>,
+    pub rep_state: Option<RepType>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    MemRead,
+    MemWrite,
+    IoRead,
+    IoWrite,
+}
+
+/// A single bus access made by the CPU. `addr` is the physical address for
+/// memory and the port number for I/O; `cs` and `ip` are those of the
+/// instruction that made the access.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub addr: u32,
+    pub size: u8,
+    pub value: u16,
+    pub cs: u16,
+    pub ip: u16,
+}
+
+pub type ExecHook = Box<dyn FnMut(&Cpu8086, u16, u16, &Instruction) + Send>;
+pub type AccessHook = Box<dyn FnMut(&Access) + Send>;
+
+/// Tooling callbacks run around each instruction and on each bus access.
+/// They aren't part of the CPU state, so cloning a CPU gives a copy
+/// without any hooks installed.
+#[derive(Default)]
+pub struct CpuHooks {
+    pub pre: Option<ExecHook>,
+    pub post: Option<ExecHook>,
+    pub access: Option<AccessHook>,
+}
+
+impl CpuHooks {
+    pub fn has_exec_hooks(&self) -> bool {
+        self.pre.is_some() || self.post.is_some()
+    }
+}
+
+impl Clone for CpuHooks {
+    fn clone(&self) -> CpuHooks {
+        CpuHooks::default()
+    }
+}
+
+impl fmt::Debug for CpuHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CpuHooks")
+            .field("pre", &self.pre.is_some())
+            .field("post", &self.post.is_some())
+            .field("access", &self.access.is_some())
+            .finish()
+    }
+}
+
+#[test]
+fn test_access_hook_reports_instruction_address() {
+    use std::sync::{Arc, Mutex};
+    let mut machine = crate::hardware::IbmPc5150Machine::new();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let log = seen.clone();
+    machine.set_access_hook(move |access| {
+        if access.kind == AccessKind::IoWrite {
+            log.lock().unwrap().push(*access);
+        }
+    });
+    machine.cpu.regs.seg_regs[1] = 0x10;
+    machine.cpu.regs.ip = 0;
+    machine.hardware.memory.ram[0x100..0x104].copy_from_slice(&[0xb0, 0x55, 0xe6, 0x80]);
+    machine.run_for_cycles(8);
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!((seen[0].addr, seen[0].value), (0x80, 0x55));
+    assert_eq!((seen[0].cs, seen[0].ip), (0x10, 2));
+}
+
+#[test]
+fn test_exec_hooks_fire_once_per_instruction() {
+    use std::sync::{Arc, Mutex};
+    let mut machine = crate::hardware::IbmPc5150Machine::new();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let pre = seen.clone();
+    machine.cpu.hooks.pre = Some(Box::new(move |_, cs, ip, instruction| {
+        pre.lock()
+            .unwrap()
+            .push(("pre", cs, ip, instruction.opcode));
+    }));
+    let post = seen.clone();
+    machine.cpu.hooks.post = Some(Box::new(move |_, cs, ip, instruction| {
+        post.lock()
+            .unwrap()
+            .push(("post", cs, ip, instruction.opcode));
+    }));
+    machine.cpu.regs.seg_regs[1] = 0;
+    machine.cpu.regs.ip = 0x100;
+    // clc; es: mov al, [bx]; rep stc; hlt
+    let code = [0xf8, 0x26, 0x8a, 0x07, 0xf3, 0xf9, 0xf4];
+    machine.hardware.memory.ram[0x100..0x107].copy_from_slice(&code);
+    // The hooks get six bytes of each instruction, which goes past the
+    // HLT, but that's not a read the watchpoint should see.
+    machine
+        .hardware
+        .memory_watch
+        .watch(0x107, 0x10f, crate::hardware::breakpoints::Watch::Read);
+    assert_eq!(
+        machine.run_instructions(10),
+        crate::hardware::StopReason::Halted
+    );
+    let expected: Vec<_> = [(0x100, 0xf8), (0x101, 0x26), (0x104, 0xf3), (0x106, 0xf4)]
+        .iter()
+        .flat_map(|&(ip, opcode)| vec![("pre", 0, ip, opcode), ("post", 0, ip, opcode)])
+        .collect();
+    assert_eq!(*seen.lock().unwrap(), expected);
+    assert_eq!(machine.hardware.memory_watch.last(), None);
+}
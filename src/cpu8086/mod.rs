@@ -1,21 +1,28 @@
 //use crate::scheduler::Jiffies;
+use hooks::*;
+use log::{debug, trace};
 use operand::*;
 use registers::*;
 
+pub mod diagnostics;
+pub mod hooks;
 pub mod operand;
 pub mod registers;
 
 pub trait Cpu8086Context {
     fn mem_read_byte(&mut self, addr: u32) -> u8;
+    /// Reads memory without anything else seeing it: no watchpoints, and
+    /// none of the side effects a device has on a read.
+    fn peek_byte(&self, addr: u32) -> u8;
     fn mem_write_byte(&mut self, addr: u32, value: u8);
     fn io_read_byte(&mut self, addr: u16) -> u8;
     fn io_write_byte(&mut self, addr: u16, value: u8);
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RepType {
     REPE,
-    REPNE
+    REPNE,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -24,7 +31,11 @@ pub struct Cpu8086 {
     pub opcode: u8,
     pub seg_override: Option<SegReg>,
     pub rep_state: Option<RepType>,
-    pub floppy: Vec<u8>
+    pub halted: bool,
+    pub floppy: Vec<u8>,
+    pub hooks: CpuHooks,
+    pub instr_cs: u16,
+    pub instr_ip: u16,
 }
 
 impl Cpu8086 {
@@ -34,42 +45,125 @@ impl Cpu8086 {
             opcode: 0,
             seg_override: None,
             rep_state: None,
+            halted: false,
             floppy: vec![],
+            hooks: CpuHooks::default(),
+            instr_cs: 0,
+            instr_ip: 0,
+        }
+    }
+
+    /// Puts the CPU back in its power-on state. The loaded floppy image and
+    /// any installed hooks are kept.
+    pub fn reset(&mut self) {
+        self.regs = Registers::new();
+        self.opcode = 0;
+        self.seg_override = None;
+        self.rep_state = None;
+        self.halted = false;
+    }
+
+    /// Installs a callback that runs before every instruction with the
+    /// CPU state, the instruction's CS:IP and its raw bytes.
+    pub fn set_exec_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&Cpu8086, u16, u16, &Instruction) + Send + 'static,
+    {
+        self.hooks.pre = Some(Box::new(hook));
+    }
+
+    /// Like set_exec_hook, but runs after the instruction has executed.
+    /// CS:IP still refer to where the instruction started.
+    pub fn set_post_exec_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&Cpu8086, u16, u16, &Instruction) + Send + 'static,
+    {
+        self.hooks.post = Some(Box::new(hook));
+    }
+
+    /// Installs a callback that sees every memory and I/O access the CPU
+    /// makes, including instruction fetches.
+    pub fn set_access_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&Access) + Send + 'static,
+    {
+        self.hooks.access = Some(Box::new(hook));
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks = CpuHooks::default();
+    }
+
+    fn notify_access(&mut self, kind: AccessKind, addr: u32, size: u8, value: u16) {
+        let access = Access {
+            kind,
+            addr,
+            size,
+            value,
+            cs: self.instr_cs,
+            ip: self.instr_ip,
+        };
+        if let Some(hook) = self.hooks.access.as_mut() {
+            hook(&access);
+        }
+    }
+
+    /// The bytes at CS:IP for the exec hooks. The instruction may well be
+    /// shorter than that, so they're peeked rather than read.
+    fn fetch_instruction<T: Cpu8086Context>(&mut self, ctx: &mut T) -> Instruction {
+        let cs = self.regs.readseg16(SegReg::CS);
+        let mut bytes = [0u8; 6];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let offset = self.regs.ip.wrapping_add(i as u16);
+            *byte = ctx.peek_byte((((cs as u32) << 4) + offset as u32) & 0xf_ffff);
+        }
+        Instruction {
+            opcode: bytes[0],
+            bytes,
+            seg_override: self.seg_override,
+            rep_state: self.rep_state,
         }
     }
     pub fn interrupt_hook<T: Cpu8086Context>(&mut self, ctx: &mut T, intr: u8) {
         match intr {
-            0x13 => {
-                match self.regs.read8(Reg8::AH) {
-                    0x00 => {
-                        println!("reset disk system");
-                        self.regs.write8(Reg8::AH, 0);
-                        self.regs.flags.set(Flags::CARRY, false);
-                    },
-                    0x02 => {
-                        println!("read sectors");
-                        let count: u16 = self.regs.read8(Reg8::AL) as u16;
-                        let sector: u16 = self.regs.read8(Reg8::CL) as u16;
-                        let buf_seg = self.regs.readseg16(SegReg::ES);
-                        let buf_off = self.regs.read16(Reg16::BX);
-                        for i in 0..=(count-1) {
-                            for j in 0..=511 {
-                                self.mem_write_byte(ctx, buf_seg, buf_off + (i*512)+j, self.floppy[(((sector+i)*512)+j) as usize]);
-                            }
+            0x13 => match self.regs.read8(Reg8::AH) {
+                0x00 => {
+                    debug!(target: "disk", "int 13h: reset disk system");
+                    self.regs.write8(Reg8::AH, 0);
+                    self.regs.flags.set(Flags::CARRY, false);
+                }
+                0x02 => {
+                    debug!(target: "disk", "int 13h: read sectors");
+                    let count: u16 = self.regs.read8(Reg8::AL) as u16;
+                    let sector: u16 = self.regs.read8(Reg8::CL) as u16;
+                    let buf_seg = self.regs.readseg16(SegReg::ES);
+                    let buf_off = self.regs.read16(Reg16::BX);
+                    for i in 0..=(count - 1) {
+                        for j in 0..=511 {
+                            self.mem_write_byte(
+                                ctx,
+                                buf_seg,
+                                buf_off + (i * 512) + j,
+                                self.floppy[(((sector + i) * 512) + j) as usize],
+                            );
                         }
-                        self.regs.flags.set(Flags::CARRY, false);
-                        self.regs.write8(Reg8::AH, 0);
-                        self.regs.write8(Reg8::AL, count as u8);
-                    },
-                    _ => panic!("Unimplmented int 13"),
+                    }
+                    self.regs.flags.set(Flags::CARRY, false);
+                    self.regs.write8(Reg8::AH, 0);
+                    self.regs.write8(Reg8::AL, count as u8);
                 }
-            }
-            _ => panic!("Unimplemented interrupt"),
+                _ => self.unimplemented(ctx, "int 13h function"),
+            },
+            _ => self.unimplemented(ctx, "interrupt"),
         }
     }
     pub fn mem_read_byte<T: Cpu8086Context>(&mut self, ctx: &mut T, seg: u16, addr: u16) -> u8 {
-        let masked_addr = (((seg as u32) << 4) | addr as u32) & 0xf_ffff;
-        ctx.mem_read_byte(masked_addr)
+        let masked_addr = (((seg as u32) << 4) + addr as u32) & 0xf_ffff;
+        let value = ctx.mem_read_byte(masked_addr);
+        if self.hooks.access.is_some() {
+            self.notify_access(AccessKind::MemRead, masked_addr, 1, value as u16);
+        }
+        value
     }
     pub fn mem_write_byte<T: Cpu8086Context>(
         &mut self,
@@ -78,23 +172,40 @@ impl Cpu8086 {
         addr: u16,
         value: u8,
     ) {
-        let masked_addr = (((seg as u32) << 4) | addr as u32) & 0xf_ffff;
+        let masked_addr = (((seg as u32) << 4) + addr as u32) & 0xf_ffff;
+        if self.hooks.access.is_some() {
+            self.notify_access(AccessKind::MemWrite, masked_addr, 1, value as u16);
+        }
         ctx.mem_write_byte(masked_addr, value)
     }
 
     pub fn io_read_byte<T: Cpu8086Context>(&mut self, ctx: &mut T, addr: u16) -> u8 {
-        ctx.io_read_byte(addr)
+        let value = ctx.io_read_byte(addr);
+        trace!(target: "io", "in {:#06x} -> {:#04x}", addr, value);
+        if self.hooks.access.is_some() {
+            self.notify_access(AccessKind::IoRead, addr as u32, 1, value as u16);
+        }
+        value
     }
 
     pub fn io_write_byte<T: Cpu8086Context>(&mut self, ctx: &mut T, addr: u16, value: u8) {
+        trace!(target: "io", "out {:#06x} <- {:#04x}", addr, value);
+        if self.hooks.access.is_some() {
+            self.notify_access(AccessKind::IoWrite, addr as u32, 1, value as u16);
+        }
         ctx.io_write_byte(addr, value)
     }
 
     pub fn mem_read_word<T: Cpu8086Context>(&mut self, ctx: &mut T, seg: u16, addr: u16) -> u16 {
-        let masked_addr = (((seg as u32) << 4) | addr as u32) & 0xf_ffff;
+        let masked_addr = (((seg as u32) << 4) + addr as u32) & 0xf_ffff;
+        let hi_addr = (((seg as u32) << 4) + addr.wrapping_add(1) as u32) & 0xf_ffff;
         let lo = ctx.mem_read_byte(masked_addr);
-        let hi = ctx.mem_read_byte(masked_addr.wrapping_add(1) & 0xf_ffff);
-        u16::from_le_bytes([lo, hi])
+        let hi = ctx.mem_read_byte(hi_addr);
+        let value = u16::from_le_bytes([lo, hi]);
+        if self.hooks.access.is_some() {
+            self.notify_access(AccessKind::MemRead, masked_addr, 2, value);
+        }
+        value
     }
 
     pub fn mem_write_word<T: Cpu8086Context>(
@@ -104,9 +215,15 @@ impl Cpu8086 {
         addr: u16,
         value: u16,
     ) {
-        let masked_addr = (((seg as u32) << 4) | addr as u32) & 0xf_ffff;
+        let masked_addr = (((seg as u32) << 4) + addr as u32) & 0xf_ffff;
+        if self.hooks.access.is_some() {
+            self.notify_access(AccessKind::MemWrite, masked_addr, 2, value);
+        }
+        // A word at offset FFFFh wraps around within the segment, and one
+        // at the top of memory wraps around to 0.
+        let hi_addr = (((seg as u32) << 4) + addr.wrapping_add(1) as u32) & 0xf_ffff;
         ctx.mem_write_byte(masked_addr, value as u8);
-        ctx.mem_write_byte(masked_addr + 1, (value >> 8) as u8);
+        ctx.mem_write_byte(hi_addr, (value >> 8) as u8);
     }
 
     pub fn set_parity_flag(&mut self, mut data: u16) {
@@ -136,19 +253,57 @@ impl Cpu8086 {
         self.mem_read_word(ctx, self.regs.readseg16(SegReg::SS), stack_pointer)
     }
 
+    pub fn push16<T: Cpu8086Context>(&mut self, ctx: &mut T, value: u16) {
+        let stack_pointer = self.regs.read16(Reg16::SP).wrapping_sub(2);
+        self.regs.write16(Reg16::SP, stack_pointer);
+        self.mem_write_word(ctx, self.regs.readseg16(SegReg::SS), stack_pointer, value);
+    }
+
+    /// Delivers an interrupt through the vector table at address 0, the way
+    /// hardware interrupts and NMI reach the CPU. Software INTs still go
+    /// through `interrupt_hook`.
+    pub fn interrupt<T: Cpu8086Context>(&mut self, ctx: &mut T, vector: u8) {
+        trace!(target: "cpu", "Interrupt {:#04x}", vector);
+        self.halted = false;
+        self.push16(ctx, self.regs.flags.bits());
+        self.push16(ctx, self.regs.readseg16(SegReg::CS));
+        self.push16(ctx, self.regs.ip);
+        self.regs.flags.remove(Flags::INTERRUPT | Flags::TRAP);
+        let offset = (vector as u16) * 4;
+        self.regs.ip = self.mem_read_word(ctx, 0, offset);
+        let cs = self.mem_read_word(ctx, 0, offset + 2);
+        self.regs.writeseg16(SegReg::CS, cs);
+    }
+
     pub fn tick<T: Cpu8086Context>(&mut self, ctx: &mut T) -> usize {
+        if self.halted {
+            return 4;
+        }
+        self.instr_cs = self.regs.readseg16(SegReg::CS);
+        self.instr_ip = self.regs.ip;
+        if !self.hooks.has_exec_hooks() {
+            return self.execute(ctx);
+        }
+        let cs = self.instr_cs;
+        let ip = self.instr_ip;
+        let instruction = self.fetch_instruction(ctx);
+        if let Some(mut hook) = self.hooks.pre.take() {
+            hook(self, cs, ip, &instruction);
+            self.hooks.pre = Some(hook);
+        }
+        let cycles = self.execute(ctx);
+        if let Some(mut hook) = self.hooks.post.take() {
+            hook(self, cs, ip, &instruction);
+            self.hooks.post = Some(hook);
+        }
+        cycles
+    }
+
+    fn execute<T: Cpu8086Context>(&mut self, ctx: &mut T) -> usize {
         self.opcode = self.mem_read_byte(ctx, self.regs.readseg16(SegReg::CS), self.regs.ip);
-        println!(
-            "Opcode {:#02x} CS {:#04x} IP {:#04x}\nGPRs {:x?} FLAGS {:#04x}",
-            self.opcode,
-            self.regs.readseg16(SegReg::CS),
-            self.regs.ip,
-            self.regs.gprs,
-            self.regs.flags.bits()
-        );
         match self.opcode {
             0x00 => {
-                println!("add rm8, reg8");
+                trace!(target: "cpu", "add rm8, reg8");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -188,7 +343,7 @@ impl Cpu8086 {
                 );
             }
             0x02 => {
-                println!("add reg8, rm8");
+                trace!(target: "cpu", "add reg8, rm8");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -219,7 +374,7 @@ impl Cpu8086 {
                 self.regs.write8(Reg8::from_num(reg_num).unwrap(), result);
             }
             0x03 => {
-                println!("add reg16, rm16");
+                trace!(target: "cpu", "add reg16, rm16");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -250,7 +405,7 @@ impl Cpu8086 {
                 self.regs.write16(Reg16::from_num(reg_num).unwrap(), result);
             }
             0x0a => {
-                println!("or reg8, rm8");
+                trace!(target: "cpu", "or reg8, rm8");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -273,7 +428,7 @@ impl Cpu8086 {
                 self.regs.write8(Reg8::from_num(reg_num).unwrap(), result);
             }
             0x0b => {
-                println!("or reg16, rm16");
+                trace!(target: "cpu", "or reg16, rm16");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -296,7 +451,7 @@ impl Cpu8086 {
                 self.regs.write16(Reg16::from_num(reg_num).unwrap(), result);
             }
             0x1e => {
-                println!("push ds");
+                trace!(target: "cpu", "push ds");
                 self.regs
                     .write16(Reg16::SP, self.regs.read16(Reg16::SP).wrapping_sub(2));
                 self.mem_write_word(
@@ -308,7 +463,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x22 => {
-                println!("and reg8, rm8");
+                trace!(target: "cpu", "and reg8, rm8");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -331,7 +486,7 @@ impl Cpu8086 {
                 self.regs.write8(Reg8::from_num(reg_num).unwrap(), result);
             }
             0x23 => {
-                println!("and reg16, rm16");
+                trace!(target: "cpu", "and reg16, rm16");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -354,13 +509,13 @@ impl Cpu8086 {
                 self.regs.write16(Reg16::from_num(reg_num).unwrap(), result);
             }
             0x26 => {
-                println!("es:");
+                trace!(target: "cpu", "es:");
                 self.seg_override = Some(SegReg::ES);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
-                self.tick(ctx);
+                self.execute(ctx);
             }
             0x2a => {
-                println!("sub reg8, rm8");
+                trace!(target: "cpu", "sub reg8, rm8");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -391,7 +546,7 @@ impl Cpu8086 {
                 self.regs.write8(Reg8::from_num(reg_num).unwrap(), result);
             }
             0x2b => {
-                println!("sub reg16, rm16");
+                trace!(target: "cpu", "sub reg16, rm16");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -422,13 +577,13 @@ impl Cpu8086 {
                 self.regs.write16(Reg16::from_num(reg_num).unwrap(), result);
             }
             0x2e => {
-                println!("cs:");
+                trace!(target: "cpu", "cs:");
                 self.seg_override = Some(SegReg::CS);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
-                self.tick(ctx);
+                self.execute(ctx);
             }
             0x32 => {
-                println!("xor reg8, rm8");
+                trace!(target: "cpu", "xor reg8, rm8");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -451,7 +606,7 @@ impl Cpu8086 {
                 self.regs.write8(Reg8::from_num(reg_num).unwrap(), result);
             }
             0x33 => {
-                println!("xor reg16, rm16");
+                trace!(target: "cpu", "xor reg16, rm16");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -474,13 +629,13 @@ impl Cpu8086 {
                 self.regs.write16(Reg16::from_num(reg_num).unwrap(), result);
             }
             0x36 => {
-                println!("ss:");
+                trace!(target: "cpu", "ss:");
                 self.seg_override = Some(SegReg::SS);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
-                self.tick(ctx);
+                self.execute(ctx);
             }
             0x3a => {
-                println!("cmp reg8, rm8");
+                trace!(target: "cpu", "cmp reg8, rm8");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -510,7 +665,7 @@ impl Cpu8086 {
                     .set(Flags::CARRY, (rm & 0x80) > (reg & 0x80));
             }
             0x3b => {
-                println!("cmp reg16, rm16");
+                trace!(target: "cpu", "cmp reg16, rm16");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -540,13 +695,13 @@ impl Cpu8086 {
                     .set(Flags::CARRY, (rm & 0x8000) > (reg & 0x8000));
             }
             0x3e => {
-                println!("ds:");
+                trace!(target: "cpu", "ds:");
                 self.seg_override = Some(SegReg::DS);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
-                self.tick(ctx);
+                self.execute(ctx);
             }
             0x40 => {
-                println!("inc ax");
+                trace!(target: "cpu", "inc ax");
                 let reg: u16 = self.regs.read16(Reg16::AX);
                 let result = reg.wrapping_add(1);
                 self.set_pzs16(result);
@@ -561,7 +716,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x41 => {
-                println!("inc cx");
+                trace!(target: "cpu", "inc cx");
                 let reg: u16 = self.regs.read16(Reg16::CX);
                 let result = reg.wrapping_add(1);
                 self.set_pzs16(result);
@@ -576,7 +731,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x42 => {
-                println!("inc dx");
+                trace!(target: "cpu", "inc dx");
                 let reg: u16 = self.regs.read16(Reg16::DX);
                 let result = reg.wrapping_add(1);
                 self.set_pzs16(result);
@@ -591,7 +746,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x43 => {
-                println!("inc bx");
+                trace!(target: "cpu", "inc bx");
                 let reg: u16 = self.regs.read16(Reg16::BX);
                 let result = reg.wrapping_add(1);
                 self.set_pzs16(result);
@@ -606,7 +761,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x44 => {
-                println!("inc sp");
+                trace!(target: "cpu", "inc sp");
                 let reg: u16 = self.regs.read16(Reg16::SP);
                 let result = reg.wrapping_add(1);
                 self.set_pzs16(result);
@@ -621,7 +776,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x45 => {
-                println!("inc bp");
+                trace!(target: "cpu", "inc bp");
                 let reg: u16 = self.regs.read16(Reg16::BP);
                 let result = reg.wrapping_add(1);
                 self.set_pzs16(result);
@@ -636,7 +791,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x46 => {
-                println!("inc si");
+                trace!(target: "cpu", "inc si");
                 let reg: u16 = self.regs.read16(Reg16::SI);
                 let result = reg.wrapping_add(1);
                 self.set_pzs16(result);
@@ -651,7 +806,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x47 => {
-                println!("inc di");
+                trace!(target: "cpu", "inc di");
                 let reg: u16 = self.regs.read16(Reg16::DI);
                 let result = reg.wrapping_add(1);
                 self.set_pzs16(result);
@@ -666,7 +821,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x48 => {
-                println!("dec ax");
+                trace!(target: "cpu", "dec ax");
                 let reg: u16 = self.regs.read16(Reg16::AX);
                 let result = reg.wrapping_sub(1);
                 self.set_pzs16(result);
@@ -681,7 +836,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x49 => {
-                println!("dec cx");
+                trace!(target: "cpu", "dec cx");
                 let reg: u16 = self.regs.read16(Reg16::CX);
                 let result = reg.wrapping_sub(1);
                 self.set_pzs16(result);
@@ -696,7 +851,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x4a => {
-                println!("dec dx");
+                trace!(target: "cpu", "dec dx");
                 let reg: u16 = self.regs.read16(Reg16::DX);
                 let result = reg.wrapping_sub(1);
                 self.set_pzs16(result);
@@ -711,7 +866,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x4b => {
-                println!("dec bx");
+                trace!(target: "cpu", "dec bx");
                 let reg: u16 = self.regs.read16(Reg16::BX);
                 let result = reg.wrapping_sub(1);
                 self.set_pzs16(result);
@@ -726,7 +881,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x4c => {
-                println!("dec sp");
+                trace!(target: "cpu", "dec sp");
                 let reg: u16 = self.regs.read16(Reg16::SP);
                 let result = reg.wrapping_sub(1);
                 self.set_pzs16(result);
@@ -741,7 +896,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x4d => {
-                println!("dec bp");
+                trace!(target: "cpu", "dec bp");
                 let reg: u16 = self.regs.read16(Reg16::BP);
                 let result = reg.wrapping_sub(1);
                 self.set_pzs16(result);
@@ -756,7 +911,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x4e => {
-                println!("dec si");
+                trace!(target: "cpu", "dec si");
                 let reg: u16 = self.regs.read16(Reg16::SI);
                 let result = reg.wrapping_sub(1);
                 self.set_pzs16(result);
@@ -771,7 +926,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x4f => {
-                println!("dec di");
+                trace!(target: "cpu", "dec di");
                 let reg: u16 = self.regs.read16(Reg16::DI);
                 let result = reg.wrapping_sub(1);
                 self.set_pzs16(result);
@@ -786,7 +941,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x70 => {
-                println!("jo");
+                trace!(target: "cpu", "jo");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -798,7 +953,7 @@ impl Cpu8086 {
                 }
             }
             0x71 => {
-                println!("jno");
+                trace!(target: "cpu", "jno");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -810,7 +965,7 @@ impl Cpu8086 {
                 }
             }
             0x72 => {
-                println!("jc");
+                trace!(target: "cpu", "jc");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -822,7 +977,7 @@ impl Cpu8086 {
                 }
             }
             0x73 => {
-                println!("jnc");
+                trace!(target: "cpu", "jnc");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -834,7 +989,7 @@ impl Cpu8086 {
                 }
             }
             0x74 => {
-                println!("jz");
+                trace!(target: "cpu", "jz");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -846,7 +1001,7 @@ impl Cpu8086 {
                 }
             }
             0x75 => {
-                println!("jnz");
+                trace!(target: "cpu", "jnz");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -858,7 +1013,7 @@ impl Cpu8086 {
                 }
             }
             0x78 => {
-                println!("js");
+                trace!(target: "cpu", "js");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -870,7 +1025,7 @@ impl Cpu8086 {
                 }
             }
             0x79 => {
-                println!("jns");
+                trace!(target: "cpu", "jns");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -882,7 +1037,7 @@ impl Cpu8086 {
                 }
             }
             0x7a => {
-                println!("jp");
+                trace!(target: "cpu", "jp");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -894,7 +1049,7 @@ impl Cpu8086 {
                 }
             }
             0x7b => {
-                println!("jnp");
+                trace!(target: "cpu", "jnp");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -922,7 +1077,7 @@ impl Cpu8086 {
                 let group_op = (modrm & 0x38) >> 3;
                 match group_op {
                     1 => {
-                        println!("or rm8, imm8");
+                        trace!(target: "cpu", "or rm8, imm8");
                         if let Operand::Register(reg_num) = opcode_params.rm {
                             let reg: u8 = self.regs.read8(Reg8::from_num(reg_num).unwrap());
                             let result = reg | imm;
@@ -930,14 +1085,20 @@ impl Cpu8086 {
                             self.regs.write8(Reg8::from_num(reg_num).unwrap(), result);
                         }
                         if let Operand::Address(segment, opcode_rm) = opcode_params.rm {
-                            let src = self.mem_read_byte(ctx, self.regs.readseg16(segment), opcode_rm);
+                            let src =
+                                self.mem_read_byte(ctx, self.regs.readseg16(segment), opcode_rm);
                             let result = src | imm;
                             self.set_pzs8(result);
-                            self.mem_write_byte(ctx, self.regs.readseg16(segment), opcode_rm, result);
+                            self.mem_write_byte(
+                                ctx,
+                                self.regs.readseg16(segment),
+                                opcode_rm,
+                                result,
+                            );
                         }
                     }
                     7 => {
-                        println!("cmp rm8, imm8");
+                        trace!(target: "cpu", "cmp rm8, imm8");
                         if let Operand::Register(reg_num) = opcode_params.rm {
                             let reg: u8 = self.regs.read8(Reg8::from_num(reg_num).unwrap());
                             let result = reg.wrapping_sub(imm);
@@ -954,7 +1115,8 @@ impl Cpu8086 {
                                 .set(Flags::CARRY, (imm & 0x80) > (reg & 0x80));
                         }
                         if let Operand::Address(segment, opcode_rm) = opcode_params.rm {
-                            let src = self.mem_read_byte(ctx, self.regs.readseg16(segment), opcode_rm);
+                            let src =
+                                self.mem_read_byte(ctx, self.regs.readseg16(segment), opcode_rm);
                             let result = src.wrapping_sub(imm);
                             self.set_pzs8(result);
                             self.regs.flags.set(
@@ -969,11 +1131,11 @@ impl Cpu8086 {
                                 .set(Flags::CARRY, (imm & 0x80) > (src & 0x80));
                         }
                     }
-                    _ => panic!("Unimplemented group opcode!"),
+                    _ => self.unimplemented(ctx, "group opcode"),
                 }
             }
             0x88 => {
-                println!("mov rm8, reg8");
+                trace!(target: "cpu", "mov rm8, reg8");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -997,7 +1159,7 @@ impl Cpu8086 {
                 }
             }
             0x89 => {
-                println!("mov rm16, reg16");
+                trace!(target: "cpu", "mov rm16, reg16");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1021,7 +1183,7 @@ impl Cpu8086 {
                 }
             }
             0x8a => {
-                println!("mov reg8, rm8");
+                trace!(target: "cpu", "mov reg8, rm8");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1041,7 +1203,7 @@ impl Cpu8086 {
                 }
             }
             0x8b => {
-                println!("mov reg16, rm16");
+                trace!(target: "cpu", "mov reg16, rm16");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1061,7 +1223,7 @@ impl Cpu8086 {
                 }
             }
             0x8c => {
-                println!("mov rm, seg");
+                trace!(target: "cpu", "mov rm, seg");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1085,7 +1247,7 @@ impl Cpu8086 {
                 }
             }
             0x8e => {
-                println!("mov seg, rm");
+                trace!(target: "cpu", "mov seg, rm");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1105,7 +1267,7 @@ impl Cpu8086 {
                 }
             }
             0x9e => {
-                println!("sahf");
+                trace!(target: "cpu", "sahf");
                 self.regs.flags = Flags::from_bits(
                     (self.regs.flags.bits() & 0xff02) | (self.regs.read8(Reg8::AH) as u16),
                 )
@@ -1113,47 +1275,37 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0x9f => {
-                println!("lahf");
+                trace!(target: "cpu", "lahf");
                 self.regs
                     .write8(Reg8::AH, (self.regs.flags.bits() & 0xd5) as u8);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0xa0 => {
-                println!("mov al, [imm]");
+                trace!(target: "cpu", "mov al, [imm]");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
                     self.regs.ip.wrapping_add(1),
                 );
                 self.regs.ip = self.regs.ip.wrapping_add(3);
-                let mut result: u8 = 0;
-                if let Some(segment) = self.seg_override {
-                    result = self.mem_read_byte(ctx, self.regs.readseg16(segment), imm_value);
-                }
-                else {
-                    result = self.mem_read_byte(ctx, self.regs.readseg16(SegReg::DS), imm_value);
-                }
+                let segment = self.seg_override.unwrap_or(SegReg::DS);
+                let result = self.mem_read_byte(ctx, self.regs.readseg16(segment), imm_value);
                 self.regs.write8(Reg8::AL, result);
             }
             0xa1 => {
-                println!("mov ax, [imm]");
+                trace!(target: "cpu", "mov ax, [imm]");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
                     self.regs.ip.wrapping_add(1),
                 );
                 self.regs.ip = self.regs.ip.wrapping_add(3);
-                let mut result: u16 = 0;
-                if let Some(segment) = self.seg_override {
-                    result = self.mem_read_word(ctx, self.regs.readseg16(segment), imm_value);
-                }
-                else {
-                    result = self.mem_read_word(ctx, self.regs.readseg16(SegReg::DS), imm_value);
-                }
+                let segment = self.seg_override.unwrap_or(SegReg::DS);
+                let result = self.mem_read_word(ctx, self.regs.readseg16(segment), imm_value);
                 self.regs.write16(Reg16::AX, result);
             }
             0xb0 => {
-                println!("mov al, imm");
+                trace!(target: "cpu", "mov al, imm");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1163,7 +1315,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xb1 => {
-                println!("mov cl, imm");
+                trace!(target: "cpu", "mov cl, imm");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1173,7 +1325,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xb2 => {
-                println!("mov dl, imm");
+                trace!(target: "cpu", "mov dl, imm");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1183,7 +1335,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xb3 => {
-                println!("mov bl, imm");
+                trace!(target: "cpu", "mov bl, imm");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1193,7 +1345,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xb4 => {
-                println!("mov ah, imm");
+                trace!(target: "cpu", "mov ah, imm");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1203,7 +1355,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xb5 => {
-                println!("mov ch, imm");
+                trace!(target: "cpu", "mov ch, imm");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1213,7 +1365,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xb6 => {
-                println!("mov dh, imm");
+                trace!(target: "cpu", "mov dh, imm");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1223,7 +1375,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xb7 => {
-                println!("mov bh, imm");
+                trace!(target: "cpu", "mov bh, imm");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1233,7 +1385,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xb8 => {
-                println!("mov ax, imm");
+                trace!(target: "cpu", "mov ax, imm");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1243,7 +1395,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(3);
             }
             0xb9 => {
-                println!("mov cx, imm");
+                trace!(target: "cpu", "mov cx, imm");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1253,7 +1405,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(3);
             }
             0xba => {
-                println!("mov dx, imm");
+                trace!(target: "cpu", "mov dx, imm");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1263,7 +1415,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(3);
             }
             0xbb => {
-                println!("mov bx, imm");
+                trace!(target: "cpu", "mov bx, imm");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1273,7 +1425,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(3);
             }
             0xbc => {
-                println!("mov sp, imm");
+                trace!(target: "cpu", "mov sp, imm");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1283,7 +1435,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(3);
             }
             0xbd => {
-                println!("mov bp, imm");
+                trace!(target: "cpu", "mov bp, imm");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1293,7 +1445,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(3);
             }
             0xbe => {
-                println!("mov si, imm");
+                trace!(target: "cpu", "mov si, imm");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1303,7 +1455,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(3);
             }
             0xbf => {
-                println!("mov di, imm");
+                trace!(target: "cpu", "mov di, imm");
                 let imm_value = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1313,11 +1465,11 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(3);
             }
             0xc3 => {
-                println!("ret");
+                trace!(target: "cpu", "ret");
                 self.regs.ip = self.pop16(ctx);
             }
             0xc4 => {
-                println!("les");
+                trace!(target: "cpu", "les");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1334,7 +1486,7 @@ impl Cpu8086 {
                 }
             }
             0xc5 => {
-                println!("lds");
+                trace!(target: "cpu", "lds");
                 let modrm = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1356,7 +1508,7 @@ impl Cpu8086 {
                     self.regs.readseg16(SegReg::CS),
                     self.regs.ip.wrapping_add(1),
                 );
-                println!("int {:x}", intr);
+                trace!(target: "cpu", "int {:x}", intr);
                 self.interrupt_hook(ctx, intr);
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
@@ -1370,12 +1522,12 @@ impl Cpu8086 {
                 let opcode_params = self.get_opcode_params_from_modrm(ctx, modrm);
                 match opcode_params.rm {
                     Operand::Register(_) => (),
-                    _ => panic!("Opcode doesn't support memory operands!"),
+                    _ => self.unimplemented(ctx, "memory operand form"),
                 }
                 let group_op = (modrm & 0x38) >> 3;
                 match group_op {
                     4 => {
-                        println!("shl reg, 1");
+                        trace!(target: "cpu", "shl reg, 1");
                         if let Operand::Register(reg_num) = opcode_params.rm {
                             let mut reg: u8 = self.regs.read8(Reg8::from_num(reg_num).unwrap());
                             self.regs.flags.set(Flags::CARRY, (reg & 1) == 1);
@@ -1387,7 +1539,7 @@ impl Cpu8086 {
                         }
                     }
                     5 => {
-                        println!("shr reg, 1");
+                        trace!(target: "cpu", "shr reg, 1");
                         if let Operand::Register(reg_num) = opcode_params.rm {
                             let mut reg: u8 = self.regs.read8(Reg8::from_num(reg_num).unwrap());
                             self.regs.flags.set(Flags::CARRY, (reg & 1) == 1);
@@ -1397,7 +1549,7 @@ impl Cpu8086 {
                             self.regs.write8(Reg8::from_num(reg_num).unwrap(), reg);
                         }
                     }
-                    _ => panic!("Unimplemented group opcode!"),
+                    _ => self.unimplemented(ctx, "group opcode"),
                 }
             }
             0xd2 => {
@@ -1410,12 +1562,12 @@ impl Cpu8086 {
                 let opcode_params = self.get_opcode_params_from_modrm(ctx, modrm);
                 match opcode_params.rm {
                     Operand::Register(_) => (),
-                    _ => panic!("Opcode doesn't support memory operands!"),
+                    _ => self.unimplemented(ctx, "memory operand form"),
                 }
                 let group_op = (modrm & 0x38) >> 3;
                 match group_op {
                     4 => {
-                        println!("shl reg, cl");
+                        trace!(target: "cpu", "shl reg, cl");
                         let mut count = self.regs.read8(Reg8::CL);
                         if let Operand::Register(reg_num) = opcode_params.rm {
                             let mut reg: u8 = self.regs.read8(Reg8::from_num(reg_num).unwrap());
@@ -1429,7 +1581,7 @@ impl Cpu8086 {
                         }
                     }
                     5 => {
-                        println!("shr reg, cl");
+                        trace!(target: "cpu", "shr reg, cl");
                         let mut count = self.regs.read8(Reg8::CL);
                         if let Operand::Register(reg_num) = opcode_params.rm {
                             let mut reg: u8 = self.regs.read8(Reg8::from_num(reg_num).unwrap());
@@ -1442,11 +1594,11 @@ impl Cpu8086 {
                             self.regs.write8(Reg8::from_num(reg_num).unwrap(), reg);
                         }
                     }
-                    _ => panic!("Unimplemented group opcode!"),
+                    _ => self.unimplemented(ctx, "group opcode"),
                 }
             }
             0xe2 => {
-                println!("loop");
+                trace!(target: "cpu", "loop");
                 let offset: i16 = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1460,7 +1612,7 @@ impl Cpu8086 {
                 }
             }
             0xe4 => {
-                println!("in al, imm");
+                trace!(target: "cpu", "in al, imm");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1471,7 +1623,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xe6 => {
-                println!("out imm, al");
+                trace!(target: "cpu", "out imm, al");
                 let imm_value = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1481,18 +1633,24 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(2);
             }
             0xe8 => {
-                println!("call near");
+                trace!(target: "cpu", "call near");
                 let offset = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
                     self.regs.ip.wrapping_add(1),
                 );
-                self.regs.write16(Reg16::SP, self.regs.read16(Reg16::SP).wrapping_sub(2));
-                self.mem_write_word(ctx, self.regs.readseg16(SegReg::SS), self.regs.read16(Reg16::SP), self.regs.ip);
-                self.regs.ip = self.regs.ip.wrapping_add(offset + 3);
+                self.regs
+                    .write16(Reg16::SP, self.regs.read16(Reg16::SP).wrapping_sub(2));
+                self.mem_write_word(
+                    ctx,
+                    self.regs.readseg16(SegReg::SS),
+                    self.regs.read16(Reg16::SP),
+                    self.regs.ip.wrapping_add(3),
+                );
+                self.regs.ip = self.regs.ip.wrapping_add(offset).wrapping_add(3);
             }
             0xe9 => {
-                println!("jmp near");
+                trace!(target: "cpu", "jmp near");
                 let offset = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1501,7 +1659,7 @@ impl Cpu8086 {
                 self.regs.ip = self.regs.ip.wrapping_add(offset + 3);
             }
             0xea => {
-                println!("jmp far");
+                trace!(target: "cpu", "jmp far");
                 let offset = self.mem_read_word(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
@@ -1516,58 +1674,67 @@ impl Cpu8086 {
                 self.regs.ip = offset;
             }
             0xeb => {
-                println!("jmp rel8");
+                trace!(target: "cpu", "jmp rel8");
                 let offset = self.mem_read_byte(
                     ctx,
                     self.regs.readseg16(SegReg::CS),
                     self.regs.ip.wrapping_add(1),
                 );
-                self.regs.ip = self.regs.ip.wrapping_add((offset as i8 as u16) + 2u16);
+                self.regs.ip = self
+                    .regs
+                    .ip
+                    .wrapping_add(offset as i8 as u16)
+                    .wrapping_add(2);
             }
             0xee => {
-                println!("out dx, al");
+                trace!(target: "cpu", "out dx, al");
                 self.io_write_byte(ctx, self.regs.read16(Reg16::DX), self.regs.read8(Reg8::AL));
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0xf2 => {
-                println!("repne:");
+                trace!(target: "cpu", "repne:");
                 self.rep_state = Some(RepType::REPNE);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
-                self.tick(ctx);
+                self.execute(ctx);
             }
             0xf3 => {
-                println!("repe:");
+                trace!(target: "cpu", "repe:");
                 self.rep_state = Some(RepType::REPE);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
-                self.tick(ctx);
+                self.execute(ctx);
+            }
+            0xf4 => {
+                trace!(target: "cpu", "hlt");
+                self.halted = true;
+                self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0xf8 => {
-                println!("clc");
+                trace!(target: "cpu", "clc");
                 self.regs.flags.set(Flags::CARRY, false);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0xf9 => {
-                println!("stc");
+                trace!(target: "cpu", "stc");
                 self.regs.flags.set(Flags::CARRY, true);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0xfa => {
-                println!("cli");
+                trace!(target: "cpu", "cli");
                 self.regs.flags.set(Flags::INTERRUPT, false);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0xfb => {
-                println!("sti");
+                trace!(target: "cpu", "sti");
                 self.regs.flags.set(Flags::INTERRUPT, true);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0xfc => {
-                println!("cld");
+                trace!(target: "cpu", "cld");
                 self.regs.flags.set(Flags::DIRECTION, false);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
             0xfd => {
-                println!("std");
+                trace!(target: "cpu", "std");
                 self.regs.flags.set(Flags::DIRECTION, true);
                 self.regs.ip = self.regs.ip.wrapping_add(1);
             }
@@ -1581,12 +1748,12 @@ impl Cpu8086 {
                 let opcode_params = self.get_opcode_params_from_modrm(ctx, modrm);
                 match opcode_params.rm {
                     Operand::Register(_) => (),
-                    _ => panic!("Memory operands not supported yet!"),
+                    _ => self.unimplemented(ctx, "memory operand form"),
                 }
                 let group_op = (modrm & 0x38) >> 3;
                 match group_op {
                     0 => {
-                        println!("inc rm8");
+                        trace!(target: "cpu", "inc rm8");
                         if let Operand::Register(reg_num) = opcode_params.rm {
                             let reg: u8 = self.regs.read8(Reg8::from_num(reg_num).unwrap());
                             let result = reg.wrapping_add(1);
@@ -1602,7 +1769,7 @@ impl Cpu8086 {
                         }
                     }
                     1 => {
-                        println!("dec rm8");
+                        trace!(target: "cpu", "dec rm8");
                         if let Operand::Register(reg_num) = opcode_params.rm {
                             let reg: u8 = self.regs.read8(Reg8::from_num(reg_num).unwrap());
                             let result = reg.wrapping_sub(1);
@@ -1616,10 +1783,10 @@ impl Cpu8086 {
                             self.regs.write8(Reg8::from_num(reg_num).unwrap(), result);
                         }
                     }
-                    _ => panic!("Unimplemented group opcode!"),
+                    _ => self.unimplemented(ctx, "group opcode"),
                 }
             }
-            _ => panic!("Unhandled opcode!"),
+            _ => self.unimplemented(ctx, "opcode"),
         }
         self.seg_override = None;
         4
@@ -121,33 +121,27 @@ impl Cpu8086 {
             0 => {
                 let addr_type = Cpu8086::get_addr_type_from_modrm(modrm);
                 let disp_type = Cpu8086::get_disp_type_from_modrm(modrm);
-                let addr: u16;
-                let displacement: u16;
                 let segment: SegReg = self.get_operand_seg(addr_type, disp_type);
-                match disp_type {
-                    None => {
-                        displacement = 0;
-                    }
+                let displacement: u16 = match disp_type {
+                    None => 0,
                     Some(DisplacementType::Byte) => {
-                        displacement =
+                        let displacement =
                             self.mem_read_byte(ctx, self.regs.readseg16(SegReg::CS), self.regs.ip)
                                 as u16;
                         self.regs.ip = self.regs.ip.wrapping_add(1);
+                        displacement
                     }
                     Some(DisplacementType::Word) => {
-                        displacement =
+                        let displacement =
                             self.mem_read_word(ctx, self.regs.readseg16(SegReg::CS), self.regs.ip);
                         self.regs.ip = self.regs.ip.wrapping_add(2);
+                        displacement
                     }
-                }
-                match addr_type {
-                    None => {
-                        addr = displacement;
-                    }
-                    Some(_) => {
-                        addr = self.get_offset(addr_type.unwrap(), displacement);
-                    }
-                }
+                };
+                let addr: u16 = match addr_type {
+                    None => displacement,
+                    Some(addr_type) => self.get_offset(addr_type, displacement),
+                };
                 let operand_rm = Operand::Address(segment, addr);
                 let operand_reg = reg;
                 OpcodeParams {
@@ -157,17 +151,14 @@ impl Cpu8086 {
             }
             1 => {
                 let addr_type = Cpu8086::get_addr_type_from_modrm(modrm);
-                let addr: u16;
                 let displacement: u16 =
                     self.mem_read_byte(ctx, self.regs.readseg16(SegReg::CS), self.regs.ip) as u16;
                 let segment: SegReg = self.get_operand_seg(addr_type, Some(DisplacementType::Byte));
                 self.regs.ip = self.regs.ip.wrapping_add(1);
-                match addr_type {
+                let addr: u16 = match addr_type {
                     None => panic!("Invalid address type for this ModR/M type!"),
-                    Some(_) => {
-                        addr = self.get_offset(addr_type.unwrap(), displacement);
-                    }
-                }
+                    Some(addr_type) => self.get_offset(addr_type, displacement),
+                };
                 let operand_rm = Operand::Address(segment, addr);
                 let operand_reg = reg;
                 OpcodeParams {
@@ -177,17 +168,14 @@ impl Cpu8086 {
             }
             2 => {
                 let addr_type = Cpu8086::get_addr_type_from_modrm(modrm);
-                let addr: u16;
                 let displacement: u16 =
                     self.mem_read_word(ctx, self.regs.readseg16(SegReg::CS), self.regs.ip);
                 let segment: SegReg = self.get_operand_seg(addr_type, Some(DisplacementType::Byte));
                 self.regs.ip = self.regs.ip.wrapping_add(2);
-                match addr_type {
+                let addr: u16 = match addr_type {
                     None => panic!("Invalid address type for this ModR/M type!"),
-                    Some(_) => {
-                        addr = self.get_offset(addr_type.unwrap(), displacement);
-                    }
-                }
+                    Some(addr_type) => self.get_offset(addr_type, displacement),
+                };
                 let operand_rm = Operand::Address(segment, addr);
                 let operand_reg = reg;
                 OpcodeParams {
@@ -210,7 +198,7 @@ impl Cpu8086 {
 
 #[test]
 fn test_modrm() {
-    let mut machine = IbmPc5150Machine::new();
+    let mut machine = crate::hardware::IbmPc5150Machine::new();
     for modrm in 0..=0xffu8 {
         machine
             .cpu
@@ -1,5 +1,4 @@
 use bitflags::bitflags;
-#[allow(dead_code)]
 
 bitflags!(
     pub struct Flags: u16
@@ -187,7 +186,7 @@ impl Registers {
             BP => self.gprs[5],
             SI => self.gprs[6],
             DI => self.gprs[7],
-            FLAGS => (self.flags.bits() as u16) | 0xf002u16,
+            FLAGS => self.flags.bits() | 0xf002u16,
         }
     }
 
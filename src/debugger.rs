@@ -0,0 +1,600 @@
+// A monitor in the style of DOS DEBUG, for looking into a stopped machine
+// and running it an instruction at a time. It takes one command a line:
+//
+//     r [REG VALUE]        shows the registers, or sets one
+//     s [N]                steps N instructions, 1 by default
+//     p                    steps over a call, interrupt, loop or repeated
+//                          string instruction, or steps
+//     c                    carries on running
+//     d [ADDR] [LEN]       dumps memory, on from the last dump
+//     e ADDR BYTE...       writes bytes to memory
+//     u [ADDR] [N]         disassembles, from CS:IP or on from the last
+//     ivt [FIRST] [N]      shows the interrupt vectors
+//     devices              shows which device answers which ports
+//     b                    lists the breakpoints and watchpoints, numbered
+//     bp ADDR              stops before the instruction at ADDR
+//     bpa PHYS             the same, by physical address
+//     bm ADDR [LEN] [K]    stops after LEN bytes of memory, 1 by default,
+//                          are read (K is r), written (w, the default) or
+//                          either (rw)
+//     bio PORT [K]         stops after a port is read or written, the same
+//     bc [N]               clears breakpoint N, or all of them
+//     q                    quits
+//
+// Numbers are hex. An address is SEG:OFFSET, where SEG can be a segment
+// register, or just an offset into DS, or CS for `u`; segments other than
+// the CPU's own CS are taken as real mode ones. An empty line repeats the
+// last command, so stepping is a matter of pressing Enter.
+use crate::hardware::breakpoints::{Breakpoint, Watch};
+use crate::hardware::machine::Machine;
+use crate::hardware::StopReason;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/// How long `p` runs for before giving up on getting back, in frames.
+const STEP_OVER_FRAMES: usize = 600;
+
+/// The instructions `p` runs until the next one for, rather than stepping
+/// into.
+const STEP_OVER: [&str; 10] = [
+    "call", "int", "int3", "into", "loop", "loopz", "loopnz", "rep", "repe", "repne",
+];
+
+const REGISTERS: [&str; 14] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "es", "cs", "ss", "ds", "ip", "flags",
+];
+
+const HELP: &str = "\
+r [REG VALUE]    show the registers, or set one
+s [N]            step N instructions
+p                step over a call, interrupt, loop or rep
+c                carry on running
+d [ADDR] [LEN]   dump memory
+e ADDR BYTE...   write memory
+u [ADDR] [N]     disassemble
+ivt [FIRST] [N]  show the interrupt vectors
+devices          show the devices' ports
+b                list breakpoints
+bp ADDR          break at an instruction
+bpa PHYS         break at a physical address
+bm ADDR [N] [K]  watch memory for r, w or rw
+bio PORT [K]     watch a port for r, w or rw
+bc [N]           clear breakpoints
+q                quit";
+
+/// What the frontend does after a command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    /// Shows the text and waits for the next command.
+    Text(String),
+    /// Runs the machine again.
+    Continue,
+    Quit,
+}
+
+/// A breakpoint or watchpoint, as `b` lists them and `bc` clears them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Entry {
+    Instruction(Breakpoint),
+    /// The index in the `MemoryWatch`, the first and last address and the
+    /// accesses.
+    Memory(usize, u32, u32, Watch),
+    Port(u16, Watch, Option<u8>),
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::Instruction(at @ Breakpoint::Address(..)) => write!(f, "bp {}", at),
+            Entry::Instruction(at) => write!(f, "bpa {}", at),
+            Entry::Memory(_, start, end, kind) => {
+                write!(f, "bm {:05X}-{:05X} {}", start, end, kind)
+            }
+            Entry::Port(port, kind, None) => write!(f, "bio {:04X} {}", port, kind),
+            Entry::Port(port, kind, Some(value)) => {
+                write!(f, "bio {:04X} {} {:02X}", port, kind, value)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Debugger {
+    /// Where `d` and `u` carry on from.
+    dump_at: Option<(u16, u16)>,
+    list_at: Option<(u16, u16)>,
+    last: String,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Takes commands from `input` until one runs the machine again,
+    /// returning whether it was `q` or the end of the input instead.
+    pub fn repl(
+        &mut self,
+        machine: &mut Machine,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<bool> {
+        writeln!(output, "{}", self.status(machine))?;
+        loop {
+            write!(output, "-")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(true);
+            }
+            match self.command(machine, &line) {
+                Ok(Reply::Text(text)) => writeln!(output, "{}", text)?,
+                Ok(Reply::Continue) => return Ok(false),
+                Ok(Reply::Quit) => return Ok(true),
+                Err(err) => writeln!(output, "{}", err)?,
+            }
+        }
+    }
+
+    /// Carries out one command line.
+    pub fn command(&mut self, machine: &mut Machine, line: &str) -> Result<Reply, String> {
+        let line = match line.trim() {
+            "" => self.last.clone(),
+            line => line.to_ascii_lowercase(),
+        };
+        self.last = line.clone();
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(Reply::Text(String::new())),
+        };
+        let args: Vec<&str> = words.collect();
+        let text = match (command, args.as_slice()) {
+            ("r", []) => self.status(machine),
+            ("r", [reg, value]) => {
+                set_register(machine, reg, number(value)?)?;
+                self.status(machine)
+            }
+            ("s", []) => self.step(machine, 1),
+            ("s", [count]) => self.step(machine, number(count)? as usize),
+            ("p", []) => self.step_over(machine),
+            ("c", []) => return Ok(Reply::Continue),
+            ("d", args) if args.len() <= 2 => {
+                let start = match args.first() {
+                    Some(addr) => address(machine, addr, 3)?,
+                    None => self.dump_at.unwrap_or((machine.cpu_state().seg_regs[3], 0)),
+                };
+                let len = args.get(1).map_or(Ok(0x80), |len| number(len))?;
+                self.dump(machine, start, len)
+            }
+            ("e", [addr, bytes @ ..]) if !bytes.is_empty() => {
+                let (seg, offset) = address(machine, addr, 3)?;
+                let bytes = bytes
+                    .iter()
+                    .map(|byte| number(byte).and_then(byte_value))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                for (n, byte) in (0..).zip(bytes) {
+                    machine.write_memory(physical(seg, offset.wrapping_add(n)), byte);
+                }
+                String::new()
+            }
+            ("u", args) if args.len() <= 2 => {
+                let state = machine.cpu_state();
+                let (cs, ip) = match args.first() {
+                    Some(addr) => address(machine, addr, 1)?,
+                    None => self.list_at.unwrap_or((state.cs(), state.ip)),
+                };
+                let count = args.get(1).map_or(Ok(8), |count| number(count))?;
+                let instructions = machine.disassemble(cs, ip, count as usize);
+                let next = instructions.last().map_or(ip, |last| last.next_ip());
+                self.list_at = Some((cs, next));
+                let lines: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+                lines.join("\n")
+            }
+            ("ivt", args) if args.len() <= 2 => {
+                let first = args.first().map_or(Ok(0), |first| number(first))?;
+                let count = args.get(1).map_or(Ok(0x100), |count| number(count))?;
+                ivt(machine, first.min(0x100), count)
+            }
+            ("devices", []) => {
+                let ranges = machine.io_map();
+                let lines: Vec<String> = ranges
+                    .iter()
+                    .map(|(start, end, name)| format!("{:04X}-{:04X}  {}", start, end, name))
+                    .collect();
+                lines.join("\n")
+            }
+            ("b", []) => {
+                let lines: Vec<String> = entries(machine)
+                    .iter()
+                    .enumerate()
+                    .map(|(n, entry)| format!("{:X}  {}", n, entry))
+                    .collect();
+                lines.join("\n")
+            }
+            ("bp", [addr]) => {
+                let (cs, ip) = address(machine, addr, 1)?;
+                machine.breakpoints_mut().add(Breakpoint::Address(cs, ip));
+                String::new()
+            }
+            ("bpa", [addr]) => {
+                let addr = number(addr)?;
+                machine.breakpoints_mut().add(Breakpoint::Physical(addr));
+                String::new()
+            }
+            ("bm", [addr, rest @ ..]) if rest.len() <= 2 => {
+                let (seg, offset) = address(machine, addr, 3)?;
+                let (len, kind) = length_and_kind(rest)?;
+                let start = physical(seg, offset);
+                machine
+                    .memory_watch_mut()
+                    .watch(start, start + len - 1, kind);
+                String::new()
+            }
+            ("bio", [port, rest @ ..]) if rest.len() <= 1 => {
+                let port = word(number(port)?)?;
+                let kind = rest
+                    .first()
+                    .map_or(Ok(Watch::Write), |kind| Watch::parse(kind))?;
+                let watch = machine.port_watch_mut();
+                if kind.covers(Watch::Read) {
+                    watch.watch_reads(port);
+                }
+                if kind.covers(Watch::Write) {
+                    watch.watch(port, None);
+                }
+                String::new()
+            }
+            ("bc", []) => {
+                machine.breakpoints_mut().clear();
+                machine.memory_watch_mut().clear();
+                machine.port_watch_mut().clear();
+                String::new()
+            }
+            ("bc", [n]) => {
+                let n = number(n)?;
+                let entry = *entries(machine)
+                    .get(n as usize)
+                    .ok_or_else(|| format!("no breakpoint {:X}", n))?;
+                match entry {
+                    Entry::Instruction(at) => {
+                        machine.breakpoints_mut().remove(at);
+                    }
+                    Entry::Memory(index, ..) => machine.memory_watch_mut().unwatch(index),
+                    Entry::Port(port, kind, _) => machine.port_watch_mut().unwatch(port, kind),
+                }
+                String::new()
+            }
+            ("q", []) => return Ok(Reply::Quit),
+            ("h" | "?", []) => HELP.to_string(),
+            _ => return Err(format!("bad command '{}', h for help", line)),
+        };
+        Ok(Reply::Text(text))
+    }
+
+    /// The registers and the next instruction.
+    fn status(&mut self, machine: &mut Machine) -> String {
+        let state = machine.cpu_state();
+        self.list_at = None;
+        match machine.disassemble(state.cs(), state.ip, 1).first() {
+            Some(next) => format!("{}\n{}", state, next),
+            None => state.to_string(),
+        }
+    }
+
+    fn step(&mut self, machine: &mut Machine, count: usize) -> String {
+        let reason = machine.run_instructions(count);
+        format!("{}{}", stopped(machine, reason), self.status(machine))
+    }
+
+    fn step_over(&mut self, machine: &mut Machine) -> String {
+        let state = machine.cpu_state();
+        let next = match machine.disassemble(state.cs(), state.ip, 1).pop() {
+            Some(next) => next,
+            None => return self.step(machine, 1),
+        };
+        if !next.text.split(' ').any(|word| STEP_OVER.contains(&word)) {
+            return self.step(machine, 1);
+        }
+        let back = (state.cs(), next.next_ip());
+        let added = machine
+            .breakpoints_mut()
+            .add(Breakpoint::Address(back.0, back.1));
+        let mut reason = StopReason::FrameComplete;
+        for _ in 0..STEP_OVER_FRAMES {
+            reason = machine.run_frame();
+            if reason != StopReason::FrameComplete {
+                break;
+            }
+        }
+        if added {
+            machine
+                .breakpoints_mut()
+                .remove(Breakpoint::Address(back.0, back.1));
+        }
+        let state = machine.cpu_state();
+        let text = match (reason, (state.cs(), state.ip) == back) {
+            (StopReason::Breakpoint, true) => String::new(),
+            (StopReason::FrameComplete, _) => {
+                format!("Still not back after {} frames\n", STEP_OVER_FRAMES)
+            }
+            (reason, _) => stopped(machine, reason),
+        };
+        text + &self.status(machine)
+    }
+
+    fn dump(&mut self, machine: &mut Machine, (seg, offset): (u16, u16), len: u32) -> String {
+        let mut lines = vec![];
+        for row in (0..len).step_by(16) {
+            let start = offset.wrapping_add(row as u16);
+            let bytes: Vec<u8> = (0..(len - row).min(16) as u16)
+                .map(|n| machine.read_memory(physical(seg, start.wrapping_add(n))))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let text: String = bytes
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7e => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            lines.push(format!(
+                "{:04X}:{:04X}  {:<47}  {}",
+                seg,
+                start,
+                hex.join(" "),
+                text
+            ));
+        }
+        self.dump_at = Some((seg, offset.wrapping_add(len as u16)));
+        lines.join("\n")
+    }
+}
+
+/// Why a run stopped short, when it did, as a line of its own.
+fn stopped(machine: &mut Machine, reason: StopReason) -> String {
+    let access = match reason {
+        StopReason::PortRead | StopReason::MemoryRead => "read",
+        _ => "written",
+    };
+    match reason {
+        StopReason::Breakpoint => "Breakpoint\n".to_string(),
+        StopReason::PortWritten | StopReason::PortRead => match machine.port_watch_mut().last() {
+            Some((port, value)) => format!("Port {:04X} {}: {:02X}\n", port, access, value),
+            None => String::new(),
+        },
+        StopReason::MemoryWritten | StopReason::MemoryRead => {
+            match machine.memory_watch_mut().last() {
+                Some((addr, _, value)) => {
+                    format!("Memory {:05X} {}: {:02X}\n", addr, access, value)
+                }
+                None => String::new(),
+            }
+        }
+        StopReason::Halted => "Halted\n".to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Every breakpoint and watchpoint, instructions first, then memory, then
+/// ports.
+fn entries(machine: &mut Machine) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = machine
+        .breakpoints_mut()
+        .list()
+        .into_iter()
+        .map(Entry::Instruction)
+        .collect();
+    let memory = machine.memory_watch_mut().watches().iter().enumerate();
+    entries.extend(memory.map(|(n, &(start, end, kind))| Entry::Memory(n, start, end, kind)));
+    let ports = machine.port_watch_mut().watches().into_iter();
+    entries.extend(ports.map(|(port, kind, value)| Entry::Port(port, kind, value)));
+    entries
+}
+
+/// `bm`'s `[LEN] [K]`, a byte written by default.
+fn length_and_kind(args: &[&str]) -> Result<(u32, Watch), String> {
+    let (len, kind) = match args {
+        [] => (1, Watch::Write),
+        [kind] if Watch::parse(kind).is_ok() => (1, Watch::parse(kind)?),
+        [len] => (number(len)?, Watch::Write),
+        [len, kind, ..] => (number(len)?, Watch::parse(kind)?),
+    };
+    match len {
+        0 => Err("nothing to watch".to_string()),
+        len => Ok((len, kind)),
+    }
+}
+
+fn physical(seg: u16, offset: u16) -> u32 {
+    ((seg as u32) << 4) + offset as u32
+}
+
+fn number(text: &str) -> Result<u32, String> {
+    u32::from_str_radix(text.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("'{}' isn't a hex number", text))
+}
+
+fn word(value: u32) -> Result<u16, String> {
+    u16::try_from(value).map_err(|_| format!("{:X} doesn't fit in a word", value))
+}
+
+fn byte_value(value: u32) -> Result<u8, String> {
+    u8::try_from(value).map_err(|_| format!("{:X} doesn't fit in a byte", value))
+}
+
+/// SEG:OFFSET or OFFSET into the `default` segment register, counting
+/// ES, CS, SS, DS from 0.
+fn address(machine: &Machine, text: &str, default: usize) -> Result<(u16, u16), String> {
+    let state = machine.cpu_state();
+    let (seg, offset) = match text.split_once(':') {
+        Some((seg, offset)) => {
+            let seg = match REGISTERS[8..12].iter().position(|&name| name == seg) {
+                Some(n) => state.seg_regs[n],
+                None => word(number(seg)?)?,
+            };
+            (seg, offset)
+        }
+        None => (state.seg_regs[default], text),
+    };
+    Ok((seg, word(number(offset)?)?))
+}
+
+fn set_register(machine: &mut Machine, name: &str, value: u32) -> Result<(), String> {
+    let n = REGISTERS
+        .iter()
+        .position(|&reg| reg == name)
+        .ok_or_else(|| format!("no register '{}'", name))?;
+    let value = word(value)?;
+    let mut state = machine.cpu_state();
+    match n {
+        0..=7 => state.gprs[n] = value,
+        8..=11 => state.seg_regs[n - 8] = value,
+        12 => state.ip = value,
+        _ => state.flags = value,
+    }
+    machine.set_cpu_state(&state);
+    Ok(())
+}
+
+/// `count` interrupt vectors from `first`, four to a line.
+fn ivt(machine: &mut Machine, first: u32, count: u32) -> String {
+    let last = (first + count).min(0x100);
+    let vectors: Vec<String> = (first..last)
+        .map(|vector| {
+            let read = |machine: &mut Machine, n: u32| {
+                let addr = vector * 4 + n;
+                u16::from_le_bytes([machine.read_memory(addr), machine.read_memory(addr + 1)])
+            };
+            let (offset, seg) = (read(machine, 0), read(machine, 2));
+            format!("{:02X} {:04X}:{:04X}", vector, seg, offset)
+        })
+        .collect();
+    let lines: Vec<String> = vectors.chunks(4).map(|line| line.join("   ")).collect();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+use crate::hardware::IbmPc5150Machine;
+
+#[test]
+fn test_debugger() {
+    let mut pc = IbmPc5150Machine::new();
+    pc.cpu.regs.seg_regs[1] = 0;
+    pc.cpu.regs.ip = 0x100;
+    pc.cpu.regs.gprs[4] = 0x1000;
+    // call 0x0106; jmp $; nop; mov al, 0x42; ret
+    let code = [0xe8, 0x03, 0x00, 0xeb, 0xfe, 0x90, 0xb0, 0x42, 0xc3];
+    pc.hardware.memory.ram[0x100..0x109].copy_from_slice(&code);
+    pc.hardware.memory.ram[0x84..0x88].copy_from_slice(&[0x34, 0x12, 0x00, 0xf0]);
+    let mut machine = Machine::Pc(Box::new(pc));
+    let mut debugger = Debugger::new();
+    let mut run = |machine: &mut Machine, line: &str| match debugger.command(machine, line) {
+        Ok(Reply::Text(text)) => text,
+        reply => panic!("{:?}", reply),
+    };
+
+    let status = run(&mut machine, "r");
+    assert!(status.contains("IP=0100"));
+    assert!(status.ends_with("0000:0100  E80300        call 0x0106"));
+
+    // Stepping into the call, and again with an empty line.
+    assert!(run(&mut machine, "s").contains("IP=0106"));
+    assert!(run(&mut machine, "").contains("IP=0108"));
+    assert!(run(&mut machine, "s").contains("IP=0103"));
+
+    // Stepping over it runs the whole subroutine.
+    run(&mut machine, "r ip 100");
+    assert!(run(&mut machine, "p").contains("IP=0103"));
+    assert_eq!(machine.cpu_state().gprs[0] & 0xff, 0x42);
+    assert!(machine.breakpoints_mut().is_empty());
+
+    run(&mut machine, "r ax 1234");
+    assert_eq!(machine.cpu_state().gprs[0], 0x1234);
+    run(&mut machine, "e 0:200 41 42");
+    assert_eq!(
+        run(&mut machine, "d 0:200 2"),
+        format!("0000:0200  41 42{:42}  AB", "")
+    );
+    let listing = run(&mut machine, "u cs:106 1");
+    assert!(listing.ends_with("mov al, 0x42"));
+    assert!(run(&mut machine, "u")
+        .lines()
+        .next()
+        .unwrap()
+        .ends_with("ret"));
+    assert_eq!(run(&mut machine, "ivt 21 1"), "21 F000:1234");
+    assert!(run(&mut machine, "devices").contains("0020-0021  Pic"));
+
+    assert!(debugger.command(&mut machine, "r zz 1").is_err());
+    assert!(debugger.command(&mut machine, "e 0:200 100").is_err());
+    assert_eq!(debugger.command(&mut machine, "c"), Ok(Reply::Continue));
+    assert_eq!(debugger.command(&mut machine, "Q"), Ok(Reply::Quit));
+}
+
+#[test]
+fn test_debugger_breakpoints() {
+    let mut pc = IbmPc5150Machine::new();
+    pc.cpu.regs.seg_regs[1] = 0;
+    pc.cpu.regs.ip = 0x100;
+    // mov [0x200], al; in al, 0x60; clc; jmp $
+    let code = [0x88, 0x06, 0x00, 0x02, 0xe4, 0x60, 0xf8, 0xeb, 0xfe];
+    pc.hardware.memory.ram[0x100..0x109].copy_from_slice(&code);
+    let mut machine = Machine::Pc(Box::new(pc));
+    let mut debugger = Debugger::new();
+    let mut run = |machine: &mut Machine, line: &str| match debugger.command(machine, line) {
+        Ok(Reply::Text(text)) => text,
+        reply => panic!("{:?}", reply),
+    };
+
+    run(&mut machine, "r ax 42");
+    run(&mut machine, "bm 1ff 2");
+    run(&mut machine, "bio 60 r");
+    run(&mut machine, "bpa 107");
+    run(&mut machine, "bp 0:108");
+    assert_eq!(
+        run(&mut machine, "b"),
+        "0  bp 0000:0108\n1  bpa 00107\n2  bm 001FF-00200 w\n3  bio 0060 r"
+    );
+    // The debugger's own writes don't set off watchpoints.
+    run(&mut machine, "e 200 0");
+    let text = run(&mut machine, "s 10");
+    assert!(text.starts_with("Memory 00200 written: 42\n"));
+    assert!(text.contains("IP=0104"));
+    assert!(run(&mut machine, "s 10").starts_with("Port 0060 read: "));
+    assert!(run(&mut machine, "s 10").starts_with("Breakpoint\n"));
+
+    run(&mut machine, "bc 1");
+    assert_eq!(
+        run(&mut machine, "b"),
+        "0  bp 0000:0108\n1  bm 001FF-00200 w\n2  bio 0060 r"
+    );
+    run(&mut machine, "bc");
+    assert_eq!(run(&mut machine, "b"), "");
+
+    assert!(debugger.command(&mut machine, "bc 0").is_err());
+    assert!(debugger.command(&mut machine, "bm 200 0").is_err());
+    assert!(debugger.command(&mut machine, "bio 60 x").is_err());
+}
+
+#[test]
+fn test_debugger_repl() {
+    let mut pc = IbmPc5150Machine::new();
+    pc.cpu.regs.seg_regs[1] = 0;
+    pc.cpu.regs.ip = 0x100;
+    let mut machine = Machine::Pc(Box::new(pc));
+    let mut debugger = Debugger::new();
+    let mut output = vec![];
+    let quit = debugger
+        .repl(&mut machine, &mut &b"bogus\ns\nc\n"[..], &mut output)
+        .unwrap();
+    assert!(!quit);
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("-bad command 'bogus'"));
+    assert!(output.contains("IP=0102"));
+    // The end of the input quits.
+    assert!(debugger
+        .repl(&mut machine, &mut &b""[..], &mut vec![])
+        .unwrap());
+}
@@ -0,0 +1,610 @@
+// Intel syntax text for 8086, 186 and 286 machine code, for the debugger,
+// the tracer and anything else that wants to show what the CPU is running.
+// It only looks at the bytes, so it's as happy with code that hasn't run
+// yet as with code that has.
+//
+// The output is lower case with the numbers in hex, as in
+// `mov ax, es:[bx+si+0x0010]`. A size only goes on a memory operand when
+// nothing else gives it one, as in `inc word [bx]`. Jumps, calls and loops
+// show where they go rather than how far. Anything the chosen processor
+// doesn't decode comes out as a `db` of its first byte.
+use std::fmt;
+
+const REG8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const REG16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+const SREG: [&str; 4] = ["es", "cs", "ss", "ds"];
+const BASES: [&str; 8] = ["bx+si", "bx+di", "bp+si", "bp+di", "si", "di", "bp", "bx"];
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const GROUP3: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+const JCC: [&str; 16] = [
+    "jo", "jno", "jc", "jnc", "jz", "jnz", "jbe", "ja", "js", "jns", "jp", "jnp", "jl", "jge",
+    "jle", "jg",
+];
+
+/// The most bytes worth reading for one instruction. Only prefixes can
+/// make one longer, and the 286 faults on those.
+pub const MAX_LENGTH: usize = 10;
+
+/// Which processor's instructions to decode. Each takes everything the one
+/// before did, bar the 8086's aliases for opcodes the 186 gave new jobs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Isa {
+    #[default]
+    I8086,
+    I186,
+    I286,
+}
+
+/// One decoded instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub cs: u16,
+    pub ip: u16,
+    /// Its bytes, prefixes and all.
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl Instruction {
+    /// Where the instruction after this one starts.
+    pub fn next_ip(&self) -> u16 {
+        self.ip.wrapping_add(self.bytes.len() as u16)
+    }
+}
+
+/// `F000:E05B  EA5BE000F0    jmp 0xf000:0xe05b`, as a listing has it.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex: String = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        write!(
+            f,
+            "{:04X}:{:04X}  {:<12}  {}",
+            self.cs, self.ip, hex, self.text
+        )
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, which came from
+/// `cs`:`ip`. Bytes missing off the end read as zero.
+pub fn decode(bytes: &[u8], cs: u16, ip: u16, isa: Isa) -> Instruction {
+    let mut decoder = Decoder {
+        bytes,
+        pos: 0,
+        ip,
+        isa,
+        segment: None,
+        segment_used: false,
+    };
+    let text = decoder.instruction();
+    let mut code = bytes[..decoder.pos.min(bytes.len())].to_vec();
+    code.resize(decoder.pos, 0);
+    Instruction {
+        cs,
+        ip,
+        bytes: code,
+        text,
+    }
+}
+
+/// Decodes `bytes` from `cs`:`ip` on, leaving off an instruction that runs
+/// past the end.
+pub fn disassemble(bytes: &[u8], cs: u16, ip: u16, isa: Isa) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let instruction = decode(&bytes[pos..], cs, ip.wrapping_add(pos as u16), isa);
+        pos += instruction.bytes.len();
+        if pos > bytes.len() {
+            break;
+        }
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Width {
+    Byte,
+    Word,
+}
+
+impl Width {
+    /// The operand size bit at the bottom of most opcodes.
+    fn of(opcode: u8) -> Width {
+        match opcode & 1 {
+            0 => Width::Byte,
+            _ => Width::Word,
+        }
+    }
+
+    fn reg(self, n: u8) -> &'static str {
+        match self {
+            Width::Byte => REG8[n as usize & 7],
+            Width::Word => REG16[n as usize & 7],
+        }
+    }
+
+    fn ptr(self) -> &'static str {
+        match self {
+            Width::Byte => "byte ",
+            Width::Word => "word ",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ModRm {
+    mode: u8,
+    reg: u8,
+    rm: u8,
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    ip: u16,
+    isa: Isa,
+    segment: Option<&'static str>,
+    /// Whether a memory operand took the segment prefix. If none did, it's
+    /// shown as a prefix of its own.
+    segment_used: bool,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> u8 {
+        let byte = self.bytes.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        byte
+    }
+
+    fn word(&mut self) -> u16 {
+        u16::from_le_bytes([self.byte(), self.byte()])
+    }
+
+    fn imm(&mut self, width: Width) -> String {
+        match width {
+            Width::Byte => format!("{:#04x}", self.byte()),
+            Width::Word => format!("{:#06x}", self.word()),
+        }
+    }
+
+    /// A byte the CPU sign extends to a word.
+    fn simm8(&mut self) -> String {
+        format!("{:#06x}", self.byte() as i8 as u16)
+    }
+
+    /// A relative jump, taken from the end of the instruction, which the
+    /// offset always is.
+    fn target(&mut self, width: Width) -> String {
+        let offset = match width {
+            Width::Byte => self.byte() as i8 as u16,
+            Width::Word => self.word(),
+        };
+        let target = self.ip.wrapping_add(self.pos as u16).wrapping_add(offset);
+        format!("{:#06x}", target)
+    }
+
+    fn modrm(&mut self) -> ModRm {
+        let byte = self.byte();
+        ModRm {
+            mode: byte >> 6,
+            reg: (byte >> 3) & 7,
+            rm: byte & 7,
+        }
+    }
+
+    /// A memory operand at `address`, in whichever segment was asked for.
+    fn memory(&mut self, address: &str, ptr: &str) -> String {
+        let segment = match self.segment {
+            Some(segment) => {
+                self.segment_used = true;
+                format!("{}:", segment)
+            }
+            None => String::new(),
+        };
+        format!("{}{}[{}]", ptr, segment, address)
+    }
+
+    /// The register or memory operand a ModR/M byte picks, reading the
+    /// displacement after it. `ptr` is the size or distance to show on a
+    /// memory operand.
+    fn rm(&mut self, modrm: ModRm, width: Width, ptr: &str) -> String {
+        let base = BASES[modrm.rm as usize];
+        let address = match (modrm.mode, modrm.rm) {
+            (3, _) => return width.reg(modrm.rm).to_string(),
+            (0, 6) => format!("{:#06x}", self.word()),
+            (0, _) => base.to_string(),
+            (1, _) => {
+                let disp = self.byte() as i8;
+                let sign = if disp < 0 { '-' } else { '+' };
+                format!("{}{}{:#04x}", base, sign, disp.unsigned_abs())
+            }
+            _ => format!("{}+{:#06x}", base, self.word()),
+        };
+        self.memory(&address, ptr)
+    }
+
+    /// The two operand form with a register and a register or memory
+    /// operand, which way round bit 1 of the opcode says.
+    fn rm_reg(&mut self, name: &str, opcode: u8) -> String {
+        let width = Width::of(opcode);
+        let modrm = self.modrm();
+        let rm = self.rm(modrm, width, "");
+        let reg = width.reg(modrm.reg);
+        match opcode & 2 {
+            0 => format!("{} {}, {}", name, rm, reg),
+            _ => format!("{} {}, {}", name, reg, rm),
+        }
+    }
+
+    /// A 16-bit register loaded from a register or memory operand.
+    fn reg_rm(&mut self, name: &str) -> String {
+        let modrm = self.modrm();
+        let rm = self.rm(modrm, Width::Word, "");
+        format!("{} {}, {}", name, REG16[modrm.reg as usize], rm)
+    }
+
+    fn instruction(&mut self) -> String {
+        let mut prefixes = vec![];
+        let opcode = loop {
+            match self.byte() {
+                prefix @ (0x26 | 0x2e | 0x36 | 0x3e) => {
+                    self.segment = Some(SREG[(prefix >> 3) as usize & 3]);
+                    prefixes.push(prefix);
+                }
+                prefix @ (0xf0 | 0xf2 | 0xf3) => prefixes.push(prefix),
+                opcode => break opcode,
+            }
+        };
+        let start = self.pos - 1;
+        let text = match self.operation(opcode) {
+            Some(text) => text,
+            None => {
+                self.pos = start + 1;
+                format!("db {:#04x}", opcode)
+            }
+        };
+        let mut words = vec![];
+        for prefix in prefixes {
+            match prefix {
+                0xf0 => words.push("lock"),
+                0xf2 => words.push("repne"),
+                0xf3 if matches!(opcode, 0xa6 | 0xa7 | 0xae | 0xaf) => words.push("repe"),
+                0xf3 => words.push("rep"),
+                _ if !self.segment_used => words.push(SREG[(prefix >> 3) as usize & 3]),
+                _ => {}
+            }
+        }
+        words.push(&text);
+        words.join(" ")
+    }
+
+    fn operation(&mut self, opcode: u8) -> Option<String> {
+        let width = Width::of(opcode);
+        let acc = width.reg(0);
+        let text = match opcode {
+            0x0f => match self.isa {
+                Isa::I8086 => "pop cs".to_string(),
+                Isa::I186 => return None,
+                Isa::I286 => return self.extended(),
+            },
+            0x27 => "daa".to_string(),
+            0x2f => "das".to_string(),
+            0x37 => "aaa".to_string(),
+            0x3f => "aas".to_string(),
+            0x00..=0x3f => {
+                let name = ALU[(opcode >> 3) as usize];
+                match opcode & 7 {
+                    0..=3 => self.rm_reg(name, opcode),
+                    4 | 5 => format!("{} {}, {}", name, acc, self.imm(width)),
+                    6 => format!("push {}", SREG[(opcode >> 3) as usize & 3]),
+                    _ => format!("pop {}", SREG[(opcode >> 3) as usize & 3]),
+                }
+            }
+            0x40..=0x47 => format!("inc {}", REG16[opcode as usize & 7]),
+            0x48..=0x4f => format!("dec {}", REG16[opcode as usize & 7]),
+            0x50..=0x57 => format!("push {}", REG16[opcode as usize & 7]),
+            0x58..=0x5f => format!("pop {}", REG16[opcode as usize & 7]),
+            // The 8086 decodes these as the conditional jumps above them.
+            0x60..=0x6f if self.isa == Isa::I8086 => {
+                let name = JCC[opcode as usize & 0xf];
+                format!("{} {}", name, self.target(Width::Byte))
+            }
+            0x60 => "pusha".to_string(),
+            0x61 => "popa".to_string(),
+            0x62 => self.reg_rm("bound"),
+            0x63 if self.isa == Isa::I286 => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, Width::Word, "");
+                format!("arpl {}, {}", rm, REG16[modrm.reg as usize])
+            }
+            0x68 => format!("push {}", self.imm(Width::Word)),
+            0x6a => format!("push {}", self.simm8()),
+            0x69 | 0x6b => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, Width::Word, "");
+                let imm = match opcode {
+                    0x69 => self.imm(Width::Word),
+                    _ => self.simm8(),
+                };
+                format!("imul {}, {}, {}", REG16[modrm.reg as usize], rm, imm)
+            }
+            0x6c => "insb".to_string(),
+            0x6d => "insw".to_string(),
+            0x6e => "outsb".to_string(),
+            0x6f => "outsw".to_string(),
+            0x70..=0x7f => {
+                let name = JCC[opcode as usize & 0xf];
+                format!("{} {}", name, self.target(Width::Byte))
+            }
+            0x80..=0x83 => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, width, width.ptr());
+                let imm = match opcode {
+                    0x83 => self.simm8(),
+                    _ => self.imm(width),
+                };
+                format!("{} {}, {}", ALU[modrm.reg as usize], rm, imm)
+            }
+            0x84 | 0x85 => self.rm_reg("test", opcode),
+            0x86 | 0x87 => self.rm_reg("xchg", opcode),
+            0x88..=0x8b => self.rm_reg("mov", opcode),
+            0x8c => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, Width::Word, "");
+                format!("mov {}, {}", rm, SREG[modrm.reg as usize & 3])
+            }
+            0x8d => self.reg_rm("lea"),
+            0x8e => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, Width::Word, "");
+                format!("mov {}, {}", SREG[modrm.reg as usize & 3], rm)
+            }
+            0x8f => {
+                let modrm = self.modrm();
+                format!("pop {}", self.rm(modrm, Width::Word, "word "))
+            }
+            0x90 => "nop".to_string(),
+            0x91..=0x97 => format!("xchg ax, {}", REG16[opcode as usize & 7]),
+            0x98 => "cbw".to_string(),
+            0x99 => "cwd".to_string(),
+            0x9a => {
+                let (ip, cs) = (self.word(), self.word());
+                format!("call {:#06x}:{:#06x}", cs, ip)
+            }
+            0x9b => "wait".to_string(),
+            0x9c => "pushf".to_string(),
+            0x9d => "popf".to_string(),
+            0x9e => "sahf".to_string(),
+            0x9f => "lahf".to_string(),
+            0xa0..=0xa3 => {
+                let address = format!("{:#06x}", self.word());
+                let memory = self.memory(&address, "");
+                match opcode & 2 {
+                    0 => format!("mov {}, {}", acc, memory),
+                    _ => format!("mov {}, {}", memory, acc),
+                }
+            }
+            0xa4 => "movsb".to_string(),
+            0xa5 => "movsw".to_string(),
+            0xa6 => "cmpsb".to_string(),
+            0xa7 => "cmpsw".to_string(),
+            0xa8 | 0xa9 => format!("test {}, {}", acc, self.imm(width)),
+            0xaa => "stosb".to_string(),
+            0xab => "stosw".to_string(),
+            0xac => "lodsb".to_string(),
+            0xad => "lodsw".to_string(),
+            0xae => "scasb".to_string(),
+            0xaf => "scasw".to_string(),
+            0xb0..=0xb7 => format!(
+                "mov {}, {}",
+                REG8[opcode as usize & 7],
+                self.imm(Width::Byte)
+            ),
+            0xb8..=0xbf => {
+                let reg = REG16[opcode as usize & 7];
+                format!("mov {}, {}", reg, self.imm(Width::Word))
+            }
+            0xc0 | 0xc1 if self.isa >= Isa::I186 => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, width, width.ptr());
+                let count = self.imm(Width::Byte);
+                format!("{} {}, {}", SHIFT[modrm.reg as usize], rm, count)
+            }
+            0xc8 if self.isa >= Isa::I186 => {
+                let size = self.imm(Width::Word);
+                format!("enter {}, {}", size, self.imm(Width::Byte))
+            }
+            0xc9 if self.isa >= Isa::I186 => "leave".to_string(),
+            // Before the 186 took them over, bit 1 didn't matter.
+            0xc0 | 0xc2 => format!("ret {}", self.imm(Width::Word)),
+            0xc1 | 0xc3 => "ret".to_string(),
+            0xc8 | 0xca => format!("retf {}", self.imm(Width::Word)),
+            0xc9 | 0xcb => "retf".to_string(),
+            0xc4 => self.reg_rm("les"),
+            0xc5 => self.reg_rm("lds"),
+            0xc6 | 0xc7 => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, width, width.ptr());
+                format!("mov {}, {}", rm, self.imm(width))
+            }
+            0xcc => "int3".to_string(),
+            0xcd => format!("int {}", self.imm(Width::Byte)),
+            0xce => "into".to_string(),
+            0xcf => "iret".to_string(),
+            0xd0..=0xd3 => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, width, width.ptr());
+                let count = if opcode < 0xd2 { "1" } else { "cl" };
+                format!("{} {}, {}", SHIFT[modrm.reg as usize], rm, count)
+            }
+            0xd4 | 0xd5 => {
+                let name = if opcode == 0xd4 { "aam" } else { "aad" };
+                match self.byte() {
+                    10 => name.to_string(),
+                    base => format!("{} {:#04x}", name, base),
+                }
+            }
+            0xd6 => "salc".to_string(),
+            0xd7 => "xlat".to_string(),
+            // The coprocessor's instructions, which the CPU only works out
+            // the operand of.
+            0xd8..=0xdf => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, Width::Word, "");
+                format!("esc {:#04x}, {}", (opcode & 7) << 3 | modrm.reg, rm)
+            }
+            0xe0..=0xe3 => {
+                let name = ["loopnz", "loopz", "loop", "jcxz"][opcode as usize & 3];
+                format!("{} {}", name, self.target(Width::Byte))
+            }
+            0xe4 | 0xe5 => format!("in {}, {}", acc, self.imm(Width::Byte)),
+            0xe6 | 0xe7 => format!("out {}, {}", self.imm(Width::Byte), acc),
+            0xe8 => format!("call {}", self.target(Width::Word)),
+            0xe9 => format!("jmp {}", self.target(Width::Word)),
+            0xea => {
+                let (ip, cs) = (self.word(), self.word());
+                format!("jmp {:#06x}:{:#06x}", cs, ip)
+            }
+            0xeb => format!("jmp {}", self.target(Width::Byte)),
+            0xec | 0xed => format!("in {}, dx", acc),
+            0xee | 0xef => format!("out dx, {}", acc),
+            0xf4 => "hlt".to_string(),
+            0xf5 => "cmc".to_string(),
+            0xf6 | 0xf7 => {
+                let modrm = self.modrm();
+                let rm = self.rm(modrm, width, width.ptr());
+                match modrm.reg {
+                    0 | 1 => format!("test {}, {}", rm, self.imm(width)),
+                    reg => format!("{} {}", GROUP3[reg as usize], rm),
+                }
+            }
+            0xf8 => "clc".to_string(),
+            0xf9 => "stc".to_string(),
+            0xfa => "cli".to_string(),
+            0xfb => "sti".to_string(),
+            0xfc => "cld".to_string(),
+            0xfd => "std".to_string(),
+            0xfe | 0xff => {
+                let modrm = self.modrm();
+                let (name, ptr) = match (opcode, modrm.reg) {
+                    (_, 0) => ("inc", width.ptr()),
+                    (_, 1) => ("dec", width.ptr()),
+                    (0xff, 2) => ("call", ""),
+                    (0xff, 3) => ("call", "far "),
+                    (0xff, 4) => ("jmp", ""),
+                    (0xff, 5) => ("jmp", "far "),
+                    (0xff, 6) => ("push", "word "),
+                    _ => return None,
+                };
+                format!("{} {}", name, self.rm(modrm, width, ptr))
+            }
+            _ => return None,
+        };
+        Some(text)
+    }
+
+    /// The 286's 0Fh opcodes, nearly all for protected mode.
+    fn extended(&mut self) -> Option<String> {
+        let text = match self.byte() {
+            opcode @ (0x00 | 0x01) => {
+                let names = match opcode {
+                    0x00 => ["sldt", "str", "lldt", "ltr", "verr", "verw", "", ""],
+                    _ => ["sgdt", "sidt", "lgdt", "lidt", "smsw", "", "lmsw", ""],
+                };
+                let modrm = self.modrm();
+                let name = names[modrm.reg as usize];
+                if name.is_empty() {
+                    return None;
+                }
+                format!("{} {}", name, self.rm(modrm, Width::Word, ""))
+            }
+            0x02 => self.reg_rm("lar"),
+            0x03 => self.reg_rm("lsl"),
+            0x05 => "loadall".to_string(),
+            0x06 => "clts".to_string(),
+            _ => return None,
+        };
+        Some(text)
+    }
+}
+
+#[test]
+fn test_decode() {
+    let text = |bytes: &[u8], isa| decode(bytes, 0, 0x100, isa).text;
+    let i8086 = |bytes: &[u8]| text(bytes, Isa::I8086);
+    assert_eq!(i8086(&[0x01, 0xd8]), "add ax, bx");
+    assert_eq!(i8086(&[0x8a, 0x46, 0xfe]), "mov al, [bp-0x02]");
+    assert_eq!(i8086(&[0xd3, 0xe0]), "shl ax, cl");
+    assert_eq!(i8086(&[0xff, 0x1e, 0x34, 0x12]), "call far [0x1234]");
+    assert_eq!(i8086(&[0xf4]), "hlt");
+    assert_eq!(
+        i8086(&[0x26, 0x8b, 0x80, 0x10, 0x00]),
+        "mov ax, es:[bx+si+0x0010]"
+    );
+    assert_eq!(i8086(&[0xc6, 0x07, 0x41]), "mov byte [bx], 0x41");
+    assert_eq!(i8086(&[0x83, 0xc4, 0xfe]), "add sp, 0xfffe");
+    assert_eq!(i8086(&[0xfe, 0x06, 0x00, 0x02]), "inc byte [0x0200]");
+    assert_eq!(i8086(&[0xf3, 0xa4]), "rep movsb");
+    assert_eq!(i8086(&[0xf3, 0xa6]), "repe cmpsb");
+    assert_eq!(i8086(&[0x2e, 0xac]), "cs lodsb");
+    assert_eq!(i8086(&[0xeb, 0xfe]), "jmp 0x0100");
+    assert_eq!(i8086(&[0xe8, 0xfd, 0xff]), "call 0x0100");
+    assert_eq!(i8086(&[0xea, 0x5b, 0xe0, 0x00, 0xf0]), "jmp 0xf000:0xe05b");
+    assert_eq!(i8086(&[0xe4, 0x60]), "in al, 0x60");
+    assert_eq!(i8086(&[0xef]), "out dx, ax");
+    assert_eq!(i8086(&[0xd5, 0x0a]), "aad");
+
+    // The 8086's aliases, and what later processors made of them.
+    for (bytes, old, new) in [
+        (&[0x0f][..], "pop cs", "db 0x0f"),
+        (&[0x60, 0x02], "jo 0x0104", "pusha"),
+        (&[0xc1, 0xe0, 0x04], "ret", "shl ax, 0x04"),
+        (
+            &[0xc8, 0x10, 0x00, 0x00],
+            "retf 0x0010",
+            "enter 0x0010, 0x00",
+        ),
+        (&[0x6a, 0xff], "jp 0x0101", "push 0xffff"),
+        (&[0x6b, 0xc3, 0x03], "jnp 0x00c5", "imul ax, bx, 0x0003"),
+    ] {
+        assert_eq!(i8086(bytes), old);
+        assert_eq!(text(bytes, Isa::I186), new);
+    }
+    assert_eq!(text(&[0x63, 0xc8], Isa::I186), "db 0x63");
+    assert_eq!(text(&[0x63, 0xc8], Isa::I286), "arpl ax, cx");
+    assert_eq!(
+        text(&[0x0f, 0x01, 0x16, 0x00, 0x10], Isa::I286),
+        "lgdt [0x1000]"
+    );
+    assert_eq!(text(&[0x0f, 0x00, 0xd8], Isa::I286), "ltr ax");
+    assert_eq!(text(&[0x0f, 0x06], Isa::I286), "clts");
+    assert_eq!(text(&[0x0f, 0x01, 0xe8], Isa::I286), "db 0x0f");
+}
+
+#[test]
+fn test_disassemble() {
+    // mov ax, 0x1234; nop; then half an instruction.
+    let code = [0xb8, 0x34, 0x12, 0x90, 0xb8, 0x00];
+    let instructions = disassemble(&code, 0xf000, 0xfff0, Isa::I286);
+    assert_eq!(instructions.len(), 2);
+    assert_eq!(instructions[0].bytes, [0xb8, 0x34, 0x12]);
+    assert_eq!(instructions[1].ip, 0xfff3);
+    assert_eq!(instructions[1].next_ip(), 0xfff4);
+    assert_eq!(
+        instructions[0].to_string(),
+        "F000:FFF0  B83412        mov ax, 0x1234"
+    );
+
+    // Something undefined is one byte long, and decoding carries on after
+    // it.
+    let instructions = disassemble(&[0xff, 0xf8, 0x90], 0, 0, Isa::I8086);
+    assert_eq!(instructions[0].text, "db 0xff");
+    assert_eq!(instructions[1].text, "clc");
+    assert_eq!(instructions[2].text, "nop");
+}
@@ -0,0 +1,217 @@
+use crate::hardware::reset::{Reset, ResetKind};
+
+/// The AT's A20 gate. The keyboard controller's output port drives it, and
+/// later boards added port 92h ("fast A20") as a quicker way in. Either
+/// one enabling it is enough.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct A20Gate {
+    pub keyboard_controller: bool,
+    pub fast: bool,
+}
+
+impl Default for A20Gate {
+    fn default() -> A20Gate {
+        A20Gate::new()
+    }
+}
+
+impl A20Gate {
+    /// The 8042 output port comes out of reset with A20 enabled.
+    pub fn new() -> A20Gate {
+        A20Gate {
+            keyboard_controller: true,
+            fast: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.keyboard_controller || self.fast
+    }
+
+    /// Mask to apply to 24-bit physical addresses.
+    pub fn mask(&self) -> u32 {
+        if self.enabled() {
+            0xff_ffff
+        } else {
+            0xef_ffff
+        }
+    }
+}
+
+/// The 8042 and port 92h are both on the system reset line, so a CPU-only
+/// reset leaves A20 as it was.
+impl Reset for A20Gate {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind != ResetKind::Warm {
+            *self = A20Gate::new();
+        }
+    }
+}
+
+// Everything around the 1 MB boundary, through each CPU core and the
+// boards' own buses. Real mode software depends on all of it: DOS's CALL 5
+// entry point relies on the wrap, HIMEM.SYS on the HMA not wrapping.
+
+#[cfg(test)]
+use crate::cpu286::registers::{SegReg as SegReg286, SegmentRegister};
+#[cfg(test)]
+use crate::cpu286::{Cpu286, Cpu286Context};
+#[cfg(test)]
+use crate::cpu386::registers::SegReg as SegReg386;
+#[cfg(test)]
+use crate::cpu386::{operand::OpSize, Cpu386, Cpu386Context};
+#[cfg(test)]
+use crate::cpu8086::{Cpu8086, Cpu8086Context};
+#[cfg(test)]
+use crate::hardware::ibmpc5150machine::IbmPc5150Hardware;
+#[cfg(test)]
+use crate::hardware::ibmpcatmachine::IbmPcAtHardware;
+
+/// 2 MB of RAM behind an A20 gate, enough to see the HMA.
+#[cfg(test)]
+struct HmaBus {
+    ram: Vec<u8>,
+    a20: A20Gate,
+}
+
+#[cfg(test)]
+impl Cpu286Context for HmaBus {
+    fn mem_read_byte(&mut self, addr: u32) -> u8 {
+        self.ram.get(addr as usize).copied().unwrap_or(0xff)
+    }
+    fn mem_write_byte(&mut self, addr: u32, value: u8) {
+        if let Some(byte) = self.ram.get_mut(addr as usize) {
+            *byte = value;
+        }
+    }
+    fn io_read_byte(&mut self, _addr: u16) -> u8 {
+        0xff
+    }
+    fn io_write_byte(&mut self, _addr: u16, _value: u8) {}
+    fn a20_mask(&self) -> u32 {
+        self.a20.mask()
+    }
+}
+
+/// A 386 board gates the same line but passes the other 31 through.
+#[cfg(test)]
+impl Cpu386Context for HmaBus {
+    fn mem_read_byte(&mut self, addr: u32) -> u8 {
+        Cpu286Context::mem_read_byte(self, addr)
+    }
+    fn mem_write_byte(&mut self, addr: u32, value: u8) {
+        Cpu286Context::mem_write_byte(self, addr, value)
+    }
+    fn io_read_byte(&mut self, _addr: u16) -> u8 {
+        0xff
+    }
+    fn io_write_byte(&mut self, _addr: u16, _value: u8) {}
+    fn a20_mask(&self) -> u32 {
+        if self.a20.enabled() {
+            0xffff_ffff
+        } else {
+            0xffef_ffff
+        }
+    }
+}
+
+#[test]
+fn test_8086_wraps_at_1mb() {
+    let mut hardware = IbmPc5150Hardware::new();
+    let mut cpu = Cpu8086::new();
+    cpu.mem_write_byte(&mut hardware, 0xffff, 0x0010, 0x55);
+    assert_eq!(hardware.memory.ram[0], 0x55);
+    // The low byte lands in ROM, the high byte wraps to 0.
+    cpu.mem_write_word(&mut hardware, 0xffff, 0x000f, 0x1234);
+    assert_eq!(hardware.memory.ram[0], 0x12);
+    // Offsets wrap within the segment before the segment is added.
+    hardware.memory.ram[0xffff] = 0x78;
+    assert_eq!(cpu.mem_read_word(&mut hardware, 0, 0xffff), 0x1278);
+    cpu.mem_write_word(&mut hardware, 0, 0xffff, 0xabcd);
+    assert_eq!(hardware.memory.ram[0], 0xab);
+    assert_eq!(hardware.mem_read_byte(0x1_0000), 0xff);
+}
+
+#[test]
+fn test_286_a20_gate_on_the_at_bus() {
+    let mut hardware = IbmPcAtHardware::new();
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg286::ES, 0xffff);
+
+    hardware.a20.keyboard_controller = false;
+    cpu.write8(&mut hardware, SegReg286::ES, 0x10, 0x55)
+        .unwrap();
+    assert_eq!(hardware.memory.ram[0], 0x55);
+    cpu.write16(&mut hardware, SegReg286::ES, 0x0f, 0x1234)
+        .unwrap();
+    assert_eq!(hardware.memory.ram[0], 0x12);
+
+    // Either source of A20 stops the wrap; the AT has nothing above 1 MB
+    // yet, so the HMA reads as open bus.
+    for (keyboard_controller, fast) in [(true, false), (false, true)].iter() {
+        hardware.a20.keyboard_controller = *keyboard_controller;
+        hardware.a20.fast = *fast;
+        cpu.write8(&mut hardware, SegReg286::ES, 0x10, 0xaa)
+            .unwrap();
+        assert_eq!(hardware.memory.ram[0], 0x12);
+        assert_eq!(cpu.read8(&mut hardware, SegReg286::ES, 0x10), Ok(0xff));
+    }
+}
+
+#[test]
+fn test_hma_through_286_and_386() {
+    let mut bus = HmaBus {
+        ram: vec![0; 0x20_0000],
+        a20: A20Gate::new(),
+    };
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(SegReg286::ES, 0xffff);
+    cpu.write8(&mut bus, SegReg286::ES, 0x10, 0x11).unwrap();
+    cpu.write8(&mut bus, SegReg286::ES, 0xffff, 0x22).unwrap();
+    assert_eq!(bus.ram[0x10_0000], 0x11);
+    assert_eq!(bus.ram[0x10_ffef], 0x22);
+    assert_eq!(bus.ram[0], 0);
+
+    let mut cpu = Cpu386::new();
+    cpu.regs.writeseg_real(SegReg386::ES, 0xffff);
+    assert_eq!(
+        cpu.read(&mut bus, SegReg386::ES, 0xffff, OpSize::Byte),
+        Ok(0x22)
+    );
+    cpu.write(&mut bus, SegReg386::ES, 0x10, OpSize::Word, 0x3344)
+        .unwrap();
+    assert_eq!(&bus.ram[0x10_0000..0x10_0002], &[0x44, 0x33]);
+
+    bus.a20.keyboard_controller = false;
+    assert_eq!(cpu.read(&mut bus, SegReg386::ES, 0x10, OpSize::Byte), Ok(0));
+    cpu.write(&mut bus, SegReg386::ES, 0x0f, OpSize::Word, 0x6655)
+        .unwrap();
+    assert_eq!(bus.ram[0], 0x66);
+    assert_eq!(bus.ram[0x0f_ffff], 0x55);
+}
+
+#[test]
+fn test_286_masks_addresses_to_24_bits() {
+    let mut bus = HmaBus {
+        ram: vec![0; 0x20_0000],
+        a20: A20Gate::new(),
+    };
+    let mut cpu = Cpu286::new();
+    // A protected mode segment near the top of the 16 MB address space;
+    // the 286 drops the carry out of bit 23.
+    cpu.regs.setseg(
+        SegReg286::ES,
+        SegmentRegister {
+            selector: 0x08,
+            base: 0xff_fff0,
+            limit: 0xffff,
+            rights: 0x93,
+            valid: true,
+        },
+    );
+    cpu.write16(&mut bus, SegReg286::ES, 0x20, 0xbeef).unwrap();
+    assert_eq!(&bus.ram[0x10..0x12], &[0xef, 0xbe]);
+    // Bit 20 of that is already clear, so A20 makes no difference.
+    bus.a20.keyboard_controller = false;
+    assert_eq!(cpu.read16(&mut bus, SegReg286::ES, 0x20), Ok(0xbeef));
+}
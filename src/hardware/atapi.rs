@@ -0,0 +1,424 @@
+use crate::hardware::cdrom::{to_msf, CdImage, ReadError, CD_SECTOR_SIZE};
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace, warn};
+
+/// What went wrong with a packet command, for REQUEST SENSE: the sense key,
+/// then the additional sense code and its qualifier.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sense {
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+const fn sense(key: u8, asc: u8, ascq: u8) -> Sense {
+    Sense { key, asc, ascq }
+}
+
+const NO_SENSE: Sense = sense(0, 0, 0);
+const NO_MEDIUM: Sense = sense(0x02, 0x3a, 0x00);
+const UNRECOVERED_READ_ERROR: Sense = sense(0x03, 0x11, 0x00);
+const INVALID_COMMAND: Sense = sense(0x05, 0x20, 0x00);
+const LBA_OUT_OF_RANGE: Sense = sense(0x05, 0x21, 0x00);
+const INVALID_FIELD: Sense = sense(0x05, 0x24, 0x00);
+const REMOVAL_PREVENTED: Sense = sense(0x05, 0x53, 0x02);
+const ILLEGAL_MODE_FOR_TRACK: Sense = sense(0x05, 0x64, 0x00);
+const MEDIUM_CHANGED: Sense = sense(0x06, 0x28, 0x00);
+
+/// GET EVENT STATUS NOTIFICATION's media event codes.
+const EVENT_NEW_MEDIA: u8 = 0x02;
+const EVENT_MEDIA_REMOVAL: u8 = 0x03;
+/// The bit for the media class in its class masks.
+const MEDIA_CLASS: u8 = 0x10;
+
+/// The lead-out's track number in the table of contents.
+const LEAD_OUT: u8 = 0xaa;
+
+fn be16(bytes: &[u8]) -> usize {
+    u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// An ATAPI CD-ROM drive: the packet commands DOS drivers and installers
+/// use, run against whatever image is in it. The IDE channel moves the
+/// packets and the data; this only answers them.
+#[derive(Debug, Clone)]
+pub struct AtapiDrive {
+    pub image: Option<CdImage>,
+    sense: Sense,
+    /// A disc has gone in since the host last heard, so the next command
+    /// that needs one fails with UNIT ATTENTION.
+    unit_attention: bool,
+    /// The media event GET EVENT STATUS NOTIFICATION has yet to report.
+    event: Option<u8>,
+    /// PREVENT ALLOW MEDIUM REMOVAL has locked the tray.
+    locked: bool,
+}
+
+impl AtapiDrive {
+    pub fn new(image: Option<CdImage>) -> AtapiDrive {
+        AtapiDrive {
+            image,
+            sense: NO_SENSE,
+            unit_attention: false,
+            event: None,
+            locked: false,
+        }
+    }
+
+    /// Swaps the disc, letting the host know, and returns the old one.
+    pub fn insert(&mut self, image: CdImage) -> Option<CdImage> {
+        debug!(target: "disk", "Inserted {}", image.path.display());
+        self.unit_attention = true;
+        self.event = Some(EVENT_NEW_MEDIA);
+        self.image.replace(image)
+    }
+
+    /// Takes the disc out, even if the host has locked the tray, as the
+    /// paper clip in the emergency eject hole would.
+    pub fn eject(&mut self) -> Option<CdImage> {
+        let image = self.image.take();
+        if image.is_some() {
+            self.event = Some(EVENT_MEDIA_REMOVAL);
+        }
+        image
+    }
+
+    /// IDENTIFY PACKET DEVICE's 256 words.
+    pub fn identify(&self) -> Vec<u8> {
+        let mut words = [0u16; 256];
+        // ATAPI, a CD-ROM, removable, with 12 byte packets taken at once.
+        words[0] = 0x85c0;
+        let mut string = |start: usize, text: &str, length: usize| {
+            let text = format!("{:<width$}", text, width = length * 2);
+            for (i, pair) in text.as_bytes().chunks(2).take(length).enumerate() {
+                words[start + i] = u16::from_be_bytes([pair[0], pair[1]]);
+            }
+        };
+        string(10, "EMUPC0002", 10);
+        string(23, "1.0", 4);
+        string(27, "EMUPC CD-ROM", 20);
+        words[49] = 0x0200;
+        words[53] = 0x0002;
+        // PIO mode 3 and 4.
+        words[64] = 0x0003;
+        words[80] = 0x001e;
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    /// Runs a packet command, returning the data it hands back. The sense
+    /// data is kept for REQUEST SENSE either way.
+    pub fn command(&mut self, packet: &[u8]) -> Result<Vec<u8>, Sense> {
+        trace!(target: "disk", "Packet {:02x?}", packet);
+        let opcode = packet[0];
+        if opcode == 0x03 {
+            let sense = std::mem::replace(&mut self.sense, NO_SENSE);
+            return Ok(self.request_sense(sense, packet[4] as usize));
+        }
+        // These report on the drive rather than the disc, and don't clear
+        // a pending UNIT ATTENTION.
+        let result = if matches!(opcode, 0x12 | 0x4a) {
+            self.execute(packet)
+        } else if std::mem::take(&mut self.unit_attention) {
+            Err(MEDIUM_CHANGED)
+        } else {
+            self.execute(packet)
+        };
+        self.sense = result.as_ref().err().copied().unwrap_or(NO_SENSE);
+        if let Err(sense) = result {
+            debug!(target: "disk", "Packet command {:#04x} failed: {:?}", opcode, sense);
+        }
+        result
+    }
+
+    fn execute(&mut self, packet: &[u8]) -> Result<Vec<u8>, Sense> {
+        match packet[0] {
+            // TEST UNIT READY and SEEK only need a disc.
+            0x00 | 0x2b => self.disc().map(|_| vec![]),
+            0x12 => Ok(self.inquiry(packet[4] as usize)),
+            0x1b => self.start_stop(packet[4]),
+            0x1e => {
+                self.locked = (packet[4] & 1) != 0;
+                Ok(vec![])
+            }
+            0x25 => {
+                let last = self.disc()?.total_sectors().saturating_sub(1);
+                Ok([last.to_be_bytes(), (CD_SECTOR_SIZE as u32).to_be_bytes()].concat())
+            }
+            0x28 => self.read(be32(&packet[2..]), be16(&packet[7..]) as u32),
+            0xa8 => self.read(be32(&packet[2..]), be32(&packet[6..])),
+            0x43 => self.read_toc(packet),
+            0x4a => self.event_status(packet),
+            0x5a => self.mode_sense(packet[2] & 0x3f, be16(&packet[7..])),
+            opcode => {
+                debug!(target: "disk", "Unsupported packet command {:#04x}", opcode);
+                Err(INVALID_COMMAND)
+            }
+        }
+    }
+
+    fn disc(&self) -> Result<&CdImage, Sense> {
+        self.image.as_ref().ok_or(NO_MEDIUM)
+    }
+
+    fn request_sense(&self, sense: Sense, length: usize) -> Vec<u8> {
+        let mut data = vec![0; 18];
+        data[0] = 0x70;
+        data[2] = sense.key;
+        data[7] = 10;
+        data[12] = sense.asc;
+        data[13] = sense.ascq;
+        data.truncate(length);
+        data
+    }
+
+    fn inquiry(&self, length: usize) -> Vec<u8> {
+        let mut data = vec![0x05, 0x80, 0x00, 0x21, 31, 0, 0, 0];
+        data.extend(b"EMUPC   CD-ROM          1.0 ");
+        data.truncate(length);
+        data
+    }
+
+    /// START STOP UNIT only does anything here when it ejects the disc.
+    fn start_stop(&mut self, flags: u8) -> Result<Vec<u8>, Sense> {
+        let load_eject = (flags & 0x02) != 0;
+        let start = (flags & 0x01) != 0;
+        if load_eject && !start {
+            if self.locked {
+                return Err(REMOVAL_PREVENTED);
+            }
+            self.eject();
+        }
+        Ok(vec![])
+    }
+
+    fn read(&self, lba: u32, count: u32) -> Result<Vec<u8>, Sense> {
+        let image = self.disc()?;
+        let mut data = Vec::with_capacity(count as usize * CD_SECTOR_SIZE);
+        for lba in lba..lba.saturating_add(count) {
+            match image.read_sector(lba) {
+                Ok(sector) => data.extend(sector),
+                Err(ReadError::OutOfRange) => return Err(LBA_OUT_OF_RANGE),
+                Err(ReadError::AudioTrack) => return Err(ILLEGAL_MODE_FOR_TRACK),
+                Err(ReadError::Io(err)) => {
+                    warn!(target: "disk", "Couldn't read sector {}: {}", lba, err);
+                    return Err(UNRECOVERED_READ_ERROR);
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// READ TOC in the track list and session formats, with addresses as
+    /// LBAs or MSF.
+    fn read_toc(&self, packet: &[u8]) -> Result<Vec<u8>, Sense> {
+        let image = self.disc()?;
+        let msf = (packet[1] & 0x02) != 0;
+        // Older drivers put the format in the top of the control byte.
+        let format = match packet[2] & 0x0f {
+            0 => packet[9] >> 6,
+            format => format,
+        };
+        let address = |lba: u32| match msf {
+            true => {
+                let (minutes, seconds, frames) = to_msf(lba);
+                [0, minutes, seconds, frames]
+            }
+            false => lba.to_be_bytes(),
+        };
+        let descriptor = |number: u8, audio: bool, lba: u32| {
+            let control = if audio { 0x10 } else { 0x14 };
+            [[0, control, number, 0], address(lba)].concat()
+        };
+        let first = image.tracks.first().map_or(1, |track| track.number);
+        let last = image.tracks.last().map_or(1, |track| track.number);
+        let mut data = vec![0, 0, first, last];
+        match format {
+            0 => {
+                let start = packet[6];
+                if start > last && start != LEAD_OUT {
+                    return Err(INVALID_FIELD);
+                }
+                for track in image.tracks.iter().filter(|track| track.number >= start) {
+                    data.extend(descriptor(track.number, track.audio, track.start));
+                }
+                data.extend(descriptor(LEAD_OUT, false, image.total_sectors()));
+            }
+            // There's only ever the one session.
+            1 => {
+                data[2] = 1;
+                data[3] = 1;
+                let track = &image.tracks[0];
+                data.extend(descriptor(track.number, track.audio, track.start));
+            }
+            _ => return Err(INVALID_FIELD),
+        }
+        let length = (data.len() - 2) as u16;
+        data[..2].copy_from_slice(&length.to_be_bytes());
+        data.truncate(be16(&packet[7..]));
+        Ok(data)
+    }
+
+    /// GET EVENT STATUS NOTIFICATION, polled, for the media class only.
+    fn event_status(&mut self, packet: &[u8]) -> Result<Vec<u8>, Sense> {
+        if (packet[1] & 0x01) == 0 {
+            return Err(INVALID_FIELD);
+        }
+        let mut data = vec![0, 2, 0x80, MEDIA_CLASS];
+        if (packet[4] & MEDIA_CLASS) != 0 {
+            let code = self.event.take().unwrap_or(0);
+            let present = self.image.is_some() as u8 * 0x02;
+            data[1] = 6;
+            data[2] = 0x04;
+            data.extend([code, present, 0, 0]);
+        }
+        data.truncate(be16(&packet[7..]));
+        Ok(data)
+    }
+
+    /// MODE SENSE (10) for the error recovery, CD-ROM and capabilities
+    /// pages, none of which can be changed.
+    fn mode_sense(&self, page: u8, length: usize) -> Result<Vec<u8>, Sense> {
+        let pages: [(u8, &[u8]); 3] = [
+            (0x01, &[0x01, 0x06, 0x00, 0x05, 0, 0, 0, 0]),
+            (0x0d, &[0x0d, 0x06, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x4b]),
+            // Reads mode 2 form 1, ejects and locks a tray, at 4x.
+            (
+                0x2a,
+                &[
+                    0x2a, 0x12, 0x00, 0x00, 0x10, 0x00, 0x29, 0x00, 0x02, 0xc2, 0x01, 0x00, 0x00,
+                    0x40, 0x02, 0xc2, 0x00, 0x00, 0x00, 0x00,
+                ],
+            ),
+        ];
+        // A 120mm data disc, or a closed tray with nothing in it.
+        let medium = if self.image.is_some() { 0x01 } else { 0x70 };
+        let mut data = vec![0, 0, medium, 0, 0, 0, 0, 0];
+        let mut found = false;
+        for &(number, bytes) in pages.iter() {
+            if page == number || page == 0x3f {
+                data.extend(bytes);
+                found = true;
+            }
+        }
+        if !found {
+            return Err(INVALID_FIELD);
+        }
+        let total = (data.len() - 2) as u16;
+        data[..2].copy_from_slice(&total.to_be_bytes());
+        data.truncate(length);
+        Ok(data)
+    }
+}
+
+// A reset unlocks the tray, and the host hears about the disc again as if
+// it had just gone in.
+impl Reset for AtapiDrive {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        self.sense = NO_SENSE;
+        self.locked = false;
+        self.unit_attention = self.image.is_some();
+    }
+}
+
+#[cfg(test)]
+use crate::hardware::ide::IDE;
+
+#[test]
+fn test_atapi_packet_commands() {
+    let path = std::env::temp_dir().join("emupc-atapi-test.iso");
+    let iso: Vec<u8> = (0..20u8).flat_map(|sector| vec![sector; 2048]).collect();
+    std::fs::write(&path, iso).unwrap();
+    let image = CdImage::open(path.to_str().unwrap()).unwrap();
+    let mut ide = IDE::secondary();
+    ide.cdroms[0] = Some(AtapiDrive::new(Some(image.clone())));
+    ide.reset(ResetKind::Cold);
+    assert_eq!((ide.rb(0x174), ide.rb(0x175)), (0x14, 0xeb));
+
+    // It isn't a hard disk, but IDENTIFY PACKET DEVICE works.
+    ide.wb(0x177, 0xec);
+    assert_eq!((ide.rb(0x177), ide.rb(0x171)), (0x51, 0x04));
+    ide.wb(0x177, 0xa1);
+    assert_eq!(ide.rb(0x177), 0x58);
+    let identify: Vec<u16> = (0..256).map(|_| ide.read_data()).collect();
+    assert_eq!(identify[0], 0x85c0);
+    assert_eq!(ide.rb(0x177), 0x50);
+
+    // Sends a packet, letting the drive hand back `limit` bytes at a time.
+    let packet = |ide: &mut IDE, bytes: &[u8], limit: u16| {
+        ide.wb(0x174, limit as u8);
+        ide.wb(0x175, (limit >> 8) as u8);
+        ide.wb(0x177, 0xa0);
+        assert_eq!((ide.rb(0x376), ide.rb(0x172)), (0x58, 0x01));
+        let mut bytes = bytes.to_vec();
+        bytes.resize(12, 0);
+        for pair in bytes.chunks(2) {
+            ide.write_data(u16::from_le_bytes([pair[0], pair[1]]));
+        }
+    };
+    let reply = |ide: &mut IDE| {
+        let mut data = vec![];
+        while ide.rb(0x177) & 0x08 != 0 {
+            assert_eq!(ide.rb(0x172), 0x02);
+            let length = u16::from_le_bytes([ide.rb(0x174), ide.rb(0x175)]);
+            for _ in 0..length.div_ceil(2) {
+                data.extend(ide.read_data().to_le_bytes());
+            }
+            data.truncate(data.len() - length as usize % 2);
+        }
+        data
+    };
+
+    // The reset leaves a UNIT ATTENTION for the disc.
+    packet(&mut ide, &[0x00], 0);
+    assert!(ide.irq());
+    assert_eq!(ide.rb(0x177), 0x51);
+    assert_eq!((ide.rb(0x171), ide.rb(0x172)), (0x64, 0x03));
+    packet(&mut ide, &[0x03, 0, 0, 0, 18], 0);
+    let sense = reply(&mut ide);
+    assert_eq!((sense.len(), sense[2], sense[12]), (18, 0x06, 0x28));
+    assert_eq!(ide.rb(0x172), 0x03);
+
+    // Two sectors, 2K per interrupt.
+    packet(&mut ide, &[0x28, 0, 0, 0, 0, 3, 0, 0, 2], 2048);
+    assert_eq!(ide.rb(0x175), 0x08);
+    let data = reply(&mut ide);
+    assert_eq!(data.len(), 4096);
+    assert_eq!((data[0], data[2048]), (3, 4));
+    packet(&mut ide, &[0x28, 0, 0, 0, 0, 19, 0, 0, 2], 0);
+    assert_eq!(ide.rb(0x171), 0x54);
+
+    packet(&mut ide, &[0x43, 0, 0, 0, 0, 0, 0, 0, 0xff], 0);
+    assert_eq!(
+        reply(&mut ide),
+        vec![0, 18, 1, 1, 0, 0x14, 1, 0, 0, 0, 0, 0, 0, 0x14, 0xaa, 0, 0, 0, 0, 20]
+    );
+    packet(&mut ide, &[0x25], 0);
+    assert_eq!(reply(&mut ide), vec![0, 0, 0, 19, 0, 0, 8, 0]);
+
+    // The host locks the tray, so ejecting fails until it's unlocked, then
+    // hears the disc went.
+    packet(&mut ide, &[0x1e, 0, 0, 0, 1], 0);
+    packet(&mut ide, &[0x1b, 0, 0, 0, 0x02], 0);
+    assert_eq!(ide.rb(0x171), 0x54);
+    packet(&mut ide, &[0x1e], 0);
+    packet(&mut ide, &[0x1b, 0, 0, 0, 0x02], 0);
+    assert_eq!(ide.rb(0x177), 0x50);
+    let event = [0x4a, 0x01, 0, 0, MEDIA_CLASS, 0, 0, 0, 8];
+    packet(&mut ide, &event, 0);
+    assert_eq!(reply(&mut ide), vec![0, 6, 0x04, 0x10, 0x03, 0, 0, 0]);
+    packet(&mut ide, &[0x00], 0);
+    assert_eq!(ide.rb(0x171), 0x24);
+
+    let cdrom = ide.cdroms[0].as_mut().unwrap();
+    assert!(cdrom.insert(image).is_none());
+    packet(&mut ide, &event, 0);
+    assert_eq!(reply(&mut ide), vec![0, 6, 0x04, 0x10, 0x02, 0x02, 0, 0]);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,338 @@
+// What stops a run before it's done: breakpoints on the instruction about
+// to run, by CS:IP or by physical address, and watchpoints on the memory
+// the last instruction read or wrote. Ports are watched the same way by
+// the board's `PortWatch`. The run loop asks after every instruction, so
+// all of them are looked up by address, in a hash or an ordered map,
+// rather than checked one by one, and with none set the check is a test
+// for an empty table.
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// Where an instruction breakpoint is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Breakpoint {
+    /// CS:IP, with CS as the CPU has it, which is a selector in protected
+    /// mode.
+    Address(u16, u16),
+    /// A physical address, whatever segment gets there.
+    Physical(u32),
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breakpoint::Address(cs, ip) => write!(f, "{:04X}:{:04X}", cs, ip),
+            Breakpoint::Physical(addr) => write!(f, "{:05X}", addr),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Breakpoints {
+    set: HashSet<Breakpoint>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    /// Adds a breakpoint, returning whether it wasn't there already.
+    pub fn add(&mut self, breakpoint: Breakpoint) -> bool {
+        self.set.insert(breakpoint)
+    }
+
+    pub fn remove(&mut self, breakpoint: Breakpoint) -> bool {
+        self.set.remove(&breakpoint)
+    }
+
+    pub fn contains(&self, breakpoint: Breakpoint) -> bool {
+        self.set.contains(&breakpoint)
+    }
+
+    pub fn clear(&mut self) {
+        self.set.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    /// All the breakpoints, CS:IP ones first, in address order.
+    pub fn list(&self) -> Vec<Breakpoint> {
+        let mut list: Vec<Breakpoint> = self.set.iter().copied().collect();
+        list.sort();
+        list
+    }
+
+    /// Whether there's a breakpoint on the instruction at `cs`:`ip`, which
+    /// is at `physical`.
+    pub fn hit(&self, cs: u16, ip: u16, physical: u32) -> bool {
+        !self.set.is_empty()
+            && (self.set.contains(&Breakpoint::Address(cs, ip))
+                || self.set.contains(&Breakpoint::Physical(physical)))
+    }
+}
+
+/// The accesses a watchpoint stops on, and which of them one caught.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Watch {
+    /// `r`, `w` or `rw`.
+    pub fn parse(text: &str) -> Result<Watch, String> {
+        match text {
+            "r" => Ok(Watch::Read),
+            "w" => Ok(Watch::Write),
+            "rw" => Ok(Watch::ReadWrite),
+            _ => Err(format!("expected r, w or rw, not '{}'", text)),
+        }
+    }
+
+    /// Whether a watchpoint for these accesses catches `access`.
+    pub fn covers(self, access: Watch) -> bool {
+        self == Watch::ReadWrite || self == access
+    }
+
+    /// What two watchpoints over the same address stop on between them.
+    fn with(self, other: Watch) -> Watch {
+        match self == other {
+            true => self,
+            false => Watch::ReadWrite,
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Watch::Read => "r",
+            Watch::Write => "w",
+            Watch::ReadWrite => "rw",
+        })
+    }
+}
+
+/// Ranges of physical memory to stop the machine on reads or writes of,
+/// after the instruction that made them. The board calls `rb` and `wb`
+/// for every byte the CPU reads and writes, instruction fetches included.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryWatch {
+    /// The first and last address of each watchpoint, and what it's for.
+    watches: Vec<(u32, u32, Watch)>,
+    /// The watched addresses, cut up where watchpoints start and end so
+    /// the pieces don't overlap, by first address. Each piece has its last
+    /// address and the accesses any watchpoint over it stops on, so a
+    /// watchpoint over all of memory is as cheap to look up as one byte.
+    ranges: BTreeMap<u32, (u32, Watch)>,
+    /// The address, access and value of the last access a watchpoint
+    /// caught.
+    last: Option<(u32, Watch, u8)>,
+    /// A watchpoint has caught an access the run loop hasn't stopped for
+    /// yet.
+    triggered: Option<Watch>,
+}
+
+impl MemoryWatch {
+    pub fn new() -> MemoryWatch {
+        MemoryWatch::default()
+    }
+
+    /// Watches `start` to `end`, inclusive, for `kind` accesses.
+    pub fn watch(&mut self, start: u32, end: u32, kind: Watch) {
+        self.watches.push((start.min(end), start.max(end), kind));
+        self.index();
+    }
+
+    /// Removes the watchpoint that's `index` in `watches`.
+    pub fn unwatch(&mut self, index: usize) {
+        if index < self.watches.len() {
+            self.watches.remove(index);
+            self.index();
+        }
+    }
+
+    pub fn watches(&self) -> &[(u32, u32, Watch)] {
+        &self.watches
+    }
+
+    pub fn clear(&mut self) {
+        self.watches.clear();
+        self.ranges.clear();
+        self.last = None;
+        self.triggered = None;
+    }
+
+    fn index(&mut self) {
+        self.ranges.clear();
+        // Every address a watchpoint starts at or stops just short of, with
+        // the end of the address space as 2^32.
+        let mut edges: Vec<u64> = self
+            .watches
+            .iter()
+            .flat_map(|&(start, end, _)| [start as u64, end as u64 + 1])
+            .collect();
+        edges.sort_unstable();
+        edges.dedup();
+        for piece in edges.windows(2) {
+            let (start, end) = (piece[0] as u32, (piece[1] - 1) as u32);
+            let covering = self
+                .watches
+                .iter()
+                .filter(|&&(first, last, _)| first <= start && end <= last);
+            let kind = match covering.map(|&(_, _, kind)| kind).reduce(Watch::with) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            // A piece that carries straight on from the last, stopping on
+            // the same accesses, goes back together with it.
+            if let Some((_, (last, was))) = self.ranges.iter_mut().next_back() {
+                if *last as u64 + 1 == start as u64 && *was == kind {
+                    *last = end;
+                    continue;
+                }
+            }
+            self.ranges.insert(start, (end, kind));
+        }
+    }
+
+    /// The board calls this for every byte read from memory.
+    #[inline]
+    pub fn rb(&mut self, addr: u32, data: u8) {
+        if !self.ranges.is_empty() {
+            self.check(addr, Watch::Read, data);
+        }
+    }
+
+    /// The board calls this for every byte written to memory.
+    #[inline]
+    pub fn wb(&mut self, addr: u32, data: u8) {
+        if !self.ranges.is_empty() {
+            self.check(addr, Watch::Write, data);
+        }
+    }
+
+    fn check(&mut self, addr: u32, access: Watch, data: u8) {
+        let hit = self
+            .ranges
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(_, &(end, kind))| addr <= end && kind.covers(access));
+        if hit {
+            self.last = Some((addr, access, data));
+            self.triggered = Some(access);
+        }
+    }
+
+    /// The address, access and value of the last access a watchpoint
+    /// caught.
+    pub fn last(&self) -> Option<(u32, Watch, u8)> {
+        self.last
+    }
+
+    /// The kind of access a watchpoint has caught since the last time this
+    /// was asked, if one has, for the run loop to stop on.
+    pub fn take_triggered(&mut self) -> Option<Watch> {
+        self.triggered.take()
+    }
+}
+
+#[test]
+fn test_breakpoints() {
+    let mut breakpoints = Breakpoints::new();
+    assert!(!breakpoints.hit(0xf000, 0xe05b, 0xfe05b));
+    assert!(breakpoints.add(Breakpoint::Physical(0xfe05b)));
+    assert!(breakpoints.add(Breakpoint::Address(0x0000, 0x7c00)));
+    assert!(!breakpoints.add(Breakpoint::Address(0x0000, 0x7c00)));
+    // A physical breakpoint is hit whatever the segment.
+    assert!(breakpoints.hit(0xf000, 0xe05b, 0xfe05b));
+    assert!(breakpoints.hit(0xfe00, 0x005b, 0xfe05b));
+    assert!(breakpoints.hit(0x0000, 0x7c00, 0x7c00));
+    assert!(!breakpoints.hit(0x07c0, 0x0000, 0x7c00));
+    assert_eq!(
+        breakpoints.list(),
+        [
+            Breakpoint::Address(0x0000, 0x7c00),
+            Breakpoint::Physical(0xfe05b)
+        ]
+    );
+    assert!(breakpoints.remove(Breakpoint::Physical(0xfe05b)));
+    assert!(!breakpoints.hit(0xf000, 0xe05b, 0xfe05b));
+    assert_eq!(Breakpoint::Address(0xf000, 0xe05b).to_string(), "F000:E05B");
+}
+
+#[test]
+fn test_memory_watch() {
+    let mut watch = MemoryWatch::new();
+    watch.wb(0x400, 0x12);
+    assert_eq!(watch.take_triggered(), None);
+
+    // Across a page boundary, for writes only.
+    watch.watch(0xff8, 0x1007, Watch::Write);
+    watch.watch(0xb8000, 0xb8000, Watch::ReadWrite);
+    watch.rb(0xffc, 0x34);
+    assert_eq!(watch.take_triggered(), None);
+    watch.wb(0x1007, 0x56);
+    assert_eq!(watch.take_triggered(), Some(Watch::Write));
+    assert_eq!(watch.take_triggered(), None);
+    assert_eq!(watch.last(), Some((0x1007, Watch::Write, 0x56)));
+    watch.wb(0x1008, 0x56);
+    watch.wb(0xff7, 0x56);
+    assert_eq!(watch.take_triggered(), None);
+    watch.rb(0xb8000, 0x20);
+    assert_eq!(watch.take_triggered(), Some(Watch::Read));
+
+    watch.unwatch(0);
+    assert_eq!(watch.watches(), [(0xb8000, 0xb8000, Watch::ReadWrite)]);
+    watch.wb(0x1000, 0x56);
+    assert_eq!(watch.take_triggered(), None);
+    watch.wb(0xb8000, 0x41);
+    assert_eq!(watch.take_triggered(), Some(Watch::Write));
+}
+
+#[test]
+fn test_wide_and_overlapping_memory_watches() {
+    let mut watch = MemoryWatch::new();
+    // All of memory, with a read watchpoint inside it and another that
+    // straddles the first's end.
+    watch.watch(0, 0xffff_ffff, Watch::Write);
+    watch.watch(0x2000, 0x2fff, Watch::Read);
+    watch.watch(0x2800, 0x37ff, Watch::Read);
+    assert_eq!(
+        watch
+            .ranges
+            .iter()
+            .map(|(&start, &range)| (start, range))
+            .collect::<Vec<_>>(),
+        [
+            (0, (0x1fff, Watch::Write)),
+            (0x2000, (0x37ff, Watch::ReadWrite)),
+            (0x3800, (0xffff_ffff, Watch::Write)),
+        ]
+    );
+    watch.wb(0xffff_ffff, 0x12);
+    assert_eq!(watch.take_triggered(), Some(Watch::Write));
+    watch.rb(0x1fff, 0x34);
+    assert_eq!(watch.take_triggered(), None);
+    watch.rb(0x37ff, 0x56);
+    assert_eq!(watch.take_triggered(), Some(Watch::Read));
+    watch.rb(0x3800, 0x56);
+    assert_eq!(watch.take_triggered(), None);
+
+    // Without the wide one, only the reads are left, and nothing between.
+    watch.unwatch(0);
+    watch.wb(0xffff_fff0, 0x12);
+    watch.wb(0x2000, 0x12);
+    assert_eq!(watch.take_triggered(), None);
+    watch.rb(0x2fff, 0x78);
+    assert_eq!(watch.take_triggered(), Some(Watch::Read));
+    assert_eq!(watch.last(), Some((0x2fff, Watch::Read, 0x78)));
+    watch.unwatch(0);
+    assert_eq!(watch.ranges.len(), 1);
+    watch.rb(0x2000, 0x78);
+    assert_eq!(watch.take_triggered(), None);
+}
@@ -0,0 +1,326 @@
+// Puts a machine together part by part, for configurations no template
+// covers: an XT with a card of the caller's own in place of a video
+// adapter, say, or an AT without a floppy controller. It starts from a
+// bare board with its standard floppy drives and no adapters, and the
+// board's own RAM size and clock unless told otherwise. Cards the boards
+// know about are fitted by `IsaCard`; anything else goes on the board's
+// device list.
+use crate::hardware::device::Device;
+use crate::hardware::fdc::FDC;
+use crate::hardware::floppy::{DriveType, FloppyDrive};
+use crate::hardware::machine::{IsaCard, Machine};
+use crate::hardware::memory::UpperMemory;
+use crate::hardware::ppi::{DisplaySwitch, PpiModel};
+use crate::hardware::reset::ResetKind;
+use crate::hardware::rom::{BiosImage, OptionRom};
+use crate::hardware::templates::{Board, VideoCard};
+use crate::hardware::video::cga::CGA;
+use crate::hardware::video::ega::EGA;
+use crate::hardware::video::mda::MDA;
+use crate::hardware::{IbmPc5150Machine, IbmPcAtMachine};
+
+#[derive(Debug)]
+pub struct MachineBuilder {
+    board: Board,
+    clock_hz: Option<u32>,
+    slow_clock_hz: Option<u32>,
+    ram_kb: Option<usize>,
+    extended_kb: usize,
+    video: Option<VideoCard>,
+    /// `None` for a board without a floppy controller.
+    floppy_drives: Option<[Option<DriveType>; 2]>,
+    cards: Vec<IsaCard>,
+    devices: Vec<Box<dyn Device>>,
+    bios: Option<BiosImage>,
+    option_roms: Vec<(String, Option<u32>)>,
+    upper_memory: Vec<(u32, u32, UpperMemory)>,
+}
+
+impl MachineBuilder {
+    pub fn new(board: Board) -> MachineBuilder {
+        let floppy_drives = match board {
+            Board::Ibm5150 | Board::Ibm5160 | Board::GenericXt => [Some(DriveType::Drive360K); 2],
+            Board::Ibm5170 | Board::Generic286 => {
+                [Some(DriveType::Drive1200K), Some(DriveType::Drive360K)]
+            }
+        };
+        MachineBuilder {
+            board,
+            clock_hz: None,
+            slow_clock_hz: None,
+            ram_kb: None,
+            extended_kb: 0,
+            video: None,
+            floppy_drives: Some(floppy_drives),
+            cards: vec![],
+            devices: vec![],
+            bios: None,
+            option_roms: vec![],
+            upper_memory: vec![],
+        }
+    }
+
+    pub fn clock_hz(mut self, hz: u32) -> MachineBuilder {
+        self.clock_hz = Some(hz);
+        self
+    }
+
+    /// Gives the board a turbo switch, and the clock the CPU drops to when
+    /// it's off. The machine starts with turbo on.
+    pub fn slow_clock_hz(mut self, hz: u32) -> MachineBuilder {
+        self.slow_clock_hz = Some(hz);
+        self
+    }
+
+    /// Conventional RAM in kilobytes.
+    pub fn ram_kb(mut self, kb: usize) -> MachineBuilder {
+        self.ram_kb = Some(kb);
+        self
+    }
+
+    /// Memory above 1 MB in kilobytes, on an AT.
+    pub fn extended_memory_kb(mut self, kb: usize) -> MachineBuilder {
+        self.extended_kb = kb;
+        self
+    }
+
+    /// Fits one of the video adapters the boards know about. Any other,
+    /// like a VGA, has to come as a `device`, with this left unset.
+    pub fn video(mut self, card: VideoCard) -> MachineBuilder {
+        self.video = Some(card);
+        self
+    }
+
+    pub fn floppy_drives(mut self, drives: [Option<DriveType>; 2]) -> MachineBuilder {
+        self.floppy_drives = Some(drives);
+        self
+    }
+
+    /// Leaves the floppy controller out, freeing 3F0h-3F7h, IRQ 6 and DMA
+    /// channel 2.
+    pub fn without_fdc(mut self) -> MachineBuilder {
+        self.floppy_drives = None;
+        self
+    }
+
+    pub fn card(mut self, card: IsaCard) -> MachineBuilder {
+        self.cards.push(card);
+        self
+    }
+
+    /// Puts a card of the caller's own on the board's device list.
+    pub fn device(mut self, device: Box<dyn Device>) -> MachineBuilder {
+        self.devices.push(device);
+        self
+    }
+
+    /// Replaces the board's own BIOS with an image from disk.
+    pub fn bios(mut self, image: BiosImage) -> MachineBuilder {
+        self.bios = Some(image);
+        self
+    }
+
+    /// Adds an option ROM from disk, at `addr` or in the first space the
+    /// BIOS will find it in once the cards are fitted.
+    pub fn option_rom(mut self, path: &str, addr: Option<u32>) -> MachineBuilder {
+        self.option_roms.push((path.to_string(), addr));
+        self
+    }
+
+    /// Has the chipset shadow ROMs or open upper memory blocks in `start`
+    /// to `end` inclusive, once the cards and ROMs are in.
+    pub fn upper_memory(mut self, start: u32, end: u32, kind: UpperMemory) -> MachineBuilder {
+        self.upper_memory.push((start, end, kind));
+        self
+    }
+
+    fn drives(&self) -> Option<[Option<FloppyDrive>; 2]> {
+        self.floppy_drives.map(|drives| {
+            [
+                drives[0].map(FloppyDrive::new),
+                drives[1].map(FloppyDrive::new),
+            ]
+        })
+    }
+
+    fn cga(&self) -> Option<CGA> {
+        match self.video {
+            Some(VideoCard::Cga) => Some(CGA::new()),
+            _ => None,
+        }
+    }
+
+    fn mda(&self) -> Option<MDA> {
+        match self.video {
+            Some(VideoCard::Hercules) => Some(MDA::hercules()),
+            _ => None,
+        }
+    }
+
+    fn ega(&self) -> Option<EGA> {
+        match self.video {
+            Some(VideoCard::Ega) => Some(EGA::new()),
+            _ => None,
+        }
+    }
+
+    /// Builds the machine and turns it on.
+    pub fn build(self) -> Result<Machine, String> {
+        let mut machine = match self.board {
+            Board::Ibm5150 | Board::Ibm5160 | Board::GenericXt => {
+                let mut machine = IbmPc5150Machine::new();
+                let hardware = &mut machine.hardware;
+                if self.board != Board::Ibm5150 {
+                    hardware.ppi.model = PpiModel::Xt;
+                }
+                hardware.ppi.switches.floppy_drives = self
+                    .floppy_drives
+                    .map_or(0, |drives| drives.iter().flatten().count() as u8);
+                hardware.ppi.switches.display = match self.video {
+                    Some(VideoCard::Cga) => DisplaySwitch::Cga80,
+                    Some(VideoCard::Hercules) => DisplaySwitch::Mda,
+                    _ => DisplaySwitch::None,
+                };
+                hardware.common.cga = self.cga();
+                hardware.common.mda = self.mda();
+                hardware.common.ega = self.ega();
+                hardware.common.fdc = self.drives().map(FDC::pc);
+                if let Some(kb) = self.ram_kb {
+                    machine.set_ram_size(kb)?;
+                }
+                if self.extended_kb > 0 {
+                    machine.set_extended_memory(self.extended_kb)?;
+                }
+                if let Some(hz) = self.clock_hz {
+                    machine.set_clock_hz(hz)?;
+                }
+                for device in self.devices {
+                    machine.hardware.add_device(device);
+                }
+                Machine::Pc(Box::new(machine))
+            }
+            Board::Ibm5170 | Board::Generic286 => {
+                let mut machine = IbmPcAtMachine::new();
+                let hardware = &mut machine.hardware;
+                hardware.common.cga = self.cga();
+                hardware.common.mda = self.mda();
+                hardware.common.ega = self.ega();
+                hardware.common.fdc = self.drives().map(FDC::at);
+                if let Some(kb) = self.ram_kb {
+                    machine.set_ram_size(kb)?;
+                }
+                if self.extended_kb > 0 {
+                    machine.set_extended_memory(self.extended_kb)?;
+                }
+                if let Some(hz) = self.clock_hz {
+                    machine.set_clock_hz(hz)?;
+                }
+                for device in self.devices {
+                    machine.hardware.add_device(device);
+                }
+                Machine::At(Box::new(machine))
+            }
+        };
+        machine.set_slow_clock_hz(self.slow_clock_hz)?;
+        if self.slow_clock_hz.is_some() {
+            machine.set_turbo(true);
+        }
+        for card in self.cards {
+            machine.add_card(card)?;
+        }
+        if let Some(image) = &self.bios {
+            machine.load_bios(image.load()?)?;
+        }
+        for (path, addr) in &self.option_roms {
+            machine.add_option_rom(OptionRom::load(path)?, *addr)?;
+        }
+        for &(start, end, kind) in &self.upper_memory {
+            machine.set_upper_memory(start, end, kind)?;
+        }
+        machine.reset(ResetKind::Cold);
+        Ok(machine)
+    }
+}
+
+#[cfg(test)]
+use crate::cpu286::Cpu286Context;
+#[cfg(test)]
+use crate::hardware::reset::Reset;
+#[cfg(test)]
+use crate::hardware::soundblaster::SbModel;
+
+/// A latch at 300h that interrupts on IRQ 2 while bit 0 is set.
+#[cfg(test)]
+#[derive(Clone, Debug, Default)]
+struct LatchCard {
+    value: u8,
+}
+
+#[cfg(test)]
+impl Reset for LatchCard {
+    fn reset(&mut self, _kind: ResetKind) {
+        self.value = 0;
+    }
+}
+
+#[cfg(test)]
+impl Device for LatchCard {
+    fn name(&self) -> &str {
+        "latch"
+    }
+    fn io_ranges(&self) -> Vec<(u16, u16)> {
+        vec![(0x300, 0x300)]
+    }
+    fn rb(&mut self, _addr: u16) -> u8 {
+        self.value
+    }
+    fn wb(&mut self, _addr: u16, value: u8) {
+        self.value = value;
+    }
+    fn irq_line(&self) -> Option<u8> {
+        Some(2)
+    }
+    fn irq(&self) -> bool {
+        self.value & 1 != 0
+    }
+}
+
+#[test]
+fn test_builder_composes_machines() {
+    let machine = MachineBuilder::new(Board::Ibm5170)
+        .without_fdc()
+        .ram_kb(256)
+        .card(IsaCard::SoundBlaster(SbModel::Pro))
+        .device(Box::new(LatchCard::default()))
+        .build()
+        .unwrap();
+    let mut at = match machine {
+        Machine::At(at) => at,
+        Machine::Pc(_) => unreachable!(),
+    };
+    assert!(at.hardware.common.fdc.is_none());
+    assert!(at.hardware.io.device(0x3f5).is_none());
+    assert_eq!(at.ram_size(), 256);
+    assert!(at.hardware.common.sound_blaster.is_some());
+    // IRQ 2 comes out on the slave PIC's IRQ 9.
+    at.hardware.io_write_byte(0x300, 0x01);
+    assert_eq!(at.hardware.io_read_byte(0x300), 0x01);
+    assert_eq!(at.hardware.pic.slave.irr & 0x02, 0x02);
+
+    let machine = MachineBuilder::new(Board::Ibm5160)
+        .video(VideoCard::Ega)
+        .device(Box::new(LatchCard::default()))
+        .build()
+        .unwrap();
+    let xt = match machine {
+        Machine::Pc(xt) => xt,
+        Machine::At(_) => unreachable!(),
+    };
+    assert!(xt.hardware.common.ega.is_some() && xt.hardware.common.cga.is_none());
+    assert_eq!(xt.hardware.ppi.model, PpiModel::Xt);
+    assert_eq!(xt.hardware.common.devices.len(), 1);
+    assert!(MachineBuilder::new(Board::Ibm5150)
+        .ram_kb(8)
+        .build()
+        .is_err());
+}
@@ -0,0 +1,324 @@
+// CD images for the ATAPI drive. An ISO is nothing but the 2048 byte
+// sectors of a single data track. A cue sheet lists the tracks in one or
+// more BIN files, whose sectors can be raw 2352 byte ones with the sync
+// pattern and header in front of the data, and can hold audio tracks too.
+use log::debug;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// The data in a sector as the drive hands it over.
+pub const CD_SECTOR_SIZE: usize = 2048;
+
+/// Sectors per second, which MSF addresses count frames in.
+const FRAMES_PER_SECOND: u32 = 75;
+
+/// The two second pregap before track 1 that LBA 0 comes after.
+pub const MSF_OFFSET: u32 = 150;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub number: u8,
+    pub audio: bool,
+    /// The first sector, counting from the start of the disc.
+    pub start: u32,
+    pub length: u32,
+    path: PathBuf,
+    /// Where the track's first sector is in the file.
+    offset: u64,
+    sector_size: usize,
+    /// Where the user data is in each sector.
+    data_offset: usize,
+}
+
+/// Why a sector couldn't be read.
+#[derive(Debug)]
+pub enum ReadError {
+    OutOfRange,
+    /// The sector is in an audio track, which READ doesn't reach.
+    AudioTrack,
+    Io(io::Error),
+}
+
+/// A disc image: where each track's sectors are in which file. The files
+/// are read as the sectors are wanted rather than kept in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CdImage {
+    pub path: PathBuf,
+    pub tracks: Vec<Track>,
+}
+
+/// Turns an LBA into minutes, seconds and frames.
+pub fn to_msf(lba: u32) -> (u8, u8, u8) {
+    let frames = lba + MSF_OFFSET;
+    (
+        (frames / (60 * FRAMES_PER_SECOND)) as u8,
+        (frames / FRAMES_PER_SECOND % 60) as u8,
+        (frames % FRAMES_PER_SECOND) as u8,
+    )
+}
+
+impl CdImage {
+    /// Opens a cue sheet if the name ends in .cue, otherwise an ISO.
+    pub fn open(path: &str) -> Result<CdImage, String> {
+        let image = if path.to_ascii_lowercase().ends_with(".cue") {
+            let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+            parse_cue(Path::new(path), &text)
+        } else {
+            iso(Path::new(path))
+        };
+        let image = image.map_err(|err| format!("{}: {}", path, err))?;
+        debug!(target: "disk", "Opened {} with {} tracks", path, image.tracks.len());
+        Ok(image)
+    }
+
+    pub fn total_sectors(&self) -> u32 {
+        self.tracks
+            .last()
+            .map_or(0, |track| track.start + track.length)
+    }
+
+    pub fn track_at(&self, lba: u32) -> Option<&Track> {
+        self.tracks
+            .iter()
+            .find(|track| (track.start..track.start + track.length).contains(&lba))
+    }
+
+    /// The 2048 bytes of user data in sector `lba` of a data track.
+    pub fn read_sector(&self, lba: u32) -> Result<Vec<u8>, ReadError> {
+        let track = self.track_at(lba).ok_or(ReadError::OutOfRange)?;
+        if track.audio {
+            return Err(ReadError::AudioTrack);
+        }
+        let offset = track.offset
+            + (lba - track.start) as u64 * track.sector_size as u64
+            + track.data_offset as u64;
+        let mut sector = vec![0; CD_SECTOR_SIZE];
+        let mut file = File::open(&track.path).map_err(ReadError::Io)?;
+        file.seek(SeekFrom::Start(offset)).map_err(ReadError::Io)?;
+        file.read_exact(&mut sector).map_err(ReadError::Io)?;
+        Ok(sector)
+    }
+}
+
+fn iso(path: &Path) -> Result<CdImage, String> {
+    let size = fs::metadata(path).map_err(|err| err.to_string())?.len();
+    if size < CD_SECTOR_SIZE as u64 {
+        return Err("too small for a CD image".to_string());
+    }
+    Ok(CdImage {
+        path: path.to_path_buf(),
+        tracks: vec![Track {
+            number: 1,
+            audio: false,
+            start: 0,
+            length: (size / CD_SECTOR_SIZE as u64) as u32,
+            path: path.to_path_buf(),
+            offset: 0,
+            sector_size: CD_SECTOR_SIZE,
+            data_offset: 0,
+        }],
+    })
+}
+
+/// Splits a cue sheet line into words, keeping quoted file names whole.
+fn words(line: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        let (word, next) = match rest.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => match rest.find(char::is_whitespace) {
+                Some(end) => (&rest[..end], &rest[end..]),
+                None => (rest, ""),
+            },
+        };
+        words.push(word.to_string());
+        rest = next.trim_start();
+    }
+    words
+}
+
+fn parse_msf(text: &str) -> Result<u32, String> {
+    let fields: Vec<u32> = text
+        .split(':')
+        .map(|field| field.parse().map_err(|_| format!("bad time {}", text)))
+        .collect::<Result<_, _>>()?;
+    match fields[..] {
+        [minutes, seconds, frames] => Ok((minutes * 60 + seconds) * FRAMES_PER_SECOND + frames),
+        _ => Err(format!("bad time {}", text)),
+    }
+}
+
+/// The sector size and where the data is in each sector for a TRACK
+/// command's mode, or `None` for audio.
+fn track_mode(mode: &str) -> Result<(usize, Option<usize>), String> {
+    match mode {
+        "AUDIO" => Ok((2352, None)),
+        "MODE1/2048" => Ok((2048, Some(0))),
+        "MODE1/2352" => Ok((2352, Some(16))),
+        // Form 1 sectors, with the subheader after the header.
+        "MODE2/2336" => Ok((2336, Some(8))),
+        "MODE2/2352" => Ok((2352, Some(24))),
+        _ => Err(format!("unsupported track mode {}", mode)),
+    }
+}
+
+/// A track as the cue sheet gives it, before the file it's in is sized up.
+struct CueTrack {
+    number: u8,
+    sector_size: usize,
+    data_offset: Option<usize>,
+    /// Sectors into the file where INDEX 01 is.
+    index: Option<u32>,
+    /// Sectors of PREGAP before it that aren't in the file.
+    pregap: u32,
+}
+
+fn parse_cue(path: &Path, text: &str) -> Result<CdImage, String> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut tracks = vec![];
+    // The sectors on the disc before the current file, and the gaps that
+    // aren't in any file.
+    let mut disc_start = 0;
+    let mut file: Option<(PathBuf, Vec<CueTrack>)> = None;
+    for line in text.lines() {
+        let words = words(line);
+        match words.first().map(String::as_str) {
+            Some("FILE") => {
+                if let Some((path, cue_tracks)) = file.take() {
+                    disc_start = add_file(&mut tracks, path, cue_tracks, disc_start)?;
+                }
+                let name = words.get(1).ok_or("FILE without a name")?;
+                if words.get(2).map(String::as_str) != Some("BINARY") {
+                    return Err(format!("{} isn't a BINARY file", name));
+                }
+                file = Some((dir.join(name), vec![]));
+            }
+            Some("TRACK") => {
+                let (_, cue_tracks) = file.as_mut().ok_or("TRACK before FILE")?;
+                let number = words
+                    .get(1)
+                    .and_then(|number| number.parse().ok())
+                    .ok_or("bad TRACK number")?;
+                let (sector_size, data_offset) =
+                    track_mode(words.get(2).map_or("", String::as_str))?;
+                cue_tracks.push(CueTrack {
+                    number,
+                    sector_size,
+                    data_offset,
+                    index: None,
+                    pregap: 0,
+                });
+            }
+            Some("INDEX") if words.get(1).map(String::as_str) == Some("01") => {
+                let track = current_track(&mut file)?;
+                track.index = Some(parse_msf(words.get(2).map_or("", String::as_str))?);
+            }
+            Some("PREGAP") => {
+                let track = current_track(&mut file)?;
+                track.pregap = parse_msf(words.get(1).map_or("", String::as_str))?;
+            }
+            _ => {}
+        }
+    }
+    if let Some((path, cue_tracks)) = file.take() {
+        add_file(&mut tracks, path, cue_tracks, disc_start)?;
+    }
+    if tracks.is_empty() {
+        return Err("no tracks in the cue sheet".to_string());
+    }
+    Ok(CdImage {
+        path: path.to_path_buf(),
+        tracks,
+    })
+}
+
+fn current_track(file: &mut Option<(PathBuf, Vec<CueTrack>)>) -> Result<&mut CueTrack, String> {
+    file.as_mut()
+        .and_then(|(_, tracks)| tracks.last_mut())
+        .ok_or_else(|| "INDEX or PREGAP before TRACK".to_string())
+}
+
+/// Places the tracks of one BIN file on the disc after `disc_start`, each
+/// running up to the next one's INDEX 01 or the end of the file, and
+/// returns where the next file's go.
+fn add_file(
+    tracks: &mut Vec<Track>,
+    path: PathBuf,
+    cue_tracks: Vec<CueTrack>,
+    mut disc_start: u32,
+) -> Result<u32, String> {
+    let size = fs::metadata(&path)
+        .map_err(|err| format!("{}: {}", path.display(), err))?
+        .len();
+    let mut offset = 0;
+    let mut previous_index = 0;
+    for (i, track) in cue_tracks.iter().enumerate() {
+        let index = track
+            .index
+            .ok_or_else(|| format!("track {} has no INDEX 01", track.number))?;
+        if let Some(previous) = i.checked_sub(1).map(|i| &cue_tracks[i]) {
+            offset += (index - previous_index) as u64 * previous.sector_size as u64;
+        } else {
+            offset = index as u64 * track.sector_size as u64;
+        }
+        previous_index = index;
+        disc_start += track.pregap;
+        let length = match cue_tracks.get(i + 1).and_then(|next| next.index) {
+            Some(next) => next.saturating_sub(index),
+            None => (size.saturating_sub(offset) / track.sector_size as u64) as u32,
+        };
+        tracks.push(Track {
+            number: track.number,
+            audio: track.data_offset.is_none(),
+            start: disc_start + index,
+            length,
+            path: path.clone(),
+            offset,
+            sector_size: track.sector_size,
+            data_offset: track.data_offset.unwrap_or(0),
+        });
+    }
+    Ok(tracks
+        .last()
+        .map_or(disc_start, |track| track.start + track.length))
+}
+
+#[test]
+fn test_cue_sheet_tracks() {
+    let dir = std::env::temp_dir();
+    let bin = dir.join("emupc-cue-test.bin");
+    let cue = dir.join("emupc-cue-test.cue");
+    // Ten raw data sectors, each with its number after the 16 byte header,
+    // then four sectors of audio.
+    let mut data = vec![];
+    for sector in 0..10u8 {
+        let mut raw = vec![0; 2352];
+        raw[16] = sector;
+        data.extend(raw);
+    }
+    data.extend(vec![0x55; 4 * 2352]);
+    fs::write(&bin, &data).unwrap();
+    let sheet = "FILE \"emupc-cue-test.bin\" BINARY\n  \
+                 TRACK 01 MODE1/2352\n    INDEX 01 00:00:00\n  \
+                 TRACK 02 AUDIO\n    PREGAP 00:02:00\n    INDEX 01 00:00:10\n";
+    fs::write(&cue, sheet).unwrap();
+
+    let image = CdImage::open(cue.to_str().unwrap()).unwrap();
+    let sector = image.read_sector(3).unwrap();
+    fs::remove_file(&bin).unwrap();
+    fs::remove_file(&cue).unwrap();
+    assert_eq!(image.tracks.len(), 2);
+    assert_eq!((image.tracks[0].start, image.tracks[0].length), (0, 10));
+    assert_eq!((image.tracks[1].start, image.tracks[1].length), (160, 4));
+    assert!(image.tracks[1].audio);
+    assert_eq!((sector.len(), sector[0]), (CD_SECTOR_SIZE, 3));
+    assert_eq!(image.total_sectors(), 164);
+    assert_eq!(to_msf(image.total_sectors()), (0, 4, 14));
+    assert!(matches!(image.read_sector(161), Err(ReadError::AudioTrack)));
+    assert!(matches!(image.read_sector(10), Err(ReadError::OutOfRange)));
+}
@@ -0,0 +1,68 @@
+/// The fastest the ISA bus runs. AT-class boards with a faster CPU divide
+/// its clock down for the bus, so an 8-bit card costs more CPU cycles the
+/// faster the CPU gets.
+pub const ISA_BUS_MAX_HZ: u32 = 8_333_333;
+
+/// CPU clocks per ISA bus clock at a CPU clock of `cpu_hz`.
+pub fn isa_bus_divisor(cpu_hz: u32) -> usize {
+    cpu_hz.div_ceil(ISA_BUS_MAX_HZ).max(1) as usize
+}
+
+/// Turns CPU cycles into cycles of a device's own clock, such as the
+/// PIT's 1.19 MHz or the RTC's 32 kHz crystal. The remainder carries over
+/// so no cycles are lost at any CPU speed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceClock {
+    cpu_hz: u64,
+    device_hz: u64,
+    phase: u64,
+}
+
+impl DeviceClock {
+    pub fn new(cpu_hz: u32, device_hz: u32) -> DeviceClock {
+        DeviceClock {
+            cpu_hz: cpu_hz as u64,
+            device_hz: device_hz as u64,
+            phase: 0,
+        }
+    }
+
+    pub fn set_cpu_hz(&mut self, cpu_hz: u32) {
+        self.cpu_hz = cpu_hz as u64;
+        self.phase = 0;
+    }
+
+    pub fn cpu_hz(&self) -> u32 {
+        self.cpu_hz as u32
+    }
+
+    pub fn device_hz(&self) -> u32 {
+        self.device_hz as u32
+    }
+
+    pub fn set_device_hz(&mut self, device_hz: u32) {
+        self.device_hz = device_hz as u64;
+        self.phase = 0;
+    }
+
+    /// Device clock cycles that have gone by in `cycles` CPU cycles.
+    pub fn ticks(&mut self, cycles: usize) -> usize {
+        self.phase += cycles as u64 * self.device_hz;
+        let ticks = self.phase / self.cpu_hz;
+        self.phase %= self.cpu_hz;
+        ticks as usize
+    }
+
+    /// CPU cycles until `ticks` more device clock cycles have gone by.
+    pub fn cycles_until(&self, ticks: usize) -> usize {
+        let target = (ticks as u64 * self.cpu_hz).saturating_sub(self.phase);
+        target.div_ceil(self.device_hz) as usize
+    }
+}
+
+/// One device cycle per CPU cycle.
+impl Default for DeviceClock {
+    fn default() -> DeviceClock {
+        DeviceClock::new(1, 1)
+    }
+}
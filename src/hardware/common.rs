@@ -0,0 +1,597 @@
+// What the PC and AT boards have in common: the PIT and DMA controller,
+// the floppy controller, the cards that fit either board, the scheduler
+// and the clocks that run it all. Each board embeds one and routes its
+// IRQs through its own interrupt controller, keeping only the chips and
+// wiring that set it apart.
+
+use crate::audio::mixer::Mixer;
+use crate::hardware::clock::DeviceClock;
+use crate::hardware::device::Device;
+use crate::hardware::dma::DmaController;
+use crate::hardware::ems::EmsBoard;
+use crate::hardware::fdc::{FDC, FDC_IRQ};
+use crate::hardware::gameport::{GamePort, GAME_PORT_CLOCK_HZ};
+use crate::hardware::lpt::LPT;
+use crate::hardware::mpu401::MPU401;
+use crate::hardware::ne2000::{NE2000, NE2000_POLL_HZ};
+use crate::hardware::opl2::{OPL2, OPL_SAMPLE_RATE};
+use crate::hardware::perfcounter::PerfCounter;
+use crate::hardware::pic::InterruptLines;
+use crate::hardware::pit::{PIT, PIT_CLOCK_HZ};
+use crate::hardware::postcard::PostCard;
+use crate::hardware::reset::{Reset, ResetKind};
+use crate::hardware::scheduler::{DeviceEvent, Scheduler};
+use crate::hardware::soundblaster::{SoundBlaster, SB_CLOCK_HZ};
+use crate::hardware::speaker::{Speaker, DEFAULT_SAMPLE_RATE};
+use crate::hardware::uart::{UartModel, UART, UART_CLOCK_HZ};
+use crate::hardware::video::cga::CGA;
+use crate::hardware::video::ega::EGA;
+use crate::hardware::video::mda::{MDA, MDA_CLOCK_HZ};
+use crate::hardware::MASTER_CLOCK_HZ;
+
+#[derive(Clone, Debug, Default)]
+pub struct CommonHardware {
+    pub dma: DmaController,
+    pub pit: PIT,
+    pub pit_clock: DeviceClock,
+    /// Deadlines for the devices that aren't ticked every instruction.
+    pub scheduler: Scheduler<DeviceEvent>,
+    /// How far the serial ports, the PIT and speaker, the sound cards, the
+    /// video cards and the game port have been run, on the scheduler's
+    /// clock. Each catches up when its event comes due or the CPU touches
+    /// it.
+    serial_synced: u64,
+    pit_synced: u64,
+    audio_synced: u64,
+    video_synced: u64,
+    game_port_synced: u64,
+    /// Port 61h's speaker data bit, which lets PIT channel 2 through to
+    /// the speaker.
+    speaker_data: bool,
+    /// Refreshes PIT channel 1 has asked for that the board hasn't carried
+    /// out yet.
+    refresh_requests: usize,
+    /// Missing on a board built without one.
+    pub fdc: Option<FDC>,
+    pub cga: Option<CGA>,
+    pub ega: Option<EGA>,
+    /// Counts hdots of the 14.318 MHz master clock, which the CGA and EGA
+    /// run from.
+    pub hdot_clock: DeviceClock,
+    pub mda: Option<MDA>,
+    pub mda_clock: DeviceClock,
+    pub speaker: Speaker,
+    /// An AdLib card's OPL2 at 388h, and the clock it makes samples by.
+    pub adlib: Option<OPL2>,
+    pub opl_clock: DeviceClock,
+    pub sound_blaster: Option<SoundBlaster>,
+    pub sb_clock: DeviceClock,
+    pub mpu401: Option<MPU401>,
+    /// COM1-COM4, and the bit clock their baud rates divide down from.
+    pub serial: [Option<UART>; 4],
+    pub serial_clock: DeviceClock,
+    /// LPT1-LPT3.
+    pub parallel: [Option<LPT>; 3],
+    pub game_port: Option<GamePort>,
+    pub game_port_clock: DeviceClock,
+    pub nic: Option<NE2000>,
+    /// Expanded memory, seen through its page frame.
+    pub ems: Option<EmsBoard>,
+    /// Cards from a `MachineBuilder` that the board has no field for.
+    pub devices: Vec<Box<dyn Device>>,
+    /// Counts samples at the host's audio rate.
+    pub sample_clock: DeviceClock,
+    pub mixer: Mixer,
+    /// Shows what the BIOS writes to port 80h.
+    pub post_card: PostCard,
+    /// Lets benchmarks in the guest read the emulator's clocks.
+    pub perf_counter: Option<PerfCounter>,
+}
+
+impl CommonHardware {
+    /// The board's own PIT, DMA controller and floppy controller, with
+    /// COM1, COM2 and LPT1 fitted and nothing else.
+    pub fn new(
+        cpu_hz: u32,
+        pit: PIT,
+        dma: DmaController,
+        fdc: Option<FDC>,
+        uart: UartModel,
+    ) -> CommonHardware {
+        CommonHardware {
+            dma,
+            pit,
+            pit_clock: DeviceClock::new(cpu_hz, PIT_CLOCK_HZ),
+            scheduler: Scheduler::new(),
+            serial_synced: 0,
+            pit_synced: 0,
+            audio_synced: 0,
+            video_synced: 0,
+            game_port_synced: 0,
+            speaker_data: false,
+            refresh_requests: 0,
+            fdc,
+            cga: None,
+            ega: None,
+            hdot_clock: DeviceClock::new(cpu_hz, MASTER_CLOCK_HZ as u32),
+            mda: None,
+            mda_clock: DeviceClock::new(cpu_hz, MDA_CLOCK_HZ),
+            speaker: Speaker::new(),
+            adlib: None,
+            opl_clock: DeviceClock::new(cpu_hz, OPL_SAMPLE_RATE),
+            sound_blaster: None,
+            sb_clock: DeviceClock::new(cpu_hz, SB_CLOCK_HZ),
+            mpu401: None,
+            serial: [
+                Some(UART::com(0, uart)),
+                Some(UART::com(1, uart)),
+                None,
+                None,
+            ],
+            serial_clock: DeviceClock::new(cpu_hz, UART_CLOCK_HZ),
+            parallel: [Some(LPT::port(0)), None, None],
+            game_port: None,
+            game_port_clock: DeviceClock::new(cpu_hz, GAME_PORT_CLOCK_HZ),
+            nic: None,
+            ems: None,
+            devices: vec![],
+            sample_clock: DeviceClock::new(cpu_hz, DEFAULT_SAMPLE_RATE),
+            mixer: Mixer::default(),
+            post_card: PostCard::new(),
+            perf_counter: None,
+        }
+    }
+
+    /// COM1 and COM3 share IRQ 4, and COM2 and COM4 IRQ 3.
+    pub fn update_serial_irqs(&self, pic: &mut impl InterruptLines) {
+        for irq in [3, 4] {
+            let level = self
+                .serial
+                .iter()
+                .flatten()
+                .any(|uart| uart.irq == irq && uart.irq());
+            pic.set_line(irq, level);
+        }
+    }
+
+    /// The parallel ports all interrupt on IRQ 7.
+    pub fn update_parallel_irq(&self, pic: &mut impl InterruptLines) {
+        let level = self.parallel.iter().flatten().any(LPT::irq);
+        pic.set_line(7, level);
+    }
+
+    pub fn update_nic_irq(&self, pic: &mut impl InterruptLines) {
+        if let Some(nic) = &self.nic {
+            pic.set_line(nic.irq, nic.irq());
+        }
+    }
+
+    pub fn update_device_irqs(&self, pic: &mut impl InterruptLines) {
+        for device in &self.devices {
+            if let Some(line) = device.irq_line() {
+                pic.set_line(line, device.irq());
+            }
+        }
+    }
+
+    /// Brings the IRQ lines and schedule up to date after a card has been
+    /// fitted or taken out, so a card that's gone stops interrupting.
+    pub fn refit(&mut self, pic: &mut impl InterruptLines) {
+        self.update_serial_irqs(pic);
+        self.update_parallel_irq(pic);
+        self.update_nic_irq(pic);
+        self.update_device_irqs(pic);
+        self.schedule();
+    }
+
+    /// Puts every device's next event on the schedule afresh.
+    pub fn schedule(&mut self) {
+        self.schedule_serial();
+        self.schedule_nic_poll();
+        self.schedule_pit();
+        self.schedule_sample();
+        self.schedule_video();
+    }
+
+    /// CPU cycles in `us` microseconds.
+    fn cycles_for_us(&self, us: u64) -> u64 {
+        us * self.pit_clock.cpu_hz() as u64 / 1_000_000
+    }
+
+    /// Runs the serial ports up to the present and schedules their next
+    /// event. Done before the CPU touches their registers, too, so it sees
+    /// them as they are now.
+    pub fn sync_serial(&mut self, pic: &mut impl InterruptLines) {
+        let elapsed = self.scheduler.now() - self.serial_synced;
+        self.serial_synced = self.scheduler.now();
+        let ticks = self.serial_clock.ticks(elapsed as usize);
+        for uart in self.serial.iter_mut().flatten() {
+            uart.tick(ticks);
+        }
+        self.update_serial_irqs(pic);
+        self.schedule_serial();
+    }
+
+    pub fn schedule_serial(&mut self) {
+        let next = self
+            .serial
+            .iter()
+            .flatten()
+            .map(UART::ticks_until_event)
+            .min();
+        match next {
+            Some(ticks) => {
+                let cycles = self.serial_clock.cycles_until(ticks.max(1));
+                self.scheduler.schedule(DeviceEvent::Serial, cycles as u64);
+            }
+            None => self.scheduler.cancel(DeviceEvent::Serial),
+        }
+    }
+
+    pub fn schedule_nic_poll(&mut self) {
+        match self.nic {
+            Some(_) => {
+                let period = self.pit_clock.cpu_hz() / NE2000_POLL_HZ;
+                self.scheduler.schedule(DeviceEvent::NicPoll, period as u64);
+            }
+            None => self.scheduler.cancel(DeviceEvent::NicPoll),
+        }
+    }
+
+    /// Times the seek the floppy controller has just started.
+    pub fn start_fdc_delay(&mut self) {
+        if let Some(us) = self.fdc.as_mut().and_then(FDC::take_delay) {
+            let cycles = self.cycles_for_us(us);
+            self.scheduler.schedule(DeviceEvent::FdcSeek, cycles);
+        }
+    }
+
+    /// Runs the PIT up to the present. The speaker hears each stretch
+    /// between channel 2's edges at the level it had, and channel 0's
+    /// edges go to IRQ 0 as they happen.
+    pub fn sync_pit(&mut self, pic: &mut impl InterruptLines) {
+        let elapsed = self.scheduler.now() - self.pit_synced;
+        self.pit_synced = self.scheduler.now();
+        let mut ticks = self.pit_clock.ticks(elapsed as usize);
+        while ticks > 0 {
+            let on = self.speaker_data && self.pit.out(2);
+            let step = match self.pit.ticks_until_edge(2) {
+                Some(edge) if self.speaker_data => edge.min(ticks),
+                _ => ticks,
+            };
+            self.refresh_requests += self.pit.tick(step, |out| pic.set_line(0, out));
+            self.speaker.advance(step, on);
+            ticks -= step;
+        }
+        self.schedule_pit();
+    }
+
+    /// Wakes up for channel 0's next edge, which is IRQ 0. Channel 1's
+    /// refreshes and channel 2's speaker wave wait for the next time
+    /// something catches the PIT up.
+    fn schedule_pit(&mut self) {
+        match self.pit.ticks_until_edge(0) {
+            Some(ticks) => {
+                let cycles = self.pit_clock.cycles_until(ticks);
+                self.scheduler.schedule(DeviceEvent::Pit, cycles as u64);
+            }
+            None => self.scheduler.cancel(DeviceEvent::Pit),
+        }
+    }
+
+    pub fn read_pit(&mut self, addr: u16, pic: &mut impl InterruptLines) -> u8 {
+        self.sync_pit(pic);
+        self.pit.rb(addr)
+    }
+
+    pub fn write_pit(&mut self, addr: u16, value: u8, pic: &mut impl InterruptLines) {
+        self.sync_pit(pic);
+        self.pit.wb(addr, value);
+        self.schedule_pit();
+    }
+
+    /// Channel 2's output as of now, for port 61h or the PPI's port C.
+    pub fn timer_2_out(&mut self, pic: &mut impl InterruptLines) -> bool {
+        self.sync_pit(pic);
+        self.pit.out(2)
+    }
+
+    /// Follows port 61h's timer 2 gate and speaker data bits.
+    pub fn set_speaker(&mut self, gate: bool, data: bool, pic: &mut impl InterruptLines) {
+        self.sync_pit(pic);
+        self.pit.set_gate(2, gate);
+        self.speaker_data = data;
+    }
+
+    /// Hands over the refreshes asked for since the last call.
+    pub fn take_refresh_requests(&mut self) -> usize {
+        std::mem::take(&mut self.refresh_requests)
+    }
+
+    /// Runs the sound cards up to the present and makes the samples that
+    /// have come due. Done before the CPU touches the cards, too, so it
+    /// sees their timers and DMA as they are now. The Sound Blaster's IRQ
+    /// is left to the board.
+    pub fn sync_audio(&mut self, pic: &mut impl InterruptLines, ram: &[u8]) {
+        self.sync_pit(pic);
+        let elapsed = (self.scheduler.now() - self.audio_synced) as usize;
+        self.audio_synced = self.scheduler.now();
+        let opl_ticks = self.opl_clock.ticks(elapsed);
+        if let Some(adlib) = &mut self.adlib {
+            adlib.tick(opl_ticks);
+        }
+        let sb_ticks = self.sb_clock.ticks(elapsed);
+        if let Some(sb) = &mut self.sound_blaster {
+            sb.tick(sb_ticks, &mut self.dma, ram);
+        }
+        for _ in 0..self.sample_clock.ticks(elapsed) {
+            let fm = self.adlib.as_mut().map_or(0.0, OPL2::sample);
+            let card = match &mut self.sound_blaster {
+                Some(sb) => sb.sample(fm),
+                None => fm,
+            };
+            self.mixer.push(self.speaker.sample() + card);
+        }
+        self.schedule_sample();
+    }
+
+    fn schedule_sample(&mut self) {
+        let cycles = self.sample_clock.cycles_until(1);
+        self.scheduler.schedule(DeviceEvent::Sample, cycles as u64);
+    }
+
+    /// Makes samples at `hz` from now on, the rate the host plays them at.
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        self.sample_clock.set_device_hz(hz);
+        self.schedule_sample();
+    }
+
+    /// Runs the video cards up to the present, drawing the lines the beam
+    /// has passed. Done before the CPU touches the cards, so it reads the
+    /// status bits where the beam is and its writes show from there on.
+    fn sync_video(&mut self) {
+        let elapsed = (self.scheduler.now() - self.video_synced) as usize;
+        self.video_synced = self.scheduler.now();
+        let hdots = self.hdot_clock.ticks(elapsed);
+        if let Some(cga) = &mut self.cga {
+            cga.tick(hdots);
+        }
+        if let Some(ega) = &mut self.ega {
+            ega.tick(hdots);
+        }
+        let dots = self.mda_clock.ticks(elapsed);
+        if let Some(mda) = &mut self.mda {
+            mda.tick(dots);
+        }
+    }
+
+    /// Wakes up when the first of the cards gets back to the top of its
+    /// frame, to show the one it has finished.
+    fn schedule_video(&mut self) {
+        let cga = self.cga.as_ref().and_then(CGA::hdots_until_frame);
+        let ega = self.ega.as_ref().map(EGA::hdots_until_frame);
+        let hdots = cga.into_iter().chain(ega).min();
+        let hdot_cycles = hdots.map(|hdots| self.hdot_clock.cycles_until(hdots));
+        let dots = self.mda.as_ref().and_then(MDA::dots_until_frame);
+        let mda_cycles = dots.map(|dots| self.mda_clock.cycles_until(dots));
+        match hdot_cycles.into_iter().chain(mda_cycles).min() {
+            Some(cycles) => {
+                self.scheduler.schedule(DeviceEvent::Video, cycles as u64);
+            }
+            None => self.scheduler.cancel(DeviceEvent::Video),
+        }
+    }
+
+    /// Runs the game port's one-shots up to the present.
+    fn sync_game_port(&mut self) {
+        let elapsed = (self.scheduler.now() - self.game_port_synced) as usize;
+        self.game_port_synced = self.scheduler.now();
+        let ticks = self.game_port_clock.ticks(elapsed);
+        if let Some(game_port) = &mut self.game_port {
+            game_port.tick(ticks);
+        }
+    }
+
+    pub fn read_game_port(&mut self, addr: u16) -> u8 {
+        self.sync_game_port();
+        self.game_port
+            .as_mut()
+            .map_or(0xff, |game_port| game_port.rb(addr))
+    }
+
+    pub fn write_game_port(&mut self, addr: u16, value: u8) {
+        self.sync_game_port();
+        if let Some(game_port) = &mut self.game_port {
+            game_port.wb(addr, value);
+        }
+    }
+
+    fn handle_event(&mut self, event: DeviceEvent, pic: &mut impl InterruptLines, ram: &[u8]) {
+        match event {
+            DeviceEvent::FdcSeek => {
+                if let Some(fdc) = &mut self.fdc {
+                    fdc.delay_elapsed();
+                    pic.set_line(FDC_IRQ, fdc.irq());
+                }
+            }
+            DeviceEvent::Serial => self.sync_serial(pic),
+            DeviceEvent::NicPoll => {
+                if let Some(nic) = &mut self.nic {
+                    nic.poll();
+                }
+                self.update_nic_irq(pic);
+                self.schedule_nic_poll();
+            }
+            DeviceEvent::Pit => self.sync_pit(pic),
+            DeviceEvent::Sample => self.sync_audio(pic, ram),
+            DeviceEvent::Video => {
+                self.sync_video();
+                self.schedule_video();
+            }
+        }
+    }
+
+    /// Hands out whatever has come due in the next `cycles` CPU cycles,
+    /// and runs the devices that still go an instruction at a time: the
+    /// floppy controller, the perf counter and the cards from the device
+    /// list.
+    pub fn tick(&mut self, cycles: usize, pic: &mut impl InterruptLines, ram: &mut [u8]) {
+        self.scheduler.advance(cycles as u64);
+        while let Some((event, _)) = self.scheduler.pop_due() {
+            self.handle_event(event, pic, ram);
+        }
+        if let Some(perf_counter) = &mut self.perf_counter {
+            perf_counter.tick(cycles);
+        }
+        if let Some(fdc) = &mut self.fdc {
+            fdc.tick(&mut self.dma, ram);
+            pic.set_line(FDC_IRQ, fdc.irq());
+        }
+        for device in &mut self.devices {
+            device.tick(cycles);
+            device.dma(&mut self.dma, ram);
+        }
+        self.update_device_irqs(pic);
+    }
+
+    /// Moves every clock here over to a CPU clock of `hz`, bringing the
+    /// devices up to date first so no time is lost.
+    pub fn set_clock_hz(&mut self, hz: u32, pic: &mut impl InterruptLines, ram: &[u8]) {
+        self.sync_serial(pic);
+        self.sync_audio(pic, ram);
+        self.sync_video();
+        self.sync_game_port();
+        self.scheduler.rescale(self.pit_clock.cpu_hz(), hz);
+        self.pit_clock.set_cpu_hz(hz);
+        self.hdot_clock.set_cpu_hz(hz);
+        self.mda_clock.set_cpu_hz(hz);
+        self.sample_clock.set_cpu_hz(hz);
+        self.opl_clock.set_cpu_hz(hz);
+        self.sb_clock.set_cpu_hz(hz);
+        self.serial_clock.set_cpu_hz(hz);
+        self.game_port_clock.set_cpu_hz(hz);
+        if let Some(perf_counter) = &mut self.perf_counter {
+            perf_counter.set_cpu_hz(hz);
+        }
+        for device in &mut self.devices {
+            device.set_clock_hz(hz);
+        }
+        self.schedule();
+    }
+
+    /// Video memory, from whichever card answers for the address. The EGA
+    /// comes first, then a Hercules card's second page wins over a CGA.
+    pub fn read_video(&mut self, addr: u32) -> u8 {
+        self.sync_video();
+        if let Some(ega) = self.ega.as_mut().filter(|ega| ega.decodes(addr)) {
+            return ega.read_vram(addr);
+        }
+        match (&self.mda, &mut self.cga) {
+            (Some(mda), _) if mda.decodes(addr) => mda.read_vram(addr),
+            (_, Some(cga)) if addr >= 0x0b_8000 => cga.read_vram(addr),
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_video(&mut self, addr: u32, value: u8) {
+        self.sync_video();
+        if let Some(ega) = self.ega.as_mut().filter(|ega| ega.decodes(addr)) {
+            ega.write_vram(addr, value);
+            return;
+        }
+        match (&mut self.mda, &mut self.cga) {
+            (Some(mda), _) if mda.decodes(addr) => mda.write_vram(addr, value),
+            (_, Some(cga)) if addr >= 0x0b_8000 => cga.write_vram(addr, value),
+            _ => {}
+        }
+    }
+
+    /// The video ports, sorted out among the cards the same way.
+    pub fn read_video_port(&mut self, addr: u16) -> u8 {
+        self.sync_video();
+        if let Some(ega) = self.ega.as_mut().filter(|ega| ega.claims(addr)) {
+            return ega.rb(addr);
+        }
+        match (addr, &mut self.mda, &mut self.cga) {
+            (0x03b0..=0x03bf, Some(mda), _) => mda.rb(addr),
+            (0x03d0..=0x03df, _, Some(cga)) => cga.rb(addr),
+            _ => 0xff,
+        }
+    }
+
+    /// Reschedules the next frame afterwards, as the write may have moved
+    /// it.
+    pub fn write_video_port(&mut self, addr: u16, value: u8) {
+        self.sync_video();
+        if let Some(ega) = self.ega.as_mut().filter(|ega| ega.claims(addr)) {
+            ega.wb(addr, value);
+        } else {
+            match (addr, &mut self.mda, &mut self.cga) {
+                (0x03b0..=0x03bf, Some(mda), _) => mda.wb(addr, value),
+                (0x03d0..=0x03df, _, Some(cga)) => cga.wb(addr, value),
+                _ => {}
+            }
+        }
+        self.schedule_video();
+    }
+}
+
+/// Leaves the IRQ lines to the board, which calls `refit` once its
+/// interrupt controller is reset as well.
+impl Reset for CommonHardware {
+    fn reset(&mut self, kind: ResetKind) {
+        self.dma.reset(kind);
+        self.post_card.reset(kind);
+        if let Some(fdc) = &mut self.fdc {
+            fdc.reset(kind);
+        }
+        self.pit.reset(kind);
+        self.speaker.reset(kind);
+        if let Some(adlib) = &mut self.adlib {
+            adlib.reset(kind);
+        }
+        if let Some(sb) = &mut self.sound_blaster {
+            sb.reset(kind);
+        }
+        if let Some(mpu401) = &mut self.mpu401 {
+            mpu401.reset(kind);
+        }
+        if let Some(game_port) = &mut self.game_port {
+            game_port.reset(kind);
+        }
+        if let Some(ems) = &mut self.ems {
+            ems.reset(kind);
+        }
+        for uart in self.serial.iter_mut().flatten() {
+            uart.reset(kind);
+        }
+        for lpt in self.parallel.iter_mut().flatten() {
+            lpt.reset(kind);
+        }
+        if let Some(nic) = &mut self.nic {
+            nic.reset(kind);
+        }
+        if let Some(perf_counter) = &mut self.perf_counter {
+            perf_counter.reset(kind);
+        }
+        if let Some(cga) = &mut self.cga {
+            cga.reset(kind);
+        }
+        if let Some(mda) = &mut self.mda {
+            mda.reset(kind);
+        }
+        if let Some(ega) = &mut self.ega {
+            ega.reset(kind);
+        }
+        for device in &mut self.devices {
+            device.reset(kind);
+        }
+        // Whatever time hadn't been caught up on went to the old state.
+        let now = self.scheduler.now();
+        self.pit_synced = now;
+        self.audio_synced = now;
+        self.video_synced = now;
+        self.game_port_synced = now;
+        self.refresh_requests = 0;
+    }
+}
@@ -0,0 +1,67 @@
+// An expansion card the boards know nothing about in advance. Cards built
+// into the boards have fields of their own; anything else goes in the
+// board's device list, and the board gives it its ports, memory, IRQ line
+// and DMA transfers through this trait. Every method has a default, so a
+// card only has to fill in the parts of the bus it uses.
+use crate::hardware::dma::DmaController;
+use crate::hardware::reset::Reset;
+use std::fmt;
+
+pub trait Device: Reset + DeviceClone + fmt::Debug {
+    fn name(&self) -> &str;
+
+    /// Tells the card the CPU clock, so `tick` can convert cycles to its
+    /// own clock.
+    fn set_clock_hz(&mut self, _hz: u32) {}
+    /// Advances the card by `cycles` CPU cycles.
+    fn tick(&mut self, _cycles: usize) {}
+
+    /// The port ranges the card decodes, inclusive. The board asks when
+    /// the card is fitted and on reset.
+    fn io_ranges(&self) -> Vec<(u16, u16)> {
+        vec![]
+    }
+    fn rb(&mut self, _addr: u16) -> u8 {
+        0xff
+    }
+    fn wb(&mut self, _addr: u16, _value: u8) {}
+
+    /// The memory ranges the card answers for, inclusive, asked once when
+    /// it's fitted.
+    fn mmio_ranges(&self) -> Vec<(u32, u32)> {
+        vec![]
+    }
+    fn read_mem(&mut self, _addr: u32) -> u8 {
+        0xff
+    }
+    fn write_mem(&mut self, _addr: u32, _value: u8) {}
+
+    /// The ISA IRQ line the card is jumpered to, if any.
+    fn irq_line(&self) -> Option<u8> {
+        None
+    }
+    /// The level the card drives its IRQ line to.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Runs the card's side of any DMA transfers, after `tick`.
+    fn dma(&mut self, _dma: &mut DmaController, _memory: &mut [u8]) {}
+}
+
+/// Lets a board holding boxed devices be cloned, for snapshots.
+pub trait DeviceClone {
+    fn clone_device(&self) -> Box<dyn Device>;
+}
+
+impl<T: Device + Clone + 'static> DeviceClone for T {
+    fn clone_device(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Box<dyn Device> {
+        self.clone_device()
+    }
+}
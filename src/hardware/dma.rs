@@ -0,0 +1,520 @@
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferType {
+    /// Counts like a transfer but moves nothing. The PC uses it on channel
+    /// 0 for DRAM refresh.
+    Verify,
+    /// From the device to memory.
+    Write,
+    /// From memory to the device.
+    Read,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferMode {
+    /// Transfers for as long as the device holds DREQ.
+    Demand,
+    /// One transfer per DREQ.
+    Single,
+    /// One DREQ starts a transfer of the whole count.
+    Block,
+    /// DREQ comes from another 8237 behind this channel.
+    Cascade,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DmaChannel {
+    pub base_address: u16,
+    pub base_count: u16,
+    pub current_address: u16,
+    /// Transfers left, less one. It wraps to FFFFh on the last one, which
+    /// is the terminal count.
+    pub current_count: u16,
+    /// The raw mode register, bits 2-7.
+    pub mode: u8,
+    pub masked: bool,
+    /// DREQ from the device.
+    pub request: bool,
+    /// A request made through the request register.
+    pub software_request: bool,
+    /// A block transfer has started and runs until terminal count.
+    block_active: bool,
+}
+
+impl DmaChannel {
+    fn new() -> DmaChannel {
+        DmaChannel {
+            base_address: 0,
+            base_count: 0,
+            current_address: 0,
+            current_count: 0,
+            mode: 0,
+            masked: true,
+            request: false,
+            software_request: false,
+            block_active: false,
+        }
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        match (self.mode >> 2) & 3 {
+            1 => TransferType::Write,
+            2 => TransferType::Read,
+            // 3 is illegal and behaves like verify.
+            _ => TransferType::Verify,
+        }
+    }
+
+    pub fn transfer_mode(&self) -> TransferMode {
+        match self.mode >> 6 {
+            0 => TransferMode::Demand,
+            1 => TransferMode::Single,
+            2 => TransferMode::Block,
+            _ => TransferMode::Cascade,
+        }
+    }
+
+    pub fn auto_init(&self) -> bool {
+        (self.mode & 0x10) != 0
+    }
+
+    fn decrement(&self) -> bool {
+        (self.mode & 0x20) != 0
+    }
+}
+
+/// One 8237A DMA controller: four channels and the registers shared
+/// between them. Addresses and counts are 16 bits; the page registers
+/// outside the chip supply the rest of the address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DMA {
+    pub channels: [DmaChannel; 4],
+    pub command: u8,
+    /// Bits 0-3 are set when a channel reaches terminal count and cleared
+    /// when the status is read.
+    pub terminal_count: u8,
+    /// Which byte of an address or count the next access goes to.
+    pub flip_flop: bool,
+}
+
+impl DMA {
+    pub fn new() -> DMA {
+        DMA {
+            channels: [DmaChannel::new(); 4],
+            command: 0,
+            terminal_count: 0,
+            flip_flop: false,
+        }
+    }
+
+    fn disabled(&self) -> bool {
+        (self.command & 0x04) != 0
+    }
+
+    fn status(&self) -> u8 {
+        let requests = self
+            .channels
+            .iter()
+            .enumerate()
+            .filter(|(_, channel)| channel.request || channel.software_request)
+            .fold(0, |bits, (i, _)| bits | (0x10 << i));
+        requests | self.terminal_count
+    }
+
+    /// Whether `channel` would transfer if asked to right now.
+    pub fn active(&self, channel: usize) -> bool {
+        let ch = &self.channels[channel];
+        if self.disabled() || ch.masked {
+            return false;
+        }
+        ch.software_request || ch.block_active || ch.request
+    }
+
+    /// Moves the channel on by one transfer and returns the address it
+    /// used, and whether that was the last.
+    fn advance(&mut self, channel: usize) -> (u16, bool) {
+        let ch = &mut self.channels[channel];
+        let address = ch.current_address;
+        ch.current_address = if ch.decrement() {
+            address.wrapping_sub(1)
+        } else {
+            address.wrapping_add(1)
+        };
+        ch.current_count = ch.current_count.wrapping_sub(1);
+        let done = ch.current_count == 0xffff;
+        match ch.transfer_mode() {
+            TransferMode::Single => {
+                ch.request = false;
+                ch.software_request = false;
+            }
+            TransferMode::Block => ch.block_active = !done,
+            _ => {}
+        }
+        if done {
+            trace!(target: "dma", "Channel {} reached terminal count", channel);
+            self.terminal_count |= 1 << channel;
+            ch.software_request = false;
+            ch.block_active = false;
+            if ch.auto_init() {
+                ch.current_address = ch.base_address;
+                ch.current_count = ch.base_count;
+            } else {
+                ch.masked = true;
+            }
+        }
+        (address, done)
+    }
+
+    fn write_word_register(&mut self, value: &mut u16, data: u8) {
+        *value = if self.flip_flop {
+            (*value & 0x00ff) | (data as u16) << 8
+        } else {
+            (*value & 0xff00) | data as u16
+        };
+        self.flip_flop = !self.flip_flop;
+    }
+
+    /// Reads register `reg`, 0-15.
+    pub fn rb(&mut self, reg: u8) -> u8 {
+        match reg & 0xf {
+            reg @ 0..=7 => {
+                let ch = &self.channels[(reg >> 1) as usize];
+                let value = if (reg & 1) == 0 {
+                    ch.current_address
+                } else {
+                    ch.current_count
+                };
+                let byte = if self.flip_flop { value >> 8 } else { value };
+                self.flip_flop = !self.flip_flop;
+                byte as u8
+            }
+            8 => {
+                let status = self.status();
+                self.terminal_count = 0;
+                status
+            }
+            // The temporary register only holds anything after a memory to
+            // memory transfer, which the PC can't do.
+            13 => 0,
+            15 => self
+                .channels
+                .iter()
+                .enumerate()
+                .fold(0xf0, |bits, (i, ch)| bits | (ch.masked as u8) << i),
+            _ => 0xff,
+        }
+    }
+
+    /// Writes register `reg`, 0-15.
+    pub fn wb(&mut self, reg: u8, data: u8) {
+        match reg & 0xf {
+            reg @ 0..=7 => {
+                let mut ch = self.channels[(reg >> 1) as usize];
+                // Both the base and current registers take the write.
+                if (reg & 1) == 0 {
+                    self.write_word_register(&mut ch.base_address, data);
+                    ch.current_address = ch.base_address;
+                } else {
+                    self.write_word_register(&mut ch.base_count, data);
+                    ch.current_count = ch.base_count;
+                }
+                self.channels[(reg >> 1) as usize] = ch;
+            }
+            8 => {
+                if (data & 0x01) != 0 {
+                    debug!(target: "dma", "Memory to memory transfers aren't supported");
+                }
+                self.command = data;
+            }
+            9 => {
+                let ch = &mut self.channels[(data & 3) as usize];
+                ch.software_request = (data & 0x04) != 0;
+            }
+            10 => self.channels[(data & 3) as usize].masked = (data & 0x04) != 0,
+            11 => {
+                let ch = &mut self.channels[(data & 3) as usize];
+                ch.mode = data & 0xfc;
+                ch.block_active = false;
+            }
+            12 => self.flip_flop = false,
+            13 => *self = DMA::new(),
+            14 => self.channels.iter_mut().for_each(|ch| ch.masked = false),
+            15 => {
+                for (i, ch) in self.channels.iter_mut().enumerate() {
+                    ch.masked = (data & (1 << i)) != 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Default for DMA {
+    fn default() -> DMA {
+        DMA::new()
+    }
+}
+
+// RESET is wired to the system reset, which does the same as a master
+// clear.
+impl Reset for DMA {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind != ResetKind::Warm {
+            *self = DMA::new();
+        }
+    }
+}
+
+/// Which page register (80h + index) holds the high address bits for each
+/// channel. The gaps are unused latches BIOSes like to keep things in.
+const PAGE_REGISTERS: [usize; 8] = [7, 3, 1, 2, 0xf, 0xb, 9, 0xa];
+
+/// The DMA side of the bus: the 8237 the PC has for channels 0-3, the
+/// second one the AT adds for 16-bit channels 4-7, and the page registers
+/// at 80h-8Fh.
+///
+/// Devices assert DREQ with `dma_request` and move data with `dma_read`
+/// (memory to device) and `dma_write` (device to memory). Both return
+/// `None` when the channel won't transfer, for instance because it's
+/// masked or programmed the other way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DmaController {
+    pub dma1: DMA,
+    /// The AT's 16-bit controller. Channel 4 is the cascade from `dma1`.
+    pub dma2: Option<DMA>,
+    pub pages: [u8; 16],
+}
+
+impl DmaController {
+    /// The PC and XT's single 8237 at 00h-0Fh.
+    pub fn pc() -> DmaController {
+        DmaController {
+            dma1: DMA::new(),
+            dma2: None,
+            pages: [0; 16],
+        }
+    }
+
+    /// The AT's pair, the second at C0h-DFh on even ports.
+    pub fn at() -> DmaController {
+        DmaController {
+            dma2: Some(DMA::new()),
+            ..DmaController::pc()
+        }
+    }
+
+    fn chip(&mut self, channel: usize) -> Option<&mut DMA> {
+        if channel < 4 {
+            Some(&mut self.dma1)
+        } else {
+            self.dma2.as_mut()
+        }
+    }
+
+    fn active(&self, channel: usize) -> bool {
+        match &self.dma2 {
+            // Channels 0-3 only get the bus through the cascade on 4.
+            Some(dma2) if channel < 4 => {
+                !dma2.channels[0].masked
+                    && !dma2.disabled()
+                    && dma2.channels[0].transfer_mode() == TransferMode::Cascade
+                    && self.dma1.active(channel)
+            }
+            Some(dma2) => dma2.active(channel - 4),
+            None => channel < 4 && self.dma1.active(channel),
+        }
+    }
+
+    /// Asserts DREQ. In block mode that's enough to start the whole
+    /// transfer.
+    pub fn dma_request(&mut self, channel: usize) {
+        if let Some(dma) = self.chip(channel) {
+            let ch = &mut dma.channels[channel & 3];
+            ch.request = true;
+            if ch.transfer_mode() == TransferMode::Block {
+                ch.block_active = true;
+            }
+        }
+    }
+
+    /// Drops DREQ. Only demand mode transfers notice.
+    pub fn dma_release(&mut self, channel: usize) {
+        if let Some(dma) = self.chip(channel) {
+            dma.channels[channel & 3].request = false;
+        }
+    }
+
+    pub fn terminal_count(&self, channel: usize) -> bool {
+        let dma = if channel < 4 {
+            Some(&self.dma1)
+        } else {
+            self.dma2.as_ref()
+        };
+        dma.is_some_and(|dma| (dma.terminal_count & (1 << (channel & 3))) != 0)
+    }
+
+    /// Runs one transfer cycle if `channel` is ready to go `way`. Returns
+    /// the physical address, or `None` for a verify cycle, and whether it
+    /// was the last one.
+    fn transfer(&mut self, channel: usize, way: TransferType) -> Option<(Option<usize>, bool)> {
+        if !self.active(channel) {
+            return None;
+        }
+        let page = self.pages[PAGE_REGISTERS[channel]] as usize;
+        let dma = self.chip(channel)?;
+        let ty = dma.channels[channel & 3].transfer_type();
+        if ty != way && ty != TransferType::Verify {
+            return None;
+        }
+        let (address, done) = dma.advance(channel & 3);
+        // The 16-bit channels count words, so their addresses are shifted
+        // and the page register's low bit is left out.
+        let physical = if channel < 4 {
+            page << 16 | address as usize
+        } else {
+            (page & 0xfe) << 16 | (address as usize) << 1
+        };
+        if ty == TransferType::Verify {
+            return Some((None, done));
+        }
+        Some((Some(physical), done))
+    }
+
+    /// Reads the next byte, or word on channels 4-7, from `memory` for the
+    /// device on `channel`. Also returns whether this was the terminal
+    /// count.
+    pub fn dma_read(&mut self, channel: usize, memory: &[u8]) -> Option<(u16, bool)> {
+        let (address, done) = self.transfer(channel, TransferType::Read)?;
+        let address = match address {
+            Some(address) => address,
+            None => return Some((0xffff, done)),
+        };
+        let byte = |address: usize| memory.get(address).copied().unwrap_or(0xff);
+        let value = if channel < 4 {
+            byte(address) as u16
+        } else {
+            u16::from_le_bytes([byte(address), byte(address.wrapping_add(1))])
+        };
+        Some((value, done))
+    }
+
+    /// Writes the device's byte, or word on channels 4-7, to `memory`.
+    /// Returns whether this was the terminal count.
+    pub fn dma_write(&mut self, channel: usize, memory: &mut [u8], value: u16) -> Option<bool> {
+        let (address, done) = self.transfer(channel, TransferType::Write)?;
+        let address = match address {
+            Some(address) => address,
+            None => return Some(done),
+        };
+        let bytes = value.to_le_bytes();
+        let count = if channel < 4 { 1 } else { 2 };
+        for (i, byte) in bytes.iter().take(count).enumerate() {
+            if let Some(cell) = memory.get_mut(address.wrapping_add(i)) {
+                *cell = *byte;
+            }
+        }
+        Some(done)
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x00..=0x1f => self.dma1.rb(addr as u8),
+            0x80..=0x8f => self.pages[(addr & 0xf) as usize],
+            0xc0..=0xdf => match self.dma2.as_mut() {
+                Some(dma) => dma.rb(((addr - 0xc0) >> 1) as u8),
+                None => 0xff,
+            },
+            _ => 0xff,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x00..=0x1f => self.dma1.wb(addr as u8, data),
+            0x80..=0x8f => self.pages[(addr & 0xf) as usize] = data,
+            0xc0..=0xdf => {
+                if let Some(dma) = self.dma2.as_mut() {
+                    dma.wb(((addr - 0xc0) >> 1) as u8, data);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for DmaController {
+    fn default() -> DmaController {
+        DmaController::pc()
+    }
+}
+
+// The page registers are plain latches with no reset line.
+impl Reset for DmaController {
+    fn reset(&mut self, kind: ResetKind) {
+        self.dma1.reset(kind);
+        if let Some(dma) = self.dma2.as_mut() {
+            dma.reset(kind);
+        }
+        if kind == ResetKind::Cold {
+            self.pages = [0; 16];
+        }
+    }
+}
+
+#[test]
+fn test_dma_transfers() {
+    let mut dma = DmaController::at();
+    let mut memory = vec![0; 0x4_0000];
+    // Unmask the cascade so channels 0-3 can get the bus.
+    dma.wb(0xd6, 0xc0);
+    dma.wb(0xd4, 0x00);
+
+    // Channel 2, single mode, device to memory, 3 bytes at 2:0010h.
+    dma.wb(0x0c, 0);
+    dma.wb(0x0b, 0x46);
+    dma.wb(0x04, 0x10);
+    dma.wb(0x04, 0x00);
+    dma.wb(0x05, 0x02);
+    dma.wb(0x05, 0x00);
+    dma.wb(0x81, 0x02);
+    assert_eq!(dma.dma_write(2, &mut memory, 0xaa), None);
+    dma.wb(0x0a, 0x02);
+    assert_eq!(dma.dma_write(2, &mut memory, 0xaa), None);
+    dma.dma_request(2);
+    assert_eq!(dma.rb(0x08) & 0x40, 0x40);
+    assert_eq!(dma.dma_write(2, &mut memory, 0xaa), Some(false));
+    assert_eq!(dma.dma_write(2, &mut memory, 0xbb), None);
+    dma.dma_request(2);
+    assert_eq!(dma.dma_read(2, &memory), None);
+    assert_eq!(dma.dma_write(2, &mut memory, 0xbb), Some(false));
+    dma.dma_request(2);
+    assert_eq!(dma.dma_write(2, &mut memory, 0xcc), Some(true));
+    assert_eq!(&memory[0x2_0010..0x2_0013], &[0xaa, 0xbb, 0xcc]);
+    assert!(dma.terminal_count(2));
+    assert_eq!(dma.rb(0x08), 0x04);
+    assert!(!dma.terminal_count(2));
+    // Without auto-init the channel masks itself.
+    assert!(dma.dma1.channels[2].masked);
+
+    // Channel 5, block mode with auto-init, memory to device, decrementing.
+    // Word address 1000h in page 2 is byte address 22000h.
+    memory[0x2_2000..0x2_2002].copy_from_slice(&[0x34, 0x12]);
+    memory[0x2_1ffe..0x2_2000].copy_from_slice(&[0x78, 0x56]);
+    dma.wb(0xd6, 0xb9);
+    dma.wb(0xd8, 0);
+    dma.wb(0xc4, 0x00);
+    dma.wb(0xc4, 0x10);
+    dma.wb(0xc6, 0x01);
+    dma.wb(0xc6, 0x00);
+    dma.wb(0x8b, 0x02);
+    dma.wb(0xd4, 0x01);
+    dma.dma_request(5);
+    dma.dma_release(5);
+    assert_eq!(dma.dma_read(5, &memory), Some((0x1234, false)));
+    assert_eq!(dma.dma_read(5, &memory), Some((0x5678, true)));
+    assert_eq!(dma.dma_read(5, &memory), None);
+    dma.dma_request(5);
+    assert_eq!(dma.dma_read(5, &memory), Some((0x1234, false)));
+}
@@ -0,0 +1,146 @@
+// An expanded memory board in the style of the Lo-tech 2MB EMS card,
+// which the LTEMM driver turns into LIM EMS for DOS. Its memory is only
+// seen through a 64K page frame of four 16K windows in the upper memory
+// area, and each window shows whichever 16K page of the board was last
+// written to its page register. There's nothing else on the card: the
+// driver does the bookkeeping LIM 4.0 asks for, handles and page maps
+// and all, in conventional memory.
+//
+// The registers are write-only and the page number is only decoded as far
+// as the board has memory, so a larger number wraps around.
+use crate::hardware::reset::{Reset, ResetKind};
+
+pub const EMS_BASE: u16 = 0x0260;
+pub const EMS_PAGE_SIZE: usize = 0x4000;
+pub const EMS_FRAME_SIZE: u32 = 0x1_0000;
+
+/// Where the jumpers put the page frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageFrame {
+    D000,
+    E000,
+}
+
+impl PageFrame {
+    pub fn addr(self) -> u32 {
+        match self {
+            PageFrame::D000 => 0x0d_0000,
+            PageFrame::E000 => 0x0e_0000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EmsConfig {
+    pub base: u16,
+    pub frame: PageFrame,
+    /// Memory on the board, in 16K steps up to 4 MB.
+    pub size_kb: usize,
+}
+
+impl Default for EmsConfig {
+    fn default() -> EmsConfig {
+        EmsConfig {
+            base: EMS_BASE,
+            frame: PageFrame::D000,
+            size_kb: 2048,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmsBoard {
+    pub config: EmsConfig,
+    memory: Vec<u8>,
+    /// The page each window of the frame shows.
+    pages: [u8; 4],
+}
+
+impl EmsBoard {
+    pub fn new(config: EmsConfig) -> Result<EmsBoard, String> {
+        let pages = config.size_kb * 1024 / EMS_PAGE_SIZE;
+        if !config.size_kb.is_multiple_of(16) || pages == 0 || pages > 256 {
+            return Err(format!(
+                "an EMS board holds 16K to 4096K in 16K steps, not {}K",
+                config.size_kb
+            ));
+        }
+        Ok(EmsBoard {
+            config,
+            memory: vec![0; pages * EMS_PAGE_SIZE],
+            pages: [0; 4],
+        })
+    }
+
+    /// The page frame, inclusive.
+    pub fn frame(&self) -> (u32, u32) {
+        let start = self.config.frame.addr();
+        (start, start + EMS_FRAME_SIZE - 1)
+    }
+
+    fn offset(&self, addr: u32) -> usize {
+        let offset = (addr - self.config.frame.addr()) as usize;
+        let page = self.pages[offset / EMS_PAGE_SIZE] as usize;
+        (page * EMS_PAGE_SIZE + offset % EMS_PAGE_SIZE) % self.memory.len()
+    }
+
+    pub fn read_mem(&self, addr: u32) -> u8 {
+        self.memory[self.offset(addr)]
+    }
+
+    pub fn write_mem(&mut self, addr: u32, value: u8) {
+        let offset = self.offset(addr);
+        self.memory[offset] = value;
+    }
+
+    pub fn rb(&mut self, _addr: u16) -> u8 {
+        0xff
+    }
+
+    pub fn wb(&mut self, addr: u16, value: u8) {
+        self.pages[(addr - self.config.base) as usize & 3] = value;
+    }
+}
+
+// The memory is plain DRAM, so only switching off loses it.
+impl Reset for EmsBoard {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            self.memory.fill(0);
+            self.pages = [0; 4];
+        }
+    }
+}
+
+#[test]
+fn test_ems_page_mapping() {
+    assert!(EmsBoard::new(EmsConfig {
+        size_kb: 8192,
+        ..EmsConfig::default()
+    })
+    .is_err());
+    let mut ems = EmsBoard::new(EmsConfig {
+        frame: PageFrame::E000,
+        size_kb: 256,
+        ..EmsConfig::default()
+    })
+    .unwrap();
+    assert_eq!(ems.frame(), (0x0e_0000, 0x0e_ffff));
+    ems.wb(EMS_BASE, 5);
+    ems.wb(EMS_BASE + 3, 5);
+    ems.write_mem(0x0e_0010, 0x42);
+    // Both windows show page 5.
+    assert_eq!(ems.read_mem(0x0e_c010), 0x42);
+    ems.wb(EMS_BASE + 3, 6);
+    assert_eq!(ems.read_mem(0x0e_c010), 0);
+    // Sixteen pages, so page 21 is page 5 again.
+    ems.wb(EMS_BASE + 1, 21);
+    assert_eq!(ems.read_mem(0x0e_4010), 0x42);
+    assert_eq!(ems.rb(EMS_BASE), 0xff);
+
+    ems.reset(ResetKind::Warm);
+    assert_eq!(ems.read_mem(0x0e_0010), 0x42);
+    ems.reset(ResetKind::Cold);
+    ems.wb(EMS_BASE, 5);
+    assert_eq!(ems.read_mem(0x0e_0010), 0);
+}
@@ -0,0 +1,827 @@
+use crate::hardware::dma::DmaController;
+use crate::hardware::floppy::{DataRate, FloppyDrive, FloppyError};
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace};
+use std::collections::VecDeque;
+
+/// The DMA channel and IRQ the PC's floppy controller is wired to.
+pub const FDC_DMA_CHANNEL: usize = 2;
+pub const FDC_IRQ: u8 = 6;
+
+/// Digital output register (3F2h) bits. The low two select a drive and the
+/// top four turn the motors on.
+const DOR_NOT_RESET: u8 = 0x04;
+const DOR_DMA_ENABLE: u8 = 0x08;
+
+/// Main status register (3F4h) bits.
+const MSR_BUSY: u8 = 0x10;
+const MSR_DATA_OUT: u8 = 0x40;
+const MSR_READY: u8 = 0x80;
+
+/// Status register 0 bits. The top two are the interrupt code.
+const ST0_ABNORMAL: u8 = 0x40;
+const ST0_INVALID: u8 = 0x80;
+const ST0_READY_CHANGED: u8 = 0xc0;
+const ST0_SEEK_END: u8 = 0x20;
+const ST0_EQUIPMENT_CHECK: u8 = 0x10;
+const ST0_NOT_READY: u8 = 0x08;
+
+/// Status register 1 bits.
+const ST1_END_OF_CYLINDER: u8 = 0x80;
+const ST1_NOT_WRITABLE: u8 = 0x02;
+const ST1_NO_DATA: u8 = 0x04;
+const ST1_MISSING_ADDRESS_MARK: u8 = 0x01;
+const ST1_DATA_ERROR: u8 = 0x20;
+
+/// Status register 2 bits.
+const ST2_CONTROL_MARK: u8 = 0x40;
+const ST2_DATA_ERROR_IN_DATA: u8 = 0x20;
+const ST2_MISSING_DATA_MARK: u8 = 0x01;
+
+/// Status register 3 bits.
+const ST3_WRITE_PROTECTED: u8 = 0x40;
+const ST3_READY: u8 = 0x20;
+const ST3_TRACK0: u8 = 0x10;
+const ST3_TWO_SIDE: u8 = 0x08;
+
+/// The step rate SPECIFY sets, until it's given: the IBM BIOS's 3 ms at
+/// 500 kbps.
+const DEFAULT_STEP_RATE: u8 = 0x0d;
+
+/// How many step pulses RECALIBRATE gives before it gives up on finding
+/// track 0.
+const RECALIBRATE_STEPS: u8 = 77;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// Taking command bytes.
+    Command,
+    /// Moving data over DMA.
+    Execution,
+    /// Handing back result bytes.
+    Result,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operation {
+    Read,
+    Write,
+    Format,
+}
+
+/// A READ DATA, WRITE DATA or FORMAT TRACK under way.
+#[derive(Debug, Clone, PartialEq)]
+struct Transfer {
+    operation: Operation,
+    drive: usize,
+    head: u8,
+    /// The C, H, R and N of the sector being transferred.
+    id: [u8; 4],
+    /// The last sector on the track, or for a format the sectors to lay
+    /// down.
+    end: u8,
+    multi_track: bool,
+    /// Whether a read passes over sectors with a deleted data mark.
+    skip: bool,
+    /// What the format fills each sector with.
+    filler: u8,
+    /// The sector's data, or for a format the ID bytes, as far as the DMA
+    /// has got.
+    buffer: Vec<u8>,
+    position: usize,
+    /// ST0, ST1 and ST2 to end on once the sector in the buffer has gone,
+    /// for one with a data error or a deleted mark.
+    ending: Option<(u8, u8, u8)>,
+}
+
+/// The NEC µPD765 floppy disk controller at 3F0h-3F7h, with the digital
+/// output register in front of it that holds it in reset, turns the drive
+/// motors on and gates its DMA and interrupt lines. The AT's controller
+/// adds a data rate register at 3F7h and the disk change line; the PC's
+/// card runs at 250 kbps only.
+///
+/// Seeks take as long as the step rate SPECIFY set: the board collects
+/// the delay with `take_delay` and calls `delay_elapsed` once it's up.
+/// Data moves on DMA channel 2 when the board ticks the controller, as
+/// fast as the DMA controller takes it.
+#[derive(Debug, Clone)]
+pub struct FDC {
+    pub drives: [Option<FloppyDrive>; 2],
+    pub dor: u8,
+    /// The data rate select bits, written at 3F7h on the AT.
+    pub rate_select: u8,
+    at: bool,
+    phase: Phase,
+    command: Vec<u8>,
+    result: VecDeque<u8>,
+    /// The cylinder the controller thinks each drive is on.
+    pcn: [u8; 4],
+    /// ST0 of interrupts still to be collected by SENSE INTERRUPT STATUS,
+    /// with the drive's cylinder.
+    interrupts: VecDeque<(u8, u8)>,
+    transfer: Option<Transfer>,
+    irq: bool,
+    /// The SRT field of the last SPECIFY.
+    step_rate: u8,
+    /// The drive a seek or recalibrate is moving, with the ST0 and
+    /// cylinder to interrupt with when it's done.
+    seeking: Option<(usize, u8, u8)>,
+    /// Microseconds until the seek is done, for the board to collect.
+    delay_us: Option<u64>,
+    /// State for what weak bits read back as.
+    noise: u32,
+}
+
+impl FDC {
+    /// The PC and XT's controller card.
+    pub fn pc(drives: [Option<FloppyDrive>; 2]) -> FDC {
+        FDC {
+            drives,
+            dor: 0,
+            rate_select: 2,
+            at: false,
+            phase: Phase::Command,
+            command: vec![],
+            result: VecDeque::new(),
+            pcn: [0; 4],
+            interrupts: VecDeque::new(),
+            transfer: None,
+            irq: false,
+            step_rate: DEFAULT_STEP_RATE,
+            seeking: None,
+            delay_us: None,
+            noise: 0x2545_f491,
+        }
+    }
+
+    /// The AT's fixed disk and diskette adapter, which starts at 500 kbps.
+    pub fn at(drives: [Option<FloppyDrive>; 2]) -> FDC {
+        FDC {
+            rate_select: 0,
+            at: true,
+            ..FDC::pc(drives)
+        }
+    }
+
+    /// IRQ 6, which the DOR can keep off the bus.
+    pub fn irq(&self) -> bool {
+        self.irq && (self.dor & DOR_DMA_ENABLE) != 0
+    }
+
+    fn data_rate(&self) -> Option<DataRate> {
+        match self.rate_select & 3 {
+            0 => Some(DataRate::Rate500K),
+            1 => Some(DataRate::Rate300K),
+            2 => Some(DataRate::Rate250K),
+            _ => None,
+        }
+    }
+
+    /// Whether `drive`'s motor is on, which is what its light shows.
+    pub fn motor_on(&self, drive: usize) -> bool {
+        (self.dor & (0x10 << drive)) != 0
+    }
+
+    /// A drive that's there, spinning and has a disk in it.
+    fn ready_drive(&self, drive: usize) -> Option<&FloppyDrive> {
+        self.drives
+            .get(drive)?
+            .as_ref()
+            .filter(|floppy| floppy.media.is_some() && self.motor_on(drive))
+    }
+
+    /// The low four bits show which drive is seeking.
+    fn status(&self) -> u8 {
+        let seeking = self.seeking.map_or(0, |(drive, _, _)| 1 << drive);
+        seeking
+            | match self.phase {
+                Phase::Command if self.command.is_empty() => MSR_READY,
+                Phase::Command => MSR_READY | MSR_BUSY,
+                Phase::Execution => MSR_BUSY,
+                Phase::Result => MSR_READY | MSR_DATA_OUT | MSR_BUSY,
+            }
+    }
+
+    /// How long a seek of `steps` tracks takes. The step rate counts in
+    /// milliseconds at 500 kbps and stretches at slower data rates.
+    fn seek_time_us(&self, steps: u8) -> u64 {
+        let rate_kbps = match self.rate_select & 3 {
+            0 => 500,
+            1 => 300,
+            2 => 250,
+            _ => 1000,
+        };
+        let step_us = (16 - self.step_rate as u64) * 1000 * 500 / rate_kbps;
+        steps as u64 * step_us
+    }
+
+    /// The time the board should wait before calling `delay_elapsed`, once
+    /// a seek has started.
+    pub fn take_delay(&mut self) -> Option<u64> {
+        self.delay_us.take()
+    }
+
+    /// Ends the seek under way and interrupts.
+    pub fn delay_elapsed(&mut self) {
+        if let Some((_, st0, pcn)) = self.seeking.take() {
+            self.interrupts.push_back((st0, pcn));
+            self.irq = true;
+        }
+    }
+
+    /// Starts the heads moving for `steps` tracks, interrupting with `st0`
+    /// once they get there.
+    fn start_seek(&mut self, drive: usize, steps: u8, st0: u8, pcn: u8) {
+        // The 765 can overlap seeks on different drives; one at a time
+        // will do, so an earlier one finishes here.
+        self.delay_elapsed();
+        self.seeking = Some((drive, st0, pcn));
+        match steps {
+            0 => self.delay_elapsed(),
+            _ => self.delay_us = Some(self.seek_time_us(steps)),
+        }
+    }
+
+    /// Dropping the DOR's reset bit resets the 765. Letting it go again
+    /// leaves four "ready changed" interrupts for the BIOS to sense, one
+    /// per drive select.
+    fn write_dor(&mut self, data: u8) {
+        trace!(target: "fdc", "DOR <- {:#04x}", data);
+        let was_reset = (self.dor & DOR_NOT_RESET) == 0;
+        self.dor = data;
+        if (data & DOR_NOT_RESET) == 0 {
+            self.reset_controller();
+        } else if was_reset {
+            self.interrupts = (0..4).map(|drive| (ST0_READY_CHANGED | drive, 0)).collect();
+            self.irq = true;
+        }
+    }
+
+    fn reset_controller(&mut self) {
+        self.phase = Phase::Command;
+        self.command.clear();
+        self.result.clear();
+        self.interrupts.clear();
+        self.transfer = None;
+        self.irq = false;
+        self.seeking = None;
+        self.delay_us = None;
+    }
+
+    /// How many bytes each command takes, including the command byte.
+    fn command_length(command: u8) -> usize {
+        match command & 0x1f {
+            0x03 => 3,
+            0x04 | 0x07 => 2,
+            0x05 | 0x06 => 9,
+            0x08 => 1,
+            0x0d => 6,
+            0x0f => 3,
+            _ => 1,
+        }
+    }
+
+    fn write_data(&mut self, data: u8) {
+        if self.phase != Phase::Command {
+            debug!(target: "fdc", "Data byte {:#04x} outside the command phase", data);
+            return;
+        }
+        self.command.push(data);
+        if self.command.len() == Self::command_length(self.command[0]) {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        if self.phase != Phase::Result {
+            return 0xff;
+        }
+        // Reading the result of a data transfer clears its interrupt.
+        self.irq = false;
+        let value = self.result.pop_front().unwrap_or(0xff);
+        if self.result.is_empty() {
+            self.phase = Phase::Command;
+        }
+        value
+    }
+
+    fn finish(&mut self, result: &[u8]) {
+        self.result = result.iter().copied().collect();
+        self.phase = Phase::Result;
+    }
+
+    fn execute(&mut self, command: &[u8]) {
+        trace!(target: "fdc", "Command {:02x?}", command);
+        let drive = (command.get(1).copied().unwrap_or(0) & 3) as usize;
+        let head = (command.get(1).copied().unwrap_or(0) >> 2) & 1;
+        match command[0] & 0x1f {
+            // SPECIFY sets the step rate and head load and unload times,
+            // of which only the step rate is timed. Non-DMA mode isn't
+            // supported.
+            0x03 => {
+                self.step_rate = command[1] >> 4;
+                if (command[2] & 1) != 0 {
+                    debug!(target: "fdc", "Non-DMA mode isn't supported");
+                }
+            }
+            0x04 => {
+                let st3 = self.sense_drive(drive) | head << 2 | drive as u8;
+                self.finish(&[st3]);
+            }
+            0x05 | 0x06 | 0x0d => {
+                let (operation, id, end) = match command[0] & 0x1f {
+                    0x05 => (
+                        Operation::Write,
+                        [command[2], command[3], command[4], command[5]],
+                        command[6],
+                    ),
+                    0x06 => (
+                        Operation::Read,
+                        [command[2], command[3], command[4], command[5]],
+                        command[6],
+                    ),
+                    _ => (Operation::Format, [0, head, 0, command[2]], command[3]),
+                };
+                self.transfer = Some(Transfer {
+                    operation,
+                    drive,
+                    head,
+                    id,
+                    end,
+                    multi_track: (command[0] & 0x80) != 0,
+                    skip: (command[0] & 0x20) != 0,
+                    filler: command[5],
+                    buffer: vec![],
+                    position: 0,
+                    ending: None,
+                });
+                self.phase = Phase::Execution;
+            }
+            0x07 => self.recalibrate(drive),
+            0x08 => {
+                self.irq = false;
+                match self.interrupts.pop_front() {
+                    Some((st0, pcn)) => self.finish(&[st0, pcn]),
+                    None => self.finish(&[ST0_INVALID]),
+                }
+            }
+            0x0f => self.seek(drive, head, command[2]),
+            _ => {
+                debug!(target: "fdc", "Invalid command {:#04x}", command[0]);
+                self.finish(&[ST0_INVALID]);
+            }
+        }
+    }
+
+    fn sense_drive(&self, drive: usize) -> u8 {
+        let floppy = match self.drives.get(drive).and_then(Option::as_ref) {
+            Some(floppy) => floppy,
+            None => return ST3_READY,
+        };
+        let write_protected = floppy
+            .media
+            .as_ref()
+            .is_some_and(|media| media.write_protected);
+        ST3_READY
+            | ST3_TWO_SIDE
+            | ((write_protected as u8) * ST3_WRITE_PROTECTED)
+            | ((floppy.track0() as u8) * ST3_TRACK0)
+    }
+
+    /// Steps out until the drive says it's at track 0. An 80 track drive
+    /// beyond track 77 needs a second go, as on the real thing.
+    fn recalibrate(&mut self, drive: usize) {
+        let mut st0 = ST0_SEEK_END | drive as u8;
+        let mut steps = 0;
+        match self.drives.get_mut(drive).and_then(Option::as_mut) {
+            Some(floppy) => {
+                while steps < RECALIBRATE_STEPS && !floppy.track0() {
+                    floppy.step(false);
+                    steps += 1;
+                }
+                if !floppy.track0() {
+                    st0 |= ST0_ABNORMAL | ST0_EQUIPMENT_CHECK;
+                }
+            }
+            None => st0 |= ST0_ABNORMAL | ST0_EQUIPMENT_CHECK,
+        }
+        self.pcn[drive] = 0;
+        self.start_seek(drive, steps, st0, 0);
+    }
+
+    /// Steps the drive from where the controller thinks it is to
+    /// `cylinder`. Double stepping for 40 track media is up to the BIOS.
+    fn seek(&mut self, drive: usize, head: u8, cylinder: u8) {
+        let current = self.pcn[drive];
+        let steps = current.abs_diff(cylinder);
+        if let Some(floppy) = self.drives.get_mut(drive).and_then(Option::as_mut) {
+            for _ in 0..steps {
+                floppy.step(cylinder > current);
+            }
+        }
+        self.pcn[drive] = cylinder;
+        let st0 = ST0_SEEK_END | head << 2 | drive as u8;
+        self.start_seek(drive, steps, st0, cylinder);
+    }
+
+    /// Moves a data transfer on for as long as the DMA controller keeps
+    /// up, then interrupts with the result when it's done.
+    pub fn tick(&mut self, dma: &mut DmaController, memory: &mut [u8]) {
+        if self.phase != Phase::Execution || (self.dor & DOR_DMA_ENABLE) == 0 {
+            return;
+        }
+        let mut transfer = match self.transfer.take() {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        let outcome = match transfer.operation {
+            Operation::Read => self.read_sectors(&mut transfer, dma, memory),
+            Operation::Write => self.write_sectors(&mut transfer, dma, memory),
+            Operation::Format => self.format_track(&mut transfer, dma, memory),
+        };
+        match outcome {
+            Some((st0, st1, st2)) => {
+                dma.dma_release(FDC_DMA_CHANNEL);
+                let st0 = st0 | transfer.head << 2 | transfer.drive as u8;
+                let [c, h, r, n] = transfer.id;
+                self.finish(&[st0, st1, st2, c, h, r, n]);
+                self.irq = true;
+            }
+            None => self.transfer = Some(transfer),
+        }
+    }
+
+    /// Why a sector couldn't be found or written, as ST0, ST1 and ST2.
+    fn error_status(&self, drive: usize, err: FloppyError) -> (u8, u8, u8) {
+        if self.ready_drive(drive).is_none() {
+            return (ST0_ABNORMAL | ST0_NOT_READY, 0, 0);
+        }
+        let st1 = match err {
+            FloppyError::SectorNotFound => ST1_NO_DATA,
+            FloppyError::WriteProtected => ST1_NOT_WRITABLE,
+            _ => ST1_MISSING_ADDRESS_MARK,
+        };
+        (ST0_ABNORMAL, st1, 0)
+    }
+
+    /// What a weak bit reads back as this time, from a xorshift generator.
+    fn noise(&mut self) -> u8 {
+        let mut x = self.noise;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise = x;
+        x as u8
+    }
+
+    /// Moves the ID on to the next sector, over to the second side for a
+    /// multi-track command. Returns whether that ran off the end of the
+    /// cylinder.
+    fn next_sector(transfer: &mut Transfer) -> bool {
+        if transfer.id[2] < transfer.end {
+            transfer.id[2] += 1;
+            return false;
+        }
+        transfer.id[2] = 1;
+        if transfer.multi_track && transfer.head == 0 {
+            transfer.head = 1;
+            transfer.id[1] = 1;
+            return false;
+        }
+        if transfer.multi_track {
+            transfer.head = 0;
+            transfer.id[1] = 0;
+        }
+        transfer.id[0] = transfer.id[0].wrapping_add(1);
+        true
+    }
+
+    /// Sector by sector until terminal count. Returns ST0, ST1 and ST2
+    /// once it's over, or `None` while waiting on the DMA controller.
+    fn read_sectors(
+        &mut self,
+        transfer: &mut Transfer,
+        dma: &mut DmaController,
+        memory: &mut [u8],
+    ) -> Option<(u8, u8, u8)> {
+        loop {
+            if transfer.buffer.is_empty() {
+                let [c, _, r, _] = transfer.id;
+                let sector = self
+                    .data_rate()
+                    .ok_or(FloppyError::DataRateMismatch)
+                    .and_then(|rate| match self.ready_drive(transfer.drive) {
+                        Some(floppy) => floppy.find_sector(transfer.head, c, r, rate),
+                        None => Err(FloppyError::NoMedia),
+                    })
+                    .cloned();
+                let sector = match sector {
+                    Ok(sector) => sector,
+                    Err(err) => return Some(self.error_status(transfer.drive, err)),
+                };
+                if sector.no_data {
+                    return Some((
+                        ST0_ABNORMAL,
+                        ST1_MISSING_ADDRESS_MARK,
+                        ST2_MISSING_DATA_MARK,
+                    ));
+                }
+                if sector.deleted && transfer.skip {
+                    if Self::next_sector(transfer) {
+                        return Some((ST0_ABNORMAL, ST1_END_OF_CYLINDER, ST2_CONTROL_MARK));
+                    }
+                    continue;
+                }
+                // A data error ends the read on the bad sector, after
+                // passing on what was read. A deleted sector is read, then
+                // ends it.
+                transfer.ending = if sector.data_error {
+                    Some((ST0_ABNORMAL, ST1_DATA_ERROR, ST2_DATA_ERROR_IN_DATA))
+                } else if sector.deleted {
+                    Some((0, 0, ST2_CONTROL_MARK))
+                } else {
+                    None
+                };
+                transfer.buffer = sector.read(|| self.noise());
+            }
+            let mut terminal_count = false;
+            while transfer.position < transfer.buffer.len() && !terminal_count {
+                dma.dma_request(FDC_DMA_CHANNEL);
+                let value = transfer.buffer[transfer.position] as u16;
+                terminal_count = dma.dma_write(FDC_DMA_CHANNEL, memory, value)?;
+                transfer.position += 1;
+            }
+            transfer.buffer.clear();
+            transfer.position = 0;
+            if let Some((st0, st1, st2)) = transfer.ending.take() {
+                if (st0 & ST0_ABNORMAL) == 0 {
+                    Self::next_sector(transfer);
+                }
+                return Some((st0, st1, st2));
+            }
+            let end_of_cylinder = Self::next_sector(transfer);
+            if terminal_count {
+                return Some((0, 0, 0));
+            }
+            if end_of_cylinder {
+                return Some((ST0_ABNORMAL, ST1_END_OF_CYLINDER, 0));
+            }
+        }
+    }
+
+    fn write_sectors(
+        &mut self,
+        transfer: &mut Transfer,
+        dma: &mut DmaController,
+        memory: &mut [u8],
+    ) -> Option<(u8, u8, u8)> {
+        loop {
+            let size = 128 << (transfer.id[3] & 7);
+            let mut terminal_count = false;
+            while transfer.buffer.len() < size && !terminal_count {
+                dma.dma_request(FDC_DMA_CHANNEL);
+                let (value, done) = dma.dma_read(FDC_DMA_CHANNEL, memory)?;
+                transfer.buffer.push(value as u8);
+                terminal_count = done;
+            }
+            // A terminal count part way through a sector pads it out
+            // with zeroes.
+            transfer.buffer.resize(size, 0);
+            let [c, _, r, _] = transfer.id;
+            let rate = self.data_rate();
+            let result =
+                match (self.ready_drive(transfer.drive).is_some(), rate) {
+                    (true, Some(rate)) => self.drives[transfer.drive]
+                        .as_mut()
+                        .unwrap()
+                        .write_sector(transfer.head, c, r, rate, &transfer.buffer),
+                    (true, None) => Err(FloppyError::DataRateMismatch),
+                    (false, _) => Err(FloppyError::NoMedia),
+                };
+            if let Err(err) = result {
+                return Some(self.error_status(transfer.drive, err));
+            }
+            transfer.buffer.clear();
+            let end_of_cylinder = Self::next_sector(transfer);
+            if terminal_count {
+                return Some((0, 0, 0));
+            }
+            if end_of_cylinder {
+                return Some((ST0_ABNORMAL, ST1_END_OF_CYLINDER, 0));
+            }
+        }
+    }
+
+    /// Takes four ID bytes per sector over DMA, then lays the track down
+    /// with each sector full of the filler byte.
+    fn format_track(
+        &mut self,
+        transfer: &mut Transfer,
+        dma: &mut DmaController,
+        memory: &mut [u8],
+    ) -> Option<(u8, u8, u8)> {
+        let length = transfer.end as usize * 4;
+        while transfer.buffer.len() < length {
+            dma.dma_request(FDC_DMA_CHANNEL);
+            let (value, terminal_count) = dma.dma_read(FDC_DMA_CHANNEL, memory)?;
+            transfer.buffer.push(value as u8);
+            if terminal_count {
+                break;
+            }
+        }
+        let ids: Vec<[u8; 4]> = transfer
+            .buffer
+            .chunks_exact(4)
+            .map(|id| [id[0], id[1], id[2], id[3]])
+            .collect();
+        if let Some(last) = ids.last() {
+            transfer.id = *last;
+        }
+        let result = match (self.ready_drive(transfer.drive).is_some(), self.data_rate()) {
+            (true, Some(rate)) => self.drives[transfer.drive].as_mut().unwrap().format_track(
+                transfer.head,
+                &ids,
+                transfer.filler,
+                rate,
+            ),
+            (true, None) => Err(FloppyError::DataRateMismatch),
+            (false, _) => Err(FloppyError::NoMedia),
+        };
+        match result {
+            Ok(()) => Some((0, 0, 0)),
+            Err(err) => Some(self.error_status(transfer.drive, err)),
+        }
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr & 7 {
+            4 => self.status(),
+            5 => self.read_data(),
+            // Bit 7 of the AT's digital input register is the selected
+            // drive's disk change line. The rest belongs to the fixed
+            // disk controller.
+            7 if self.at => {
+                let drive = (self.dor & 3) as usize;
+                let changed = self
+                    .drives
+                    .get(drive)
+                    .and_then(Option::as_ref)
+                    .is_some_and(|floppy| floppy.disk_changed);
+                (changed as u8) << 7
+            }
+            _ => 0xff,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr & 7 {
+            2 => self.write_dor(data),
+            5 => self.write_data(data),
+            7 if self.at => self.rate_select = data & 3,
+            _ => {}
+        }
+    }
+}
+
+impl Default for FDC {
+    fn default() -> FDC {
+        FDC::pc([None, None])
+    }
+}
+
+// The board's reset line clears the DOR, which holds the 765 in reset and
+// turns the motors off. The drives and their disks stay.
+impl Reset for FDC {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        self.dor = 0;
+        self.rate_select = if self.at { 0 } else { 2 };
+        self.pcn = [0; 4];
+        self.reset_controller();
+    }
+}
+
+#[cfg(test)]
+use crate::hardware::floppy::{DriveType, FloppyMedia, MediaType};
+
+#[test]
+fn test_fdc_commands_and_dma() {
+    let mut fdc = FDC::pc([None, None]);
+    let mut dma = DmaController::pc();
+    let mut memory = vec![0; 0x1_0000];
+    let mut data = vec![0; MediaType::Media360K.size()];
+    // Track 1, head 1 is the fourth track in the image.
+    let offset = 3 * 9 * 512;
+    data[offset + 7 * 512] = 0x11;
+    data[offset + 8 * 512 + 87] = 0x22;
+    let mut drive = FloppyDrive::new(DriveType::Drive360K);
+    drive
+        .insert(FloppyMedia::new(MediaType::Media360K, data))
+        .unwrap();
+    fdc.drives[0] = Some(drive);
+    let command = |fdc: &mut FDC, bytes: &[u8]| {
+        for byte in bytes {
+            assert_eq!(fdc.rb(0x3f4) & 0xc0, 0x80);
+            fdc.wb(0x3f5, *byte);
+        }
+    };
+    let results = |fdc: &mut FDC| {
+        let mut bytes = vec![];
+        while fdc.rb(0x3f4) & 0xc0 == 0xc0 {
+            bytes.push(fdc.rb(0x3f5));
+        }
+        bytes
+    };
+
+    // Coming out of reset leaves an interrupt for each drive select.
+    fdc.wb(0x3f2, 0x1c);
+    assert!(fdc.irq());
+    for drive in 0..4 {
+        command(&mut fdc, &[0x08]);
+        assert_eq!(results(&mut fdc), vec![0xc0 | drive, 0]);
+    }
+    assert!(!fdc.irq());
+    command(&mut fdc, &[0x08]);
+    assert_eq!(results(&mut fdc), vec![0x80]);
+
+    // At 250 kbps a step rate of Dh is 6 ms a track, and the drive shows
+    // as seeking until then.
+    command(&mut fdc, &[0x03, 0xdf, 0x02]);
+    command(&mut fdc, &[0x0f, 0x00, 0x01]);
+    assert!(!fdc.irq());
+    assert_eq!(fdc.rb(0x3f4), 0x81);
+    assert_eq!(fdc.take_delay(), Some(6_000));
+    fdc.delay_elapsed();
+    assert!(fdc.irq());
+    command(&mut fdc, &[0x08]);
+    assert_eq!(results(&mut fdc), vec![0x20, 1]);
+    assert_eq!(fdc.drives[0].as_ref().unwrap().physical_track, 1);
+
+    // Channel 2 reads 600 bytes to 1000h. Terminal count ends the read
+    // after the second sector, and the result points past it.
+    dma.wb(0x0b, 0x46);
+    dma.wb(0x0c, 0);
+    dma.wb(0x04, 0x00);
+    dma.wb(0x04, 0x10);
+    dma.wb(0x05, 0x57);
+    dma.wb(0x05, 0x02);
+    dma.wb(0x0a, 0x02);
+    command(&mut fdc, &[0xe6, 0x04, 1, 1, 8, 2, 9, 0x2a, 0xff]);
+    assert_eq!(fdc.rb(0x3f4), 0x10);
+    fdc.tick(&mut dma, &mut memory);
+    assert!(fdc.irq());
+    assert_eq!(results(&mut fdc), vec![0x00, 0, 0, 2, 0, 1, 2]);
+    assert!(!fdc.irq());
+    assert_eq!((memory[0x1000], memory[0x1257]), (0x11, 0x22));
+
+    // Writes to a write protected disk fail.
+    fdc.drives[0]
+        .as_mut()
+        .unwrap()
+        .media
+        .as_mut()
+        .unwrap()
+        .write_protected = true;
+    dma.wb(0x0b, 0x4a);
+    dma.wb(0x0a, 0x02);
+    command(&mut fdc, &[0x45, 0x00, 1, 0, 1, 2, 9, 0x2a, 0xff]);
+    fdc.tick(&mut dma, &mut memory);
+    assert_eq!(results(&mut fdc)[..2], [0x40, 0x02]);
+
+    // Formatting track 1, head 0 takes the IDs from memory.
+    fdc.drives[0]
+        .as_mut()
+        .unwrap()
+        .media
+        .as_mut()
+        .unwrap()
+        .write_protected = false;
+    for sector in 0..9 {
+        memory[0x2000 + sector * 4..][..4].copy_from_slice(&[1, 0, sector as u8 + 1, 2]);
+    }
+    dma.wb(0x0c, 0);
+    dma.wb(0x04, 0x00);
+    dma.wb(0x04, 0x20);
+    dma.wb(0x05, 35);
+    dma.wb(0x05, 0);
+    dma.wb(0x0a, 0x02);
+    command(&mut fdc, &[0x4d, 0x00, 2, 9, 0x50, 0xf6]);
+    fdc.tick(&mut dma, &mut memory);
+    assert_eq!(results(&mut fdc), vec![0x00, 0, 0, 1, 0, 9, 2]);
+    let floppy = fdc.drives[0].as_ref().unwrap();
+    assert!((1..=9).all(|sector| {
+        floppy
+            .read_sector(0, 1, sector, DataRate::Rate250K)
+            .is_ok_and(|data| data.iter().all(|byte| *byte == 0xf6))
+    }));
+
+    // Recalibrating a missing drive fails, and a bad command is invalid.
+    command(&mut fdc, &[0x07, 0x01]);
+    command(&mut fdc, &[0x08]);
+    assert_eq!(results(&mut fdc), vec![0x71, 0]);
+    command(&mut fdc, &[0x1f]);
+    assert_eq!(results(&mut fdc), vec![0x80]);
+}
@@ -0,0 +1,181 @@
+// 86Box's flux images (.86f): a header, a table of where each track
+// starts, and for each track a few flags and the raw bit cells, with an
+// optional second layer marking which cells aren't really there. The
+// cells are stored as 16-bit words, and since images written on different
+// hosts disagree on their byte order the decoder tries both.
+use crate::hardware::floppy::mfm::Bitstream;
+use crate::hardware::floppy::{normalize_rate, DataRate, FloppyMedia, ImageReader, Track};
+
+/// Disk flags.
+const HAS_SURFACE: u16 = 0x0001;
+const TWO_SIDES: u16 = 0x0008;
+const WRITE_PROTECTED: u16 = 0x0010;
+const BITCELL_MODE: u16 = 0x0080;
+
+/// The number of bit cells in one turn of a track at `rate` and `rpm`.
+fn track_length(rate: DataRate, rpm: usize) -> usize {
+    let kbps = match rate {
+        DataRate::Rate250K => 250,
+        DataRate::Rate300K => 300,
+        DataRate::Rate500K => 500,
+        DataRate::Rate1M => 1000,
+    };
+    kbps * 1000 * 2 * 60 / rpm
+}
+
+struct RawTrack<'a> {
+    cylinder: u8,
+    head: u8,
+    rate: DataRate,
+    length: usize,
+    cells: &'a [u8],
+    surface: &'a [u8],
+}
+
+impl RawTrack<'_> {
+    /// Decodes the sectors, reading the cells either as stored or with
+    /// each pair of bytes swapped.
+    fn decode(&self, swap: bool) -> Track {
+        let order = |bytes: &[u8]| -> Vec<u8> {
+            match swap {
+                true => bytes
+                    .chunks(2)
+                    .flat_map(|pair| pair.iter().rev().copied())
+                    .collect(),
+                false => bytes.to_vec(),
+            }
+        };
+        let cells = order(self.cells);
+        // A surface bit set over a data bit set marks a weak cell; over a
+        // clear one it's a hole, which reads as nothing at all.
+        let weak: Vec<u8> = order(self.surface)
+            .iter()
+            .zip(&cells)
+            .map(|(surface, cell)| surface & cell)
+            .collect();
+        let bits = Bitstream {
+            cells: &cells,
+            weak: &weak,
+            length: self.length,
+        };
+        let mut track = Track::new(normalize_rate(self.rate));
+        track.sectors = bits.sectors();
+        track
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<FloppyMedia, String> {
+    let mut reader = ImageReader::new(data);
+    reader.bytes(4)?;
+    let version = reader.u16_le()?;
+    if version >> 8 != 2 {
+        return Err(format!("86F version {:x} isn't supported", version));
+    }
+    let flags = reader.u16_le()?;
+    let sides = if (flags & TWO_SIDES) != 0 { 2 } else { 1 };
+
+    let first = ImageReader::new(data.get(8..).unwrap_or(&[])).u32_le()? as usize;
+    let mut offsets = vec![];
+    for _ in 0..first.saturating_sub(8) / 4 {
+        offsets.push(reader.u32_le()? as usize);
+    }
+
+    let mut raw_tracks = vec![];
+    for (index, &offset) in offsets.iter().enumerate() {
+        if offset == 0 {
+            continue;
+        }
+        let mut reader = ImageReader::new(data.get(offset..).ok_or("track offset out of range")?);
+        let track_flags = reader.u16_le()?;
+        let extra = match flags & BITCELL_MODE {
+            0 => 0,
+            _ => reader.u32_le()? as i32,
+        };
+        reader.u32_le()?;
+        let rate = match track_flags & 7 {
+            0 => DataRate::Rate500K,
+            1 => DataRate::Rate300K,
+            2 => DataRate::Rate250K,
+            3 => DataRate::Rate1M,
+            rate => return Err(format!("unknown data rate {}", rate)),
+        };
+        let rpm = if (track_flags >> 5) & 7 == 1 {
+            360
+        } else {
+            300
+        };
+        let length = (track_length(rate, rpm) as i32 + extra).max(0) as usize;
+        let size = length.div_ceil(16) * 2;
+        let cells = reader.bytes(size)?;
+        let surface = match flags & HAS_SURFACE {
+            0 => &[][..],
+            _ => reader.bytes(size)?,
+        };
+        raw_tracks.push(RawTrack {
+            cylinder: (index / sides) as u8,
+            head: (index % sides) as u8,
+            rate,
+            length,
+            cells,
+            surface,
+        });
+    }
+
+    let swap = match raw_tracks.first() {
+        Some(track) => {
+            track.decode(false).sectors.is_empty() && !track.decode(true).sectors.is_empty()
+        }
+        None => false,
+    };
+    let tracks = raw_tracks
+        .iter()
+        .map(|track| ((track.cylinder, track.head), track.decode(swap)))
+        .collect();
+    let mut media = FloppyMedia::from_tracks(tracks);
+    media.write_protected = (flags & WRITE_PROTECTED) != 0;
+    Ok(media)
+}
+
+#[cfg(test)]
+use crate::hardware::floppy::mfm::{crc16, encode};
+
+#[test]
+fn test_86f_swapped_words() {
+    let mut field = vec![0xa1, 0xa1, 0xa1, 0xfe, 0, 0, 1, 1];
+    let crc = crc16(0xffff, &field);
+    field.extend_from_slice(&crc.to_be_bytes());
+    let mut data = vec![0xa1, 0xa1, 0xa1, 0xfb];
+    data.extend((0..256).map(|i| i as u8));
+    let crc = crc16(0xffff, &data);
+    data.extend_from_slice(&crc.to_be_bytes());
+    let mut cells = encode(&[0x4e; 80], 0);
+    cells.extend(encode(&[0; 12], 0));
+    cells.extend(encode(&field, 3));
+    cells.extend(encode(&[0x4e; 22], 0));
+    cells.extend(encode(&[0; 12], 0));
+    cells.extend(encode(&data, 3));
+    let length = track_length(DataRate::Rate250K, 300);
+    cells.resize(length / 8, 0x92);
+    // Stored as little endian words.
+    for pair in cells.chunks_mut(2) {
+        pair.swap(0, 1);
+    }
+
+    let mut image = b"86BF".to_vec();
+    image.extend_from_slice(&0x020cu16.to_le_bytes());
+    image.extend_from_slice(&(TWO_SIDES | WRITE_PROTECTED).to_le_bytes());
+    image.extend_from_slice(&16u32.to_le_bytes());
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&2u16.to_le_bytes());
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend(cells);
+
+    let media = parse(&image).unwrap();
+    assert!(media.write_protected);
+    let track = media.track(0, 0).unwrap();
+    assert_eq!(track.rate, DataRate::Rate250K);
+    assert_eq!(track.sectors.len(), 1);
+    assert_eq!(track.sectors[0].id, [0, 0, 1, 1]);
+    assert_eq!(track.sectors[0].data[200], 200);
+    assert!(!track.sectors[0].data_error);
+}
@@ -0,0 +1,101 @@
+// ImageDisk (.imd) images: a text header ending in 1Ah, then each track
+// with its mode, the IDs of its sectors in the order they were found, and
+// each sector's data, compressed when it's all one byte. Sectors keep
+// their own IDs, sizes, deleted marks and CRC errors.
+use crate::hardware::floppy::{normalize_rate, DataRate, FloppyMedia, ImageReader, Sector, Track};
+
+/// Head byte flags saying the track has a cylinder or head number for
+/// each sector that differs from the physical one.
+const CYLINDER_MAP: u8 = 0x80;
+const HEAD_MAP: u8 = 0x40;
+
+pub fn parse(data: &[u8]) -> Result<FloppyMedia, String> {
+    let header = data
+        .iter()
+        .position(|&byte| byte == 0x1a)
+        .ok_or("no end to the ImageDisk header")?;
+    let mut reader = ImageReader::new(&data[header + 1..]);
+    let mut tracks = vec![];
+    while !reader.at_end() {
+        let mode = reader.u8()?;
+        let cylinder = reader.u8()?;
+        let head = reader.u8()?;
+        let count = reader.u8()? as usize;
+        let size = reader.u8()?;
+        // Modes 0-2 are FM and 3-5 MFM, each at 500, 300 and 250 kbps.
+        let rate = match mode % 3 {
+            0 => DataRate::Rate500K,
+            1 => DataRate::Rate300K,
+            _ => DataRate::Rate250K,
+        };
+        let records = reader.bytes(count)?;
+        let cylinders = match head & CYLINDER_MAP {
+            0 => vec![cylinder; count],
+            _ => reader.bytes(count)?.to_vec(),
+        };
+        let heads = match head & HEAD_MAP {
+            0 => vec![head & 1; count],
+            _ => reader.bytes(count)?.to_vec(),
+        };
+        // Size code FFh means a table of 16-bit sizes follows.
+        let sizes = match size {
+            0xff => (0..count)
+                .map(|_| reader.u16_le().map(|size| size as usize))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => vec![128 << (size & 7); count],
+        };
+        let mut track = Track::new(normalize_rate(rate));
+        for i in 0..count {
+            let size_code = (sizes[i] / 128).max(1).trailing_zeros() as u8;
+            let id = [cylinders[i], heads[i], records[i], size_code];
+            let kind = reader.u8()?;
+            let mut sector = Sector::new(id, vec![]);
+            match kind {
+                0 => sector.no_data = true,
+                // Odd types are stored in full and even ones as a single
+                // byte. Past that, they go normal, deleted, data error,
+                // deleted with a data error.
+                1..=8 => {
+                    sector.data = match kind % 2 {
+                        1 => reader.bytes(sizes[i])?.to_vec(),
+                        _ => vec![reader.u8()?; sizes[i]],
+                    };
+                    let flags = (kind - 1) / 2;
+                    sector.deleted = (flags & 1) != 0;
+                    sector.data_error = flags >= 2;
+                }
+                _ => return Err(format!("unknown sector type {}", kind)),
+            }
+            track.sectors.push(sector);
+        }
+        tracks.push(((cylinder, head & 1), track));
+    }
+    Ok(FloppyMedia::from_tracks(tracks))
+}
+
+#[cfg(test)]
+use crate::hardware::floppy::MediaType;
+
+#[test]
+fn test_imd_sectors() {
+    let mut image = b"IMD 1.18: 01/01/1990 00:00:00\r\nA disk\x1a".to_vec();
+    // Cylinder 0, head 1 at 250 kbps MFM with a head map: a compressed
+    // 512 byte sector and a deleted 256 byte one from a table of sizes.
+    image.extend_from_slice(&[5, 0, 1 | HEAD_MAP, 2, 0xff, 9, 1, 1, 0]);
+    image.extend_from_slice(&[0x00, 0x02, 0x00, 0x01]);
+    image.extend_from_slice(&[2, 0xe5, 3]);
+    image.extend((0..256).map(|i| i as u8));
+    image.extend_from_slice(&[3, 39, 0, 1, 2, 1, 0]);
+
+    let media = parse(&image).unwrap();
+    assert_eq!(media.media_type, MediaType::Media320K);
+    let track = media.track(0, 1).unwrap();
+    assert_eq!(track.sectors[0].id, [0, 1, 9, 2]);
+    assert_eq!(track.sectors[0].data, vec![0xe5; 512]);
+    assert_eq!(track.sectors[1].id, [0, 0, 1, 1]);
+    assert!(track.sectors[1].deleted && !track.sectors[1].data_error);
+    assert_eq!(track.sectors[1].data[255], 255);
+    let track = media.track(39, 0).unwrap();
+    assert!(track.sectors[0].no_data);
+    assert_eq!(track.rate, DataRate::Rate500K);
+}
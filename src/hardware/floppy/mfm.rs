@@ -0,0 +1,198 @@
+// Decoding of the flux level formats, which store each track as the
+// stream of bit cells the drive's head sees rather than as sectors. The
+// sectors are found the way a controller finds them: by their address
+// marks, MFM's A1h sync bytes with a missing clock bit or FM's marks with
+// a C7h clock.
+use crate::hardware::floppy::Sector;
+
+/// A1h with the clock bit between bits 4 and 5 left out.
+const MFM_SYNC: u16 = 0x4489;
+/// FM's ID, data and deleted data marks with their C7h clock.
+const FM_ID_MARK: u16 = 0xf57e;
+const FM_DATA_MARK: u16 = 0xf56f;
+const FM_DELETED_MARK: u16 = 0xf56a;
+
+const ID_MARK: u8 = 0xfe;
+const DATA_MARK: u8 = 0xfb;
+const DELETED_MARK: u8 = 0xf8;
+
+/// CRC-CCITT, as the controller computes it over each field from the
+/// sync bytes on.
+pub fn crc16(crc: u16, data: &[u8]) -> u16 {
+    data.iter().fold(crc, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if (crc & 0x8000) != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// A track's bit cells, most significant bit of each byte first, and the
+/// cells that come back at random.
+pub struct Bitstream<'a> {
+    pub cells: &'a [u8],
+    pub weak: &'a [u8],
+    pub length: usize,
+}
+
+impl Bitstream<'_> {
+    /// Tracks are loops, so fields can run over the index.
+    fn cell(&self, position: usize) -> bool {
+        let position = position % self.length;
+        (self.cells[position / 8] >> (7 - position % 8)) & 1 != 0
+    }
+
+    fn weak_cell(&self, position: usize) -> bool {
+        let position = position % self.length;
+        self.weak
+            .get(position / 8)
+            .is_some_and(|byte| (byte >> (7 - position % 8)) & 1 != 0)
+    }
+
+    fn raw_word(&self, position: usize) -> u16 {
+        (0..16).fold(0, |word, i| word << 1 | self.cell(position + i) as u16)
+    }
+
+    /// The byte in the 16 cells from `position`, from the data cells in
+    /// between the clock cells, and which of its bits are weak.
+    fn byte(&self, position: usize) -> (u8, u8) {
+        (0..8).fold((0, 0), |(byte, weak), i| {
+            let cell = position + i * 2;
+            let weak_bit = self.weak_cell(cell) || self.weak_cell(cell + 1);
+            (
+                byte << 1 | self.cell(cell + 1) as u8,
+                weak << 1 | weak_bit as u8,
+            )
+        })
+    }
+
+    fn bytes(&self, position: usize, count: usize) -> (Vec<u8>, Vec<u8>) {
+        (0..count).map(|i| self.byte(position + i * 16)).unzip()
+    }
+
+    /// Pulls out every sector on the track. An ID with no data field
+    /// after it before the next ID becomes a sector with no data.
+    pub fn sectors(&self) -> Vec<Sector> {
+        let mut sectors = vec![];
+        if self.length < 16 || self.cells.len() * 8 < self.length {
+            return sectors;
+        }
+        let mut pending: Option<Sector> = None;
+        let mut shift = 0u16;
+        let mut position = 0;
+        while position < self.length {
+            shift = shift << 1 | self.cell(position) as u16;
+            position += 1;
+            // The position of the mark byte and the bytes the CRC starts
+            // with.
+            let (mark_at, prefix): (usize, &[u8]) = match shift {
+                MFM_SYNC => {
+                    if self.raw_word(position) != MFM_SYNC
+                        || self.raw_word(position + 16) != MFM_SYNC
+                    {
+                        continue;
+                    }
+                    (position + 32, &[0xa1, 0xa1, 0xa1])
+                }
+                FM_ID_MARK | FM_DATA_MARK | FM_DELETED_MARK => (position - 16, &[]),
+                _ => continue,
+            };
+            let (mark, _) = self.byte(mark_at);
+            let start = mark_at + 16;
+            let crc = crc16(0xffff, prefix);
+            match mark {
+                ID_MARK => {
+                    let (id, _) = self.bytes(start, 6);
+                    if crc16(crc, &[&[mark], &id[..]].concat()) != 0 {
+                        continue;
+                    }
+                    sectors.extend(pending.take());
+                    let mut sector = Sector::new([id[0], id[1], id[2], id[3]], vec![]);
+                    sector.no_data = true;
+                    pending = Some(sector);
+                    position = start + 6 * 16;
+                }
+                DATA_MARK | DELETED_MARK => {
+                    let mut sector = match pending.take() {
+                        Some(sector) => sector,
+                        None => continue,
+                    };
+                    let size = 128 << (sector.id[3] & 7);
+                    let (data, weak) = self.bytes(start, size + 2);
+                    sector.data_error = crc16(crc, &[&[mark], &data[..]].concat()) != 0;
+                    sector.deleted = mark == DELETED_MARK;
+                    sector.no_data = false;
+                    sector.data = data[..size].to_vec();
+                    if weak.iter().any(|byte| *byte != 0) {
+                        sector.weak = weak[..size].to_vec();
+                    }
+                    sectors.push(sector);
+                    position = start + (size + 2) * 16;
+                }
+                _ => {}
+            }
+        }
+        sectors.extend(pending);
+        sectors
+    }
+}
+
+/// Writes `data` as an MFM field the way a controller would, for tests.
+#[cfg(test)]
+pub fn encode(bytes: &[u8], sync: usize) -> Vec<u8> {
+    let mut cells = vec![];
+    let mut previous = false;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let mut word = 0u16;
+        for bit in (0..8).rev() {
+            let data = (byte >> bit) & 1 != 0;
+            let clock = !previous && !data;
+            word = word << 2 | (clock as u16) << 1 | data as u16;
+            previous = data;
+        }
+        if i < sync {
+            word = MFM_SYNC;
+        }
+        cells.extend_from_slice(&word.to_be_bytes());
+    }
+    cells
+}
+
+#[test]
+fn test_mfm_sectors() {
+    let id = [0x00, 0x00, 0x01, 0x00];
+    let mut field = vec![0xa1, 0xa1, 0xa1, ID_MARK];
+    field.extend_from_slice(&id);
+    let crc = crc16(0xffff, &field);
+    field.extend_from_slice(&crc.to_be_bytes());
+    let mut cells = encode(&[0x4e; 8], 0);
+    cells.extend(encode(&[0; 12], 0));
+    cells.extend(encode(&field, 3));
+    cells.extend(encode(&[0x4e; 22], 0));
+    let mut data = vec![0xa1, 0xa1, 0xa1, DELETED_MARK];
+    data.extend((0..128).map(|i| i as u8));
+    let crc = crc16(0xffff, &data);
+    data.extend_from_slice(&(crc ^ 1).to_be_bytes());
+    cells.extend(encode(&[0; 12], 0));
+    cells.extend(encode(&data, 3));
+    cells.extend(encode(&[0x4e; 40], 0));
+    let mut weak = vec![0; cells.len()];
+    let first_data = (8 + 12 + 10 + 22 + 12 + 4) * 2;
+    weak[first_data + 2] = 0xff;
+
+    let track = Bitstream {
+        cells: &cells,
+        weak: &weak,
+        length: cells.len() * 8,
+    };
+    let sectors = track.sectors();
+    assert_eq!(sectors.len(), 1);
+    let sector = &sectors[0];
+    assert_eq!(sector.id, id);
+    assert_eq!(sector.data[5], 5);
+    assert!(sector.deleted && sector.data_error && !sector.no_data);
+    assert_eq!(&sector.weak[..3], &[0x00, 0xf0, 0x00]);
+}
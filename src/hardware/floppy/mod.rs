@@ -0,0 +1,675 @@
+use log::{debug, warn};
+use std::fs;
+use std::path::PathBuf;
+
+pub mod d86f;
+pub mod imd;
+mod mfm;
+pub mod pri;
+pub mod psi;
+pub mod raw;
+pub mod td0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormFactor {
+    Inch525,
+    Inch35,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataRate {
+    Rate250K,
+    Rate300K,
+    Rate500K,
+    Rate1M,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriveType {
+    Drive360K,
+    Drive1200K,
+    Drive720K,
+    Drive1440K,
+}
+
+impl DriveType {
+    pub fn form_factor(self) -> FormFactor {
+        match self {
+            DriveType::Drive360K | DriveType::Drive1200K => FormFactor::Inch525,
+            DriveType::Drive720K | DriveType::Drive1440K => FormFactor::Inch35,
+        }
+    }
+
+    /// Number of physical track positions the head can step to.
+    pub fn tracks(self) -> u8 {
+        match self {
+            DriveType::Drive360K => 40,
+            _ => 80,
+        }
+    }
+
+    pub fn rpm(self) -> u16 {
+        match self {
+            DriveType::Drive1200K => 360,
+            _ => 300,
+        }
+    }
+
+    pub fn high_density(self) -> bool {
+        matches!(self, DriveType::Drive1200K | DriveType::Drive1440K)
+    }
+
+    /// Drive type code as stored in CMOS register 10h.
+    pub fn cmos_type(self) -> u8 {
+        match self {
+            DriveType::Drive360K => 1,
+            DriveType::Drive1200K => 2,
+            DriveType::Drive720K => 3,
+            DriveType::Drive1440K => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaType {
+    Media160K,
+    Media180K,
+    Media320K,
+    Media360K,
+    Media720K,
+    Media1200K,
+    Media1440K,
+    Media2880K,
+}
+
+impl MediaType {
+    pub const ALL: [MediaType; 8] = [
+        MediaType::Media160K,
+        MediaType::Media180K,
+        MediaType::Media320K,
+        MediaType::Media360K,
+        MediaType::Media720K,
+        MediaType::Media1200K,
+        MediaType::Media1440K,
+        MediaType::Media2880K,
+    ];
+
+    pub fn form_factor(self) -> FormFactor {
+        match self {
+            MediaType::Media720K | MediaType::Media1440K | MediaType::Media2880K => {
+                FormFactor::Inch35
+            }
+            _ => FormFactor::Inch525,
+        }
+    }
+
+    pub fn tracks(self) -> u8 {
+        match self {
+            MediaType::Media720K
+            | MediaType::Media1200K
+            | MediaType::Media1440K
+            | MediaType::Media2880K => 80,
+            _ => 40,
+        }
+    }
+
+    pub fn heads(self) -> u8 {
+        match self {
+            MediaType::Media160K | MediaType::Media180K => 1,
+            _ => 2,
+        }
+    }
+
+    pub fn sectors(self) -> u8 {
+        match self {
+            MediaType::Media160K | MediaType::Media320K => 8,
+            MediaType::Media180K | MediaType::Media360K | MediaType::Media720K => 9,
+            MediaType::Media1200K => 15,
+            MediaType::Media1440K => 18,
+            MediaType::Media2880K => 36,
+        }
+    }
+
+    pub fn high_density(self) -> bool {
+        matches!(
+            self,
+            MediaType::Media1200K | MediaType::Media1440K | MediaType::Media2880K
+        )
+    }
+
+    pub fn size(self) -> usize {
+        self.tracks() as usize * self.heads() as usize * self.sectors() as usize * 512
+    }
+
+    /// The standard format nearest to a disk with this geometry, written at
+    /// `rate` in a 300 rpm drive.
+    pub fn closest(cylinders: usize, heads: u8, sectors: usize, rate: DataRate) -> MediaType {
+        let tracks = if cylinders > 45 { 80 } else { 40 };
+        let rate = normalize_rate(rate);
+        MediaType::ALL
+            .iter()
+            .copied()
+            .filter(|media_type| media_type.data_rate_in(DriveType::Drive1440K) == rate)
+            .min_by_key(|media_type| {
+                (
+                    media_type.tracks() != tracks,
+                    media_type.heads() != heads.clamp(1, 2),
+                    (media_type.sectors() as isize - sectors as isize).abs(),
+                )
+            })
+            .unwrap_or(MediaType::Media360K)
+    }
+
+    /// The data rate the controller has to select to read this media in a
+    /// given drive. Double density 5.25" media spins faster in a 1.2M drive,
+    /// so it needs 300 kbps there instead of 250 kbps.
+    pub fn data_rate_in(self, drive: DriveType) -> DataRate {
+        if self == MediaType::Media2880K {
+            DataRate::Rate1M
+        } else if self.high_density() {
+            DataRate::Rate500K
+        } else if drive.rpm() == 360 {
+            DataRate::Rate300K
+        } else {
+            DataRate::Rate250K
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloppyError {
+    NoMedia,
+    WrongFormFactor,
+    DataRateMismatch,
+    NoTrack,
+    SectorNotFound,
+    WriteProtected,
+}
+
+/// What happens to sectors the machine writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MountMode {
+    /// The disk shows as write protected.
+    ReadOnly,
+    /// Each sector written goes straight back to a raw image file. Other
+    /// formats keep their writes in memory.
+    WriteBack,
+}
+
+/// A sector as it sits on the disk: the ID the controller searches for
+/// and the data field behind it, along with whatever is wrong with either.
+/// Copy protection relies on all of these.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sector {
+    /// The cylinder, head, record and size code from the ID field.
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+    /// Bits set here read back differently every time. Empty if the
+    /// sector has no weak bits.
+    pub weak: Vec<u8>,
+    /// The data field's CRC doesn't match.
+    pub data_error: bool,
+    /// Written with a deleted data address mark.
+    pub deleted: bool,
+    /// An ID field with no data field behind it.
+    pub no_data: bool,
+}
+
+impl Sector {
+    pub fn new(id: [u8; 4], data: Vec<u8>) -> Sector {
+        Sector {
+            id,
+            data,
+            weak: vec![],
+            data_error: false,
+            deleted: false,
+            no_data: false,
+        }
+    }
+
+    /// The data as one read sees it, with `noise` standing in for the
+    /// flux the drive picks up where the bits are weak.
+    pub fn read(&self, mut noise: impl FnMut() -> u8) -> Vec<u8> {
+        let mut data = self.data.clone();
+        for (byte, mask) in data.iter_mut().zip(&self.weak) {
+            if *mask != 0 {
+                *byte = (*byte & !mask) | (noise() & mask);
+            }
+        }
+        data
+    }
+}
+
+/// The sectors of one side of one cylinder, in the order they pass the
+/// head.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    /// The rate the track was written at in a 300 rpm drive.
+    pub rate: DataRate,
+    pub sectors: Vec<Sector>,
+}
+
+impl Track {
+    pub fn new(rate: DataRate) -> Track {
+        Track {
+            rate,
+            sectors: vec![],
+        }
+    }
+}
+
+/// The rate a track written at `rate` in a 300 rpm drive reads at in
+/// `drive`. Double density 5.25" media spins faster in a 1.2M drive, so it
+/// needs 300 kbps there instead of 250 kbps.
+fn rate_in_drive(rate: DataRate, drive: DriveType) -> DataRate {
+    match rate {
+        DataRate::Rate250K if drive.rpm() == 360 => DataRate::Rate300K,
+        _ => rate,
+    }
+}
+
+/// Image formats record the rate the disk was read at, which for double
+/// density disks imaged in a 1.2M drive is 300 kbps.
+fn normalize_rate(rate: DataRate) -> DataRate {
+    match rate {
+        DataRate::Rate300K => DataRate::Rate250K,
+        _ => rate,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FloppyMedia {
+    /// The standard format closest to the disk, which decides which drives
+    /// it fits in and how many tracks it has.
+    pub media_type: MediaType,
+    /// Indexed by cylinder * 2 + head.
+    pub tracks: Vec<Track>,
+    pub write_protected: bool,
+    /// The raw image file writes are saved back to, if the disk was
+    /// mounted that way.
+    pub image_path: Option<PathBuf>,
+}
+
+impl FloppyMedia {
+    /// A disk in a standard format, from the sectors in order.
+    pub fn new(media_type: MediaType, mut data: Vec<u8>) -> FloppyMedia {
+        data.resize(media_type.size(), 0);
+        let rate = media_type.data_rate_in(DriveType::Drive1440K);
+        let mut sectors = data.chunks(512);
+        let mut tracks = vec![];
+        for cylinder in 0..media_type.tracks() {
+            for head in 0..2 {
+                let mut track = Track::new(rate);
+                if head < media_type.heads() {
+                    track.sectors = (1..=media_type.sectors())
+                        .zip(&mut sectors)
+                        .map(|(record, data)| {
+                            Sector::new([cylinder, head, record, 2], data.to_vec())
+                        })
+                        .collect();
+                }
+                tracks.push(track);
+            }
+        }
+        FloppyMedia {
+            media_type,
+            tracks,
+            write_protected: false,
+            image_path: None,
+        }
+    }
+
+    /// A disk from an image that records each track, by physical cylinder
+    /// and head.
+    fn from_tracks(tracks: Vec<((u8, u8), Track)>) -> FloppyMedia {
+        let cylinders = tracks
+            .iter()
+            .map(|((cylinder, _), _)| *cylinder as usize + 1)
+            .max();
+        let heads = tracks
+            .iter()
+            .map(|((_, head), _)| *head + 1)
+            .max()
+            .unwrap_or(1);
+        let sectors = tracks
+            .iter()
+            .map(|(_, track)| track.sectors.len())
+            .max()
+            .unwrap_or(0);
+        let rate = tracks
+            .iter()
+            .find(|(_, track)| !track.sectors.is_empty())
+            .map_or(DataRate::Rate250K, |(_, track)| track.rate);
+        let media_type = MediaType::closest(cylinders.unwrap_or(0), heads, sectors, rate);
+        let mut media = FloppyMedia {
+            media_type,
+            tracks: vec![],
+            write_protected: false,
+            image_path: None,
+        };
+        for ((cylinder, head), track) in tracks {
+            let index = cylinder as usize * 2 + (head & 1) as usize;
+            if media.tracks.len() <= index {
+                media.tracks.resize(index + 1, Track::new(rate));
+            }
+            media.tracks[index] = track;
+        }
+        media
+    }
+
+    /// Loads an image in any of the formats there's support for, telling
+    /// them apart by their signatures. Anything else has to be a raw image.
+    pub fn open(path: &str, mode: MountMode) -> Result<FloppyMedia, String> {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        let parsed = match data.get(..4).unwrap_or(&[]) {
+            b"IMD " => imd::parse(&data),
+            b"86BF" => d86f::parse(&data),
+            b"PSI " => psi::parse(&data),
+            b"PRI " => pri::parse(&data),
+            [b'T', b'D', ..] | [b't', b'd', ..] => td0::parse(&data),
+            _ => return FloppyMedia::open_raw(path, data, mode),
+        };
+        let mut media = parsed.map_err(|err| format!("{}: {}", path, err))?;
+        debug!(target: "fdc", "Opened {} as {:?}", path, media.media_type);
+        match mode {
+            MountMode::ReadOnly => media.write_protected = true,
+            MountMode::WriteBack => {
+                warn!(target: "fdc", "{}: only raw images can be written back", path)
+            }
+        }
+        Ok(media)
+    }
+
+    pub fn track(&self, cylinder: u8, head: u8) -> Option<&Track> {
+        self.tracks.get(cylinder as usize * 2 + (head & 1) as usize)
+    }
+
+    fn track_mut(&mut self, cylinder: u8, head: u8) -> &mut Track {
+        let index = cylinder as usize * 2 + (head & 1) as usize;
+        if self.tracks.len() <= index {
+            self.tracks
+                .resize(index + 1, Track::new(DataRate::Rate250K));
+        }
+        &mut self.tracks[index]
+    }
+}
+
+/// Reads the little and big endian fields of image files, failing instead
+/// of running off the end.
+struct ImageReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ImageReader<'a> {
+    fn new(data: &'a [u8]) -> ImageReader<'a> {
+        ImageReader { data, position: 0 }
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + count)
+            .ok_or("the image is cut short")?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_le(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u16_be(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32_le(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u32_be(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+/// A physical drive mechanism. The media inserted into it is tracked
+/// separately so that mismatched combinations behave like real hardware:
+/// 40-track media in an 80-track drive needs double-stepping, high density
+/// media can't be read by a double density drive, and so on.
+#[derive(Debug, Clone)]
+pub struct FloppyDrive {
+    pub drive_type: DriveType,
+    pub media: Option<FloppyMedia>,
+    pub physical_track: u8,
+    pub disk_changed: bool,
+}
+
+impl FloppyDrive {
+    pub fn new(drive_type: DriveType) -> FloppyDrive {
+        FloppyDrive {
+            drive_type,
+            media: None,
+            physical_track: 0,
+            disk_changed: true,
+        }
+    }
+
+    pub fn insert(&mut self, media: FloppyMedia) -> Result<(), FloppyError> {
+        if media.media_type.form_factor() != self.drive_type.form_factor() {
+            return Err(FloppyError::WrongFormFactor);
+        }
+        debug!(
+            target: "fdc",
+            "Inserted {:?} media into {:?} drive",
+            media.media_type,
+            self.drive_type
+        );
+        self.media = Some(media);
+        self.disk_changed = true;
+        Ok(())
+    }
+
+    pub fn eject(&mut self) -> Option<FloppyMedia> {
+        self.disk_changed = true;
+        self.media.take()
+    }
+
+    pub fn track0(&self) -> bool {
+        self.physical_track == 0
+    }
+
+    /// Steps the head one track position, clamping at the mechanical stops.
+    pub fn step(&mut self, inward: bool) {
+        if inward {
+            if self.physical_track + 1 < self.drive_type.tracks() {
+                self.physical_track += 1;
+            }
+        } else if self.physical_track > 0 {
+            self.physical_track -= 1;
+        }
+        // A step pulse with media present clears the disk change line.
+        if self.media.is_some() {
+            self.disk_changed = false;
+        }
+    }
+
+    /// The media track currently under the head, if the head is sitting on
+    /// one. 40-track media in an 80-track drive only has data on the even
+    /// physical positions, which is why controllers must double-step.
+    pub fn media_track(&self) -> Result<u8, FloppyError> {
+        let media = self.media.as_ref().ok_or(FloppyError::NoMedia)?;
+        let drive_tracks = self.drive_type.tracks();
+        let media_tracks = media.media_type.tracks();
+        if media_tracks >= drive_tracks {
+            Ok(self.physical_track)
+        } else if self.physical_track.is_multiple_of(2) {
+            Ok(self.physical_track / 2)
+        } else {
+            Err(FloppyError::NoTrack)
+        }
+    }
+
+    fn check_rate(&self, written: DataRate, rate: DataRate) -> Result<(), FloppyError> {
+        let high_density = matches!(written, DataRate::Rate500K | DataRate::Rate1M);
+        if high_density && !self.drive_type.high_density() {
+            return Err(FloppyError::DataRateMismatch);
+        }
+        if rate_in_drive(written, self.drive_type) != rate {
+            return Err(FloppyError::DataRateMismatch);
+        }
+        Ok(())
+    }
+
+    /// The track under `head`, if it can be read at `rate`.
+    fn readable_track(&self, head: u8, rate: DataRate) -> Result<&Track, FloppyError> {
+        let track = self.media_track()?;
+        let media = self.media.as_ref().ok_or(FloppyError::NoMedia)?;
+        let track = media
+            .track(track, head)
+            .filter(|track| !track.sectors.is_empty())
+            .ok_or(FloppyError::NoTrack)?;
+        self.check_rate(track.rate, rate)?;
+        Ok(track)
+    }
+
+    /// Finds the sector with the given ID on the track under the head.
+    pub fn find_sector(
+        &self,
+        head: u8,
+        cylinder: u8,
+        sector: u8,
+        rate: DataRate,
+    ) -> Result<&Sector, FloppyError> {
+        self.readable_track(head, rate)?
+            .sectors
+            .iter()
+            .find(|found| found.id[0] == cylinder && found.id[2] == sector)
+            .ok_or(FloppyError::SectorNotFound)
+    }
+
+    /// Reads the sector with the given ID from the track under the head.
+    pub fn read_sector(
+        &self,
+        head: u8,
+        cylinder: u8,
+        sector: u8,
+        rate: DataRate,
+    ) -> Result<&[u8], FloppyError> {
+        Ok(&self.find_sector(head, cylinder, sector, rate)?.data)
+    }
+
+    /// Rewrites the data field of a sector, which clears whatever was wrong
+    /// with it.
+    pub fn write_sector(
+        &mut self,
+        head: u8,
+        cylinder: u8,
+        sector: u8,
+        rate: DataRate,
+        data: &[u8],
+    ) -> Result<(), FloppyError> {
+        self.find_sector(head, cylinder, sector, rate)?;
+        let track = self.media_track()?;
+        let media = self.media.as_mut().unwrap();
+        if media.write_protected {
+            return Err(FloppyError::WriteProtected);
+        }
+        let found = media
+            .track_mut(track, head)
+            .sectors
+            .iter_mut()
+            .find(|found| found.id[0] == cylinder && found.id[2] == sector)
+            .unwrap();
+        let length = found.data.len().min(data.len());
+        found.data[..length].copy_from_slice(&data[..length]);
+        found.weak.clear();
+        found.data_error = false;
+        found.deleted = false;
+        found.no_data = false;
+        let (id, data) = (found.id, found.data.clone());
+        media.write_back(track, head, id, &data);
+        Ok(())
+    }
+
+    /// Lays down a new track under `head` with the given sector IDs, each
+    /// filled with `filler`.
+    pub fn format_track(
+        &mut self,
+        head: u8,
+        ids: &[[u8; 4]],
+        filler: u8,
+        rate: DataRate,
+    ) -> Result<(), FloppyError> {
+        let track = self.media_track()?;
+        let drive_type = self.drive_type;
+        let media = self.media.as_mut().ok_or(FloppyError::NoMedia)?;
+        if media.write_protected {
+            return Err(FloppyError::WriteProtected);
+        }
+        let written = normalize_rate(rate);
+        if rate_in_drive(written, drive_type) != rate {
+            return Err(FloppyError::DataRateMismatch);
+        }
+        let sectors: Vec<Sector> = ids
+            .iter()
+            .map(|id| Sector::new(*id, vec![filler; 128 << (id[3] & 7)]))
+            .collect();
+        for sector in &sectors {
+            media.write_back(track, head, sector.id, &sector.data);
+        }
+        *media.track_mut(track, head) = Track {
+            rate: written,
+            sectors,
+        };
+        Ok(())
+    }
+}
+
+/// Builds CMOS register 10h: drive A type in the high nibble, B in the low.
+pub fn cmos_drive_types(drives: &[Option<FloppyDrive>; 2]) -> u8 {
+    let code = |drive: &Option<FloppyDrive>| match drive {
+        Some(drive) => drive.drive_type.cmos_type(),
+        None => 0,
+    };
+    (code(&drives[0]) << 4) | code(&drives[1])
+}
+
+#[test]
+fn test_360k_media_in_1200k_drive() {
+    let mut drive = FloppyDrive::new(DriveType::Drive1200K);
+    let mut data = vec![0; MediaType::Media360K.size()];
+    data[9 * 2 * 512] = 0xa5; // track 1, head 0, sector 1
+    drive
+        .insert(FloppyMedia::new(MediaType::Media360K, data))
+        .unwrap();
+
+    drive.step(true);
+    assert_eq!(
+        drive.read_sector(0, 1, 1, DataRate::Rate300K),
+        Err(FloppyError::NoTrack)
+    );
+    drive.step(true);
+    assert_eq!(
+        drive.read_sector(0, 1, 1, DataRate::Rate250K),
+        Err(FloppyError::DataRateMismatch)
+    );
+    assert_eq!(
+        drive.read_sector(0, 1, 1, DataRate::Rate300K).unwrap()[0],
+        0xa5
+    );
+
+    let media = FloppyMedia::new(MediaType::Media720K, vec![]);
+    assert_eq!(drive.insert(media), Err(FloppyError::WrongFormFactor));
+}
@@ -0,0 +1,91 @@
+// PCE bit stream images (.pri): the same chunks as PSI, with a TRAK chunk
+// giving each track's position, length in bits and bit clock, and a DATA
+// chunk with its cells. Sectors are decoded from the cells when loading.
+use crate::hardware::floppy::mfm::Bitstream;
+use crate::hardware::floppy::psi::chunks;
+use crate::hardware::floppy::{normalize_rate, DataRate, FloppyMedia, ImageReader, Track};
+
+/// The data rate for a track's bit clock, which ticks twice per bit.
+fn rate_for_clock(clock: u32) -> DataRate {
+    match clock {
+        1_500_000..=u32::MAX => DataRate::Rate1M,
+        750_000..=1_499_999 => DataRate::Rate500K,
+        550_000..=749_999 => DataRate::Rate300K,
+        _ => DataRate::Rate250K,
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<FloppyMedia, String> {
+    let mut tracks = vec![];
+    // The cylinder, head, length and rate from the last TRAK.
+    let mut current = None;
+    for (id, body) in chunks(data)? {
+        let mut reader = ImageReader::new(body);
+        match &id {
+            b"TRAK" => {
+                let cylinder = reader.u32_be()? as u8;
+                let head = reader.u32_be()? as u8 & 1;
+                let length = reader.u32_be()? as usize;
+                let clock = reader.u32_be()?;
+                current = Some((cylinder, head, length, rate_for_clock(clock)));
+            }
+            b"DATA" => {
+                let (cylinder, head, length, rate) = match current {
+                    Some(track) => track,
+                    None => continue,
+                };
+                let bits = Bitstream {
+                    cells: body,
+                    weak: &[],
+                    length,
+                };
+                let mut track = Track::new(normalize_rate(rate));
+                track.sectors = bits.sectors();
+                tracks.push(((cylinder, head), track));
+            }
+            // WEAK chunks list cells by run rather than as a mask, and
+            // copy protection that depends on them is rare on the PC, so
+            // they're left out.
+            _ => {}
+        }
+    }
+    Ok(FloppyMedia::from_tracks(tracks))
+}
+
+#[cfg(test)]
+use crate::hardware::floppy::mfm::{crc16, encode};
+#[cfg(test)]
+use crate::hardware::floppy::psi::chunk;
+
+#[test]
+fn test_pri_track() {
+    let mut field = vec![0xa1, 0xa1, 0xa1, 0xfe, 2, 1, 3, 0];
+    let crc = crc16(0xffff, &field);
+    field.extend_from_slice(&crc.to_be_bytes());
+    let mut data = vec![0xa1, 0xa1, 0xa1, 0xfb];
+    data.extend_from_slice(&[0x77; 128]);
+    let crc = crc16(0xffff, &data);
+    data.extend_from_slice(&crc.to_be_bytes());
+    let mut cells = encode(&[0; 12], 0);
+    cells.extend(encode(&field, 3));
+    cells.extend(encode(&[0x4e; 22], 0));
+    cells.extend(encode(&[0; 12], 0));
+    cells.extend(encode(&data, 3));
+    cells.extend(encode(&[0x4e; 16], 0));
+
+    let mut trak = vec![];
+    for value in [2, 1, cells.len() as u32 * 8, 500_000] {
+        trak.extend_from_slice(&value.to_be_bytes());
+    }
+    let mut image = chunk(b"PRI ", &[0, 0, 0, 0]);
+    image.extend(chunk(b"TRAK", &trak));
+    image.extend(chunk(b"DATA", &cells));
+    image.extend(chunk(b"END ", &[]));
+
+    let media = parse(&image).unwrap();
+    let track = media.track(2, 1).unwrap();
+    assert_eq!(track.rate, DataRate::Rate250K);
+    assert_eq!(track.sectors[0].id, [2, 1, 3, 0]);
+    assert_eq!(track.sectors[0].data, vec![0x77; 128]);
+    assert!(!track.sectors[0].data_error);
+}
@@ -0,0 +1,143 @@
+// PCE sector images (.psi). Like PCE's other formats the file is a run of
+// chunks, each a four character ID, a big endian length, the data and a
+// CRC. A SECT chunk starts each sector and the chunks after it fill it in:
+// IBMM or IBMF for an ID that differs from its position, DATA and WEAK.
+use crate::hardware::floppy::{DataRate, FloppyMedia, ImageReader, Sector, Track};
+
+/// SECT flags.
+const SECT_COMPRESSED: u8 = 0x01;
+const SECT_DATA_ERROR: u8 = 0x04;
+
+/// IBMM and IBMF flags.
+const IBM_ID_ERROR: u8 = 0x01;
+const IBM_DATA_ERROR: u8 = 0x02;
+const IBM_DELETED: u8 = 0x04;
+const IBM_NO_DATA: u8 = 0x08;
+
+/// A chunk's ID and data.
+pub(super) type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// Splits a PCE image into its chunks, up to the END chunk. The CRCs
+/// aren't checked.
+pub(super) fn chunks(data: &[u8]) -> Result<Vec<Chunk<'_>>, String> {
+    let mut reader = ImageReader::new(data);
+    let mut chunks = vec![];
+    while !reader.at_end() {
+        let id = reader.bytes(4)?;
+        let id = [id[0], id[1], id[2], id[3]];
+        let length = reader.u32_be()? as usize;
+        let body = reader.bytes(length)?;
+        reader.bytes(4)?;
+        if &id == b"END " {
+            break;
+        }
+        chunks.push((id, body));
+    }
+    Ok(chunks)
+}
+
+/// The size code for a sector of `size` bytes.
+pub(super) fn size_code(size: usize) -> u8 {
+    (size / 128).max(1).trailing_zeros() as u8
+}
+
+pub fn parse(data: &[u8]) -> Result<FloppyMedia, String> {
+    let mut tracks: Vec<((u8, u8), Track)> = vec![];
+    let mut rate = DataRate::Rate250K;
+    // The track and sector the chunks after a SECT go to.
+    let mut current: Option<(usize, usize)> = None;
+    for (id, body) in chunks(data)? {
+        let mut reader = ImageReader::new(body);
+        let sector = current.map(|(track, sector)| &mut tracks[track].1.sectors[sector]);
+        match (&id, sector) {
+            // The second half of the version is the format: high byte 2
+            // is MFM, with the low byte picking double, high or extra
+            // high density.
+            (b"PSI ", _) => {
+                reader.u16_be()?;
+                rate = match reader.u16_be()? {
+                    0x0201 => DataRate::Rate500K,
+                    0x0202 => DataRate::Rate1M,
+                    _ => DataRate::Rate250K,
+                };
+            }
+            (b"SECT", _) => {
+                let cylinder = reader.u16_be()? as u8;
+                let head = reader.u8()? & 1;
+                let record = reader.u8()?;
+                let size = reader.u16_be()? as usize;
+                let flags = reader.u8()?;
+                let filler = reader.u8()?;
+                let id = [cylinder, head, record, size_code(size)];
+                let mut sector = Sector::new(id, vec![filler; size]);
+                if (flags & SECT_COMPRESSED) == 0 {
+                    sector.data.fill(0);
+                }
+                sector.data_error = (flags & SECT_DATA_ERROR) != 0;
+                let index = match tracks.iter().position(|(at, _)| *at == (cylinder, head)) {
+                    Some(index) => index,
+                    None => {
+                        tracks.push(((cylinder, head), Track::new(rate)));
+                        tracks.len() - 1
+                    }
+                };
+                tracks[index].1.sectors.push(sector);
+                current = Some((index, tracks[index].1.sectors.len() - 1));
+            }
+            (b"IBMM", Some(sector)) | (b"IBMF", Some(sector)) => {
+                let id = reader.bytes(4)?;
+                let flags = reader.u8()?;
+                sector.id = [id[0], id[1], id[2], id[3]];
+                sector.data_error |= (flags & IBM_DATA_ERROR) != 0;
+                sector.deleted = (flags & IBM_DELETED) != 0;
+                sector.no_data = (flags & IBM_NO_DATA) != 0;
+                // A controller can't find a sector whose ID doesn't check
+                // out.
+                if (flags & IBM_ID_ERROR) != 0 {
+                    let (track, index) = current.take().unwrap();
+                    tracks[track].1.sectors.remove(index);
+                }
+            }
+            (b"DATA", Some(sector)) => sector.data = body.to_vec(),
+            (b"WEAK", Some(sector)) => {
+                sector.weak = body.to_vec();
+                sector.weak.resize(sector.data.len(), 0);
+            }
+            _ => {}
+        }
+    }
+    Ok(FloppyMedia::from_tracks(tracks))
+}
+
+/// Wraps `body` in a chunk, for tests.
+#[cfg(test)]
+pub(super) fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut chunk = id.to_vec();
+    chunk.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(body);
+    chunk.extend_from_slice(&[0; 4]);
+    chunk
+}
+
+#[test]
+fn test_psi_sectors() {
+    let mut image = chunk(b"PSI ", &[0, 0, 0x02, 0x00]);
+    image.extend(chunk(b"SECT", &[0, 1, 0, 5, 0x01, 0x00, 0x04, 0x00]));
+    image.extend(chunk(b"IBMM", &[0x4f, 0x00, 0x05, 0x01, IBM_DELETED, 0]));
+    image.extend(chunk(b"DATA", &[0x11; 256]));
+    image.extend(chunk(b"WEAK", &[0x00, 0x0f]));
+    image.extend(chunk(b"SECT", &[0, 1, 0, 6, 0x02, 0x00, 0x01, 0xf6]));
+    image.extend(chunk(b"SECT", &[0, 1, 0, 7, 0x02, 0x00, 0x00, 0x00]));
+    image.extend(chunk(b"IBMM", &[1, 0, 7, 2, IBM_ID_ERROR, 0]));
+    image.extend(chunk(b"END ", &[]));
+
+    let media = parse(&image).unwrap();
+    let track = media.track(1, 0).unwrap();
+    assert_eq!(track.sectors.len(), 2);
+    let sector = &track.sectors[0];
+    assert_eq!(sector.id, [0x4f, 0x00, 0x05, 0x01]);
+    assert!(sector.deleted && sector.data_error);
+    assert_eq!(sector.data, vec![0x11; 256]);
+    assert_eq!(sector.weak[..3], [0x00, 0x0f, 0x00]);
+    assert_eq!(track.sectors[1].data, vec![0xf6; 512]);
+}
@@ -0,0 +1,133 @@
+// Raw sector images (.img, .ima): every sector on the disk in order, by
+// cylinder then head, with no header. The file's size is all there is to
+// tell the geometry by.
+use crate::hardware::floppy::{FloppyMedia, MediaType, MountMode};
+use log::{debug, warn};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+impl MediaType {
+    /// The standard format a raw image of `size` bytes holds.
+    pub fn from_image_size(size: usize) -> Option<MediaType> {
+        MediaType::ALL
+            .iter()
+            .copied()
+            .find(|media_type| media_type.size() == size)
+    }
+}
+
+impl FloppyMedia {
+    /// Takes a raw image, working out the format from its size.
+    pub(super) fn open_raw(
+        path: &str,
+        data: Vec<u8>,
+        mode: MountMode,
+    ) -> Result<FloppyMedia, String> {
+        let media_type = MediaType::from_image_size(data.len()).ok_or_else(|| {
+            format!(
+                "{}: {} bytes isn't the size of any standard floppy format",
+                path,
+                data.len()
+            )
+        })?;
+        debug!(target: "fdc", "Opened {} as {:?}", path, media_type);
+        let mut media = FloppyMedia::new(media_type, data);
+        match mode {
+            MountMode::ReadOnly => media.write_protected = true,
+            MountMode::WriteBack => media.image_path = Some(PathBuf::from(path)),
+        }
+        Ok(media)
+    }
+
+    /// Saves a sector to the image file, if there is one and the sector
+    /// is one the format has room for. A failure is logged rather than
+    /// shown to the machine, which has already seen the write succeed.
+    pub(super) fn write_back(&self, track: u8, head: u8, id: [u8; 4], data: &[u8]) {
+        let path = match &self.image_path {
+            Some(path) => path,
+            None => return,
+        };
+        let media_type = self.media_type;
+        if track >= media_type.tracks()
+            || head >= media_type.heads()
+            || id[2] == 0
+            || id[2] > media_type.sectors()
+            || data.len() != 512
+        {
+            warn!(
+                target: "fdc",
+                "{}: sector {:?} doesn't fit in a raw image",
+                path.display(),
+                id
+            );
+            return;
+        }
+        let lba = (track as usize * media_type.heads() as usize + head as usize)
+            * media_type.sectors() as usize
+            + (id[2] as usize - 1);
+        let result = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start(lba as u64 * 512))?;
+                file.write_all(data)
+            });
+        if let Err(err) = result {
+            warn!(target: "fdc", "Couldn't write back to {}: {}", path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::hardware::floppy::{DataRate, DriveType, FloppyDrive, FloppyError};
+#[cfg(test)]
+use std::fs;
+
+#[test]
+fn test_raw_image_geometry_and_write_back() {
+    assert_eq!(
+        MediaType::from_image_size(368_640),
+        Some(MediaType::Media360K)
+    );
+    assert_eq!(
+        MediaType::from_image_size(163_840),
+        Some(MediaType::Media160K)
+    );
+    assert_eq!(
+        MediaType::from_image_size(2_949_120),
+        Some(MediaType::Media2880K)
+    );
+    assert_eq!(MediaType::from_image_size(1_000_000), None);
+
+    let path = std::env::temp_dir().join("emupc-raw-image-test.img");
+    let path = path.to_str().unwrap();
+    let mut data = vec![0; 1_474_560];
+    data[18 * 2 * 512 + 512] = 0x5a; // track 1, head 0, sector 2
+    fs::write(path, &data).unwrap();
+
+    let mut drive = FloppyDrive::new(DriveType::Drive1440K);
+    drive
+        .insert(FloppyMedia::open(path, MountMode::ReadOnly).unwrap())
+        .unwrap();
+    drive.step(true);
+    assert_eq!(
+        drive.read_sector(0, 1, 2, DataRate::Rate500K).unwrap()[0],
+        0x5a
+    );
+    assert_eq!(
+        drive.write_sector(0, 1, 2, DataRate::Rate500K, &[0xa5; 512]),
+        Err(FloppyError::WriteProtected)
+    );
+
+    drive
+        .insert(FloppyMedia::open(path, MountMode::WriteBack).unwrap())
+        .unwrap();
+    drive
+        .write_sector(1, 1, 18, DataRate::Rate500K, &[0xa5; 512])
+        .unwrap();
+    let saved = fs::read(path).unwrap();
+    fs::remove_file(path).unwrap();
+    assert_eq!(saved[(18 * 3 + 17) * 512], 0xa5);
+    assert_eq!(saved[18 * 2 * 512 + 512], 0x5a);
+}
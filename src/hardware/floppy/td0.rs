@@ -0,0 +1,384 @@
+// TeleDisk (.td0) images. A 12 byte header, an optional comment, then each
+// track's header and its sectors' headers and data, which can be stored as
+// repeated patterns. Images made with "advanced compression" start with
+// "td" instead of "TD" and have everything after the header packed with
+// LZHUF.
+use crate::hardware::floppy::{normalize_rate, DataRate, FloppyMedia, ImageReader, Sector, Track};
+
+/// Sector header flags.
+const SECTOR_DATA_ERROR: u8 = 0x02;
+const SECTOR_DELETED: u8 = 0x04;
+/// Skipped because DOS had it down as unused, or an ID with no data.
+const SECTOR_NO_DATA: u8 = 0x30;
+
+/// Set in the stepping byte when a comment block follows the header.
+const COMMENT_PRESENT: u8 = 0x80;
+
+pub fn parse(data: &[u8]) -> Result<FloppyMedia, String> {
+    let mut reader = ImageReader::new(data);
+    let header = reader.bytes(12)?;
+    let body = match &header[..2] {
+        b"td" => lzhuf::decompress(&data[12..]),
+        _ => data[12..].to_vec(),
+    };
+    // Bit 7 of the rate is FM, which leaves the rate the same.
+    let rate = match header[5] & 0x03 {
+        0 => DataRate::Rate250K,
+        1 => DataRate::Rate300K,
+        _ => DataRate::Rate500K,
+    };
+    let mut reader = ImageReader::new(&body);
+    if (header[7] & COMMENT_PRESENT) != 0 {
+        reader.bytes(2)?;
+        let length = reader.u16_le()? as usize;
+        reader.bytes(6 + length)?;
+    }
+    let mut tracks = vec![];
+    loop {
+        let count = reader.u8()?;
+        if count == 0xff {
+            break;
+        }
+        let cylinder = reader.u8()?;
+        let head = reader.u8()? & 1;
+        reader.u8()?;
+        let mut track = Track::new(normalize_rate(rate));
+        for _ in 0..count {
+            let header = reader.bytes(6)?;
+            let mut sector = Sector::new([header[0], header[1], header[2], header[3]], vec![]);
+            let flags = header[4];
+            sector.data_error = (flags & SECTOR_DATA_ERROR) != 0;
+            sector.deleted = (flags & SECTOR_DELETED) != 0;
+            if (flags & SECTOR_NO_DATA) != 0 || header[3] > 6 {
+                sector.no_data = true;
+            } else {
+                let length = reader.u16_le()? as usize;
+                let block = reader.bytes(length)?;
+                sector.data = decode_sector(block, 128 << header[3])?;
+            }
+            track.sectors.push(sector);
+        }
+        tracks.push(((cylinder, head), track));
+    }
+    Ok(FloppyMedia::from_tracks(tracks))
+}
+
+/// Unpacks a sector's data block: stored as is, as a repeated pair of
+/// bytes, or as runs of literals and repeated patterns.
+fn decode_sector(block: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let (&method, block) = block.split_first().ok_or("empty sector data block")?;
+    let mut reader = ImageReader::new(block);
+    let mut data = vec![];
+    match method {
+        0 => data.extend_from_slice(reader.bytes(size)?),
+        1 => {
+            while data.len() < size {
+                let count = reader.u16_le()? as usize;
+                let pattern = reader.bytes(2)?;
+                for _ in 0..count {
+                    data.extend_from_slice(pattern);
+                }
+            }
+        }
+        2 => {
+            while data.len() < size {
+                match reader.u8()? {
+                    0 => {
+                        let length = reader.u8()? as usize;
+                        data.extend_from_slice(reader.bytes(length)?);
+                    }
+                    kind => {
+                        let count = reader.u8()? as usize;
+                        let pattern = reader.bytes(1 << kind)?;
+                        for _ in 0..count {
+                            data.extend_from_slice(pattern);
+                        }
+                    }
+                }
+            }
+        }
+        _ => return Err(format!("unknown sector encoding {}", method)),
+    }
+    data.resize(size, 0);
+    Ok(data)
+}
+
+/// Okumura's LZHUF: LZSS over a 4K window, with the literals and match
+/// lengths coded by an adaptive Huffman tree and match positions by a
+/// fixed code for their top six bits.
+mod lzhuf {
+    const WINDOW: usize = 4096;
+    const LOOKAHEAD: usize = 60;
+    const THRESHOLD: usize = 2;
+    const CHARACTERS: usize = 256 - THRESHOLD + LOOKAHEAD;
+    const TABLE_SIZE: usize = CHARACTERS * 2 - 1;
+    const ROOT: usize = TABLE_SIZE - 1;
+    const MAX_FREQUENCY: u16 = 0x8000;
+
+    /// How the first byte of a position codes its top six bits, and how
+    /// many bits long that code is.
+    fn position_code(byte: usize) -> (usize, usize) {
+        match byte {
+            0x00..=0x1f => (0, 3),
+            0x20..=0x4f => ((byte - 0x20) / 16 + 1, 4),
+            0x50..=0x8f => ((byte - 0x50) / 8 + 4, 5),
+            0x90..=0xbf => ((byte - 0x90) / 4 + 12, 6),
+            0xc0..=0xef => ((byte - 0xc0) / 2 + 24, 7),
+            _ => (byte - 0xf0 + 48, 8),
+        }
+    }
+
+    struct Bits<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl Bits<'_> {
+        /// Past the end, the input reads as zeroes.
+        fn bit(&mut self) -> usize {
+            let byte = self.data.get(self.position / 8).copied().unwrap_or(0);
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            self.position += 1;
+            bit as usize
+        }
+
+        fn bits(&mut self, count: usize) -> usize {
+            (0..count).fold(0, |value, _| value << 1 | self.bit())
+        }
+
+        fn exhausted(&self) -> bool {
+            self.position >= self.data.len() * 8
+        }
+    }
+
+    struct Tree {
+        frequency: Vec<u16>,
+        parent: Vec<usize>,
+        son: Vec<usize>,
+    }
+
+    impl Tree {
+        fn new() -> Tree {
+            let mut tree = Tree {
+                frequency: vec![0; TABLE_SIZE + 1],
+                parent: vec![0; TABLE_SIZE + CHARACTERS],
+                son: vec![0; TABLE_SIZE],
+            };
+            for i in 0..CHARACTERS {
+                tree.frequency[i] = 1;
+                tree.son[i] = i + TABLE_SIZE;
+                tree.parent[i + TABLE_SIZE] = i;
+            }
+            let mut i = 0;
+            for j in CHARACTERS..TABLE_SIZE {
+                tree.frequency[j] = tree.frequency[i] + tree.frequency[i + 1];
+                tree.son[j] = i;
+                tree.parent[i] = j;
+                tree.parent[i + 1] = j;
+                i += 2;
+            }
+            tree.frequency[TABLE_SIZE] = 0xffff;
+            tree.parent[ROOT] = 0;
+            tree
+        }
+
+        /// Halves the frequencies and rebuilds the tree once the root's
+        /// count gets too big.
+        fn rebuild(&mut self) {
+            let mut j = 0;
+            for i in 0..TABLE_SIZE {
+                if self.son[i] >= TABLE_SIZE {
+                    self.frequency[j] = self.frequency[i].div_ceil(2);
+                    self.son[j] = self.son[i];
+                    j += 1;
+                }
+            }
+            let mut i = 0;
+            for j in CHARACTERS..TABLE_SIZE {
+                let frequency = self.frequency[i] + self.frequency[i + 1];
+                let mut k = j;
+                while k > 0 && frequency < self.frequency[k - 1] {
+                    k -= 1;
+                }
+                self.frequency.copy_within(k..j, k + 1);
+                self.frequency[k] = frequency;
+                self.son.copy_within(k..j, k + 1);
+                self.son[k] = i;
+                i += 2;
+            }
+            for i in 0..TABLE_SIZE {
+                let k = self.son[i];
+                self.parent[k] = i;
+                if k < TABLE_SIZE {
+                    self.parent[k + 1] = i;
+                }
+            }
+        }
+
+        fn update(&mut self, character: usize) {
+            if self.frequency[ROOT] == MAX_FREQUENCY {
+                self.rebuild();
+            }
+            let mut c = self.parent[character + TABLE_SIZE];
+            loop {
+                self.frequency[c] += 1;
+                let k = self.frequency[c];
+                let mut l = c + 1;
+                if k > self.frequency[l] {
+                    while k > self.frequency[l + 1] {
+                        l += 1;
+                    }
+                    self.frequency[c] = self.frequency[l];
+                    self.frequency[l] = k;
+                    let i = self.son[c];
+                    self.parent[i] = l;
+                    if i < TABLE_SIZE {
+                        self.parent[i + 1] = l;
+                    }
+                    let j = self.son[l];
+                    self.son[l] = i;
+                    self.parent[j] = c;
+                    if j < TABLE_SIZE {
+                        self.parent[j + 1] = c;
+                    }
+                    self.son[c] = j;
+                    c = l;
+                }
+                c = self.parent[c];
+                if c == 0 {
+                    break;
+                }
+            }
+        }
+
+        fn decode_character(&mut self, bits: &mut Bits) -> usize {
+            let mut c = self.son[ROOT];
+            while c < TABLE_SIZE {
+                c = self.son[c + bits.bit()];
+            }
+            let character = c - TABLE_SIZE;
+            self.update(character);
+            character
+        }
+    }
+
+    fn decode_position(bits: &mut Bits) -> usize {
+        let byte = bits.bits(8);
+        let (high, length) = position_code(byte);
+        let low = (byte << (length - 2) | bits.bits(length - 2)) & 0x3f;
+        high << 6 | low
+    }
+
+    #[cfg(test)]
+    pub enum Token {
+        Literal(u8),
+        /// How far back and how long.
+        Match(usize, usize),
+    }
+
+    /// Packs `tokens` the way the decompressor expects, for tests.
+    #[cfg(test)]
+    pub fn compress(tokens: &[Token]) -> Vec<u8> {
+        let mut tree = Tree::new();
+        let mut bits = vec![];
+        for token in tokens {
+            let character = match *token {
+                Token::Literal(byte) => byte as usize,
+                Token::Match(_, length) => length + 255 - THRESHOLD,
+            };
+            let mut code = vec![];
+            let mut k = tree.parent[character + TABLE_SIZE];
+            while k != ROOT {
+                let parent = tree.parent[k];
+                code.push(k != tree.son[parent]);
+                k = parent;
+            }
+            bits.extend(code.iter().rev());
+            tree.update(character);
+            if let Token::Match(distance, _) = *token {
+                let position = distance - 1;
+                let (byte, extra, length) = (0..256)
+                    .flat_map(|byte| {
+                        let (high, length) = position_code(byte);
+                        (0..1 << (length - 2)).map(move |extra| (byte, extra, length, high))
+                    })
+                    .find(|&(byte, extra, length, high)| {
+                        high << 6 | ((byte << (length - 2) | extra) & 0x3f) == position
+                    })
+                    .map(|(byte, extra, length, _)| (byte, extra, length))
+                    .unwrap();
+                bits.extend((0..8).rev().map(|i| (byte >> i) & 1 != 0));
+                bits.extend((0..length - 2).rev().map(|i| (extra >> i) & 1 != 0));
+            }
+        }
+        bits.chunks(8)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0, |byte, (i, &bit)| byte | (bit as u8) << (7 - i))
+            })
+            .collect()
+    }
+
+    pub fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut bits = Bits { data, position: 0 };
+        let mut tree = Tree::new();
+        let mut window = [b' '; WINDOW];
+        let mut r = WINDOW - LOOKAHEAD;
+        let mut output = vec![];
+        while !bits.exhausted() {
+            let c = tree.decode_character(&mut bits);
+            if c < 256 {
+                output.push(c as u8);
+                window[r] = c as u8;
+                r = (r + 1) % WINDOW;
+                continue;
+            }
+            let start = (r + WINDOW - decode_position(&mut bits) - 1) % WINDOW;
+            for k in 0..c - 255 + THRESHOLD {
+                let byte = window[(start + k) % WINDOW];
+                output.push(byte);
+                window[r] = byte;
+                r = (r + 1) % WINDOW;
+            }
+        }
+        output
+    }
+}
+
+#[test]
+fn test_td0_sectors() {
+    let mut image = b"TD\x00\x00\x15\x02\x00\x80\x00\x01\x00\x00".to_vec();
+    image.extend_from_slice(&[0, 0, 3, 0, 90, 1, 1, 0, 0, 0]);
+    image.extend_from_slice(b"abc");
+    // Cylinder 2, head 0: sector 1 as runs of a literal and a repeated
+    // word, sector 2 as a repeated pair and sector 3 with no data.
+    image.extend_from_slice(&[3, 2, 0, 0]);
+    image.extend_from_slice(&[2, 0, 1, 1, 0, 0]);
+    image.extend_from_slice(&[9, 0, 2, 0, 2, 0xaa, 0xbb, 1, 255, 0x12, 0x34]);
+    image.extend_from_slice(&[2, 0, 2, 1, SECTOR_DELETED, 0]);
+    image.extend_from_slice(&[5, 0, 1, 128, 0, 0x5a, 0xa5]);
+    image.extend_from_slice(&[2, 0, 3, 1, 0x10, 0]);
+    image.push(0xff);
+
+    let media = parse(&image).unwrap();
+    let track = media.track(2, 0).unwrap();
+    assert_eq!(track.rate, DataRate::Rate500K);
+    let sector = &track.sectors[0];
+    assert_eq!(&sector.data[..4], &[0xaa, 0xbb, 0x12, 0x34]);
+    assert_eq!(sector.data.len(), 256);
+    assert_eq!(&track.sectors[1].data[254..], &[0x5a, 0xa5]);
+    assert!(track.sectors[1].deleted);
+    assert!(track.sectors[2].no_data);
+
+    // Advanced compression, with two literals then a match overlapping
+    // itself and one reaching back into the window's initial spaces.
+    let packed = lzhuf::compress(&[
+        lzhuf::Token::Literal(b'a'),
+        lzhuf::Token::Literal(b'b'),
+        lzhuf::Token::Match(2, 4),
+        lzhuf::Token::Match(100, 3),
+    ]);
+    assert!(lzhuf::decompress(&packed).starts_with(b"ababab   "));
+}
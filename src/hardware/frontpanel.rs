@@ -0,0 +1,108 @@
+use log::debug;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PanelEvent {
+    PowerLed(bool),
+    TurboLed(bool),
+    HddLed(bool),
+    Turbo(bool),
+    KeyLock(bool),
+}
+
+/// The lights, buttons and key lock on the front of the case. Every change
+/// is queued as an event so a frontend can redraw only when something
+/// actually happened.
+#[derive(Debug, Clone, Default)]
+pub struct FrontPanel {
+    pub power_led: bool,
+    pub turbo_led: bool,
+    pub hdd_led: bool,
+    pub turbo: bool,
+    pub key_locked: bool,
+    events: Vec<PanelEvent>,
+}
+
+impl FrontPanel {
+    pub fn new() -> FrontPanel {
+        let mut panel = FrontPanel::default();
+        panel.set_power(true);
+        panel
+    }
+
+    pub fn set_power(&mut self, on: bool) {
+        if self.power_led != on {
+            self.power_led = on;
+            self.events.push(PanelEvent::PowerLed(on));
+        }
+    }
+
+    pub fn set_hdd_activity(&mut self, active: bool) {
+        if self.hdd_led != active {
+            self.hdd_led = active;
+            self.events.push(PanelEvent::HddLed(active));
+        }
+    }
+
+    /// The turbo button latches, and the turbo LED follows it.
+    pub fn press_turbo(&mut self) {
+        self.turbo = !self.turbo;
+        debug!("Turbo {}", if self.turbo { "on" } else { "off" });
+        self.events.push(PanelEvent::Turbo(self.turbo));
+        self.turbo_led = self.turbo;
+        self.events.push(PanelEvent::TurboLed(self.turbo_led));
+    }
+
+    pub fn set_key_lock(&mut self, locked: bool) {
+        if self.key_locked != locked {
+            self.key_locked = locked;
+            self.events.push(PanelEvent::KeyLock(locked));
+        }
+    }
+
+    /// The key lock is wired to the keyboard controller's inhibit input,
+    /// which the 8042 firmware checks before passing on keystrokes.
+    pub fn keyboard_inhibited(&self) -> bool {
+        self.key_locked
+    }
+
+    /// Takes all events queued since the last call.
+    pub fn take_events(&mut self) -> Vec<PanelEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// A one-line text rendering of the panel, for status bars and the
+/// console frontend.
+impl fmt::Display for FrontPanel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let led = |on: bool| if on { '*' } else { '.' };
+        write!(
+            f,
+            "PWR {} TURBO {} HDD {} KEY {}",
+            led(self.power_led),
+            led(self.turbo_led),
+            led(self.hdd_led),
+            if self.key_locked { "locked" } else { "open" }
+        )
+    }
+}
+
+#[test]
+fn test_front_panel_events() {
+    let mut panel = FrontPanel::new();
+    assert_eq!(panel.take_events(), vec![PanelEvent::PowerLed(true)]);
+    panel.press_turbo();
+    panel.set_key_lock(true);
+    panel.set_key_lock(true);
+    assert_eq!(
+        panel.take_events(),
+        vec![
+            PanelEvent::Turbo(true),
+            PanelEvent::TurboLed(true),
+            PanelEvent::KeyLock(true),
+        ]
+    );
+    assert!(panel.keyboard_inhibited());
+    assert_eq!(panel.to_string(), "PWR * TURBO * HDD . KEY locked");
+}
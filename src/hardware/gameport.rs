@@ -0,0 +1,141 @@
+// The game port at 201h. Writing anything fires four one-shots, one per
+// joystick axis, each timed by the resistance of its potentiometer; games
+// count how long each bit of the port stays high to find the stick's
+// position. The top four bits are the buttons, low when pressed. An axis
+// with no stick plugged in never times out.
+use crate::hardware::reset::{Reset, ResetKind};
+use crate::input::joystick::VirtualJoystick;
+use crate::input::InputEvent;
+
+/// The one-shots are timed in microseconds.
+pub const GAME_PORT_CLOCK_HZ: u32 = 1_000_000;
+
+/// How long a one-shot lasts: 24.2 µs, plus 11 µs for each kΩ of the
+/// 0-100 kΩ potentiometer. Axis values run 0-255 over its travel.
+const BASE_TIME: u32 = 24;
+const FULL_SCALE_TIME: u32 = 1100;
+
+#[derive(Debug, Clone)]
+pub struct GamePort {
+    /// Sticks A and B, each with an X and Y axis from 0 to 255.
+    pub axes: [u8; 4],
+    /// Buttons 1 and 2 of stick A, then of stick B.
+    pub buttons: u8,
+    pub connected: [bool; 2],
+    /// Keys that work the sticks, for a host without a joystick.
+    pub virtual_joystick: Option<VirtualJoystick>,
+    /// Microseconds until each one-shot times out.
+    timers: [Option<u32>; 4],
+}
+
+impl GamePort {
+    pub fn new() -> GamePort {
+        GamePort {
+            axes: [128; 4],
+            buttons: 0,
+            connected: [true, false],
+            virtual_joystick: None,
+            timers: [None; 4],
+        }
+    }
+
+    /// Host input: joystick events, or keys for the virtual joystick.
+    pub fn input(&mut self, event: InputEvent) {
+        if let Some(joystick) = &mut self.virtual_joystick {
+            for event in joystick.translate(event) {
+                self.joystick(event);
+            }
+        }
+        self.joystick(event);
+    }
+
+    fn joystick(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::JoystickAxis { stick, axis, value } if stick < 2 && axis < 2 => {
+                self.connected[stick as usize] = true;
+                self.axes[(stick * 2 + axis) as usize] = value;
+            }
+            InputEvent::JoystickButton { button, pressed } if button < 4 => match pressed {
+                true => self.buttons |= 1 << button,
+                false => self.buttons &= !(1 << button),
+            },
+            _ => {}
+        }
+    }
+
+    pub fn rb(&mut self, _addr: u16) -> u8 {
+        let timing = self
+            .timers
+            .iter()
+            .enumerate()
+            .filter(|(_, timer)| timer.is_some())
+            .fold(0, |bits, (axis, _)| bits | 1 << axis);
+        (!self.buttons << 4) | timing
+    }
+
+    pub fn wb(&mut self, _addr: u16, _data: u8) {
+        for (axis, timer) in self.timers.iter_mut().enumerate() {
+            *timer = match self.connected[axis / 2] {
+                true => Some(BASE_TIME + self.axes[axis] as u32 * FULL_SCALE_TIME / 255),
+                false => Some(u32::MAX),
+            };
+        }
+    }
+
+    /// Runs the one-shots for `ticks` microseconds.
+    pub fn tick(&mut self, ticks: usize) {
+        for timer in self.timers.iter_mut() {
+            if let Some(remaining) = timer {
+                *timer = remaining.checked_sub(ticks as u32).filter(|&left| left > 0);
+            }
+        }
+    }
+}
+
+impl Default for GamePort {
+    fn default() -> GamePort {
+        GamePort::new()
+    }
+}
+
+// The sticks and buttons are what the player is holding, so only the
+// one-shots reset.
+impl Reset for GamePort {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind != ResetKind::Warm {
+            self.timers = [None; 4];
+        }
+    }
+}
+
+#[test]
+fn test_game_port_timing() {
+    let mut port = GamePort::new();
+    port.input(InputEvent::JoystickAxis {
+        stick: 0,
+        axis: 0,
+        value: 0,
+    });
+    port.input(InputEvent::JoystickAxis {
+        stick: 0,
+        axis: 1,
+        value: 255,
+    });
+    port.input(InputEvent::JoystickButton {
+        button: 1,
+        pressed: true,
+    });
+    assert_eq!(port.rb(0x201), 0xd0);
+
+    // X all the way left times out first; stick B isn't plugged in.
+    port.wb(0x201, 0);
+    assert_eq!(port.rb(0x201), 0xdf);
+    port.tick(BASE_TIME as usize);
+    assert_eq!(port.rb(0x201), 0xde);
+    port.tick(FULL_SCALE_TIME as usize - 1);
+    assert_eq!(port.rb(0x201), 0xde);
+    port.tick(1);
+    assert_eq!(port.rb(0x201), 0xdc);
+    port.tick(1_000_000);
+    assert_eq!(port.rb(0x201), 0xdc);
+}
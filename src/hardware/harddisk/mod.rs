@@ -0,0 +1,306 @@
+// Hard disk images, shared by the controllers. A raw image is every sector
+// on the disk in order, by cylinder then head, like a raw floppy image;
+// VHDs are read too. In snapshot mode writes go to an overlay file rather
+// than the image, until they're committed or discarded.
+use crate::hardware::harddisk::overlay::Overlay;
+use crate::hardware::rtc::RTC;
+use log::{debug, warn};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub mod overlay;
+pub mod vhd;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// The cylinders and heads of types 1 to 14 in the AT BIOS's fixed disk
+/// table. They all have 17 sectors per track.
+const AT_DRIVE_TYPES: [(u16, u8); 14] = [
+    (306, 4),
+    (615, 4),
+    (615, 6),
+    (940, 8),
+    (940, 6),
+    (615, 4),
+    (462, 8),
+    (733, 5),
+    (900, 15),
+    (820, 3),
+    (855, 5),
+    (855, 7),
+    (306, 8),
+    (733, 7),
+];
+
+/// The type AMI's setup program keeps a drive's geometry in CMOS for.
+pub const USER_DRIVE_TYPE: u8 = 47;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Geometry {
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+impl Geometry {
+    pub fn total_sectors(&self) -> u64 {
+        self.cylinders as u64 * self.heads as u64 * self.sectors as u64
+    }
+
+    /// The geometry of a disk `size` bytes long: a type from the BIOS
+    /// table if one is exactly that size, otherwise 16 heads of 63
+    /// sectors, as large as fits.
+    pub fn from_image_size(size: usize) -> Option<Geometry> {
+        let sectors = (size / SECTOR_SIZE) as u64;
+        let table = AT_DRIVE_TYPES
+            .iter()
+            .map(|&(cylinders, heads)| Geometry {
+                cylinders,
+                heads,
+                sectors: 17,
+            })
+            .find(|geometry| geometry.total_sectors() == sectors);
+        let cylinders = (sectors / (16 * 63)).min(u16::MAX as u64) as u16;
+        match (table, cylinders) {
+            (Some(geometry), _) => Some(geometry),
+            (None, 0) => None,
+            (None, cylinders) => Some(Geometry {
+                cylinders,
+                heads: 16,
+                sectors: 63,
+            }),
+        }
+    }
+
+    /// The drive type the BIOS knows this geometry as, which for anything
+    /// not in its table is the user-defined one.
+    pub fn cmos_type(&self) -> u8 {
+        AT_DRIVE_TYPES
+            .iter()
+            .position(|&(cylinders, heads)| {
+                cylinders == self.cylinders && heads == self.heads && self.sectors == 17
+            })
+            .map_or(USER_DRIVE_TYPE, |index| index as u8 + 1)
+    }
+}
+
+/// Stores the types of drives C and D in CMOS as the setup program would,
+/// with the geometry of a user-defined type in AMI's slots at 1Bh and 24h.
+pub fn set_cmos_types(rtc: &mut RTC, disks: [Option<Geometry>; 2]) {
+    let types = disks.map(|disk| disk.map_or(0, |geometry| geometry.cmos_type()));
+    // Types 15 and up don't fit in a nibble and go in 19h and 1Ah instead.
+    let nibble = |disk_type: u8| if disk_type < 15 { disk_type } else { 0xf };
+    rtc.ram[0x12] = nibble(types[0]) << 4 | nibble(types[1]);
+    for (drive, geometry) in disks.iter().enumerate() {
+        rtc.ram[0x19 + drive] = if types[drive] < 15 { 0 } else { types[drive] };
+        let slot = &mut rtc.ram[0x1b + drive * 9..][..9];
+        match geometry {
+            Some(geometry) if types[drive] == USER_DRIVE_TYPE => {
+                let cylinders = geometry.cylinders.to_le_bytes();
+                // No write precompensation, and the heads land on the
+                // last cylinder.
+                slot.copy_from_slice(&[
+                    cylinders[0],
+                    cylinders[1],
+                    geometry.heads,
+                    0xff,
+                    0xff,
+                    if geometry.heads > 8 { 0x08 } else { 0x00 },
+                    cylinders[0],
+                    cylinders[1],
+                    geometry.sectors,
+                ]);
+            }
+            _ => slot.fill(0),
+        }
+    }
+    rtc.update_checksum();
+}
+
+/// Where writes to a disk are saved.
+#[derive(Debug, Clone)]
+enum Image {
+    /// Nowhere; the disk only lives in memory.
+    Memory,
+    /// A file holding the sectors in order from its start: a raw image, or
+    /// a fixed VHD with its footer after them.
+    Flat(PathBuf),
+    DynamicVhd(vhd::DynamicVhd),
+}
+
+impl Image {
+    fn write_sector(&mut self, lba: u64, data: &[u8]) -> Result<(), String> {
+        let (path, result) = match self {
+            Image::Memory => return Ok(()),
+            Image::Flat(path) => (&*path, write_at(path, lba * SECTOR_SIZE as u64, data)),
+            Image::DynamicVhd(vhd) => {
+                let result = vhd.write_sector(lba, data);
+                (&vhd.path, result)
+            }
+        };
+        result.map_err(|err| format!("{}: {}", path.display(), err))
+    }
+}
+
+/// A disk's sectors, kept in memory and saved to the image file they came
+/// from as they're written.
+#[derive(Debug, Clone)]
+pub struct HardDisk {
+    pub geometry: Geometry,
+    data: Vec<u8>,
+    image: Image,
+    /// Where writes go instead of the image in snapshot mode.
+    overlay: Option<Overlay>,
+}
+
+impl HardDisk {
+    /// A blank disk with no image behind it.
+    pub fn new(geometry: Geometry) -> HardDisk {
+        HardDisk {
+            geometry,
+            data: vec![0; geometry.total_sectors() as usize * SECTOR_SIZE],
+            image: Image::Memory,
+            overlay: None,
+        }
+    }
+
+    /// Opens a raw image, or a VHD if it has a VHD footer.
+    pub fn open(path: &str) -> Result<HardDisk, String> {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        if vhd::is_vhd(&data) {
+            return vhd::open(path, &data).map_err(|err| format!("{}: {}", path, err));
+        }
+        let geometry = Geometry::from_image_size(data.len())
+            .ok_or_else(|| format!("{}: too small for a hard disk image", path))?;
+        debug!(target: "disk", "Opened {} as {:?}", path, geometry);
+        Ok(HardDisk {
+            geometry,
+            data,
+            image: Image::Flat(PathBuf::from(path)),
+            overlay: None,
+        })
+    }
+
+    /// The number of sectors LBA addressing reaches, which can be a few
+    /// more than the geometry covers.
+    pub fn total_sectors(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    pub fn read_sector(&self, lba: u64) -> Option<&[u8]> {
+        let start = (lba as usize).checked_mul(SECTOR_SIZE)?;
+        self.data.get(start..start + SECTOR_SIZE)
+    }
+
+    /// Returns false if there's no such sector. A failure to save it is
+    /// logged rather than shown to the machine.
+    pub fn write_sector(&mut self, lba: u64, data: &[u8]) -> bool {
+        let start = lba as usize * SECTOR_SIZE;
+        let sector = match self.data.get_mut(start..start + SECTOR_SIZE) {
+            Some(sector) => sector,
+            None => return false,
+        };
+        let data = &data[..SECTOR_SIZE];
+        let result = match &mut self.overlay {
+            Some(overlay) => overlay
+                .write_sector(lba, sector, data)
+                .map_err(|err| format!("{}: {}", overlay.path.display(), err)),
+            None => self.image.write_sector(lba, data),
+        };
+        sector.copy_from_slice(data);
+        if let Err(err) = result {
+            warn!(target: "disk", "Couldn't save sector {}: {}", lba, err);
+        }
+        true
+    }
+
+    /// Puts the disk in snapshot mode, with an overlay at `path` taking
+    /// the writes and the image left as it is.
+    pub fn start_snapshot(&mut self, path: &str) -> Result<(), String> {
+        if self.overlay.is_some() {
+            return Err("the disk is already in snapshot mode".to_string());
+        }
+        let overlay = Overlay::create(path).map_err(|err| format!("{}: {}", path, err))?;
+        debug!(target: "disk", "Writes go to the overlay {} from now on", path);
+        self.overlay = Some(overlay);
+        Ok(())
+    }
+
+    pub fn in_snapshot(&self) -> bool {
+        self.overlay.is_some()
+    }
+
+    /// Saves the sectors written in snapshot mode to the image, and goes
+    /// back to writing them straight there. If that fails, the disk stays
+    /// in snapshot mode so nothing is lost.
+    pub fn commit_snapshot(&mut self) -> Result<(), String> {
+        let overlay = self
+            .overlay
+            .take()
+            .ok_or("the disk isn't in snapshot mode")?;
+        let (image, data) = (&mut self.image, &self.data);
+        let result = overlay.written().try_for_each(|lba| {
+            let start = lba as usize * SECTOR_SIZE;
+            image.write_sector(lba, &data[start..start + SECTOR_SIZE])
+        });
+        if let Err(err) = result {
+            self.overlay = Some(overlay);
+            return Err(err);
+        }
+        debug!(target: "disk", "Committed {} sectors", overlay.written().count());
+        remove_overlay(&overlay);
+        Ok(())
+    }
+
+    /// Throws away the sectors written in snapshot mode, putting back what
+    /// they held, and goes back to writing to the image.
+    pub fn discard_snapshot(&mut self) -> Result<(), String> {
+        let overlay = self
+            .overlay
+            .take()
+            .ok_or("the disk isn't in snapshot mode")?;
+        for (lba, original) in overlay.originals() {
+            let start = lba as usize * SECTOR_SIZE;
+            self.data[start..start + SECTOR_SIZE].copy_from_slice(original);
+        }
+        debug!(target: "disk", "Discarded {} sectors", overlay.written().count());
+        remove_overlay(&overlay);
+        Ok(())
+    }
+}
+
+/// The overlay has served its purpose by the time it's removed, so failing
+/// to is only worth a warning.
+fn remove_overlay(overlay: &Overlay) {
+    if let Err(err) = overlay.remove() {
+        warn!(target: "disk", "Couldn't remove {}: {}", overlay.path.display(), err);
+    }
+}
+
+/// Writes `data` into the file at `offset`.
+fn write_at(path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
+}
+
+#[test]
+fn test_geometry_and_cmos_types() {
+    let type2 = Geometry::from_image_size(615 * 4 * 17 * 512).unwrap();
+    assert_eq!(type2.cmos_type(), 2);
+    let big = Geometry::from_image_size(100 * 1024 * 1024).unwrap();
+    assert_eq!((big.cylinders, big.heads, big.sectors), (203, 16, 63));
+    assert_eq!(big.cmos_type(), USER_DRIVE_TYPE);
+    assert_eq!(Geometry::from_image_size(4096), None);
+
+    let mut rtc = RTC::new();
+    set_cmos_types(&mut rtc, [Some(big), Some(type2)]);
+    assert_eq!(rtc.ram[0x12], 0xf2);
+    assert_eq!(&rtc.ram[0x19..0x1b], &[47, 0]);
+    assert_eq!(&rtc.ram[0x1b..0x1e], &[203, 0, 16]);
+    assert_eq!(rtc.ram[0x23], 63);
+    assert_eq!(rtc.ram[0x24], 0);
+    let checksum: u16 = rtc.ram[0x10..0x2e].iter().map(|&byte| byte as u16).sum();
+    assert_eq!(rtc.ram[0x2e..0x30], checksum.to_be_bytes());
+}
@@ -0,0 +1,105 @@
+// Copy-on-write overlays, for running a disk in snapshot mode. While one is
+// active the image the disk came from is left alone: each sector written
+// goes to the overlay file instead, as a record of its LBA (eight bytes,
+// little endian) then its data, and a sector written twice reuses its
+// record. What the sectors held before is kept too, so the session can be
+// thrown away as well as committed.
+use crate::hardware::harddisk::SECTOR_SIZE;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+const RECORD_SIZE: u64 = 8 + SECTOR_SIZE as u64;
+
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub path: PathBuf,
+    /// The sectors written so far: where each one's record is, and what it
+    /// held before the session.
+    sectors: BTreeMap<u64, (u64, Vec<u8>)>,
+}
+
+impl Overlay {
+    /// Starts an empty overlay, replacing any an earlier session left.
+    pub fn create(path: &str) -> io::Result<Overlay> {
+        File::create(path)?;
+        Ok(Overlay {
+            path: PathBuf::from(path),
+            sectors: BTreeMap::new(),
+        })
+    }
+
+    /// Saves `data` as sector `lba`, which held `original` before. Only the
+    /// first write to a sector keeps what it held.
+    pub fn write_sector(&mut self, lba: u64, original: &[u8], data: &[u8]) -> io::Result<()> {
+        let next = self.sectors.len() as u64;
+        let (record, _) = self
+            .sectors
+            .entry(lba)
+            .or_insert_with(|| (next, original.to_vec()));
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.seek(SeekFrom::Start(*record * RECORD_SIZE))?;
+        file.write_all(&lba.to_le_bytes())?;
+        file.write_all(data)
+    }
+
+    /// The sectors written during the session, in order.
+    pub fn written(&self) -> impl Iterator<Item = u64> + '_ {
+        self.sectors.keys().copied()
+    }
+
+    /// The written sectors and what they held before the session.
+    pub fn originals(&self) -> impl Iterator<Item = (u64, &[u8])> + '_ {
+        self.sectors
+            .iter()
+            .map(|(&lba, (_, original))| (lba, original.as_slice()))
+    }
+
+    /// Deletes the overlay file, once the session is over.
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+#[cfg(test)]
+use crate::hardware::harddisk::HardDisk;
+
+#[test]
+fn test_snapshot_commit_and_discard() {
+    let dir = std::env::temp_dir();
+    let image = dir.join("emupc-snapshot-test.img");
+    let image = image.to_str().unwrap();
+    let overlay = format!("{}.snapshot", image);
+    fs::write(image, vec![0x11; 615 * 4 * 17 * SECTOR_SIZE]).unwrap();
+    let mut disk = HardDisk::open(image).unwrap();
+
+    // Written twice, the sector keeps one record and its first contents.
+    disk.start_snapshot(&overlay).unwrap();
+    assert!(disk.start_snapshot(&overlay).is_err());
+    disk.write_sector(5, &[0x22; 512]);
+    disk.write_sector(5, &[0x33; 512]);
+    disk.write_sector(9, &[0x44; 512]);
+    assert_eq!(disk.read_sector(5).unwrap()[0], 0x33);
+    let saved = fs::read(&overlay).unwrap();
+    assert_eq!(saved.len() as u64, 2 * RECORD_SIZE);
+    assert_eq!(&saved[..8], &5u64.to_le_bytes());
+    assert_eq!(saved[8], 0x33);
+    assert_eq!(fs::read(image).unwrap()[5 * SECTOR_SIZE], 0x11);
+
+    disk.discard_snapshot().unwrap();
+    assert!(!disk.in_snapshot());
+    assert!(fs::metadata(&overlay).is_err());
+    assert_eq!(disk.read_sector(5).unwrap()[0], 0x11);
+    assert_eq!(disk.read_sector(9).unwrap()[0], 0x11);
+
+    disk.start_snapshot(&overlay).unwrap();
+    disk.write_sector(9, &[0x55; 512]);
+    disk.commit_snapshot().unwrap();
+    assert!(disk.commit_snapshot().is_err());
+    assert!(fs::metadata(&overlay).is_err());
+    let saved = fs::read(image).unwrap();
+    fs::remove_file(image).unwrap();
+    assert_eq!(saved[9 * SECTOR_SIZE], 0x55);
+    assert_eq!(saved[5 * SECTOR_SIZE], 0x11);
+}
@@ -0,0 +1,300 @@
+// Virtual PC's VHD images, as 86Box, VirtualBox and Hyper-V write them. A
+// fixed VHD is a raw image with a 512 byte footer after it. A dynamic one
+// keeps a copy of the footer at the front, then a header pointing at a
+// block allocation table (BAT), and only stores the blocks that have been
+// written, each behind a bitmap of which of its sectors hold data. All the
+// fields are big endian.
+use crate::hardware::harddisk::{Geometry, HardDisk, Image, SECTOR_SIZE};
+use log::{debug, warn};
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+const FOOTER_COOKIE: &[u8] = b"conectix";
+const HEADER_COOKIE: &[u8] = b"cxsparse";
+const FOOTER_SIZE: usize = 512;
+const HEADER_SIZE: usize = 1024;
+
+const DISK_FIXED: u32 = 2;
+const DISK_DYNAMIC: u32 = 3;
+const DISK_DIFFERENCING: u32 = 4;
+
+/// A BAT entry for a block that isn't in the file.
+const UNALLOCATED: u32 = 0xffff_ffff;
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    (u32_at(data, offset) as u64) << 32 | u32_at(data, offset + 4) as u64
+}
+
+/// The ones' complement of the sum of the bytes, leaving out the checksum
+/// field at `field`.
+fn checksum(data: &[u8], field: usize) -> u32 {
+    let sum = data
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(field..field + 4).contains(i))
+        .fold(0u32, |sum, (_, &byte)| sum.wrapping_add(byte as u32));
+    !sum
+}
+
+/// Whether `data` is a VHD: the footer is at the end, and for dynamic
+/// disks at the start too.
+pub fn is_vhd(data: &[u8]) -> bool {
+    data.len() >= FOOTER_SIZE
+        && (data[data.len() - FOOTER_SIZE..].starts_with(FOOTER_COOKIE)
+            || data.starts_with(FOOTER_COOKIE))
+}
+
+/// What's needed to add blocks to a dynamic VHD as they're written.
+#[derive(Debug, Clone)]
+pub struct DynamicVhd {
+    pub path: PathBuf,
+    bat: Vec<u32>,
+    bat_offset: u64,
+    block_size: usize,
+    footer: Vec<u8>,
+    /// Where the footer at the end of the file starts, which is where the
+    /// next block goes.
+    end: u64,
+}
+
+impl DynamicVhd {
+    fn sectors_per_block(&self) -> usize {
+        self.block_size / SECTOR_SIZE
+    }
+
+    /// The bitmap in front of each block, padded out to whole sectors.
+    fn bitmap_size(&self) -> usize {
+        self.sectors_per_block()
+            .div_ceil(8)
+            .next_multiple_of(SECTOR_SIZE)
+    }
+
+    /// Writes a sector, adding its block to the end of the file first if
+    /// it isn't there yet, and marks it in the block's bitmap.
+    pub fn write_sector(&mut self, lba: u64, data: &[u8]) -> io::Result<()> {
+        let block = lba as usize / self.sectors_per_block();
+        let index = lba as usize % self.sectors_per_block();
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        if self.bat[block] == UNALLOCATED {
+            let offset = self.end;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&vec![0; self.bitmap_size() + self.block_size])?;
+            file.write_all(&self.footer)?;
+            self.bat[block] = (offset / SECTOR_SIZE as u64) as u32;
+            file.seek(SeekFrom::Start(self.bat_offset + block as u64 * 4))?;
+            file.write_all(&self.bat[block].to_be_bytes())?;
+            self.end = offset + (self.bitmap_size() + self.block_size) as u64;
+            debug!(target: "disk", "Allocated VHD block {} at {:#x}", block, offset);
+        }
+        let start = self.bat[block] as u64 * SECTOR_SIZE as u64;
+        file.seek(SeekFrom::Start(
+            start + (self.bitmap_size() + index * SECTOR_SIZE) as u64,
+        ))?;
+        file.write_all(data)?;
+        let bitmap_byte = start + (index / 8) as u64;
+        let mut byte = [0];
+        file.seek(SeekFrom::Start(bitmap_byte))?;
+        file.read_exact(&mut byte)?;
+        byte[0] |= 0x80 >> (index % 8);
+        file.seek(SeekFrom::Start(bitmap_byte))?;
+        file.write_all(&byte)
+    }
+}
+
+/// Loads a fixed or dynamic VHD. Differencing disks, which need their
+/// parent, aren't supported.
+pub fn open(path: &str, data: &[u8]) -> Result<HardDisk, String> {
+    let trailing = &data[data.len() - FOOTER_SIZE..];
+    let footer = if trailing.starts_with(FOOTER_COOKIE) {
+        trailing
+    } else {
+        &data[..FOOTER_SIZE]
+    };
+    if checksum(footer, 64) != u32_at(footer, 64) {
+        warn!(target: "disk", "{}: the VHD footer's checksum is wrong", path);
+    }
+    let size = u64_at(footer, 48) as usize;
+    let geometry = Geometry {
+        cylinders: u16::from_be_bytes([footer[56], footer[57]]),
+        heads: footer[58],
+        sectors: footer[59],
+    };
+    let geometry = match geometry.total_sectors() {
+        0 => Geometry::from_image_size(size).ok_or("too small for a hard disk image")?,
+        _ => geometry,
+    };
+    let (sectors, image) = match u32_at(footer, 60) {
+        DISK_FIXED => {
+            let sectors = data.get(..size).ok_or("the VHD is cut short")?.to_vec();
+            (sectors, Image::Flat(PathBuf::from(path)))
+        }
+        DISK_DYNAMIC => {
+            let (sectors, vhd) = open_dynamic(path, data, footer, size)?;
+            (sectors, Image::DynamicVhd(vhd))
+        }
+        DISK_DIFFERENCING => return Err("differencing VHDs aren't supported".to_string()),
+        disk_type => return Err(format!("unknown VHD disk type {}", disk_type)),
+    };
+    debug!(target: "disk", "Opened {} as a VHD, {:?}", path, geometry);
+    Ok(HardDisk {
+        geometry,
+        data: sectors,
+        image,
+        overlay: None,
+    })
+}
+
+fn open_dynamic(
+    path: &str,
+    data: &[u8],
+    footer: &[u8],
+    size: usize,
+) -> Result<(Vec<u8>, DynamicVhd), String> {
+    let header_offset = u64_at(footer, 16) as usize;
+    let header = data
+        .get(header_offset..header_offset + HEADER_SIZE)
+        .filter(|header| header.starts_with(HEADER_COOKIE))
+        .ok_or("no dynamic disk header")?;
+    let bat_offset = u64_at(header, 16) as usize;
+    let entries = u32_at(header, 28) as usize;
+    let block_size = u32_at(header, 32) as usize;
+    if block_size == 0 || !block_size.is_multiple_of(SECTOR_SIZE) {
+        return Err(format!("bad VHD block size {}", block_size));
+    }
+    let bat: Vec<u32> = data
+        .get(bat_offset..bat_offset + entries * 4)
+        .ok_or("the block allocation table is cut short")?
+        .chunks(4)
+        .map(|entry| u32_at(entry, 0))
+        .collect();
+    let vhd = DynamicVhd {
+        path: PathBuf::from(path),
+        bat,
+        bat_offset: bat_offset as u64,
+        block_size,
+        footer: footer.to_vec(),
+        end: (data.len() - FOOTER_SIZE) as u64,
+    };
+    if vhd.bat.len() * block_size < size {
+        return Err("the block allocation table doesn't cover the disk".to_string());
+    }
+    // Sectors whose bitmap bit is clear were never written, and read as
+    // zeroes.
+    let mut sectors = vec![0; size];
+    for (block, &entry) in vhd.bat.iter().enumerate() {
+        if entry == UNALLOCATED {
+            continue;
+        }
+        let start = entry as usize * SECTOR_SIZE;
+        let bitmap = data
+            .get(start..start + vhd.bitmap_size())
+            .ok_or("a block is past the end of the file")?;
+        for index in 0..vhd.sectors_per_block() {
+            let offset = block * block_size + index * SECTOR_SIZE;
+            if offset >= size || (bitmap[index / 8] & (0x80 >> (index % 8))) == 0 {
+                continue;
+            }
+            let from = start + vhd.bitmap_size() + index * SECTOR_SIZE;
+            let sector = data
+                .get(from..from + SECTOR_SIZE)
+                .ok_or("a block is past the end of the file")?;
+            sectors[offset..offset + SECTOR_SIZE].copy_from_slice(sector);
+        }
+    }
+    Ok((sectors, vhd))
+}
+
+/// A footer for a disk of `geometry`, for tests.
+#[cfg(test)]
+fn footer(geometry: Geometry, disk_type: u32, data_offset: u64) -> Vec<u8> {
+    let mut footer = vec![0; FOOTER_SIZE];
+    footer[..8].copy_from_slice(FOOTER_COOKIE);
+    footer[12..16].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+    footer[16..24].copy_from_slice(&data_offset.to_be_bytes());
+    let size = geometry.total_sectors() * SECTOR_SIZE as u64;
+    footer[40..48].copy_from_slice(&size.to_be_bytes());
+    footer[48..56].copy_from_slice(&size.to_be_bytes());
+    footer[56..58].copy_from_slice(&geometry.cylinders.to_be_bytes());
+    footer[58] = geometry.heads;
+    footer[59] = geometry.sectors;
+    footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+    let sum = checksum(&footer, 64);
+    footer[64..68].copy_from_slice(&sum.to_be_bytes());
+    footer
+}
+
+#[cfg(test)]
+use std::fs;
+
+#[test]
+fn test_vhd_images() {
+    let geometry = Geometry {
+        cylinders: 4,
+        heads: 2,
+        sectors: 16,
+    };
+    let dir = std::env::temp_dir();
+
+    // Fixed: the footer stays where it is when a sector is written.
+    let path = dir.join("emupc-fixed-test.vhd");
+    let path = path.to_str().unwrap();
+    let mut image = vec![0x11; 128 * SECTOR_SIZE];
+    image.extend(footer(geometry, DISK_FIXED, u64::MAX));
+    fs::write(path, &image).unwrap();
+    let mut disk = HardDisk::open(path).unwrap();
+    assert_eq!(disk.geometry, geometry);
+    assert_eq!(disk.total_sectors(), 128);
+    disk.write_sector(127, &[0x22; 512]);
+    let saved = fs::read(path).unwrap();
+    fs::remove_file(path).unwrap();
+    assert_eq!(saved.len(), image.len());
+    assert_eq!(saved[127 * SECTOR_SIZE], 0x22);
+    assert!(saved[128 * SECTOR_SIZE..].starts_with(FOOTER_COOKIE));
+
+    // Dynamic, with 4K blocks and only the first one there. Its sector 0
+    // isn't marked in the bitmap, so it reads as zeroes.
+    let path = dir.join("emupc-dynamic-test.vhd");
+    let path = path.to_str().unwrap();
+    let footer = footer(geometry, DISK_DYNAMIC, 512);
+    let mut header = vec![0; HEADER_SIZE];
+    header[..8].copy_from_slice(HEADER_COOKIE);
+    header[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+    header[16..24].copy_from_slice(&1536u64.to_be_bytes());
+    header[28..32].copy_from_slice(&16u32.to_be_bytes());
+    header[32..36].copy_from_slice(&4096u32.to_be_bytes());
+    let mut bat = vec![0xff; 512];
+    bat[..4].copy_from_slice(&4u32.to_be_bytes());
+    let mut bitmap = vec![0; 512];
+    bitmap[0] = 0x40;
+    let mut image = [footer.clone(), header, bat, bitmap].concat();
+    image.extend(vec![0x99; SECTOR_SIZE]);
+    image.extend(vec![0x42; SECTOR_SIZE]);
+    image.extend(vec![0; 6 * SECTOR_SIZE]);
+    image.extend(&footer);
+    fs::write(path, &image).unwrap();
+
+    let mut disk = HardDisk::open(path).unwrap();
+    assert_eq!(disk.read_sector(0).unwrap()[0], 0x00);
+    assert_eq!(disk.read_sector(1).unwrap()[0], 0x42);
+    assert_eq!(disk.read_sector(20).unwrap()[0], 0x00);
+    disk.write_sector(20, &[0x33; 512]);
+    let saved = fs::read(path).unwrap();
+    assert_eq!(saved.len(), image.len() + 512 + 4096);
+    assert!(saved[saved.len() - FOOTER_SIZE..].starts_with(FOOTER_COOKIE));
+
+    let disk = HardDisk::open(path).unwrap();
+    fs::remove_file(path).unwrap();
+    assert_eq!(disk.read_sector(20).unwrap()[0], 0x33);
+    assert_eq!(disk.read_sector(21).unwrap()[0], 0x00);
+    assert_eq!(disk.read_sector(1).unwrap()[0], 0x42);
+}
@@ -0,0 +1,582 @@
+use crate::hardware::dma::DmaController;
+use crate::hardware::harddisk::{Geometry, HardDisk, SECTOR_SIZE};
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace, warn};
+use std::fs;
+
+/// The DMA channel and IRQ the XT's fixed disk adapter is wired to.
+pub const HDC_DMA_CHANNEL: usize = 3;
+pub const HDC_IRQ: u8 = 5;
+
+/// Where the adapter's ROM sits, and how big it is.
+pub const HDC_ROM_BASE: u32 = 0xc_8000;
+const HDC_ROM_SIZE: usize = 0x2000;
+
+/// Cylinders and heads of the four drives the IBM controller's ROM knows,
+/// picked by the jumpers read at 322h. They all have 17 sectors per track.
+const XT_DRIVE_TYPES: [(u16, u8); 4] = [(306, 2), (375, 8), (306, 6), (306, 4)];
+const SECTORS: u8 = 17;
+
+/// Status register (321h) bits.
+const STATUS_REQUEST: u8 = 0x01;
+const STATUS_INPUT: u8 = 0x02;
+const STATUS_COMMAND: u8 = 0x04;
+const STATUS_BUSY: u8 = 0x08;
+const STATUS_INTERRUPT: u8 = 0x20;
+
+/// DMA and interrupt mask register (323h) bits.
+const MASK_DMA: u8 = 0x01;
+const MASK_IRQ: u8 = 0x02;
+
+/// Error codes for REQUEST SENSE.
+const ERROR_NOT_READY: u8 = 0x04;
+const ERROR_SECTOR_NOT_FOUND: u8 = 0x14;
+const ERROR_INVALID_COMMAND: u8 = 0x20;
+const ERROR_ILLEGAL_ADDRESS: u8 = 0x21;
+
+/// The completion status byte's error bit.
+const COMPLETION_ERROR: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    /// Taking the six byte command block.
+    Command,
+    /// Taking bytes from the host without DMA, for INITIALIZE DRIVE
+    /// CHARACTERISTICS.
+    DataIn,
+    /// Handing bytes to the host without DMA, for REQUEST SENSE.
+    DataOut,
+    /// Moving sectors over DMA.
+    Dma,
+    /// Handing back the completion status byte.
+    Status,
+}
+
+/// A drive on the adapter, and the geometry INITIALIZE DRIVE
+/// CHARACTERISTICS gave the controller for it.
+#[derive(Debug, Clone)]
+pub struct XtDrive {
+    pub disk: HardDisk,
+    /// The entry in the ROM's table the jumpers select.
+    drive_type: u8,
+    cylinders: u16,
+    heads: u8,
+}
+
+/// A READ, WRITE or sector buffer transfer under way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Transfer {
+    write: bool,
+    /// The sector, or `None` for the sector buffer commands.
+    lba: Option<u64>,
+    remaining: u32,
+}
+
+/// The XT's fixed disk adapter at 320h-323h: a Xebec (or Western Digital
+/// WD1002) controller for two ST-506 drives. The host selects it, hands it
+/// a six byte command block, moves any data over DMA channel 3, then reads
+/// a completion status byte after IRQ 5. Its ROM at C8000h holds the
+/// INT 13h code, so the BIOS needs no help to boot from it.
+#[derive(Debug, Clone)]
+pub struct HDC {
+    pub drives: [Option<XtDrive>; 2],
+    pub rom: Vec<u8>,
+    mask: u8,
+    phase: Phase,
+    command: Vec<u8>,
+    /// Sector data, or the bytes of a PIO phase.
+    buffer: Vec<u8>,
+    position: usize,
+    /// The last command's status byte and sense bytes.
+    completion: u8,
+    sense: [u8; 4],
+    transfer: Option<Transfer>,
+    irq: bool,
+    /// Set whenever a sector is read or written, for an activity light.
+    /// Whoever shows the light clears it.
+    pub activity: bool,
+}
+
+impl HDC {
+    /// Loads the controller's ROM from `path`. Without it the BIOS never
+    /// hears about the drives.
+    pub fn new(path: &str) -> HDC {
+        let mut rom = fs::read(path).unwrap_or_else(|err| {
+            warn!("Couldn't load the fixed disk adapter ROM {}: {}", path, err);
+            vec![]
+        });
+        rom.resize(HDC_ROM_SIZE, 0xff);
+        HDC {
+            drives: [None, None],
+            rom,
+            mask: 0,
+            phase: Phase::Idle,
+            command: vec![],
+            buffer: vec![],
+            position: 0,
+            completion: 0,
+            sense: [0; 4],
+            transfer: None,
+            irq: false,
+            activity: false,
+        }
+    }
+
+    /// Puts a disk in as drive 0 or 1, setting the jumpers to the ROM's
+    /// drive type of the same size.
+    pub fn attach(&mut self, drive: usize, mut disk: HardDisk) -> Result<(), String> {
+        let sectors = disk.total_sectors();
+        let drive_type = XT_DRIVE_TYPES
+            .iter()
+            .position(|&(cylinders, heads)| {
+                cylinders as u64 * heads as u64 * SECTORS as u64 == sectors
+            })
+            .ok_or_else(|| {
+                format!(
+                    "{} sectors isn't the size of any drive the XT controller knows",
+                    sectors
+                )
+            })?;
+        let (cylinders, heads) = XT_DRIVE_TYPES[drive_type];
+        disk.geometry = Geometry {
+            cylinders,
+            heads,
+            sectors: SECTORS,
+        };
+        self.drives[drive] = Some(XtDrive {
+            disk,
+            drive_type: drive_type as u8,
+            cylinders,
+            heads,
+        });
+        Ok(())
+    }
+
+    /// IRQ 5, if the mask register lets it through.
+    pub fn irq(&self) -> bool {
+        self.irq && (self.mask & MASK_IRQ) != 0
+    }
+
+    pub fn read_rom(&self, addr: u32) -> u8 {
+        self.rom[(addr - HDC_ROM_BASE) as usize % HDC_ROM_SIZE]
+    }
+
+    /// The drive type jumpers: drive 0 in bits 2-3, drive 1 in bits 0-1.
+    fn switches(&self) -> u8 {
+        let drive_type =
+            |drive: &Option<XtDrive>| drive.as_ref().map_or(0, |drive| drive.drive_type);
+        drive_type(&self.drives[0]) << 2 | drive_type(&self.drives[1])
+    }
+
+    fn status(&self) -> u8 {
+        let phase = match self.phase {
+            Phase::Idle => 0,
+            Phase::Command => STATUS_BUSY | STATUS_REQUEST | STATUS_COMMAND,
+            Phase::DataIn => STATUS_BUSY | STATUS_REQUEST,
+            Phase::DataOut => STATUS_BUSY | STATUS_REQUEST | STATUS_INPUT,
+            Phase::Dma => STATUS_BUSY,
+            Phase::Status => STATUS_BUSY | STATUS_REQUEST | STATUS_INPUT | STATUS_COMMAND,
+        };
+        phase | ((self.irq as u8) * STATUS_INTERRUPT)
+    }
+
+    fn drive_number(&self) -> usize {
+        ((self.command[1] >> 5) & 1) as usize
+    }
+
+    /// The sector the command block points at, checked against the drive.
+    fn address(&self) -> Result<u64, u8> {
+        let drive = self.drives[self.drive_number()]
+            .as_ref()
+            .ok_or(ERROR_NOT_READY)?;
+        let head = self.command[1] & 0x1f;
+        let sector = self.command[2] & 0x3f;
+        let cylinder = ((self.command[2] & 0xc0) as u16) << 2 | self.command[3] as u16;
+        if head >= drive.heads || sector >= SECTORS || cylinder >= drive.cylinders {
+            return Err(ERROR_ILLEGAL_ADDRESS);
+        }
+        Ok((cylinder as u64 * drive.heads as u64 + head as u64) * SECTORS as u64 + sector as u64)
+    }
+
+    /// Moves the command block's address on a sector, so sense bytes
+    /// point at the right one.
+    fn advance(&mut self) {
+        let heads = match &self.drives[self.drive_number()] {
+            Some(drive) => drive.heads,
+            None => return,
+        };
+        let mut head = self.command[1] & 0x1f;
+        let mut sector = (self.command[2] & 0x3f) + 1;
+        let mut cylinder = ((self.command[2] & 0xc0) as u16) << 2 | self.command[3] as u16;
+        if sector == SECTORS {
+            sector = 0;
+            head += 1;
+            if head == heads {
+                head = 0;
+                cylinder += 1;
+            }
+        }
+        self.command[1] = (self.command[1] & 0xe0) | head;
+        self.command[2] = ((cylinder >> 2) as u8 & 0xc0) | sector;
+        self.command[3] = cylinder as u8;
+    }
+
+    /// Ends the command, with `error` as the sense code if it failed.
+    fn complete(&mut self, error: Option<u8>) {
+        let drive = self.drive_number() as u8;
+        self.completion = drive << 5;
+        self.sense = [
+            0,
+            drive << 5 | (self.command[1] & 0x1f),
+            self.command[2],
+            self.command[3],
+        ];
+        if let Some(code) = error {
+            debug!(target: "disk", "XT controller error {:#04x}", code);
+            self.completion |= COMPLETION_ERROR;
+            self.sense[0] = 0x80 | code;
+        }
+        self.transfer = None;
+        self.phase = Phase::Status;
+        self.irq = true;
+    }
+
+    fn execute(&mut self) {
+        trace!(target: "disk", "XT controller command {:02x?}", self.command);
+        let count = match self.command[4] {
+            0 => 256,
+            count => count as u32,
+        };
+        let ready = self.drives[self.drive_number()].is_some();
+        match self.command[0] {
+            // The diagnostics always pass.
+            0xe0 | 0xe3 | 0xe4 => self.complete(None),
+            _ if !ready => self.complete(Some(ERROR_NOT_READY)),
+            // TEST DRIVE READY and RECALIBRATE.
+            0x00 | 0x01 => self.complete(None),
+            0x03 => {
+                self.buffer = self.sense.to_vec();
+                self.position = 0;
+                self.phase = Phase::DataOut;
+            }
+            // FORMAT DRIVE, FORMAT TRACK and FORMAT BAD TRACK clear the
+            // sectors; the interleave means nothing to an image.
+            0x04 | 0x06 | 0x07 => {
+                let result = self.address().map(|lba| {
+                    let drive = self.drives[self.drive_number()].as_mut().unwrap();
+                    let end = match self.command[0] {
+                        0x04 => drive.disk.total_sectors(),
+                        _ => lba - lba % SECTORS as u64 + SECTORS as u64,
+                    };
+                    for lba in lba..end {
+                        drive.disk.write_sector(lba, &[0; SECTOR_SIZE]);
+                    }
+                });
+                self.complete(result.err());
+            }
+            // VERIFY and SEEK only need the address to be there.
+            0x05 | 0x0b => {
+                let result = self.address().map(|_| ());
+                self.complete(result.err());
+            }
+            0x08 | 0x0a => match self.address() {
+                Ok(lba) => self.start_dma(self.command[0] == 0x0a, Some(lba), count),
+                Err(code) => self.complete(Some(code)),
+            },
+            0x0c => {
+                self.buffer.clear();
+                self.phase = Phase::DataIn;
+            }
+            0x0e | 0x0f => self.start_dma(self.command[0] == 0x0f, None, 1),
+            _ => self.complete(Some(ERROR_INVALID_COMMAND)),
+        }
+    }
+
+    fn start_dma(&mut self, write: bool, lba: Option<u64>, count: u32) {
+        self.transfer = Some(Transfer {
+            write,
+            lba,
+            remaining: count,
+        });
+        self.position = 0;
+        if lba.is_some() {
+            self.buffer.clear();
+        }
+        self.phase = Phase::Dma;
+    }
+
+    /// Takes the eight bytes of drive characteristics: the cylinders and
+    /// heads, then write current and precompensation settings that don't
+    /// matter here.
+    fn initialize_drive(&mut self) {
+        let cylinders = u16::from_be_bytes([self.buffer[0], self.buffer[1]]);
+        let heads = self.buffer[2];
+        let number = self.drive_number();
+        if let Some(drive) = self.drives[number].as_mut() {
+            drive.cylinders = cylinders;
+            drive.heads = heads;
+        }
+        self.complete(None);
+    }
+
+    /// Moves sectors over DMA for as long as the DMA controller keeps up.
+    pub fn tick(&mut self, dma: &mut DmaController, memory: &mut [u8]) {
+        if self.phase != Phase::Dma || (self.mask & MASK_DMA) == 0 {
+            return;
+        }
+        let mut transfer = match self.transfer.take() {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        let outcome = match transfer.write {
+            false => self.dma_to_memory(&mut transfer, dma, memory),
+            true => self.dma_from_memory(&mut transfer, dma, memory),
+        };
+        match outcome {
+            Some(error) => {
+                dma.dma_release(HDC_DMA_CHANNEL);
+                self.complete(error);
+            }
+            None => self.transfer = Some(transfer),
+        }
+    }
+
+    /// Returns the error the command ends with once it's over, or `None`
+    /// while waiting on the DMA controller.
+    fn dma_to_memory(
+        &mut self,
+        transfer: &mut Transfer,
+        dma: &mut DmaController,
+        memory: &mut [u8],
+    ) -> Option<Option<u8>> {
+        while transfer.remaining > 0 {
+            if self.position == 0 {
+                if let Some(lba) = transfer.lba {
+                    let drive = self.drives[self.drive_number()].as_ref().unwrap();
+                    self.activity = true;
+                    match drive.disk.read_sector(lba) {
+                        Some(data) => self.buffer = data.to_vec(),
+                        None => return Some(Some(ERROR_SECTOR_NOT_FOUND)),
+                    }
+                }
+                self.buffer.resize(SECTOR_SIZE, 0);
+            }
+            while self.position < SECTOR_SIZE {
+                dma.dma_request(HDC_DMA_CHANNEL);
+                let value = self.buffer[self.position] as u16;
+                dma.dma_write(HDC_DMA_CHANNEL, memory, value)?;
+                self.position += 1;
+            }
+            self.next_sector(transfer);
+        }
+        Some(None)
+    }
+
+    fn dma_from_memory(
+        &mut self,
+        transfer: &mut Transfer,
+        dma: &mut DmaController,
+        memory: &mut [u8],
+    ) -> Option<Option<u8>> {
+        while transfer.remaining > 0 {
+            if self.position == 0 {
+                self.buffer.clear();
+            }
+            while self.position < SECTOR_SIZE {
+                dma.dma_request(HDC_DMA_CHANNEL);
+                let (value, _) = dma.dma_read(HDC_DMA_CHANNEL, memory)?;
+                self.buffer.push(value as u8);
+                self.position += 1;
+            }
+            if let Some(lba) = transfer.lba {
+                let number = self.drive_number();
+                let drive = self.drives[number].as_mut().unwrap();
+                self.activity = true;
+                if !drive.disk.write_sector(lba, &self.buffer) {
+                    return Some(Some(ERROR_SECTOR_NOT_FOUND));
+                }
+            }
+            self.next_sector(transfer);
+        }
+        Some(None)
+    }
+
+    fn next_sector(&mut self, transfer: &mut Transfer) {
+        self.position = 0;
+        transfer.remaining -= 1;
+        if let Some(lba) = &mut transfer.lba {
+            *lba += 1;
+            if transfer.remaining > 0 {
+                self.advance();
+            }
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        match self.phase {
+            Phase::DataOut => {
+                let value = self.buffer.get(self.position).copied().unwrap_or(0xff);
+                self.position += 1;
+                if self.position >= self.buffer.len() {
+                    self.complete(None);
+                    // REQUEST SENSE doesn't interrupt, and leaves the sense
+                    // bytes it reported alone.
+                    self.irq = false;
+                }
+                value
+            }
+            Phase::Status => {
+                self.irq = false;
+                self.phase = Phase::Idle;
+                self.completion
+            }
+            _ => 0xff,
+        }
+    }
+
+    fn write_data(&mut self, data: u8) {
+        match self.phase {
+            Phase::Command => {
+                self.command.push(data);
+                if self.command.len() == 6 {
+                    self.execute();
+                }
+            }
+            Phase::DataIn => {
+                self.buffer.push(data);
+                if self.buffer.len() == 8 {
+                    self.initialize_drive();
+                }
+            }
+            _ => debug!(target: "disk", "XT controller data byte {:#04x} out of phase", data),
+        }
+    }
+
+    fn reset_controller(&mut self) {
+        self.phase = Phase::Idle;
+        self.command.clear();
+        self.buffer.clear();
+        self.position = 0;
+        self.transfer = None;
+        self.irq = false;
+        for drive in self.drives.iter_mut().flatten() {
+            drive.cylinders = drive.disk.geometry.cylinders;
+            drive.heads = drive.disk.geometry.heads;
+        }
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr & 3 {
+            0 => self.read_data(),
+            1 => self.status(),
+            2 => self.switches(),
+            _ => 0xff,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr & 3 {
+            0 => self.write_data(data),
+            1 => self.reset_controller(),
+            2 => {
+                self.command.clear();
+                self.phase = Phase::Command;
+            }
+            _ => self.mask = data,
+        }
+    }
+}
+
+impl Reset for HDC {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        self.mask = 0;
+        self.reset_controller();
+    }
+}
+
+#[test]
+fn test_hdc_command_blocks() {
+    let mut hdc = HDC::new("");
+    let mut disk = HardDisk::new(Geometry {
+        cylinders: 306,
+        heads: 4,
+        sectors: SECTORS,
+    });
+    // Cylinder 300, head 3, sector 16 is the last on the disk.
+    disk.write_sector(306 * 4 * 17 - 1, &[0x77; 512]);
+    hdc.attach(0, disk).unwrap();
+    assert!(hdc
+        .attach(
+            1,
+            HardDisk::new(Geometry::from_image_size(1 << 20).unwrap())
+        )
+        .is_err());
+    assert_eq!(hdc.rb(0x322), 0x0c);
+    assert_eq!(hdc.read_rom(0xc_8000), 0xff);
+
+    let mut dma = DmaController::pc();
+    let mut memory = vec![0; 0x1_0000];
+    let command = |hdc: &mut HDC, bytes: [u8; 6]| {
+        hdc.wb(0x322, 0);
+        for byte in bytes {
+            assert_eq!(hdc.rb(0x321) & 0x0f, 0x0d);
+            hdc.wb(0x320, byte);
+        }
+    };
+    hdc.wb(0x323, MASK_DMA | MASK_IRQ);
+
+    // Read two sectors over channel 3 to 2000h from the last one on the
+    // disk, which runs off the end.
+    dma.wb(0x0b, 0x47);
+    dma.wb(0x0c, 0);
+    dma.wb(0x06, 0x00);
+    dma.wb(0x06, 0x20);
+    dma.wb(0x07, 0xff);
+    dma.wb(0x07, 0x03);
+    dma.wb(0x0a, 0x03);
+    command(&mut hdc, [0x08, 0x03, 0x40 | 16, 0x31, 2, 0x05]);
+    assert_eq!(hdc.rb(0x321), 0x08);
+    hdc.tick(&mut dma, &mut memory);
+    assert!(hdc.irq());
+    assert_eq!(hdc.rb(0x321), 0x2f);
+    assert_eq!(hdc.rb(0x320), COMPLETION_ERROR);
+    assert!(!hdc.irq());
+    assert_eq!(memory[0x2000], 0x77);
+
+    // The sense bytes say where it stopped.
+    command(&mut hdc, [0x03, 0, 0, 0, 0, 0]);
+    assert!(!hdc.irq());
+    let sense: Vec<u8> = (0..4).map(|_| hdc.rb(0x320)).collect();
+    assert_eq!(sense, vec![0x80 | ERROR_SECTOR_NOT_FOUND, 0x00, 0x40, 0x32]);
+    assert_eq!(hdc.rb(0x320), 0x00);
+
+    // Tell the controller the drive has 8 heads, then write a sector on
+    // head 5, which only exists with that geometry.
+    command(&mut hdc, [0x0c, 0, 0, 0, 0, 0]);
+    for byte in [0x01, 0x32, 8, 0, 0, 0, 0, 0] {
+        hdc.wb(0x320, byte);
+    }
+    assert_eq!(hdc.rb(0x320), 0x00);
+    dma.wb(0x0b, 0x4b);
+    dma.wb(0x0c, 0);
+    dma.wb(0x06, 0x00);
+    dma.wb(0x06, 0x20);
+    dma.wb(0x07, 0xff);
+    dma.wb(0x07, 0x01);
+    dma.wb(0x0a, 0x03);
+    command(&mut hdc, [0x0a, 0x05, 0x02, 0x01, 1, 0x05]);
+    hdc.tick(&mut dma, &mut memory);
+    assert_eq!(hdc.rb(0x320), 0x00);
+    let disk = &hdc.drives[0].as_ref().unwrap().disk;
+    assert_eq!(disk.read_sector((8 + 5) * 17 + 2).unwrap()[0], 0x77);
+
+    // Drive 1 isn't there, and 0x20 isn't a command.
+    command(&mut hdc, [0x00, 0x20, 0, 0, 0, 0]);
+    assert_eq!(hdc.rb(0x320), 0x22);
+    command(&mut hdc, [0x20, 0, 0, 0, 0, 0]);
+    assert_eq!(hdc.rb(0x320), COMPLETION_ERROR);
+}
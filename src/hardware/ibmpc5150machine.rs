@@ -1,57 +1,699 @@
 use crate::cpu8086::*;
+use crate::hardware::breakpoints::MemoryWatch;
+use crate::hardware::common::CommonHardware;
+use crate::hardware::device::Device;
+use crate::hardware::dma::DmaController;
+use crate::hardware::ems::{EmsBoard, EmsConfig};
+use crate::hardware::fdc::*;
+use crate::hardware::floppy::*;
+use crate::hardware::harddisk::HardDisk;
+use crate::hardware::hdc::*;
+use crate::hardware::io::{IoBus, PortWatch};
+use crate::hardware::memory::{MemoryBus, MemoryRead};
+use crate::hardware::pic::PIC;
 use crate::hardware::pit::*;
+use crate::hardware::ppi::*;
+use crate::hardware::reset::*;
+use crate::hardware::rom::{self, OptionRom};
+#[cfg(test)]
+use crate::hardware::scheduler::DeviceEvent;
+use crate::hardware::soundblaster::*;
+use crate::hardware::uart::*;
+use crate::hardware::video::cga::CGA;
+use crate::hardware::Motherboard;
+#[cfg(test)]
+use crate::hardware::{RunEvent, StopReason};
+use log::{debug, warn};
 use std::fs;
+use std::mem;
+
+/// The parts of the memory map that adapters answer for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PcMmio {
+    /// A0000h-BFFFFh, shared out among the video adapters.
+    Video,
+    EgaRom,
+    HdcRom,
+    /// The EMS board's page frame.
+    Ems,
+    /// A card from the device list, by its index.
+    Device(usize),
+}
+
+/// Who answers for each I/O port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PcIo {
+    Dma,
+    /// Port 80h, a DMA page register that POST cards listen to as well.
+    PostCard,
+    Pic,
+    Pit,
+    Ppi,
+    NmiMask,
+    /// 3B0h-3DFh, shared out among the video adapters.
+    Video,
+    Fdc,
+    Hdc,
+    Adlib,
+    SoundBlaster,
+    Mpu401,
+    GamePort,
+    Serial(usize),
+    Parallel(usize),
+    Nic,
+    PerfCounter,
+    Ems,
+    Device(usize),
+}
+
+/// A DMA cycle takes four clocks.
+const REFRESH_CYCLES: usize = 4;
 
 #[derive(Clone, Debug, Default)]
 pub struct IbmPc5150Hardware {
-    pub ram: Vec<u8>,
-    pub bios_rom: Vec<u8>,
-    pub pit: PIT,
+    pub memory: MemoryBus<PcMmio>,
+    pub io: IoBus<PcIo>,
+    pub pic: PIC,
+    /// Everything the AT has too, cards included.
+    pub common: CommonHardware,
+    /// CPU cycles lost to refresh since the run loop last asked.
+    pub stolen_cycles: usize,
+    pub ppi: PPI,
+    /// The fixed disk adapter, for machines with a hard disk.
+    pub hdc: Option<HDC>,
+    /// The NMI mask register at A0h, whose bit 7 lets NMIs through to the
+    /// CPU. It comes out of reset masked.
+    pub nmi_enabled: bool,
+    /// Ports the run loop stops on accesses to.
+    pub port_watch: PortWatch,
+    /// Memory the run loop stops on accesses to.
+    pub memory_watch: MemoryWatch,
+    pub reset_controller: ResetController,
 }
 
 impl IbmPc5150Hardware {
     pub fn new() -> IbmPc5150Hardware {
-        IbmPc5150Hardware {
-            ram: vec![0; 0x10000],
-            bios_rom: fs::read("roms/machines/ibmpc/BIOS_5150_24APR81_U33.BIN").unwrap(),
-            pit: PIT::new(),
+        let mut common = CommonHardware::new(
+            Self::CLOCK_RANGE_HZ.0,
+            PIT::new(),
+            DmaController::pc(),
+            Some(FDC::pc([
+                Some(FloppyDrive::new(DriveType::Drive360K)),
+                Some(FloppyDrive::new(DriveType::Drive360K)),
+            ])),
+            UartModel::Ins8250,
+        );
+        common.cga = Some(CGA::new());
+        let mut hardware = IbmPc5150Hardware {
+            memory: {
+                let mut memory = MemoryBus::new(0x10000);
+                memory.map_mmio(0xa_0000, 0xb_ffff, PcMmio::Video);
+                memory.map_mmio(0xc_0000, 0xc_3fff, PcMmio::EgaRom);
+                let bios = fs::read("roms/machines/ibmpc/BIOS_5150_24APR81_U33.BIN")
+                    .unwrap_or_else(|err| {
+                        warn!("Couldn't load the 5150 BIOS ROM: {}", err);
+                        vec![0xff; 0x2000]
+                    });
+                memory.map_rom(0xf_e000, bios);
+                memory
+            },
+            io: IoBus::new(),
+            pic: PIC::new(),
+            common,
+            stolen_cycles: 0,
+            ppi: PPI::new(PpiModel::Pc, DipSwitches::default()),
+            hdc: None,
+            nmi_enabled: false,
+            port_watch: PortWatch::new(),
+            memory_watch: MemoryWatch::new(),
+            reset_controller: ResetController::new(),
+        };
+        hardware.map_io();
+        hardware.common.schedule();
+        hardware
+    }
+}
+
+impl IbmPc5150Hardware {
+    /// Puts a disk on the fixed disk adapter as drive 0 or 1, fitting the
+    /// adapter first if the machine doesn't have one.
+    pub fn attach_hard_disk(&mut self, drive: usize, disk: HardDisk) -> Result<(), String> {
+        if self.hdc.is_none() {
+            self.hdc = Some(HDC::new("roms/hdd/xebec/ibm_xebec_62x0822_1985.bin"));
+            self.memory.map_mmio(0xc_8000, 0xc_9fff, PcMmio::HdcRom);
+            self.map_io();
+        }
+        self.hdc.as_mut().unwrap().attach(drive, disk)
+    }
+
+    /// Follows the PPI's port B into the PIT, and the keyboard into IRQ 1.
+    fn update_ppi(&mut self) {
+        let (gate, data) = (self.ppi.timer2_gate(), self.ppi.speaker_data());
+        self.common.set_speaker(gate, data, &mut self.pic);
+        self.pic.set_irq(1, self.ppi.irq1());
+    }
+
+    /// The fixed disk adapter and the Sound Blaster both come on IRQ 5.
+    /// A real ISA line can't be shared, but either works on its own.
+    fn update_irq5(&mut self) {
+        let hdc = self.hdc.as_ref().is_some_and(HDC::irq);
+        let sb = self
+            .common
+            .sound_blaster
+            .as_ref()
+            .is_some_and(SoundBlaster::irq);
+        self.pic.set_irq(HDC_IRQ, hdc || sb);
+    }
+
+    /// PIT channel 1 asks DMA channel 0 for a refresh cycle every 15 µs,
+    /// and the BIOS sets the channel up to read through memory a row at a
+    /// time. Each one holds the bus for a DMA cycle.
+    fn refresh(&mut self) {
+        for _ in 0..self.common.take_refresh_requests() {
+            self.common.dma.dma_request(0);
+            if self.common.dma.dma_read(0, &self.memory.ram).is_some() {
+                self.stolen_cycles += REFRESH_CYCLES;
+            }
         }
     }
-    pub fn tick(&mut self, cycles: usize) {
-        self.pit.tick(cycles);
+
+    /// Brings the ports, IRQ lines and schedule up to date after a card
+    /// has been fitted or taken out, so a card that's gone stops
+    /// interrupting.
+    pub fn refit(&mut self) {
+        self.map_io();
+        self.update_irq5();
+        self.common.refit(&mut self.pic);
+    }
+
+    /// Gives the fitted devices their ports, afresh. Called when a card is
+    /// fitted or moved, and on reset.
+    pub fn map_io(&mut self) {
+        let io = &mut self.io;
+        io.clear();
+        io.map(0x0000, 0x000f, PcIo::Dma);
+        io.map(0x0080, 0x008f, PcIo::Dma);
+        io.map(0x0080, 0x0080, PcIo::PostCard);
+        io.map(0x0020, 0x0021, PcIo::Pic);
+        io.map(0x0040, 0x0043, PcIo::Pit);
+        io.map(0x0060, 0x0063, PcIo::Ppi);
+        io.map(0x00a0, 0x00a0, PcIo::NmiMask);
+        if self.common.cga.is_some() || self.common.mda.is_some() || self.common.ega.is_some() {
+            io.map(0x03b0, 0x03df, PcIo::Video);
+        }
+        if self.common.fdc.is_some() {
+            io.map(0x03f0, 0x03f5, PcIo::Fdc);
+            io.map(0x03f7, 0x03f7, PcIo::Fdc);
+        }
+        if self.hdc.is_some() {
+            io.map(0x0320, 0x0323, PcIo::Hdc);
+        }
+        if self.common.adlib.is_some() {
+            io.map(0x0388, 0x0389, PcIo::Adlib);
+        }
+        if let Some(sb) = &self.common.sound_blaster {
+            // The card's own FM ports are the OPL2's, if it has one.
+            for addr in sb.base..=sb.base + 0xf {
+                if sb.claims(addr) {
+                    io.map(addr, addr, PcIo::SoundBlaster);
+                } else if sb.fm_port(addr) && self.common.adlib.is_some() {
+                    io.map(addr, addr, PcIo::Adlib);
+                }
+            }
+        }
+        if self.common.mpu401.is_some() {
+            io.map(0x0330, 0x0331, PcIo::Mpu401);
+        }
+        if self.common.game_port.is_some() {
+            io.map(0x0200, 0x0207, PcIo::GamePort);
+        }
+        for (port, uart) in self.common.serial.iter().enumerate() {
+            if let Some(uart) = uart {
+                io.map(uart.base, uart.base + 7, PcIo::Serial(port));
+            }
+        }
+        for (port, lpt) in self.common.parallel.iter().enumerate() {
+            if let Some(lpt) = lpt {
+                io.map(lpt.base, lpt.base + 2, PcIo::Parallel(port));
+            }
+        }
+        if let Some(nic) = &self.common.nic {
+            io.map(nic.base, nic.base + 0x1f, PcIo::Nic);
+        }
+        if let Some(perf_counter) = &self.common.perf_counter {
+            io.map(perf_counter.base, perf_counter.base + 1, PcIo::PerfCounter);
+        }
+        if let Some(ems) = &self.common.ems {
+            io.map(ems.config.base, ems.config.base + 3, PcIo::Ems);
+        }
+        for (index, device) in self.common.devices.iter().enumerate() {
+            for (start, end) in device.io_ranges() {
+                io.map(start, end, PcIo::Device(index));
+            }
+        }
+    }
+
+    /// Puts in another BIOS image, of up to 64K: an XT BIOS, say, or the
+    /// 5150's with the cassette BASIC ROMs below it.
+    pub fn load_bios(&mut self, bios: Vec<u8>) -> Result<(), String> {
+        rom::map_bios(&mut self.memory, bios, &[0x10_0000], 0x1_0000)
+    }
+
+    /// Maps an option ROM for the BIOS to find at POST. Returns where it
+    /// went.
+    pub fn add_option_rom(&mut self, rom: OptionRom, addr: Option<u32>) -> Result<u32, String> {
+        rom.map(&mut self.memory, addr)
+    }
+
+    /// Fits an EMS board, with its page frame where nothing else answers.
+    pub fn attach_ems(&mut self, config: EmsConfig) -> Result<(), String> {
+        let ems = EmsBoard::new(config)?;
+        let (start, end) = ems.frame();
+        if self.common.ems.is_some() || !self.memory.is_free(start, end) {
+            return Err(format!("something already answers at {:05X}h", start));
+        }
+        self.memory.map_mmio(start, end, PcMmio::Ems);
+        self.common.ems = Some(ems);
+        self.map_io();
+        Ok(())
+    }
+
+    pub fn detach_ems(&mut self) {
+        if let Some(ems) = self.common.ems.take() {
+            self.memory.unmap(ems.frame().0);
+            self.map_io();
+        }
+    }
+
+    /// Fits a card the board doesn't have a place of its own for.
+    pub fn add_device(&mut self, device: Box<dyn Device>) {
+        let index = self.common.devices.len();
+        for (start, end) in device.mmio_ranges() {
+            self.memory.map_mmio(start, end, PcMmio::Device(index));
+        }
+        self.common.devices.push(device);
+        self.map_io();
     }
 }
 
-impl<'a> Cpu8086Context for IbmPc5150Hardware {
+impl Motherboard for IbmPc5150Hardware {
+    const MIN_RAM_KB: usize = 16;
+    /// From IBM's own 4.77 MHz up to the turbo XT clones' 10 MHz. The
+    /// 8088's bus is the expansion bus, so cards run at the CPU's clock.
+    const CLOCK_RANGE_HZ: (u32, u32) = (4_772_727, 10_000_000);
+
+    fn tick(&mut self, cycles: usize) {
+        self.common
+            .tick(cycles, &mut self.pic, &mut self.memory.ram);
+        self.refresh();
+        self.ppi.poll();
+        self.pic.set_irq(1, self.ppi.irq1());
+        if let Some(hdc) = &mut self.hdc {
+            hdc.tick(&mut self.common.dma, &mut self.memory.ram);
+        }
+        self.update_irq5();
+    }
+
+    fn set_clock_hz(&mut self, hz: u32) {
+        self.common
+            .set_clock_hz(hz, &mut self.pic, &self.memory.ram);
+    }
+
+    fn resize_ram(&mut self, kb: usize) {
+        self.memory.ram = vec![0; kb * 1024];
+        self.ppi.switches.ram_kb = kb;
+    }
+
+    fn ram_size(&self) -> usize {
+        self.memory.ram.len() / 1024
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.memory.ram
+    }
+
+    fn peek(&mut self, addr: u32) -> u8 {
+        let watch = mem::take(&mut self.memory_watch);
+        let value = Cpu8086Context::mem_read_byte(self, addr);
+        self.memory_watch = watch;
+        value
+    }
+
+    fn reset_controller(&mut self) -> &mut ResetController {
+        &mut self.reset_controller
+    }
+
+    fn port_watch(&mut self) -> Option<&mut PortWatch> {
+        Some(&mut self.port_watch)
+    }
+
+    fn memory_watch(&mut self) -> Option<&mut MemoryWatch> {
+        Some(&mut self.memory_watch)
+    }
+
+    fn acknowledge_interrupt(&mut self) -> Option<u8> {
+        if !self.pic.int_output() {
+            return None;
+        }
+        let line = self.pic.acknowledge();
+        Some(self.pic.vector(line.unwrap_or(7)))
+    }
+
+    fn take_stolen_cycles(&mut self) -> usize {
+        std::mem::take(&mut self.stolen_cycles)
+    }
+
+    /// A parity error or I/O channel check that the PPI has enabled, once
+    /// the mask register lets it through.
+    fn nmi(&self) -> bool {
+        self.nmi_enabled && self.ppi.nmi()
+    }
+}
+
+impl Reset for IbmPc5150Hardware {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            self.memory.ram.iter_mut().for_each(|byte| *byte = 0);
+        }
+        self.map_io();
+        if kind != ResetKind::Warm {
+            self.nmi_enabled = false;
+        }
+        if let Some(hdc) = &mut self.hdc {
+            hdc.reset(kind);
+        }
+        self.pic.reset(kind);
+        self.common.reset(kind);
+        self.common.refit(&mut self.pic);
+        self.ppi.reset(kind);
+        self.update_ppi();
+    }
+}
+
+impl Cpu8086Context for IbmPc5150Hardware {
     fn mem_read_byte(&mut self, addr: u32) -> u8 {
         let actual_addr = addr & 0xf_ffff;
-        match actual_addr {
-            0..=0x1_0000 => self.ram[(actual_addr & 0xffff) as usize],
-            0xf_e000..=0xf_ffff => self.bios_rom[(actual_addr & 0x1fff) as usize],
-            _ => 0xff,
+        let value = match self.memory.read(actual_addr) {
+            MemoryRead::Data(value) => value,
+            MemoryRead::Mmio(PcMmio::Video) => self.common.read_video(actual_addr),
+            MemoryRead::Mmio(PcMmio::EgaRom) => self
+                .common
+                .ega
+                .as_ref()
+                .map_or(0xff, |ega| ega.read_rom(actual_addr)),
+            MemoryRead::Mmio(PcMmio::HdcRom) => self
+                .hdc
+                .as_ref()
+                .map_or(0xff, |hdc| hdc.read_rom(actual_addr)),
+            MemoryRead::Mmio(PcMmio::Ems) => self
+                .common
+                .ems
+                .as_ref()
+                .map_or(0xff, |ems| ems.read_mem(actual_addr)),
+            MemoryRead::Mmio(PcMmio::Device(index)) => {
+                self.common.devices[index].read_mem(actual_addr)
+            }
+        };
+        self.memory_watch.rb(actual_addr, value);
+        value
+    }
+    fn peek_byte(&self, addr: u32) -> u8 {
+        let actual_addr = addr & 0xf_ffff;
+        match self.memory.read(actual_addr) {
+            MemoryRead::Data(value) => value,
+            MemoryRead::Mmio(PcMmio::EgaRom) => self
+                .common
+                .ega
+                .as_ref()
+                .map_or(0xff, |ega| ega.read_rom(actual_addr)),
+            MemoryRead::Mmio(PcMmio::HdcRom) => self
+                .hdc
+                .as_ref()
+                .map_or(0xff, |hdc| hdc.read_rom(actual_addr)),
+            MemoryRead::Mmio(PcMmio::Ems) => self
+                .common
+                .ems
+                .as_ref()
+                .map_or(0xff, |ems| ems.read_mem(actual_addr)),
+            // Reading video memory loads the EGA's latches and can snow
+            // on a CGA, and a card's own memory may do anything.
+            MemoryRead::Mmio(PcMmio::Video) | MemoryRead::Mmio(PcMmio::Device(_)) => 0xff,
         }
     }
     fn mem_write_byte(&mut self, addr: u32, value: u8) {
         let actual_addr = addr & 0xf_ffff;
-        if let 0..=0x0a_0000 = actual_addr {
-            self.ram[(actual_addr & 0xffff) as usize] = value
+        self.memory_watch.wb(actual_addr, value);
+        match self.memory.write(actual_addr, value) {
+            Some(PcMmio::Video) => self.common.write_video(actual_addr, value),
+            Some(PcMmio::Ems) => {
+                if let Some(ems) = &mut self.common.ems {
+                    ems.write_mem(actual_addr, value);
+                }
+            }
+            Some(PcMmio::Device(index)) => self.common.devices[index].write_mem(actual_addr, value),
+            _ => {}
         }
     }
 
     fn io_read_byte(&mut self, addr: u16) -> u8 {
-        match addr {
-            0x0040..=0x0043 => self.pit.rb(addr),
-            _ => {
-                println!("Unimplemented IO read");
+        let value = match self.io.device(addr) {
+            Some(PcIo::Dma) | Some(PcIo::PostCard) => self.common.dma.rb(addr),
+            Some(PcIo::Pic) => self.pic.rb(addr),
+            Some(PcIo::Pit) => self.common.read_pit(addr, &mut self.pic),
+            Some(PcIo::Ppi) => {
+                self.ppi.timer2_out = self.common.timer_2_out(&mut self.pic);
+                self.ppi.rb(addr)
+            }
+            Some(PcIo::NmiMask) => 0xff,
+            Some(PcIo::Video) => self.common.read_video_port(addr),
+            Some(PcIo::Hdc) => {
+                let value = self.hdc.as_mut().unwrap().rb(addr);
+                self.update_irq5();
+                value
+            }
+            Some(PcIo::Adlib) => {
+                self.common.sync_audio(&mut self.pic, &self.memory.ram);
+                self.common.adlib.as_mut().unwrap().rb(addr)
+            }
+            Some(PcIo::SoundBlaster) => {
+                self.common.sync_audio(&mut self.pic, &self.memory.ram);
+                let value = self.common.sound_blaster.as_mut().unwrap().rb(addr);
+                self.update_irq5();
+                value
+            }
+            Some(PcIo::Mpu401) => self.common.mpu401.as_mut().unwrap().rb(addr),
+            Some(PcIo::Serial(port)) => {
+                self.common.sync_serial(&mut self.pic);
+                let value = self.common.serial[port].as_mut().unwrap().rb(addr);
+                self.common.update_serial_irqs(&mut self.pic);
+                value
+            }
+            Some(PcIo::Parallel(port)) => {
+                let value = self.common.parallel[port].as_mut().unwrap().rb(addr);
+                self.common.update_parallel_irq(&mut self.pic);
+                value
+            }
+            Some(PcIo::GamePort) => self.common.read_game_port(addr),
+            Some(PcIo::PerfCounter) => self.common.perf_counter.as_mut().unwrap().rb(addr),
+            Some(PcIo::Ems) => self.common.ems.as_mut().unwrap().rb(addr),
+            Some(PcIo::Nic) => {
+                let value = self.common.nic.as_mut().unwrap().rb(addr);
+                self.common.update_nic_irq(&mut self.pic);
+                value
+            }
+            Some(PcIo::Fdc) => self.common.fdc.as_mut().unwrap().rb(addr),
+            Some(PcIo::Device(index)) => {
+                let value = self.common.devices[index].rb(addr);
+                self.common.update_device_irqs(&mut self.pic);
+                value
+            }
+            None => {
+                debug!(target: "io", "Unimplemented IO read {:#06x}", addr);
                 0xff
             }
-        }
+        };
+        self.port_watch.rb(addr, value);
+        value
     }
 
     fn io_write_byte(&mut self, addr: u16, value: u8) {
-        match addr {
-            0x0040..=0x0043 => self.pit.wb(addr, value),
-            _ => println!("Unimplemented IO write"),
+        self.port_watch.wb(addr, value);
+        match self.io.device(addr) {
+            Some(PcIo::Dma) => self.common.dma.wb(addr, value),
+            Some(PcIo::PostCard) => {
+                self.common.post_card.wb(value);
+                self.common.dma.wb(addr, value);
+            }
+            Some(PcIo::Pic) => self.pic.wb(addr, value),
+            Some(PcIo::Pit) => self.common.write_pit(addr, value, &mut self.pic),
+            Some(PcIo::Ppi) => {
+                self.ppi.wb(addr, value);
+                self.update_ppi();
+            }
+            Some(PcIo::NmiMask) => self.nmi_enabled = (value & 0x80) != 0,
+            Some(PcIo::Video) => self.common.write_video_port(addr, value),
+            Some(PcIo::Hdc) => {
+                self.hdc.as_mut().unwrap().wb(addr, value);
+                self.update_irq5();
+            }
+            Some(PcIo::Adlib) => {
+                self.common.sync_audio(&mut self.pic, &self.memory.ram);
+                self.common.adlib.as_mut().unwrap().wb(addr, value);
+            }
+            Some(PcIo::SoundBlaster) => {
+                self.common.sync_audio(&mut self.pic, &self.memory.ram);
+                self.common.sound_blaster.as_mut().unwrap().wb(addr, value);
+                self.update_irq5();
+            }
+            Some(PcIo::Mpu401) => self.common.mpu401.as_mut().unwrap().wb(addr, value),
+            Some(PcIo::Serial(port)) => {
+                self.common.sync_serial(&mut self.pic);
+                self.common.serial[port].as_mut().unwrap().wb(addr, value);
+                self.common.update_serial_irqs(&mut self.pic);
+                self.common.schedule_serial();
+            }
+            Some(PcIo::Parallel(port)) => {
+                self.common.parallel[port].as_mut().unwrap().wb(addr, value);
+                self.common.update_parallel_irq(&mut self.pic);
+            }
+            Some(PcIo::GamePort) => self.common.write_game_port(addr, value),
+            Some(PcIo::PerfCounter) => self.common.perf_counter.as_mut().unwrap().wb(addr, value),
+            Some(PcIo::Ems) => self.common.ems.as_mut().unwrap().wb(addr, value),
+            Some(PcIo::Nic) => {
+                self.common.nic.as_mut().unwrap().wb(addr, value);
+                self.common.update_nic_irq(&mut self.pic);
+            }
+            Some(PcIo::Fdc) => {
+                let fdc = self.common.fdc.as_mut().unwrap();
+                fdc.wb(addr, value);
+                self.pic.set_irq(FDC_IRQ, fdc.irq());
+                self.common.start_fdc_delay();
+            }
+            Some(PcIo::Device(index)) => {
+                self.common.devices[index].wb(addr, value);
+                self.common.update_device_irqs(&mut self.pic);
+            }
+            None => debug!(
+                target: "io",
+                "Unimplemented IO write {:#06x} <- {:#04x}",
+                addr,
+                value
+            ),
         }
     }
 }
+
+#[test]
+fn test_irq_reaches_the_cpu() {
+    let mut machine = crate::hardware::IbmPc5150Machine::new();
+    for (addr, data) in [(0x20, 0x13), (0x21, 0x08), (0x21, 0x09), (0x21, 0xfe)].iter() {
+        machine.hardware.io_write_byte(*addr, *data);
+    }
+    // IRQ 0 is vector 8, handled at 0000:0200.
+    machine.hardware.memory.ram[0x20..0x24].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+    machine.cpu.regs.seg_regs[1] = 0;
+    machine.cpu.regs.ip = 0x100;
+    machine.cpu.regs.gprs[4] = 0x1000;
+    machine.hardware.memory.ram[0x100..0x102].copy_from_slice(&[0xfb, 0xf4]); // sti; hlt
+    assert_eq!(machine.run_until(RunEvent::Halt), StopReason::Halted);
+    machine.hardware.pic.set_irq(0, true);
+    machine.run_instructions(1);
+    assert!(!machine.cpu.halted);
+    assert_eq!(machine.cpu.regs.ip, 0x200);
+    assert_eq!(machine.hardware.pic.isr, 0x01);
+}
+
+#[test]
+fn test_refresh_through_dma_channel_0() {
+    let mut hardware = IbmPc5150Hardware::new();
+    // What the BIOS does: channel 0 reads 64K over and over, and PIT
+    // channel 1 asks for a cycle every 18 ticks.
+    for (addr, data) in [(0x0b, 0x58), (0x01, 0xff), (0x01, 0xff), (0x0a, 0x00)] {
+        hardware.io_write_byte(addr, data);
+    }
+    hardware.io_write_byte(0x43, 0x54);
+    hardware.io_write_byte(0x41, 18);
+    hardware.tick(4 * 18 * 100);
+    let refreshes = hardware.take_stolen_cycles() / REFRESH_CYCLES;
+    assert!((99..=100).contains(&refreshes));
+    let address = hardware.common.dma.dma1.channels[0].current_address as usize;
+    assert_eq!(address, refreshes);
+    assert_eq!(hardware.take_stolen_cycles(), 0);
+
+    // Masked, the channel leaves the bus alone.
+    hardware.io_write_byte(0x0a, 0x04);
+    hardware.tick(4 * 18 * 10);
+    assert_eq!(hardware.take_stolen_cycles(), 0);
+}
+
+#[test]
+fn test_scheduled_seek_and_serial() {
+    let mut hardware = IbmPc5150Hardware::new();
+    hardware.io_write_byte(0x3f2, 0x1c);
+    // Take the reset interrupt so the seek's interrupt is a fresh edge.
+    for _ in 0..4 {
+        hardware.io_write_byte(0x3f5, 0x08);
+        hardware.io_read_byte(0x3f5);
+        hardware.io_read_byte(0x3f5);
+    }
+    // SPECIFY a 6 ms step rate at 250 kbps, then seek 10 tracks.
+    for byte in [0x03, 0xdf, 0x02, 0x0f, 0x00, 10] {
+        hardware.io_write_byte(0x3f5, byte);
+    }
+    let deadline = hardware
+        .common
+        .scheduler
+        .deadline(DeviceEvent::FdcSeek)
+        .unwrap();
+    assert_eq!(deadline, 60 * 4_772_727 / 1000);
+    hardware.tick(deadline as usize - 1);
+    assert_eq!(hardware.pic.irr & 0x40, 0);
+    hardware.tick(1);
+    assert_eq!(hardware.pic.irr & 0x40, 0x40);
+
+    // COM1 at 9600 baud in loopback sends a byte in a character time.
+    for (addr, data) in [
+        (0x3fb, 0x83),
+        (0x3f8, 12),
+        (0x3f9, 0),
+        (0x3fb, 0x03),
+        (0x3fc, 0x10),
+    ] {
+        hardware.io_write_byte(addr, data);
+    }
+    hardware.io_write_byte(0x3f8, 0x5a);
+    assert_eq!(hardware.io_read_byte(0x3fd) & 0x01, 0);
+    hardware.tick(4_772_727 / 960 * 2);
+    assert_eq!(hardware.io_read_byte(0x3fd) & 0x01, 0x01);
+    assert_eq!(hardware.io_read_byte(0x3f8), 0x5a);
+}
+
+#[test]
+fn test_timer_interrupt_on_schedule() {
+    let mut hardware = IbmPc5150Hardware::new();
+    // Channel 0 in mode 0 with a count of 100: IRQ 0 once it runs out,
+    // at the same clock as the chip ticked one clock at a time.
+    for (addr, data) in [(0x43, 0x30), (0x40, 100), (0x40, 0)] {
+        hardware.io_write_byte(addr, data);
+    }
+    let mut pit = hardware.common.pit;
+    let mut clocks = 0;
+    while !pit.out(0) {
+        pit.tick(1, |_| {});
+        clocks += 1;
+    }
+    let mut cycles = 0;
+    while (hardware.pic.irr & 0x01) == 0 {
+        hardware.tick(1);
+        cycles += 1;
+    }
+    // The PIT runs at a quarter of the 4.77 MHz CPU clock.
+    assert_eq!(cycles, clocks * 4);
+    // Nothing more to wake up for until the counter is written again.
+    assert_eq!(hardware.common.scheduler.deadline(DeviceEvent::Pit), None);
+    assert!(hardware
+        .common
+        .scheduler
+        .deadline(DeviceEvent::Sample)
+        .is_some());
+}
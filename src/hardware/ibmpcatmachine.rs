@@ -1,54 +1,894 @@
 use crate::cpu286::*;
+use crate::hardware::a20::A20Gate;
+use crate::hardware::atapi::AtapiDrive;
+use crate::hardware::breakpoints::MemoryWatch;
+use crate::hardware::cdrom::CdImage;
+use crate::hardware::clock::{isa_bus_divisor, DeviceClock};
+use crate::hardware::common::CommonHardware;
+use crate::hardware::device::Device;
+use crate::hardware::dma::DmaController;
+use crate::hardware::ems::{EmsBoard, EmsConfig};
+use crate::hardware::fdc::*;
+use crate::hardware::floppy::*;
+use crate::hardware::frontpanel::FrontPanel;
+use crate::hardware::harddisk::{self, HardDisk};
+use crate::hardware::ide::*;
+use crate::hardware::io::{IoBus, PortWatch};
+use crate::hardware::kbc::KBC;
+use crate::hardware::memory::{self, MemoryBus, MemoryRead, UpperMemory, UPPER_MEMORY_BLOCK};
+use crate::hardware::pic::DualPIC;
+use crate::hardware::pit::*;
+use crate::hardware::reset::*;
+use crate::hardware::rom::{self, OptionRom};
+use crate::hardware::rtc::*;
+use crate::hardware::soundblaster::*;
+use crate::hardware::systemcontrol::SystemControl;
+use crate::hardware::uart::*;
+use crate::hardware::Motherboard;
+use log::warn;
 use std::fs;
+use std::mem;
+
+/// The parts of the memory map that adapters answer for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AtMmio {
+    /// A0000h-BFFFFh, shared out among the video adapters.
+    Video,
+    EgaRom,
+    /// The EMS board's page frame.
+    Ems,
+    /// A card from the device list, by its index.
+    Device(usize),
+}
+
+/// Who answers for each I/O port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AtIo {
+    Dma,
+    /// Port 80h, a DMA page register that POST cards listen to as well.
+    PostCard,
+    Pic,
+    Pit,
+    Kbc,
+    /// Port 61h.
+    PortB,
+    Rtc,
+    /// Port 92h.
+    PortA,
+    /// 3B0h-3DFh, shared out among the video adapters.
+    Video,
+    Fdc,
+    Ide,
+    SecondaryIde,
+    Adlib,
+    SoundBlaster,
+    Mpu401,
+    GamePort,
+    Serial(usize),
+    Parallel(usize),
+    Nic,
+    PerfCounter,
+    Ems,
+    Device(usize),
+}
+
+/// A refresh cycle holds the bus for five clocks.
+const REFRESH_CYCLES: usize = 5;
 
 #[derive(Clone, Debug, Default)]
 pub struct IbmPcAtHardware {
-    pub ram: Vec<u8>,
-    pub bios_rom: Vec<u8>,
+    pub memory: MemoryBus<AtMmio>,
+    pub io: IoBus<AtIo>,
+    pub pic: DualPIC,
+    /// Everything the PC has too, cards included. The PIT is an 8254, so
+    /// the read-back command works.
+    pub common: CommonHardware,
+    /// CPU cycles lost to refresh since the run loop last asked.
+    pub stolen_cycles: usize,
+    pub front_panel: FrontPanel,
+    pub ide: IDE,
+    /// The second IDE channel, where the CD-ROM drive goes.
+    pub secondary_ide: IDE,
+    pub a20: A20Gate,
+    pub kbc: KBC,
+    /// Ports 61h and 92h.
+    pub system_control: SystemControl,
+    pub rtc: RTC,
+    pub rtc_clock: DeviceClock,
+    /// Ports the run loop stops on accesses to.
+    pub port_watch: PortWatch,
+    /// Memory the run loop stops on accesses to.
+    pub memory_watch: MemoryWatch,
+    pub reset_controller: ResetController,
 }
 
 impl IbmPcAtHardware {
     pub fn new() -> IbmPcAtHardware {
-        IbmPcAtHardware {
-            ram: vec![0; 0xa0000],
-            bios_rom: {
-                let low_rom: Vec<u8> =
-                    fs::read("roms/machines/ibmatami/BIOS_5170_30APR89_U27_AMI_27256.BIN").unwrap();
-                let high_rom: Vec<u8> =
-                    fs::read("roms/machines/ibmatami/BIOS_5170_30APR89_U47_AMI_27256.BIN").unwrap();
+        let mut hardware = IbmPcAtHardware {
+            memory: {
+                let load = |path: &str| {
+                    fs::read(path).unwrap_or_else(|err| {
+                        warn!("Couldn't load the AT BIOS ROM {}: {}", path, err);
+                        vec![0xff; 0x8000]
+                    })
+                };
+                let low_rom = load("roms/machines/ibmatami/BIOS_5170_30APR89_U27_AMI_27256.BIN");
+                let high_rom = load("roms/machines/ibmatami/BIOS_5170_30APR89_U47_AMI_27256.BIN");
 
                 let mut bios: Vec<u8> = vec![0; 0x10000];
 
                 for i in 0..0x8000 {
-                    bios[(i << 1)] = low_rom[i];
+                    bios[i << 1] = low_rom[i];
                     bios[(i << 1) + 1] = high_rom[i];
                 }
-                bios
+                let mut memory = MemoryBus::new(0xa0000);
+                memory.map_mmio(0x0a_0000, 0x0b_ffff, AtMmio::Video);
+                memory.map_mmio(0x0c_0000, 0x0c_3fff, AtMmio::EgaRom);
+                // The BIOS is at the top of the first megabyte for real
+                // mode and at the top of the 16 MB for the reset vector.
+                memory.map_rom(0x0f_0000, bios.clone());
+                memory.map_rom(0xff_0000, bios);
+                memory
             },
+            io: IoBus::new(),
+            pic: DualPIC::new(),
+            common: CommonHardware::new(
+                Self::CLOCK_RANGE_HZ.0,
+                PIT::with_type(PitType::PIT8254),
+                DmaController::at(),
+                Some(FDC::at([
+                    Some(FloppyDrive::new(DriveType::Drive1200K)),
+                    Some(FloppyDrive::new(DriveType::Drive360K)),
+                ])),
+                UartModel::Ns16450,
+            ),
+            stolen_cycles: 0,
+            front_panel: FrontPanel::new(),
+            ide: IDE::new(),
+            secondary_ide: IDE::secondary(),
+            a20: A20Gate::new(),
+            kbc: KBC::new(),
+            system_control: SystemControl::new(),
+            rtc: RTC::new(),
+            rtc_clock: DeviceClock::new(Self::CLOCK_RANGE_HZ.0, RTC_CLOCK_HZ),
+            port_watch: PortWatch::new(),
+            memory_watch: MemoryWatch::new(),
+            reset_controller: ResetController::new(),
+        };
+        hardware.map_io();
+        hardware.common.schedule();
+        hardware
+    }
+}
+
+impl IbmPcAtHardware {
+    /// Puts a disk on the IDE channel as master (0) or slave (1), and
+    /// records its type in CMOS so the BIOS finds it.
+    pub fn attach_hard_disk(&mut self, drive: usize, disk: HardDisk) {
+        self.ide.drives[drive] = Some(AtaDrive::new(disk));
+        let geometry = |drive: &Option<AtaDrive>| drive.as_ref().map(|drive| drive.disk.geometry);
+        let disks = [geometry(&self.ide.drives[0]), geometry(&self.ide.drives[1])];
+        harddisk::set_cmos_types(&mut self.rtc, disks);
+    }
+
+    /// Puts a CD-ROM drive on the secondary channel as its master.
+    pub fn attach_cdrom(&mut self, image: Option<CdImage>) {
+        self.secondary_ide.cdroms[0] = Some(AtapiDrive::new(image));
+    }
+
+    /// Follows the 8042's output lines: IRQ 1 and 12, A20 and the CPU reset.
+    fn update_kbc(&mut self) {
+        self.kbc.inhibited = self.front_panel.keyboard_inhibited();
+        self.a20.keyboard_controller = self.kbc.a20();
+        if self.kbc.take_reset() {
+            self.reset_controller.request(ResetKind::Warm);
+        }
+        self.pic.set_irq(1, self.kbc.irq1());
+        self.pic.set_irq(12, self.kbc.irq12());
+    }
+
+    /// Follows port 61h's timer 2 gate and speaker data bits.
+    fn update_speaker(&mut self) {
+        let gate = self.system_control.speaker_gate();
+        let data = self.system_control.speaker_data();
+        self.common.set_speaker(gate, data, &mut self.pic);
+    }
+
+    /// The AT has refresh logic of its own rather than using a DMA
+    /// channel, but PIT channel 1 still paces it.
+    fn refresh(&mut self) {
+        let requests = self.common.take_refresh_requests();
+        self.system_control.refresh(requests);
+        self.stolen_cycles += requests * REFRESH_CYCLES;
+    }
+
+    /// Brings the ports, IRQ lines and schedule up to date after a card
+    /// has been fitted or taken out, so a card that's gone stops
+    /// interrupting.
+    pub fn refit(&mut self) {
+        self.map_io();
+        let sb = self
+            .common
+            .sound_blaster
+            .as_ref()
+            .is_some_and(SoundBlaster::irq);
+        self.pic.set_irq(SB_IRQ, sb);
+        self.common.refit(&mut self.pic);
+    }
+
+    /// An 8-bit bus cycle takes six clocks of the ISA bus. The CPU counts
+    /// two of its own for any cycle, so the rest are wait states, and more
+    /// of them once the bus clock is divided down from a fast CPU's.
+    fn isa_8bit_wait_states(&self) -> usize {
+        6 * isa_bus_divisor(self.common.pit_clock.cpu_hz()) - 2
+    }
+
+    /// Gives the fitted devices their ports, afresh. Called when a card is
+    /// fitted or moved, and on reset.
+    pub fn map_io(&mut self) {
+        let io = &mut self.io;
+        io.clear();
+        io.map(0x0000, 0x001f, AtIo::Dma);
+        io.map(0x0080, 0x008f, AtIo::Dma);
+        io.map(0x00c0, 0x00df, AtIo::Dma);
+        io.map(0x0080, 0x0080, AtIo::PostCard);
+        io.map(0x0020, 0x0021, AtIo::Pic);
+        io.map(0x00a0, 0x00a1, AtIo::Pic);
+        io.map(0x0040, 0x0043, AtIo::Pit);
+        io.map(0x0060, 0x0060, AtIo::Kbc);
+        io.map(0x0064, 0x0064, AtIo::Kbc);
+        io.map(0x0061, 0x0061, AtIo::PortB);
+        io.map(0x0070, 0x0071, AtIo::Rtc);
+        io.map(0x0092, 0x0092, AtIo::PortA);
+        io.map(0x01f0, 0x01f7, AtIo::Ide);
+        io.map(0x03f6, 0x03f6, AtIo::Ide);
+        io.map(0x0170, 0x0177, AtIo::SecondaryIde);
+        io.map(0x0376, 0x0376, AtIo::SecondaryIde);
+        if self.common.fdc.is_some() {
+            io.map(0x03f0, 0x03f5, AtIo::Fdc);
+            io.map(0x03f7, 0x03f7, AtIo::Fdc);
+        }
+        if self.common.cga.is_some() || self.common.mda.is_some() || self.common.ega.is_some() {
+            io.map(0x03b0, 0x03df, AtIo::Video);
+        }
+        if self.common.adlib.is_some() {
+            io.map(0x0388, 0x0389, AtIo::Adlib);
+        }
+        if let Some(sb) = &self.common.sound_blaster {
+            // The card's own FM ports are the OPL2's, if it has one.
+            for addr in sb.base..=sb.base + 0xf {
+                if sb.claims(addr) {
+                    io.map(addr, addr, AtIo::SoundBlaster);
+                } else if sb.fm_port(addr) && self.common.adlib.is_some() {
+                    io.map(addr, addr, AtIo::Adlib);
+                }
+            }
+        }
+        if self.common.mpu401.is_some() {
+            io.map(0x0330, 0x0331, AtIo::Mpu401);
+        }
+        if self.common.game_port.is_some() {
+            io.map(0x0200, 0x0207, AtIo::GamePort);
+        }
+        for (port, uart) in self.common.serial.iter().enumerate() {
+            if let Some(uart) = uart {
+                io.map(uart.base, uart.base + 7, AtIo::Serial(port));
+            }
+        }
+        for (port, lpt) in self.common.parallel.iter().enumerate() {
+            if let Some(lpt) = lpt {
+                io.map(lpt.base, lpt.base + 2, AtIo::Parallel(port));
+            }
+        }
+        if let Some(nic) = &self.common.nic {
+            io.map(nic.base, nic.base + 0x1f, AtIo::Nic);
+        }
+        if let Some(perf_counter) = &self.common.perf_counter {
+            io.map(perf_counter.base, perf_counter.base + 1, AtIo::PerfCounter);
+        }
+        if let Some(ems) = &self.common.ems {
+            io.map(ems.config.base, ems.config.base + 3, AtIo::Ems);
+        }
+        for (index, device) in self.common.devices.iter().enumerate() {
+            for (start, end) in device.io_ranges() {
+                io.map(start, end, AtIo::Device(index));
+            }
+        }
+    }
+
+    /// Puts in another BIOS image, of up to 128K, at the top of the first
+    /// megabyte and of the sixteenth.
+    pub fn load_bios(&mut self, bios: Vec<u8>) -> Result<(), String> {
+        rom::map_bios(&mut self.memory, bios, &[0x10_0000, 0x100_0000], 0x2_0000)
+    }
+
+    /// Maps an option ROM for the BIOS to find at POST. Returns where it
+    /// went.
+    pub fn add_option_rom(&mut self, rom: OptionRom, addr: Option<u32>) -> Result<u32, String> {
+        rom.map(&mut self.memory, addr)
+    }
+
+    /// Keeps the memory sizes in CMOS in step with the memory fitted, so
+    /// POST doesn't stop with a memory size error.
+    fn update_cmos_memory(&mut self) {
+        let base_kb = self.ram_size() as u16;
+        let extended_kb = self.extended_size() as u16;
+        self.rtc.set_memory_sizes(base_kb, extended_kb);
+    }
+
+    /// Has the chipset put `kind` in `start` to `end` inclusive, whole 16K
+    /// blocks of C0000h-EFFFFh. A shadow copies what the block reads as
+    /// now, so a card's ROM has to be fitted before it's shadowed.
+    pub fn set_upper_memory(
+        &mut self,
+        start: u32,
+        end: u32,
+        kind: UpperMemory,
+    ) -> Result<(), String> {
+        memory::check_upper_memory(start, end)?;
+        for block in (start..=end).step_by(UPPER_MEMORY_BLOCK as usize) {
+            let block_end = block + UPPER_MEMORY_BLOCK - 1;
+            self.memory.unmap_ram(block, block_end);
+            match kind {
+                UpperMemory::Bus => {}
+                UpperMemory::Shadow => {
+                    let data = (block..=block_end)
+                        .map(|addr| self.mem_read_byte(addr))
+                        .collect();
+                    self.memory.map_ram(block, data, false);
+                }
+                UpperMemory::Umb => {
+                    let data = vec![0; UPPER_MEMORY_BLOCK as usize];
+                    self.memory.map_ram(block, data, true);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fits an EMS board, with its page frame where nothing else answers.
+    pub fn attach_ems(&mut self, config: EmsConfig) -> Result<(), String> {
+        let ems = EmsBoard::new(config)?;
+        let (start, end) = ems.frame();
+        if self.common.ems.is_some() || !self.memory.is_free(start, end) {
+            return Err(format!("something already answers at {:05X}h", start));
+        }
+        self.memory.map_mmio(start, end, AtMmio::Ems);
+        self.common.ems = Some(ems);
+        self.map_io();
+        Ok(())
+    }
+
+    pub fn detach_ems(&mut self) {
+        if let Some(ems) = self.common.ems.take() {
+            self.memory.unmap(ems.frame().0);
+            self.map_io();
+        }
+    }
+
+    /// Fits a card the board doesn't have a place of its own for.
+    pub fn add_device(&mut self, device: Box<dyn Device>) {
+        let index = self.common.devices.len();
+        for (start, end) in device.mmio_ranges() {
+            self.memory.map_mmio(start, end, AtMmio::Device(index));
+        }
+        self.common.devices.push(device);
+        self.map_io();
+    }
+}
+
+impl Motherboard for IbmPcAtHardware {
+    /// The AT BIOS won't get through POST with less than 128K.
+    const MIN_RAM_KB: usize = 128;
+    /// IBM sold the 5170 at 6 and 8 MHz; clones went up to 25.
+    const CLOCK_RANGE_HZ: (u32, u32) = (6_000_000, 25_000_000);
+    /// The 286's 24 address lines reach 16 MB, the last megabyte of it
+    /// shared with the BIOS.
+    const MAX_EXTENDED_KB: usize = 15 * 1024;
+
+    fn tick(&mut self, cycles: usize) {
+        self.common
+            .tick(cycles, &mut self.pic, &mut self.memory.ram);
+        self.refresh();
+        let sb = self
+            .common
+            .sound_blaster
+            .as_ref()
+            .is_some_and(SoundBlaster::irq);
+        self.pic.set_irq(SB_IRQ, sb);
+        self.rtc.tick(self.rtc_clock.ticks(cycles));
+        self.pic.set_irq(8, self.rtc.irq());
+        self.kbc.poll();
+        self.update_kbc();
+    }
+
+    fn set_clock_hz(&mut self, hz: u32) {
+        self.common
+            .set_clock_hz(hz, &mut self.pic, &self.memory.ram);
+        self.rtc_clock.set_cpu_hz(hz);
+    }
+
+    fn resize_ram(&mut self, kb: usize) {
+        self.memory.ram = vec![0; kb * 1024];
+        self.update_cmos_memory();
+    }
+
+    fn ram_size(&self) -> usize {
+        self.memory.ram.len() / 1024
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.memory.ram
+    }
+
+    fn peek(&mut self, addr: u32) -> u8 {
+        let watch = mem::take(&mut self.memory_watch);
+        let value = Cpu286Context::mem_read_byte(self, addr);
+        self.memory_watch = watch;
+        value
+    }
+
+    fn resize_extended(&mut self, kb: usize) {
+        self.memory.extended = vec![0; kb * 1024];
+        self.update_cmos_memory();
+    }
+
+    fn extended_size(&self) -> usize {
+        self.memory.extended.len() / 1024
+    }
+
+    fn reset_controller(&mut self) -> &mut ResetController {
+        &mut self.reset_controller
+    }
+
+    fn port_watch(&mut self) -> Option<&mut PortWatch> {
+        Some(&mut self.port_watch)
+    }
+
+    fn memory_watch(&mut self) -> Option<&mut MemoryWatch> {
+        Some(&mut self.memory_watch)
+    }
+
+    fn acknowledge_interrupt(&mut self) -> Option<u8> {
+        if !self.pic.int_output() {
+            return None;
+        }
+        Some(self.pic.acknowledge())
+    }
+
+    fn take_stolen_cycles(&mut self) -> usize {
+        std::mem::take(&mut self.stolen_cycles)
+    }
+
+    /// A parity error or I/O channel check that port 61h has enabled,
+    /// unless bit 7 of port 70h masks it.
+    fn nmi(&self) -> bool {
+        self.system_control.nmi() && !self.rtc.nmi_masked
+    }
+}
+
+impl Reset for IbmPcAtHardware {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            self.memory.ram.iter_mut().for_each(|byte| *byte = 0);
+            self.memory.extended.fill(0);
+            self.memory.clear_umbs();
         }
+        self.map_io();
+        self.a20.reset(kind);
+        self.kbc.reset(kind);
+        self.ide.reset(kind);
+        self.secondary_ide.reset(kind);
+        self.pic.reset(kind);
+        self.common.reset(kind);
+        self.common.refit(&mut self.pic);
+        self.rtc.reset(kind);
+        self.system_control.reset(kind);
+        self.update_speaker();
     }
 }
 
-impl<'a> Cpu286Context for IbmPcAtHardware {
+impl Cpu286Context for IbmPcAtHardware {
     fn mem_read_byte(&mut self, addr: u32) -> u8 {
         let actual_addr = addr & 0xff_ffff;
-        match actual_addr {
-            0..=0x0a_0000 => self.ram[(actual_addr & 0xffff) as usize],
-            0x0f_0000..=0x0f_ffff => self.bios_rom[(actual_addr & 0xffff) as usize],
-            0xff_0000..=0xff_ffff => self.bios_rom[(actual_addr & 0xffff) as usize],
-            _ => 0xff,
-        }
+        let value = match self.memory.read(actual_addr) {
+            MemoryRead::Data(value) => value,
+            MemoryRead::Mmio(AtMmio::Video) => self.common.read_video(actual_addr),
+            MemoryRead::Mmio(AtMmio::EgaRom) => self
+                .common
+                .ega
+                .as_ref()
+                .map_or(0xff, |ega| ega.read_rom(actual_addr)),
+            MemoryRead::Mmio(AtMmio::Ems) => self
+                .common
+                .ems
+                .as_ref()
+                .map_or(0xff, |ems| ems.read_mem(actual_addr)),
+            MemoryRead::Mmio(AtMmio::Device(index)) => {
+                self.common.devices[index].read_mem(actual_addr)
+            }
+        };
+        self.memory_watch.rb(actual_addr, value);
+        value
     }
     fn mem_write_byte(&mut self, addr: u32, value: u8) {
         let actual_addr = addr & 0xff_ffff;
-        if let 0..=0x0a_0000 = actual_addr {
-            self.ram[(actual_addr & 0xffff) as usize] = value
+        self.memory_watch.wb(actual_addr, value);
+        match self.memory.write(actual_addr, value) {
+            Some(AtMmio::Video) => self.common.write_video(actual_addr, value),
+            Some(AtMmio::Ems) => {
+                if let Some(ems) = &mut self.common.ems {
+                    ems.write_mem(actual_addr, value);
+                }
+            }
+            Some(AtMmio::Device(index)) => self.common.devices[index].write_mem(actual_addr, value),
+            _ => {}
         }
     }
 
-    fn io_read_byte(&mut self, _addr: u16) -> u8 {
-        0xff
+    fn io_read_byte(&mut self, addr: u16) -> u8 {
+        let value = match self.io.device(addr) {
+            Some(AtIo::Dma) | Some(AtIo::PostCard) => self.common.dma.rb(addr),
+            Some(AtIo::Pic) => self.pic.rb(addr),
+            Some(AtIo::Pit) => self.common.read_pit(addr, &mut self.pic),
+            Some(AtIo::Kbc) => {
+                let value = self.kbc.rb(addr);
+                self.update_kbc();
+                value
+            }
+            Some(AtIo::PortB) => {
+                let timer_2_out = self.common.timer_2_out(&mut self.pic);
+                // The refresh toggle has to be where the PIT says it is.
+                self.refresh();
+                self.system_control.read_port_b(timer_2_out)
+            }
+            Some(AtIo::Rtc) => {
+                let value = self.rtc.rb(addr);
+                self.pic.set_irq(8, self.rtc.irq());
+                value
+            }
+            Some(AtIo::PortA) => self.system_control.read_port_a(),
+            Some(AtIo::Video) => self.common.read_video_port(addr),
+            Some(AtIo::Adlib) => {
+                self.common.sync_audio(&mut self.pic, &self.memory.ram);
+                self.common.adlib.as_mut().unwrap().rb(addr)
+            }
+            Some(AtIo::SoundBlaster) => {
+                self.common.sync_audio(&mut self.pic, &self.memory.ram);
+                let sb = self.common.sound_blaster.as_mut().unwrap();
+                let value = sb.rb(addr);
+                self.pic.set_irq(SB_IRQ, sb.irq());
+                value
+            }
+            Some(AtIo::Mpu401) => self.common.mpu401.as_mut().unwrap().rb(addr),
+            Some(AtIo::Serial(port)) => {
+                self.common.sync_serial(&mut self.pic);
+                let value = self.common.serial[port].as_mut().unwrap().rb(addr);
+                self.common.update_serial_irqs(&mut self.pic);
+                value
+            }
+            Some(AtIo::Parallel(port)) => {
+                let value = self.common.parallel[port].as_mut().unwrap().rb(addr);
+                self.common.update_parallel_irq(&mut self.pic);
+                value
+            }
+            Some(AtIo::GamePort) => self.common.read_game_port(addr),
+            Some(AtIo::PerfCounter) => self.common.perf_counter.as_mut().unwrap().rb(addr),
+            Some(AtIo::Ems) => self.common.ems.as_mut().unwrap().rb(addr),
+            Some(AtIo::Nic) => {
+                let value = self.common.nic.as_mut().unwrap().rb(addr);
+                self.common.update_nic_irq(&mut self.pic);
+                value
+            }
+            Some(AtIo::Ide) => {
+                let value = self.ide.rb(addr);
+                self.pic.set_irq(IDE_IRQ, self.ide.irq());
+                value
+            }
+            Some(AtIo::SecondaryIde) => {
+                let value = self.secondary_ide.rb(addr);
+                self.pic
+                    .set_irq(SECONDARY_IDE_IRQ, self.secondary_ide.irq());
+                value
+            }
+            Some(AtIo::Fdc) => self.common.fdc.as_mut().unwrap().rb(addr),
+            Some(AtIo::Device(index)) => {
+                let value = self.common.devices[index].rb(addr);
+                self.common.update_device_irqs(&mut self.pic);
+                value
+            }
+            None => 0xff,
+        };
+        self.port_watch.rb(addr, value);
+        value
+    }
+
+    fn io_write_byte(&mut self, addr: u16, value: u8) {
+        self.port_watch.wb(addr, value);
+        match self.io.device(addr) {
+            Some(AtIo::Dma) => self.common.dma.wb(addr, value),
+            Some(AtIo::PostCard) => {
+                self.common.post_card.wb(value);
+                self.common.dma.wb(addr, value);
+            }
+            Some(AtIo::Pic) => self.pic.wb(addr, value),
+            Some(AtIo::Pit) => self.common.write_pit(addr, value, &mut self.pic),
+            Some(AtIo::Kbc) => {
+                self.kbc.wb(addr, value);
+                self.update_kbc();
+            }
+            Some(AtIo::PortB) => {
+                self.system_control.write_port_b(value);
+                self.update_speaker();
+            }
+            Some(AtIo::Rtc) => self.rtc.wb(addr, value),
+            Some(AtIo::PortA) => {
+                if self.system_control.write_port_a(value) {
+                    self.reset_controller.request(ResetKind::Warm);
+                }
+                self.a20.fast = self.system_control.fast_a20();
+            }
+            Some(AtIo::Video) => self.common.write_video_port(addr, value),
+            Some(AtIo::Adlib) => {
+                self.common.sync_audio(&mut self.pic, &self.memory.ram);
+                self.common.adlib.as_mut().unwrap().wb(addr, value);
+            }
+            Some(AtIo::SoundBlaster) => {
+                self.common.sync_audio(&mut self.pic, &self.memory.ram);
+                let sb = self.common.sound_blaster.as_mut().unwrap();
+                sb.wb(addr, value);
+                self.pic.set_irq(SB_IRQ, sb.irq());
+            }
+            Some(AtIo::Mpu401) => self.common.mpu401.as_mut().unwrap().wb(addr, value),
+            Some(AtIo::Serial(port)) => {
+                self.common.sync_serial(&mut self.pic);
+                self.common.serial[port].as_mut().unwrap().wb(addr, value);
+                self.common.update_serial_irqs(&mut self.pic);
+                self.common.schedule_serial();
+            }
+            Some(AtIo::Parallel(port)) => {
+                self.common.parallel[port].as_mut().unwrap().wb(addr, value);
+                self.common.update_parallel_irq(&mut self.pic);
+            }
+            Some(AtIo::GamePort) => self.common.write_game_port(addr, value),
+            Some(AtIo::PerfCounter) => self.common.perf_counter.as_mut().unwrap().wb(addr, value),
+            Some(AtIo::Ems) => self.common.ems.as_mut().unwrap().wb(addr, value),
+            Some(AtIo::Nic) => {
+                self.common.nic.as_mut().unwrap().wb(addr, value);
+                self.common.update_nic_irq(&mut self.pic);
+            }
+            Some(AtIo::Ide) => {
+                self.ide.wb(addr, value);
+                self.pic.set_irq(IDE_IRQ, self.ide.irq());
+            }
+            Some(AtIo::SecondaryIde) => {
+                self.secondary_ide.wb(addr, value);
+                self.pic
+                    .set_irq(SECONDARY_IDE_IRQ, self.secondary_ide.irq());
+            }
+            Some(AtIo::Fdc) => {
+                let fdc = self.common.fdc.as_mut().unwrap();
+                fdc.wb(addr, value);
+                self.pic.set_irq(FDC_IRQ, fdc.irq());
+                self.common.start_fdc_delay();
+            }
+            Some(AtIo::Device(index)) => {
+                self.common.devices[index].wb(addr, value);
+                self.common.update_device_irqs(&mut self.pic);
+            }
+            None => {}
+        }
     }
 
-    fn io_write_byte(&mut self, _addr: u16, _value: u8) {}
+    /// The IDE data ports are the only 16-bit ports on the board.
+    fn io_read_word(&mut self, addr: u16) -> u16 {
+        if addr == 0x01f0 && self.ide.connected() {
+            let value = self.ide.read_data();
+            self.pic.set_irq(IDE_IRQ, self.ide.irq());
+            return value;
+        }
+        if addr == 0x0170 && self.secondary_ide.connected() {
+            let value = self.secondary_ide.read_data();
+            self.pic
+                .set_irq(SECONDARY_IDE_IRQ, self.secondary_ide.irq());
+            return value;
+        }
+        let lo = self.io_read_byte(addr);
+        let hi = self.io_read_byte(addr.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+
+    fn io_write_word(&mut self, addr: u16, value: u16) {
+        if addr == 0x01f0 {
+            self.ide.write_data(value);
+            self.pic.set_irq(IDE_IRQ, self.ide.irq());
+            return;
+        }
+        if addr == 0x0170 {
+            self.secondary_ide.write_data(value);
+            self.pic
+                .set_irq(SECONDARY_IDE_IRQ, self.secondary_ide.irq());
+            return;
+        }
+        let [lo, hi] = value.to_le_bytes();
+        self.io_write_byte(addr, lo);
+        self.io_write_byte(addr.wrapping_add(1), hi);
+    }
+
+    fn a20_mask(&self) -> u32 {
+        self.a20.mask()
+    }
+
+    /// Video memory and adapter ROMs sit on 8-bit cards, which the bus
+    /// controller gives four wait states per byte. The system board's own
+    /// memory, shadow RAM and upper memory blocks included, runs with one
+    /// wait state per 16-bit bus cycle, charged here on the even byte of
+    /// each word.
+    fn mem_wait_states(&self, addr: u32) -> usize {
+        match addr {
+            0x0a_0000..=0x0e_ffff if !self.memory.is_mapped_ram(addr) => {
+                self.isa_8bit_wait_states()
+            }
+            _ if addr.is_multiple_of(2) => 1,
+            _ => 0,
+        }
+    }
+
+    /// Every chip on the system board's I/O bus is 8 bits wide.
+    fn io_wait_states(&self, _addr: u16) -> usize {
+        self.isa_8bit_wait_states()
+    }
+}
+
+#[test]
+fn test_a20_gate_wraps_at_1mb() {
+    let mut hardware = IbmPcAtHardware::new();
+    let mut cpu = Cpu286::new();
+    hardware.a20.keyboard_controller = false;
+    // FFFF:0010 is 0x100000, which wraps to 0 with A20 off.
+    cpu.regs.writeseg16(registers::SegReg::ES, 0xffff);
+    cpu.write8(&mut hardware, registers::SegReg::ES, 0x10, 0x55)
+        .unwrap();
+    assert_eq!(hardware.memory.ram[0], 0x55);
+
+    hardware.io_write_byte(0x92, 0x02);
+    assert_eq!(hardware.io_read_byte(0x92), 0x02);
+    assert_eq!(
+        cpu.read8(&mut hardware, registers::SegReg::ES, 0x10),
+        Ok(0xff)
+    );
+}
+
+#[test]
+fn test_wait_states_and_clock() {
+    let mut machine = crate::hardware::IbmPcAtMachine::new();
+    machine.cpu.regs.writeseg16(registers::SegReg::CS, 0);
+    machine.cpu.regs.ip = 0x100;
+    machine.cpu.regs.writeseg16(registers::SegReg::ES, 0xb800);
+    machine.hardware.memory.ram[0x100..0x107].copy_from_slice(&[
+        0x89, 0x07, // mov [bx], ax
+        0x26, 0x89, 0x07, // mov es:[bx], ax
+        0xe6, 0x80, // out 80h, al
+    ]);
+    // Base clocks, then the memory operand, then a wait state for every
+    // even byte fetched or stored in RAM and four per byte of video memory
+    // or I/O.
+    assert_eq!(machine.cpu.tick(&mut machine.hardware), 2 + 3 + 1 + 1);
+    assert_eq!(machine.cpu.tick(&mut machine.hardware), 2 + 3 + 2 + 8);
+    assert_eq!(machine.cpu.tick(&mut machine.hardware), 3 + 1 + 4);
+
+    assert!(machine.set_clock_hz(4_772_727).is_err());
+    machine.set_clock_hz(8_000_000).unwrap();
+    assert_eq!(machine.cycles_per_frame, 133_505);
+
+    // At 12 MHz the bus runs at half the CPU clock, so an 8-bit cycle
+    // takes twice as many CPU clocks.
+    machine.set_clock_hz(12_000_000).unwrap();
+    machine.cpu.regs.ip = 0x105;
+    assert_eq!(machine.cpu.tick(&mut machine.hardware), 3 + 1 + 10);
+}
+
+#[test]
+fn test_warm_reset_keeps_ram_and_a20() {
+    let mut machine = crate::hardware::IbmPcAtMachine::new();
+    // The BIOS warm boot flag, 1234h at 40:72.
+    machine.hardware.memory.ram[0x472..0x474].copy_from_slice(&[0x34, 0x12]);
+    machine.cpu.regs.writeseg16(registers::SegReg::CS, 0);
+    machine.cpu.regs.ip = 0x100;
+    machine.hardware.memory.ram[0x100..0x10d].copy_from_slice(&[
+        0xb0, 0xd1, // mov al, 0d1h
+        0xe6, 0x64, // out 64h, al
+        0xb0, 0xdd, // mov al, 0ddh
+        0xe6, 0x60, // out 60h, al
+        0xb0, 0x03, // mov al, 3
+        0xe6, 0x92, // out 92h, al
+        0xf4, // hlt
+    ]);
+    machine.run_instructions(4);
+    assert!(!machine.hardware.a20.keyboard_controller);
+    machine.run_instructions(2);
+    assert_eq!(machine.cpu.regs.ip, 0xfff0);
+    assert_eq!(&machine.hardware.memory.ram[0x472..0x474], &[0x34, 0x12]);
+    assert!(machine.hardware.a20.fast);
+    assert!(!machine.hardware.a20.keyboard_controller);
+
+    machine.reset_with(ResetKind::Hard);
+    assert_eq!(machine.hardware.a20, A20Gate::new());
+    assert_eq!(&machine.hardware.memory.ram[0x472..0x474], &[0x34, 0x12]);
+
+    machine.reset();
+    assert_eq!(&machine.hardware.memory.ram[0x472..0x474], &[0, 0]);
+}
+
+#[test]
+fn test_extended_memory() {
+    let mut machine = crate::hardware::IbmPcAtMachine::new();
+    assert!(machine.set_extended_memory(16 * 1024).is_err());
+    machine.set_extended_memory(1024).unwrap();
+    assert_eq!(machine.extended_memory(), 0);
+    machine.reset();
+    assert_eq!(machine.extended_memory(), 1024);
+    assert_eq!(&machine.hardware.rtc.ram[0x17..0x19], &[0x00, 0x04]);
+    assert_eq!(&machine.hardware.rtc.ram[0x30..0x32], &[0x00, 0x04]);
+
+    // The HMA with A20 on, and the bottom of memory again with it off.
+    let hardware = &mut machine.hardware;
+    let mut cpu = Cpu286::new();
+    cpu.regs.writeseg16(registers::SegReg::ES, 0xffff);
+    cpu.write8(hardware, registers::SegReg::ES, 0x10, 0x55)
+        .unwrap();
+    assert_eq!(hardware.memory.extended[0], 0x55);
+    hardware.a20.keyboard_controller = false;
+    assert_eq!(cpu.read8(hardware, registers::SegReg::ES, 0x10), Ok(0));
+    // Past the end of what's fitted is open bus, and the BIOS is still at
+    // the top of the address space.
+    assert_eq!(hardware.mem_read_byte(0x20_0000), 0xff);
+    assert_eq!(
+        hardware.mem_read_byte(0xff_fff0),
+        hardware.mem_read_byte(0x0f_fff0)
+    );
+}
+
+#[test]
+fn test_upper_memory() {
+    let mut hardware = crate::hardware::IbmPcAtMachine::new().hardware;
+    hardware.memory.map_rom(0x0c_8000, vec![0x55; 0x4000]);
+    assert!(hardware
+        .set_upper_memory(0x0c_8000, 0x0c_bfff, UpperMemory::Umb)
+        .is_ok());
+    assert!(hardware
+        .set_upper_memory(0x0c_8000, 0x0c_9fff, UpperMemory::Umb)
+        .is_err());
+    assert!(hardware
+        .set_upper_memory(0x0f_0000, 0x0f_3fff, UpperMemory::Umb)
+        .is_err());
+
+    // A shadowed ROM reads the same, ignores writes, and runs at the
+    // speed of the board's own memory.
+    hardware
+        .set_upper_memory(0x0c_8000, 0x0c_bfff, UpperMemory::Shadow)
+        .unwrap();
+    assert_eq!(hardware.mem_read_byte(0x0c_8100), 0x55);
+    hardware.mem_write_byte(0x0c_8100, 0);
+    assert_eq!(hardware.mem_read_byte(0x0c_8100), 0x55);
+    assert_eq!(hardware.mem_wait_states(0x0c_8100), 1);
+    assert_eq!(hardware.mem_wait_states(0x0c_c100), 4);
+
+    hardware
+        .set_upper_memory(0x0d_0000, 0x0d_ffff, UpperMemory::Umb)
+        .unwrap();
+    hardware.mem_write_byte(0x0d_f000, 0x42);
+    assert_eq!(hardware.mem_read_byte(0x0d_f000), 0x42);
+    hardware.reset(ResetKind::Warm);
+    assert_eq!(hardware.mem_read_byte(0x0d_f000), 0x42);
+    hardware.reset(ResetKind::Cold);
+    assert_eq!(hardware.mem_read_byte(0x0d_f000), 0);
+    assert_eq!(hardware.mem_read_byte(0x0c_8100), 0x55);
+
+    // Handing a block back to the bus shows the ROM again.
+    hardware.memory.map_rom(0x0c_8000, vec![0xaa; 0x4000]);
+    hardware
+        .set_upper_memory(0x0c_8000, 0x0c_bfff, UpperMemory::Bus)
+        .unwrap();
+    assert_eq!(hardware.mem_read_byte(0x0c_8100), 0xaa);
 }
@@ -0,0 +1,756 @@
+use crate::hardware::atapi::AtapiDrive;
+use crate::hardware::harddisk::{HardDisk, SECTOR_SIZE};
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace};
+
+/// The IRQs the primary and secondary channels are wired to.
+pub const IDE_IRQ: u8 = 14;
+pub const SECONDARY_IDE_IRQ: u8 = 15;
+
+/// The device control register's distance from the task file.
+const CONTROL_OFFSET: u16 = 0x206;
+
+/// Status register bits.
+const STATUS_BUSY: u8 = 0x80;
+const STATUS_READY: u8 = 0x40;
+const STATUS_SEEK_COMPLETE: u8 = 0x10;
+const STATUS_DATA_REQUEST: u8 = 0x08;
+const STATUS_ERROR: u8 = 0x01;
+
+/// Error register bits.
+const ERROR_ID_NOT_FOUND: u8 = 0x10;
+const ERROR_ABORTED: u8 = 0x04;
+
+/// Device control register (3F6h) bits.
+const CONTROL_RESET: u8 = 0x04;
+const CONTROL_NO_INTERRUPT: u8 = 0x02;
+
+/// Drive/head register bits.
+const HEAD_LBA: u8 = 0x40;
+const HEAD_SLAVE: u8 = 0x10;
+
+/// The interrupt reason an ATAPI device leaves in the sector count
+/// register: whether it wants a packet or has finished, and which way the
+/// data goes.
+const REASON_COMMAND: u8 = 0x01;
+const REASON_INPUT: u8 = 0x02;
+
+/// What a packet device leaves in the cylinder registers after a reset, so
+/// the host can tell it from a hard disk.
+const ATAPI_SIGNATURE: u16 = 0xeb14;
+
+/// The most sectors READ and WRITE MULTIPLE move per interrupt.
+const MAX_MULTIPLE: u8 = 16;
+
+/// A drive on the channel: the disk, plus the geometry the BIOS asked for
+/// with INITIALIZE DEVICE PARAMETERS and the block size for the multiple
+/// commands.
+#[derive(Debug, Clone)]
+pub struct AtaDrive {
+    pub disk: HardDisk,
+    heads: u8,
+    sectors: u8,
+    multiple: u8,
+}
+
+impl AtaDrive {
+    pub fn new(disk: HardDisk) -> AtaDrive {
+        AtaDrive {
+            heads: disk.geometry.heads,
+            sectors: disk.geometry.sectors,
+            multiple: 0,
+            disk,
+        }
+    }
+
+    /// IDENTIFY DEVICE's 256 words.
+    fn identify(&self) -> Vec<u8> {
+        let geometry = self.disk.geometry;
+        let total = self.disk.total_sectors().min(u32::MAX as u64) as u32;
+        let cylinders = self.logical_cylinders();
+        let current = cylinders as u32 * self.heads as u32 * self.sectors as u32;
+        let mut words = [0u16; 256];
+        words[0] = 0x0040;
+        words[1] = geometry.cylinders;
+        words[3] = geometry.heads as u16;
+        words[6] = geometry.sectors as u16;
+        // Strings go two characters to a word, the first in the high byte.
+        let mut string = |start: usize, text: &str, length: usize| {
+            let text = format!("{:<width$}", text, width = length * 2);
+            for (i, pair) in text.as_bytes().chunks(2).take(length).enumerate() {
+                words[start + i] = u16::from_be_bytes([pair[0], pair[1]]);
+            }
+        };
+        string(10, "EMUPC0001", 10);
+        string(23, "1.0", 4);
+        string(27, "EMUPC HARD DISK", 20);
+        words[47] = 0x8000 | MAX_MULTIPLE as u16;
+        words[49] = 0x0200;
+        words[53] = 0x0001;
+        words[54] = cylinders;
+        words[55] = self.heads as u16;
+        words[56] = self.sectors as u16;
+        words[57] = current as u16;
+        words[58] = (current >> 16) as u16;
+        if self.multiple != 0 {
+            words[59] = 0x0100 | self.multiple as u16;
+        }
+        words[60] = total as u16;
+        words[61] = (total >> 16) as u16;
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    /// The cylinders the translated geometry has room for.
+    fn logical_cylinders(&self) -> u16 {
+        let track = self.heads as u64 * self.sectors as u64;
+        match track {
+            0 => 0,
+            _ => (self.disk.geometry.total_sectors() / track).min(u16::MAX as u64) as u16,
+        }
+    }
+}
+
+/// A READ or WRITE SECTORS (or MULTIPLE) under way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Transfer {
+    write: bool,
+    /// Sectors still to go.
+    remaining: u32,
+    /// Sectors per interrupt.
+    block: u32,
+}
+
+/// Where an ATAPI PACKET command is, with the most bytes the host wants
+/// per interrupt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Packet {
+    /// Taking the 12 byte packet.
+    Command { limit: usize },
+    /// Handing the reply over, up to `end` before the next interrupt.
+    DataIn { limit: usize, end: usize },
+}
+
+/// An ATA channel with a master and a slave: the AT's primary one at
+/// 1F0h-1F7h and 3F6h, or a secondary one at 170h-177h and 376h. Commands
+/// finish at once; data moves through the 16-bit data port a block of
+/// sectors at a time, with an interrupt per block. Either drive can be a
+/// hard disk, or an ATAPI CD-ROM taking packet commands.
+#[derive(Debug, Clone)]
+pub struct IDE {
+    pub drives: [Option<AtaDrive>; 2],
+    pub cdroms: [Option<AtapiDrive>; 2],
+    /// The data port; the rest of the task file follows it.
+    base: u16,
+    /// The error register when read, features when written.
+    error: u8,
+    features: u8,
+    sector_count: u8,
+    sector_number: u8,
+    cylinder: u16,
+    drive_head: u8,
+    status: u8,
+    control: u8,
+    buffer: Vec<u8>,
+    position: usize,
+    transfer: Option<Transfer>,
+    packet: Option<Packet>,
+    irq: bool,
+    /// Set whenever a sector is read or written, for an activity light.
+    /// Whoever shows the light clears it.
+    pub activity: bool,
+}
+
+impl IDE {
+    pub fn new() -> IDE {
+        IDE::at(0x1f0)
+    }
+
+    pub fn secondary() -> IDE {
+        IDE::at(0x170)
+    }
+
+    fn at(base: u16) -> IDE {
+        IDE {
+            drives: [None, None],
+            cdroms: [None, None],
+            base,
+            error: 0,
+            features: 0,
+            sector_count: 1,
+            sector_number: 1,
+            cylinder: 0,
+            drive_head: 0xa0,
+            status: STATUS_READY | STATUS_SEEK_COMPLETE,
+            control: 0,
+            buffer: vec![],
+            position: 0,
+            transfer: None,
+            packet: None,
+            irq: false,
+            activity: false,
+        }
+    }
+
+    /// IRQ 14 or 15, unless the device control register masks it.
+    pub fn irq(&self) -> bool {
+        self.irq && (self.control & CONTROL_NO_INTERRUPT) == 0
+    }
+
+    fn selected(&self) -> usize {
+        ((self.drive_head & HEAD_SLAVE) != 0) as usize
+    }
+
+    fn drive(&self) -> Option<&AtaDrive> {
+        self.drives[self.selected()].as_ref()
+    }
+
+    fn cdrom(&mut self) -> Option<&mut AtapiDrive> {
+        self.cdroms[self.selected()].as_mut()
+    }
+
+    /// Whether anything answers as the selected drive.
+    fn present(&self) -> bool {
+        self.drive().is_some() || self.cdroms[self.selected()].is_some()
+    }
+
+    /// The sector the task file points at, by LBA or through the
+    /// translated geometry.
+    fn address(&self) -> Option<u64> {
+        let drive = self.drive()?;
+        if (self.drive_head & HEAD_LBA) != 0 {
+            let lba = ((self.drive_head & 0x0f) as u64) << 24
+                | (self.cylinder as u64) << 8
+                | self.sector_number as u64;
+            return Some(lba);
+        }
+        let head = self.drive_head & 0x0f;
+        if self.sector_number == 0 || self.sector_number > drive.sectors || head >= drive.heads {
+            return None;
+        }
+        Some(
+            (self.cylinder as u64 * drive.heads as u64 + head as u64) * drive.sectors as u64
+                + (self.sector_number - 1) as u64,
+        )
+    }
+
+    /// Points the task file at `lba`, as the drive does after each sector.
+    fn set_address(&mut self, lba: u64) {
+        let (heads, sectors) = match self.drive() {
+            Some(drive) => (drive.heads as u64, drive.sectors as u64),
+            None => return,
+        };
+        if (self.drive_head & HEAD_LBA) != 0 {
+            self.sector_number = lba as u8;
+            self.cylinder = (lba >> 8) as u16;
+            self.drive_head = (self.drive_head & 0xf0) | ((lba >> 24) as u8 & 0x0f);
+            return;
+        }
+        let track = lba / sectors;
+        self.sector_number = (lba % sectors) as u8 + 1;
+        self.cylinder = (track / heads) as u16;
+        self.drive_head = (self.drive_head & 0xf0) | (track % heads) as u8;
+    }
+
+    fn finish(&mut self) {
+        self.status = STATUS_READY | STATUS_SEEK_COMPLETE;
+        self.transfer = None;
+        self.packet = None;
+        self.irq = true;
+    }
+
+    fn abort(&mut self, error: u8) {
+        debug!(target: "disk", "Command failed with error {:#04x}", error);
+        self.error = error;
+        self.finish();
+        self.status |= STATUS_ERROR;
+    }
+
+    fn execute(&mut self, command: u8) {
+        trace!(target: "disk", "Command {:#04x} on drive {}", command, self.selected());
+        if self.cdrom().is_some() {
+            return self.execute_atapi(command);
+        }
+        if self.drive().is_none() {
+            return;
+        }
+        self.error = 0;
+        let count = match self.sector_count {
+            0 => 256,
+            count => count as u32,
+        };
+        let multiple = self.drive().map_or(0, |drive| drive.multiple) as u32;
+        match command {
+            // RECALIBRATE, SEEK, READ VERIFY and SET FEATURES have nothing
+            // to do.
+            0x10..=0x1f | 0x70..=0x7f | 0x40 | 0x41 | 0xef => self.finish(),
+            0x20 | 0x21 | 0x30 | 0x31 => self.start_transfer(command >= 0x30, count, 1),
+            0xc4 | 0xc5 if multiple == 0 => self.abort(ERROR_ABORTED),
+            0xc4 | 0xc5 => self.start_transfer(command == 0xc5, count, multiple),
+            // EXECUTE DEVICE DIAGNOSTIC reports both drives passing in the
+            // error register.
+            0x90 => {
+                self.finish();
+                self.error = 0x01;
+            }
+            0x91 => {
+                let heads = (self.drive_head & 0x0f) + 1;
+                let sectors = self.sector_count;
+                if sectors == 0 {
+                    return self.abort(ERROR_ABORTED);
+                }
+                let drive = self.drives[self.selected()].as_mut().unwrap();
+                drive.heads = heads;
+                drive.sectors = sectors;
+                self.finish();
+            }
+            0xc6 => {
+                let multiple = self.sector_count;
+                if multiple > MAX_MULTIPLE || !multiple.is_power_of_two() && multiple != 0 {
+                    self.abort(ERROR_ABORTED);
+                } else {
+                    self.drives[self.selected()].as_mut().unwrap().multiple = multiple;
+                    self.finish();
+                }
+            }
+            0xec => {
+                self.buffer = self.drive().unwrap().identify();
+                self.position = 0;
+                self.status = STATUS_READY | STATUS_SEEK_COMPLETE | STATUS_DATA_REQUEST;
+                self.irq = true;
+            }
+            _ => {
+                debug!(target: "disk", "Unsupported command {:#04x}", command);
+                self.abort(ERROR_ABORTED);
+            }
+        }
+    }
+
+    /// The ATA commands a packet device takes. The hard disk ones abort,
+    /// leaving the signature that tells the host to use IDENTIFY PACKET
+    /// DEVICE instead.
+    fn execute_atapi(&mut self, command: u8) {
+        self.error = 0;
+        match command {
+            0xa0 => {
+                // Zero means as much as fits in the registers.
+                let limit = match self.cylinder & !1 {
+                    0 => 0xfffe,
+                    limit => limit as usize,
+                };
+                self.packet = Some(Packet::Command { limit });
+                self.buffer.clear();
+                self.position = 0;
+                self.sector_count = REASON_COMMAND;
+                self.status = STATUS_READY | STATUS_SEEK_COMPLETE | STATUS_DATA_REQUEST;
+            }
+            0xa1 => {
+                self.buffer = self.cdrom().unwrap().identify();
+                self.position = 0;
+                self.status = STATUS_READY | STATUS_SEEK_COMPLETE | STATUS_DATA_REQUEST;
+                self.irq = true;
+            }
+            // DEVICE RESET doesn't interrupt.
+            0x08 => {
+                self.reset_task_file();
+                self.drive_head |= (self.selected() as u8) * HEAD_SLAVE;
+                self.cylinder = ATAPI_SIGNATURE;
+            }
+            0x90 => {
+                self.finish();
+                self.error = 0x01;
+                self.cylinder = ATAPI_SIGNATURE;
+            }
+            0x00 | 0xef => self.finish(),
+            _ => {
+                self.abort(ERROR_ABORTED);
+                self.sector_count = 1;
+                self.sector_number = 1;
+                self.cylinder = ATAPI_SIGNATURE;
+            }
+        }
+    }
+
+    /// Runs the packet the host has written, failing it with the sense key
+    /// in the error register, or starting on its reply.
+    fn run_packet(&mut self, limit: usize) {
+        let packet = std::mem::take(&mut self.buffer);
+        let result = self.cdrom().unwrap().command(&packet);
+        match result {
+            Ok(reply) => {
+                self.buffer = reply;
+                self.position = 0;
+                self.packet = Some(Packet::DataIn { limit, end: 0 });
+                self.send_packet_data();
+            }
+            Err(sense) => {
+                self.abort(sense.key << 4 | ERROR_ABORTED);
+                self.sector_count = REASON_COMMAND | REASON_INPUT;
+            }
+        }
+    }
+
+    /// Offers the host the next piece of a packet command's reply, with its
+    /// length in the cylinder registers, or ends the command once it's all
+    /// been read.
+    fn send_packet_data(&mut self) {
+        let limit = match self.packet {
+            Some(Packet::DataIn { limit, .. }) => limit,
+            _ => return,
+        };
+        let rest = self.buffer.len().saturating_sub(self.position);
+        if rest == 0 {
+            self.finish();
+            self.sector_count = REASON_COMMAND | REASON_INPUT;
+            return;
+        }
+        let length = rest.min(limit);
+        self.packet = Some(Packet::DataIn {
+            limit,
+            end: self.position + length,
+        });
+        self.cylinder = length as u16;
+        self.sector_count = REASON_INPUT;
+        self.status = STATUS_READY | STATUS_SEEK_COMPLETE | STATUS_DATA_REQUEST;
+        self.irq = true;
+    }
+
+    fn start_transfer(&mut self, write: bool, count: u32, block: u32) {
+        self.transfer = Some(Transfer {
+            write,
+            remaining: count,
+            block,
+        });
+        self.buffer.clear();
+        self.position = 0;
+        if write {
+            // The host fills the first block without being interrupted.
+            self.status = STATUS_READY | STATUS_SEEK_COMPLETE | STATUS_DATA_REQUEST;
+        } else {
+            self.read_block();
+        }
+    }
+
+    /// The sectors in this transfer's next block.
+    fn block_length(&self) -> usize {
+        self.transfer.map_or(0, |transfer| {
+            transfer.block.min(transfer.remaining) as usize
+        })
+    }
+
+    /// Reads the next block into the buffer and interrupts for it.
+    fn read_block(&mut self) {
+        self.buffer.clear();
+        self.position = 0;
+        self.activity = true;
+        for i in 0..self.block_length() {
+            let sector = self.address().and_then(|lba| {
+                let data = self.drive()?.disk.read_sector(lba)?;
+                Some((lba, data.to_vec()))
+            });
+            match sector {
+                Some((lba, data)) => {
+                    self.buffer.extend(data);
+                    // The registers are left on the last sector read.
+                    if self.remaining() as usize - i > 1 {
+                        self.set_address(lba + 1);
+                    }
+                    self.sector_count = self.sector_count.wrapping_sub(1);
+                }
+                None => return self.abort(ERROR_ID_NOT_FOUND),
+            }
+        }
+        self.status = STATUS_READY | STATUS_SEEK_COMPLETE | STATUS_DATA_REQUEST;
+        self.irq = true;
+    }
+
+    fn remaining(&self) -> u32 {
+        self.transfer.map_or(0, |transfer| transfer.remaining)
+    }
+
+    /// Writes the block the host has filled the buffer with.
+    fn write_block(&mut self) {
+        let buffer = std::mem::take(&mut self.buffer);
+        self.activity = true;
+        for (i, data) in buffer.chunks(SECTOR_SIZE).enumerate() {
+            let lba = self.address();
+            let selected = self.selected();
+            let written = match (lba, self.drives[selected].as_mut()) {
+                (Some(lba), Some(drive)) => drive.disk.write_sector(lba, data),
+                _ => false,
+            };
+            if !written {
+                return self.abort(ERROR_ID_NOT_FOUND);
+            }
+            if self.remaining() as usize - i > 1 {
+                self.set_address(lba.unwrap() + 1);
+            }
+            self.sector_count = self.sector_count.wrapping_sub(1);
+        }
+    }
+
+    /// Takes a word off the buffer, moving the transfer on when it runs
+    /// out.
+    pub fn read_data(&mut self) -> u16 {
+        if (self.status & STATUS_DATA_REQUEST) == 0 || self.transfer.is_some_and(|t| t.write) {
+            return 0xffff;
+        }
+        let word = u16::from_le_bytes([
+            self.buffer.get(self.position).copied().unwrap_or(0xff),
+            self.buffer.get(self.position + 1).copied().unwrap_or(0xff),
+        ]);
+        self.position += 2;
+        if let Some(Packet::DataIn { end, .. }) = self.packet {
+            if self.position >= end {
+                self.send_packet_data();
+            }
+            return word;
+        }
+        if self.position < self.buffer.len() {
+            return word;
+        }
+        let sectors = self.block_length() as u32;
+        match &mut self.transfer {
+            Some(transfer) if transfer.remaining > sectors => {
+                transfer.remaining -= sectors;
+                self.read_block();
+            }
+            // The last block of a read, or IDENTIFY, ends without an
+            // interrupt.
+            _ => {
+                self.status = STATUS_READY | STATUS_SEEK_COMPLETE;
+                self.transfer = None;
+            }
+        }
+        word
+    }
+
+    pub fn write_data(&mut self, data: u16) {
+        if let Some(Packet::Command { limit }) = self.packet {
+            self.buffer.extend_from_slice(&data.to_le_bytes());
+            if self.buffer.len() >= 12 {
+                self.status = STATUS_READY | STATUS_SEEK_COMPLETE;
+                self.run_packet(limit);
+            }
+            return;
+        }
+        if (self.status & STATUS_DATA_REQUEST) == 0 || !self.transfer.is_some_and(|t| t.write) {
+            return;
+        }
+        self.buffer.extend_from_slice(&data.to_le_bytes());
+        if self.buffer.len() < self.block_length() * SECTOR_SIZE {
+            return;
+        }
+        let sectors = self.block_length() as u32;
+        self.write_block();
+        if (self.status & STATUS_ERROR) != 0 {
+            return;
+        }
+        match &mut self.transfer {
+            Some(transfer) if transfer.remaining > sectors => {
+                transfer.remaining -= sectors;
+                self.irq = true;
+            }
+            _ => self.finish(),
+        }
+    }
+
+    /// Whether any drive is on the cable.
+    pub fn connected(&self) -> bool {
+        self.drives.iter().any(Option::is_some) || self.cdroms.iter().any(Option::is_some)
+    }
+
+    /// The task file reads as all ones when there are no drives on the
+    /// cable, and as zero for a slave that isn't there.
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        let register = addr.wrapping_sub(self.base);
+        if !self.connected() {
+            return 0xff;
+        }
+        if !self.present() && register != 6 {
+            return 0;
+        }
+        match register {
+            0 => self.read_data() as u8,
+            1 => self.error,
+            2 => self.sector_count,
+            3 => self.sector_number,
+            4 => self.cylinder as u8,
+            5 => (self.cylinder >> 8) as u8,
+            6 => self.drive_head,
+            // Reading the status register acknowledges the interrupt,
+            // the alternate status at 3F6h doesn't.
+            7 => {
+                self.irq = false;
+                self.status
+            }
+            CONTROL_OFFSET => self.status,
+            _ => 0xff,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr.wrapping_sub(self.base) {
+            0 => self.write_data(data as u16),
+            1 => self.features = data,
+            2 => self.sector_count = data,
+            3 => self.sector_number = data,
+            4 => self.cylinder = (self.cylinder & 0xff00) | data as u16,
+            5 => self.cylinder = (self.cylinder & 0x00ff) | (data as u16) << 8,
+            6 => self.drive_head = data | 0xa0,
+            7 => self.execute(data),
+            CONTROL_OFFSET => self.write_control(data),
+            _ => {}
+        }
+    }
+
+    /// Setting SRST holds both drives in reset; clearing it leaves the
+    /// task file with the diagnostic signature.
+    fn write_control(&mut self, data: u8) {
+        let was_reset = (self.control & CONTROL_RESET) != 0;
+        self.control = data;
+        if (data & CONTROL_RESET) != 0 {
+            self.status = STATUS_BUSY;
+            self.transfer = None;
+            self.packet = None;
+            self.irq = false;
+        } else if was_reset {
+            self.reset_task_file();
+        }
+    }
+
+    /// Puts the diagnostic signature in the task file, with the master
+    /// selected.
+    fn reset_task_file(&mut self) {
+        self.error = 0x01;
+        self.sector_count = 1;
+        self.sector_number = 1;
+        self.cylinder = match self.cdroms[0] {
+            Some(_) => ATAPI_SIGNATURE,
+            None => 0,
+        };
+        self.drive_head = 0xa0;
+        self.status = STATUS_READY | STATUS_SEEK_COMPLETE;
+        self.buffer.clear();
+        self.position = 0;
+        self.transfer = None;
+        self.packet = None;
+    }
+}
+
+// The drives see the bus reset, but keep the disks in them.
+impl Reset for IDE {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        self.control = 0;
+        self.irq = false;
+        self.reset_task_file();
+        for cdrom in self.cdroms.iter_mut().flatten() {
+            cdrom.reset(kind);
+        }
+        for drive in self.drives.iter_mut().flatten() {
+            drive.heads = drive.disk.geometry.heads;
+            drive.sectors = drive.disk.geometry.sectors;
+            drive.multiple = 0;
+        }
+    }
+}
+
+impl Default for IDE {
+    fn default() -> IDE {
+        IDE::new()
+    }
+}
+
+#[cfg(test)]
+use crate::hardware::harddisk::Geometry;
+
+#[test]
+fn test_ide_commands_and_transfers() {
+    let mut disk = HardDisk::new(Geometry {
+        cylinders: 100,
+        heads: 4,
+        sectors: 17,
+    });
+    // Cylinder 1, head 2, sector 5.
+    let lba = (4 + 2) * 17 + 4;
+    disk.write_sector(lba, &[0x5a; 512]);
+    let mut ide = IDE::new();
+    assert_eq!(ide.rb(0x1f7), 0xff);
+    ide.drives[0] = Some(AtaDrive::new(disk));
+
+    // The slave isn't there.
+    ide.wb(0x1f6, 0x10);
+    assert_eq!(ide.rb(0x1f7), 0x00);
+
+    ide.wb(0x1f6, 0x00);
+    ide.wb(0x1f7, 0xec);
+    assert!(ide.irq());
+    assert_eq!(ide.rb(0x1f7), 0x58);
+    let identify: Vec<u16> = (0..256).map(|_| ide.read_data()).collect();
+    assert_eq!((identify[1], identify[3], identify[6]), (100, 4, 17));
+    assert_eq!(identify[27], u16::from_be_bytes(*b"EM"));
+    assert_eq!(identify[60], 100 * 4 * 17);
+    assert_eq!(ide.rb(0x1f7), 0x50);
+
+    // Two sectors by CHS, interrupting for each, ending on the second.
+    for (port, value) in [
+        (0x1f2, 2),
+        (0x1f3, 5),
+        (0x1f4, 1),
+        (0x1f5, 0),
+        (0x1f6, 0xa2),
+    ] {
+        ide.wb(port, value);
+    }
+    ide.wb(0x1f7, 0x20);
+    assert!(ide.irq());
+    assert_eq!(ide.rb(0x1f7), 0x58);
+    assert_eq!(ide.read_data(), 0x5a5a);
+    for _ in 1..512 {
+        ide.read_data();
+    }
+    assert_eq!(ide.rb(0x1f7), 0x50);
+    assert_eq!((ide.rb(0x1f2), ide.rb(0x1f3)), (0, 6));
+
+    // WRITE MULTIPLE by LBA in blocks of four, with one interrupt per
+    // block.
+    ide.wb(0x1f2, 4);
+    ide.wb(0x1f7, 0xc6);
+    assert_eq!(ide.rb(0x1f7), 0x50);
+    for (port, value) in [
+        (0x1f2, 6),
+        (0x1f3, 10),
+        (0x1f4, 0),
+        (0x1f5, 0),
+        (0x1f6, 0xe0),
+    ] {
+        ide.wb(port, value);
+    }
+    ide.wb(0x1f7, 0xc5);
+    assert!(!ide.irq());
+    for word in 0..4 * 256 {
+        ide.write_data(word as u16);
+    }
+    assert!(ide.irq());
+    assert_eq!(ide.rb(0x1f7), 0x58);
+    for _ in 0..2 * 256 {
+        ide.write_data(0xa5a5);
+    }
+    assert_eq!(ide.rb(0x1f7), 0x50);
+    assert_eq!(ide.rb(0x1f3), 15);
+    let disk = &ide.drives[0].as_ref().unwrap().disk;
+    assert_eq!(disk.read_sector(11).unwrap()[..2], [0x00, 0x01]);
+    assert_eq!(disk.read_sector(15).unwrap()[0], 0xa5);
+
+    // Past the end of the disk, and an unknown command.
+    ide.wb(0x1f2, 1);
+    ide.wb(0x1f4, 0xff);
+    ide.wb(0x1f7, 0x20);
+    assert_eq!((ide.rb(0x1f7), ide.rb(0x1f1)), (0x51, 0x10));
+    ide.wb(0x3f6, CONTROL_NO_INTERRUPT);
+    ide.wb(0x1f7, 0xff);
+    assert!(!ide.irq());
+    assert_eq!(ide.rb(0x1f1), 0x04);
+}
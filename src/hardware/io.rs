@@ -0,0 +1,231 @@
+// The I/O side of a board's bus. Devices take ranges of the 64K port space
+// when they're fitted, at whatever base they've been configured for, and
+// the board looks each access up here to find the device it's for. Like
+// the memory bus, the devices are named by the board's own `D`. A port
+// nothing has taken reads as open bus.
+use crate::hardware::breakpoints::Watch;
+use log::trace;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IoBus<D> {
+    /// Who answers for each port.
+    ports: Vec<Option<D>>,
+}
+
+impl<D: Copy> Default for IoBus<D> {
+    fn default() -> IoBus<D> {
+        IoBus::new()
+    }
+}
+
+impl<D: Copy> IoBus<D> {
+    pub fn new() -> IoBus<D> {
+        IoBus {
+            ports: vec![None; 0x10000],
+        }
+    }
+
+    /// Hands `start` to `end` inclusive to a device, over whatever had
+    /// them before.
+    pub fn map(&mut self, start: u16, end: u16, device: D) {
+        trace!("I/O at {:#06x}-{:#06x}", start, end);
+        self.ports[start as usize..=end as usize].fill(Some(device));
+    }
+
+    /// Frees `start` to `end` inclusive.
+    pub fn unmap(&mut self, start: u16, end: u16) {
+        self.ports[start as usize..=end as usize].fill(None);
+    }
+
+    /// Frees every port, for mapping the devices afresh.
+    pub fn clear(&mut self) {
+        self.ports.fill(None);
+    }
+
+    pub fn device(&self, addr: u16) -> Option<D> {
+        self.ports[addr as usize]
+    }
+}
+
+impl<D: Copy + PartialEq> IoBus<D> {
+    /// Each run of ports one device answers for, as start, end inclusive
+    /// and the device, in port order.
+    pub fn ranges(&self) -> Vec<(u16, u16, D)> {
+        let mut ranges: Vec<(u16, u16, D)> = vec![];
+        for (port, device) in (0..=0xffff).zip(&self.ports) {
+            match (device, ranges.last_mut()) {
+                (Some(device), Some((_, end, last))) if *last == *device && *end + 1 == port => {
+                    *end = port
+                }
+                (Some(device), _) => ranges.push((port, port, *device)),
+                (None, _) => {}
+            }
+        }
+        ranges
+    }
+}
+
+/// Ports to stop the machine on when software reads or writes them, the
+/// way test harnesses have programs signal they're done. A write watch can
+/// be for one value written to the port only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PortWatch {
+    /// The values to stop on writes of, by port, `None` for any value.
+    writes: HashMap<u16, Vec<Option<u8>>>,
+    reads: HashSet<u16>,
+    /// The last access a watch caught.
+    last: Option<(u16, u8)>,
+    /// A watch has caught an access the run loop hasn't stopped for yet.
+    triggered: Option<Watch>,
+}
+
+impl PortWatch {
+    pub fn new() -> PortWatch {
+        PortWatch::default()
+    }
+
+    /// Watches for `value`, or any value, written to `port`.
+    pub fn watch(&mut self, port: u16, value: Option<u8>) {
+        let values = self.writes.entry(port).or_default();
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+
+    pub fn watch_reads(&mut self, port: u16) {
+        self.reads.insert(port);
+    }
+
+    /// Stops watching `port` for `kind` accesses.
+    pub fn unwatch(&mut self, port: u16, kind: Watch) {
+        if kind.covers(Watch::Read) {
+            self.reads.remove(&port);
+        }
+        if kind.covers(Watch::Write) {
+            self.writes.remove(&port);
+        }
+    }
+
+    /// Every watch, as the port, the access and the value written, in port
+    /// order.
+    pub fn watches(&self) -> Vec<(u16, Watch, Option<u8>)> {
+        let reads = self.reads.iter().map(|&port| (port, Watch::Read, None));
+        let writes = self.writes.iter().flat_map(|(&port, values)| {
+            values.iter().map(move |&value| (port, Watch::Write, value))
+        });
+        let mut watches: Vec<_> = reads.chain(writes).collect();
+        watches.sort();
+        watches
+    }
+
+    pub fn clear(&mut self) {
+        self.writes.clear();
+        self.reads.clear();
+        self.last = None;
+        self.triggered = None;
+    }
+
+    /// The board calls this for every byte read from a port.
+    pub fn rb(&mut self, addr: u16, data: u8) {
+        if !self.reads.is_empty() && self.reads.contains(&addr) {
+            self.last = Some((addr, data));
+            self.triggered = Some(Watch::Read);
+        }
+    }
+
+    /// The board calls this for every byte written to a port.
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        if self.writes.is_empty() {
+            return;
+        }
+        let hit = self.writes.get(&addr).is_some_and(|values| {
+            values
+                .iter()
+                .any(|value| value.is_none_or(|value| value == data))
+        });
+        if hit {
+            self.last = Some((addr, data));
+            self.triggered = Some(Watch::Write);
+        }
+    }
+
+    /// The port and value of the last access a watch caught.
+    pub fn last(&self) -> Option<(u16, u8)> {
+        self.last
+    }
+
+    /// Whether a watch has caught a read or a write since the last time
+    /// this was asked, for the run loop to stop on.
+    pub fn take_triggered(&mut self) -> Option<Watch> {
+        self.triggered.take()
+    }
+}
+
+#[test]
+fn test_io_bus_ranges() {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Card {
+        Pic,
+        Serial(usize),
+    }
+    let mut bus = IoBus::new();
+    bus.map(0x20, 0x21, Card::Pic);
+    bus.map(0x3f8, 0x3ff, Card::Serial(0));
+    assert_eq!(bus.device(0x1f), None);
+    assert_eq!(bus.device(0x21), Some(Card::Pic));
+    assert_eq!(bus.device(0x3ff), Some(Card::Serial(0)));
+    assert_eq!(bus.device(0xffff), None);
+
+    // A later mapping takes the ports it covers.
+    bus.map(0x3fc, 0x3fc, Card::Serial(1));
+    assert_eq!(bus.device(0x3fb), Some(Card::Serial(0)));
+    assert_eq!(bus.device(0x3fc), Some(Card::Serial(1)));
+    assert_eq!(
+        bus.ranges(),
+        [
+            (0x20, 0x21, Card::Pic),
+            (0x3f8, 0x3fb, Card::Serial(0)),
+            (0x3fc, 0x3fc, Card::Serial(1)),
+            (0x3fd, 0x3ff, Card::Serial(0)),
+        ]
+    );
+    bus.unmap(0x3f8, 0x3ff);
+    assert_eq!(bus.device(0x3fc), None);
+    bus.clear();
+    assert_eq!(bus.device(0x20), None);
+}
+
+#[test]
+fn test_port_watch() {
+    let mut watch = PortWatch::new();
+    watch.wb(0xe9, 0x42);
+    assert_eq!(watch.take_triggered(), None);
+    watch.watch(0xe9, Some(0x42));
+    watch.watch(0x402, None);
+    watch.wb(0xe9, 0x41);
+    assert_eq!(watch.take_triggered(), None);
+    watch.wb(0xe9, 0x42);
+    assert_eq!(watch.take_triggered(), Some(Watch::Write));
+    assert_eq!(watch.take_triggered(), None);
+    watch.wb(0x402, 0x07);
+    assert_eq!(watch.take_triggered(), Some(Watch::Write));
+    assert_eq!(watch.last(), Some((0x402, 0x07)));
+
+    watch.watch_reads(0x60);
+    watch.rb(0x61, 0x30);
+    assert_eq!(watch.take_triggered(), None);
+    watch.rb(0x60, 0x1c);
+    assert_eq!(watch.take_triggered(), Some(Watch::Read));
+    assert_eq!(
+        watch.watches(),
+        [
+            (0x60, Watch::Read, None),
+            (0xe9, Watch::Write, Some(0x42)),
+            (0x402, Watch::Write, None),
+        ]
+    );
+    watch.unwatch(0xe9, Watch::ReadWrite);
+    watch.wb(0xe9, 0x42);
+    assert_eq!(watch.take_triggered(), None);
+}
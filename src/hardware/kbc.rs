@@ -0,0 +1,342 @@
+use crate::hardware::keyboard::{set2_to_set1, AtKeyboard};
+use crate::hardware::ps2mouse::Ps2Mouse;
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace};
+
+/// Status register (port 64h) bits.
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_SYSTEM_FLAG: u8 = 0x04;
+const STATUS_COMMAND: u8 = 0x08;
+const STATUS_NOT_INHIBITED: u8 = 0x10;
+const STATUS_AUX_OUTPUT_FULL: u8 = 0x20;
+
+/// Command byte bits.
+const COMMAND_KEYBOARD_IRQ: u8 = 0x01;
+const COMMAND_AUX_IRQ: u8 = 0x02;
+const COMMAND_SYSTEM_FLAG: u8 = 0x04;
+const COMMAND_KEYBOARD_DISABLED: u8 = 0x10;
+const COMMAND_AUX_DISABLED: u8 = 0x20;
+const COMMAND_TRANSLATE: u8 = 0x40;
+
+/// Output port bits.
+const OUTPUT_NOT_RESET: u8 = 0x01;
+const OUTPUT_A20: u8 = 0x02;
+
+/// The AT's 8042 keyboard controller, as programmed by IBM's firmware. It
+/// sits between the keyboard and the CPU, translating set 2 scan codes to
+/// the set 1 codes PC software expects, and its spare output lines drive
+/// A20 and the CPU's reset. A PS/2 mouse can hang off its auxiliary port.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KBC {
+    pub keyboard: AtKeyboard,
+    pub mouse: Option<Ps2Mouse>,
+    pub output_buffer: u8,
+    pub status: u8,
+    /// Byte 0 of the controller's RAM.
+    pub command_byte: u8,
+    pub output_port: u8,
+    /// The key lock, on the input port. The board keeps it up to date.
+    pub inhibited: bool,
+    /// The rest of the controller's 32 bytes of RAM, which the BIOS can
+    /// read and write. Byte 0 is the command byte.
+    ram: [u8; 32],
+    /// A command written to port 64h waiting for its data on port 60h.
+    pending_command: Option<u8>,
+    /// A set 2 break prefix waiting for the code it belongs to.
+    break_prefix: bool,
+    reset_pulsed: bool,
+}
+
+impl KBC {
+    pub fn new() -> KBC {
+        KBC {
+            keyboard: AtKeyboard::new(),
+            mouse: None,
+            output_buffer: 0,
+            status: 0,
+            command_byte: 0,
+            output_port: 0xcf,
+            inhibited: false,
+            ram: [0; 32],
+            pending_command: None,
+            break_prefix: false,
+            reset_pulsed: false,
+        }
+    }
+
+    pub fn a20(&self) -> bool {
+        (self.output_port & OUTPUT_A20) != 0
+    }
+
+    /// Takes a request to reset the CPU made since the last call.
+    pub fn take_reset(&mut self) -> bool {
+        std::mem::take(&mut self.reset_pulsed)
+    }
+
+    /// IRQ 1, wired to the output port's "output buffer full" line.
+    pub fn irq1(&self) -> bool {
+        (self.command_byte & COMMAND_KEYBOARD_IRQ) != 0
+            && (self.status & (STATUS_OUTPUT_FULL | STATUS_AUX_OUTPUT_FULL)) == STATUS_OUTPUT_FULL
+    }
+
+    /// IRQ 12, the same for a byte from the auxiliary device.
+    pub fn irq12(&self) -> bool {
+        (self.command_byte & COMMAND_AUX_IRQ) != 0
+            && (self.status & (STATUS_OUTPUT_FULL | STATUS_AUX_OUTPUT_FULL))
+                == (STATUS_OUTPUT_FULL | STATUS_AUX_OUTPUT_FULL)
+    }
+
+    /// A key on the keyboard, by its set 1 make code. The key lock stops
+    /// keystrokes from getting through.
+    pub fn key(&mut self, scancode: u16, pressed: bool) {
+        if !self.inhibited {
+            self.keyboard.key(scancode, pressed);
+        }
+    }
+
+    fn fill_output(&mut self, data: u8, aux: bool) {
+        self.output_buffer = data;
+        self.status |= STATUS_OUTPUT_FULL;
+        if aux {
+            self.status |= STATUS_AUX_OUTPUT_FULL;
+        } else {
+            self.status &= !STATUS_AUX_OUTPUT_FULL;
+        }
+    }
+
+    /// Moves the next byte from the keyboard, or failing that the mouse,
+    /// into the output buffer, if the buffer is free and the device isn't
+    /// disabled.
+    pub fn poll(&mut self) {
+        while (self.status & STATUS_OUTPUT_FULL) == 0 {
+            let keyboard = match self.command_byte & COMMAND_KEYBOARD_DISABLED {
+                0 => self.keyboard.read(),
+                _ => None,
+            };
+            if let Some(code) = keyboard {
+                if (self.command_byte & COMMAND_TRANSLATE) == 0 {
+                    self.fill_output(code, false);
+                } else if code == 0xf0 {
+                    self.break_prefix = true;
+                } else {
+                    let code = set2_to_set1(code) | if self.break_prefix { 0x80 } else { 0 };
+                    self.break_prefix = false;
+                    self.fill_output(code, false);
+                }
+                continue;
+            }
+            if (self.command_byte & COMMAND_AUX_DISABLED) != 0 {
+                return;
+            }
+            match self.mouse.as_mut().and_then(Ps2Mouse::read) {
+                Some(data) => self.fill_output(data, true),
+                None => return,
+            }
+        }
+    }
+
+    fn input_port(&self) -> u8 {
+        // No manufacturing jumper, 512K on the system board.
+        let not_inhibited = if self.inhibited { 0 } else { 0x80 };
+        not_inhibited | 0x30
+    }
+
+    fn write_command_byte(&mut self, data: u8) {
+        self.command_byte = data;
+        self.ram[0] = data;
+        if (data & COMMAND_SYSTEM_FLAG) != 0 {
+            self.status |= STATUS_SYSTEM_FLAG;
+        } else {
+            self.status &= !STATUS_SYSTEM_FLAG;
+        }
+    }
+
+    fn write_output_port(&mut self, data: u8) {
+        if (data & OUTPUT_NOT_RESET) == 0 {
+            debug!(target: "io", "8042 reset the CPU");
+            self.reset_pulsed = true;
+        }
+        // The reset line doesn't stay low; the firmware lets it go again.
+        self.output_port = data | OUTPUT_NOT_RESET;
+    }
+
+    fn command(&mut self, command: u8) {
+        trace!(target: "io", "8042 command {:#04x}", command);
+        match command {
+            0x20..=0x3f => {
+                let value = self.ram[(command & 0x1f) as usize];
+                self.fill_output(value, false);
+            }
+            0x60..=0x7f | 0xd1..=0xd4 => self.pending_command = Some(command),
+            0xa7 => self.command_byte |= COMMAND_AUX_DISABLED,
+            0xa8 => self.command_byte &= !COMMAND_AUX_DISABLED,
+            0xa9 | 0xab => self.fill_output(0x00, false),
+            0xaa => self.fill_output(0x55, false),
+            0xad => self.command_byte |= COMMAND_KEYBOARD_DISABLED,
+            0xae => self.command_byte &= !COMMAND_KEYBOARD_DISABLED,
+            0xc0 => {
+                let value = self.input_port();
+                self.fill_output(value, false);
+            }
+            0xd0 => self.fill_output(self.output_port, false),
+            0xdd => self.output_port &= !OUTPUT_A20,
+            0xdf => self.output_port |= OUTPUT_A20,
+            0xe0 => self.fill_output(0x00, false),
+            // Pulse the output port's low four bits that are clear in the
+            // command. Only the reset line is wired to anything.
+            0xf0..=0xff => {
+                if (command & OUTPUT_NOT_RESET) == 0 {
+                    debug!(target: "io", "8042 reset the CPU");
+                    self.reset_pulsed = true;
+                }
+            }
+            _ => debug!(target: "io", "Unknown 8042 command {:#04x}", command),
+        }
+        self.ram[0] = self.command_byte;
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        if (addr & 4) != 0 {
+            let not_inhibited = if self.inhibited {
+                0
+            } else {
+                STATUS_NOT_INHIBITED
+            };
+            return (self.status & !STATUS_NOT_INHIBITED) | not_inhibited;
+        }
+        self.status &= !(STATUS_OUTPUT_FULL | STATUS_AUX_OUTPUT_FULL);
+        self.output_buffer
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        if (addr & 4) != 0 {
+            self.status |= STATUS_COMMAND;
+            self.pending_command = None;
+            self.command(data);
+            return;
+        }
+        self.status &= !STATUS_COMMAND;
+        match self.pending_command.take() {
+            Some(0x60) => self.write_command_byte(data),
+            Some(command @ 0x61..=0x7f) => self.ram[(command & 0x1f) as usize] = data,
+            Some(0xd1) => self.write_output_port(data),
+            Some(0xd2) => self.fill_output(data, false),
+            Some(0xd3) => self.fill_output(data, true),
+            Some(0xd4) => {
+                // Like the keyboard, the mouse's clock line comes back on.
+                self.command_byte &= !COMMAND_AUX_DISABLED;
+                self.ram[0] = self.command_byte;
+                match &mut self.mouse {
+                    Some(mouse) => mouse.write(data),
+                    None => debug!(target: "io", "No auxiliary device for {:#04x}", data),
+                }
+            }
+            _ => {
+                // Data with no command goes to the keyboard, which wakes
+                // up if it was disabled.
+                self.command_byte &= !COMMAND_KEYBOARD_DISABLED;
+                self.keyboard.write(data);
+            }
+        }
+    }
+}
+
+impl Default for KBC {
+    fn default() -> KBC {
+        KBC::new()
+    }
+}
+
+// A warm reset comes from the 8042 itself, so it can't reset the 8042. The
+// keyboard and mouse have their own power-on resets.
+impl Reset for KBC {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind != ResetKind::Warm {
+            let keyboard = self.keyboard.clone();
+            let mouse = self.mouse.take();
+            let inhibited = self.inhibited;
+            *self = KBC::new();
+            self.keyboard = keyboard;
+            self.mouse = mouse;
+            self.inhibited = inhibited;
+        }
+        self.keyboard.reset(kind);
+        if let Some(mouse) = &mut self.mouse {
+            mouse.reset(kind);
+        }
+    }
+}
+
+#[test]
+fn test_8042_and_keyboard() {
+    let mut kbc = KBC::new();
+    let command = |kbc: &mut KBC, byte: u8| {
+        kbc.wb(0x64, byte);
+        kbc.poll();
+    };
+    command(&mut kbc, 0xaa);
+    assert_eq!(kbc.rb(0x64) & 0x19, 0x19);
+    assert_eq!(kbc.rb(0x60), 0x55);
+    assert_eq!(kbc.rb(0x64) & 0x01, 0x00);
+
+    // What the BIOS sets up: translation, IRQ 1, system flag.
+    command(&mut kbc, 0x60);
+    kbc.wb(0x60, 0x45);
+    command(&mut kbc, 0x20);
+    assert_eq!(kbc.rb(0x60), 0x45);
+    assert_eq!(kbc.rb(0x64) & 0x04, 0x04);
+
+    // Resetting the keyboard, then setting the LEDs and typematic rate.
+    kbc.wb(0x60, 0xff);
+    kbc.poll();
+    assert!(kbc.irq1());
+    assert_eq!(kbc.rb(0x60), 0xfa);
+    assert!(!kbc.irq1());
+    kbc.poll();
+    assert_eq!(kbc.rb(0x60), 0xaa);
+    for data in [0xed, 0x04, 0xf3, 0x20].iter() {
+        kbc.wb(0x60, *data);
+        kbc.poll();
+        assert_eq!(kbc.rb(0x60), 0xfa);
+    }
+    assert_eq!((kbc.keyboard.leds, kbc.keyboard.typematic), (0x04, 0x20));
+
+    // The keyboard sends 1Ch and F0h 1Ch for A; the CPU sees 1Eh and 9Eh.
+    kbc.key(0x1e, true);
+    kbc.key(0x1e, false);
+    assert_eq!(kbc.keyboard.output, [0x1c, 0xf0, 0x1c]);
+    kbc.poll();
+    assert_eq!(kbc.rb(0x60), 0x1e);
+    kbc.poll();
+    assert_eq!(kbc.rb(0x60), 0x9e);
+
+    // The key lock holds keystrokes back.
+    kbc.inhibited = true;
+    kbc.key(0x1e, true);
+    kbc.poll();
+    assert_eq!(kbc.rb(0x64) & 0x11, 0x00);
+
+    // A20 and reset through the output port.
+    command(&mut kbc, 0xd1);
+    kbc.wb(0x60, 0xdd);
+    assert!(!kbc.a20());
+    assert!(!kbc.take_reset());
+    command(&mut kbc, 0xfe);
+    assert!(kbc.take_reset());
+    kbc.reset(ResetKind::Warm);
+    assert!(!kbc.a20());
+    assert_eq!(kbc.command_byte, 0x45);
+
+    // A mouse on the auxiliary port answers through the output buffer
+    // with the aux bit set, and on IRQ 12 rather than IRQ 1.
+    kbc.mouse = Some(Ps2Mouse::new());
+    command(&mut kbc, 0x60);
+    kbc.wb(0x60, 0x47);
+    command(&mut kbc, 0xd4);
+    kbc.wb(0x60, 0xf4);
+    kbc.poll();
+    assert_eq!(kbc.rb(0x64) & 0x21, 0x21);
+    assert!(kbc.irq12() && !kbc.irq1());
+    assert_eq!(kbc.rb(0x60), 0xfa);
+    assert!(!kbc.irq12());
+}
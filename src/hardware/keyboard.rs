@@ -0,0 +1,295 @@
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace};
+use std::collections::VecDeque;
+
+/// Scan code set 2 for each set 1 make code from 00h to 58h, in other
+/// words every key on an 84 or 102 key keyboard that doesn't need an E0h
+/// prefix. 0 means there's no such key.
+#[rustfmt::skip]
+const SET1_TO_SET2: [u8; 0x59] = [
+//   0     1     2     3     4     5     6     7     8     9     a     b     c     d     e     f
+    0x00, 0x76, 0x16, 0x1e, 0x26, 0x25, 0x2e, 0x36, 0x3d, 0x3e, 0x46, 0x45, 0x4e, 0x55, 0x66, 0x0d, // 0
+    0x15, 0x1d, 0x24, 0x2d, 0x2c, 0x35, 0x3c, 0x43, 0x44, 0x4d, 0x54, 0x5b, 0x5a, 0x14, 0x1c, 0x1b, // 1
+    0x23, 0x2b, 0x34, 0x33, 0x3b, 0x42, 0x4b, 0x4c, 0x52, 0x0e, 0x12, 0x5d, 0x1a, 0x22, 0x21, 0x2a, // 2
+    0x32, 0x31, 0x3a, 0x41, 0x49, 0x4a, 0x59, 0x7c, 0x11, 0x29, 0x58, 0x05, 0x06, 0x04, 0x0c, 0x03, // 3
+    0x0b, 0x83, 0x0a, 0x01, 0x09, 0x77, 0x7e, 0x6c, 0x75, 0x7d, 0x7b, 0x6b, 0x73, 0x74, 0x79, 0x69, // 4
+    0x72, 0x7a, 0x70, 0x71, 0x84, 0x00, 0x61, 0x78, 0x07,                                           // 5
+];
+
+/// The Windows and Menu keys, the only ones past 58h, as set 1 and set 2
+/// codes. Both send E0h first.
+const WINDOWS_KEYS: [(u8, u8); 3] = [(0x5b, 0x1f), (0x5c, 0x27), (0x5d, 0x2f)];
+
+/// Print Screen and Pause, as `InputEvent::Key` has them. Neither sends
+/// an E0h-prefixed code like the other keys 101-key keyboards added, but
+/// a sequence of its own.
+pub const KEY_PRINT_SCREEN: u16 = 0xe037;
+pub const KEY_PAUSE: u16 = 0xe11d;
+
+/// The set 2 code for a set 1 make code, if the key exists.
+pub fn set1_to_set2(code: u8) -> Option<u8> {
+    SET1_TO_SET2
+        .get(code as usize)
+        .copied()
+        .filter(|&code| code != 0)
+        .or_else(|| {
+            WINDOWS_KEYS
+                .iter()
+                .find(|&&(set1, _)| set1 == code)
+                .map(|&(_, set2)| set2)
+        })
+}
+
+/// The reverse, as the 8042 does it when translation is on. Codes without
+/// a key behind them pass through unchanged.
+pub fn set2_to_set1(code: u8) -> u8 {
+    SET1_TO_SET2
+        .iter()
+        .position(|&set2| set2 == code && code != 0)
+        .map(|set1| set1 as u8)
+        .or_else(|| {
+            WINDOWS_KEYS
+                .iter()
+                .find(|&&(_, set2)| set2 == code)
+                .map(|&(set1, _)| set1)
+        })
+        .unwrap_or(code)
+}
+
+/// The set 1 bytes a key sends going down or up, for a set 1 make code
+/// with E0h in the high byte if the key has one. Pause sends it all going
+/// down and nothing coming up.
+pub fn set1_sequence(scancode: u16, pressed: bool) -> Vec<u8> {
+    let code = scancode as u8 | if pressed { 0 } else { 0x80 };
+    match (scancode, pressed) {
+        (KEY_PAUSE, true) => vec![0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5],
+        (KEY_PAUSE, false) => vec![],
+        (KEY_PRINT_SCREEN, true) => vec![0xe0, 0x2a, 0xe0, 0x37],
+        (KEY_PRINT_SCREEN, false) => vec![0xe0, 0xb7, 0xe0, 0xaa],
+        (0x00..=0xff, _) => vec![code],
+        (0xe000..=0xe0ff, _) => vec![0xe0, code],
+        _ => vec![],
+    }
+}
+
+/// The same in set 2, if the key exists.
+pub fn set2_sequence(scancode: u16, pressed: bool) -> Option<Vec<u8>> {
+    let release: &[u8] = if pressed { &[] } else { &[0xf0] };
+    match (scancode, pressed) {
+        (KEY_PAUSE, true) => Some(vec![0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77]),
+        (KEY_PAUSE, false) => Some(vec![]),
+        (KEY_PRINT_SCREEN, true) => Some(vec![0xe0, 0x12, 0xe0, 0x7c]),
+        (KEY_PRINT_SCREEN, false) => Some(vec![0xe0, 0xf0, 0x7c, 0xe0, 0xf0, 0x12]),
+        (0x00..=0xff, _) => {
+            let code = set1_to_set2(scancode as u8)?;
+            Some([release, &[code]].concat())
+        }
+        (0xe000..=0xe0ff, _) => {
+            let code = set1_to_set2(scancode as u8)?;
+            Some([&[0xe0], release, &[code]].concat())
+        }
+        _ => None,
+    }
+}
+
+/// The typematic rate and delay the keyboard comes up with: 10.9
+/// characters a second after half a second.
+const DEFAULT_TYPEMATIC: u8 = 0x2b;
+
+/// An AT (84 key) or MF2 keyboard. It talks scan code set 2 and answers
+/// the commands the BIOS and DOS send it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AtKeyboard {
+    /// Bytes waiting to go to the controller.
+    pub output: VecDeque<u8>,
+    pub scanning: bool,
+    /// Scroll, Num and Caps Lock, in bits 0-2.
+    pub leds: u8,
+    /// Bits 0-4 are the repeat rate, 5-6 the delay.
+    pub typematic: u8,
+    /// A command waiting for its parameter byte.
+    pending_command: Option<u8>,
+    last_sent: u8,
+}
+
+impl AtKeyboard {
+    pub fn new() -> AtKeyboard {
+        AtKeyboard {
+            output: VecDeque::new(),
+            scanning: true,
+            leds: 0,
+            typematic: DEFAULT_TYPEMATIC,
+            pending_command: None,
+            last_sent: 0,
+        }
+    }
+
+    fn set_defaults(&mut self) {
+        self.typematic = DEFAULT_TYPEMATIC;
+        self.output.clear();
+    }
+
+    /// A key going down or up, by its set 1 make code.
+    pub fn key(&mut self, scancode: u16, pressed: bool) {
+        if !self.scanning {
+            return;
+        }
+        match set2_sequence(scancode, pressed) {
+            Some(bytes) => self.output.extend(bytes),
+            None => debug!(target: "io", "No set 2 code for key {:#06x}", scancode),
+        }
+    }
+
+    /// The next byte for the controller.
+    pub fn read(&mut self) -> Option<u8> {
+        let byte = self.output.pop_front()?;
+        self.last_sent = byte;
+        Some(byte)
+    }
+
+    /// A byte from the controller: a command, or a command's parameter.
+    pub fn write(&mut self, data: u8) {
+        trace!(target: "io", "Keyboard command {:#04x}", data);
+        if let Some(command) = self.pending_command.take() {
+            // A command byte in place of the parameter cancels the wait and
+            // runs as a command instead.
+            if data < 0xed {
+                match command {
+                    0xed => self.leds = data & 7,
+                    0xf3 => self.typematic = data & 0x7f,
+                    // Only set 2 is supported. Asking which set is in use
+                    // gets an extra byte back.
+                    _ if data == 0 => {
+                        self.output.push_back(0xfa);
+                        self.output.push_back(0x02);
+                        return;
+                    }
+                    _ => {}
+                }
+                self.output.push_back(0xfa);
+                return;
+            }
+        }
+        match data {
+            0xed | 0xf0 | 0xf3 => {
+                self.pending_command = Some(data);
+                self.output.push_back(0xfa);
+            }
+            0xee => self.output.push_back(0xee),
+            0xf2 => self.output.extend([0xfa, 0xab, 0x83].iter()),
+            0xf4 => {
+                self.output.clear();
+                self.scanning = true;
+                self.output.push_back(0xfa);
+            }
+            0xf5 => {
+                self.set_defaults();
+                self.scanning = false;
+                self.output.push_back(0xfa);
+            }
+            0xf6 => {
+                self.set_defaults();
+                self.output.push_back(0xfa);
+            }
+            0xfe => self.output.push_back(self.last_sent),
+            0xff => {
+                // The basic assurance test always passes.
+                *self = AtKeyboard::new();
+                self.output.extend([0xfa, 0xaa].iter());
+            }
+            _ => {
+                debug!(target: "io", "Unknown keyboard command {:#04x}", data);
+                self.output.push_back(0xfe);
+            }
+        }
+    }
+}
+
+impl Default for AtKeyboard {
+    fn default() -> AtKeyboard {
+        AtKeyboard::new()
+    }
+}
+
+// The keyboard's own microcontroller only resets when it loses power.
+impl Reset for AtKeyboard {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            *self = AtKeyboard::new();
+        }
+    }
+}
+
+/// The PC and XT keyboard. It sends set 1 codes one way, down a clock and
+/// data line, and has no commands; the only thing the PC can do to it is
+/// hold the clock line low, which resets it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct XtKeyboard {
+    pub output: VecDeque<u8>,
+    clock: bool,
+}
+
+impl XtKeyboard {
+    pub fn new() -> XtKeyboard {
+        XtKeyboard {
+            output: VecDeque::new(),
+            clock: true,
+        }
+    }
+
+    /// A key going down or up. The 83-key keyboard had no E0h keys, but
+    /// the clone keyboards that did work with XTs, whose BIOS ignores E0h.
+    pub fn key(&mut self, scancode: u16, pressed: bool) {
+        self.output.extend(set1_sequence(scancode, pressed));
+    }
+
+    /// Releasing the clock line after holding it low makes the keyboard
+    /// run its self test and send AAh.
+    pub fn set_clock(&mut self, clock: bool) {
+        if clock && !self.clock {
+            trace!(target: "io", "XT keyboard reset");
+            self.output.clear();
+            self.output.push_back(0xaa);
+        }
+        self.clock = clock;
+    }
+
+    pub fn read(&mut self) -> Option<u8> {
+        if self.clock {
+            self.output.pop_front()
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for XtKeyboard {
+    fn default() -> XtKeyboard {
+        XtKeyboard::new()
+    }
+}
+
+#[test]
+fn test_key_sequences() {
+    let mut xt = XtKeyboard::new();
+    xt.key(0x1e, true);
+    xt.key(0xe048, false);
+    xt.key(KEY_PAUSE, true);
+    xt.key(KEY_PAUSE, false);
+    assert_eq!(
+        xt.output,
+        [0x1e, 0xe0, 0xc8, 0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5]
+    );
+
+    let mut at = AtKeyboard::new();
+    at.key(0xe01c, false);
+    at.key(0xe05b, true);
+    at.key(KEY_PRINT_SCREEN, true);
+    at.key(0x7f, true);
+    assert_eq!(
+        at.output,
+        [0xe0, 0xf0, 0x5a, 0xe0, 0x1f, 0xe0, 0x12, 0xe0, 0x7c]
+    );
+    assert_eq!(set2_to_set1(0x1f), 0x5b);
+    assert_eq!(set2_to_set1(0xe1), 0xe1);
+}
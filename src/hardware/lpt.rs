@@ -0,0 +1,236 @@
+// The parallel ports: an IBM-style output-only printer port at each of
+// LPT1-LPT3. The data and control registers are latches whose outputs go
+// to a `ParallelBackend`, and the status register reads the backend's
+// lines. With nothing connected the inputs float high, which the BIOS sees
+// as a printer that is switched off. The port interrupts on the printer's
+// acknowledge pulse when the guest enables it.
+use crate::hardware::passthrough::ParallelBackend;
+use crate::hardware::reset::{Reset, ResetKind};
+use std::fmt;
+
+/// The base port and IRQ of each port, in the order the BIOS numbers them
+/// when there's no port on a monochrome card at 3BCh. IBM's adapters could
+/// only use IRQ 7.
+pub const LPT_PORTS: [(u16, u8); 3] = [(0x378, 7), (0x278, 7), (0x3bc, 7)];
+
+/// Status register bits.
+const STATUS_ERROR: u8 = 0x08;
+const STATUS_SELECT: u8 = 0x10;
+const STATUS_PAPER_OUT: u8 = 0x20;
+const STATUS_ACK: u8 = 0x40;
+const STATUS_NOT_BUSY: u8 = 0x80;
+/// The low bits aren't wired and read as 1s.
+const STATUS_UNUSED: u8 = 0x07;
+
+/// Control register bits. STROBE, AUTOFEED and SELECT IN are inverted on
+/// their way to the connector, so a 1 asserts them.
+const CONTROL_STROBE: u8 = 0x01;
+const CONTROL_AUTOFEED: u8 = 0x02;
+const CONTROL_INIT: u8 = 0x04;
+const CONTROL_SELECT_IN: u8 = 0x08;
+const CONTROL_IRQ: u8 = 0x10;
+
+/// What's plugged into a port. A copy of the machine comes up with
+/// nothing plugged in, as two ports can't share one host device.
+#[derive(Default)]
+pub struct ParallelConnection(Option<Box<dyn ParallelBackend>>);
+
+impl Clone for ParallelConnection {
+    fn clone(&self) -> ParallelConnection {
+        ParallelConnection(None)
+    }
+}
+
+impl fmt::Debug for ParallelConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "ParallelConnection(connected)"),
+            None => write!(f, "ParallelConnection(none)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LPT {
+    pub base: u16,
+    pub irq: u8,
+    backend: ParallelConnection,
+    data: u8,
+    control: u8,
+    /// The acknowledge line was last seen active.
+    ack: bool,
+    /// An acknowledge pulse since the status register was last read.
+    ack_pending: bool,
+}
+
+impl LPT {
+    pub fn new(base: u16, irq: u8) -> LPT {
+        LPT {
+            base,
+            irq,
+            backend: ParallelConnection::default(),
+            data: 0,
+            control: CONTROL_INIT,
+            ack: false,
+            ack_pending: false,
+        }
+    }
+
+    /// LPT1-LPT3, counting from 0.
+    pub fn port(port: usize) -> LPT {
+        let (base, irq) = LPT_PORTS[port];
+        LPT::new(base, irq)
+    }
+
+    /// Plugs the port into `backend`, handing it the current outputs.
+    pub fn connect(&mut self, mut backend: Box<dyn ParallelBackend>) {
+        backend.write_data(self.data);
+        backend.write_control(self.control);
+        self.backend = ParallelConnection(Some(backend));
+        self.ack = false;
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn ParallelBackend>> {
+        self.backend.0.take()
+    }
+
+    pub fn claims(&self, addr: u16) -> bool {
+        addr.wrapping_sub(self.base) < 3
+    }
+
+    pub fn irq(&self) -> bool {
+        (self.control & CONTROL_IRQ) != 0 && self.ack_pending
+    }
+
+    fn status(&mut self) -> u8 {
+        let status = match &mut self.backend.0 {
+            Some(backend) => backend.read_status(),
+            None => !STATUS_NOT_BUSY,
+        };
+        status | STATUS_UNUSED
+    }
+
+    /// Watches the acknowledge line for the start of a pulse.
+    fn sample_ack(&mut self) -> u8 {
+        let status = self.status();
+        let ack = (status & STATUS_ACK) == 0;
+        if ack && !self.ack {
+            self.ack_pending = true;
+        }
+        self.ack = ack;
+        status
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr.wrapping_sub(self.base) {
+            0 => self.data,
+            1 => {
+                let status = self.sample_ack();
+                self.ack_pending = false;
+                status
+            }
+            _ => self.control | 0xe0,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr.wrapping_sub(self.base) {
+            0 => {
+                self.data = data;
+                if let Some(backend) = &mut self.backend.0 {
+                    backend.write_data(data);
+                }
+            }
+            2 => {
+                self.control = data & 0x1f;
+                if let Some(backend) = &mut self.backend.0 {
+                    backend.write_control(self.control);
+                }
+            }
+            _ => return,
+        }
+        self.sample_ack();
+    }
+}
+
+impl Reset for LPT {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        let backend = self.disconnect();
+        *self = LPT::new(self.base, self.irq);
+        if let Some(backend) = backend {
+            self.connect(backend);
+        }
+    }
+}
+
+/// A wrap plug, which diagnostics use to test a port without a printer.
+/// It's wired the usual way: D0 to ERROR, STROBE to SELECT, AUTOFEED to
+/// PAPER OUT, INIT to ACK and SELECT IN to BUSY.
+#[derive(Debug, Clone, Default)]
+pub struct LoopbackPlug {
+    data: u8,
+    control: u8,
+}
+
+impl LoopbackPlug {
+    pub fn new() -> LoopbackPlug {
+        LoopbackPlug::default()
+    }
+}
+
+impl ParallelBackend for LoopbackPlug {
+    fn write_data(&mut self, value: u8) {
+        self.data = value;
+    }
+
+    fn write_control(&mut self, value: u8) {
+        self.control = value;
+    }
+
+    fn read_status(&mut self) -> u8 {
+        // Both ends of each wire count the same inversions, so only the
+        // line levels matter.
+        let wired = [
+            ((self.data & 0x01) != 0, STATUS_ERROR),
+            ((self.control & CONTROL_STROBE) == 0, STATUS_SELECT),
+            ((self.control & CONTROL_AUTOFEED) == 0, STATUS_PAPER_OUT),
+            ((self.control & CONTROL_INIT) != 0, STATUS_ACK),
+            ((self.control & CONTROL_SELECT_IN) != 0, STATUS_NOT_BUSY),
+        ];
+        wired
+            .iter()
+            .filter(|(high, _)| *high)
+            .fold(0, |status, (_, bit)| status | bit)
+    }
+}
+
+#[test]
+fn test_parallel_port_loopback_and_ack() {
+    let mut lpt = LPT::port(0);
+    // Nothing plugged in looks like a printer that's switched off.
+    assert_eq!(lpt.rb(0x379), 0x7f);
+
+    lpt.connect(Box::new(LoopbackPlug::new()));
+    lpt.wb(0x378, 0x01);
+    lpt.wb(0x37a, CONTROL_INIT);
+    assert_eq!(lpt.rb(0x379), 0x7f);
+    lpt.wb(0x378, 0x00);
+    lpt.wb(
+        0x37a,
+        CONTROL_INIT | CONTROL_STROBE | CONTROL_AUTOFEED | CONTROL_SELECT_IN,
+    );
+    assert_eq!(lpt.rb(0x379), STATUS_NOT_BUSY | STATUS_ACK | STATUS_UNUSED);
+    assert_eq!(lpt.rb(0x37a), 0xef);
+
+    // Pulling INIT low acknowledges through the plug, which interrupts
+    // once the guest enables it.
+    lpt.wb(0x37a, CONTROL_IRQ | CONTROL_INIT);
+    assert!(!lpt.irq());
+    lpt.wb(0x37a, CONTROL_IRQ);
+    assert!(lpt.irq());
+    lpt.rb(0x379);
+    assert!(!lpt.irq());
+}
@@ -0,0 +1,1193 @@
+// A built machine, whichever board it has, and what a frontend does with
+// it: running it a frame at a time, debugging it, feeding it input,
+// drawing its display, changing media and fitting cards. The work happens
+// on the `PcMachine` inside; this only sends each call to the right one.
+use crate::cpu::{Cpu, CpuState};
+use crate::cpu286::Cpu286Context;
+use crate::cpu8086::Cpu8086Context;
+use crate::disasm::{self, Instruction, Isa};
+use crate::hardware::breakpoints::{Breakpoints, MemoryWatch};
+use crate::hardware::cdrom::CdImage;
+use crate::hardware::ems::EmsConfig;
+use crate::hardware::fdc::FDC;
+use crate::hardware::floppy::FloppyMedia;
+use crate::hardware::gameport::GamePort;
+use crate::hardware::harddisk::HardDisk;
+use crate::hardware::ibmpc5150machine::PcIo;
+use crate::hardware::ibmpcatmachine::AtIo;
+use crate::hardware::io::PortWatch;
+use crate::hardware::lpt::LPT;
+use crate::hardware::memory::UpperMemory;
+use crate::hardware::mpu401::MPU401;
+use crate::hardware::ne2000::NE2000;
+use crate::hardware::opl2::OPL2;
+use crate::hardware::passthrough::NetworkBackend;
+use crate::hardware::perfcounter::PerfCounter;
+use crate::hardware::ps2mouse::Ps2Mouse;
+use crate::hardware::reset::ResetKind;
+use crate::hardware::rom::OptionRom;
+use crate::hardware::soundblaster::{SbModel, SoundBlaster};
+use crate::hardware::uart::{UartModel, UART};
+use crate::hardware::video::cga::CGA;
+use crate::hardware::video::ega::EGA;
+use crate::hardware::video::mda::MDA;
+use crate::hardware::{
+    IbmPc5150Hardware, IbmPc5150Machine, IbmPcAtHardware, IbmPcAtMachine, Motherboard, RunEvent,
+    StopReason,
+};
+use crate::input::joystick::VirtualJoystick;
+use crate::input::InputEvent;
+use crate::renderer::{screenshot, Frame};
+use crate::trace::Tracer;
+use std::fmt;
+use std::mem;
+use std::path::Path;
+
+/// The cards that can be fitted or taken out of a built machine, by the
+/// frontend or the control API.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IsaCard {
+    Adlib,
+    SoundBlaster(SbModel),
+    Mpu401,
+    GamePort,
+    /// COM1-COM4, counting from 0.
+    Serial(usize),
+    /// LPT1-LPT3, counting from 0.
+    Parallel(usize),
+    PerfCounter,
+    /// An expanded memory board.
+    Ems(EmsConfig),
+}
+
+impl IsaCard {
+    /// Whether the guest only finds out about the change at the next
+    /// reset. The BIOS looks for serial and parallel ports and the game
+    /// port during POST and records them in its data area; drivers probe
+    /// for the other cards when they load.
+    pub fn needs_reset(self) -> bool {
+        matches!(
+            self,
+            IsaCard::GamePort | IsaCard::Serial(_) | IsaCard::Parallel(_)
+        )
+    }
+}
+
+impl fmt::Display for IsaCard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IsaCard::Adlib => write!(f, "AdLib"),
+            IsaCard::SoundBlaster(SbModel::Sb2) => write!(f, "Sound Blaster 2.0"),
+            IsaCard::SoundBlaster(SbModel::Pro) => write!(f, "Sound Blaster Pro"),
+            IsaCard::Mpu401 => write!(f, "MPU-401"),
+            IsaCard::GamePort => write!(f, "game port"),
+            IsaCard::Serial(port) => write!(f, "COM{}", port + 1),
+            IsaCard::Parallel(port) => write!(f, "LPT{}", port + 1),
+            IsaCard::PerfCounter => write!(f, "performance counter card"),
+            IsaCard::Ems(config) => write!(f, "{}K EMS board", config.size_kb),
+        }
+    }
+}
+
+/// Which drives are busy, for a frontend's activity lights. A floppy
+/// drive's light is its motor's; the hard disk's is lit by any sector read
+/// or written since the lights were last looked at.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DriveLights {
+    pub floppy: [bool; 2],
+    pub hard_disk: bool,
+}
+
+/// A machine built from a template. Boxed, since the two boards carry
+/// very different amounts of hardware.
+#[derive(Clone, Debug)]
+pub enum Machine {
+    Pc(Box<IbmPc5150Machine>),
+    At(Box<IbmPcAtMachine>),
+}
+
+impl Machine {
+    pub fn run_until(&mut self, event: RunEvent) -> StopReason {
+        match self {
+            Machine::Pc(machine) => machine.run_until(event),
+            Machine::At(machine) => machine.run_until(event),
+        }
+    }
+
+    /// Runs until the display has been drawn once more, or something
+    /// stops the CPU first.
+    pub fn run_frame(&mut self) -> StopReason {
+        self.run_until(RunEvent::FrameComplete)
+    }
+
+    pub fn run_instructions(&mut self, count: usize) -> StopReason {
+        match self {
+            Machine::Pc(machine) => machine.run_instructions(count),
+            Machine::At(machine) => machine.run_instructions(count),
+        }
+    }
+
+    /// Instructions run since the machine was made, counting each step the
+    /// CPU spends halted as one.
+    pub fn instructions(&self) -> u64 {
+        match self {
+            Machine::Pc(machine) => machine.instructions,
+            Machine::At(machine) => machine.instructions,
+        }
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        match self {
+            Machine::Pc(machine) => Cpu::<IbmPc5150Hardware>::snapshot(&machine.cpu),
+            Machine::At(machine) => Cpu::<IbmPcAtHardware>::snapshot(&machine.cpu),
+        }
+    }
+
+    /// Changes the registers, as a debugger does.
+    pub fn set_cpu_state(&mut self, state: &CpuState) {
+        match self {
+            Machine::Pc(machine) => Cpu::<IbmPc5150Hardware>::restore(&mut machine.cpu, state),
+            Machine::At(machine) => Cpu::<IbmPcAtHardware>::restore(&mut machine.cpu, state),
+        }
+    }
+
+    /// Reads memory the way the CPU would, cards and all, so a read from a
+    /// card's memory is one the card sees. Watchpoints don't, as it isn't
+    /// the CPU reading.
+    pub fn read_memory(&mut self, addr: u32) -> u8 {
+        match self {
+            Machine::Pc(machine) => machine.hardware.peek(addr),
+            Machine::At(machine) => machine.hardware.peek(addr),
+        }
+    }
+
+    /// Writes memory the way the CPU would, but for watchpoints.
+    pub fn write_memory(&mut self, addr: u32, value: u8) {
+        let watch = mem::take(self.memory_watch_mut());
+        match self {
+            Machine::Pc(machine) => {
+                Cpu8086Context::mem_write_byte(&mut machine.hardware, addr, value)
+            }
+            Machine::At(machine) => {
+                Cpu286Context::mem_write_byte(&mut machine.hardware, addr, value)
+            }
+        }
+        *self.memory_watch_mut() = watch;
+    }
+
+    /// Which device answers for which ports, as the first and last port
+    /// and the device's name, in port order.
+    pub fn io_map(&self) -> Vec<(u16, u16, String)> {
+        match self {
+            Machine::Pc(machine) => {
+                let hardware = &machine.hardware;
+                let name = |device| match device {
+                    PcIo::Device(index) => hardware.common.devices[index].name().to_string(),
+                    device => format!("{:?}", device),
+                };
+                let ranges = hardware.io.ranges().into_iter();
+                ranges
+                    .map(|(start, end, device)| (start, end, name(device)))
+                    .collect()
+            }
+            Machine::At(machine) => {
+                let hardware = &machine.hardware;
+                let name = |device| match device {
+                    AtIo::Device(index) => hardware.common.devices[index].name().to_string(),
+                    device => format!("{:?}", device),
+                };
+                let ranges = hardware.io.ranges().into_iter();
+                ranges
+                    .map(|(start, end, device)| (start, end, name(device)))
+                    .collect()
+            }
+        }
+    }
+
+    /// The instruction set the CPU runs.
+    pub fn isa(&self) -> Isa {
+        match self {
+            Machine::Pc(_) => Isa::I8086,
+            Machine::At(_) => Isa::I286,
+        }
+    }
+
+    /// Starts an instruction trace, finishing any trace that was running.
+    pub fn start_trace(&mut self, tracer: Tracer) -> Result<(), String> {
+        self.stop_trace()?;
+        match self {
+            Machine::Pc(machine) => machine.trace.0 = Some(tracer),
+            Machine::At(machine) => machine.trace.0 = Some(tracer),
+        }
+        Ok(())
+    }
+
+    /// Finishes the instruction trace, if there is one, returning how many
+    /// instructions went in it.
+    pub fn stop_trace(&mut self) -> Result<Option<u64>, String> {
+        let tracer = match self {
+            Machine::Pc(machine) => machine.trace.0.take(),
+            Machine::At(machine) => machine.trace.0.take(),
+        };
+        tracer.map(Tracer::finish).transpose()
+    }
+
+    /// The `count` instructions from `cs`:`ip` on. The CPU's own CS is
+    /// found wherever it points, protected mode or not; any other segment
+    /// is taken as a real mode one.
+    pub fn disassemble(&mut self, cs: u16, ip: u16, count: usize) -> Vec<Instruction> {
+        let isa = self.isa();
+        let (current, base) = match self {
+            Machine::Pc(machine) => (
+                Cpu::<IbmPc5150Hardware>::program_counter(&machine.cpu).0,
+                Cpu::<IbmPc5150Hardware>::code_base(&machine.cpu),
+            ),
+            Machine::At(machine) => (
+                Cpu::<IbmPcAtHardware>::program_counter(&machine.cpu).0,
+                Cpu::<IbmPcAtHardware>::code_base(&machine.cpu),
+            ),
+        };
+        let base = if cs == current {
+            base
+        } else {
+            (cs as u32) << 4
+        };
+        let mut ip = ip;
+        (0..count)
+            .map(|_| {
+                let bytes: Vec<u8> = (0..disasm::MAX_LENGTH as u16)
+                    .map(|n| self.read_memory(base + ip.wrapping_add(n) as u32))
+                    .collect();
+                let instruction = disasm::decode(&bytes, cs, ip, isa);
+                ip = instruction.next_ip();
+                instruction
+            })
+            .collect()
+    }
+
+    /// The instructions runs stop at, before they run.
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        match self {
+            Machine::Pc(machine) => &mut machine.breakpoints,
+            Machine::At(machine) => &mut machine.breakpoints,
+        }
+    }
+
+    /// The ports runs stop on accesses to, after the instruction that made
+    /// them.
+    pub fn port_watch_mut(&mut self) -> &mut PortWatch {
+        match self {
+            Machine::Pc(machine) => &mut machine.hardware.port_watch,
+            Machine::At(machine) => &mut machine.hardware.port_watch,
+        }
+    }
+
+    /// The memory runs stop on accesses to, after the instruction that
+    /// made them.
+    pub fn memory_watch_mut(&mut self) -> &mut MemoryWatch {
+        match self {
+            Machine::Pc(machine) => &mut machine.hardware.memory_watch,
+            Machine::At(machine) => &mut machine.hardware.memory_watch,
+        }
+    }
+
+    /// The character codes in each video card's text memory, attributes
+    /// left out, for looking for what's on the screen. It's all of the
+    /// memory, every page of it, and nonsense in graphics modes.
+    pub fn text_chars(&self) -> Vec<u8> {
+        let (cga, ega, mda) = match self {
+            Machine::Pc(machine) => {
+                let hardware = &machine.hardware;
+                (
+                    &hardware.common.cga,
+                    &hardware.common.ega,
+                    &hardware.common.mda,
+                )
+            }
+            Machine::At(machine) => {
+                let hardware = &machine.hardware;
+                (
+                    &hardware.common.cga,
+                    &hardware.common.ega,
+                    &hardware.common.mda,
+                )
+            }
+        };
+        let mut chars = vec![];
+        if let Some(cga) = cga {
+            chars.extend(cga.vram.iter().step_by(2));
+        }
+        if let Some(ega) = ega {
+            chars.extend_from_slice(&ega.planes[0]);
+        }
+        if let Some(mda) = mda {
+            chars.extend(mda.vram.iter().step_by(2));
+        }
+        chars
+    }
+
+    /// The last checkpoint the BIOS wrote to port 80h.
+    pub fn last_post_code(&self) -> Option<u8> {
+        match self {
+            Machine::Pc(machine) => machine.hardware.common.post_card.last(),
+            Machine::At(machine) => machine.hardware.common.post_card.last(),
+        }
+    }
+
+    pub fn ram_size(&self) -> usize {
+        match self {
+            Machine::Pc(machine) => machine.ram_size(),
+            Machine::At(machine) => machine.ram_size(),
+        }
+    }
+
+    /// Shadows ROMs in, or opens upper memory blocks in, whole 16K blocks
+    /// of C0000h-EFFFFh. Only the AT's chipset can.
+    pub fn set_upper_memory(
+        &mut self,
+        start: u32,
+        end: u32,
+        kind: UpperMemory,
+    ) -> Result<(), String> {
+        match self {
+            Machine::Pc(_) => Err("the PC and XT can't map RAM into upper memory".to_string()),
+            Machine::At(machine) => machine.hardware.set_upper_memory(start, end, kind),
+        }
+    }
+
+    /// Memory above 1 MB, in kilobytes.
+    pub fn extended_memory(&self) -> usize {
+        match self {
+            Machine::Pc(machine) => machine.extended_memory(),
+            Machine::At(machine) => machine.extended_memory(),
+        }
+    }
+
+    /// Changes the memory above 1 MB at the next cold reset.
+    pub fn set_extended_memory(&mut self, kb: usize) -> Result<(), String> {
+        match self {
+            Machine::Pc(machine) => machine.set_extended_memory(kb),
+            Machine::At(machine) => machine.set_extended_memory(kb),
+        }
+    }
+
+    /// Resets the CPU and whatever else `kind` reaches, with a cold reset
+    /// being power on.
+    pub fn reset(&mut self, kind: ResetKind) {
+        match self {
+            Machine::Pc(machine) => machine.reset_with(kind),
+            Machine::At(machine) => machine.reset_with(kind),
+        }
+    }
+
+    /// Ctrl-Alt-Del: a warm reset with the BIOS's warm boot flag set.
+    pub fn warm_boot(&mut self) {
+        match self {
+            Machine::Pc(machine) => machine.warm_boot(),
+            Machine::At(machine) => machine.warm_boot(),
+        }
+    }
+
+    /// Takes the ports and IRQ lines of cards that have come and gone.
+    fn refit(&mut self) {
+        match self {
+            Machine::Pc(machine) => machine.hardware.refit(),
+            Machine::At(machine) => machine.hardware.refit(),
+        }
+    }
+
+    fn has_card(&self, card: IsaCard) -> bool {
+        let hardware = match self {
+            Machine::Pc(machine) => (
+                machine.hardware.common.adlib.is_some(),
+                machine.hardware.common.sound_blaster.is_some(),
+                machine.hardware.common.mpu401.is_some(),
+                machine.hardware.common.game_port.is_some(),
+                &machine.hardware.common.serial,
+                &machine.hardware.common.parallel,
+                machine.hardware.common.perf_counter.is_some(),
+                machine.hardware.common.ems.is_some(),
+            ),
+            Machine::At(machine) => (
+                machine.hardware.common.adlib.is_some(),
+                machine.hardware.common.sound_blaster.is_some(),
+                machine.hardware.common.mpu401.is_some(),
+                machine.hardware.common.game_port.is_some(),
+                &machine.hardware.common.serial,
+                &machine.hardware.common.parallel,
+                machine.hardware.common.perf_counter.is_some(),
+                machine.hardware.common.ems.is_some(),
+            ),
+        };
+        let (adlib, sound_blaster, mpu401, game_port, serial, parallel, perf_counter, ems) =
+            hardware;
+        match card {
+            IsaCard::Adlib => adlib,
+            IsaCard::SoundBlaster(_) => sound_blaster,
+            IsaCard::Mpu401 => mpu401,
+            IsaCard::GamePort => game_port,
+            IsaCard::Serial(port) => serial[port].is_some(),
+            IsaCard::Parallel(port) => parallel[port].is_some(),
+            IsaCard::PerfCounter => perf_counter,
+            IsaCard::Ems(_) => ems,
+        }
+    }
+
+    fn check_slot(card: IsaCard) -> Result<(), String> {
+        match card {
+            IsaCard::Serial(port) if port >= 4 => Err("there are only four COM ports".to_string()),
+            IsaCard::Parallel(port) if port >= 3 => {
+                Err("there are only three LPT ports".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fits `card` to the running machine. Check `IsaCard::needs_reset`
+    /// for whether the guest will see it straight away.
+    pub fn add_card(&mut self, card: IsaCard) -> Result<(), String> {
+        Self::check_slot(card)?;
+        if self.has_card(card) {
+            return Err(format!("there's a {} fitted already", card));
+        }
+        match card {
+            IsaCard::Adlib => self.attach_adlib(),
+            IsaCard::SoundBlaster(model) => self.attach_sound_blaster(model),
+            IsaCard::Mpu401 => self.attach_mpu401(),
+            IsaCard::GamePort => self.attach_game_port(None),
+            IsaCard::Serial(port) => {
+                let (serial, model) = match self {
+                    Machine::Pc(machine) => {
+                        (&mut machine.hardware.common.serial, UartModel::Ins8250)
+                    }
+                    Machine::At(machine) => {
+                        (&mut machine.hardware.common.serial, UartModel::Ns16450)
+                    }
+                };
+                serial[port] = Some(UART::com(port, model));
+            }
+            IsaCard::Parallel(port) => {
+                self.lpt_mut(port);
+            }
+            IsaCard::PerfCounter => self.attach_perf_counter(),
+            IsaCard::Ems(config) => match self {
+                Machine::Pc(machine) => machine.hardware.attach_ems(config)?,
+                Machine::At(machine) => machine.hardware.attach_ems(config)?,
+            },
+        }
+        self.refit();
+        Ok(())
+    }
+
+    /// Takes `card` out of the running machine, along with whatever is
+    /// plugged into it. The Sound Blaster's OPL2 stays behind at 388h, as
+    /// an AdLib.
+    pub fn remove_card(&mut self, card: IsaCard) -> Result<(), String> {
+        Self::check_slot(card)?;
+        if !self.has_card(card) {
+            return Err(format!("there's no {} fitted", card));
+        }
+        match self {
+            Machine::Pc(machine) => {
+                let hardware = &mut machine.hardware;
+                match card {
+                    IsaCard::Adlib => hardware.common.adlib = None,
+                    IsaCard::SoundBlaster(_) => hardware.common.sound_blaster = None,
+                    IsaCard::Mpu401 => hardware.common.mpu401 = None,
+                    IsaCard::GamePort => hardware.common.game_port = None,
+                    IsaCard::Serial(port) => hardware.common.serial[port] = None,
+                    IsaCard::Parallel(port) => hardware.common.parallel[port] = None,
+                    IsaCard::PerfCounter => hardware.common.perf_counter = None,
+                    IsaCard::Ems(_) => hardware.detach_ems(),
+                }
+            }
+            Machine::At(machine) => {
+                let hardware = &mut machine.hardware;
+                match card {
+                    IsaCard::Adlib => hardware.common.adlib = None,
+                    IsaCard::SoundBlaster(_) => hardware.common.sound_blaster = None,
+                    IsaCard::Mpu401 => hardware.common.mpu401 = None,
+                    IsaCard::GamePort => hardware.common.game_port = None,
+                    IsaCard::Serial(port) => hardware.common.serial[port] = None,
+                    IsaCard::Parallel(port) => hardware.common.parallel[port] = None,
+                    IsaCard::PerfCounter => hardware.common.perf_counter = None,
+                    IsaCard::Ems(_) => hardware.detach_ems(),
+                }
+            }
+        }
+        self.refit();
+        Ok(())
+    }
+
+    /// Fits the performance counter card, unless there's one already.
+    pub fn attach_perf_counter(&mut self) {
+        let (perf_counter, cpu_hz) = match self {
+            Machine::Pc(machine) => (
+                &mut machine.hardware.common.perf_counter,
+                machine.hardware.common.pit_clock.cpu_hz(),
+            ),
+            Machine::At(machine) => (
+                &mut machine.hardware.common.perf_counter,
+                machine.hardware.common.pit_clock.cpu_hz(),
+            ),
+        };
+        perf_counter.get_or_insert_with(|| PerfCounter::new(cpu_hz));
+        self.refit();
+    }
+
+    pub fn set_clock_hz(&mut self, hz: u32) -> Result<(), String> {
+        match self {
+            Machine::Pc(machine) => machine.set_clock_hz(hz),
+            Machine::At(machine) => machine.set_clock_hz(hz),
+        }
+    }
+
+    pub fn load_bios(&mut self, bios: Vec<u8>) -> Result<(), String> {
+        match self {
+            Machine::Pc(machine) => machine.hardware.load_bios(bios),
+            Machine::At(machine) => machine.hardware.load_bios(bios),
+        }
+    }
+
+    /// Maps an option ROM at `addr`, or wherever there's room. Returns
+    /// where it went.
+    pub fn add_option_rom(&mut self, rom: OptionRom, addr: Option<u32>) -> Result<u32, String> {
+        match self {
+            Machine::Pc(machine) => machine.hardware.add_option_rom(rom, addr),
+            Machine::At(machine) => machine.hardware.add_option_rom(rom, addr),
+        }
+    }
+
+    pub fn set_slow_clock_hz(&mut self, hz: Option<u32>) -> Result<(), String> {
+        match self {
+            Machine::Pc(machine) => machine.set_slow_clock_hz(hz),
+            Machine::At(machine) => machine.set_slow_clock_hz(hz),
+        }
+    }
+
+    /// The clock the CPU is running at now, turbo and all.
+    pub fn clock_hz(&self) -> u32 {
+        match self {
+            Machine::Pc(machine) => machine.clock_hz(),
+            Machine::At(machine) => machine.clock_hz(),
+        }
+    }
+
+    /// The drive lights as they are now. On the AT this is also what
+    /// lights the front panel's HDD LED.
+    pub fn drive_lights(&mut self) -> DriveLights {
+        let (fdc, hard_disk) = match self {
+            Machine::Pc(machine) => {
+                let hardware = &mut machine.hardware;
+                let hard_disk = hardware
+                    .hdc
+                    .as_mut()
+                    .is_some_and(|hdc| std::mem::take(&mut hdc.activity));
+                (&hardware.common.fdc, hard_disk)
+            }
+            Machine::At(machine) => {
+                let hardware = &mut machine.hardware;
+                let hard_disk = std::mem::take(&mut hardware.ide.activity)
+                    | std::mem::take(&mut hardware.secondary_ide.activity);
+                hardware.front_panel.set_hdd_activity(hard_disk);
+                (&hardware.common.fdc, hard_disk)
+            }
+        };
+        let floppy = |drive| fdc.as_ref().is_some_and(|fdc| fdc.motor_on(drive));
+        DriveLights {
+            floppy: [floppy(0), floppy(1)],
+            hard_disk,
+        }
+    }
+
+    pub fn turbo(&self) -> bool {
+        match self {
+            Machine::Pc(machine) => machine.turbo(),
+            Machine::At(machine) => machine.turbo(),
+        }
+    }
+
+    /// Flips the turbo switch. On an AT the front panel's turbo button and
+    /// LED go with it.
+    pub fn set_turbo(&mut self, on: bool) {
+        match self {
+            Machine::Pc(machine) => machine.set_turbo(on),
+            Machine::At(machine) => {
+                if machine.hardware.front_panel.turbo != on {
+                    machine.hardware.front_panel.press_turbo();
+                }
+                machine.set_turbo(on);
+            }
+        }
+    }
+
+    /// The picture on the machine's display, if it has a card that draws
+    /// one. With both a colour and a monochrome card, this is the colour
+    /// one.
+    pub fn frame(&self) -> Option<Frame<'_>> {
+        self.frames().into_iter().next()
+    }
+
+    /// A frame from each display, the primary's first: the EGA or CGA
+    /// when there's one, with an MDA beside it as the second monitor.
+    pub fn frames(&self) -> Vec<Frame<'_>> {
+        let (ega, cga, mda) = match self {
+            Machine::Pc(machine) => {
+                let hardware = &machine.hardware;
+                (
+                    &hardware.common.ega,
+                    &hardware.common.cga,
+                    &hardware.common.mda,
+                )
+            }
+            Machine::At(machine) => {
+                let hardware = &machine.hardware;
+                (
+                    &hardware.common.ega,
+                    &hardware.common.cga,
+                    &hardware.common.mda,
+                )
+            }
+        };
+        let ega = ega.as_ref().map(EGA::frame);
+        ega.into_iter()
+            .chain(cga.as_ref().map(CGA::frame))
+            .chain(mda.as_ref().map(MDA::frame))
+            .collect()
+    }
+
+    /// Saves what's on the display to a PNG, as the card made it or
+    /// stretched to 4:3.
+    pub fn screenshot(&self, path: &Path, aspect_correct: bool) -> Result<(), String> {
+        let frame = self
+            .frame()
+            .ok_or_else(|| "nothing on the display".to_string())?;
+        screenshot::save(&frame, path, aspect_correct)
+    }
+
+    /// The floppy controller, if the machine was built with one.
+    pub fn fdc_mut(&mut self) -> Option<&mut FDC> {
+        match self {
+            Machine::Pc(machine) => machine.hardware.common.fdc.as_mut(),
+            Machine::At(machine) => machine.hardware.common.fdc.as_mut(),
+        }
+    }
+
+    /// Puts `media` in floppy drive 0 or 1 while the machine runs, handing
+    /// back the disk that was in it. The drive's disk change line goes up
+    /// as it would for a real swap, so DOS rereads the disk rather than
+    /// trusting what it cached of the last one.
+    pub fn change_floppy(
+        &mut self,
+        drive: usize,
+        media: FloppyMedia,
+    ) -> Result<Option<FloppyMedia>, String> {
+        let floppy = self
+            .fdc_mut()
+            .and_then(|fdc| fdc.drives.get_mut(drive)?.as_mut())
+            .ok_or_else(|| "this machine has no such drive".to_string())?;
+        let old = floppy.eject();
+        if let Err(err) = floppy.insert(media) {
+            floppy.media = old;
+            return Err(format!("{:?}", err));
+        }
+        Ok(old)
+    }
+
+    /// Takes the disk out of floppy drive 0 or 1.
+    pub fn eject_floppy(&mut self, drive: usize) -> Option<FloppyMedia> {
+        self.fdc_mut()?.drives.get_mut(drive)?.as_mut()?.eject()
+    }
+
+    /// Puts `image` in the CD-ROM drive while the machine runs, handing back
+    /// the disc that was in it. The next packet command fails with a
+    /// medium changed UNIT ATTENTION, as drivers expect after a swap.
+    pub fn change_cdrom(&mut self, image: CdImage) -> Result<Option<CdImage>, String> {
+        match self {
+            Machine::At(machine) => match &mut machine.hardware.secondary_ide.cdroms[0] {
+                Some(cdrom) => Ok(cdrom.insert(image)),
+                None => Err("this machine has no CD-ROM drive".to_string()),
+            },
+            Machine::Pc(_) => Err("this machine has no CD-ROM drive".to_string()),
+        }
+    }
+
+    /// Takes the disc out of the CD-ROM drive, locked tray or not.
+    pub fn eject_cdrom(&mut self) -> Option<CdImage> {
+        match self {
+            Machine::At(machine) => machine.hardware.secondary_ide.cdroms[0].as_mut()?.eject(),
+            Machine::Pc(_) => None,
+        }
+    }
+
+    /// Hard disk 0 or 1, on whichever controller the machine has, for
+    /// committing or discarding its snapshot.
+    pub fn hard_disk_mut(&mut self, drive: usize) -> Option<&mut HardDisk> {
+        match self {
+            Machine::Pc(machine) => machine.hardware.hdc.as_mut()?.drives[drive]
+                .as_mut()
+                .map(|drive| &mut drive.disk),
+            Machine::At(machine) => machine.hardware.ide.drives[drive]
+                .as_mut()
+                .map(|drive| &mut drive.disk),
+        }
+    }
+
+    /// The rate the machine makes audio samples at.
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            Machine::Pc(machine) => machine.hardware.common.sample_clock.device_hz(),
+            Machine::At(machine) => machine.hardware.common.sample_clock.device_hz(),
+        }
+    }
+
+    /// Sets the rate the machine makes audio samples at, to the rate the
+    /// host plays them at.
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        let common = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common,
+            Machine::At(machine) => &mut machine.hardware.common,
+        };
+        common.set_sample_rate(hz);
+        common.mixer.resume_after_load();
+    }
+
+    /// Fills `out` with the mono samples made since the last call, padding
+    /// with silence if the machine hasn't made enough. Returns how many
+    /// were real.
+    pub fn render_audio(&mut self, out: &mut [i16]) -> usize {
+        let mixer = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.mixer,
+            Machine::At(machine) => &mut machine.hardware.common.mixer,
+        };
+        let mut samples = vec![0.0; out.len()];
+        let available = mixer.pull(&mut samples);
+        for (dst, sample) in out.iter_mut().zip(samples) {
+            *dst = (sample * i16::MAX as f32) as i16;
+        }
+        available
+    }
+
+    /// Takes every sample made since the last call, for a frontend that
+    /// paces the sound itself.
+    pub fn take_audio(&mut self) -> Vec<f32> {
+        let mixer = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.mixer,
+            Machine::At(machine) => &mut machine.hardware.common.mixer,
+        };
+        let mut samples = vec![0.0; mixer.queued()];
+        mixer.pull(&mut samples);
+        samples
+    }
+
+    /// Fits an AdLib card, unless there's one already.
+    pub fn attach_adlib(&mut self) {
+        let adlib = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.adlib,
+            Machine::At(machine) => &mut machine.hardware.common.adlib,
+        };
+        adlib.get_or_insert_with(OPL2::new);
+        self.refit();
+    }
+
+    /// Fits a Sound Blaster, with the OPL2 it carries. Any AdLib already
+    /// there becomes the Sound Blaster's.
+    pub fn attach_sound_blaster(&mut self, model: SbModel) {
+        let (adlib, sound_blaster) = match self {
+            Machine::Pc(machine) => (
+                &mut machine.hardware.common.adlib,
+                &mut machine.hardware.common.sound_blaster,
+            ),
+            Machine::At(machine) => (
+                &mut machine.hardware.common.adlib,
+                &mut machine.hardware.common.sound_blaster,
+            ),
+        };
+        adlib.get_or_insert_with(OPL2::new);
+        *sound_blaster = Some(SoundBlaster::new(model));
+        self.refit();
+    }
+
+    /// Fits a game port, unless there's one already, with the keys for a
+    /// virtual joystick if there's no real one.
+    pub fn attach_game_port(&mut self, virtual_joystick: Option<VirtualJoystick>) {
+        let game_port = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.game_port,
+            Machine::At(machine) => &mut machine.hardware.common.game_port,
+        };
+        game_port.get_or_insert_with(GamePort::new).virtual_joystick = virtual_joystick;
+        self.refit();
+    }
+
+    /// Fits a monochrome display adapter beside the colour card, for a
+    /// second monitor as debuggers used them. The two decode apart, the MDA
+    /// at B0000h and 3B0h-3BFh and the colour card above, and the BIOS
+    /// keeps to whichever display the switches or CMOS say.
+    pub fn attach_mda(&mut self) -> Result<(), String> {
+        let mda = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.mda,
+            Machine::At(machine) => &mut machine.hardware.common.mda,
+        };
+        if mda.is_some() {
+            return Err("there's a monochrome card fitted already".to_string());
+        }
+        *mda = Some(MDA::new());
+        self.refit();
+        Ok(())
+    }
+
+    /// Fits an NE2000 with its cable plugged into `backend`.
+    pub fn attach_ne2000(
+        &mut self,
+        base: u16,
+        irq: u8,
+        mac: [u8; 6],
+        backend: Box<dyn NetworkBackend>,
+    ) {
+        let nic = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.nic,
+            Machine::At(machine) => &mut machine.hardware.common.nic,
+        };
+        nic.insert(NE2000::new(base, irq, mac)).connect(backend);
+        self.refit();
+    }
+
+    /// Fits an MPU-401, unless there's one already.
+    pub fn attach_mpu401(&mut self) {
+        let mpu401 = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.mpu401,
+            Machine::At(machine) => &mut machine.hardware.common.mpu401,
+        };
+        mpu401.get_or_insert_with(MPU401::new);
+        self.refit();
+    }
+
+    /// The MIDI bytes the MPU-401 has sent since the last call.
+    pub fn take_midi(&mut self) -> Vec<u8> {
+        let mpu401 = match self {
+            Machine::Pc(machine) => machine.hardware.common.mpu401.as_mut(),
+            Machine::At(machine) => machine.hardware.common.mpu401.as_mut(),
+        };
+        mpu401.map_or_else(Vec::new, MPU401::take_midi)
+    }
+
+    /// Passes host input to the devices that take it.
+    pub fn input(&mut self, event: InputEvent) {
+        let (serial, game_port) = match self {
+            Machine::Pc(machine) => {
+                if let InputEvent::Key { scancode, pressed } = event {
+                    machine.hardware.ppi.keyboard.key(scancode, pressed);
+                }
+                (
+                    &mut machine.hardware.common.serial,
+                    &mut machine.hardware.common.game_port,
+                )
+            }
+            Machine::At(machine) => {
+                if let InputEvent::Key { scancode, pressed } = event {
+                    machine.hardware.kbc.key(scancode, pressed);
+                }
+                if let Some(mouse) = &mut machine.hardware.kbc.mouse {
+                    mouse.input(event);
+                }
+                (
+                    &mut machine.hardware.common.serial,
+                    &mut machine.hardware.common.game_port,
+                )
+            }
+        };
+        if let Some(game_port) = game_port {
+            game_port.input(event);
+        }
+        for uart in serial.iter_mut().flatten() {
+            uart.input(event);
+        }
+    }
+
+    /// Plugs a PS/2 mouse into the 8042's auxiliary port, which only the
+    /// AT has.
+    pub fn attach_ps2_mouse(&mut self) -> Result<(), String> {
+        match self {
+            Machine::Pc(_) => Err("this machine has no auxiliary port".to_string()),
+            Machine::At(machine) => {
+                machine.hardware.kbc.mouse.get_or_insert_with(Ps2Mouse::new);
+                Ok(())
+            }
+        }
+    }
+
+    /// LPT1-LPT3, counting from 0, fitting the port if the machine doesn't
+    /// have it.
+    pub fn lpt_mut(&mut self, port: usize) -> &mut LPT {
+        let parallel = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.parallel,
+            Machine::At(machine) => &mut machine.hardware.common.parallel,
+        };
+        if parallel[port].is_none() {
+            parallel[port] = Some(LPT::port(port));
+            self.refit();
+        }
+        let parallel = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.parallel,
+            Machine::At(machine) => &mut machine.hardware.common.parallel,
+        };
+        parallel[port].as_mut().unwrap()
+    }
+
+    /// COM1-COM4, counting from 0, if the machine has that port.
+    pub fn serial_mut(&mut self, port: usize) -> Option<&mut UART> {
+        let serial = match self {
+            Machine::Pc(machine) => &mut machine.hardware.common.serial,
+            Machine::At(machine) => &mut machine.hardware.common.serial,
+        };
+        serial.get_mut(port)?.as_mut()
+    }
+
+    /// The machine's CGA, for turning its snow and composite colours on.
+    pub fn cga_mut(&mut self) -> Option<&mut CGA> {
+        match self {
+            Machine::Pc(machine) => machine.hardware.common.cga.as_mut(),
+            Machine::At(machine) => machine.hardware.common.cga.as_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::hardware::templates::find_template;
+
+#[test]
+fn test_turbo_switch() {
+    let mut machine = find_template("generic286").unwrap().build().unwrap();
+    assert!(machine.turbo());
+    machine.set_turbo(false);
+    assert_eq!(machine.clock_hz(), 8_000_000);
+    match &mut machine {
+        Machine::At(at) => {
+            assert!(!at.hardware.front_panel.turbo_led);
+            assert_eq!(at.cycles_per_frame, 133_505);
+        }
+        Machine::Pc(_) => unreachable!(),
+    }
+    machine.set_turbo(true);
+    assert_eq!(machine.clock_hz(), 12_000_000);
+
+    // A board without a switch ignores it.
+    let mut machine = find_template("ibm5150").unwrap().build().unwrap();
+    machine.set_turbo(false);
+    assert_eq!(machine.clock_hz(), 4_772_727);
+    machine.set_slow_clock_hz(Some(4_772_727)).unwrap();
+    machine.set_clock_hz(9_545_454).unwrap();
+    assert_eq!(machine.clock_hz(), 4_772_727);
+    machine.set_turbo(true);
+    assert_eq!(machine.clock_hz(), 9_545_454);
+}
+
+#[test]
+fn test_ems_card() {
+    use crate::cpu8086::Cpu8086Context;
+    use crate::hardware::ems::PageFrame;
+
+    let mut machine = find_template("ibm5160").unwrap().build().unwrap();
+    let config = EmsConfig::default();
+    machine.add_card(IsaCard::Ems(config)).unwrap();
+    let xt = match &mut machine {
+        Machine::Pc(xt) => xt,
+        Machine::At(_) => unreachable!(),
+    };
+    xt.hardware.io_write_byte(0x260, 7);
+    xt.hardware.mem_write_byte(0xd_0000, 0x5a);
+    xt.hardware.io_write_byte(0x261, 7);
+    assert_eq!(xt.hardware.mem_read_byte(0xd_4000), 0x5a);
+
+    // One page frame at a time.
+    let config = EmsConfig {
+        frame: PageFrame::E000,
+        ..config
+    };
+    assert!(machine.add_card(IsaCard::Ems(config)).is_err());
+    machine.remove_card(IsaCard::Ems(config)).unwrap();
+    machine.add_card(IsaCard::Ems(config)).unwrap();
+}
+
+#[test]
+fn test_key_input() {
+    use crate::input::InputEvent;
+    let key = InputEvent::Key {
+        scancode: 0x1e,
+        pressed: true,
+    };
+    // The PC's keyboard is held in reset until POST lets its clock go,
+    // when it sends AAh.
+    let mut machine = find_template("ibm5150").unwrap().build().unwrap();
+    match &mut machine {
+        Machine::Pc(pc) => {
+            pc.hardware.ppi.wb(0x61, 0x40);
+            pc.hardware.ppi.poll();
+            assert_eq!(pc.hardware.ppi.rb(0x60), 0xaa);
+            pc.hardware.ppi.wb(0x61, 0xc0);
+            pc.hardware.ppi.wb(0x61, 0x40);
+        }
+        Machine::At(_) => unreachable!(),
+    }
+    machine.input(key);
+    match &mut machine {
+        Machine::Pc(pc) => {
+            pc.hardware.ppi.poll();
+            assert_eq!(pc.hardware.ppi.rb(0x60), 0x1e);
+        }
+        Machine::At(_) => unreachable!(),
+    }
+    // The AT's keyboard sends set 2, which the controller translates back
+    // once POST has turned translation on.
+    let mut machine = find_template("ibm5170").unwrap().build().unwrap();
+    match &mut machine {
+        Machine::At(at) => {
+            at.hardware.kbc.wb(0x64, 0x60);
+            at.hardware.kbc.wb(0x60, 0x61);
+        }
+        Machine::Pc(_) => unreachable!(),
+    }
+    machine.input(key);
+    match &mut machine {
+        Machine::At(at) => {
+            at.hardware.kbc.poll();
+            assert_eq!(at.hardware.kbc.rb(0x60), 0x1e);
+        }
+        Machine::Pc(_) => unreachable!(),
+    }
+}
+
+#[test]
+fn test_screenshot() {
+    let machine = find_template("ibm5150").unwrap().build().unwrap();
+    let frame = machine.frame().unwrap();
+    let size = (frame.width, frame.height);
+    let path = std::env::temp_dir().join("emupc-screenshot-test.png");
+    for aspect_correct in [false, true] {
+        machine.screenshot(&path, aspect_correct).unwrap();
+        let decoder = png::Decoder::new(std::fs::File::open(&path).unwrap());
+        let info = decoder.read_info().unwrap();
+        let expected = match aspect_correct {
+            false => size,
+            true => screenshot::aspect_size(size.0, size.1),
+        };
+        assert_eq!((info.info().width, info.info().height), expected);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_dual_monitor() {
+    let mut machine = find_template("ibm5150").unwrap().build().unwrap();
+    assert_eq!(machine.frames().len(), 1);
+    machine.attach_mda().unwrap();
+    assert!(machine.attach_mda().is_err());
+    let frames = machine.frames();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].width, machine.frame().unwrap().width);
+
+    // Each card answers for its own memory and ports only.
+    let hardware = match &mut machine {
+        Machine::Pc(pc) => &mut pc.hardware,
+        Machine::At(_) => unreachable!(),
+    };
+    hardware.mem_write_byte(0xb_0000, b'M');
+    hardware.mem_write_byte(0xb_8000, b'C');
+    hardware.io_write_byte(0x3b8, 0x29);
+    hardware.io_write_byte(0x3d8, 0x09);
+    let (mda, cga) = (
+        hardware.common.mda.as_ref().unwrap(),
+        hardware.common.cga.as_ref().unwrap(),
+    );
+    assert_eq!((mda.vram[0], mda.mode), (b'M', 0x29));
+    assert_eq!((cga.vram[0], cga.mode), (b'C', 0x09));
+    assert_eq!(hardware.mem_read_byte(0xb_0000), b'M');
+    assert_eq!(hardware.mem_read_byte(0xb_8000), b'C');
+
+    let mut xt = find_template("ibm5160").unwrap().build().unwrap();
+    assert!(xt.attach_mda().is_err());
+}
+
+#[test]
+fn test_disassemble() {
+    let mut pc = IbmPc5150Machine::new();
+    pc.cpu.regs.seg_regs[1] = 0;
+    pc.cpu.regs.ip = 0x100;
+    pc.hardware.memory.ram[0x100..0x104].copy_from_slice(&[0xb0, 0x42, 0xe6, 0xe9]);
+    let mut machine = Machine::Pc(Box::new(pc));
+    let text = |machine: &mut Machine, cs, ip| {
+        let instructions = machine.disassemble(cs, ip, 2);
+        instructions
+            .iter()
+            .map(|instruction| instruction.text.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        text(&mut machine, 0, 0x100),
+        ["mov al, 0x42", "out 0xe9, al"]
+    );
+    assert_eq!(
+        text(&mut machine, 0x10, 0),
+        ["mov al, 0x42", "out 0xe9, al"]
+    );
+    assert_eq!(machine.disassemble(0x10, 0, 2)[1].ip, 2);
+}
+
+#[test]
+fn test_drive_lights() {
+    let mut machine = find_template("ibm5170").unwrap().build().unwrap();
+    assert_eq!(machine.drive_lights(), DriveLights::default());
+    if let Machine::At(at) = &mut machine {
+        at.hardware.secondary_ide.activity = true;
+    }
+    // The hard disk's light stays lit until it's been looked at once.
+    assert!(machine.drive_lights().hard_disk);
+    assert!(!machine.drive_lights().hard_disk);
+}
+
+#[test]
+fn test_change_media() {
+    use crate::hardware::floppy::MediaType;
+
+    let mut machine = find_template("ibm5170").unwrap().build().unwrap();
+    let disk = |media_type: MediaType| FloppyMedia::new(media_type, vec![0; media_type.size()]);
+    let disk_changed = |machine: &mut Machine| machine.fdc_mut().unwrap().rb(0x3f7) & 0x80 != 0;
+    assert!(machine
+        .change_floppy(0, disk(MediaType::Media1200K))
+        .unwrap()
+        .is_none());
+    assert!(disk_changed(&mut machine));
+    machine.fdc_mut().unwrap().drives[0]
+        .as_mut()
+        .unwrap()
+        .step(true);
+    assert!(!disk_changed(&mut machine));
+
+    // A swap puts the line back up, and a disk that doesn't fit the drive
+    // leaves the one that's there.
+    let old = machine
+        .change_floppy(0, disk(MediaType::Media360K))
+        .unwrap();
+    assert_eq!(old.unwrap().media_type, MediaType::Media1200K);
+    assert!(disk_changed(&mut machine));
+    assert!(machine
+        .change_floppy(0, disk(MediaType::Media1440K))
+        .is_err());
+    assert!(machine
+        .change_floppy(2, disk(MediaType::Media360K))
+        .is_err());
+    let old = machine.eject_floppy(0).unwrap();
+    assert_eq!(old.media_type, MediaType::Media360K);
+    assert!(machine.eject_floppy(0).is_none());
+
+    let image = CdImage {
+        path: "disc.iso".into(),
+        tracks: vec![],
+    };
+    assert!(machine.change_cdrom(image.clone()).is_err());
+    if let Machine::At(at) = &mut machine {
+        at.hardware.attach_cdrom(None);
+    }
+    assert_eq!(machine.change_cdrom(image.clone()), Ok(None));
+    assert_eq!(machine.eject_cdrom(), Some(image));
+}
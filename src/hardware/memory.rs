@@ -0,0 +1,336 @@
+// The memory side of a board's bus. RAM starts at address 0 and goes as far
+// as the board has fitted; above it are regions mapped over the address
+// space: ROMs, which the bus holds itself, and ranges a device answers for,
+// such as video memory and the ROMs on adapter cards. Those are named by
+// the board's own `M`, so it can hand the access to the device. Chipsets
+// that can put system board RAM in the upper memory area map it as a
+// region too, either a write-protected copy of the ROM it covers or plain
+// RAM for a memory manager's upper memory blocks. An AT's extended memory
+// starts again at 1 MB, under any regions mapped up there. Anything
+// nothing answers for reads as open bus.
+use log::trace;
+
+/// Where extended memory starts.
+pub const EXTENDED_BASE: u32 = 0x10_0000;
+
+/// The part of the upper memory area a chipset can fill with RAM, and the
+/// size of the blocks it does it in.
+pub const UPPER_MEMORY_START: u32 = 0x0c_0000;
+pub const UPPER_MEMORY_END: u32 = 0x0e_ffff;
+pub const UPPER_MEMORY_BLOCK: u32 = 0x4000;
+
+/// What the chipset puts in a block of the upper memory area.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpperMemory {
+    /// Whatever the cards put there, or open bus.
+    Bus,
+    /// RAM holding a copy of the ROM that was there, write-protected like
+    /// the ROM and without the 8-bit card's wait states.
+    Shadow,
+    /// RAM for a memory manager to load drivers into.
+    Umb,
+}
+
+/// Checks that `start` to `end` inclusive is whole blocks of the upper
+/// memory area.
+pub fn check_upper_memory(start: u32, end: u32) -> Result<(), String> {
+    if start < UPPER_MEMORY_START
+        || end > UPPER_MEMORY_END
+        || start > end
+        || !start.is_multiple_of(UPPER_MEMORY_BLOCK)
+        || !(end + 1).is_multiple_of(UPPER_MEMORY_BLOCK)
+    {
+        return Err(format!(
+            "upper memory goes in 16K blocks from {:05X}h to {:05X}h, got {:05X}h-{:05X}h",
+            UPPER_MEMORY_START, UPPER_MEMORY_END, start, end
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Mapping<M> {
+    Rom(Vec<u8>),
+    /// System board RAM mapped over the upper memory area.
+    Ram {
+        data: Vec<u8>,
+        writable: bool,
+    },
+    Mmio(M),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Region<M> {
+    start: u32,
+    end: u32,
+    mapping: Mapping<M>,
+}
+
+/// What a read found: a byte, or the device that has to supply it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryRead<M> {
+    Data(u8),
+    Mmio(M),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryBus<M> {
+    pub ram: Vec<u8>,
+    /// Memory from 1 MB up, on boards that can address it.
+    pub extended: Vec<u8>,
+    /// Checked after RAM, the latest mapped first, so a region can be
+    /// mapped over part of another.
+    regions: Vec<Region<M>>,
+}
+
+impl<M> Default for MemoryBus<M> {
+    fn default() -> MemoryBus<M> {
+        MemoryBus::new(0)
+    }
+}
+
+impl<M> MemoryBus<M> {
+    pub fn new(ram_bytes: usize) -> MemoryBus<M> {
+        MemoryBus {
+            ram: vec![0; ram_bytes],
+            extended: vec![],
+            regions: vec![],
+        }
+    }
+
+    /// Maps `rom` at `start`. A ROM mapped twice, like the AT's BIOS at
+    /// both ends of the address space, needs mapping at each.
+    pub fn map_rom(&mut self, start: u32, rom: Vec<u8>) {
+        let end = start + (rom.len() as u32).max(1) - 1;
+        trace!("ROM at {:#08x}-{:#08x}", start, end);
+        self.regions.push(Region {
+            start,
+            end,
+            mapping: Mapping::Rom(rom),
+        });
+    }
+
+    /// Hands `start` to `end` inclusive to a device.
+    pub fn map_mmio(&mut self, start: u32, end: u32, device: M) {
+        trace!("MMIO at {:#08x}-{:#08x}", start, end);
+        self.regions.push(Region {
+            start,
+            end,
+            mapping: Mapping::Mmio(device),
+        });
+    }
+
+    /// Removes whatever is mapped starting at `start`.
+    pub fn unmap(&mut self, start: u32) {
+        self.regions.retain(|region| region.start != start);
+    }
+
+    /// Removes the ROMs that lie wholly within `start` to `end`
+    /// inclusive, as when a BIOS image is swapped for another.
+    pub fn unmap_roms(&mut self, start: u32, end: u32) {
+        self.regions.retain(|region| {
+            !matches!(region.mapping, Mapping::Rom(_)) || region.start < start || region.end > end
+        });
+    }
+
+    /// Maps `data` at `start` as RAM over whatever is there, read-only
+    /// unless `writable`.
+    pub fn map_ram(&mut self, start: u32, data: Vec<u8>, writable: bool) {
+        let end = start + (data.len() as u32).max(1) - 1;
+        trace!("RAM at {:#08x}-{:#08x}", start, end);
+        self.regions.push(Region {
+            start,
+            end,
+            mapping: Mapping::Ram { data, writable },
+        });
+    }
+
+    /// Removes the RAM mapped within `start` to `end` inclusive.
+    pub fn unmap_ram(&mut self, start: u32, end: u32) {
+        self.regions.retain(|region| {
+            !matches!(region.mapping, Mapping::Ram { .. })
+                || region.start < start
+                || region.end > end
+        });
+    }
+
+    /// Whether `addr` is in RAM mapped over the upper memory area.
+    pub fn is_mapped_ram(&self, addr: u32) -> bool {
+        matches!(
+            self.region(addr),
+            Some(Region {
+                mapping: Mapping::Ram { .. },
+                ..
+            })
+        )
+    }
+
+    /// Clears the upper memory blocks, as switching off would. Shadowed
+    /// ROMs keep their copies, since POST would make them again.
+    pub fn clear_umbs(&mut self) {
+        for region in &mut self.regions {
+            if let Mapping::Ram {
+                data,
+                writable: true,
+            } = &mut region.mapping
+            {
+                data.fill(0);
+            }
+        }
+    }
+
+    /// Whether nothing, RAM included, answers anywhere in `start` to `end`
+    /// inclusive.
+    pub fn is_free(&self, start: u32, end: u32) -> bool {
+        let extended_end = EXTENDED_BASE as usize + self.extended.len();
+        (start as usize) >= self.ram.len()
+            && (end < EXTENDED_BASE || start as usize >= extended_end)
+            && !self
+                .regions
+                .iter()
+                .any(|region| region.start <= end && start <= region.end)
+    }
+
+    fn region(&self, addr: u32) -> Option<&Region<M>> {
+        self.regions
+            .iter()
+            .rev()
+            .find(|region| (region.start..=region.end).contains(&addr))
+    }
+
+    fn region_mut(&mut self, addr: u32) -> Option<&mut Region<M>> {
+        self.regions
+            .iter_mut()
+            .rev()
+            .find(|region| (region.start..=region.end).contains(&addr))
+    }
+}
+
+impl<M: Copy> MemoryBus<M> {
+    pub fn read(&self, addr: u32) -> MemoryRead<M> {
+        if let Some(&byte) = self.ram.get(addr as usize) {
+            return MemoryRead::Data(byte);
+        }
+        match self.region(addr) {
+            Some(Region {
+                start,
+                mapping: Mapping::Rom(rom),
+                ..
+            }) => MemoryRead::Data(rom[(addr - start) as usize]),
+            Some(Region {
+                start,
+                mapping: Mapping::Ram { data, .. },
+                ..
+            }) => MemoryRead::Data(data[(addr - start) as usize]),
+            Some(Region {
+                mapping: Mapping::Mmio(device),
+                ..
+            }) => MemoryRead::Mmio(*device),
+            None => MemoryRead::Data(
+                addr.checked_sub(EXTENDED_BASE)
+                    .and_then(|offset| self.extended.get(offset as usize))
+                    .map_or(0xff, |&byte| byte),
+            ),
+        }
+    }
+
+    /// Stores `value` in RAM, or returns the device it's for. Writes to
+    /// ROM and to nothing at all are lost.
+    pub fn write(&mut self, addr: u32, value: u8) -> Option<M> {
+        if let Some(byte) = self.ram.get_mut(addr as usize) {
+            *byte = value;
+            return None;
+        }
+        match self.region_mut(addr) {
+            Some(Region {
+                mapping: Mapping::Mmio(device),
+                ..
+            }) => Some(*device),
+            Some(Region {
+                start,
+                mapping:
+                    Mapping::Ram {
+                        data,
+                        writable: true,
+                    },
+                ..
+            }) => {
+                data[(addr - *start) as usize] = value;
+                None
+            }
+            Some(_) => None,
+            None => {
+                let offset = addr.checked_sub(EXTENDED_BASE)?;
+                if let Some(byte) = self.extended.get_mut(offset as usize) {
+                    *byte = value;
+                }
+                None
+            }
+        }
+    }
+}
+
+#[test]
+fn test_memory_bus_regions() {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Card {
+        Video,
+        Rom,
+    }
+    let mut bus = MemoryBus::new(0x1000);
+    bus.map_mmio(0xa_0000, 0xb_ffff, Card::Video);
+    bus.map_rom(0xf_0000, vec![0x12, 0x34]);
+    bus.map_mmio(0xc_8000, 0xc_9fff, Card::Rom);
+
+    assert_eq!(bus.write(0xfff, 0x55), None);
+    assert_eq!(bus.read(0xfff), MemoryRead::Data(0x55));
+    assert_eq!(bus.read(0x1000), MemoryRead::Data(0xff));
+    assert_eq!(bus.read(0xb_8000), MemoryRead::Mmio(Card::Video));
+    assert_eq!(bus.write(0xa_0000, 1), Some(Card::Video));
+    assert_eq!(bus.read(0xf_0001), MemoryRead::Data(0x34));
+    assert_eq!(bus.write(0xf_0001, 0), None);
+    assert_eq!(bus.read(0xf_0001), MemoryRead::Data(0x34));
+    assert_eq!(bus.read(0xf_0002), MemoryRead::Data(0xff));
+
+    // A later region wins where they overlap, until it's taken away.
+    bus.map_mmio(0xb_0000, 0xb_7fff, Card::Rom);
+    assert_eq!(bus.read(0xb_0000), MemoryRead::Mmio(Card::Rom));
+    bus.unmap(0xb_0000);
+    assert_eq!(bus.read(0xb_0000), MemoryRead::Mmio(Card::Video));
+    bus.unmap(0xc_8000);
+    assert_eq!(bus.write(0xc_8000, 0), None);
+
+    assert!(bus.is_free(0xc_8000, 0xc_9fff));
+    assert!(!bus.is_free(0xe_f000, 0xf_0000));
+    assert!(!bus.is_free(0x800, 0xc_0000));
+    bus.unmap_roms(0xa_0000, 0xf_ffff);
+    assert_eq!(bus.read(0xf_0001), MemoryRead::Data(0xff));
+    assert_eq!(bus.read(0xb_8000), MemoryRead::Mmio(Card::Video));
+
+    // Extended memory from 1 MB, under a ROM mapped at its top.
+    bus.extended = vec![0; 0x2_0000];
+    bus.map_rom(0x11_0000, vec![0x56]);
+    assert_eq!(bus.write(0x10_0000, 0x78), None);
+    assert_eq!(bus.read(0x10_0000), MemoryRead::Data(0x78));
+    assert_eq!(bus.write(0x11_0000, 0), None);
+    assert_eq!(bus.read(0x11_0000), MemoryRead::Data(0x56));
+    assert_eq!(bus.read(0x12_0000), MemoryRead::Data(0xff));
+    assert!(!bus.is_free(0x10_0000, 0x10_ffff));
+
+    // RAM over the upper memory area, writable or not.
+    bus.map_ram(0xc_8000, vec![0x9a; 0x4000], false);
+    bus.map_ram(0xd_0000, vec![0; 0x4000], true);
+    assert_eq!(bus.write(0xc_8000, 0), None);
+    assert_eq!(bus.read(0xc_8000), MemoryRead::Data(0x9a));
+    assert_eq!(bus.write(0xd_3fff, 0x11), None);
+    assert_eq!(bus.read(0xd_3fff), MemoryRead::Data(0x11));
+    assert!(bus.is_mapped_ram(0xc_8000) && !bus.is_mapped_ram(0xb_8000));
+    bus.clear_umbs();
+    assert_eq!(bus.read(0xd_3fff), MemoryRead::Data(0));
+    assert_eq!(bus.read(0xc_8000), MemoryRead::Data(0x9a));
+    bus.unmap_ram(0xc_0000, 0xe_ffff);
+    assert!(bus.is_free(0xc_8000, 0xd_3fff));
+    assert!(check_upper_memory(0xd_0000, 0xe_ffff).is_ok());
+    assert!(check_upper_memory(0xd_0000, 0xf_ffff).is_err());
+    assert!(check_upper_memory(0xd_1000, 0xd_4fff).is_err());
+}
@@ -1,42 +1,663 @@
+use crate::cpu::{Cpu, CpuState};
+use crate::cpu8086;
 use crate::cpu8086::*;
-use crate::ibmpc5150machine::*;
+use crate::hardware::ibmpc5150machine::*;
 
 use crate::cpu286::*;
-use crate::ibmpcatmachine::*;
+use crate::hardware::ibmpcatmachine::*;
 
+use crate::disasm;
+use crate::hardware::breakpoints::{Breakpoints, MemoryWatch, Watch};
+use crate::hardware::io::PortWatch;
+use crate::hardware::reset::*;
+use crate::trace::TraceSlot;
+use log::{debug, trace, warn};
+
+pub mod a20;
+pub mod atapi;
+pub mod breakpoints;
+pub mod builder;
+pub mod cdrom;
+pub mod clock;
+pub mod common;
+pub mod device;
+pub mod dma;
+pub mod ems;
+pub mod fdc;
+pub mod floppy;
+pub mod frontpanel;
+pub mod gameport;
+pub mod harddisk;
+pub mod hdc;
 pub mod ibmpc5150machine;
 pub mod ibmpcatmachine;
+pub mod ide;
+pub mod io;
+pub mod kbc;
+pub mod keyboard;
+pub mod lpt;
+pub mod machine;
+pub mod memory;
+pub mod mpu401;
+pub mod ne2000;
+pub mod opl2;
+pub mod passthrough;
+pub mod perfcounter;
+pub mod pic;
 pub mod pit;
+pub mod postcard;
+pub mod ppi;
+pub mod printer;
+pub mod ps2mouse;
+pub mod reset;
+pub mod rom;
+pub mod rtc;
+pub mod runcontrol;
+pub mod scheduler;
+pub mod serialmouse;
+pub mod slirp;
+pub mod soundblaster;
+pub mod speaker;
+pub mod systemcontrol;
+pub mod templates;
+pub mod uart;
+pub mod video;
+
+// One CGA frame (912 hdots x 262 lines) at the 4.77 MHz CPU clock, which
+// is a third of the 14.318 MHz master clock.
+pub const CYCLES_PER_FRAME: usize = 79_648;
+
+const MASTER_CLOCK_HZ: u64 = 14_318_180;
+const HDOTS_PER_FRAME: u64 = 912 * 262;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunEvent {
+    Breakpoint,
+    Halt,
+    FrameComplete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    CyclesElapsed,
+    InstructionsDone,
+    Breakpoint,
+    /// A write to a port in the board's `PortWatch`.
+    PortWritten,
+    PortRead,
+    /// An access to memory in the board's `MemoryWatch`.
+    MemoryWritten,
+    MemoryRead,
+    Halted,
+    FrameComplete,
+}
 
+impl StopReason {
+    /// Whether a breakpoint stopped the run, one on an instruction or a
+    /// watch on memory or a port.
+    pub fn is_breakpoint(self) -> bool {
+        matches!(
+            self,
+            StopReason::Breakpoint
+                | StopReason::PortWritten
+                | StopReason::PortRead
+                | StopReason::MemoryWritten
+                | StopReason::MemoryRead
+        )
+    }
+}
+
+/// Checks a conventional memory size in kilobytes. Real boards populate
+/// RAM in 16K steps and the video buffer starts at 640K.
+fn check_ram_size(kb: usize, min_kb: usize) -> Result<(), String> {
+    if kb < min_kb || kb > 640 || !kb.is_multiple_of(16) {
+        return Err(format!(
+            "RAM size must be a multiple of 16K between {}K and 640K, got {}K",
+            min_kb, kb
+        ));
+    }
+    Ok(())
+}
+
+/// The parts of a motherboard the generic run loop drives. Resetting the
+/// board resets its devices, and RAM on a cold reset.
+pub trait Motherboard: Reset {
+    /// The least conventional RAM in kilobytes the BIOS gets through POST
+    /// with.
+    const MIN_RAM_KB: usize;
+    /// The slowest and fastest CPU clocks boards of this family shipped
+    /// with.
+    const CLOCK_RANGE_HZ: (u32, u32);
+    /// The most memory above 1 MB the board can address, in kilobytes.
+    const MAX_EXTENDED_KB: usize = 0;
+
+    /// Advances the board's devices by `cycles` CPU cycles.
+    fn tick(&mut self, _cycles: usize) {}
+    /// Tells the board the CPU clock, for devices with clocks of their own
+    /// that `tick` has to convert CPU cycles to.
+    fn set_clock_hz(&mut self, _hz: u32) {}
+    /// Replaces conventional RAM with `kb` kilobytes of cleared memory.
+    fn resize_ram(&mut self, kb: usize);
+    fn ram_size(&self) -> usize;
+    fn ram_mut(&mut self) -> &mut [u8];
+    /// Reads memory the way the CPU would, cards and all, for a debugger
+    /// or a trace looking at it. Watchpoints don't see it.
+    fn peek(&mut self, addr: u32) -> u8;
+    /// Replaces extended memory with `kb` kilobytes of cleared memory.
+    fn resize_extended(&mut self, _kb: usize) {}
+    fn extended_size(&self) -> usize {
+        0
+    }
+    fn reset_controller(&mut self) -> &mut ResetController;
+    /// Runs an INTA cycle if the interrupt controller is asserting INTR,
+    /// returning the vector it puts on the bus. The caller checks that the
+    /// CPU takes interrupts first.
+    fn acknowledge_interrupt(&mut self) -> Option<u8> {
+        None
+    }
+    /// CPU cycles the board has held the bus for since it was last asked,
+    /// for DRAM refresh. The CPU loses them.
+    fn take_stolen_cycles(&mut self) -> usize {
+        0
+    }
+    /// The level of the NMI line, after the board's mask. The CPU takes an
+    /// NMI when it rises.
+    fn nmi(&self) -> bool {
+        false
+    }
+    /// The ports to stop on writes to, on boards that can watch them.
+    fn port_watch(&mut self) -> Option<&mut PortWatch> {
+        None
+    }
+    /// The memory to stop on accesses to, on boards that can watch it.
+    fn memory_watch(&mut self) -> Option<&mut MemoryWatch> {
+        None
+    }
+}
+
+/// A PC built from a CPU and a motherboard. Everything here is shared by
+/// the PC/XT and AT families; model differences live in `C` and `H`.
 #[derive(Clone, Debug, Default)]
-pub struct IbmPc5150Machine {
-    pub cpu: Cpu8086,
-    pub hardware: IbmPc5150Hardware,
+pub struct PcMachine<C, H> {
+    pub cpu: C,
+    pub hardware: H,
+    pub breakpoints: Breakpoints,
+    /// The instruction trace, while there is one.
+    pub trace: TraceSlot,
+    /// Instructions run since the machine was made, counting each step the
+    /// CPU spends halted as one.
+    pub instructions: u64,
+    pub frame_cycles: usize,
+    /// CPU cycles in one video frame at the current clock.
+    pub cycles_per_frame: usize,
+    /// Conventional RAM size in kilobytes to switch to at the next reset.
+    pub pending_ram_kb: Option<usize>,
+    /// Extended memory in kilobytes to switch to at the next reset.
+    pub pending_extended_kb: Option<usize>,
+    /// The NMI line as of the last instruction, to find its rising edge.
+    nmi_line: bool,
+    /// The CPU clock with turbo on, which is the only one on a board
+    /// without a turbo switch.
+    clock_hz: u32,
+    /// The CPU clock with turbo off, if the board has a turbo switch.
+    slow_clock_hz: Option<u32>,
+    turbo: bool,
 }
 
+pub type IbmPc5150Machine = PcMachine<Cpu8086, IbmPc5150Hardware>;
+pub type IbmPcAtMachine = PcMachine<Cpu286, IbmPcAtHardware>;
+
 impl IbmPc5150Machine {
     pub fn new() -> IbmPc5150Machine {
-        IbmPc5150Machine {
-            cpu: Cpu8086::new(),
-            hardware: IbmPc5150Hardware::new(),
+        PcMachine::with_parts(Cpu8086::new(), IbmPc5150Hardware::new())
+    }
+
+    /// Watches every physical memory access and I/O port access the CPU
+    /// makes on this machine.
+    pub fn set_access_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&cpu8086::hooks::Access) + Send + 'static,
+    {
+        self.cpu.set_access_hook(hook);
+    }
+}
+
+impl IbmPcAtMachine {
+    pub fn new() -> IbmPcAtMachine {
+        PcMachine::with_parts(Cpu286::new(), IbmPcAtHardware::new())
+    }
+}
+
+impl<C: Cpu<H>, H: Motherboard> PcMachine<C, H> {
+    pub fn with_parts(cpu: C, hardware: H) -> PcMachine<C, H> {
+        PcMachine {
+            cpu,
+            hardware,
+            breakpoints: Breakpoints::new(),
+            trace: TraceSlot::default(),
+            instructions: 0,
+            frame_cycles: 0,
+            cycles_per_frame: CYCLES_PER_FRAME,
+            pending_ram_kb: None,
+            pending_extended_kb: None,
+            nmi_line: false,
+            clock_hz: H::CLOCK_RANGE_HZ.0,
+            slow_clock_hz: None,
+            turbo: true,
+        }
+    }
+
+    fn check_clock_hz(hz: u32) -> Result<(), String> {
+        let (min, max) = H::CLOCK_RANGE_HZ;
+        if hz < min || hz > max {
+            return Err(format!(
+                "CPU clock must be between {} and {} Hz, got {} Hz",
+                min, max, hz
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sets the CPU clock, or on a board with a turbo switch the clock
+    /// with turbo on. The video frame rate stays tied to the master
+    /// clock, so a faster CPU gets more cycles per frame.
+    pub fn set_clock_hz(&mut self, hz: u32) -> Result<(), String> {
+        Self::check_clock_hz(hz)?;
+        self.clock_hz = hz;
+        self.apply_clock();
+        Ok(())
+    }
+
+    /// Gives the board a turbo switch that drops the CPU to `hz` when
+    /// it's off, like the clones that slowed to 4.77 or 8 MHz for games
+    /// timed by the CPU. `None` takes the switch away.
+    pub fn set_slow_clock_hz(&mut self, hz: Option<u32>) -> Result<(), String> {
+        if let Some(hz) = hz {
+            Self::check_clock_hz(hz)?;
+        }
+        self.slow_clock_hz = hz;
+        self.apply_clock();
+        Ok(())
+    }
+
+    /// Flips the turbo switch. Devices keep their own clocks, and the
+    /// scheduler's pending events keep their timing in real time, so only
+    /// the CPU changes speed.
+    pub fn set_turbo(&mut self, on: bool) {
+        if self.turbo != on {
+            debug!("Turbo {}", if on { "on" } else { "off" });
+            self.turbo = on;
+            self.apply_clock();
         }
     }
+
+    pub fn turbo(&self) -> bool {
+        self.turbo
+    }
+
+    /// The clock the CPU is running at now.
+    pub fn clock_hz(&self) -> u32 {
+        match self.slow_clock_hz {
+            Some(slow) if !self.turbo => slow,
+            _ => self.clock_hz,
+        }
+    }
+
+    fn apply_clock(&mut self) {
+        let hz = self.clock_hz();
+        self.cycles_per_frame =
+            ((hz as u64 * HDOTS_PER_FRAME + MASTER_CLOCK_HZ / 2) / MASTER_CLOCK_HZ) as usize;
+        self.hardware.set_clock_hz(hz);
+    }
+
     pub fn tick(&mut self, cycles: usize) {
         self.hardware.tick(cycles);
     }
-}
 
-#[derive(Clone, Debug, Default)]
-pub struct IbmPcAtMachine {
-    pub cpu: Cpu286,
-    pub hardware: IbmPcAtHardware,
-}
+    /// Changes the amount of RAM. The new size takes effect at the next
+    /// `reset`, so the running program never sees memory disappear.
+    pub fn set_ram_size(&mut self, kb: usize) -> Result<(), String> {
+        check_ram_size(kb, H::MIN_RAM_KB)?;
+        self.pending_ram_kb = Some(kb);
+        Ok(())
+    }
 
-impl IbmPcAtMachine {
-    pub fn new() -> IbmPcAtMachine {
-        IbmPcAtMachine {
-            cpu: Cpu286::new(),
-            hardware: IbmPcAtHardware::new(),
+    pub fn ram_size(&self) -> usize {
+        self.hardware.ram_size()
+    }
+
+    /// Changes the amount of memory above 1 MB, at the next `reset` like
+    /// `set_ram_size`. Boards fitted it in 64K steps at the least.
+    pub fn set_extended_memory(&mut self, kb: usize) -> Result<(), String> {
+        if kb > H::MAX_EXTENDED_KB || !kb.is_multiple_of(64) {
+            return Err(match H::MAX_EXTENDED_KB {
+                0 => "this machine can't address memory above 1 MB".to_string(),
+                max => format!(
+                    "extended memory must be a multiple of 64K up to {}K, got {}K",
+                    max, kb
+                ),
+            });
         }
+        self.pending_extended_kb = Some(kb);
+        Ok(())
+    }
+
+    pub fn extended_memory(&self) -> usize {
+        self.hardware.extended_size()
     }
+
+    /// Switches the machine off and on again.
+    pub fn reset(&mut self) {
+        self.reset_with(ResetKind::Cold);
+    }
+
+    /// Resets the CPU and whatever else `kind` reaches. Pending memory
+    /// sizes only take effect on a cold reset. Inserted media, breakpoints,
+    /// hooks and the front panel state are always left alone.
+    pub fn reset_with(&mut self, kind: ResetKind) {
+        debug!("{:?} reset", kind);
+        if kind == ResetKind::Cold {
+            if let Some(kb) = self.pending_ram_kb.take() {
+                debug!("Resizing RAM from {}K to {}K", self.ram_size(), kb);
+                self.hardware.resize_ram(kb);
+            }
+            if let Some(kb) = self.pending_extended_kb.take() {
+                debug!(
+                    "Resizing extended memory from {}K to {}K",
+                    self.extended_memory(),
+                    kb
+                );
+                self.hardware.resize_extended(kb);
+            }
+        }
+        self.hardware.reset(kind);
+        self.cpu.reset();
+        if kind != ResetKind::Warm {
+            self.frame_cycles = 0;
+        }
+    }
+
+    /// What Ctrl-Alt-Del does. The BIOS's keyboard handler sets the warm
+    /// boot flag, 1234h at 40:72, so POST skips the memory test, then
+    /// starts again from the reset vector with RAM as it was.
+    pub fn warm_boot(&mut self) {
+        if let Some(flag) = self.hardware.ram_mut().get_mut(0x472..0x474) {
+            flag.copy_from_slice(&0x1234u16.to_le_bytes());
+        }
+        self.reset_with(ResetKind::Warm);
+    }
+
+    /// A breakpoint at the next instruction, or watched memory or a port
+    /// accessed by the last, which stop every kind of run.
+    fn stop_reason(&mut self) -> Option<StopReason> {
+        if !self.breakpoints.is_empty() {
+            let (cs, ip) = self.cpu.program_counter();
+            let physical = self.cpu.code_base().wrapping_add(ip as u32);
+            if self.breakpoints.hit(cs, ip, physical) {
+                return Some(StopReason::Breakpoint);
+            }
+        }
+        let memory = self.hardware.memory_watch();
+        match memory.and_then(|watch| watch.take_triggered()) {
+            Some(Watch::Read) => return Some(StopReason::MemoryRead),
+            Some(_) => return Some(StopReason::MemoryWritten),
+            None => {}
+        }
+        let ports = self.hardware.port_watch();
+        match ports.and_then(|watch| watch.take_triggered()) {
+            Some(Watch::Read) => Some(StopReason::PortRead),
+            Some(_) => Some(StopReason::PortWritten),
+            None => None,
+        }
+    }
+
+    /// Executes one instruction and advances the hardware by the cycles it
+    /// took. Also reports whether this completed a video frame.
+    fn step(&mut self) -> (usize, bool) {
+        let traced = self.trace_before();
+        let cycles: usize = self.cpu.tick(&mut self.hardware);
+        if let Some((before, bytes)) = traced {
+            self.trace_after(&before, bytes);
+        }
+        self.instructions += 1;
+        if self.cpu.shutdown() {
+            // The AT's motherboard logic turns a shutdown cycle into a CPU
+            // reset. This is how the BIOS gets back to real mode.
+            debug!(target: "cpu", "Shutdown cycle, resetting the CPU");
+            self.hardware.reset_controller().request(ResetKind::Warm);
+        }
+        if let Some(kind) = self.hardware.reset_controller().take() {
+            self.reset_with(kind);
+        }
+        self.tick(cycles);
+        let stolen = self.hardware.take_stolen_cycles();
+        if stolen > 0 {
+            self.tick(stolen);
+        }
+        let cycles = cycles + stolen;
+        let nmi = self.hardware.nmi();
+        if nmi && !self.nmi_line {
+            trace!(target: "cpu", "NMI");
+            self.cpu.nmi(&mut self.hardware);
+        }
+        self.nmi_line = nmi;
+        if self.cpu.interrupts_enabled() {
+            if let Some(vector) = self.hardware.acknowledge_interrupt() {
+                trace!(target: "cpu", "IRQ vector {:#04x}", vector);
+                self.cpu.irq(&mut self.hardware, vector);
+            }
+        }
+        self.frame_cycles += cycles;
+        if self.frame_cycles >= self.cycles_per_frame {
+            self.frame_cycles -= self.cycles_per_frame;
+            return (cycles, true);
+        }
+        (cycles, false)
+    }
+
+    /// The registers and the code bytes before the next instruction runs,
+    /// if it's to be traced. A halted CPU isn't running any.
+    fn trace_before(&mut self) -> Option<(CpuState, [u8; disasm::MAX_LENGTH])> {
+        let tracer = self.trace.0.as_ref()?;
+        let (cs, ip) = self.cpu.program_counter();
+        let base = self.cpu.code_base();
+        if self.cpu.halted() || !tracer.wants(cs, base.wrapping_add(ip as u32)) {
+            return None;
+        }
+        let mut bytes = [0; disasm::MAX_LENGTH];
+        for (n, byte) in (0..).zip(bytes.iter_mut()) {
+            *byte = self
+                .hardware
+                .peek(base.wrapping_add(ip.wrapping_add(n) as u32));
+        }
+        Some((self.cpu.snapshot(), bytes))
+    }
+
+    /// Traces the instruction that just ran. A trace that can't be written
+    /// stops.
+    fn trace_after(&mut self, before: &CpuState, bytes: [u8; disasm::MAX_LENGTH]) {
+        let after = self.cpu.snapshot();
+        if let Some(tracer) = &mut self.trace.0 {
+            if let Err(err) = tracer.record(before, bytes, &after) {
+                warn!("Trace: {}", err);
+                self.trace.0 = None;
+            }
+        }
+    }
+
+    /// Runs for at least `cycles` CPU cycles, stopping early on a breakpoint,
+    /// a watchpoint or when the CPU halts.
+    pub fn run_for_cycles(&mut self, cycles: usize) -> StopReason {
+        let mut elapsed = 0;
+        while elapsed < cycles {
+            elapsed += self.step().0;
+            if let Some(reason) = self.stop_reason() {
+                return reason;
+            }
+            if self.cpu.halted() {
+                return StopReason::Halted;
+            }
+        }
+        StopReason::CyclesElapsed
+    }
+
+    /// Runs exactly `count` instructions unless a breakpoint, a watchpoint
+    /// or HLT comes first.
+    pub fn run_instructions(&mut self, count: usize) -> StopReason {
+        for _ in 0..count {
+            self.step();
+            if let Some(reason) = self.stop_reason() {
+                return reason;
+            }
+            if self.cpu.halted() {
+                return StopReason::Halted;
+            }
+        }
+        StopReason::InstructionsDone
+    }
+
+    /// Runs until `event` happens. Breakpoints and watchpoints always stop
+    /// execution, even when waiting for something else.
+    pub fn run_until(&mut self, event: RunEvent) -> StopReason {
+        loop {
+            let (_, frame_complete) = self.step();
+            if let Some(reason) = self.stop_reason() {
+                return reason;
+            }
+            if event == RunEvent::Halt && self.cpu.halted() {
+                return StopReason::Halted;
+            }
+            if event == RunEvent::FrameComplete && frame_complete {
+                return StopReason::FrameComplete;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::hardware::breakpoints::Breakpoint;
+
+#[test]
+fn test_run_stops_on_halt_and_breakpoint() {
+    let mut machine = IbmPc5150Machine::new();
+    machine.cpu.regs.seg_regs[1] = 0;
+    machine.cpu.regs.ip = 0x100;
+    machine.hardware.memory.ram[0x100] = 0xf8; // clc
+    machine.hardware.memory.ram[0x101] = 0xf4; // hlt
+    machine.breakpoints.add(Breakpoint::Address(0, 0x101));
+    assert_eq!(machine.run_for_cycles(1000), StopReason::Breakpoint);
+    assert_eq!(machine.run_for_cycles(1000), StopReason::Halted);
+    assert_eq!(
+        machine.run_until(RunEvent::FrameComplete),
+        StopReason::FrameComplete
+    );
+    machine.breakpoints.clear();
+    machine.cpu.halted = false;
+    machine.cpu.regs.ip = 0x100;
+    assert_eq!(machine.run_instructions(1), StopReason::InstructionsDone);
+    assert_eq!(machine.cpu.regs.ip, 0x101);
+}
+
+#[test]
+fn test_run_stops_on_watchpoints() {
+    let mut machine = IbmPc5150Machine::new();
+    machine.cpu.regs.seg_regs[1] = 0;
+    machine.cpu.regs.ip = 0x100;
+    // mov [0x200], al; in al, 0x60; clc; jmp $
+    let code = [0x88, 0x06, 0x00, 0x02, 0xe4, 0x60, 0xf8, 0xeb, 0xfe];
+    machine.hardware.memory.ram[0x100..0x109].copy_from_slice(&code);
+    machine
+        .hardware
+        .memory_watch
+        .watch(0x1ff, 0x200, Watch::Write);
+    // Fetching the code is a read, which isn't watched for here.
+    machine
+        .hardware
+        .memory_watch
+        .watch(0x100, 0x108, Watch::Write);
+    machine.hardware.port_watch.watch_reads(0x60);
+    machine.breakpoints.add(Breakpoint::Physical(0x107));
+    assert_eq!(machine.run_instructions(10), StopReason::MemoryWritten);
+    assert_eq!(machine.cpu.regs.ip, 0x104);
+    assert_eq!(machine.run_instructions(10), StopReason::PortRead);
+    assert_eq!(machine.cpu.regs.ip, 0x106);
+    assert_eq!(machine.run_instructions(10), StopReason::Breakpoint);
+    assert_eq!(machine.cpu.regs.ip, 0x107);
+    machine.breakpoints.clear();
+    assert_eq!(machine.run_instructions(10), StopReason::InstructionsDone);
+}
+
+#[test]
+fn test_ram_resize_on_reset() {
+    let mut machine = IbmPc5150Machine::new();
+    machine.breakpoints.add(Breakpoint::Address(0xf000, 0xe05b));
+    assert!(machine.set_ram_size(100).is_err());
+    assert!(machine.set_ram_size(704).is_err());
+    machine.set_ram_size(256).unwrap();
+    assert_eq!(machine.ram_size(), 64);
+
+    machine.reset();
+    assert_eq!(machine.ram_size(), 256);
+    assert_eq!(
+        machine.breakpoints.list(),
+        [Breakpoint::Address(0xf000, 0xe05b)]
+    );
+    assert!(machine.hardware.common.fdc.as_ref().unwrap().drives[0].is_some());
+    machine.hardware.mem_write_byte(0x3_ffff, 0x12);
+    assert_eq!(machine.hardware.mem_read_byte(0x3_ffff), 0x12);
+    machine.hardware.mem_write_byte(0x4_0000, 0x12);
+    assert_eq!(machine.hardware.mem_read_byte(0x4_0000), 0xff);
+}
+
+#[test]
+fn test_warm_boot() {
+    let mut machine = IbmPc5150Machine::new();
+    machine.hardware.memory.ram[0x500] = 0x42;
+    machine.cpu.regs.ip = 0x100;
+    machine.warm_boot();
+    assert_eq!(machine.cpu.regs.ip, 0);
+    assert_eq!(machine.cpu.regs.seg_regs[1], 0xffff);
+    assert_eq!(&machine.hardware.memory.ram[0x472..0x474], &[0x34, 0x12]);
+    assert_eq!(machine.hardware.memory.ram[0x500], 0x42);
+
+    machine.reset();
+    assert_eq!(&machine.hardware.memory.ram[0x472..0x474], &[0, 0]);
+    assert_eq!(machine.hardware.memory.ram[0x500], 0);
+}
+
+#[test]
+fn test_nmi_sources_and_masks() {
+    // Both boards run `hlt` at 0:100, with the NMI handler at 0:200.
+    let mut pc = IbmPc5150Machine::new();
+    pc.cpu.regs.seg_regs[1] = 0;
+    pc.cpu.regs.ip = 0x100;
+    pc.hardware.memory.ram[0x100] = 0xf4;
+    pc.hardware.memory.ram[0x08..0x0c].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+    pc.hardware.ppi.raise_io_channel_check();
+    pc.run_instructions(2);
+    assert_eq!(pc.cpu.regs.ip, 0x101);
+    // Unmasking lets the latched error through, even with the CPU halted.
+    pc.hardware.io_write_byte(0xa0, 0x80);
+    pc.run_instructions(1);
+    assert_eq!(pc.cpu.regs.ip, 0x200);
+    assert_eq!(pc.hardware.io_read_byte(0x62) & 0xc0, 0x40);
+    // Disabling the check clears it, and with that the line.
+    pc.hardware.io_write_byte(0x61, 0x20);
+    assert!(!pc.hardware.nmi());
+
+    let mut at = IbmPcAtMachine::new();
+    at.cpu
+        .regs
+        .writeseg16(crate::cpu286::registers::SegReg::CS, 0);
+    at.cpu.regs.ip = 0x100;
+    at.hardware.memory.ram[0x100] = 0xf4;
+    at.hardware.memory.ram[0x08..0x0c].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+    at.hardware.io_write_byte(0x70, 0x80);
+    at.hardware.system_control.raise_parity_error();
+    at.run_instructions(2);
+    assert_eq!(at.cpu.regs.ip, 0x101);
+    at.hardware.io_write_byte(0x70, 0x0d);
+    at.run_instructions(1);
+    assert_eq!(at.cpu.regs.ip, 0x200);
+    assert_eq!(at.hardware.io_read_byte(0x61) & 0xc0, 0x80);
 }
@@ -0,0 +1,125 @@
+// The Roland MPU-401 MIDI interface at 330h-331h. Nearly every DOS game
+// that plays General MIDI puts it straight into UART mode and writes raw
+// MIDI to the data port, so that's what is emulated. Intelligent mode
+// commands are only acknowledged, which is enough for drivers that try
+// them first. The bytes written in UART mode queue up here for the
+// frontend to pass to the host's MIDI output.
+use crate::hardware::reset::{Reset, ResetKind};
+use log::debug;
+use std::collections::VecDeque;
+
+pub const MPU_BASE: u16 = 0x330;
+
+/// Status port bits, set when the interface is *not* ready: DRR for
+/// taking a write, DSR for having a byte to read.
+const STATUS_DRR: u8 = 0x40;
+const STATUS_DSR: u8 = 0x80;
+
+/// What the interface answers every command with.
+const ACK: u8 = 0xfe;
+
+const COMMAND_UART: u8 = 0x3f;
+const COMMAND_VERSION: u8 = 0xac;
+const COMMAND_REVISION: u8 = 0xad;
+const COMMAND_RESET: u8 = 0xff;
+
+#[derive(Debug, Clone, Default)]
+pub struct MPU401 {
+    uart: bool,
+    /// Bytes for the host to read at the data port.
+    input: VecDeque<u8>,
+    /// MIDI bytes waiting to go out.
+    midi: Vec<u8>,
+}
+
+impl MPU401 {
+    pub fn new() -> MPU401 {
+        MPU401::default()
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr & 1 {
+            0 => self.input.pop_front().unwrap_or(0xff),
+            // Always ready for a write.
+            _ => match self.input.is_empty() {
+                true => !STATUS_DRR,
+                false => !(STATUS_DRR | STATUS_DSR),
+            },
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr & 1 {
+            0 if self.uart => self.midi.push(data),
+            0 => debug!(target: "io", "MPU-401 data {:#04x} outside UART mode", data),
+            _ => self.command(data),
+        }
+    }
+
+    fn command(&mut self, command: u8) {
+        // UART mode ignores everything but a reset, which it doesn't
+        // acknowledge.
+        if command == COMMAND_RESET {
+            self.input.clear();
+            if !std::mem::take(&mut self.uart) {
+                self.input.push_back(ACK);
+            }
+            return;
+        }
+        if self.uart {
+            return;
+        }
+        self.input.push_back(ACK);
+        match command {
+            COMMAND_UART => self.uart = true,
+            COMMAND_VERSION => self.input.push_back(0x15),
+            COMMAND_REVISION => self.input.push_back(0x01),
+            _ => debug!(target: "io", "MPU-401 command {:#04x} acknowledged", command),
+        }
+    }
+
+    /// Takes the MIDI bytes written since the last call.
+    pub fn take_midi(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.midi)
+    }
+}
+
+impl Reset for MPU401 {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        *self = MPU401::new();
+    }
+}
+
+#[test]
+fn test_mpu401_uart_mode() {
+    let mut mpu = MPU401::new();
+    let status = |mpu: &mut MPU401| mpu.rb(MPU_BASE + 1);
+
+    // A reset and the switch to UART mode are both acknowledged.
+    assert_eq!(status(&mut mpu) & (STATUS_DRR | STATUS_DSR), STATUS_DSR);
+    mpu.wb(MPU_BASE + 1, COMMAND_RESET);
+    assert_eq!(status(&mut mpu) & STATUS_DSR, 0);
+    assert_eq!(mpu.rb(MPU_BASE), ACK);
+    mpu.wb(MPU_BASE + 1, COMMAND_VERSION);
+    assert_eq!((mpu.rb(MPU_BASE), mpu.rb(MPU_BASE)), (ACK, 0x15));
+    mpu.wb(MPU_BASE, 0x90);
+    mpu.wb(MPU_BASE + 1, COMMAND_UART);
+    assert_eq!(mpu.rb(MPU_BASE), ACK);
+    assert_eq!(status(&mut mpu) & STATUS_DSR, STATUS_DSR);
+
+    // Then the data port is raw MIDI out.
+    for data in [0x90, 0x3c, 0x7f] {
+        mpu.wb(MPU_BASE, data);
+    }
+    assert_eq!(mpu.take_midi(), vec![0x90, 0x3c, 0x7f]);
+    assert!(mpu.take_midi().is_empty());
+
+    // A reset leaves UART mode without an acknowledgement.
+    mpu.wb(MPU_BASE + 1, COMMAND_RESET);
+    assert_eq!(status(&mut mpu) & STATUS_DSR, STATUS_DSR);
+    mpu.wb(MPU_BASE, 0x80);
+    assert!(mpu.take_midi().is_empty());
+}
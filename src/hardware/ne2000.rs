@@ -0,0 +1,523 @@
+// An NE2000: a DP8390 network controller with 16K of buffer RAM on its
+// own side of the card, which the PC only reaches through the controller's
+// remote DMA and the data port. Received frames go into a ring of 256 byte
+// pages in that RAM, each behind a four byte header, and frames are sent
+// from wherever in it the driver assembled them. The frames themselves go
+// to and come from a `NetworkBackend`; with none connected the cable is
+// unplugged.
+use crate::hardware::passthrough::NetworkBackend;
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace};
+use std::fmt;
+
+/// Where the card's jumpers come set. IRQ 2 arrives on IRQ 9 on an AT.
+pub const NE2000_BASE: u16 = 0x300;
+pub const NE2000_IRQ: u8 = 2;
+
+/// How often the board checks the backend for frames.
+pub const NE2000_POLL_HZ: u32 = 1000;
+
+/// The buffer RAM sits at 4000h-7FFFh in the card's address space, with
+/// the station address PROM at the bottom.
+const RAM_START: usize = 0x4000;
+const RAM_SIZE: usize = 0x4000;
+const PROM_SIZE: usize = 32;
+
+const PAGE_SIZE: usize = 256;
+/// Ethernet frames shorter than this are padded, not counting the CRC.
+const MIN_FRAME: usize = 60;
+
+/// Command register bits.
+const CR_STP: u8 = 0x01;
+const CR_STA: u8 = 0x02;
+const CR_TXP: u8 = 0x04;
+const CR_RD_READ: u8 = 0x08;
+const CR_RD_WRITE: u8 = 0x10;
+const CR_RD_SEND: u8 = 0x18;
+const CR_RD_ABORT: u8 = 0x20;
+const CR_RD: u8 = 0x38;
+
+/// Interrupt status register bits.
+const ISR_PRX: u8 = 0x01;
+const ISR_PTX: u8 = 0x02;
+const ISR_OVW: u8 = 0x10;
+const ISR_RDC: u8 = 0x40;
+const ISR_RST: u8 = 0x80;
+
+/// Receive configuration register bits.
+const RCR_AB: u8 = 0x04;
+const RCR_AM: u8 = 0x08;
+const RCR_PRO: u8 = 0x10;
+const RCR_MON: u8 = 0x20;
+
+/// Receive status bits.
+const RSR_PRX: u8 = 0x01;
+const RSR_PHY: u8 = 0x20;
+
+const TSR_PTX: u8 = 0x01;
+/// TCR's loopback mode field.
+const TCR_LOOPBACK: u8 = 0x06;
+
+/// The card's MAC address unless told otherwise, in the locally
+/// administered range.
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// What the card's cable is plugged into. A copy of the machine comes up
+/// unplugged, as two cards can't share one host device.
+#[derive(Default)]
+pub struct NetworkConnection(Option<Box<dyn NetworkBackend>>);
+
+impl Clone for NetworkConnection {
+    fn clone(&self) -> NetworkConnection {
+        NetworkConnection(None)
+    }
+}
+
+impl fmt::Debug for NetworkConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "NetworkConnection(connected)"),
+            None => write!(f, "NetworkConnection(none)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NE2000 {
+    pub base: u16,
+    pub irq: u8,
+    pub mac: [u8; 6],
+    backend: NetworkConnection,
+    prom: [u8; PROM_SIZE],
+    ram: Vec<u8>,
+    cr: u8,
+    isr: u8,
+    imr: u8,
+    /// The receive ring, in pages, and the pages either end of the part
+    /// the driver hasn't taken yet.
+    pstart: u8,
+    pstop: u8,
+    bnry: u8,
+    curr: u8,
+    tpsr: u8,
+    tbcr: u16,
+    tsr: u8,
+    /// The remote DMA's address and count.
+    rsar: u16,
+    rbcr: u16,
+    rcr: u8,
+    rsr: u8,
+    tcr: u8,
+    dcr: u8,
+    par: [u8; 6],
+    mar: [u8; 8],
+}
+
+impl NE2000 {
+    pub fn new(base: u16, irq: u8, mac: [u8; 6]) -> NE2000 {
+        // Each byte of the PROM is doubled, as the card reads it a word
+        // at a time; the "WW" at the end is how drivers tell an NE2000
+        // from an NE1000.
+        let mut prom = [0x57; PROM_SIZE];
+        for (i, byte) in mac.iter().enumerate() {
+            prom[i * 2] = *byte;
+            prom[i * 2 + 1] = *byte;
+        }
+        NE2000 {
+            base,
+            irq,
+            mac,
+            backend: NetworkConnection::default(),
+            prom,
+            ram: vec![0; RAM_SIZE],
+            cr: CR_STP | CR_RD_ABORT,
+            isr: ISR_RST,
+            imr: 0,
+            pstart: 0x40,
+            pstop: 0x80,
+            bnry: 0x40,
+            curr: 0x40,
+            tpsr: 0x40,
+            tbcr: 0,
+            tsr: 0,
+            rsar: 0,
+            rbcr: 0,
+            rcr: 0,
+            rsr: 0,
+            tcr: 0,
+            dcr: 0,
+            par: [0; 6],
+            mar: [0; 8],
+        }
+    }
+
+    pub fn connect(&mut self, backend: Box<dyn NetworkBackend>) {
+        self.backend = NetworkConnection(Some(backend));
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn NetworkBackend>> {
+        self.backend.0.take()
+    }
+
+    pub fn claims(&self, addr: u16) -> bool {
+        addr.wrapping_sub(self.base) < 0x20
+    }
+
+    pub fn irq(&self) -> bool {
+        (self.isr & self.imr & 0x7f) != 0
+    }
+
+    fn started(&self) -> bool {
+        (self.cr & (CR_STA | CR_STP)) == CR_STA
+    }
+
+    /// Stopping the controller or pulsing the reset port.
+    fn soft_reset(&mut self) {
+        self.isr |= ISR_RST;
+        self.cr = (self.cr & !(CR_STA | CR_TXP)) | CR_STP;
+    }
+
+    fn read_memory(&self, addr: u16) -> u8 {
+        let addr = addr as usize;
+        match addr {
+            0..=0x3fff => self.prom[addr % PROM_SIZE],
+            RAM_START..=0x7fff => self.ram[addr - RAM_START],
+            _ => 0xff,
+        }
+    }
+
+    fn write_memory(&mut self, addr: u16, data: u8) {
+        let addr = addr as usize;
+        if (RAM_START..RAM_START + RAM_SIZE).contains(&addr) {
+            self.ram[addr - RAM_START] = data;
+        }
+    }
+
+    /// Moves the remote DMA on a byte, wrapping round the receive ring.
+    fn advance_remote_dma(&mut self) {
+        self.rsar = self.rsar.wrapping_add(1);
+        if self.rsar == (self.pstop as u16) << 8 {
+            self.rsar = (self.pstart as u16) << 8;
+        }
+        self.rbcr = self.rbcr.saturating_sub(1);
+        if self.rbcr == 0 {
+            self.isr |= ISR_RDC;
+            self.cr = (self.cr & !CR_RD) | CR_RD_ABORT;
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        if (self.cr & CR_RD) != CR_RD_READ || self.rbcr == 0 {
+            return 0xff;
+        }
+        let data = self.read_memory(self.rsar);
+        self.advance_remote_dma();
+        data
+    }
+
+    fn write_data(&mut self, data: u8) {
+        if (self.cr & CR_RD) != CR_RD_WRITE || self.rbcr == 0 {
+            return;
+        }
+        self.write_memory(self.rsar, data);
+        self.advance_remote_dma();
+    }
+
+    fn write_command(&mut self, data: u8) {
+        self.cr = data;
+        if (data & CR_STP) != 0 {
+            self.soft_reset();
+            return;
+        }
+        if (data & CR_STA) != 0 {
+            self.isr &= !ISR_RST;
+        }
+        // Send packet reads the frame at the boundary back out through the
+        // data port, using the count in its header.
+        if (data & CR_RD) == CR_RD_SEND {
+            let header = (self.bnry as u16) << 8;
+            self.rsar = header;
+            self.rbcr =
+                u16::from_le_bytes([self.read_memory(header + 2), self.read_memory(header + 3)]);
+            self.cr = (self.cr & !CR_RD) | CR_RD_READ;
+        }
+        if (data & CR_TXP) != 0 {
+            self.transmit();
+        }
+    }
+
+    fn transmit(&mut self) {
+        let start = (self.tpsr as usize) << 8;
+        let frame: Vec<u8> = (0..self.tbcr as usize)
+            .map(|i| self.read_memory((start + i) as u16))
+            .collect();
+        trace!(target: "io", "NE2000 sending {} bytes", frame.len());
+        if (self.tcr & TCR_LOOPBACK) != 0 {
+            self.receive(&frame);
+        } else if let Some(backend) = &mut self.backend.0 {
+            backend.send(&frame);
+        }
+        self.cr &= !CR_TXP;
+        self.tsr = TSR_PTX;
+        self.isr |= ISR_PTX;
+    }
+
+    /// Whether the address filter lets a frame through.
+    fn accepts(&self, frame: &[u8]) -> bool {
+        let destination = &frame[..6];
+        if (self.rcr & RCR_PRO) != 0 || destination == self.par {
+            return true;
+        }
+        if destination == [0xff; 6] {
+            return (self.rcr & RCR_AB) != 0;
+        }
+        if (destination[0] & 0x01) != 0 && (self.rcr & RCR_AM) != 0 {
+            // The top six bits of the CRC pick a bit of the hash filter.
+            let index = (crc32(destination) >> 26) as usize;
+            return (self.mar[index / 8] & (1 << (index % 8))) != 0;
+        }
+        false
+    }
+
+    /// Puts a frame into the receive ring, if the filter takes it and
+    /// there's room.
+    fn receive(&mut self, frame: &[u8]) {
+        if !self.started() || frame.len() < 6 || !self.accepts(frame) {
+            return;
+        }
+        if (self.rcr & RCR_MON) != 0 {
+            return;
+        }
+        let length = frame.len().max(MIN_FRAME);
+        let total = length + 4;
+        let pages = total.div_ceil(PAGE_SIZE);
+        let ring = self.pstop.saturating_sub(self.pstart) as usize;
+        let used = (self.curr as usize + ring - self.bnry as usize) % ring.max(1);
+        if ring == 0 || used + pages >= ring {
+            debug!(target: "io", "NE2000 receive ring overflowed");
+            self.isr |= ISR_OVW;
+            return;
+        }
+        let mut next = self.curr as usize + pages;
+        if next >= self.pstop as usize {
+            next -= ring;
+        }
+        self.rsr = RSR_PRX;
+        if (frame[0] & 0x01) != 0 {
+            self.rsr |= RSR_PHY;
+        }
+        let [low, high] = (total as u16).to_le_bytes();
+        let header = [self.rsr, next as u8, low, high];
+        let mut addr = (self.curr as usize) << 8;
+        let padding = std::iter::repeat_n(0, length - frame.len());
+        for byte in header
+            .iter()
+            .copied()
+            .chain(frame.iter().copied())
+            .chain(padding)
+        {
+            self.write_memory(addr as u16, byte);
+            addr += 1;
+            if addr == (self.pstop as usize) << 8 {
+                addr = (self.pstart as usize) << 8;
+            }
+        }
+        self.curr = next as u8;
+        self.isr |= ISR_PRX;
+    }
+
+    /// Takes in any frames waiting at the backend.
+    pub fn poll(&mut self) {
+        while self.started() {
+            let frame = match self
+                .backend
+                .0
+                .as_mut()
+                .and_then(|backend| backend.receive())
+            {
+                Some(frame) => frame,
+                None => return,
+            };
+            self.receive(&frame);
+        }
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        let offset = addr.wrapping_sub(self.base);
+        match (offset, self.cr >> 6) {
+            (0x00, _) => self.cr,
+            (0x10..=0x17, _) => self.read_data(),
+            (0x18..=0x1f, _) => {
+                self.soft_reset();
+                0xff
+            }
+            (0x01, 0) => (self.rsar & 0xff) as u8,
+            (0x02, 0) => (self.rsar >> 8) as u8,
+            (0x03, 0) => self.bnry,
+            (0x04, 0) => self.tsr,
+            (0x07, 0) => self.isr,
+            (0x08, 0) => (self.rsar & 0xff) as u8,
+            (0x09, 0) => (self.rsar >> 8) as u8,
+            (0x0c, 0) => self.rsr,
+            (0x01..=0x06, 1) => self.par[offset as usize - 1],
+            (0x07, 1) => self.curr,
+            (0x08..=0x0f, 1) => self.mar[offset as usize - 8],
+            (0x01, 2) => self.pstart,
+            (0x02, 2) => self.pstop,
+            (0x04, 2) => self.tpsr,
+            (0x0c, 2) => self.rcr,
+            (0x0d, 2) => self.tcr,
+            (0x0e, 2) => self.dcr,
+            (0x0f, 2) => self.imr,
+            // The tally counters, which never count anything.
+            _ => 0,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        let offset = addr.wrapping_sub(self.base);
+        match (offset, self.cr >> 6) {
+            (0x00, _) => self.write_command(data),
+            (0x10..=0x17, _) => self.write_data(data),
+            (0x18..=0x1f, _) => {}
+            (0x01, 0) => self.pstart = data,
+            (0x02, 0) => self.pstop = data,
+            (0x03, 0) => self.bnry = data,
+            (0x04, 0) => self.tpsr = data,
+            (0x05, 0) => self.tbcr = (self.tbcr & 0xff00) | data as u16,
+            (0x06, 0) => self.tbcr = (self.tbcr & 0x00ff) | (data as u16) << 8,
+            (0x07, 0) => self.isr &= !(data & 0x7f),
+            (0x08, 0) => self.rsar = (self.rsar & 0xff00) | data as u16,
+            (0x09, 0) => self.rsar = (self.rsar & 0x00ff) | (data as u16) << 8,
+            (0x0a, 0) => self.rbcr = (self.rbcr & 0xff00) | data as u16,
+            (0x0b, 0) => self.rbcr = (self.rbcr & 0x00ff) | (data as u16) << 8,
+            (0x0c, 0) => self.rcr = data,
+            (0x0d, 0) => self.tcr = data,
+            (0x0e, 0) => self.dcr = data,
+            (0x0f, 0) => self.imr = data,
+            (0x01..=0x06, 1) => self.par[offset as usize - 1] = data,
+            (0x07, 1) => self.curr = data,
+            (0x08..=0x0f, 1) => self.mar[offset as usize - 8] = data,
+            _ => debug!(target: "io", "NE2000 write {:#04x} to {:#04x}", data, offset),
+        }
+    }
+}
+
+impl Reset for NE2000 {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        let backend = self.disconnect();
+        *self = NE2000::new(self.base, self.irq, self.mac);
+        if let Some(backend) = backend {
+            self.connect(backend);
+        }
+    }
+}
+
+/// The Ethernet CRC, which the multicast filter hashes addresses with.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        let mut byte = byte;
+        for _ in 0..8 {
+            let carry = ((crc >> 31) as u8 ^ (byte & 1)) != 0;
+            crc <<= 1;
+            byte >>= 1;
+            if carry {
+                crc ^= 0x04c1_1db7;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+use std::collections::VecDeque;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn test_ne2000_send_and_receive() {
+    struct Cable(Arc<Mutex<Vec<Vec<u8>>>>, VecDeque<Vec<u8>>);
+    impl NetworkBackend for Cable {
+        fn send(&mut self, frame: &[u8]) {
+            self.0.lock().unwrap().push(frame.to_vec());
+        }
+        fn receive(&mut self) -> Option<Vec<u8>> {
+            self.1.pop_front()
+        }
+    }
+    let base = NE2000_BASE;
+    let mut nic = NE2000::new(base, NE2000_IRQ, DEFAULT_MAC);
+    let sent = Arc::new(Mutex::new(vec![]));
+    let mut incoming = DEFAULT_MAC.to_vec();
+    incoming.extend_from_slice(&[0x02, 0, 0, 0, 0, 1, 0x08, 0x00, 0xab]);
+    let broadcast = vec![0xff; 14];
+    nic.connect(Box::new(Cable(
+        sent.clone(),
+        VecDeque::from(vec![incoming.clone(), broadcast]),
+    )));
+
+    // What a packet driver does first: read the PROM with remote DMA.
+    let remote_dma = |nic: &mut NE2000, addr: u16, count: u16, command: u8| {
+        nic.wb(base + 0x08, addr as u8);
+        nic.wb(base + 0x09, (addr >> 8) as u8);
+        nic.wb(base + 0x0a, count as u8);
+        nic.wb(base + 0x0b, (count >> 8) as u8);
+        nic.wb(base, command | CR_STA);
+    };
+    nic.wb(base + 0x0e, 0x49);
+    remote_dma(&mut nic, 0, 32, CR_RD_READ);
+    let prom: Vec<u8> = (0..32).map(|_| nic.rb(base + 0x10)).collect();
+    assert_eq!(
+        prom[..12],
+        [0x52, 0x52, 0x54, 0x54, 0, 0, 0x12, 0x12, 0x34, 0x34, 0x56, 0x56]
+    );
+    assert_eq!(prom[28..], [0x57; 4]);
+    assert_eq!(nic.rb(base + 0x07) & ISR_RDC, ISR_RDC);
+
+    // Set up the ring at 46h-80h and the station address, taking unicast
+    // frames only.
+    nic.wb(base, CR_STP | CR_RD_ABORT);
+    nic.wb(base + 0x01, 0x46);
+    nic.wb(base + 0x02, 0x80);
+    nic.wb(base + 0x03, 0x46);
+    nic.wb(base + 0x07, 0xff);
+    nic.wb(base + 0x0f, ISR_PRX | ISR_PTX);
+    nic.wb(base, 0x40 | CR_STP | CR_RD_ABORT);
+    for (i, byte) in DEFAULT_MAC.iter().enumerate() {
+        nic.wb(base + 1 + i as u16, *byte);
+    }
+    nic.wb(base + 0x07, 0x46);
+    nic.wb(base, CR_STA | CR_RD_ABORT);
+
+    // The unicast frame lands padded behind its header; the broadcast
+    // doesn't get through the filter.
+    nic.poll();
+    assert!(nic.irq());
+    assert_eq!(nic.curr, 0x47);
+    remote_dma(&mut nic, 0x4600, 4, CR_RD_READ);
+    let header: Vec<u8> = (0..4).map(|_| nic.rb(base + 0x10)).collect();
+    assert_eq!(header, [RSR_PRX, 0x47, 64, 0]);
+    remote_dma(&mut nic, 0x4604, 16, CR_RD_READ);
+    let frame: Vec<u8> = (0..16).map(|_| nic.rb(base + 0x10)).collect();
+    assert_eq!(frame[..15], incoming[..]);
+    assert_eq!(frame[15], 0);
+    nic.wb(base + 0x07, ISR_PRX);
+    assert!(!nic.irq());
+
+    // Sending: copy the frame in by remote DMA, then transmit it.
+    remote_dma(&mut nic, 0x4000, 3, CR_RD_WRITE);
+    for byte in [1, 2, 3] {
+        nic.wb(base + 0x10, byte);
+    }
+    nic.wb(base + 0x04, 0x40);
+    nic.wb(base + 0x05, 3);
+    nic.wb(base + 0x06, 0);
+    nic.wb(base, CR_STA | CR_TXP | CR_RD_ABORT);
+    assert_eq!(*sent.lock().unwrap(), vec![vec![1, 2, 3]]);
+    assert!(nic.irq());
+    assert_eq!(nic.rb(base + 0x04), TSR_PTX);
+}
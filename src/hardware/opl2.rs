@@ -0,0 +1,626 @@
+// The Yamaha YM3812 (OPL2) FM synthesizer, as on the AdLib card and in the
+// Sound Blaster. It has eighteen operators in nine two-operator channels,
+// or six channels and five rhythm instruments. Samples come out at the
+// chip's own rate of 49716 Hz, the 3.58 MHz crystal divided by 72. Phase,
+// level scaling, the LFOs, rhythm and the timers follow the chip. The
+// envelope rates are close to its timings, without its exact step
+// patterns.
+use crate::hardware::reset::{Reset, ResetKind};
+use log::trace;
+use std::f32::consts::TAU;
+
+pub const OPL_SAMPLE_RATE: u32 = 49_716;
+
+/// Frequency multipliers, doubled so MULT 0 can be a half.
+const MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+/// Key scale level attenuation by the top four F-number bits, in 0.75 dB
+/// steps for block 7, and the shifts for KSL 0-3: none, 3, 1.5 and 6 dB an
+/// octave.
+const KSL_ROM: [i32; 16] = [
+    0, 32, 40, 45, 48, 51, 53, 55, 56, 58, 59, 60, 61, 62, 63, 64,
+];
+const KSL_SHIFT: [u32; 4] = [8, 1, 2, 0];
+
+/// Envelopes count attenuation in 0.1875 dB steps, with 16 fractional
+/// bits so the slow rates can move by less than a step a sample.
+const ENVELOPE_SHIFT: u32 = 16;
+const MAX_ATTENUATION: u32 = 511;
+const SILENT: u32 = MAX_ATTENUATION << ENVELOPE_SHIFT;
+
+/// Status register bits.
+const STATUS_IRQ: u8 = 0x80;
+const STATUS_TIMER1: u8 = 0x40;
+const STATUS_TIMER2: u8 = 0x20;
+/// Bits an OPL2 always reads back as set, which is how programs tell it
+/// from an OPL3.
+const STATUS_OPL2: u8 = 0x06;
+
+/// Timer 1 counts every 80 microseconds and timer 2 every 320, which is
+/// every 4 and every 16 samples.
+const TIMER_PERIODS: [u32; 2] = [4, 16];
+
+/// The rhythm instruments' bits in BDh, and the operators they play.
+const RHYTHM_OPERATORS: [(u8, &[usize]); 5] = [
+    (0x10, &[12, 15]), // bass drum
+    (0x08, &[16]),     // snare drum
+    (0x04, &[14]),     // tom-tom
+    (0x02, &[17]),     // top cymbal
+    (0x01, &[13]),     // hi-hat
+];
+
+/// Whether the operator is keyed on by its channel, or as a rhythm
+/// instrument. It sounds while either is on.
+const KEY_CHANNEL: u8 = 0x01;
+const KEY_RHYTHM: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    #[default]
+    Release,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Operator {
+    tremolo: bool,
+    vibrato: bool,
+    /// EGT: hold at the sustain level until key off, rather than going
+    /// straight on to release.
+    sustain: bool,
+    ksr: bool,
+    multiplier: u8,
+    ksl: u8,
+    total_level: u8,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+    waveform: u8,
+    /// A 19-bit phase, the top ten bits of which index the waveform.
+    phase: u32,
+    envelope: u32,
+    stage: Stage,
+    key: u8,
+    /// The last two outputs, for feedback.
+    out: i32,
+    previous: i32,
+}
+
+impl Operator {
+    fn key_on(&mut self, source: u8) {
+        if self.key == 0 {
+            self.phase = 0;
+            self.stage = Stage::Attack;
+        }
+        self.key |= source;
+    }
+
+    fn key_off(&mut self, source: u8) {
+        if self.key != 0 {
+            self.key &= !source;
+            if self.key == 0 {
+                self.stage = Stage::Release;
+            }
+        }
+    }
+
+    fn attenuation(&self) -> u32 {
+        self.envelope >> ENVELOPE_SHIFT
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Channel {
+    fnum: u16,
+    block: u8,
+    key: bool,
+    feedback: u8,
+    additive: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Timer {
+    preset: u8,
+    counter: u8,
+    running: bool,
+    masked: bool,
+}
+
+/// The register offset in 20h-95h and E0h-F5h of each operator. Offsets
+/// 6, 7, 0Eh and 0Fh reach none.
+fn operator_at(offset: u8) -> Option<usize> {
+    match offset {
+        0x00..=0x05 => Some(offset as usize),
+        0x08..=0x0d => Some(offset as usize - 2),
+        0x10..=0x15 => Some(offset as usize - 4),
+        _ => None,
+    }
+}
+
+/// The channel an operator belongs to: operators 0-2 are the first ones
+/// of channels 0-2, 3-5 their second ones, and so on in threes.
+fn channel_of(operator: usize) -> usize {
+    operator / 6 * 3 + operator % 3
+}
+
+/// A channel's first and second operators.
+fn operators_of(channel: usize) -> (usize, usize) {
+    let first = channel / 3 * 6 + channel % 3;
+    (first, first + 3)
+}
+
+/// The attenuation, in envelope units, that a rate changes the envelope
+/// by each sample: nothing below rate 4, then doubling every four rates.
+fn rate_increment(rate: u32) -> u32 {
+    match rate {
+        0..=3 => 0,
+        _ => (4 + (rate & 3)) << ((rate >> 2) + 1),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OPL2 {
+    operators: [Operator; 18],
+    channels: [Channel; 9],
+    address: u8,
+    waveform_select: bool,
+    /// NTS: which F-number bit key scaling splits the octave on.
+    note_select: bool,
+    deep_tremolo: bool,
+    deep_vibrato: bool,
+    rhythm: bool,
+    timers: [Timer; 2],
+    status: u8,
+    /// Samples made, which the LFOs and timers step by.
+    samples: u32,
+    noise: u32,
+    /// The output since the last `sample`, and the last sample, for when
+    /// no chip samples have been made since.
+    sum: i32,
+    count: u32,
+    last: f32,
+}
+
+impl OPL2 {
+    pub fn new() -> OPL2 {
+        OPL2 {
+            operators: [Operator {
+                envelope: SILENT,
+                ..Operator::default()
+            }; 18],
+            channels: [Channel::default(); 9],
+            address: 0,
+            waveform_select: false,
+            note_select: false,
+            deep_tremolo: false,
+            deep_vibrato: false,
+            rhythm: false,
+            timers: [Timer::default(); 2],
+            status: 0,
+            samples: 0,
+            noise: 1,
+            sum: 0,
+            count: 0,
+            last: 0.0,
+        }
+    }
+
+    /// The status register at the even port, where detection routines
+    /// watch the timer flags.
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr & 1 {
+            0 => self.status | STATUS_OPL2,
+            _ => 0xff,
+        }
+    }
+
+    /// The address register at the even port, data at the odd one.
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr & 1 {
+            0 => self.address = data,
+            _ => self.write_register(self.address, data),
+        }
+    }
+
+    fn write_register(&mut self, register: u8, data: u8) {
+        trace!(target: "io", "OPL2 register {:#04x} = {:#04x}", register, data);
+        let operator = operator_at(register & 0x1f);
+        match (register, operator) {
+            (0x01, _) => self.waveform_select = (data & 0x20) != 0,
+            (0x02, _) => self.timers[0].preset = data,
+            (0x03, _) => self.timers[1].preset = data,
+            (0x04, _) => self.write_timer_control(data),
+            (0x08, _) => self.note_select = (data & 0x40) != 0,
+            (0x20..=0x35, Some(operator)) => {
+                let op = &mut self.operators[operator];
+                op.tremolo = (data & 0x80) != 0;
+                op.vibrato = (data & 0x40) != 0;
+                op.sustain = (data & 0x20) != 0;
+                op.ksr = (data & 0x10) != 0;
+                op.multiplier = data & 0x0f;
+            }
+            (0x40..=0x55, Some(operator)) => {
+                let op = &mut self.operators[operator];
+                op.ksl = data >> 6;
+                op.total_level = data & 0x3f;
+            }
+            (0x60..=0x75, Some(operator)) => {
+                let op = &mut self.operators[operator];
+                op.attack = data >> 4;
+                op.decay = data & 0x0f;
+            }
+            (0x80..=0x95, Some(operator)) => {
+                let op = &mut self.operators[operator];
+                op.sustain_level = data >> 4;
+                op.release = data & 0x0f;
+            }
+            (0xe0..=0xf5, Some(operator)) => self.operators[operator].waveform = data & 0x03,
+            (0xa0..=0xa8, _) => {
+                let channel = &mut self.channels[(register - 0xa0) as usize];
+                channel.fnum = (channel.fnum & 0x300) | data as u16;
+            }
+            (0xb0..=0xb8, _) => {
+                let index = (register - 0xb0) as usize;
+                let channel = &mut self.channels[index];
+                channel.fnum = (channel.fnum & 0xff) | ((data & 0x03) as u16) << 8;
+                channel.block = (data >> 2) & 0x07;
+                channel.key = (data & 0x20) != 0;
+                let key = channel.key;
+                let (first, second) = operators_of(index);
+                for operator in [first, second] {
+                    match key {
+                        true => self.operators[operator].key_on(KEY_CHANNEL),
+                        false => self.operators[operator].key_off(KEY_CHANNEL),
+                    }
+                }
+            }
+            (0xbd, _) => self.write_rhythm(data),
+            (0xc0..=0xc8, _) => {
+                let channel = &mut self.channels[(register - 0xc0) as usize];
+                channel.feedback = (data >> 1) & 0x07;
+                channel.additive = (data & 0x01) != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn write_timer_control(&mut self, data: u8) {
+        if (data & 0x80) != 0 {
+            self.status = 0;
+            return;
+        }
+        for (i, timer) in self.timers.iter_mut().enumerate() {
+            timer.masked = (data & (0x40 >> i)) != 0;
+            let start = (data & (1 << i)) != 0;
+            if start && !timer.running {
+                timer.counter = timer.preset;
+            }
+            timer.running = start;
+        }
+    }
+
+    fn write_rhythm(&mut self, data: u8) {
+        self.deep_tremolo = (data & 0x80) != 0;
+        self.deep_vibrato = (data & 0x40) != 0;
+        self.rhythm = (data & 0x20) != 0;
+        for &(bit, operators) in RHYTHM_OPERATORS.iter() {
+            for &operator in operators {
+                match self.rhythm && (data & bit) != 0 {
+                    true => self.operators[operator].key_on(KEY_RHYTHM),
+                    false => self.operators[operator].key_off(KEY_RHYTHM),
+                }
+            }
+        }
+    }
+
+    /// Makes `ticks` samples at the chip's rate.
+    pub fn tick(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            let sample = self.generate();
+            self.sum += sample;
+            self.count += 1;
+        }
+    }
+
+    /// The average of the samples made since the last call, for the mixer.
+    pub fn sample(&mut self) -> f32 {
+        if self.count > 0 {
+            self.last = self.sum as f32 / self.count as f32 / 32768.0;
+            self.sum = 0;
+            self.count = 0;
+        }
+        self.last
+    }
+
+    fn tick_timers(&mut self) {
+        for (i, timer) in self.timers.iter_mut().enumerate() {
+            if !timer.running || !self.samples.is_multiple_of(TIMER_PERIODS[i]) {
+                continue;
+            }
+            timer.counter = timer.counter.wrapping_add(1);
+            if timer.counter == 0 {
+                timer.counter = timer.preset;
+                if !timer.masked {
+                    self.status |= STATUS_IRQ | [STATUS_TIMER1, STATUS_TIMER2][i];
+                }
+            }
+        }
+    }
+
+    /// The tremolo LFO's attenuation: a 3.7 Hz triangle up to 4.8 dB, or
+    /// 1.2 dB without deep tremolo.
+    fn tremolo(&self) -> u32 {
+        let position = (self.samples >> 6) % 210;
+        let triangle = if position < 105 {
+            position
+        } else {
+            210 - position
+        };
+        triangle >> if self.deep_tremolo { 2 } else { 4 }
+    }
+
+    /// The F-number, bent by the 6.1 Hz vibrato LFO if the operator has it
+    /// on, by up to 14 cents, or 7 without deep vibrato.
+    fn fnum(&self, operator: usize) -> u32 {
+        let fnum = self.channels[channel_of(operator)].fnum as i32;
+        if !self.operators[operator].vibrato {
+            return fnum as u32;
+        }
+        let position = (self.samples >> 10) & 7;
+        let mut range = (fnum >> 7) & 7;
+        if (position & 3) == 0 {
+            range = 0;
+        } else if (position & 1) != 0 {
+            range >>= 1;
+        }
+        if !self.deep_vibrato {
+            range >>= 1;
+        }
+        if (position & 4) != 0 {
+            range = -range;
+        }
+        (fnum + range) as u32
+    }
+
+    /// The key scale value the rates go up by, for higher notes.
+    fn key_scale(&self, operator: usize) -> u32 {
+        let channel = &self.channels[channel_of(operator)];
+        let bit = match self.note_select {
+            true => (channel.fnum >> 8) & 1,
+            false => channel.fnum >> 9,
+        };
+        let value = (channel.block as u32) << 1 | bit as u32;
+        match self.operators[operator].ksr {
+            true => value,
+            false => value >> 2,
+        }
+    }
+
+    fn rate(&self, operator: usize, rate: u8) -> u32 {
+        match rate {
+            0 => 0,
+            _ => (rate as u32 * 4 + self.key_scale(operator)).min(63),
+        }
+    }
+
+    fn step_envelope(&mut self, operator: usize) {
+        let attack = self.rate(operator, self.operators[operator].attack);
+        let decay = self.rate(operator, self.operators[operator].decay);
+        let release = self.rate(operator, self.operators[operator].release);
+        let op = &mut self.operators[operator];
+        // Sustain level 15 is 93 dB, not 45.
+        let sustain_level = match op.sustain_level {
+            15 => 31,
+            level => level as u32,
+        };
+        let sustain_level = (sustain_level * 16) << ENVELOPE_SHIFT;
+        match op.stage {
+            // Attack falls faster the louder the operator already is.
+            Stage::Attack => {
+                if attack >= 60 {
+                    op.envelope = 0;
+                } else {
+                    let step = rate_increment(attack) * (op.attenuation() / 16 + 1);
+                    op.envelope = op.envelope.saturating_sub(step);
+                }
+                if op.envelope == 0 {
+                    op.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                op.envelope += rate_increment(decay);
+                if op.envelope >= sustain_level {
+                    op.envelope = sustain_level;
+                    op.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain if op.sustain => {}
+            Stage::Sustain | Stage::Release => op.envelope += rate_increment(release),
+        }
+        op.envelope = op.envelope.min(SILENT);
+    }
+
+    /// The operator's total attenuation: its envelope, total level, key
+    /// scaling and tremolo.
+    fn attenuation(&self, operator: usize, tremolo: u32) -> u32 {
+        let op = &self.operators[operator];
+        let channel = &self.channels[channel_of(operator)];
+        let ksl = (KSL_ROM[(channel.fnum >> 6) as usize] << 2) - ((8 - channel.block as i32) << 5);
+        let ksl = (ksl.max(0) as u32) >> KSL_SHIFT[op.ksl as usize];
+        let tremolo = if op.tremolo { tremolo } else { 0 };
+        (op.attenuation() + (op.total_level as u32) * 4 + ksl + tremolo).min(MAX_ATTENUATION)
+    }
+
+    /// The waveform at `index`, out of 1024 to a cycle: a sine, or with
+    /// waveform select on, a half sine, an absolute sine, or the rising
+    /// quarters of one.
+    fn wave(&self, operator: usize, index: u32) -> f32 {
+        let index = index & 0x3ff;
+        let sine = (index as f32 * TAU / 1024.0).sin();
+        let waveform = match self.waveform_select {
+            true => self.operators[operator].waveform,
+            false => 0,
+        };
+        match waveform {
+            0 => sine,
+            1 => sine.max(0.0),
+            2 => sine.abs(),
+            _ if (index & 0x100) == 0 => sine.abs(),
+            _ => 0.0,
+        }
+    }
+
+    /// Runs an operator at phase index `phase`, in thirteen bits either side
+    /// of zero.
+    fn output(&self, operator: usize, phase: u32, tremolo: u32) -> i32 {
+        let attenuation = self.attenuation(operator, tremolo);
+        if attenuation >= MAX_ATTENUATION {
+            return 0;
+        }
+        let gain = 10f32.powf(-(attenuation as f32) * 0.1875 / 20.0);
+        (self.wave(operator, phase) * gain * 4095.0) as i32
+    }
+
+    /// The operator's phase as a waveform index.
+    fn phase_index(&self, operator: usize) -> u32 {
+        self.operators[operator].phase >> 9
+    }
+
+    /// Runs a channel's first operator, with feedback, then its second,
+    /// modulated by the first unless the two are simply added.
+    fn channel_output(&mut self, channel: usize, tremolo: u32) -> i32 {
+        let (first, second) = operators_of(channel);
+        let Channel {
+            feedback, additive, ..
+        } = self.channels[channel];
+        let op = &self.operators[first];
+        let modulation = match feedback {
+            0 => 0,
+            feedback => (op.out + op.previous) >> (9 - feedback),
+        };
+        let out = self.output(
+            first,
+            self.phase_index(first).wrapping_add(modulation as u32),
+            tremolo,
+        );
+        let op = &mut self.operators[first];
+        op.previous = op.out;
+        op.out = out;
+        match additive {
+            true => out + self.output(second, self.phase_index(second), tremolo),
+            false => self.output(
+                second,
+                self.phase_index(second).wrapping_add(out as u32),
+                tremolo,
+            ),
+        }
+    }
+
+    /// The hi-hat, snare and top cymbal, whose phases come from mixing the
+    /// hi-hat and cymbal operators' phase bits with noise.
+    fn percussion_output(&self, tremolo: u32) -> i32 {
+        let hihat = self.phase_index(13);
+        let cymbal = self.phase_index(17);
+        let bit = |phase: u32, n: u32| (phase >> n) & 1;
+        let noise = self.noise & 1;
+        let mixed = (bit(hihat, 2) ^ bit(hihat, 7))
+            | (bit(hihat, 3) ^ bit(cymbal, 5))
+            | (bit(cymbal, 3) ^ bit(cymbal, 5));
+        let hihat_phase = (mixed << 9) | if (mixed ^ noise) != 0 { 0xd0 } else { 0x34 };
+        let snare_phase = (bit(hihat, 8) << 9) | ((bit(hihat, 8) ^ noise) << 8);
+        let cymbal_phase = (mixed << 9) | 0x80;
+        (self.output(13, hihat_phase, tremolo)
+            + self.output(16, snare_phase, tremolo)
+            + self.output(14, self.phase_index(14), tremolo)
+            + self.output(17, cymbal_phase, tremolo))
+            * 2
+    }
+
+    fn generate(&mut self) -> i32 {
+        self.samples = self.samples.wrapping_add(1);
+        self.tick_timers();
+        let noise_bit = (self.noise ^ (self.noise >> 14)) & 1;
+        self.noise = (self.noise >> 1) | (noise_bit << 22);
+        for operator in 0..18 {
+            self.step_envelope(operator);
+        }
+        let tremolo = self.tremolo();
+        let melodic = if self.rhythm { 6 } else { 9 };
+        let mut sum: i32 = (0..melodic)
+            .map(|channel| self.channel_output(channel, tremolo))
+            .sum();
+        if self.rhythm {
+            sum += self.channel_output(6, tremolo) * 2 + self.percussion_output(tremolo);
+        }
+        for operator in 0..18 {
+            let block = self.channels[channel_of(operator)].block;
+            let base = (self.fnum(operator) << block) >> 1;
+            let multiplier = MULTIPLIERS[self.operators[operator].multiplier as usize];
+            let op = &mut self.operators[operator];
+            op.phase = (op.phase + ((base * multiplier) >> 1)) & 0x7ffff;
+        }
+        sum.clamp(-32768, 32767)
+    }
+}
+
+impl Reset for OPL2 {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        *self = OPL2::new();
+    }
+}
+
+impl Default for OPL2 {
+    fn default() -> OPL2 {
+        OPL2::new()
+    }
+}
+
+#[test]
+fn test_opl2_timers_and_tone() {
+    let mut opl = OPL2::new();
+    let write = |opl: &mut OPL2, register: u8, data: u8| {
+        opl.wb(0x388, register);
+        opl.wb(0x389, data);
+    };
+
+    // The AdLib detection routine: reset the flags, then let timer 1 run
+    // out from FFh.
+    write(&mut opl, 0x04, 0x60);
+    write(&mut opl, 0x04, 0x80);
+    assert_eq!(opl.rb(0x388) & 0xe0, 0x00);
+    write(&mut opl, 0x02, 0xff);
+    write(&mut opl, 0x04, 0x21);
+    opl.tick(4);
+    assert_eq!(opl.rb(0x388) & 0xe0, 0xc0);
+    assert_eq!(opl.rb(0x388) & 0x06, 0x06);
+    write(&mut opl, 0x04, 0x60);
+    write(&mut opl, 0x04, 0x80);
+    assert_eq!(opl.rb(0x388) & 0xe0, 0x00);
+
+    // A 440 Hz sine on channel 0's carrier, with the modulator silent.
+    write(&mut opl, 0x40, 0x3f);
+    write(&mut opl, 0x23, 0x21);
+    write(&mut opl, 0x43, 0x00);
+    write(&mut opl, 0x63, 0xf0);
+    write(&mut opl, 0x83, 0x0f);
+    write(&mut opl, 0xa0, (580 & 0xff) as u8);
+    write(&mut opl, 0xb0, 0x20 | 4 << 2 | (580 >> 8) as u8);
+    let samples: Vec<i32> = (0..OPL_SAMPLE_RATE).map(|_| opl.generate()).collect();
+    let crossings = samples
+        .windows(2)
+        .filter(|pair| (pair[0] < 0) != (pair[1] < 0))
+        .count();
+    assert!((876..=886).contains(&crossings), "{}", crossings);
+    assert!(samples.iter().any(|&sample| sample > 4000));
+
+    // Key off lets it die away with the release rate.
+    write(&mut opl, 0xb0, 4 << 2 | (580 >> 8) as u8);
+    opl.tick(OPL_SAMPLE_RATE as usize / 4);
+    assert_eq!(opl.generate(), 0);
+}
@@ -0,0 +1,645 @@
+// Backends that connect emulated serial, parallel and network ports to the
+// outside world. TCP needs nothing from the host; the host device
+// implementations need the `passthrough` feature.
+use crate::input::InputEvent;
+use log::{info, warn};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(feature = "passthrough")]
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineSettings {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+}
+
+impl Default for LineSettings {
+    fn default() -> LineSettings {
+        LineSettings {
+            baud: 2400,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ModemStatus {
+    pub cts: bool,
+    pub dsr: bool,
+    pub ri: bool,
+    pub dcd: bool,
+}
+
+/// Where the bytes of an emulated serial port go.
+pub trait SerialBackend: Send {
+    /// Host input, for backends that are devices of their own, like a
+    /// serial mouse.
+    fn input(&mut self, _event: InputEvent) {}
+    fn configure(&mut self, _settings: &LineSettings) {}
+    fn write_byte(&mut self, value: u8);
+    fn read_byte(&mut self) -> Option<u8>;
+    fn set_modem_control(&mut self, _dtr: bool, _rts: bool) {}
+    fn modem_status(&mut self) -> ModemStatus {
+        ModemStatus {
+            cts: true,
+            dsr: true,
+            ri: false,
+            dcd: true,
+        }
+    }
+}
+
+/// Where the signals of an emulated parallel port go. Status and control
+/// use the bit layout of the PC's status (379h) and control (37Ah) ports.
+pub trait ParallelBackend: Send {
+    fn write_data(&mut self, value: u8);
+    fn write_control(&mut self, _value: u8) {}
+    fn read_data(&mut self) -> Option<u8> {
+        None
+    }
+    // Not busy, selected, no error, no paper-out.
+    fn read_status(&mut self) -> u8 {
+        0xd8
+    }
+}
+
+/// Where the frames of an emulated network card go. Frames are whole
+/// Ethernet frames without the CRC.
+pub trait NetworkBackend: Send {
+    fn send(&mut self, frame: &[u8]);
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+/// A TCP connection in place of the serial cable, either waiting for a
+/// terminal program or debugger to connect, or connecting out to another
+/// emulator for a null-modem link. A listening port takes a new connection
+/// whenever the last one closes. Carrier detect follows the connection.
+pub struct TcpSerial {
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+}
+
+impl TcpSerial {
+    pub fn listen(address: &str) -> io::Result<TcpSerial> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpSerial {
+            listener: Some(listener),
+            stream: None,
+        })
+    }
+
+    pub fn connect(address: &str) -> io::Result<TcpSerial> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(TcpSerial {
+            listener: None,
+            stream: Some(stream),
+        })
+    }
+
+    fn accept(&mut self) {
+        if self.stream.is_some() {
+            return;
+        }
+        let listener = match &self.listener {
+            Some(listener) => listener,
+            None => return,
+        };
+        if let Ok((stream, peer)) = listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                let _ = stream.set_nodelay(true);
+                info!("Serial connection from {}", peer);
+                self.stream = Some(stream);
+            }
+        }
+    }
+}
+
+impl SerialBackend for TcpSerial {
+    fn write_byte(&mut self, value: u8) {
+        if let Some(stream) = &mut self.stream {
+            match stream.write(&[value]) {
+                Ok(_) => {}
+                // A full socket buffer drops the byte, as a real line
+                // with nobody listening would.
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => {
+                    warn!("Serial connection closed: {}", err);
+                    self.stream = None;
+                }
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        self.accept();
+        let stream = self.stream.as_mut()?;
+        let mut buf = [0u8; 1];
+        match stream.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => None,
+            _ => {
+                self.stream = None;
+                None
+            }
+        }
+    }
+
+    fn modem_status(&mut self) -> ModemStatus {
+        let connected = self.stream.is_some();
+        ModemStatus {
+            cts: connected,
+            dsr: connected,
+            ri: false,
+            dcd: connected,
+        }
+    }
+}
+
+/// A pseudo-terminal, whose other end a terminal program like minicom or
+/// screen can open as if it were a serial port. The end the program opens
+/// is put in raw mode, so bytes pass through untouched.
+#[cfg(all(feature = "passthrough", unix))]
+pub struct HostPty {
+    master: std::fs::File,
+    /// Held open so the master doesn't see a hang up between programs.
+    _slave: std::fs::File,
+    pub path: String,
+}
+
+#[cfg(all(feature = "passthrough", unix))]
+impl HostPty {
+    pub fn open() -> io::Result<HostPty> {
+        use std::ffi::CStr;
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { std::fs::File::from_raw_fd(fd) };
+        if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let name = unsafe { libc::ptsname(fd) };
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let path = unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned();
+        let slave = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+            }
+        }
+        Ok(HostPty {
+            master,
+            _slave: slave,
+            path,
+        })
+    }
+}
+
+#[cfg(all(feature = "passthrough", unix))]
+impl SerialBackend for HostPty {
+    fn write_byte(&mut self, value: u8) {
+        match self.master.write(&[value]) {
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => {
+                warn!("{}: {}", self.path, err);
+            }
+            _ => {}
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut buf = [0u8; 1];
+        match self.master.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+}
+
+/// A physical serial port on the host, opened through the serialport crate.
+#[cfg(feature = "passthrough")]
+pub struct HostSerialPort {
+    port: Box<dyn serialport::SerialPort>,
+}
+
+#[cfg(feature = "passthrough")]
+impl HostSerialPort {
+    pub fn open(path: &str) -> Result<HostSerialPort, serialport::Error> {
+        let settings = LineSettings::default();
+        let port = serialport::new(path, settings.baud)
+            .timeout(Duration::from_millis(0))
+            .open()?;
+        Ok(HostSerialPort { port })
+    }
+
+    fn apply(&mut self, settings: &LineSettings) -> serialport::Result<()> {
+        use serialport::{DataBits, StopBits};
+        self.port.set_baud_rate(settings.baud)?;
+        self.port.set_data_bits(match settings.data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            _ => DataBits::Eight,
+        })?;
+        // Mark and space parity have no portable host equivalent.
+        self.port.set_parity(match settings.parity {
+            Parity::Odd => serialport::Parity::Odd,
+            Parity::Even => serialport::Parity::Even,
+            _ => serialport::Parity::None,
+        })?;
+        self.port.set_stop_bits(if settings.stop_bits == 2 {
+            StopBits::Two
+        } else {
+            StopBits::One
+        })
+    }
+}
+
+#[cfg(feature = "passthrough")]
+impl SerialBackend for HostSerialPort {
+    fn configure(&mut self, settings: &LineSettings) {
+        if let Err(err) = self.apply(settings) {
+            warn!("Couldn't configure host serial port: {}", err);
+        }
+    }
+
+    fn write_byte(&mut self, value: u8) {
+        if let Err(err) = self.port.write_all(&[value]) {
+            warn!("Host serial port write failed: {}", err);
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        if self.port.bytes_to_read().ok()? == 0 {
+            return None;
+        }
+        let mut buf = [0u8; 1];
+        match self.port.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+
+    fn set_modem_control(&mut self, dtr: bool, rts: bool) {
+        let _ = self.port.write_data_terminal_ready(dtr);
+        let _ = self.port.write_request_to_send(rts);
+    }
+
+    fn modem_status(&mut self) -> ModemStatus {
+        ModemStatus {
+            cts: self.port.read_clear_to_send().unwrap_or(false),
+            dsr: self.port.read_data_set_ready().unwrap_or(false),
+            ri: self.port.read_ring_indicator().unwrap_or(false),
+            dcd: self.port.read_carrier_detect().unwrap_or(false),
+        }
+    }
+}
+
+// ioctl numbers from linux/ppdev.h
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+mod ppdev {
+    pub const PPCLAIM: libc::c_ulong = 0x708b;
+    pub const PPRELEASE: libc::c_ulong = 0x708c;
+    pub const PPRSTATUS: libc::c_ulong = 0x8001_7081;
+    pub const PPWCONTROL: libc::c_ulong = 0x4001_7084;
+    pub const PPRDATA: libc::c_ulong = 0x8001_7085;
+    pub const PPWDATA: libc::c_ulong = 0x4001_7086;
+}
+
+/// A physical parallel port driven through Linux's ppdev interface, so
+/// every line can be controlled directly. This is what bit-banging
+/// hardware like EPROM programmers needs.
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+pub struct HostParallelPort {
+    file: std::fs::File,
+}
+
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+impl HostParallelPort {
+    pub fn open(path: &str) -> io::Result<HostParallelPort> {
+        use std::os::unix::io::AsRawFd;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        if unsafe { libc::ioctl(file.as_raw_fd(), ppdev::PPCLAIM as _) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(HostParallelPort { file })
+    }
+
+    fn ioctl_write(&mut self, request: libc::c_ulong, value: u8) {
+        use std::os::unix::io::AsRawFd;
+        let mut value = value;
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, &mut value) } < 0 {
+            warn!(
+                "Host parallel port ioctl failed: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    fn ioctl_read(&mut self, request: libc::c_ulong) -> Option<u8> {
+        use std::os::unix::io::AsRawFd;
+        let mut value = 0u8;
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, &mut value) } < 0 {
+            return None;
+        }
+        Some(value)
+    }
+}
+
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+impl ParallelBackend for HostParallelPort {
+    fn write_data(&mut self, value: u8) {
+        self.ioctl_write(ppdev::PPWDATA, value);
+    }
+
+    fn write_control(&mut self, value: u8) {
+        self.ioctl_write(ppdev::PPWCONTROL, value);
+    }
+
+    fn read_data(&mut self) -> Option<u8> {
+        self.ioctl_read(ppdev::PPRDATA)
+    }
+
+    fn read_status(&mut self) -> u8 {
+        self.ioctl_read(ppdev::PPRSTATUS).unwrap_or(0xff)
+    }
+}
+
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+impl Drop for HostParallelPort {
+    fn drop(&mut self) {
+        use std::os::unix::io::AsRawFd;
+        unsafe {
+            libc::ioctl(self.file.as_raw_fd(), ppdev::PPRELEASE as _);
+        }
+    }
+}
+
+/// A host printer device node (e.g. /dev/usb/lp0) that only accepts data
+/// bytes. The latched data byte is sent when the guest pulses STROBE, so
+/// this works anywhere the device can be opened as a file.
+#[cfg(feature = "passthrough")]
+pub struct HostPrinterDevice {
+    file: std::fs::File,
+    data: u8,
+    strobe: bool,
+}
+
+#[cfg(feature = "passthrough")]
+impl HostPrinterDevice {
+    pub fn open(path: &str) -> io::Result<HostPrinterDevice> {
+        let file = std::fs::OpenOptions::new().write(true).open(path)?;
+        Ok(HostPrinterDevice {
+            file,
+            data: 0,
+            strobe: false,
+        })
+    }
+}
+
+#[cfg(feature = "passthrough")]
+impl ParallelBackend for HostPrinterDevice {
+    fn write_data(&mut self, value: u8) {
+        self.data = value;
+    }
+
+    fn write_control(&mut self, value: u8) {
+        let strobe = (value & 1) != 0;
+        if strobe && !self.strobe {
+            if let Err(err) = self.file.write_all(&[self.data]) {
+                warn!("Host printer write failed: {}", err);
+            }
+        }
+        self.strobe = strobe;
+    }
+}
+
+// ioctl numbers and flags from linux/if_tun.h
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+mod tun {
+    pub const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+    pub const IFF_TAP: libc::c_short = 0x0002;
+    pub const IFF_NO_PI: libc::c_short = 0x1000;
+}
+
+/// A TAP device on the host, which puts the guest on a virtual Ethernet
+/// interface the host can bridge or route. The interface has to exist and
+/// belong to the user already, e.g. from `ip tuntap add mode tap user ...`.
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+pub struct HostTap {
+    file: std::fs::File,
+    pub name: String,
+}
+
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+impl HostTap {
+    pub fn open(name: &str) -> io::Result<HostTap> {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::AsRawFd;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+        let mut request = unsafe { std::mem::zeroed::<libc::ifreq>() };
+        if name.len() >= request.ifr_name.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface name too long",
+            ));
+        }
+        for (dst, &src) in request.ifr_name.iter_mut().zip(name.as_bytes()) {
+            *dst = src as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags = tun::IFF_TAP | tun::IFF_NO_PI;
+        if unsafe { libc::ioctl(file.as_raw_fd(), tun::TUNSETIFF as _, &mut request) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(HostTap {
+            file,
+            name: name.to_string(),
+        })
+    }
+}
+
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+impl NetworkBackend for HostTap {
+    fn send(&mut self, frame: &[u8]) {
+        match self.file.write(frame) {
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => {
+                warn!("{}: {}", self.name, err);
+            }
+            _ => {}
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let mut buf = vec![0u8; 2048];
+        match self.file.read(&mut buf) {
+            Ok(length) if length > 0 => {
+                buf.truncate(length);
+                Some(buf)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A raw packet socket on one of the host's interfaces, which injects the
+/// guest's frames onto that network and picks up everything arriving on
+/// it, as pcap does. The guest needs a MAC address of its own, and the
+/// emulator needs CAP_NET_RAW.
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+pub struct HostPacketSocket {
+    fd: std::os::unix::io::OwnedFd,
+    pub interface: String,
+}
+
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+impl HostPacketSocket {
+    pub fn open(interface: &str) -> io::Result<HostPacketSocket> {
+        use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+        let name = std::ffi::CString::new(interface)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK,
+                protocol as libc::c_int,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut address = unsafe { std::mem::zeroed::<libc::sockaddr_ll>() };
+        address.sll_family = libc::AF_PACKET as libc::c_ushort;
+        address.sll_protocol = protocol;
+        address.sll_ifindex = index as libc::c_int;
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(HostPacketSocket {
+            fd,
+            interface: interface.to_string(),
+        })
+    }
+}
+
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+impl NetworkBackend for HostPacketSocket {
+    fn send(&mut self, frame: &[u8]) {
+        use std::os::unix::io::AsRawFd;
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                frame.as_ptr() as *const libc::c_void,
+                frame.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            warn!("{}: {}", self.interface, io::Error::last_os_error());
+        }
+    }
+
+    /// Frames the host itself sends go past too, including the guest's
+    /// own, and are left out.
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        use std::os::unix::io::AsRawFd;
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let mut address = unsafe { std::mem::zeroed::<libc::sockaddr_ll>() };
+            let mut address_length = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            let length = unsafe {
+                libc::recvfrom(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                    &mut address as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                    &mut address_length,
+                )
+            };
+            if length <= 0 {
+                return None;
+            }
+            if address.sll_pkttype != libc::PACKET_OUTGOING as libc::c_uchar {
+                buf.truncate(length as usize);
+                return Some(buf);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tcp_serial_link() {
+    let mut listener = TcpSerial::listen("127.0.0.1:0").unwrap();
+    let address = listener.listener.as_ref().unwrap().local_addr().unwrap();
+    let mut client = TcpSerial::connect(&address.to_string()).unwrap();
+    assert!(client.modem_status().dcd);
+    assert!(!listener.modem_status().dcd);
+
+    // Bytes go both ways once the listening end has taken the connection.
+    let receive = |backend: &mut TcpSerial| {
+        (0..1000).find_map(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            backend.read_byte()
+        })
+    };
+    client.write_byte(0x55);
+    assert_eq!(receive(&mut listener), Some(0x55));
+    assert!(listener.modem_status().dcd);
+    listener.write_byte(0xaa);
+    assert_eq!(receive(&mut client), Some(0xaa));
+
+    // Hanging up drops carrier on the other end.
+    drop(client);
+    let hung_up = (0..1000).any(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        listener.read_byte();
+        !listener.modem_status().dcd
+    });
+    assert!(hung_up);
+}
@@ -0,0 +1,140 @@
+// A card no real PC had: a window onto the emulator's own clocks, so a
+// benchmark running in the guest can see how many CPU cycles it took and
+// how long that was on the host, and from those how fast and how
+// faithfully it's being run. It's only fitted when asked for, at two
+// ports well away from anything period software probes.
+//
+// Writing a counter number to the command port latches that counter;
+// its eight bytes are then read from the data port, low byte first.
+// Reading the command port gives an ID byte to detect the card by.
+use crate::hardware::reset::{Reset, ResetKind};
+use std::time::Instant;
+
+pub const PERF_COUNTER_BASE: u16 = 0x00e0;
+
+/// What the command port reads as: "P".
+const ID: u8 = 0x50;
+
+/// CPU cycles since the counters were cleared, refresh included.
+pub const COUNTER_CYCLES: u8 = 0;
+/// The same, in microseconds at the current CPU clock.
+pub const COUNTER_GUEST_US: u8 = 1;
+/// Microseconds of host time since the counters were cleared.
+pub const COUNTER_HOST_US: u8 = 2;
+/// The CPU clock in Hz.
+pub const COUNTER_CLOCK_HZ: u8 = 3;
+/// Starts the cycle count and host time over from zero.
+pub const COMMAND_CLEAR: u8 = 0xff;
+
+#[derive(Debug, Clone, Default)]
+pub struct PerfCounter {
+    pub base: u16,
+    cycles: u64,
+    cpu_hz: u32,
+    /// When the counters were last cleared, on the host.
+    epoch: Option<Instant>,
+    latch: [u8; 8],
+    /// The next byte of `latch` the data port gives.
+    next: usize,
+}
+
+impl PerfCounter {
+    pub fn new(cpu_hz: u32) -> PerfCounter {
+        PerfCounter {
+            base: PERF_COUNTER_BASE,
+            cpu_hz,
+            epoch: Some(Instant::now()),
+            ..PerfCounter::default()
+        }
+    }
+
+    pub fn set_cpu_hz(&mut self, hz: u32) {
+        self.cpu_hz = hz;
+    }
+
+    /// Counts `cycles` more CPU cycles.
+    pub fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+    }
+
+    fn clear(&mut self) {
+        self.cycles = 0;
+        self.epoch = Some(Instant::now());
+    }
+
+    fn counter(&self, counter: u8) -> Option<u64> {
+        match counter {
+            COUNTER_CYCLES => Some(self.cycles),
+            COUNTER_GUEST_US => Some(self.cycles * 1_000_000 / self.cpu_hz.max(1) as u64),
+            COUNTER_HOST_US => Some(
+                self.epoch
+                    .map_or(0, |epoch| epoch.elapsed().as_micros() as u64),
+            ),
+            COUNTER_CLOCK_HZ => Some(self.cpu_hz as u64),
+            _ => None,
+        }
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr - self.base {
+            0 => ID,
+            _ => {
+                let value = self.latch.get(self.next).copied().unwrap_or(0xff);
+                self.next += 1;
+                value
+            }
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        if addr != self.base {
+            return;
+        }
+        if data == COMMAND_CLEAR {
+            self.clear();
+            return;
+        }
+        // An unknown counter reads as all ones, like an empty port.
+        self.latch = self.counter(data).map_or([0xff; 8], u64::to_le_bytes);
+        self.next = 0;
+    }
+}
+
+// The counters run from power on, so only a cold reset clears them.
+impl Reset for PerfCounter {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            self.clear();
+        }
+        self.next = self.latch.len();
+    }
+}
+
+#[test]
+fn test_perf_counter_latches() {
+    let mut card = PerfCounter::new(4_000_000);
+    let read = |card: &mut PerfCounter, counter| {
+        card.wb(PERF_COUNTER_BASE, counter);
+        let mut bytes = [0; 8];
+        for byte in bytes.iter_mut() {
+            *byte = card.rb(PERF_COUNTER_BASE + 1);
+        }
+        u64::from_le_bytes(bytes)
+    };
+    assert_eq!(card.rb(PERF_COUNTER_BASE), ID);
+    card.tick(6_000);
+    card.tick(2_000);
+    assert_eq!(read(&mut card, COUNTER_CYCLES), 8_000);
+    assert_eq!(read(&mut card, COUNTER_GUEST_US), 2_000);
+    assert_eq!(read(&mut card, COUNTER_CLOCK_HZ), 4_000_000);
+    assert_eq!(read(&mut card, 0x42), u64::MAX);
+    // The latch holds its value while the counter moves on.
+    card.wb(PERF_COUNTER_BASE, COUNTER_CYCLES);
+    card.tick(1);
+    assert_eq!(card.rb(PERF_COUNTER_BASE + 1), 0x40);
+
+    card.reset(ResetKind::Warm);
+    assert_eq!(read(&mut card, COUNTER_CYCLES), 8_001);
+    card.wb(PERF_COUNTER_BASE, COMMAND_CLEAR);
+    assert_eq!(read(&mut card, COUNTER_CYCLES), 0);
+}
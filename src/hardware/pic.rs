@@ -0,0 +1,403 @@
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InitState {
+    Ready,
+    Icw2,
+    Icw3,
+    Icw4,
+}
+
+/// One 8259A programmable interrupt controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PIC {
+    pub irr: u8,
+    pub isr: u8,
+    pub imr: u8,
+    /// ICW2. The low three bits are replaced by the IR number in 8086
+    /// mode.
+    pub vector_base: u8,
+    /// ICW3. On a master, the inputs with a slave behind them; on a slave,
+    /// the master input it's wired to.
+    pub cascade: u8,
+    pub auto_eoi: bool,
+    level_triggered: bool,
+    single: bool,
+    icw4_needed: bool,
+    init: InitState,
+    /// Input lines as last seen, to catch rising edges.
+    lines: u8,
+    read_isr: bool,
+    poll: bool,
+    special_mask: bool,
+    rotate_on_auto_eoi: bool,
+    /// The IR with the lowest priority. The one after it has the highest.
+    lowest_priority: u8,
+}
+
+impl PIC {
+    /// Everything is masked until the BIOS programs the chip.
+    pub fn new() -> PIC {
+        PIC {
+            irr: 0,
+            isr: 0,
+            imr: 0xff,
+            vector_base: 0,
+            cascade: 0,
+            auto_eoi: false,
+            level_triggered: false,
+            single: true,
+            icw4_needed: false,
+            init: InitState::Ready,
+            lines: 0,
+            read_isr: false,
+            poll: false,
+            special_mask: false,
+            rotate_on_auto_eoi: false,
+            lowest_priority: 7,
+        }
+    }
+
+    /// Drives IR input `line`. In edge triggered mode only a rising edge
+    /// latches a request. Dropping the line before the CPU acknowledges it
+    /// withdraws the request, which turns into a spurious IRQ 7.
+    pub fn set_irq(&mut self, line: u8, level: bool) {
+        let bit = 1 << line;
+        if level {
+            if self.level_triggered || (self.lines & bit) == 0 {
+                self.irr |= bit;
+            }
+            self.lines |= bit;
+        } else {
+            self.irr &= !bit;
+            self.lines &= !bit;
+        }
+    }
+
+    /// 0 is the highest priority.
+    fn priority(&self, line: u8) -> u8 {
+        line.wrapping_sub(self.lowest_priority).wrapping_sub(1) & 7
+    }
+
+    fn highest(&self, bits: u8) -> Option<u8> {
+        (0..8)
+            .map(|i| (self.lowest_priority + 1 + i) & 7)
+            .find(|line| (bits & (1 << line)) != 0)
+    }
+
+    /// The request the chip would hand over on the next INTA, if it is
+    /// asserting INT at all. A request has to beat everything in service,
+    /// except in special mask mode where masked in-service levels don't
+    /// count.
+    pub fn pending_irq(&self) -> Option<u8> {
+        let request = self.highest(self.irr & !self.imr)?;
+        let in_service = if self.special_mask {
+            self.isr & !self.imr
+        } else {
+            self.isr
+        };
+        match self.highest(in_service) {
+            Some(line) if self.priority(line) <= self.priority(request) => None,
+            _ => Some(request),
+        }
+    }
+
+    pub fn int_output(&self) -> bool {
+        self.pending_irq().is_some()
+    }
+
+    /// The INTA cycle. Returns the IR acknowledged, or `None` when the
+    /// request went away, in which case the chip answers with IR 7 but
+    /// doesn't mark it in service.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let line = self.pending_irq();
+        if let Some(line) = line {
+            let bit = 1 << line;
+            self.irr &= !bit;
+            if self.auto_eoi {
+                if self.rotate_on_auto_eoi {
+                    self.lowest_priority = line;
+                }
+            } else {
+                self.isr |= bit;
+            }
+        }
+        line
+    }
+
+    pub fn vector(&self, line: u8) -> u8 {
+        (self.vector_base & 0xf8) | line
+    }
+
+    fn end_of_interrupt(&mut self, line: Option<u8>, rotate: bool) {
+        let line = match line.or_else(|| self.highest(self.isr)) {
+            Some(line) => line,
+            None => return,
+        };
+        trace!(target: "pic", "EOI IR{}", line);
+        self.isr &= !(1 << line);
+        if rotate {
+            self.lowest_priority = line;
+        }
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        if (addr & 1) != 0 {
+            return self.imr;
+        }
+        if self.poll {
+            // A poll is an INTA without the CPU's involvement.
+            self.poll = false;
+            return match self.acknowledge() {
+                Some(line) => 0x80 | line,
+                None => 0,
+            };
+        }
+        if self.read_isr {
+            self.isr
+        } else {
+            self.irr
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        if (addr & 1) != 0 {
+            match self.init {
+                InitState::Icw2 => {
+                    self.vector_base = data;
+                    self.init = if !self.single {
+                        InitState::Icw3
+                    } else if self.icw4_needed {
+                        InitState::Icw4
+                    } else {
+                        InitState::Ready
+                    };
+                }
+                InitState::Icw3 => {
+                    self.cascade = data;
+                    self.init = if self.icw4_needed {
+                        InitState::Icw4
+                    } else {
+                        InitState::Ready
+                    };
+                }
+                InitState::Icw4 => {
+                    if (data & 0x01) == 0 {
+                        debug!(target: "pic", "8080 mode isn't supported");
+                    }
+                    self.auto_eoi = (data & 0x02) != 0;
+                    self.init = InitState::Ready;
+                }
+                InitState::Ready => self.imr = data,
+            }
+        } else if (data & 0x10) != 0 {
+            // ICW1 starts initialization over and forgets everything the
+            // OCWs set up.
+            self.level_triggered = (data & 0x08) != 0;
+            self.single = (data & 0x02) != 0;
+            self.icw4_needed = (data & 0x01) != 0;
+            self.init = InitState::Icw2;
+            self.imr = 0;
+            self.isr = 0;
+            self.irr = 0;
+            self.lines = 0;
+            self.auto_eoi = false;
+            self.read_isr = false;
+            self.poll = false;
+            self.special_mask = false;
+            self.rotate_on_auto_eoi = false;
+            self.lowest_priority = 7;
+        } else if (data & 0x08) != 0 {
+            // OCW3
+            self.poll = (data & 0x04) != 0;
+            if (data & 0x02) != 0 {
+                self.read_isr = (data & 0x01) != 0;
+            }
+            if (data & 0x40) != 0 {
+                self.special_mask = (data & 0x20) != 0;
+            }
+        } else {
+            // OCW2
+            let line = data & 7;
+            match data >> 5 {
+                0 => self.rotate_on_auto_eoi = false,
+                1 => self.end_of_interrupt(None, false),
+                3 => self.end_of_interrupt(Some(line), false),
+                4 => self.rotate_on_auto_eoi = true,
+                5 => self.end_of_interrupt(None, true),
+                6 => self.lowest_priority = line,
+                7 => self.end_of_interrupt(Some(line), true),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Default for PIC {
+    fn default() -> PIC {
+        PIC::new()
+    }
+}
+
+// The 8259 has no reset pin.
+impl Reset for PIC {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            *self = PIC::new();
+        }
+    }
+}
+
+/// The AT's pair of 8259s: the slave at A0h drives IR2 on the master at
+/// 20h, giving IRQs 8-15.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DualPIC {
+    pub master: PIC,
+    pub slave: PIC,
+}
+
+impl DualPIC {
+    pub fn new() -> DualPIC {
+        DualPIC::default()
+    }
+
+    /// The slave's INT output is a level on the master's IR2.
+    fn update_cascade(&mut self) {
+        self.master.set_irq(2, self.slave.int_output());
+    }
+
+    pub fn set_irq(&mut self, irq: u8, level: bool) {
+        if irq < 8 {
+            self.master.set_irq(irq, level);
+        } else {
+            self.slave.set_irq(irq - 8, level);
+            self.update_cascade();
+        }
+    }
+
+    pub fn int_output(&self) -> bool {
+        self.master.int_output()
+    }
+
+    /// Both INTA cycles. When the master picks an input with a slave on
+    /// it, the slave supplies the vector.
+    pub fn acknowledge(&mut self) -> u8 {
+        let vector = match self.master.acknowledge() {
+            Some(line) if (self.master.cascade & (1 << line)) != 0 => {
+                let slave_line = self.slave.acknowledge();
+                self.slave.vector(slave_line.unwrap_or(7))
+            }
+            Some(line) => self.master.vector(line),
+            None => self.master.vector(7),
+        };
+        self.update_cascade();
+        vector
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        let value = if (addr & 0x80) != 0 {
+            self.slave.rb(addr)
+        } else {
+            self.master.rb(addr)
+        };
+        self.update_cascade();
+        value
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        if (addr & 0x80) != 0 {
+            self.slave.wb(addr, data);
+        } else {
+            self.master.wb(addr, data);
+        }
+        self.update_cascade();
+    }
+}
+
+impl Reset for DualPIC {
+    fn reset(&mut self, kind: ResetKind) {
+        self.master.reset(kind);
+        self.slave.reset(kind);
+    }
+}
+
+/// Where the devices both boards have pull their IRQ lines, whichever
+/// interrupt controller the board has.
+pub trait InterruptLines {
+    fn set_line(&mut self, irq: u8, level: bool);
+}
+
+impl InterruptLines for PIC {
+    fn set_line(&mut self, irq: u8, level: bool) {
+        self.set_irq(irq, level);
+    }
+}
+
+/// A card's IRQ 2 comes out on IRQ 9, as the master's IR2 has the slave on
+/// it.
+impl InterruptLines for DualPIC {
+    fn set_line(&mut self, irq: u8, level: bool) {
+        let irq = if irq == 2 { 9 } else { irq };
+        self.set_irq(irq, level);
+    }
+}
+
+#[test]
+fn test_cascaded_pics() {
+    let mut pic = DualPIC::new();
+    // What the AT BIOS does: edge triggered, cascaded, 8086 mode.
+    for (addr, data) in [
+        (0x20, 0x11),
+        (0x21, 0x08),
+        (0x21, 0x04),
+        (0x21, 0x01),
+        (0xa0, 0x11),
+        (0xa1, 0x70),
+        (0xa1, 0x02),
+        (0xa1, 0x01),
+        (0x21, 0x00),
+        (0xa1, 0x00),
+    ]
+    .iter()
+    {
+        pic.wb(*addr, *data);
+    }
+    assert!(!pic.int_output());
+
+    // IRQ 1 and IRQ 14 at once: the keyboard wins, and blocks the disk
+    // until it's done.
+    pic.set_irq(14, true);
+    pic.set_irq(1, true);
+    assert_eq!(pic.acknowledge(), 0x09);
+    assert!(!pic.int_output());
+    pic.wb(0x20, 0x0b);
+    assert_eq!(pic.rb(0x20), 0x02);
+    pic.wb(0x20, 0x20);
+    assert_eq!(pic.rb(0x20), 0x00);
+    assert_eq!(pic.acknowledge(), 0x76);
+    assert_eq!(pic.master.isr, 0x04);
+    assert_eq!(pic.slave.isr, 0x40);
+
+    // A timer tick gets in ahead of the cascade input. Specific EOIs end
+    // the slave's IR6, then the master's IR2 and IR0.
+    pic.set_irq(0, true);
+    assert_eq!(pic.acknowledge(), 0x08);
+    pic.wb(0xa0, 0x66);
+    pic.wb(0x20, 0x62);
+    pic.wb(0x20, 0x20);
+    assert_eq!((pic.master.isr, pic.slave.isr), (0, 0));
+
+    // Edges only: holding IRQ 0 high doesn't request again, and masked
+    // or withdrawn requests never reach the CPU.
+    pic.set_irq(0, true);
+    assert!(!pic.int_output());
+    pic.set_irq(0, false);
+    pic.wb(0x21, 0x01);
+    pic.set_irq(0, true);
+    assert!(!pic.int_output());
+    pic.set_irq(3, true);
+    pic.set_irq(3, false);
+    assert_eq!(pic.acknowledge(), 0x0f);
+    assert_eq!(pic.master.isr, 0);
+}
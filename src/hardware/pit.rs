@@ -1,3 +1,11 @@
+use crate::hardware::reset::{Reset, ResetKind};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use log::{debug, trace};
+
+/// The PIT's input clock, a quarter of the 4.77 MHz 8088 clock and the
+/// same on every PC since.
+pub const PIT_CLOCK_HZ: u32 = 1_193_182;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AccessMode {
     HighThenLow = 0,
@@ -6,19 +14,335 @@ pub enum AccessMode {
     LowThenHigh = 3,
 }
 
-pub enum PitCtrState {}
+impl AccessMode {
+    fn from_bits(bits: u8) -> AccessMode {
+        match bits & 3 {
+            0 => AccessMode::HighThenLow,
+            1 => AccessMode::AlwaysLow,
+            2 => AccessMode::AlwaysHigh,
+            _ => AccessMode::LowThenHigh,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct PitCounter {
     pub timer_mode: u8,
     pub access_mode: AccessMode,
+    /// The counting element.
     pub count: u16,
+    /// The count register, reloaded into `count`. 0 means 65536, or 10000
+    /// in BCD.
+    pub reload: u16,
+    pub bcd: bool,
     pub gate: bool,
     pub out: bool,
+    /// Set from a count being written until it has been loaded.
+    pub null_count: bool,
+    /// A complete count was written and is loaded on the next clock, or on
+    /// the next trigger in modes 1 and 5.
+    load_pending: bool,
+    /// Modes 1 and 5 wait for a gate rising edge before they count.
+    counting: bool,
+    gate_triggered: bool,
+    /// Modes 4 and 5 pulse OUT low for one clock, once per count.
+    strobe_done: bool,
+    latch: Option<u16>,
+    status_latch: Option<u8>,
+    /// Which byte of a two byte count comes next.
+    write_high: bool,
+    read_high: bool,
+    write_low_byte: u8,
+}
+
+impl PitCounter {
+    fn new() -> PitCounter {
+        PitCounter {
+            timer_mode: 0,
+            access_mode: AccessMode::LowThenHigh,
+            count: 0xffff,
+            reload: 0,
+            bcd: false,
+            gate: false,
+            out: false,
+            null_count: true,
+            load_pending: false,
+            counting: false,
+            gate_triggered: false,
+            strobe_done: false,
+            latch: None,
+            status_latch: None,
+            write_high: false,
+            read_high: false,
+            write_low_byte: 0,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        (self.out as u8) << 7
+            | (self.null_count as u8) << 6
+            | (self.access_mode as u8) << 4
+            | self.timer_mode << 1
+            | self.bcd as u8
+    }
+
+    fn set_control(&mut self, data: u8) {
+        self.access_mode = AccessMode::from_bits(data >> 4);
+        // Modes 6 and 7 are aliases for 2 and 3.
+        self.timer_mode = match (data >> 1) & 7 {
+            6 => 2,
+            7 => 3,
+            mode => mode,
+        };
+        self.bcd = (data & 1) != 0;
+        self.out = self.timer_mode != 0;
+        self.null_count = true;
+        self.load_pending = false;
+        self.counting = false;
+        self.latch = None;
+        self.status_latch = None;
+        self.write_high = false;
+        self.read_high = false;
+    }
+
+    fn write_count(&mut self, data: u8) {
+        let reload = match self.access_mode {
+            AccessMode::AlwaysLow => data as u16,
+            AccessMode::AlwaysHigh => (data as u16) << 8,
+            _ if !self.write_high => {
+                self.write_low_byte = data;
+                self.write_high = true;
+                // Mode 0 stops counting on the first byte.
+                if self.timer_mode == 0 {
+                    self.counting = false;
+                }
+                return;
+            }
+            _ => {
+                self.write_high = false;
+                u16::from_le_bytes([self.write_low_byte, data])
+            }
+        };
+        self.reload = reload;
+        self.null_count = true;
+        match self.timer_mode {
+            0 => {
+                self.out = false;
+                self.load_pending = true;
+            }
+            // A new count in the middle of a period waits for the next
+            // reload, unless nothing is counting yet.
+            2 | 3 if self.counting => {}
+            _ => self.load_pending = true,
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if let Some(status) = self.status_latch.take() {
+            return status;
+        }
+        let value = self.latch.unwrap_or(self.count);
+        let (byte, done) = match self.access_mode {
+            AccessMode::AlwaysLow => (value as u8, true),
+            AccessMode::AlwaysHigh => ((value >> 8) as u8, true),
+            _ if !self.read_high => {
+                self.read_high = true;
+                (value as u8, false)
+            }
+            _ => {
+                self.read_high = false;
+                ((value >> 8) as u8, true)
+            }
+        };
+        if done {
+            self.latch = None;
+        }
+        byte
+    }
+
+    fn latch_count(&mut self) {
+        if self.latch.is_none() {
+            self.latch = Some(self.count);
+        }
+    }
+
+    fn latch_status(&mut self) {
+        if self.status_latch.is_none() {
+            self.status_latch = Some(self.status());
+        }
+    }
+
+    fn set_gate(&mut self, gate: bool) {
+        if gate && !self.gate {
+            self.gate_triggered = true;
+        }
+        self.gate = gate;
+        // Rate generators and square waves park OUT high while gated off.
+        if !gate && (self.timer_mode == 2 || self.timer_mode == 3) {
+            self.out = true;
+        }
+    }
+
+    fn decrement(&self, by: u16) -> u16 {
+        if !self.bcd {
+            return self.count.wrapping_sub(by);
+        }
+        let digits = |value: u16| {
+            (value >> 12) * 1000
+                + ((value >> 8) & 0xf) * 100
+                + ((value >> 4) & 0xf) * 10
+                + (value & 0xf)
+        };
+        let value = (digits(self.count) + 10000 - by) % 10000;
+        (value / 1000) << 12 | ((value / 100) % 10) << 8 | ((value / 10) % 10) << 4 | (value % 10)
+    }
+
+    /// Square waves count down by two. An odd count spends one more clock
+    /// high than low.
+    fn load_square_wave(&mut self) {
+        self.count = if (self.reload & 1) == 0 {
+            self.reload
+        } else if self.out {
+            self.reload.wrapping_add(1)
+        } else {
+            self.reload.wrapping_sub(1)
+        };
+    }
+
+    fn load(&mut self) {
+        self.null_count = false;
+        self.load_pending = false;
+        self.counting = true;
+        self.strobe_done = false;
+        if self.timer_mode == 3 {
+            self.load_square_wave();
+        } else {
+            self.count = self.reload;
+        }
+    }
+
+    /// One cycle of the input clock.
+    fn clock(&mut self) {
+        let triggered = std::mem::take(&mut self.gate_triggered);
+        match self.timer_mode {
+            0 | 4 => {
+                if self.load_pending {
+                    self.load();
+                    return;
+                }
+                if !self.gate || !self.counting {
+                    return;
+                }
+                self.count_down_strobe();
+            }
+            1 | 5 => {
+                // A trigger (re)starts the count, once there is one.
+                if triggered && (self.load_pending || self.counting) {
+                    self.load();
+                    if self.timer_mode == 1 {
+                        self.out = false;
+                    }
+                    return;
+                }
+                if !self.counting {
+                    return;
+                }
+                self.count_down_strobe();
+            }
+            2 => {
+                if self.load_pending || (triggered && self.gate && self.counting) {
+                    self.load();
+                    self.out = true;
+                    return;
+                }
+                if !self.gate || !self.counting {
+                    return;
+                }
+                if !self.out {
+                    self.out = true;
+                    self.load();
+                    return;
+                }
+                self.count = self.decrement(1);
+                if self.count == 1 {
+                    self.out = false;
+                }
+            }
+            _ => {
+                if self.load_pending || (triggered && self.gate && self.counting) {
+                    self.out = true;
+                    self.load();
+                    return;
+                }
+                if !self.gate || !self.counting {
+                    return;
+                }
+                self.count = self.decrement(2);
+                if self.count == 0 {
+                    self.out = !self.out;
+                    self.load();
+                }
+            }
+        }
+    }
+
+    /// At least how many clocks until OUT can change, or `None` if it
+    /// can't until the counter is written or its gate moves. It may come
+    /// early, say when a new count loads without changing OUT, but never
+    /// late.
+    fn clocks_until_edge(&self) -> Option<usize> {
+        if self.gate_triggered || (self.load_pending && !matches!(self.timer_mode, 1 | 5)) {
+            return Some(1);
+        }
+        let gated = !self.gate && !matches!(self.timer_mode, 1 | 5);
+        if !self.counting || gated {
+            return None;
+        }
+        let count = match self.count {
+            0 if self.bcd => 10000,
+            0 => 0x10000,
+            count if self.bcd => {
+                let digit = |shift: u16| ((count >> shift) & 0xf) as usize;
+                digit(12) * 1000 + digit(8) * 100 + digit(4) * 10 + digit(0)
+            }
+            count => count as usize,
+        };
+        match self.timer_mode {
+            0 | 1 if self.out => None,
+            0 | 1 => Some(count),
+            2 if self.out => Some(count.saturating_sub(1).max(1)),
+            2 => Some(1),
+            3 => Some((count / 2).max(1)),
+            _ if !self.out => Some(1),
+            _ if self.strobe_done => None,
+            _ => Some(count),
+        }
+    }
+
+    /// Modes 0, 1, 4 and 5: OUT changes once when the count hits zero,
+    /// and the counter keeps wrapping afterwards.
+    fn count_down_strobe(&mut self) {
+        if self.timer_mode >= 4 && !self.out {
+            self.out = true;
+        }
+        self.count = self.decrement(1);
+        if self.count == 0 {
+            match self.timer_mode {
+                0 | 1 => self.out = true,
+                _ if !self.strobe_done => {
+                    self.out = false;
+                    self.strobe_done = true;
+                }
+                _ => {}
+            }
+        }
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PitType {
     PIT8253,
+    /// Adds the read-back command and the status byte.
     PIT8254,
 }
 
@@ -26,39 +350,105 @@ pub enum PitType {
 pub struct PIT {
     pub counters: [PitCounter; 3],
     pub ctrl: u8,
+    pub kind: PitType,
 }
 
 impl PIT {
+    /// The PC and XT's 8253. Channels 0 and 1 have their gates tied high.
     pub fn new() -> Self {
+        PIT::with_type(PitType::PIT8253)
+    }
+
+    pub fn with_type(kind: PitType) -> Self {
+        let mut counters = [PitCounter::new(); 3];
+        counters[0].gate = true;
+        counters[1].gate = true;
         Self {
-            counters: [PitCounter {
-                timer_mode: 0,
-                access_mode: AccessMode::LowThenHigh,
-                count: 0xffffu16,
-                gate: false,
-                out: false,
-            }; 3],
+            counters,
             ctrl: 0,
+            kind,
         }
     }
-    pub fn tick(&mut self, _cycles: usize) {
-        println!("PIT TICKED");
+
+    /// Runs `cycles` cycles of the input clock. Channel 0 drives IRQ 0, and
+    /// its pulses can be shorter than an instruction, so `out0` hears about
+    /// every change of its output as it happens. Channel 1 requests DRAM
+    /// refresh, and the number of times its output rose comes back.
+    pub fn tick<F: FnMut(bool)>(&mut self, cycles: usize, mut out0: F) -> usize {
+        trace!(target: "pit", "PIT TICKED");
+        let mut refresh_requests = 0;
+        for _ in 0..cycles {
+            let out = self.counters[0].out;
+            let out1 = self.counters[1].out;
+            for counter in self.counters.iter_mut() {
+                counter.clock();
+            }
+            if self.counters[0].out != out {
+                out0(self.counters[0].out);
+            }
+            if self.counters[1].out && !out1 {
+                refresh_requests += 1;
+            }
+        }
+        refresh_requests
+    }
+
+    /// Input clocks until `channel`'s output might change, for the board to
+    /// schedule its next look at the PIT. `None` if nothing will change
+    /// until the CPU reprograms the channel or moves its gate.
+    pub fn ticks_until_edge(&self, channel: usize) -> Option<usize> {
+        self.counters[channel].clocks_until_edge()
+    }
+
+    pub fn set_gate(&mut self, channel: usize, gate: bool) {
+        self.counters[channel].set_gate(gate);
     }
 
-    pub fn rb(&mut self, _addr: u16) -> u8 {
-        0
+    pub fn out(&self, channel: usize) -> bool {
+        self.counters[channel].out
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr & 3 {
+            3 => 0xff,
+            channel => self.counters[channel as usize].read(),
+        }
     }
 
     pub fn wb(&mut self, addr: u16, data: u8) {
         match addr & 3 {
             3 => {
-                if (data >> 6) == 3 {
-                    todo!();
+                let channel = (data >> 6) as usize;
+                if channel == 3 {
+                    if self.kind == PitType::PIT8254 {
+                        self.read_back(data);
+                    } else {
+                        debug!(target: "pit", "Read-back command on an 8253");
+                    }
+                } else if (data & 0x30) == 0 {
+                    self.counters[channel].latch_count();
                 } else {
                     self.ctrl = data;
+                    self.counters[channel].set_control(data);
                 }
             }
-            _ => todo!(),
+            channel => self.counters[channel as usize].write_count(data),
+        }
+    }
+
+    /// Bits 1-3 pick the counters. A clear bit 5 latches their counts and
+    /// a clear bit 4 their status.
+    fn read_back(&mut self, data: u8) {
+        for (i, counter) in self.counters.iter_mut().enumerate() {
+            if (data & (2 << i)) == 0 {
+                continue;
+            }
+            if (data & 0x10) == 0 {
+                counter.latch_status();
+            }
+            if (data & 0x20) == 0 {
+                counter.latch_count();
+            }
         }
     }
 }
@@ -68,3 +458,190 @@ impl Default for PIT {
         PIT::new()
     }
 }
+
+// The 8253 has no reset pin, so only power cycling clears its programming.
+impl Reset for PIT {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            *self = PIT::with_type(self.kind);
+        }
+    }
+}
+
+// Channel 2 drives the PC speaker, so its count and output have to come back
+// exactly or the speaker resumes at the wrong pitch or polarity.
+impl Savestate for PIT {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.section("pit");
+        writer.write_u8(self.ctrl);
+        for counter in self.counters.iter() {
+            writer.write_u8(counter.timer_mode);
+            writer.write_u8(counter.access_mode as u8);
+            writer.write_u16(counter.count);
+            writer.write_u16(counter.reload);
+            writer.write_bool(counter.bcd);
+            writer.write_bool(counter.gate);
+            writer.write_bool(counter.out);
+            writer.write_bool(counter.null_count);
+            writer.write_bool(counter.load_pending);
+            writer.write_bool(counter.counting);
+            writer.write_bool(counter.strobe_done);
+            writer.write_bool(counter.write_high);
+            writer.write_bool(counter.read_high);
+            writer.write_u8(counter.write_low_byte);
+            writer.write_bool(counter.latch.is_some());
+            writer.write_u16(counter.latch.unwrap_or(0));
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        reader.section("pit")?;
+        self.ctrl = reader.read_u8()?;
+        for counter in self.counters.iter_mut() {
+            counter.timer_mode = reader.read_u8()?;
+            counter.access_mode = AccessMode::from_bits(reader.read_u8()?);
+            counter.count = reader.read_u16()?;
+            counter.reload = reader.read_u16()?;
+            counter.bcd = reader.read_bool()?;
+            counter.gate = reader.read_bool()?;
+            counter.out = reader.read_bool()?;
+            counter.null_count = reader.read_bool()?;
+            counter.load_pending = reader.read_bool()?;
+            counter.counting = reader.read_bool()?;
+            counter.strobe_done = reader.read_bool()?;
+            counter.write_high = reader.read_bool()?;
+            counter.read_high = reader.read_bool()?;
+            counter.write_low_byte = reader.read_u8()?;
+            let latched = reader.read_bool()?;
+            let latch = reader.read_u16()?;
+            counter.latch = if latched { Some(latch) } else { None };
+            counter.status_latch = None;
+            counter.gate_triggered = false;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pit_savestate_round_trip() {
+    let mut pit = PIT::new();
+    pit.ctrl = 0xb6;
+    pit.counters[2].count = 0x0533;
+    pit.counters[2].gate = true;
+    pit.counters[2].out = true;
+    pit.counters[2].access_mode = AccessMode::AlwaysLow;
+    let mut writer = StateWriter::new();
+    pit.save_state(&mut writer);
+    let data = writer.finish();
+
+    let mut loaded = PIT::new();
+    loaded.load_state(&mut StateReader::new(&data)).unwrap();
+    assert_eq!(loaded.ctrl, 0xb6);
+    assert_eq!(loaded.counters[2].count, 0x0533);
+    assert!(loaded.counters[2].gate && loaded.counters[2].out);
+    assert_eq!(loaded.counters[2].access_mode, AccessMode::AlwaysLow);
+    assert!(PIT::new()
+        .load_state(&mut StateReader::new(&data[..data.len() - 1]))
+        .is_err());
+}
+
+#[test]
+fn test_pit_modes() {
+    let mut pit = PIT::with_type(PitType::PIT8254);
+    // Channel 0, mode 2, count 4: OUT drops for one clock every 4.
+    pit.wb(0x43, 0x34);
+    pit.wb(0x40, 0x04);
+    pit.wb(0x40, 0x00);
+    pit.tick(1, |_| {});
+    let mut outs = vec![];
+    for _ in 0..8 {
+        pit.tick(1, |_| {});
+        outs.push(pit.out(0));
+    }
+    assert_eq!(outs, [true, true, false, true, true, true, false, true]);
+
+    // Latching freezes the value read back until both bytes are read.
+    pit.wb(0x43, 0x00);
+    pit.tick(1, |_| {});
+    let latched = pit.counters[0].latch.unwrap();
+    assert_eq!(pit.rb(0x40), latched as u8);
+    assert_eq!(pit.rb(0x40), (latched >> 8) as u8);
+    assert_eq!(pit.counters[0].latch, None);
+
+    // Channel 2, mode 3, count 5, gated: 3 clocks high, 2 low.
+    pit.wb(0x43, 0xb6);
+    pit.wb(0x42, 0x05);
+    pit.wb(0x42, 0x00);
+    pit.tick(2, |_| {});
+    assert!(pit.out(2));
+    pit.set_gate(2, true);
+    pit.tick(1, |_| {});
+    let mut outs = vec![];
+    for _ in 0..10 {
+        pit.tick(1, |_| {});
+        outs.push(pit.out(2));
+    }
+    assert_eq!(
+        outs,
+        [true, true, false, false, true, true, true, false, false, true]
+    );
+
+    // Mode 0 in BCD: OUT goes high after 10 counts and stays there.
+    pit.wb(0x43, 0x71);
+    pit.wb(0x41, 0x10);
+    pit.wb(0x41, 0x00);
+    assert!(!pit.out(1));
+    pit.tick(10, |_| {});
+    assert_eq!(pit.counters[1].count, 0x0001);
+    assert!(!pit.out(1));
+    pit.tick(1, |_| {});
+    assert!(pit.out(1));
+    pit.tick(1, |_| {});
+    assert_eq!(pit.counters[1].count, 0x9999);
+    assert!(pit.out(1));
+
+    // Read-back of channel 1's status, then its count.
+    pit.wb(0x43, 0xc4);
+    assert_eq!(pit.rb(0x41), 0xb1);
+    assert_eq!(pit.rb(0x41), 0x99);
+}
+
+#[test]
+fn test_ticks_until_edge() {
+    let mut pit = PIT::with_type(PitType::PIT8254);
+    // Channel 0 in mode 2, channel 1 in mode 0 counting 10 in BCD,
+    // channel 2 in mode 3 with its gate low to begin with.
+    for (addr, data) in [
+        (0x43, 0x34),
+        (0x40, 0x04),
+        (0x40, 0x00),
+        (0x43, 0x71),
+        (0x41, 0x10),
+        (0x41, 0x00),
+        (0x43, 0xb6),
+        (0x42, 0x06),
+        (0x42, 0x00),
+    ] {
+        pit.wb(addr, data);
+    }
+    pit.tick(2, |_| {});
+    assert_eq!(pit.ticks_until_edge(2), None);
+    pit.set_gate(2, true);
+    // No OUT changes before it says. The gate took two clocks to come up
+    // and the count a clock to load, so the square wave has 19 edges.
+    let mut edges = [0; 3];
+    for _ in 0..60 {
+        let before = [pit.out(0), pit.out(1), pit.out(2)];
+        let until = [0, 1, 2].map(|channel| pit.ticks_until_edge(channel));
+        pit.tick(1, |_| {});
+        for channel in 0..3 {
+            let changed = pit.out(channel) != before[channel];
+            match until[channel] {
+                Some(1) => edges[channel] += changed as usize,
+                _ => assert!(!changed, "channel {} changed early", channel),
+            }
+        }
+    }
+    assert_eq!(edges, [30, 1, 19]);
+    assert_eq!(pit.ticks_until_edge(1), None);
+}
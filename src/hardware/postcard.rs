@@ -0,0 +1,69 @@
+// What a POST card in a spare slot shows: the checkpoint codes the BIOS
+// writes to port 80h as it goes through the power-on self test. When POST
+// hangs, the last code says where. Port 80h is also the unused DMA page
+// register, which is why BIOSes can write there freely, so the board still
+// passes the write on to the DMA controller.
+use crate::hardware::reset::{Reset, ResetKind};
+use log::info;
+use std::collections::VecDeque;
+
+/// How many codes are kept, oldest first.
+const HISTORY: usize = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct PostCard {
+    codes: VecDeque<u8>,
+}
+
+impl PostCard {
+    pub fn new() -> PostCard {
+        PostCard::default()
+    }
+
+    /// The code on the card's display, if the BIOS has written one.
+    pub fn last(&self) -> Option<u8> {
+        self.codes.back().copied()
+    }
+
+    /// The codes written since the machine was turned on, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = u8> + '_ {
+        self.codes.iter().copied()
+    }
+
+    pub fn wb(&mut self, data: u8) {
+        // Some BIOSes write the same code over and over while they wait.
+        if self.last() != Some(data) {
+            info!(target: "post", "POST checkpoint {:02X}h", data);
+        }
+        if self.codes.len() == HISTORY {
+            self.codes.pop_front();
+        }
+        self.codes.push_back(data);
+    }
+}
+
+// The display keeps showing the last code through a reset, which is when
+// it's most wanted; only turning the machine off clears it.
+impl Reset for PostCard {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            self.codes.clear();
+        }
+    }
+}
+
+#[test]
+fn test_post_card_history() {
+    let mut card = PostCard::new();
+    assert_eq!(card.last(), None);
+    for code in 0..40 {
+        card.wb(code);
+    }
+    assert_eq!(card.last(), Some(39));
+    assert_eq!(card.history().count(), HISTORY);
+    assert_eq!(card.history().next(), Some(8));
+    card.reset(ResetKind::Warm);
+    assert_eq!(card.last(), Some(39));
+    card.reset(ResetKind::Cold);
+    assert_eq!(card.last(), None);
+}
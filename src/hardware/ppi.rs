@@ -0,0 +1,287 @@
+use crate::hardware::keyboard::XtKeyboard;
+use crate::hardware::reset::{Reset, ResetKind};
+use log::trace;
+
+/// The 5150 and 5160 wire the PPI's ports differently, mostly in how the
+/// configuration switches are read.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PpiModel {
+    /// SW1 on port A, the memory switches on SW2 through port C.
+    #[default]
+    Pc,
+    /// One bank of switches, read a nibble at a time through port C.
+    Xt,
+}
+
+/// The display the switches tell the BIOS to initialize.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisplaySwitch {
+    /// An adapter with its own BIOS, such as the EGA.
+    None,
+    Cga40,
+    Cga80,
+    Mda,
+}
+
+/// The DIP switches on the system board, described by what they select.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DipSwitches {
+    pub floppy_drives: u8,
+    pub display: DisplaySwitch,
+    pub ram_kb: usize,
+    pub fpu: bool,
+}
+
+impl DipSwitches {
+    /// The bank read as SW1 on a PC and the only bank on an XT. A switch
+    /// that's off reads as 1.
+    pub fn sw1(&self, model: PpiModel) -> u8 {
+        let ram_banks = match model {
+            PpiModel::Pc => self.ram_kb.clamp(16, 64) / 16 - 1,
+            PpiModel::Xt => self.ram_kb.clamp(64, 256) / 64 - 1,
+        };
+        let display = match self.display {
+            DisplaySwitch::None => 0,
+            DisplaySwitch::Cga40 => 1,
+            DisplaySwitch::Cga80 => 2,
+            DisplaySwitch::Mda => 3,
+        };
+        let drives = self.floppy_drives.clamp(1, 4) - 1;
+        (self.floppy_drives > 0) as u8
+            | (self.fpu as u8) << 1
+            | (ram_banks as u8) << 2
+            | display << 4
+            | drives << 6
+    }
+
+    /// The PC's SW2: memory on expansion cards, in 32K steps.
+    pub fn sw2(&self) -> u8 {
+        (self.ram_kb.saturating_sub(64) / 32) as u8 & 0x1f
+    }
+}
+
+impl Default for DipSwitches {
+    fn default() -> DipSwitches {
+        DipSwitches {
+            floppy_drives: 2,
+            display: DisplaySwitch::Cga80,
+            ram_kb: 64,
+            fpu: false,
+        }
+    }
+}
+
+/// Port B bits.
+const PB_TIMER2_GATE: u8 = 0x01;
+const PB_SPEAKER_DATA: u8 = 0x02;
+const PB_PC_SW2_LOW: u8 = 0x04;
+const PB_XT_SW1_HIGH: u8 = 0x08;
+/// The checks are enabled when these bits are clear, and setting one
+/// clears its error.
+const PB_PARITY_CHECK_DISABLE: u8 = 0x10;
+const PB_IO_CHECK_DISABLE: u8 = 0x20;
+const PB_KEYBOARD_CLOCK: u8 = 0x40;
+const PB_KEYBOARD_CLEAR: u8 = 0x80;
+
+/// The 8255 on the PC and XT system board. Port A receives keyboard scan
+/// codes, port B (61h) drives the speaker and the keyboard lines, and
+/// port C reads the switches and a few status lines.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PPI {
+    pub model: PpiModel,
+    pub switches: DipSwitches,
+    pub keyboard: XtKeyboard,
+    pub port_b: u8,
+    pub control: u8,
+    /// PIT channel 2's output, which the board keeps up to date.
+    pub timer2_out: bool,
+    /// The NMI sources' latches, read back on port C.
+    pub parity_error: bool,
+    pub io_channel_check: bool,
+    /// The keyboard's shift register, and whether it holds a full byte.
+    /// A full shift register is what raises IRQ 1.
+    shift_register: u8,
+    full: bool,
+}
+
+impl PPI {
+    pub fn new(model: PpiModel, switches: DipSwitches) -> PPI {
+        PPI {
+            model,
+            switches,
+            keyboard: XtKeyboard::new(),
+            port_b: 0,
+            control: 0x99,
+            timer2_out: false,
+            parity_error: false,
+            io_channel_check: false,
+            shift_register: 0,
+            full: false,
+        }
+    }
+
+    pub fn timer2_gate(&self) -> bool {
+        (self.port_b & PB_TIMER2_GATE) != 0
+    }
+
+    pub fn speaker_data(&self) -> bool {
+        (self.port_b & PB_SPEAKER_DATA) != 0
+    }
+
+    pub fn irq1(&self) -> bool {
+        self.full
+    }
+
+    /// A parity error from memory. Nothing emulated makes one, but a
+    /// debugger can.
+    pub fn raise_parity_error(&mut self) {
+        if (self.port_b & PB_PARITY_CHECK_DISABLE) == 0 {
+            self.parity_error = true;
+        }
+    }
+
+    /// An expansion card pulling -I/O CH CK low.
+    pub fn raise_io_channel_check(&mut self) {
+        if (self.port_b & PB_IO_CHECK_DISABLE) == 0 {
+            self.io_channel_check = true;
+        }
+    }
+
+    /// Whether either check asks for an NMI. The mask register at A0h can
+    /// still keep it from the CPU.
+    pub fn nmi(&self) -> bool {
+        self.parity_error || self.io_channel_check
+    }
+
+    /// Shifts the next byte in from the keyboard if the last one has been
+    /// cleared and the clock line is free.
+    pub fn poll(&mut self) {
+        if self.full || (self.port_b & PB_KEYBOARD_CLEAR) != 0 {
+            return;
+        }
+        if let Some(code) = self.keyboard.read() {
+            trace!(target: "io", "Keyboard sent {:#04x}", code);
+            self.shift_register = code;
+            self.full = true;
+        }
+    }
+
+    fn port_c(&self) -> u8 {
+        let switches = match self.model {
+            PpiModel::Pc if (self.port_b & PB_PC_SW2_LOW) != 0 => self.switches.sw2() & 0x0f,
+            PpiModel::Pc => self.switches.sw2() >> 4,
+            PpiModel::Xt if (self.port_b & PB_XT_SW1_HIGH) != 0 => {
+                self.switches.sw1(self.model) >> 4
+            }
+            PpiModel::Xt => self.switches.sw1(self.model) & 0x0f,
+        };
+        switches
+            | (self.timer2_out as u8) << 5
+            | (self.io_channel_check as u8) << 6
+            | (self.parity_error as u8) << 7
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr & 3 {
+            0 if self.model == PpiModel::Pc && (self.port_b & PB_KEYBOARD_CLEAR) != 0 => {
+                self.switches.sw1(self.model)
+            }
+            0 => self.shift_register,
+            1 => self.port_b,
+            2 => self.port_c(),
+            _ => self.control,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr & 3 {
+            1 => {
+                self.port_b = data;
+                if (data & PB_PARITY_CHECK_DISABLE) != 0 {
+                    self.parity_error = false;
+                }
+                if (data & PB_IO_CHECK_DISABLE) != 0 {
+                    self.io_channel_check = false;
+                }
+                if (data & PB_KEYBOARD_CLEAR) != 0 {
+                    self.shift_register = 0;
+                    self.full = false;
+                }
+                self.keyboard.set_clock((data & PB_KEYBOARD_CLOCK) != 0);
+            }
+            // Bit set/reset only touches port C, which is all inputs here.
+            3 if (data & 0x80) == 0 => {}
+            3 => self.control = data,
+            _ => {}
+        }
+    }
+}
+
+// The 8255's RESET is the system reset. Port B comes out of it cleared,
+// holding the keyboard clock low.
+impl Reset for PPI {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        self.wb(1, 0);
+        self.control = 0x99;
+        self.parity_error = false;
+        self.io_channel_check = false;
+        if kind == ResetKind::Cold {
+            self.keyboard = XtKeyboard::new();
+            self.keyboard.set_clock(false);
+        }
+    }
+}
+
+#[test]
+fn test_ppi_switches_and_keyboard() {
+    let switches = DipSwitches {
+        floppy_drives: 1,
+        display: DisplaySwitch::Mda,
+        ram_kb: 256,
+        fpu: false,
+    };
+    let mut ppi = PPI::new(PpiModel::Pc, switches);
+    ppi.wb(0x63, 0x99);
+    ppi.wb(0x61, 0x80);
+    assert_eq!(ppi.rb(0x60), 0x3d);
+    ppi.wb(0x61, 0x84);
+    assert_eq!(ppi.rb(0x62), 0x06);
+    ppi.wb(0x61, 0x80);
+    ppi.timer2_out = true;
+    assert_eq!(ppi.rb(0x62), 0x20);
+
+    let mut xt = PPI::new(
+        PpiModel::Xt,
+        DipSwitches {
+            ram_kb: 640,
+            ..switches
+        },
+    );
+    xt.wb(0x61, 0x00);
+    assert_eq!(xt.rb(0x62) & 0x0f, 0x0d);
+    xt.wb(0x61, 0x08);
+    assert_eq!(xt.rb(0x62) & 0x0f, 0x03);
+
+    // The BIOS holds the clock low, lets it go and waits for AAh.
+    ppi.wb(0x61, 0x08);
+    ppi.wb(0x61, 0x48);
+    ppi.keyboard.key(0x1e, true);
+    ppi.poll();
+    assert!(ppi.irq1());
+    assert_eq!(ppi.rb(0x60), 0xaa);
+    ppi.poll();
+    assert_eq!(ppi.rb(0x60), 0xaa);
+    ppi.wb(0x61, 0xc8);
+    assert!(!ppi.irq1());
+    ppi.wb(0x61, 0x48);
+    ppi.poll();
+    assert_eq!(ppi.rb(0x60), 0x1e);
+    ppi.wb(0x61, 0xc8);
+    ppi.wb(0x61, 0x48);
+    ppi.keyboard.key(0x1e, false);
+    ppi.poll();
+    assert_eq!(ppi.rb(0x60), 0x9e);
+}
@@ -0,0 +1,151 @@
+// A printer on a parallel port that prints into a host file. It takes the
+// byte on the data lines when the guest pulses STROBE and answers with an
+// acknowledge pulse, like a printer with an endless supply of paper. The
+// file gets either exactly what the guest sent, or the text on the page
+// with the Epson escape sequences dropped and the PC's characters turned
+// into Unicode.
+use crate::hardware::passthrough::ParallelBackend;
+use log::warn;
+use std::fs::File;
+use std::io::Write;
+
+/// Code page 437 from 80h up, for the box drawing, accents and symbols.
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+    αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+const ESC: u8 = 0x1b;
+
+/// Status lines of a printer that's on line and ready.
+const STATUS_READY: u8 = 0xd8;
+const STATUS_ACK: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrintFormat {
+    Raw,
+    Text,
+}
+
+/// How long the escape sequence started by `sequence`, the bytes after
+/// ESC, is in all, once enough of it has arrived to tell. Bit image
+/// sequences carry their own length.
+fn escape_length(sequence: &[u8]) -> Option<usize> {
+    let count = |low: u8, high: u8| low as usize | (high as usize) << 8;
+    match *sequence {
+        [b'K' | b'L' | b'Y' | b'Z', low, high, ..] => Some(3 + count(low, high)),
+        [b'K' | b'L' | b'Y' | b'Z', ..] => None,
+        [b'*', _, low, high, ..] => Some(4 + count(low, high)),
+        [b'*', ..] => None,
+        [b'C', 0, ..] => Some(3),
+        [b'C', _, ..] => Some(2),
+        [b'C'] => None,
+        [b'0' | b'1' | b'2' | b'4' | b'5' | b'8' | b'9' | b'<' | b'@', ..] => Some(1),
+        [b'E' | b'F' | b'G' | b'H' | b'M' | b'O' | b'P' | b'T' | b'g', ..] => Some(1),
+        [_, ..] => Some(2),
+        [] => None,
+    }
+}
+
+pub struct Printer {
+    file: File,
+    path: String,
+    pub format: PrintFormat,
+    data: u8,
+    strobe: bool,
+    /// The rest of an escape sequence being skipped.
+    escape: Option<Vec<u8>>,
+}
+
+impl Printer {
+    pub fn create(path: &str, format: PrintFormat) -> Result<Printer, String> {
+        let file = File::create(path).map_err(|err| format!("{}: {}", path, err))?;
+        Ok(Printer {
+            file,
+            path: path.to_string(),
+            format,
+            data: 0,
+            strobe: false,
+            escape: None,
+        })
+    }
+
+    /// What a byte puts on the page.
+    fn render(&mut self, byte: u8) -> Option<char> {
+        if let Some(sequence) = &mut self.escape {
+            sequence.push(byte);
+            if escape_length(sequence).is_some_and(|length| sequence.len() >= length) {
+                self.escape = None;
+            }
+            return None;
+        }
+        match byte {
+            ESC => {
+                self.escape = Some(Vec::new());
+                None
+            }
+            b'\t' | b'\n' | 0x0c => Some(byte as char),
+            0x20..=0x7e => Some(byte as char),
+            0x80..=0xff => CP437_HIGH.chars().nth(byte as usize - 0x80),
+            // Carriage returns only matter for overprinting, and the rest
+            // change the print mode.
+            _ => None,
+        }
+    }
+
+    fn print(&mut self, byte: u8) {
+        let result = match self.format {
+            PrintFormat::Raw => self.file.write_all(&[byte]),
+            PrintFormat::Text => match self.render(byte) {
+                Some(c) => write!(self.file, "{}", c),
+                None => Ok(()),
+            },
+        };
+        if let Err(err) = result {
+            warn!("{}: {}", self.path, err);
+        }
+    }
+}
+
+impl ParallelBackend for Printer {
+    fn write_data(&mut self, value: u8) {
+        self.data = value;
+    }
+
+    fn write_control(&mut self, value: u8) {
+        let strobe = (value & 1) != 0;
+        if strobe && !self.strobe {
+            self.print(self.data);
+        }
+        self.strobe = strobe;
+    }
+
+    /// The acknowledge pulse lasts as long as the strobe does.
+    fn read_status(&mut self) -> u8 {
+        match self.strobe {
+            true => STATUS_READY & !STATUS_ACK,
+            false => STATUS_READY,
+        }
+    }
+}
+
+#[test]
+fn test_printer_text_rendering() {
+    let path = std::env::temp_dir().join(format!("emupc-printer-{}.txt", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut printer = Printer::create(path, PrintFormat::Text).unwrap();
+    let mut print = |bytes: &[u8]| {
+        for &byte in bytes {
+            printer.write_data(byte);
+            printer.write_control(0x05);
+            assert_eq!(printer.read_status() & STATUS_ACK, 0);
+            printer.write_control(0x04);
+        }
+    };
+    // Bold on, a box corner, a bit image and condensed mode in between.
+    print(b"\x1bEHi\xc9\xcd\r\n");
+    print(b"\x1bK\x03\x00abc\x0fok\x1b@\x0c");
+    drop(printer);
+    let text = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(text, "Hi╔═\nok\x0c");
+}
@@ -0,0 +1,309 @@
+// The PS/2 mouse, on the 8042's auxiliary port. Like the keyboard it talks
+// to the controller a byte at a time and answers commands; in stream mode
+// with reporting on, movement and buttons go out as three byte packets.
+// Motion from the host adds up until the controller has room for the next
+// packet, so a busy guest sees fewer, bigger moves rather than a backlog.
+use crate::hardware::reset::{Reset, ResetKind};
+use crate::input::InputEvent;
+use log::{debug, trace};
+use std::collections::VecDeque;
+
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const SELF_TEST_PASSED: u8 = 0xaa;
+/// The device ID of a plain two or three button mouse.
+const MOUSE_ID: u8 = 0x00;
+
+const DEFAULT_SAMPLE_RATE: u8 = 100;
+/// 4 counts per millimetre.
+const DEFAULT_RESOLUTION: u8 = 2;
+
+/// Packet byte 0 bits. Bit 3 is always set, which is how drivers find
+/// the start of a packet.
+const PACKET_ALWAYS: u8 = 0x08;
+const PACKET_X_SIGN: u8 = 0x10;
+const PACKET_Y_SIGN: u8 = 0x20;
+const PACKET_X_OVERFLOW: u8 = 0x40;
+const PACKET_Y_OVERFLOW: u8 = 0x80;
+
+/// Status byte 0 bits, as the status request reports them.
+const STATUS_SCALING: u8 = 0x10;
+const STATUS_REPORTING: u8 = 0x20;
+const STATUS_REMOTE: u8 = 0x40;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ps2Mouse {
+    /// Bytes waiting to go to the controller.
+    pub output: VecDeque<u8>,
+    pub reporting: bool,
+    /// In remote mode the mouse only sends a packet when asked to.
+    pub remote: bool,
+    pub sample_rate: u8,
+    pub resolution: u8,
+    /// 2:1 scaling rather than 1:1.
+    pub scaling: bool,
+    /// Echoing every byte back, until told to stop or reset.
+    pub wrap: bool,
+    /// Buttons in bits 0-2: left, right, middle.
+    pub buttons: u8,
+    /// Motion not yet sent, right and up being positive.
+    dx: i32,
+    dy: i32,
+    sent_buttons: u8,
+    /// A command waiting for its parameter byte.
+    pending_command: Option<u8>,
+    last_sent: u8,
+}
+
+impl Ps2Mouse {
+    pub fn new() -> Ps2Mouse {
+        Ps2Mouse {
+            output: VecDeque::new(),
+            reporting: false,
+            remote: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            resolution: DEFAULT_RESOLUTION,
+            scaling: false,
+            wrap: false,
+            buttons: 0,
+            dx: 0,
+            dy: 0,
+            sent_buttons: 0,
+            pending_command: None,
+            last_sent: 0,
+        }
+    }
+
+    fn set_defaults(&mut self) {
+        self.sample_rate = DEFAULT_SAMPLE_RATE;
+        self.resolution = DEFAULT_RESOLUTION;
+        self.scaling = false;
+        self.reporting = false;
+        self.clear_motion();
+    }
+
+    fn clear_motion(&mut self) {
+        self.dx = 0;
+        self.dy = 0;
+        self.sent_buttons = self.buttons;
+    }
+
+    /// Host mouse input. Other events are for other devices.
+    pub fn input(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::MouseMove { dx, dy } => {
+                self.dx += dx;
+                self.dy -= dy;
+            }
+            InputEvent::MouseButton { button, pressed } if button < 3 => {
+                let bit = 1 << button;
+                match pressed {
+                    true => self.buttons |= bit,
+                    false => self.buttons &= !bit,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn queue_packet(&mut self) {
+        // 2:1 scaling only applies to the packets stream mode sends.
+        let scale = |delta: i32, scaling: bool| {
+            let size = match (scaling, delta.abs()) {
+                (false, size) | (true, size @ (0 | 1 | 3)) => size,
+                (true, 2) => 1,
+                (true, 4) => 6,
+                (true, 5) => 9,
+                (true, size) => size * 2,
+            };
+            size * delta.signum()
+        };
+        let scaling = self.scaling && !self.remote;
+        let dx = scale(self.dx, scaling);
+        let dy = scale(self.dy, scaling);
+        self.dx = 0;
+        self.dy = 0;
+        self.sent_buttons = self.buttons;
+        let mut flags = PACKET_ALWAYS | (self.buttons & 0x07);
+        if dx < 0 {
+            flags |= PACKET_X_SIGN;
+        }
+        if dy < 0 {
+            flags |= PACKET_Y_SIGN;
+        }
+        if !(-256..=255).contains(&dx) {
+            flags |= PACKET_X_OVERFLOW;
+        }
+        if !(-256..=255).contains(&dy) {
+            flags |= PACKET_Y_OVERFLOW;
+        }
+        let dx = dx.clamp(-256, 255) as u8;
+        let dy = dy.clamp(-256, 255) as u8;
+        self.output.extend([flags, dx, dy]);
+    }
+
+    /// The next byte for the controller, with a packet made up first if
+    /// there's nothing else to send and something has changed.
+    pub fn read(&mut self) -> Option<u8> {
+        if self.output.is_empty()
+            && self.reporting
+            && !self.remote
+            && !self.wrap
+            && (self.dx != 0 || self.dy != 0 || self.buttons != self.sent_buttons)
+        {
+            self.queue_packet();
+        }
+        let byte = self.output.pop_front()?;
+        self.last_sent = byte;
+        Some(byte)
+    }
+
+    /// A byte from the controller: a command, or a command's parameter.
+    pub fn write(&mut self, data: u8) {
+        trace!(target: "io", "Mouse command {:#04x}", data);
+        if self.wrap && data != 0xec && data != 0xff {
+            self.output.push_back(data);
+            return;
+        }
+        if let Some(command) = self.pending_command.take() {
+            match command {
+                0xe8 if data < 4 => self.resolution = data,
+                0xf3 => self.sample_rate = data,
+                _ => {
+                    self.output.push_back(RESEND);
+                    return;
+                }
+            }
+            self.output.push_back(ACK);
+            return;
+        }
+        // Every command but resend stops a stream packet being built.
+        if data != 0xfe {
+            self.output.clear();
+        }
+        match data {
+            0xe6 => self.scaling = false,
+            0xe7 => self.scaling = true,
+            0xe8 | 0xf3 => self.pending_command = Some(data),
+            0xe9 => {
+                let mut status = self.buttons & 0x07;
+                if self.scaling {
+                    status |= STATUS_SCALING;
+                }
+                if self.reporting {
+                    status |= STATUS_REPORTING;
+                }
+                if self.remote {
+                    status |= STATUS_REMOTE;
+                }
+                self.output.push_back(ACK);
+                self.output
+                    .extend([status, self.resolution, self.sample_rate]);
+                return;
+            }
+            0xea => {
+                self.remote = false;
+                self.clear_motion();
+            }
+            0xeb => {
+                self.output.push_back(ACK);
+                self.queue_packet();
+                return;
+            }
+            0xec => self.wrap = false,
+            0xee => self.wrap = true,
+            0xf0 => {
+                self.remote = true;
+                self.clear_motion();
+            }
+            0xf2 => {
+                self.output.extend([ACK, MOUSE_ID]);
+                return;
+            }
+            0xf4 => {
+                self.reporting = true;
+                self.clear_motion();
+            }
+            0xf5 => {
+                self.reporting = false;
+                self.clear_motion();
+            }
+            0xf6 => self.set_defaults(),
+            0xfe => {
+                self.output.push_back(self.last_sent);
+                return;
+            }
+            0xff => {
+                let buttons = self.buttons;
+                *self = Ps2Mouse::new();
+                self.buttons = buttons;
+                self.sent_buttons = buttons;
+                self.output.extend([ACK, SELF_TEST_PASSED, MOUSE_ID]);
+                return;
+            }
+            _ => {
+                debug!(target: "io", "Unknown mouse command {:#04x}", data);
+                self.output.push_back(RESEND);
+                return;
+            }
+        }
+        self.output.push_back(ACK);
+    }
+}
+
+impl Default for Ps2Mouse {
+    fn default() -> Ps2Mouse {
+        Ps2Mouse::new()
+    }
+}
+
+// Like the keyboard, the mouse only resets when it loses power.
+impl Reset for Ps2Mouse {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Cold {
+            *self = Ps2Mouse::new();
+        }
+    }
+}
+
+#[test]
+fn test_ps2_mouse_commands_and_packets() {
+    let mut mouse = Ps2Mouse::new();
+    let drain = |mouse: &mut Ps2Mouse| std::iter::from_fn(|| mouse.read()).collect::<Vec<u8>>();
+
+    // Reset, then what a driver does: sample rate, resolution, ID, enable.
+    mouse.write(0xff);
+    assert_eq!(drain(&mut mouse), vec![ACK, SELF_TEST_PASSED, MOUSE_ID]);
+    for data in [0xf3, 40, 0xe8, 3] {
+        mouse.write(data);
+        assert_eq!(drain(&mut mouse), vec![ACK]);
+    }
+    mouse.write(0xf2);
+    assert_eq!(drain(&mut mouse), vec![ACK, MOUSE_ID]);
+
+    // Nothing is reported until reporting is enabled.
+    mouse.input(InputEvent::MouseMove { dx: 3, dy: 3 });
+    assert!(drain(&mut mouse).is_empty());
+    mouse.write(0xf4);
+    assert_eq!(drain(&mut mouse), vec![ACK]);
+
+    // Left and down, with the right button held: down is negative.
+    mouse.input(InputEvent::MouseButton {
+        button: 1,
+        pressed: true,
+    });
+    mouse.input(InputEvent::MouseMove { dx: -5, dy: 2 });
+    assert_eq!(drain(&mut mouse), vec![0x3a, 0xfb, 0xfe]);
+    mouse.input(InputEvent::MouseMove { dx: 300, dy: 0 });
+    assert_eq!(drain(&mut mouse), vec![0x4a, 0xff, 0x00]);
+
+    // The status request, and remote mode only sending on request.
+    mouse.write(0xe9);
+    assert_eq!(drain(&mut mouse), vec![ACK, 0x22, 3, 40]);
+    mouse.write(0xf0);
+    assert_eq!(drain(&mut mouse), vec![ACK]);
+    mouse.input(InputEvent::MouseMove { dx: 1, dy: -1 });
+    assert!(drain(&mut mouse).is_empty());
+    mouse.write(0xeb);
+    assert_eq!(drain(&mut mouse), vec![ACK, 0x0a, 0x01, 0x01]);
+}
@@ -0,0 +1,55 @@
+// What survives a reset depends on which reset it is. On a real PC:
+//
+//   device     cold    hard    warm
+//   RAM        lost    kept    kept
+//   CPU        reset   reset   reset
+//   8042       reset   reset   kept   (A20 and the output port with it)
+//   8259 PIC   reset   kept    kept   (no reset pin; the BIOS reprograms it)
+//   8253 PIT   reset   kept    kept   (no reset pin either)
+//   video      reset   reset   kept   (RESET DRV on the bus)
+//   CMOS/RTC   kept    kept    kept   (battery backed)
+//
+// Warm resets are how the AT BIOS gets back to real mode and how
+// Ctrl-Alt-Del reboots: it stores 1234h at 40:72 first so POST can skip
+// the memory test, which only works because RAM is left alone.
+
+/// The kinds of reset, weakest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResetKind {
+    /// Only the CPU's RESET pin: the 8042 pulsing its reset output, port
+    /// 92h bit 0, or a shutdown cycle.
+    Warm,
+    /// The system reset line, as pulled by a reset button. Devices with a
+    /// reset pin start over and RAM is kept.
+    Hard,
+    /// Power on. Everything starts over except what the battery keeps.
+    Cold,
+}
+
+/// A device's response to a reset. Each device decides for itself what a
+/// given kind of reset clears.
+pub trait Reset {
+    fn reset(&mut self, kind: ResetKind);
+}
+
+/// Collects reset requests from the devices that can make them until the
+/// machine gets round to acting on them. If more than one arrives before
+/// then, the strongest wins.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResetController {
+    pending: Option<ResetKind>,
+}
+
+impl ResetController {
+    pub fn new() -> ResetController {
+        ResetController::default()
+    }
+
+    pub fn request(&mut self, kind: ResetKind) {
+        self.pending = self.pending.max(Some(kind));
+    }
+
+    pub fn take(&mut self) -> Option<ResetKind> {
+        self.pending.take()
+    }
+}
@@ -0,0 +1,210 @@
+// ROM images from disk: the system BIOS, and option ROMs for cards the
+// board has no model of its own for, like a VGA BIOS or a SCSI adapter's.
+//
+// The BIOS goes at the top of the address space, ending at FFFFFh so the
+// reset vector is its last sixteen bytes. 16-bit boards keep it in a pair
+// of 8-bit chips, one for the even bytes and one for the odd, and dumps
+// often come that way. Option ROMs are found by the BIOS at POST, which
+// looks every 2K from C0000h for the 55h AAh signature, a length in 512
+// byte blocks, and a checksum of zero, so they have to carry all three.
+use crate::hardware::memory::MemoryBus;
+use log::{info, warn};
+use std::fs;
+
+/// Where the POST scan for option ROMs starts and stops.
+pub const OPTION_ROM_START: u32 = 0x0c_0000;
+pub const OPTION_ROM_END: u32 = 0x0f_3fff;
+/// Option ROMs are looked for at this granularity.
+const OPTION_ROM_ALIGN: u32 = 0x800;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BiosImage {
+    /// One file holding the whole ROM.
+    File(String),
+    /// The even and odd halves of a 16-bit board's ROM, in two files.
+    Split { even: String, odd: String },
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("{}: {}", path, err))
+}
+
+impl BiosImage {
+    /// `EVEN,ODD` for a split pair, otherwise one path.
+    pub fn parse(spec: &str) -> BiosImage {
+        match spec.split_once(',') {
+            Some((even, odd)) => BiosImage::Split {
+                even: even.to_string(),
+                odd: odd.to_string(),
+            },
+            None => BiosImage::File(spec.to_string()),
+        }
+    }
+
+    pub fn load(&self) -> Result<Vec<u8>, String> {
+        match self {
+            BiosImage::File(path) => read(path),
+            BiosImage::Split { even, odd } => interleave(&read(even)?, &read(odd)?),
+        }
+    }
+}
+
+/// Puts the two halves of a split ROM back together.
+pub fn interleave(even: &[u8], odd: &[u8]) -> Result<Vec<u8>, String> {
+    if even.len() != odd.len() {
+        return Err(format!(
+            "the even and odd halves differ in size: {} and {} bytes",
+            even.len(),
+            odd.len()
+        ));
+    }
+    Ok(even
+        .iter()
+        .zip(odd)
+        .flat_map(|(&even, &odd)| [even, odd])
+        .collect())
+}
+
+/// Maps `bios` to end at each of `tops`, in place of the ROMs within
+/// `max_len` bytes below them. The AT maps its BIOS at the top of both the
+/// first and the sixteenth megabyte.
+pub fn map_bios<M>(
+    memory: &mut MemoryBus<M>,
+    bios: Vec<u8>,
+    tops: &[u32],
+    max_len: usize,
+) -> Result<(), String> {
+    if bios.is_empty() || bios.len() > max_len {
+        return Err(format!(
+            "a BIOS image must be 1 to {}K, got {} bytes",
+            max_len / 1024,
+            bios.len()
+        ));
+    }
+    for &top in tops {
+        memory.unmap_roms(top - max_len as u32, top - 1);
+        memory.map_rom(top - bios.len() as u32, bios.clone());
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptionRom {
+    pub data: Vec<u8>,
+}
+
+impl OptionRom {
+    /// Checks the header and trims the image to the length it gives. A
+    /// bad checksum is only a warning: the BIOS reports it, but some dumps
+    /// people use every day have one.
+    pub fn new(mut data: Vec<u8>) -> Result<OptionRom, String> {
+        if data.len() < 3 || data[0] != 0x55 || data[1] != 0xaa {
+            return Err("no 55AA option ROM signature".to_string());
+        }
+        let size = data[2] as usize * 512;
+        if size == 0 || size > data.len() {
+            return Err(format!(
+                "the header claims {} bytes, the image has {}",
+                size,
+                data.len()
+            ));
+        }
+        data.truncate(size);
+        let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if sum != 0 {
+            warn!("Option ROM checksum is {:02x}, not 0", sum);
+        }
+        Ok(OptionRom { data })
+    }
+
+    pub fn load(path: &str) -> Result<OptionRom, String> {
+        OptionRom::new(read(path)?).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    /// Maps the ROM at `addr`, which can be over a card's own ROM, or
+    /// with `None` in the first free space the POST scan will find it in.
+    /// Returns where it went.
+    pub fn map<M>(self, memory: &mut MemoryBus<M>, addr: Option<u32>) -> Result<u32, String> {
+        let len = self.data.len() as u32;
+        let addr = match addr {
+            Some(addr) => {
+                if addr % OPTION_ROM_ALIGN != 0
+                    || addr < OPTION_ROM_START
+                    || addr + len - 1 > OPTION_ROM_END
+                {
+                    return Err(format!(
+                        "an option ROM must be on a 2K boundary from {:05X}h to {:05X}h, \
+                         got {:05X}h",
+                        OPTION_ROM_START,
+                        OPTION_ROM_END + 1,
+                        addr
+                    ));
+                }
+                addr
+            }
+            None => (OPTION_ROM_START..=OPTION_ROM_END + 1 - len)
+                .step_by(OPTION_ROM_ALIGN as usize)
+                .find(|&addr| memory.is_free(addr, addr + len - 1))
+                .ok_or_else(|| format!("no room for a {}K option ROM", len / 1024))?,
+        };
+        info!("Option ROM at {:05X}h, {}K", addr, len / 1024);
+        memory.map_rom(addr, self.data);
+        Ok(addr)
+    }
+}
+
+#[cfg(test)]
+use crate::hardware::memory::MemoryRead;
+
+/// A ROM of `blocks` 512-byte blocks that checksums to zero.
+#[cfg(test)]
+fn option_rom(blocks: u8) -> Vec<u8> {
+    let mut data = vec![0x55, 0xaa, blocks, 0xcb];
+    data.resize(blocks as usize * 512, 0);
+    let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    data[4] = sum.wrapping_neg();
+    data
+}
+
+#[test]
+fn test_bios_images() {
+    let dir = std::env::temp_dir();
+    let even = dir.join(format!("emupc-bios-even-{}.bin", std::process::id()));
+    let odd = dir.join(format!("emupc-bios-odd-{}.bin", std::process::id()));
+    fs::write(&even, [0x01, 0x03]).unwrap();
+    fs::write(&odd, [0x02, 0x04]).unwrap();
+    let spec = format!("{},{}", even.display(), odd.display());
+    let bios = BiosImage::parse(&spec).load().unwrap();
+    assert_eq!(bios, vec![1, 2, 3, 4]);
+    fs::write(&odd, [0x02]).unwrap();
+    assert!(BiosImage::parse(&spec).load().is_err());
+    fs::remove_file(even).unwrap();
+    fs::remove_file(odd).unwrap();
+
+    let mut memory: MemoryBus<()> = MemoryBus::new(0x1000);
+    memory.map_rom(0x0f_e000, vec![0xaa; 0x2000]);
+    map_bios(&mut memory, bios, &[0x10_0000, 0x100_0000], 0x1_0000).unwrap();
+    assert_eq!(memory.read(0x0f_fffc), MemoryRead::Data(1));
+    assert_eq!(memory.read(0x0f_e000), MemoryRead::Data(0xff));
+    assert_eq!(memory.read(0xff_ffff), MemoryRead::Data(4));
+    assert!(map_bios(&mut memory, vec![0; 0x2_0000], &[0x10_0000], 0x1_0000).is_err());
+}
+
+#[test]
+fn test_option_roms() {
+    assert!(OptionRom::new(vec![0; 512]).is_err());
+    assert!(OptionRom::new(option_rom(4)[..1024].to_vec()).is_err());
+    let mut data = option_rom(4);
+    data.extend([0xff; 100]);
+    let rom = OptionRom::new(data).unwrap();
+    assert_eq!(rom.data.len(), 2048);
+
+    let mut memory: MemoryBus<()> = MemoryBus::new(0x1000);
+    memory.map_mmio(0x0c_0000, 0x0c_3fff, ());
+    assert_eq!(rom.clone().map(&mut memory, None), Ok(0x0c_4000));
+    assert_eq!(rom.clone().map(&mut memory, None), Ok(0x0c_4800));
+    assert_eq!(memory.read(0x0c_4800), MemoryRead::Data(0x55));
+    assert_eq!(rom.clone().map(&mut memory, Some(0x0c_0000)), Ok(0x0c_0000));
+    assert!(rom.clone().map(&mut memory, Some(0x0c_0100)).is_err());
+    assert!(rom.map(&mut memory, Some(0x0f_4000)).is_err());
+}
@@ -0,0 +1,474 @@
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, warn};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The RTC's crystal.
+pub const RTC_CLOCK_HZ: u32 = 32_768;
+
+const SECONDS: usize = 0x00;
+const SECONDS_ALARM: usize = 0x01;
+const MINUTES: usize = 0x02;
+const MINUTES_ALARM: usize = 0x03;
+const HOURS: usize = 0x04;
+const HOURS_ALARM: usize = 0x05;
+const DAY_OF_WEEK: usize = 0x06;
+const DAY_OF_MONTH: usize = 0x07;
+const MONTH: usize = 0x08;
+const YEAR: usize = 0x09;
+const REGISTER_A: usize = 0x0a;
+const REGISTER_B: usize = 0x0b;
+const REGISTER_C: usize = 0x0c;
+const REGISTER_D: usize = 0x0d;
+/// Where the IBM BIOS keeps the century. The chip itself knows nothing
+/// about it.
+pub const CENTURY: usize = 0x32;
+
+const A_UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Divider bits for a 32.768 kHz time base. Anything else stops the clock.
+const A_DIVIDER_32K: u8 = 0x20;
+const B_SET: u8 = 0x80;
+const B_PERIODIC: u8 = 0x40;
+const B_ALARM: u8 = 0x20;
+const B_UPDATE_ENDED: u8 = 0x10;
+const B_BINARY: u8 = 0x04;
+const B_24_HOUR: u8 = 0x02;
+const C_IRQ: u8 = 0x80;
+const D_VALID_RAM: u8 = 0x80;
+
+/// How long before the end of each second UIP goes up: 244 us.
+const UPDATE_WARNING_TICKS: u32 = 8;
+
+/// The AT's MC146818 real-time clock and its 64 bytes of battery backed
+/// RAM, at ports 70h and 71h. The clock registers hold whatever format
+/// register B asked for when they were written, BCD or binary and 12 or 24
+/// hour, just like the real chip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RTC {
+    pub ram: [u8; 64],
+    /// The register selected through port 70h.
+    pub index: u8,
+    /// Bit 7 of port 70h, which the AT wires to the NMI mask.
+    pub nmi_masked: bool,
+    /// 32 kHz cycles into the current second and periodic interval.
+    divider: u32,
+    periodic: u32,
+    /// Where the RAM is saved whenever software changes it.
+    nvram_path: Option<PathBuf>,
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+fn days_in_month(month: u8, year: u8) -> u8 {
+    match month {
+        2 if year.is_multiple_of(4) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl RTC {
+    /// Starts the clock at the host's time, in UTC, with the registers as
+    /// the AT BIOS sets them up.
+    pub fn new() -> RTC {
+        let mut rtc = RTC {
+            ram: [0; 64],
+            index: 0,
+            nmi_masked: false,
+            divider: 0,
+            periodic: 0,
+            nvram_path: None,
+        };
+        rtc.ram[REGISTER_A] = A_DIVIDER_32K | 0x06;
+        rtc.ram[REGISTER_B] = B_24_HOUR;
+        rtc.ram[REGISTER_D] = D_VALID_RAM;
+        rtc.set_host_time();
+        rtc
+    }
+
+    fn binary(&self) -> bool {
+        (self.ram[REGISTER_B] & B_BINARY) != 0
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.binary() {
+            value
+        } else {
+            to_bcd(value)
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.binary() {
+            value
+        } else {
+            from_bcd(value)
+        }
+    }
+
+    fn encode_hours(&self, hours: u8) -> u8 {
+        if (self.ram[REGISTER_B] & B_24_HOUR) != 0 {
+            return self.encode(hours);
+        }
+        let pm = if hours >= 12 { 0x80 } else { 0 };
+        let hours = match hours % 12 {
+            0 => 12,
+            hours => hours,
+        };
+        self.encode(hours) | pm
+    }
+
+    fn decode_hours(&self, value: u8) -> u8 {
+        if (self.ram[REGISTER_B] & B_24_HOUR) != 0 {
+            return self.decode(value);
+        }
+        let hours = self.decode(value & 0x7f) % 12;
+        if (value & 0x80) != 0 {
+            hours + 12
+        } else {
+            hours
+        }
+    }
+
+    /// Sets the clock, in the current format. `year` is the full year; the
+    /// century goes where the IBM BIOS looks for it.
+    pub fn set_time(&mut self, year: u16, month: u8, day: u8, hours: u8, minutes: u8, seconds: u8) {
+        // Zeller's congruence, shifted so that Sunday is 1.
+        let (y, m) = if month < 3 {
+            (year - 1, month as u16 + 12)
+        } else {
+            (year, month as u16)
+        };
+        let weekday = (day as u16 + 13 * (m + 1) / 5 + y + y / 4 - y / 100 + y / 400 + 6) % 7 + 1;
+        self.ram[SECONDS] = self.encode(seconds);
+        self.ram[MINUTES] = self.encode(minutes);
+        self.ram[HOURS] = self.encode_hours(hours);
+        self.ram[DAY_OF_WEEK] = self.encode(weekday as u8);
+        self.ram[DAY_OF_MONTH] = self.encode(day);
+        self.ram[MONTH] = self.encode(month);
+        self.ram[YEAR] = self.encode((year % 100) as u8);
+        self.ram[CENTURY] = to_bcd((year / 100) as u8);
+    }
+
+    pub fn set_host_time(&mut self) {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        // Days since 1970 to a civil date, after Howard Hinnant.
+        let z = secs / 86_400 + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u64;
+        let time = secs % 86_400;
+        self.set_time(
+            year as u16,
+            month as u8,
+            day as u8,
+            (time / 3600) as u8,
+            (time / 60 % 60) as u8,
+            (time % 60) as u8,
+        );
+    }
+
+    /// IRQ 8.
+    pub fn irq(&self) -> bool {
+        (self.ram[REGISTER_C] & C_IRQ) != 0
+    }
+
+    fn raise(&mut self, flag: u8, enable: u8) {
+        self.ram[REGISTER_C] |= flag;
+        if (self.ram[REGISTER_B] & enable) != 0 {
+            self.ram[REGISTER_C] |= C_IRQ;
+        }
+    }
+
+    /// The update cycle at the end of each second: move the clock on and
+    /// check the alarm.
+    fn update(&mut self) {
+        if (self.ram[REGISTER_B] & B_SET) != 0 {
+            return;
+        }
+        // Garbage in the registers rolls over wherever it ends up, so
+        // nothing here can be allowed to overflow.
+        let mut seconds = self.decode(self.ram[SECONDS]).wrapping_add(1);
+        let mut minutes = self.decode(self.ram[MINUTES]);
+        let mut hours = self.decode_hours(self.ram[HOURS]);
+        let mut weekday = self.decode(self.ram[DAY_OF_WEEK]);
+        let mut day = self.decode(self.ram[DAY_OF_MONTH]);
+        let mut month = self.decode(self.ram[MONTH]);
+        let mut year = self.decode(self.ram[YEAR]);
+        if seconds >= 60 {
+            seconds = 0;
+            minutes = minutes.wrapping_add(1);
+        }
+        if minutes >= 60 {
+            minutes = 0;
+            hours = hours.wrapping_add(1);
+        }
+        if hours >= 24 {
+            hours = 0;
+            weekday = weekday % 7 + 1;
+            day = day.wrapping_add(1);
+        }
+        if day > days_in_month(month, year) {
+            day = 1;
+            month = month.wrapping_add(1);
+        }
+        if month > 12 {
+            month = 1;
+            year = year.wrapping_add(1) % 100;
+        }
+        self.ram[SECONDS] = self.encode(seconds);
+        self.ram[MINUTES] = self.encode(minutes);
+        self.ram[HOURS] = self.encode_hours(hours);
+        self.ram[DAY_OF_WEEK] = self.encode(weekday);
+        self.ram[DAY_OF_MONTH] = self.encode(day);
+        self.ram[MONTH] = self.encode(month);
+        self.ram[YEAR] = self.encode(year);
+
+        // An alarm byte of C0h or above matches anything.
+        let alarm = [
+            (SECONDS_ALARM, SECONDS),
+            (MINUTES_ALARM, MINUTES),
+            (HOURS_ALARM, HOURS),
+        ]
+        .iter()
+        .all(|&(alarm, current)| self.ram[alarm] >= 0xc0 || self.ram[alarm] == self.ram[current]);
+        if alarm {
+            self.raise(0x20, B_ALARM);
+        }
+        self.raise(0x10, B_UPDATE_ENDED);
+    }
+
+    /// The periodic interrupt's period in 32 kHz cycles, if it's on. Rates
+    /// 1 and 2 are the odd ones out, repeating 7 and 8.
+    fn periodic_ticks(&self) -> Option<u32> {
+        match self.ram[REGISTER_A] & 0x0f {
+            0 => None,
+            1 => Some(128),
+            2 => Some(256),
+            rate => Some(1 << (rate - 1)),
+        }
+    }
+
+    /// Runs `ticks` cycles of the 32 kHz crystal.
+    pub fn tick(&mut self, ticks: usize) {
+        if (self.ram[REGISTER_A] & 0x70) != A_DIVIDER_32K {
+            return;
+        }
+        for _ in 0..ticks {
+            if let Some(period) = self.periodic_ticks() {
+                self.periodic += 1;
+                if self.periodic >= period {
+                    self.periodic = 0;
+                    self.raise(0x40, B_PERIODIC);
+                }
+            }
+            self.divider += 1;
+            if self.divider == RTC_CLOCK_HZ {
+                self.divider = 0;
+                self.update();
+            }
+        }
+    }
+
+    /// Port 70h picks a register, port 71h reads or writes it.
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        if (addr & 1) == 0 {
+            return 0xff;
+        }
+        let index = self.index as usize;
+        match index {
+            REGISTER_A => {
+                let updating = (self.ram[REGISTER_B] & B_SET) == 0
+                    && self.divider >= RTC_CLOCK_HZ - UPDATE_WARNING_TICKS;
+                self.ram[REGISTER_A] | if updating { A_UPDATE_IN_PROGRESS } else { 0 }
+            }
+            REGISTER_C => std::mem::take(&mut self.ram[REGISTER_C]),
+            _ => self.ram[index],
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        if (addr & 1) == 0 {
+            self.nmi_masked = (data & 0x80) != 0;
+            self.index = data & 0x3f;
+            return;
+        }
+        let index = self.index as usize;
+        match index {
+            REGISTER_A => {
+                let old = self.ram[REGISTER_A];
+                self.ram[REGISTER_A] = data & !A_UPDATE_IN_PROGRESS;
+                // Taking the divider out of reset starts the first update
+                // half a second later.
+                if (old & 0x70) != A_DIVIDER_32K && (data & 0x70) == A_DIVIDER_32K {
+                    self.divider = RTC_CLOCK_HZ / 2;
+                }
+            }
+            REGISTER_B => {
+                // Setting SET aborts any update and turns off its interrupt.
+                let data = if (data & B_SET) != 0 {
+                    data & !B_UPDATE_ENDED
+                } else {
+                    data
+                };
+                self.ram[REGISTER_B] = data;
+            }
+            REGISTER_C | REGISTER_D => {}
+            _ => self.ram[index] = data,
+        }
+        if index >= REGISTER_A && index != REGISTER_C && index != REGISTER_D {
+            self.save_nvram();
+        }
+    }
+
+    /// Keeps the RAM in `path` from now on, starting with what's already
+    /// there if the file exists. The clock still starts at the host's time.
+    pub fn attach_nvram(&mut self, path: &str) -> Result<(), String> {
+        self.nvram_path = Some(PathBuf::from(path));
+        match fs::read(path) {
+            Ok(data) => {
+                if data.len() != self.ram.len() {
+                    return Err(format!(
+                        "{}: CMOS file must be {} bytes, got {}",
+                        path,
+                        self.ram.len(),
+                        data.len()
+                    ));
+                }
+                debug!("Loaded CMOS from {}", path);
+                self.ram[REGISTER_A..=REGISTER_B].copy_from_slice(&data[REGISTER_A..=REGISTER_B]);
+                self.ram[REGISTER_D + 1..].copy_from_slice(&data[REGISTER_D + 1..]);
+                let century = self.ram[CENTURY];
+                self.set_host_time();
+                self.ram[CENTURY] = century;
+                Ok(())
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                self.save_nvram();
+                Ok(())
+            }
+            Err(err) => Err(format!("{}: {}", path, err)),
+        }
+    }
+
+    /// Records the memory fitted, as the setup program would: base memory
+    /// at 15h, and extended memory at 17h and again at 30h, where POST
+    /// leaves what it counted. A size that doesn't match what POST counts
+    /// gets a memory size error.
+    pub fn set_memory_sizes(&mut self, base_kb: u16, extended_kb: u16) {
+        self.ram[0x15..0x17].copy_from_slice(&base_kb.to_le_bytes());
+        self.ram[0x17..0x19].copy_from_slice(&extended_kb.to_le_bytes());
+        self.ram[0x30..0x32].copy_from_slice(&extended_kb.to_le_bytes());
+        self.update_checksum();
+        self.save_nvram();
+    }
+
+    /// Recomputes the checksum the IBM BIOS keeps over 10h-2Dh, after the
+    /// configuration bytes have been changed behind software's back.
+    pub fn update_checksum(&mut self) {
+        let checksum: u16 = self.ram[0x10..0x2e].iter().map(|&byte| byte as u16).sum();
+        self.ram[0x2e..0x30].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn save_nvram(&self) {
+        if let Some(path) = &self.nvram_path {
+            if let Err(err) = fs::write(path, &self.ram[..]) {
+                warn!("Couldn't save CMOS to {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+impl Default for RTC {
+    fn default() -> RTC {
+        RTC::new()
+    }
+}
+
+// Battery backed, and RESET only clears the interrupt enables and flags.
+impl Reset for RTC {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind != ResetKind::Warm {
+            self.ram[REGISTER_B] &= !(B_PERIODIC | B_ALARM | B_UPDATE_ENDED);
+            self.ram[REGISTER_C] = 0;
+        }
+    }
+}
+
+#[test]
+fn test_rtc_clock_and_interrupts() {
+    let mut rtc = RTC::new();
+    let read = |rtc: &mut RTC, index: u8| {
+        rtc.wb(0x70, index);
+        rtc.rb(0x71)
+    };
+    rtc.set_time(1999, 12, 31, 23, 59, 59);
+    assert_eq!(read(&mut rtc, 0x06), 6);
+    for (index, value) in [(0x0a, 0x20), (0x0b, 0x12)].iter() {
+        rtc.wb(0x70, *index);
+        rtc.wb(0x71, *value);
+    }
+    rtc.tick(RTC_CLOCK_HZ as usize - 8);
+    assert_eq!(read(&mut rtc, 0x0a) & 0x80, 0x80);
+    assert!(!rtc.irq());
+    rtc.tick(8);
+    let time: Vec<u8> = (0..10).map(|index| read(&mut rtc, index)).collect();
+    assert_eq!(time, [0x00, 0, 0x00, 0, 0x00, 0, 7, 0x01, 0x01, 0x00]);
+    assert_eq!(read(&mut rtc, 0x32), 0x19);
+    // The alarm registers are all zero, so midnight sets the alarm flag
+    // too, but only the update interrupt is enabled.
+    assert!(rtc.irq());
+    assert_eq!(read(&mut rtc, 0x0c), 0xb0);
+    assert!(!rtc.irq());
+
+    // Binary, 12 hour: 13:05 reads as 1 PM.
+    rtc.wb(0x70, 0x0b);
+    rtc.wb(0x71, 0x04);
+    rtc.set_time(2024, 2, 29, 13, 5, 0);
+    assert_eq!(read(&mut rtc, 0x04), 0x81);
+    assert_eq!(read(&mut rtc, 0x07), 29);
+
+    // 1024 Hz periodic interrupt, then an alarm at 1:05:01 PM.
+    for (index, value) in [(0x0a, 0x26), (0x0b, 0x64)].iter() {
+        rtc.wb(0x70, *index);
+        rtc.wb(0x71, *value);
+    }
+    rtc.tick(31);
+    assert!(!rtc.irq());
+    rtc.tick(1);
+    assert!(rtc.irq());
+    assert_eq!(read(&mut rtc, 0x0c), 0xc0);
+    for (index, value) in [(0x01, 1), (0x03, 0xff), (0x05, 0x81), (0x0b, 0x24)].iter() {
+        rtc.wb(0x70, *index);
+        rtc.wb(0x71, *value);
+    }
+    rtc.tick(RTC_CLOCK_HZ as usize);
+    assert_eq!(read(&mut rtc, 0x0c) & 0xa0, 0xa0);
+
+    // The RAM outlives the emulator in a file.
+    let path = std::env::temp_dir().join(format!("emupc-cmos-{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+    rtc.attach_nvram(path).unwrap();
+    rtc.wb(0x70, 0x10);
+    rtc.wb(0x71, 0x24);
+    let mut restored = RTC::new();
+    restored.attach_nvram(path).unwrap();
+    assert_eq!(restored.ram[0x10], 0x24);
+    assert_eq!(restored.ram[0x0b], 0x24);
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,326 @@
+use crate::hardware::machine::{IsaCard, Machine};
+use crate::hardware::reset::ResetKind;
+use crate::hardware::StopReason;
+use log::debug;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long one emulated video frame lasts on real hardware: 912 x 262
+/// hdots of the 14.318 MHz master clock.
+pub const FRAME_DURATION: Duration = Duration::from_nanos(16_688_154);
+
+/// How far behind the wall clock the machine can fall before the pacer
+/// gives up catching up. Less than this, and frames run back to back until
+/// it's caught up; more, after the host was suspended or a debugger held
+/// it, and the time is written off.
+pub const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// The slowest and fastest the machine runs, as multiples of real time.
+pub const SPEED_RANGE: (f64, f64) = (0.1, 10.0);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Step {
+    Frame,
+    Instructions(usize),
+}
+
+/// Pausing, stepping, slow and fast motion, driven by the debugger or the
+/// frontend. Call `run` once per host frame and sleep for
+/// `frame_duration` in between. Cards can be fitted and taken out while
+/// paused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RunControl {
+    paused: bool,
+    speed: f64,
+    /// Frames back to back, with no sleeping in between.
+    unthrottled: bool,
+    step: Option<Step>,
+    /// A card change the guest won't see until the machine is reset.
+    reset_required: bool,
+}
+
+impl Default for RunControl {
+    fn default() -> RunControl {
+        RunControl::new()
+    }
+}
+
+impl RunControl {
+    pub fn new() -> RunControl {
+        RunControl {
+            paused: false,
+            speed: 1.0,
+            unthrottled: false,
+            step: None,
+            reset_required: false,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.step = None;
+    }
+
+    /// Runs one more video frame, then pauses.
+    pub fn step_frame(&mut self) {
+        self.step = Some(Step::Frame);
+    }
+
+    /// Runs `count` more instructions, then pauses.
+    pub fn step_instructions(&mut self, count: usize) {
+        self.step = Some(Step::Instructions(count));
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Sets the speed as a multiple of real time, e.g. 0.1 for 10% or 2
+    /// for twice as fast.
+    pub fn set_speed(&mut self, speed: f64) -> Result<(), String> {
+        let (min, max) = SPEED_RANGE;
+        if !(min..=max).contains(&speed) {
+            return Err(format!(
+                "speed must be between {} and {}, got {}",
+                min, max, speed
+            ));
+        }
+        self.speed = speed;
+        Ok(())
+    }
+
+    pub fn unthrottled(&self) -> bool {
+        self.unthrottled
+    }
+
+    /// Runs as fast as the host can, for getting through a boot or a slow
+    /// install. The speed applies again once it's turned off.
+    pub fn set_unthrottled(&mut self, unthrottled: bool) {
+        self.unthrottled = unthrottled;
+    }
+
+    /// Wall clock time one emulated frame should take at the current speed.
+    pub fn frame_duration(&self) -> Duration {
+        match self.unthrottled {
+            true => Duration::ZERO,
+            false => FRAME_DURATION.div_f64(self.speed),
+        }
+    }
+
+    /// Fits `card`, which the machine has to be paused for.
+    pub fn add_card(&mut self, machine: &mut Machine, card: IsaCard) -> Result<(), String> {
+        self.check_paused()?;
+        machine.add_card(card)?;
+        self.reset_required |= card.needs_reset();
+        Ok(())
+    }
+
+    /// Takes `card` out, which the machine has to be paused for.
+    pub fn remove_card(&mut self, machine: &mut Machine, card: IsaCard) -> Result<(), String> {
+        self.check_paused()?;
+        machine.remove_card(card)?;
+        self.reset_required |= card.needs_reset();
+        Ok(())
+    }
+
+    fn check_paused(&self) -> Result<(), String> {
+        match self.paused {
+            true => Ok(()),
+            false => Err("pause the machine before changing cards".to_string()),
+        }
+    }
+
+    /// Whether a card change is waiting on a reset. The machine won't run
+    /// until it gets one.
+    pub fn reset_required(&self) -> bool {
+        self.reset_required
+    }
+
+    /// Resets the machine, which lets it run again after a card change.
+    pub fn reset(&mut self, machine: &mut Machine, kind: ResetKind) {
+        machine.reset(kind);
+        self.reset_required = false;
+    }
+
+    /// Advances the machine by whatever is due: a pending step, or a frame
+    /// when running. Returns `None` when paused with nothing to do, or
+    /// while a reset is required. Breakpoints and watchpoints pause
+    /// execution.
+    pub fn run(&mut self, machine: &mut Machine) -> Option<StopReason> {
+        if self.reset_required {
+            return None;
+        }
+        let reason = match self.step.take() {
+            Some(Step::Frame) => {
+                self.paused = true;
+                machine.run_frame()
+            }
+            Some(Step::Instructions(count)) => {
+                self.paused = true;
+                machine.run_instructions(count)
+            }
+            None if self.paused => return None,
+            None => machine.run_frame(),
+        };
+        if reason.is_breakpoint() {
+            self.paused = true;
+        }
+        Some(reason)
+    }
+}
+
+/// Keeps a frontend's frames in step with the wall clock. Each frame is
+/// due `frame_duration` after the last one was due, not after it finished,
+/// so time spent running it doesn't add up into drift, and the host thread
+/// sleeps rather than spins until then.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FramePacer {
+    next: Option<Instant>,
+    stalls: u64,
+}
+
+impl FramePacer {
+    pub fn new() -> FramePacer {
+        FramePacer::default()
+    }
+
+    /// Counts a frame run that should last `duration`, returning how long
+    /// from `now` until the next one is due.
+    pub fn advance(&mut self, now: Instant, duration: Duration) -> Duration {
+        let next = match self.next {
+            Some(next) if !duration.is_zero() => next + duration,
+            _ => now + duration,
+        };
+        if next + MAX_LAG < now {
+            debug!("Fell {:?} behind, resynchronising", now - next);
+            self.stalls += 1;
+            self.next = Some(now);
+            return Duration::ZERO;
+        }
+        self.next = Some(next);
+        next.saturating_duration_since(now)
+    }
+
+    /// Counts a frame and sleeps until the next is due.
+    pub fn wait(&mut self, duration: Duration) {
+        let wait = self.advance(Instant::now(), duration);
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// How many times the machine fell too far behind and was let off.
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+}
+
+#[test]
+fn test_run_control_steps_and_slow_motion() {
+    let mut machine = crate::hardware::templates::find_template("ibm5150")
+        .unwrap()
+        .build()
+        .unwrap();
+    if let Machine::Pc(pc) = &mut machine {
+        pc.cpu.regs.seg_regs[1] = 0;
+        pc.cpu.regs.ip = 0x100;
+        pc.hardware.memory.ram[0x100..0x103].copy_from_slice(&[0xf8, 0xf8, 0xf4]);
+    }
+    let mut control = RunControl::new();
+    control.pause();
+    assert_eq!(control.run(&mut machine), None);
+    control.step_instructions(2);
+    assert_eq!(
+        control.run(&mut machine),
+        Some(StopReason::InstructionsDone)
+    );
+    assert!(control.paused());
+    assert_eq!(control.run(&mut machine), None);
+    if let Machine::Pc(pc) = &machine {
+        assert_eq!(pc.cpu.regs.ip, 0x102);
+    }
+
+    control.set_speed(0.1).unwrap();
+    let expected = FRAME_DURATION * 10;
+    assert!((control.frame_duration().as_secs_f64() - expected.as_secs_f64()).abs() < 1e-6);
+    assert!(control.set_speed(0.0).is_err());
+    assert!(control.set_speed(0.05).is_err());
+    assert!(control.set_speed(0.099).is_err());
+    control.set_speed(10.0).unwrap();
+    assert!(control.set_speed(10.5).is_err());
+    control.set_unthrottled(true);
+    assert_eq!(control.frame_duration(), Duration::ZERO);
+    control.set_unthrottled(false);
+    assert_eq!(control.frame_duration(), FRAME_DURATION / 10);
+}
+
+#[test]
+fn test_cards_change_while_paused() {
+    let mut machine = crate::hardware::templates::find_template("ibm5170")
+        .unwrap()
+        .build()
+        .unwrap();
+    let mut control = RunControl::new();
+    assert!(control.add_card(&mut machine, IsaCard::Adlib).is_err());
+    control.pause();
+    control.add_card(&mut machine, IsaCard::Adlib).unwrap();
+    assert!(control.add_card(&mut machine, IsaCard::Adlib).is_err());
+    assert!(!control.reset_required());
+    if let Machine::At(at) = &mut machine {
+        assert!(at.hardware.io.device(0x388).is_some());
+    }
+    control.remove_card(&mut machine, IsaCard::Adlib).unwrap();
+    assert!(control.remove_card(&mut machine, IsaCard::Adlib).is_err());
+
+    // COM3 only shows up in the BIOS data area after POST.
+    control.add_card(&mut machine, IsaCard::Serial(2)).unwrap();
+    assert!(control.add_card(&mut machine, IsaCard::Serial(4)).is_err());
+    assert!(control.reset_required());
+    control.resume();
+    assert_eq!(control.run(&mut machine), None);
+    control.reset(&mut machine, ResetKind::Hard);
+    assert!(control.run(&mut machine).is_some());
+    if let Machine::At(at) = &mut machine {
+        assert!(at.hardware.common.serial[2].is_some());
+        assert!(at.hardware.io.device(0x388).is_none());
+    }
+}
+
+#[test]
+fn test_frame_pacer() {
+    let start = Instant::now();
+    let frame = Duration::from_millis(16);
+    let mut pacer = FramePacer::new();
+    assert_eq!(pacer.advance(start, frame), frame);
+    // Running the frame took 5 ms, which comes off the wait.
+    let now = start + frame + Duration::from_millis(5);
+    assert_eq!(pacer.advance(now, frame), Duration::from_millis(11));
+
+    // A little behind: no waiting until it's caught up.
+    let now = start + frame * 4;
+    assert_eq!(pacer.advance(now, frame), Duration::ZERO);
+    assert_eq!(pacer.advance(now, frame), Duration::ZERO);
+    assert_eq!(pacer.advance(now, frame), frame);
+    assert_eq!(pacer.stalls(), 0);
+
+    // A long stall is written off rather than caught up.
+    let now = now + Duration::from_secs(2);
+    assert_eq!(pacer.advance(now, frame), Duration::ZERO);
+    assert_eq!(pacer.stalls(), 1);
+    assert_eq!(pacer.advance(now, frame), frame);
+
+    // Unthrottled never waits, and picks up from now after.
+    assert_eq!(
+        pacer.advance(now + frame * 20, Duration::ZERO),
+        Duration::ZERO
+    );
+    assert_eq!(pacer.advance(now + frame * 20, frame), frame);
+}
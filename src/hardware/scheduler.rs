@@ -0,0 +1,282 @@
+// The boards' event scheduler. Devices that only have something to do at
+// a known point in the future, like a seek finishing or a character
+// shifting out of a UART, put a deadline here instead of being ticked
+// after every instruction; the board runs the CPU up to the earliest one
+// and hands out whatever has come due.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::Hash;
+
+/// What the boards put on their schedulers, in CPU cycles. Devices that
+/// only have something to do now and then get an event for it, rather
+/// than being ticked after every instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceEvent {
+    /// A floppy seek or recalibrate reaching its track.
+    FdcSeek,
+    /// The next thing one of the serial ports has to do: finishing a
+    /// character it's sending, or a character time, when a byte can come
+    /// in.
+    Serial,
+    /// Time for the NE2000 to look for frames from its backend.
+    NicPoll,
+    /// PIT channel 0's output changing, which is IRQ 0.
+    Pit,
+    /// Time for the next audio sample.
+    Sample,
+    /// The first of the video cards getting back to the top of its frame.
+    Video,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Entry<E> {
+    time: u64,
+    seq: u64,
+    generation: u32,
+    kind: E,
+}
+
+impl<E: Eq> Ord for Entry<E> {
+    fn cmp(&self, other: &Entry<E>) -> Ordering {
+        (self.time, self.seq).cmp(&(other.time, other.seq))
+    }
+}
+
+impl<E: Eq> PartialOrd for Entry<E> {
+    fn partial_cmp(&self, other: &Entry<E>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Timed device events, keyed by cycle count. Each kind of event has at
+/// most one pending deadline.
+///
+/// Rescheduling or cancelling doesn't touch the heap: it bumps the kind's
+/// generation, and entries from older generations are dropped when they
+/// reach the top. A device that gets reprogrammed constantly (a PIT
+/// channel, a UART at high baud rates) only costs a heap push each time.
+/// Stale entries are compacted away once they outnumber live ones.
+#[derive(Clone, Debug)]
+pub struct Scheduler<E: Copy + Eq + Hash> {
+    now: u64,
+    seq: u64,
+    heap: BinaryHeap<Reverse<Entry<E>>>,
+    generations: HashMap<E, u32>,
+    pending: HashMap<E, u64>,
+}
+
+impl<E: Copy + Eq + Hash> Default for Scheduler<E> {
+    fn default() -> Scheduler<E> {
+        Scheduler::new()
+    }
+}
+
+impl<E: Copy + Eq + Hash> Scheduler<E> {
+    pub fn new() -> Scheduler<E> {
+        Scheduler {
+            now: 0,
+            seq: 0,
+            heap: BinaryHeap::new(),
+            generations: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    fn bump_generation(&mut self, kind: E) -> u32 {
+        let generation = self.generations.entry(kind).or_insert(0);
+        *generation = generation.wrapping_add(1);
+        *generation
+    }
+
+    /// Schedules `kind` at an absolute cycle count, replacing any deadline
+    /// it already had.
+    pub fn schedule_at(&mut self, kind: E, time: u64) {
+        let generation = self.bump_generation(kind);
+        self.seq += 1;
+        self.heap.push(Reverse(Entry {
+            time,
+            seq: self.seq,
+            generation,
+            kind,
+        }));
+        self.pending.insert(kind, time);
+        if self.heap.len() > 64 && self.heap.len() > self.pending.len() * 2 {
+            self.compact();
+        }
+    }
+
+    pub fn schedule(&mut self, kind: E, delay: u64) -> u64 {
+        let time = self.now + delay;
+        self.schedule_at(kind, time);
+        time
+    }
+
+    pub fn cancel(&mut self, kind: E) {
+        if self.pending.remove(&kind).is_some() {
+            self.bump_generation(kind);
+        }
+    }
+
+    pub fn deadline(&self, kind: E) -> Option<u64> {
+        self.pending.get(&kind).copied()
+    }
+
+    fn is_live(&self, entry: &Entry<E>) -> bool {
+        self.pending.contains_key(&entry.kind)
+            && self.generations.get(&entry.kind) == Some(&entry.generation)
+    }
+
+    fn compact(&mut self) {
+        let heap = std::mem::take(&mut self.heap);
+        self.heap = heap
+            .into_iter()
+            .filter(|Reverse(entry)| self.is_live(entry))
+            .collect();
+    }
+
+    fn drop_stale(&mut self) {
+        while let Some(Reverse(entry)) = self.heap.peek() {
+            if self.is_live(entry) {
+                break;
+            }
+            self.heap.pop();
+        }
+    }
+
+    /// The earliest pending deadline. The CPU loop can run freely until
+    /// then instead of polling devices after every instruction.
+    pub fn next_deadline(&mut self) -> Option<u64> {
+        self.drop_stale();
+        self.heap.peek().map(|Reverse(entry)| entry.time)
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    /// Pops the next event that is due, along with the time it was due
+    /// at. Call this in a loop after `advance` to handle a whole batch.
+    pub fn pop_due(&mut self) -> Option<(E, u64)> {
+        self.drop_stale();
+        match self.heap.peek() {
+            Some(Reverse(entry)) if entry.time <= self.now => {
+                let Reverse(entry) = self.heap.pop().unwrap();
+                self.pending.remove(&entry.kind);
+                Some((entry.kind, entry.time))
+            }
+            _ => None,
+        }
+    }
+
+    /// Stretches or shrinks the time left on every pending event by
+    /// `to_hz / from_hz`, for when the clock the cycles are counted in
+    /// changes speed. An event a millisecond away stays a millisecond
+    /// away.
+    pub fn rescale(&mut self, from_hz: u32, to_hz: u32) {
+        if from_hz == to_hz || from_hz == 0 {
+            return;
+        }
+        let mut pending: Vec<(E, u64)> = self.pending.iter().map(|(&k, &t)| (k, t)).collect();
+        // Keep events that were due together in the order they were due.
+        pending.sort_by_key(|&(_, time)| time);
+        for (kind, time) in pending {
+            let left = time.saturating_sub(self.now);
+            let left = (left as u128 * to_hz as u128).div_ceil(from_hz as u128) as u64;
+            self.schedule_at(kind, self.now + left);
+        }
+    }
+
+    /// Number of heap entries, live or stale.
+    pub fn queued(&self) -> usize {
+        self.heap.len()
+    }
+}
+
+#[cfg(test)]
+use std::time::Instant;
+
+#[test]
+fn test_scheduler_reprogram_and_cancel() {
+    let mut scheduler = Scheduler::new();
+    scheduler.schedule("pit", 100);
+    scheduler.schedule("uart", 50);
+    scheduler.schedule("dma", 50);
+    for i in 0..10_000 {
+        scheduler.schedule("pit", 200 + i % 7);
+    }
+    assert!(scheduler.queued() < 100);
+    scheduler.cancel("dma");
+    assert_eq!(scheduler.next_deadline(), Some(50));
+
+    scheduler.advance(300);
+    let mut fired = vec![];
+    while let Some(event) = scheduler.pop_due() {
+        fired.push(event);
+    }
+    assert_eq!(fired, vec![("uart", 50), ("pit", 200 + 9_999 % 7)]);
+    assert_eq!(scheduler.next_deadline(), None);
+}
+
+#[test]
+fn test_scheduler_rescale() {
+    let mut scheduler = Scheduler::new();
+    scheduler.advance(1_000);
+    scheduler.schedule("fdc", 4_000);
+    scheduler.schedule("uart", 3);
+    // Doubling the clock doubles the cycles left, not the deadline.
+    scheduler.rescale(4_000_000, 8_000_000);
+    assert_eq!(scheduler.deadline("fdc"), Some(9_000));
+    assert_eq!(scheduler.deadline("uart"), Some(1_006));
+    scheduler.rescale(8_000_000, 6_000_000);
+    assert_eq!(scheduler.deadline("fdc"), Some(7_000));
+    assert_eq!(scheduler.deadline("uart"), Some(1_005));
+}
+
+// Run with `cargo test --release -- --ignored --nocapture` to see the
+// timing. Four UARTs at a character time apiece, a PIT channel that gets
+// reprogrammed every step and DMA channels that keep being started and
+// stopped, as a game setting up sound would.
+#[test]
+#[ignore]
+fn test_scheduler_under_load() {
+    const STEPS: u64 = 2_000_000;
+    let uarts = ["com1", "com2", "com3", "com4"];
+    let dma = ["dma1", "dma2", "dma3", "dma5"];
+    let mut scheduler = Scheduler::new();
+    let mut fired = 0u64;
+    let start = Instant::now();
+    for step in 0..STEPS {
+        for (i, &uart) in uarts.iter().enumerate() {
+            if scheduler.deadline(uart).is_none() {
+                scheduler.schedule(uart, 40 + i as u64);
+            }
+        }
+        scheduler.schedule("pit0", 3 + step % 11);
+        let channel = dma[step as usize % dma.len()];
+        if step % 3 == 0 {
+            scheduler.cancel(channel);
+        } else {
+            scheduler.schedule(channel, 25 + step % 5);
+        }
+        scheduler.advance(4);
+        while scheduler.pop_due().is_some() {
+            fired += 1;
+        }
+        // Stale entries never pile up past the compaction threshold.
+        assert!(scheduler.queued() <= 64);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{} steps, {} events fired, {:.1} ns a step",
+        STEPS,
+        fired,
+        elapsed.as_nanos() as f64 / STEPS as f64
+    );
+    // Every UART character comes due, whatever happens to the others.
+    assert!(fired >= STEPS * 4 * uarts.len() as u64 / 44);
+}
@@ -0,0 +1,176 @@
+// A serial mouse, plugged into a COM port in place of a host connection.
+// It runs off the port's RTS line like the real thing: a Microsoft mouse
+// answers RTS coming up with an "M", which is how drivers find it. After
+// that each movement or change of buttons goes out as a packet, one byte
+// per character time of the port, with the motion in between added up.
+use crate::hardware::passthrough::SerialBackend;
+use crate::input::InputEvent;
+use std::collections::VecDeque;
+
+/// What a Microsoft mouse sends when it powers up.
+const MICROSOFT_ID: u8 = b'M';
+
+/// Buttons, as numbered in `InputEvent::MouseButton`.
+const LEFT: u8 = 0x01;
+const RIGHT: u8 = 0x02;
+const MIDDLE: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MouseProtocol {
+    /// Two buttons, three byte packets at 1200 baud 7N1.
+    Microsoft,
+    /// Three buttons, five byte packets at 1200 baud 8N1.
+    MouseSystems,
+}
+
+#[derive(Debug, Clone)]
+pub struct SerialMouse {
+    pub protocol: MouseProtocol,
+    rts: bool,
+    queue: VecDeque<u8>,
+    /// Motion not yet sent, right and down being positive.
+    dx: i32,
+    dy: i32,
+    buttons: u8,
+    sent_buttons: u8,
+}
+
+impl SerialMouse {
+    pub fn new(protocol: MouseProtocol) -> SerialMouse {
+        SerialMouse {
+            protocol,
+            rts: false,
+            queue: VecDeque::new(),
+            dx: 0,
+            dy: 0,
+            buttons: 0,
+            sent_buttons: 0,
+        }
+    }
+
+    /// Takes up to a packet's worth of the motion still to send.
+    fn take_motion(&mut self) -> (i8, i8) {
+        let dx = self.dx.clamp(-128, 127);
+        let dy = self.dy.clamp(-128, 127);
+        self.dx -= dx;
+        self.dy -= dy;
+        (dx as i8, dy as i8)
+    }
+
+    fn queue_packet(&mut self) {
+        self.sent_buttons = self.buttons;
+        match self.protocol {
+            MouseProtocol::Microsoft => {
+                let (dx, dy) = self.take_motion();
+                let (dx, dy) = (dx as u8, dy as u8);
+                let left = ((self.buttons & LEFT) != 0) as u8;
+                let right = ((self.buttons & RIGHT) != 0) as u8;
+                self.queue.extend([
+                    0x40 | left << 5 | right << 4 | (dy >> 6) << 2 | dx >> 6,
+                    dx & 0x3f,
+                    dy & 0x3f,
+                ]);
+            }
+            MouseProtocol::MouseSystems => {
+                // The buttons are active low, and up is positive. The second
+                // pair of deltas is motion since the first, which there
+                // isn't any of between the two here.
+                let (dx, dy) = self.take_motion();
+                let buttons = (((self.buttons & LEFT) != 0) as u8) << 2
+                    | (((self.buttons & MIDDLE) != 0) as u8) << 1
+                    | ((self.buttons & RIGHT) != 0) as u8;
+                self.queue.extend([
+                    0x80 | (!buttons & 0x07),
+                    dx as u8,
+                    (dy as i16).wrapping_neg().clamp(-128, 127) as u8,
+                    0,
+                    0,
+                ]);
+            }
+        }
+    }
+}
+
+impl SerialBackend for SerialMouse {
+    fn write_byte(&mut self, _value: u8) {}
+
+    fn read_byte(&mut self) -> Option<u8> {
+        if !self.rts {
+            return None;
+        }
+        if self.queue.is_empty()
+            && (self.dx != 0 || self.dy != 0 || self.buttons != self.sent_buttons)
+        {
+            self.queue_packet();
+        }
+        self.queue.pop_front()
+    }
+
+    fn set_modem_control(&mut self, _dtr: bool, rts: bool) {
+        if rts && !self.rts {
+            self.queue.clear();
+            self.dx = 0;
+            self.dy = 0;
+            self.sent_buttons = self.buttons;
+            if self.protocol == MouseProtocol::Microsoft {
+                self.queue.push_back(MICROSOFT_ID);
+            }
+        }
+        self.rts = rts;
+    }
+
+    fn input(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::MouseMove { dx, dy } => {
+                self.dx += dx;
+                self.dy += dy;
+            }
+            InputEvent::MouseButton { button, pressed } => {
+                let bit = 1 << button;
+                match pressed {
+                    true => self.buttons |= bit,
+                    false => self.buttons &= !bit,
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_serial_mouse_packets() {
+    let mut mouse = SerialMouse::new(MouseProtocol::Microsoft);
+    let drain =
+        |mouse: &mut SerialMouse| std::iter::from_fn(|| mouse.read_byte()).collect::<Vec<u8>>();
+
+    // Nothing until RTS powers it, then the ID.
+    mouse.input(InputEvent::MouseMove { dx: 5, dy: 5 });
+    assert!(drain(&mut mouse).is_empty());
+    mouse.set_modem_control(true, true);
+    assert_eq!(drain(&mut mouse), vec![MICROSOFT_ID]);
+
+    // Left button down and a move left and down, split over two packets
+    // when it's too far for one.
+    mouse.input(InputEvent::MouseButton {
+        button: 0,
+        pressed: true,
+    });
+    mouse.input(InputEvent::MouseMove { dx: -200, dy: 3 });
+    assert_eq!(drain(&mut mouse), vec![0x62, 0x00, 0x03, 0x62, 0x38, 0x00]);
+
+    // Dropping RTS and raising it again resets it.
+    mouse.set_modem_control(true, false);
+    mouse.input(InputEvent::MouseMove { dx: 1, dy: 0 });
+    mouse.set_modem_control(true, true);
+    assert_eq!(drain(&mut mouse), vec![MICROSOFT_ID]);
+
+    let mut mouse = SerialMouse::new(MouseProtocol::MouseSystems);
+    mouse.set_modem_control(true, true);
+    assert!(drain(&mut mouse).is_empty());
+    mouse.input(InputEvent::MouseButton {
+        button: 1,
+        pressed: true,
+    });
+    mouse.input(InputEvent::MouseMove { dx: 2, dy: 4 });
+    assert_eq!(drain(&mut mouse), vec![0x86, 2, 0xfc, 0, 0]);
+}
@@ -0,0 +1,718 @@
+// A user-mode network in the style of SLIRP: the guest sees a small
+// Ethernet with a router, and its connections are made again from the
+// host's own sockets, so nothing needs setting up or any privileges on the
+// host. The guest gets its address by DHCP, as packet driver stacks like
+// mTCP expect to:
+//
+//   10.0.2.2   the router; connections to it go to the host's localhost
+//   10.0.2.3   the DNS server, forwarding to the host's resolver
+//   10.0.2.15  the guest
+//
+// UDP goes through a host socket per guest port. TCP connections are
+// outgoing only, and their segments are made up here, with the host
+// socket supplying the data; the virtual wire doesn't lose frames, but the
+// guest's receive ring can, so unacknowledged data is sent again.
+use crate::hardware::passthrough::NetworkBackend;
+use log::{debug, trace, warn};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const ROUTER_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+const ROUTER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+const DNS_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+const GUEST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const PROTOCOL_ICMP: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// The largest segment sent to the guest, unless it asks for less.
+const MSS: usize = 1460;
+/// What the guest is told it can send before the host socket takes it.
+const WINDOW: u16 = 8192;
+const RETRANSMIT: Duration = Duration::from_millis(500);
+
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+
+/// The Internet checksum, over `data` after `initial` has been summed in.
+fn checksum(initial: u32, data: &[u8]) -> u16 {
+    let mut sum = initial;
+    for chunk in data.chunks(2) {
+        let word = match *chunk {
+            [high, low] => u16::from_be_bytes([high, low]),
+            [high] => u16::from_be_bytes([high, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The sum of the pseudo-header that TCP and UDP checksums cover.
+fn pseudo_header(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, length: usize) -> u32 {
+    let words = |ip: Ipv4Addr| {
+        let [a, b, c, d] = ip.octets();
+        u16::from_be_bytes([a, b]) as u32 + u16::from_be_bytes([c, d]) as u32
+    };
+    words(source) + words(destination) + protocol as u32 + length as u32
+}
+
+fn be16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn be32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn ip_at(data: &[u8], at: usize) -> Ipv4Addr {
+    Ipv4Addr::new(data[at], data[at + 1], data[at + 2], data[at + 3])
+}
+
+/// Where a guest's connection to a virtual address really goes.
+fn host_address(ip: Ipv4Addr) -> Ipv4Addr {
+    match ip {
+        ROUTER_IP => Ipv4Addr::LOCALHOST,
+        _ => ip,
+    }
+}
+
+/// The host's DNS server, from resolv.conf where there is one.
+fn host_dns_server() -> SocketAddr {
+    let configured = std::fs::read_to_string("/etc/resolv.conf")
+        .ok()
+        .and_then(|conf| {
+            conf.lines()
+                .filter_map(|line| line.strip_prefix("nameserver"))
+                .find_map(|address| address.trim().parse::<Ipv4Addr>().ok())
+        });
+    let ip = configured.unwrap_or(Ipv4Addr::new(8, 8, 8, 8));
+    SocketAddr::V4(SocketAddrV4::new(ip, 53))
+}
+
+/// Which end of a connection is which: the guest's port, and the address
+/// and port it connected to.
+type ConnectionKey = (u16, SocketAddrV4);
+
+enum TcpState {
+    /// The host is still connecting; the guest gets its SYN-ACK once it
+    /// has.
+    Connecting(Receiver<io::Result<TcpStream>>),
+    Established(TcpStream),
+}
+
+struct TcpConnection {
+    state: TcpState,
+    /// The next sequence number from the guest, which is our ACK.
+    guest_next: u32,
+    /// Our oldest unacknowledged sequence number, and the next one.
+    unacked_seq: u32,
+    next_seq: u32,
+    /// Data sent but not acknowledged, from `unacked_seq`.
+    unacked: VecDeque<u8>,
+    guest_window: usize,
+    guest_mss: usize,
+    /// Data from the guest the host socket hasn't taken yet.
+    to_host: Vec<u8>,
+    guest_fin: bool,
+    host_eof: bool,
+    fin_sent: bool,
+    last_sent: Instant,
+}
+
+pub struct Slirp {
+    guest_mac: [u8; 6],
+    /// Frames waiting to go to the guest.
+    outgoing: VecDeque<Vec<u8>>,
+    /// A socket for each guest UDP port.
+    udp: HashMap<u16, UdpSocket>,
+    tcp: HashMap<ConnectionKey, TcpConnection>,
+    dns_server: SocketAddr,
+    ip_id: u16,
+}
+
+impl Slirp {
+    pub fn new() -> Slirp {
+        Slirp {
+            guest_mac: BROADCAST_MAC,
+            outgoing: VecDeque::new(),
+            udp: HashMap::new(),
+            tcp: HashMap::new(),
+            dns_server: host_dns_server(),
+            ip_id: 0,
+        }
+    }
+
+    fn ethernet(&self, destination: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(14 + payload.len());
+        frame.extend_from_slice(&destination);
+        frame.extend_from_slice(&ROUTER_MAC);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn send_ip(&mut self, source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) {
+        self.ip_id = self.ip_id.wrapping_add(1);
+        let length = (20 + payload.len()) as u16;
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&length.to_be_bytes());
+        packet.extend_from_slice(&self.ip_id.to_be_bytes());
+        packet.extend_from_slice(&[0x40, 0, 64, protocol, 0, 0]);
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&destination.octets());
+        let sum = checksum(0, &packet);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(payload);
+        let mac = match destination {
+            Ipv4Addr::BROADCAST => BROADCAST_MAC,
+            _ => self.guest_mac,
+        };
+        let frame = self.ethernet(mac, ETHERTYPE_IPV4, &packet);
+        self.outgoing.push_back(frame);
+    }
+
+    fn send_udp(&mut self, from: SocketAddrV4, to: SocketAddrV4, data: &[u8]) {
+        let length = 8 + data.len();
+        let mut datagram = Vec::with_capacity(length);
+        datagram.extend_from_slice(&from.port().to_be_bytes());
+        datagram.extend_from_slice(&to.port().to_be_bytes());
+        datagram.extend_from_slice(&(length as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        let initial = pseudo_header(*from.ip(), *to.ip(), PROTOCOL_UDP, length);
+        let sum = match checksum(initial, &datagram) {
+            0 => 0xffff,
+            sum => sum,
+        };
+        datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+        self.send_ip(*from.ip(), *to.ip(), PROTOCOL_UDP, &datagram);
+    }
+
+    fn send_tcp(&mut self, key: ConnectionKey, flags: u8, seq: u32, data: &[u8]) {
+        let (guest_port, remote) = key;
+        let ack = self.tcp.get(&key).map_or(0, |conn| conn.guest_next);
+        let mut segment = Vec::with_capacity(24 + data.len());
+        segment.extend_from_slice(&remote.port().to_be_bytes());
+        segment.extend_from_slice(&guest_port.to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&ack.to_be_bytes());
+        // A SYN carries the MSS option, which makes the header longer.
+        let header_words: u8 = if (flags & TCP_SYN) != 0 { 6 } else { 5 };
+        segment.extend_from_slice(&[header_words << 4, flags]);
+        segment.extend_from_slice(&WINDOW.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        if (flags & TCP_SYN) != 0 {
+            segment.extend_from_slice(&[2, 4]);
+            segment.extend_from_slice(&(MSS as u16).to_be_bytes());
+        }
+        segment.extend_from_slice(data);
+        let initial = pseudo_header(*remote.ip(), GUEST_IP, PROTOCOL_TCP, segment.len());
+        let sum = checksum(initial, &segment);
+        segment[16..18].copy_from_slice(&sum.to_be_bytes());
+        self.send_ip(*remote.ip(), GUEST_IP, PROTOCOL_TCP, &segment);
+    }
+
+    fn handle_arp(&mut self, packet: &[u8]) {
+        if packet.len() < 28 || be16(packet, 6) != 1 {
+            return;
+        }
+        let sender_mac: [u8; 6] = packet[8..14].try_into().unwrap();
+        let target = ip_at(packet, 24);
+        if target == GUEST_IP || (u32::from(target) & 0xffff_ff00) != 0x0a00_0200 {
+            return;
+        }
+        let mut reply = vec![0, 1, 0x08, 0x00, 6, 4, 0, 2];
+        reply.extend_from_slice(&ROUTER_MAC);
+        reply.extend_from_slice(&target.octets());
+        reply.extend_from_slice(&sender_mac);
+        reply.extend_from_slice(&packet[14..18]);
+        let frame = self.ethernet(sender_mac, ETHERTYPE_ARP, &reply);
+        self.outgoing.push_back(frame);
+    }
+
+    fn handle_ip(&mut self, packet: &[u8]) {
+        if packet.len() < 20 || (packet[0] >> 4) != 4 {
+            return;
+        }
+        let header_length = ((packet[0] & 0x0f) as usize) * 4;
+        let total = (be16(packet, 2) as usize).min(packet.len());
+        if total < header_length {
+            return;
+        }
+        let source = ip_at(packet, 12);
+        let destination = ip_at(packet, 16);
+        let payload = &packet[header_length..total];
+        match packet[9] {
+            PROTOCOL_ICMP => self.handle_icmp(source, destination, payload),
+            PROTOCOL_UDP => self.handle_udp(source, destination, payload),
+            PROTOCOL_TCP => self.handle_tcp(destination, payload),
+            protocol => debug!("SLIRP dropped IP protocol {}", protocol),
+        }
+    }
+
+    /// Pings to the router and DNS server are answered here; the rest of
+    /// the world can't be pinged without privileges on the host.
+    fn handle_icmp(&mut self, source: Ipv4Addr, destination: Ipv4Addr, message: &[u8]) {
+        if message.len() < 8
+            || message[0] != 8
+            || !(destination == ROUTER_IP || destination == DNS_IP)
+        {
+            return;
+        }
+        let mut reply = message.to_vec();
+        reply[0] = 0;
+        reply[2..4].copy_from_slice(&[0, 0]);
+        let sum = checksum(0, &reply);
+        reply[2..4].copy_from_slice(&sum.to_be_bytes());
+        self.send_ip(destination, source, PROTOCOL_ICMP, &reply);
+    }
+
+    fn handle_udp(&mut self, source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) {
+        if datagram.len() < 8 {
+            return;
+        }
+        let source_port = be16(datagram, 0);
+        let destination_port = be16(datagram, 2);
+        let length = (be16(datagram, 4) as usize).clamp(8, datagram.len());
+        let data = &datagram[8..length];
+        if destination_port == 67 {
+            self.handle_dhcp(data);
+            return;
+        }
+        let target = match (destination, destination_port) {
+            (DNS_IP, 53) => self.dns_server,
+            _ => SocketAddr::V4(SocketAddrV4::new(
+                host_address(destination),
+                destination_port,
+            )),
+        };
+        let socket = match self.udp.entry(source_port) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            }) {
+                Ok(socket) => entry.insert(socket),
+                Err(err) => {
+                    warn!("SLIRP couldn't open a UDP socket: {}", err);
+                    return;
+                }
+            },
+        };
+        trace!("SLIRP UDP {}:{} -> {}", source, source_port, target);
+        if let Err(err) = socket.send_to(data, target) {
+            debug!("SLIRP UDP to {}: {}", target, err);
+        }
+    }
+
+    /// Hands out the guest's address, with the router and DNS server.
+    fn handle_dhcp(&mut self, request: &[u8]) {
+        if request.len() < 240 || request[0] != 1 || request[236..240] != DHCP_MAGIC {
+            return;
+        }
+        let mut message_type = None;
+        let mut options = &request[240..];
+        while let [code, rest @ ..] = options {
+            match (*code, rest) {
+                (0, _) => options = rest,
+                (255, _) => break,
+                (code, [length, rest @ ..]) if rest.len() >= *length as usize => {
+                    if code == 53 && *length >= 1 {
+                        message_type = Some(rest[0]);
+                    }
+                    options = &rest[*length as usize..];
+                }
+                _ => break,
+            }
+        }
+        let reply_type = match message_type {
+            Some(DHCP_DISCOVER) => DHCP_OFFER,
+            Some(DHCP_REQUEST) => DHCP_ACK,
+            _ => return,
+        };
+        let mut reply = vec![0; 236];
+        reply[0] = 2;
+        reply[1..3].copy_from_slice(&request[1..3]);
+        reply[4..8].copy_from_slice(&request[4..8]);
+        reply[10..12].copy_from_slice(&request[10..12]);
+        reply[16..20].copy_from_slice(&GUEST_IP.octets());
+        reply[20..24].copy_from_slice(&ROUTER_IP.octets());
+        reply[28..44].copy_from_slice(&request[28..44]);
+        reply.extend_from_slice(&DHCP_MAGIC);
+        reply.extend_from_slice(&[53, 1, reply_type]);
+        reply.extend_from_slice(&[54, 4]);
+        reply.extend_from_slice(&ROUTER_IP.octets());
+        reply.extend_from_slice(&[51, 4]);
+        reply.extend_from_slice(&86_400u32.to_be_bytes());
+        reply.extend_from_slice(&[1, 4]);
+        reply.extend_from_slice(&NETMASK.octets());
+        reply.extend_from_slice(&[3, 4]);
+        reply.extend_from_slice(&ROUTER_IP.octets());
+        reply.extend_from_slice(&[6, 4]);
+        reply.extend_from_slice(&DNS_IP.octets());
+        reply.push(255);
+        let server = SocketAddrV4::new(ROUTER_IP, 67);
+        let client = SocketAddrV4::new(Ipv4Addr::BROADCAST, 68);
+        self.send_udp(server, client, &reply);
+    }
+
+    fn handle_tcp(&mut self, destination: Ipv4Addr, segment: &[u8]) {
+        if segment.len() < 20 {
+            return;
+        }
+        let guest_port = be16(segment, 0);
+        let remote = SocketAddrV4::new(destination, be16(segment, 2));
+        let key = (guest_port, remote);
+        let seq = be32(segment, 4);
+        let ack = be32(segment, 8);
+        let header_length = ((segment[12] >> 4) as usize * 4).clamp(20, segment.len());
+        let flags = segment[13];
+        let window = be16(segment, 14) as usize;
+        let data = &segment[header_length..];
+
+        if (flags & TCP_RST) != 0 {
+            self.tcp.remove(&key);
+            return;
+        }
+        if (flags & TCP_SYN) != 0 {
+            if !self.tcp.contains_key(&key) {
+                self.open_tcp(key, seq, window, &segment[20..header_length]);
+            }
+            return;
+        }
+        let conn = match self.tcp.get_mut(&key) {
+            Some(conn) => conn,
+            None => {
+                // Nothing to reset here; the guest's stack times out.
+                return;
+            }
+        };
+        if (flags & TCP_ACK) != 0 {
+            let acked = ack.wrapping_sub(conn.unacked_seq);
+            if acked <= conn.next_seq.wrapping_sub(conn.unacked_seq) {
+                let data_acked = (acked as usize).min(conn.unacked.len());
+                conn.unacked.drain(..data_acked);
+                conn.unacked_seq = ack;
+                conn.guest_window = window;
+            }
+        }
+        let mut reply = false;
+        if !data.is_empty() || (flags & TCP_FIN) != 0 {
+            reply = true;
+            if seq == conn.guest_next && !conn.guest_fin {
+                conn.to_host.extend_from_slice(data);
+                conn.guest_next = conn.guest_next.wrapping_add(data.len() as u32);
+                if (flags & TCP_FIN) != 0 {
+                    conn.guest_fin = true;
+                    conn.guest_next = conn.guest_next.wrapping_add(1);
+                }
+            }
+        }
+        if reply {
+            let seq = conn.next_seq;
+            self.send_tcp(key, TCP_ACK, seq, &[]);
+        }
+        self.service_tcp(key);
+    }
+
+    fn open_tcp(&mut self, key: ConnectionKey, seq: u32, window: usize, options: &[u8]) {
+        let mut guest_mss = 536;
+        let mut options = options;
+        while let [kind, rest @ ..] = options {
+            match (*kind, rest) {
+                (0, _) => break,
+                (1, _) => options = rest,
+                (2, [4, high, low, ..]) => {
+                    guest_mss = u16::from_be_bytes([*high, *low]) as usize;
+                    options = &rest[3..];
+                }
+                (_, [length, ..]) if *length >= 2 && rest.len() + 1 >= *length as usize => {
+                    options = &rest[*length as usize - 1..];
+                }
+                _ => break,
+            }
+        }
+        let (_, remote) = key;
+        let address = SocketAddr::V4(SocketAddrV4::new(host_address(*remote.ip()), remote.port()));
+        debug!("SLIRP connecting to {}", address);
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(TcpStream::connect_timeout(
+                &address,
+                Duration::from_secs(10),
+            ));
+        });
+        // Something that changes from one connection to the next.
+        let clock = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let iss = clock.subsec_nanos() ^ ((key.0 as u32) << 16);
+        self.tcp.insert(
+            key,
+            TcpConnection {
+                state: TcpState::Connecting(receiver),
+                guest_next: seq.wrapping_add(1),
+                unacked_seq: iss,
+                next_seq: iss,
+                unacked: VecDeque::new(),
+                guest_window: window,
+                guest_mss: guest_mss.clamp(64, MSS),
+                to_host: vec![],
+                guest_fin: false,
+                host_eof: false,
+                fin_sent: false,
+                last_sent: Instant::now(),
+            },
+        );
+    }
+
+    /// Moves a connection along: finishes connecting, passes data each
+    /// way, sends what the guest hasn't acknowledged again, and closes it
+    /// once both ends are done.
+    fn service_tcp(&mut self, key: ConnectionKey) {
+        let mut segments: Vec<(u8, u32, Vec<u8>)> = vec![];
+        let mut close = false;
+        let conn = match self.tcp.get_mut(&key) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if let TcpState::Connecting(receiver) = &conn.state {
+            match receiver.try_recv() {
+                Ok(Ok(stream)) => {
+                    let _ = stream.set_nonblocking(true);
+                    let _ = stream.set_nodelay(true);
+                    conn.state = TcpState::Established(stream);
+                    segments.push((TCP_SYN | TCP_ACK, conn.next_seq, vec![]));
+                    conn.next_seq = conn.next_seq.wrapping_add(1);
+                    conn.unacked_seq = conn.next_seq;
+                    conn.last_sent = Instant::now();
+                }
+                Ok(Err(_)) | Err(TryRecvError::Disconnected) => {
+                    segments.push((TCP_RST | TCP_ACK, conn.next_seq, vec![]));
+                    close = true;
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+        } else if let TcpState::Established(stream) = &mut conn.state {
+            if !conn.to_host.is_empty() {
+                match stream.write(&conn.to_host) {
+                    Ok(written) => {
+                        conn.to_host.drain(..written);
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(_) => close = true,
+                }
+            }
+            if conn.guest_fin && conn.to_host.is_empty() {
+                let _ = stream.shutdown(Shutdown::Write);
+            }
+            let window = conn.guest_window.min(WINDOW as usize * 4);
+            // Always one segment in flight, so a closed window gets probed.
+            while !conn.host_eof
+                && (conn.unacked.is_empty() || conn.unacked.len() + conn.guest_mss <= window)
+            {
+                let mut buf = vec![0; conn.guest_mss];
+                match stream.read(&mut buf) {
+                    Ok(0) => conn.host_eof = true,
+                    Ok(count) => {
+                        buf.truncate(count);
+                        segments.push((TCP_ACK | TCP_PSH, conn.next_seq, buf.clone()));
+                        conn.unacked.extend(buf);
+                        conn.next_seq = conn.next_seq.wrapping_add(count as u32);
+                        conn.last_sent = Instant::now();
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        close = true;
+                        break;
+                    }
+                }
+            }
+            if conn.host_eof && !conn.fin_sent {
+                segments.push((TCP_FIN | TCP_ACK, conn.next_seq, vec![]));
+                conn.next_seq = conn.next_seq.wrapping_add(1);
+                conn.fin_sent = true;
+                conn.last_sent = Instant::now();
+            }
+            let in_flight = conn.next_seq.wrapping_sub(conn.unacked_seq);
+            if in_flight > 0 && conn.last_sent.elapsed() >= RETRANSMIT {
+                let length = conn.unacked.len().min(conn.guest_mss);
+                let data: Vec<u8> = conn.unacked.iter().take(length).copied().collect();
+                let flags = match data.is_empty() && conn.fin_sent {
+                    true => TCP_FIN | TCP_ACK,
+                    false => TCP_ACK | TCP_PSH,
+                };
+                segments.push((flags, conn.unacked_seq, data));
+                conn.last_sent = Instant::now();
+            }
+            if conn.guest_fin && conn.fin_sent && in_flight == 0 {
+                close = true;
+            }
+        }
+        for (flags, seq, data) in segments {
+            self.send_tcp(key, flags, seq, &data);
+        }
+        if close {
+            self.tcp.remove(&key);
+        }
+    }
+
+    /// Collects whatever the host sockets have for the guest.
+    fn poll_host(&mut self) {
+        let mut datagrams = vec![];
+        for (&port, socket) in self.udp.iter() {
+            let mut buf = [0u8; 2048];
+            while let Ok((length, from)) = socket.recv_from(&mut buf) {
+                datagrams.push((port, from, buf[..length].to_vec()));
+            }
+        }
+        for (port, from, data) in datagrams {
+            let from = match from {
+                SocketAddr::V4(from) if from == self.dns_server_v4() => {
+                    SocketAddrV4::new(DNS_IP, 53)
+                }
+                SocketAddr::V4(from) if from.ip().is_loopback() => {
+                    SocketAddrV4::new(ROUTER_IP, from.port())
+                }
+                SocketAddr::V4(from) => from,
+                SocketAddr::V6(_) => continue,
+            };
+            self.send_udp(from, SocketAddrV4::new(GUEST_IP, port), &data);
+        }
+        let keys: Vec<ConnectionKey> = self.tcp.keys().copied().collect();
+        for key in keys {
+            self.service_tcp(key);
+        }
+    }
+
+    fn dns_server_v4(&self) -> SocketAddrV4 {
+        match self.dns_server {
+            SocketAddr::V4(address) => address,
+            SocketAddr::V6(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        }
+    }
+}
+
+impl Default for Slirp {
+    fn default() -> Slirp {
+        Slirp::new()
+    }
+}
+
+impl NetworkBackend for Slirp {
+    fn send(&mut self, frame: &[u8]) {
+        if frame.len() < 14 {
+            return;
+        }
+        self.guest_mac.copy_from_slice(&frame[6..12]);
+        match be16(frame, 12) {
+            ETHERTYPE_ARP => self.handle_arp(&frame[14..]),
+            ETHERTYPE_IPV4 => self.handle_ip(&frame[14..]),
+            _ => {}
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        if self.outgoing.is_empty() {
+            self.poll_host();
+        }
+        self.outgoing.pop_front()
+    }
+}
+
+#[test]
+fn test_slirp_dhcp_arp_and_ping() {
+    let mut slirp = Slirp::new();
+    let guest_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    let frame = |ethertype: u16, payload: &[u8]| {
+        let mut frame = BROADCAST_MAC.to_vec();
+        frame.extend_from_slice(&guest_mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    };
+    let ip = |protocol: u8, source: Ipv4Addr, destination: Ipv4Addr, payload: &[u8]| {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0, 64, protocol, 0, 0]);
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&destination.octets());
+        packet.extend_from_slice(payload);
+        frame(ETHERTYPE_IPV4, &packet)
+    };
+
+    // DHCP discover, from nowhere to everywhere, gets an offer of
+    // 10.0.2.15.
+    let mut discover = vec![0; 240];
+    discover[0] = 1;
+    discover[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+    discover[28..34].copy_from_slice(&guest_mac);
+    discover[236..240].copy_from_slice(&DHCP_MAGIC);
+    discover.extend_from_slice(&[53, 1, DHCP_DISCOVER, 255]);
+    let mut udp = vec![0, 68, 0, 67];
+    udp.extend_from_slice(&((8 + discover.len()) as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(&discover);
+    slirp.send(&ip(
+        PROTOCOL_UDP,
+        Ipv4Addr::UNSPECIFIED,
+        Ipv4Addr::BROADCAST,
+        &udp,
+    ));
+    let offer = slirp.receive().unwrap();
+    assert_eq!(be16(&offer, 12), ETHERTYPE_IPV4);
+    let bootp = &offer[14 + 20 + 8..];
+    assert_eq!(bootp[0], 2);
+    assert_eq!(bootp[4..8], [0xde, 0xad, 0xbe, 0xef]);
+    assert_eq!(ip_at(bootp, 16), GUEST_IP);
+    assert_eq!(bootp[240..243], [53, 1, DHCP_OFFER]);
+    assert_eq!(checksum(0, &offer[14..34]), 0);
+
+    // Asking who has the router gets its MAC.
+    let mut arp = vec![0, 1, 0x08, 0x00, 6, 4, 0, 1];
+    arp.extend_from_slice(&guest_mac);
+    arp.extend_from_slice(&GUEST_IP.octets());
+    arp.extend_from_slice(&[0; 6]);
+    arp.extend_from_slice(&ROUTER_IP.octets());
+    slirp.send(&frame(ETHERTYPE_ARP, &arp));
+    let reply = slirp.receive().unwrap();
+    assert_eq!(reply[..6], guest_mac);
+    assert_eq!(reply[14 + 8..14 + 14], ROUTER_MAC);
+    assert_eq!(ip_at(&reply, 14 + 14), ROUTER_IP);
+
+    // And the router answers pings.
+    let mut echo = vec![8, 0, 0, 0, 0, 1, 0, 1, b'h', b'i'];
+    let sum = checksum(0, &echo);
+    echo[2..4].copy_from_slice(&sum.to_be_bytes());
+    slirp.send(&ip(PROTOCOL_ICMP, GUEST_IP, ROUTER_IP, &echo));
+    let pong = slirp.receive().unwrap();
+    assert_eq!(ip_at(&pong, 14 + 12), ROUTER_IP);
+    assert_eq!(pong[14 + 20], 0);
+    assert_eq!(pong[14 + 28..], [b'h', b'i']);
+    assert_eq!(checksum(0, &pong[14 + 20..]), 0);
+    assert!(slirp.receive().is_none());
+}
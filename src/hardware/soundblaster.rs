@@ -0,0 +1,438 @@
+// The Sound Blaster 2.0 and Pro's DSP, and the Pro's mixer. The DSP plays
+// 8-bit unsigned PCM fetched over DMA channel 1 at a rate set by a time
+// constant, and raises IRQ 5 at the end of each block. The FM synthesizer
+// is the board's OPL2, which the card decodes at 2x8h-2x9h as well as
+// 388h, and on the Pro at 2x0h-2x3h too. Output is mono, so the Pro's
+// stereo mode, which alternates left and right bytes, comes out as their
+// mix.
+use crate::hardware::dma::DmaController;
+use crate::hardware::reset::{Reset, ResetKind};
+use log::{debug, trace};
+use std::collections::VecDeque;
+
+pub const SB_BASE: u16 = 0x220;
+pub const SB_IRQ: u8 = 5;
+pub const SB_DMA_CHANNEL: usize = 1;
+
+/// The DSP's time constants count microseconds.
+pub const SB_CLOCK_HZ: u32 = 1_000_000;
+
+/// How loud full scale PCM is next to the other sources.
+const VOLUME: f32 = 0.5;
+
+/// What the DSP answers a reset with.
+const RESET_READY: u8 = 0xaa;
+
+/// Mixer registers, and the levels they come up with.
+const MIXER_VOICE: u8 = 0x04;
+const MIXER_MASTER: u8 = 0x22;
+const MIXER_FM: u8 = 0x26;
+const MIXER_DEFAULTS: [(u8, u8); 8] = [
+    (MIXER_VOICE, 0x99),
+    (0x0a, 0x01),
+    (0x0c, 0x00),
+    (0x0e, 0x00),
+    (MIXER_MASTER, 0x99),
+    (MIXER_FM, 0x99),
+    (0x28, 0x11),
+    (0x2e, 0x11),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SbModel {
+    /// DSP 2.01, with no mixer.
+    Sb2,
+    /// DSP 3.02, with the mixer at 2x4h-2x5h and a second FM port pair.
+    Pro,
+}
+
+/// A block being played, or a stretch of silence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Playback {
+    auto_init: bool,
+    /// Silence counts out samples without fetching them.
+    silent: bool,
+    /// Bytes left in the block.
+    remaining: u32,
+    /// Microseconds until the next byte.
+    countdown: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct SoundBlaster {
+    pub model: SbModel,
+    pub base: u16,
+    mixer: [u8; 256],
+    mixer_address: u8,
+    /// Bit 0 of the reset port. The DSP resets as it goes back to 0.
+    resetting: bool,
+    /// Bytes waiting at the read data port, and the last one read, which
+    /// reads again once they run out.
+    output: VecDeque<u8>,
+    data: u8,
+    /// The command being given its parameters.
+    command: Option<u8>,
+    parameters: Vec<u8>,
+    time_constant: u8,
+    block_size: u16,
+    playback: Option<Playback>,
+    paused: bool,
+    speaker: bool,
+    /// The level on the DAC.
+    dac: u8,
+    irq: bool,
+    /// The output since the last `sample`, and the last sample, for when
+    /// no time has gone by since.
+    sum: f32,
+    count: u32,
+    last: f32,
+}
+
+/// The parameter bytes each command takes.
+fn parameter_count(command: u8) -> usize {
+    match command {
+        0x10 | 0x40 | 0xe0 => 1,
+        0x14 | 0x48 | 0x80 => 2,
+        _ => 0,
+    }
+}
+
+/// A mixer level as a fraction of full scale. Left and right are in the
+/// high and low nibbles, of which the Pro only uses the top three bits.
+fn level(value: u8) -> f32 {
+    ((value >> 4) + (value & 0x0f)) as f32 / 30.0
+}
+
+impl SoundBlaster {
+    pub fn new(model: SbModel) -> SoundBlaster {
+        let mut sb = SoundBlaster {
+            model,
+            base: SB_BASE,
+            mixer: [0xff; 256],
+            mixer_address: 0,
+            resetting: false,
+            output: VecDeque::new(),
+            data: 0,
+            command: None,
+            parameters: vec![],
+            time_constant: 0,
+            block_size: 0,
+            playback: None,
+            paused: false,
+            speaker: false,
+            dac: 0x80,
+            irq: false,
+            sum: 0.0,
+            count: 0,
+            last: 0.0,
+        };
+        sb.reset_mixer();
+        sb
+    }
+
+    /// Whether `addr` is one of the DSP's or mixer's ports.
+    pub fn claims(&self, addr: u16) -> bool {
+        matches!(addr.wrapping_sub(self.base), 0x4..=0x6 | 0xa | 0xc | 0xe)
+    }
+
+    /// Whether `addr` is one of the card's own FM ports.
+    pub fn fm_port(&self, addr: u16) -> bool {
+        match addr.wrapping_sub(self.base) {
+            0x8..=0x9 => true,
+            0x0..=0x3 => self.model == SbModel::Pro,
+            _ => false,
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match (addr.wrapping_sub(self.base), self.model) {
+            (0x5, SbModel::Pro) => self.mixer[self.mixer_address as usize],
+            (0xa, _) => {
+                if let Some(data) = self.output.pop_front() {
+                    self.data = data;
+                }
+                self.data
+            }
+            // The write buffer status: never busy.
+            (0xc, _) => 0x7f,
+            // The read buffer status, which also acknowledges the IRQ.
+            (0xe, _) => {
+                self.irq = false;
+                match self.output.is_empty() {
+                    true => 0x7f,
+                    false => 0xff,
+                }
+            }
+            _ => 0xff,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match (addr.wrapping_sub(self.base), self.model) {
+            (0x4, SbModel::Pro) => self.mixer_address = data,
+            (0x5, SbModel::Pro) => self.write_mixer(data),
+            (0x6, _) => {
+                let resetting = (data & 0x01) != 0;
+                if self.resetting && !resetting {
+                    self.reset_dsp();
+                }
+                self.resetting = resetting;
+            }
+            (0xc, _) => self.write_dsp(data),
+            _ => {}
+        }
+    }
+
+    fn reset_mixer(&mut self) {
+        for &(register, value) in MIXER_DEFAULTS.iter() {
+            self.mixer[register as usize] = value;
+        }
+    }
+
+    fn write_mixer(&mut self, data: u8) {
+        trace!(target: "io", "Sound Blaster mixer {:#04x} = {:#04x}", self.mixer_address, data);
+        match self.mixer_address {
+            0x00 => self.reset_mixer(),
+            address => self.mixer[address as usize] = data,
+        }
+    }
+
+    fn reset_dsp(&mut self) {
+        self.output.clear();
+        self.output.push_back(RESET_READY);
+        self.command = None;
+        self.parameters.clear();
+        self.playback = None;
+        self.paused = false;
+        self.speaker = false;
+        self.dac = 0x80;
+        self.irq = false;
+    }
+
+    fn write_dsp(&mut self, data: u8) {
+        let command = match self.command {
+            Some(command) => {
+                self.parameters.push(data);
+                command
+            }
+            None => data,
+        };
+        if self.parameters.len() < parameter_count(command) {
+            self.command = Some(command);
+            return;
+        }
+        self.command = None;
+        let parameters = std::mem::take(&mut self.parameters);
+        self.execute(command, &parameters);
+    }
+
+    fn execute(&mut self, command: u8, parameters: &[u8]) {
+        trace!(target: "io", "Sound Blaster DSP command {:#04x} {:02x?}", command, parameters);
+        let length = match parameters {
+            [low, high] => u16::from_le_bytes([*low, *high]) as u32 + 1,
+            _ => self.block_size as u32 + 1,
+        };
+        match command {
+            // Direct output, timed by the program.
+            0x10 => self.dac = parameters[0],
+            0x14 | 0x91 => self.start(false, false, length),
+            0x1c | 0x90 => self.start(true, false, length),
+            // There's nothing to record, so the ADC hears silence.
+            0x20 => self.output.push_back(0x80),
+            0x40 => self.time_constant = parameters[0],
+            0x48 => self.block_size = u16::from_le_bytes([parameters[0], parameters[1]]),
+            0x80 => self.start(false, true, length),
+            0xd0 => self.paused = true,
+            0xd1 => self.speaker = true,
+            0xd3 => self.speaker = false,
+            0xd4 => self.paused = false,
+            0xd8 => self
+                .output
+                .push_back(if self.speaker { 0xff } else { 0x00 }),
+            // Finish the current block, then stop.
+            0xda => {
+                if let Some(playback) = &mut self.playback {
+                    playback.auto_init = false;
+                }
+            }
+            0xe0 => self.output.push_back(!parameters[0]),
+            0xe1 => {
+                let version: [u8; 2] = match self.model {
+                    SbModel::Sb2 => [2, 1],
+                    SbModel::Pro => [3, 2],
+                };
+                self.output.extend(version);
+            }
+            0xf2 => self.irq = true,
+            _ => debug!(target: "io", "Unimplemented Sound Blaster DSP command {:#04x}", command),
+        }
+    }
+
+    fn start(&mut self, auto_init: bool, silent: bool, length: u32) {
+        if silent {
+            self.dac = 0x80;
+        }
+        self.paused = false;
+        self.playback = Some(Playback {
+            auto_init,
+            silent,
+            remaining: length,
+            countdown: self.period(),
+        });
+    }
+
+    fn period(&self) -> u32 {
+        256 - self.time_constant as u32
+    }
+
+    /// Runs the DSP for `ticks` microseconds, fetching a byte from `memory`
+    /// each sample period of a block.
+    pub fn tick(&mut self, ticks: usize, dma: &mut DmaController, memory: &[u8]) {
+        let mut ticks = ticks as u32;
+        while ticks > 0 {
+            let step = match (self.playback, self.paused) {
+                (Some(playback), false) => playback.countdown.min(ticks),
+                _ => ticks,
+            };
+            let level = match self.speaker {
+                true => (self.dac as f32 - 128.0) / 128.0,
+                false => 0.0,
+            };
+            self.sum += level * step as f32;
+            self.count += step;
+            ticks -= step;
+            if let (Some(playback), false) = (&mut self.playback, self.paused) {
+                playback.countdown -= step;
+                if playback.countdown == 0 {
+                    self.next_byte(dma, memory);
+                }
+            }
+        }
+    }
+
+    fn next_byte(&mut self, dma: &mut DmaController, memory: &[u8]) {
+        let mut playback = match self.playback {
+            Some(playback) => playback,
+            None => return,
+        };
+        playback.countdown = self.period();
+        if !playback.silent {
+            dma.dma_request(SB_DMA_CHANNEL);
+            match dma.dma_read(SB_DMA_CHANNEL, memory) {
+                Some((value, _)) => self.dac = value as u8,
+                // The channel isn't ready, so ask again next microsecond.
+                None => {
+                    playback.countdown = 1;
+                    self.playback = Some(playback);
+                    return;
+                }
+            }
+        }
+        playback.remaining -= 1;
+        if playback.remaining == 0 {
+            self.irq = true;
+            if !playback.auto_init {
+                dma.dma_release(SB_DMA_CHANNEL);
+                self.playback = None;
+                return;
+            }
+            playback.remaining = self.block_size as u32 + 1;
+        }
+        self.playback = Some(playback);
+    }
+
+    /// The card's output since the last call, with the OPL2's `fm` put
+    /// through the Pro's mixer.
+    pub fn sample(&mut self, fm: f32) -> f32 {
+        if self.count > 0 {
+            self.last = self.sum / self.count as f32;
+            self.sum = 0.0;
+            self.count = 0;
+        }
+        let voice = self.last * VOLUME;
+        match self.model {
+            SbModel::Sb2 => voice + fm,
+            SbModel::Pro => {
+                let mixer = |register: u8| level(self.mixer[register as usize]);
+                (voice * mixer(MIXER_VOICE) + fm * mixer(MIXER_FM)) * mixer(MIXER_MASTER)
+            }
+        }
+    }
+}
+
+impl Reset for SoundBlaster {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        *self = SoundBlaster::new(self.model);
+    }
+}
+
+#[test]
+fn test_sound_blaster_dma_playback() {
+    let mut sb = SoundBlaster::new(SbModel::Pro);
+    let mut dma = DmaController::pc();
+    let mut memory = vec![0; 0x10000];
+    for (i, byte) in memory[0x1000..0x1010].iter_mut().enumerate() {
+        *byte = 0x80 + i as u8 * 8;
+    }
+    // Channel 1 reads 16 bytes from 1000h.
+    dma.wb(0x0b, 0x49);
+    dma.wb(0x0c, 0);
+    dma.wb(0x02, 0x00);
+    dma.wb(0x02, 0x10);
+    dma.wb(0x03, 15);
+    dma.wb(0x03, 0);
+    dma.wb(0x0a, 0x01);
+
+    // The reset handshake, then the version.
+    sb.wb(0x226, 1);
+    sb.wb(0x226, 0);
+    assert_eq!(sb.rb(0x22e) & 0x80, 0x80);
+    assert_eq!(sb.rb(0x22a), RESET_READY);
+    assert_eq!(sb.rb(0x22e) & 0x80, 0x00);
+    sb.wb(0x22c, 0xe1);
+    assert_eq!((sb.rb(0x22a), sb.rb(0x22a)), (3, 2));
+
+    // Four bytes single cycle at 10 kHz take 400 microseconds.
+    for data in [0xd1, 0x40, 156, 0x14, 3, 0] {
+        sb.wb(0x22c, data);
+    }
+    sb.tick(399, &mut dma, &memory);
+    assert!(!sb.irq());
+    sb.tick(1, &mut dma, &memory);
+    assert!(sb.irq() && sb.playback.is_none());
+    assert_eq!(sb.dac, 0x98);
+    assert!(sb.sample(0.0) > 0.0);
+    sb.rb(0x22e);
+    assert!(!sb.irq());
+
+    // Auto-init blocks of two go on until told to stop after the block.
+    for data in [0x48, 1, 0, 0x1c] {
+        sb.wb(0x22c, data);
+    }
+    for _ in 0..2 {
+        sb.tick(200, &mut dma, &memory);
+        assert!(sb.irq() && sb.playback.is_some());
+        sb.rb(0x22e);
+    }
+    sb.wb(0x22c, 0xda);
+    sb.tick(200, &mut dma, &memory);
+    assert!(sb.irq() && sb.playback.is_none());
+    assert_eq!(sb.dac, 0x80 + 9 * 8);
+
+    // The mixer, which a reset puts back.
+    sb.wb(0x224, MIXER_MASTER);
+    sb.wb(0x225, 0xff);
+    assert_eq!(sb.rb(0x225), 0xff);
+    sb.wb(0x224, 0x00);
+    sb.wb(0x225, 0x00);
+    sb.wb(0x224, MIXER_MASTER);
+    assert_eq!(sb.rb(0x225), 0x99);
+    assert!(sb.fm_port(0x220) && sb.fm_port(0x229) && !sb.claims(0x228));
+}
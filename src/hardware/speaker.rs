@@ -0,0 +1,102 @@
+use crate::hardware::reset::{Reset, ResetKind};
+
+/// The rate samples are made at until the frontend asks for its own.
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+/// How loud the speaker is next to the sound cards.
+const VOLUME: f32 = 0.3;
+
+/// The DC blocker's pole. It lets everything above a few tens of hertz
+/// through, and brings a cone held in back to silence.
+const DC_POLE: f32 = 0.995;
+
+/// The PC speaker, driven by PIT channel 2's output ANDed with bit 1 of
+/// port 61h. Between samples it counts how many PIT clocks the cone spent
+/// out, and each sample is that fraction, which is a box filter and keeps
+/// tones above half the sample rate from folding back down as loudly. The
+/// average then goes through a DC blocker, as the real speaker's coupling
+/// doesn't pass a constant level either.
+#[derive(Debug, Clone, Default)]
+pub struct Speaker {
+    /// PIT clocks the cone was out for, and PIT clocks in all, since the
+    /// last sample.
+    high: u32,
+    total: u32,
+    /// Where the cone is, for a sample with no PIT clocks in it.
+    on: bool,
+    last_input: f32,
+    last_output: f32,
+}
+
+impl Speaker {
+    pub fn new() -> Speaker {
+        Speaker::default()
+    }
+
+    /// Records `ticks` PIT clocks with the speaker input at `on`.
+    pub fn advance(&mut self, ticks: usize, on: bool) {
+        self.on = on;
+        self.total += ticks as u32;
+        if on {
+            self.high += ticks as u32;
+        }
+    }
+
+    /// The next sample, covering the PIT clocks since the last one.
+    pub fn sample(&mut self) -> f32 {
+        let level = match self.total {
+            0 => self.on as u32 as f32,
+            total => self.high as f32 / total as f32,
+        };
+        self.high = 0;
+        self.total = 0;
+        let input = level * VOLUME;
+        self.last_output = input - self.last_input + DC_POLE * self.last_output;
+        self.last_input = input;
+        self.last_output
+    }
+}
+
+impl Reset for Speaker {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        *self = Speaker::new();
+    }
+}
+
+#[test]
+fn test_speaker_square_wave() {
+    let mut speaker = Speaker::new();
+    // 1 kHz from the 1.19 MHz PIT clock at 48 kHz: 24 samples a cycle,
+    // about 25 PIT clocks a sample.
+    let mut samples = vec![];
+    for cycle in 0..100 {
+        for half in [true, false] {
+            for _ in 0..12 {
+                speaker.advance(25, half);
+                samples.push(speaker.sample());
+            }
+        }
+        if cycle == 50 {
+            samples.clear();
+        }
+    }
+    let max = samples.iter().cloned().fold(f32::MIN, f32::max);
+    let min = samples.iter().cloned().fold(f32::MAX, f32::min);
+    assert!(max > 0.1 && min < -0.1);
+    assert!((max + min).abs() < 0.02);
+
+    // A sample split across an edge lands in between.
+    speaker.advance(10, true);
+    speaker.advance(15, false);
+    assert_eq!((speaker.high, speaker.total), (10, 25));
+
+    // Held in, it settles back to silence.
+    for _ in 0..5000 {
+        speaker.advance(25, true);
+        speaker.sample();
+    }
+    assert!(speaker.sample().abs() < 0.001);
+}
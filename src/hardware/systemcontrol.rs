@@ -0,0 +1,162 @@
+// The AT's system control ports. Port 61h gates PIT channel 2 and the
+// speaker, and enables the two NMI sources: a RAM parity error and I/O
+// channel check, raised by an expansion card. Reading it back gives their
+// latched status, the speaker timer's output and the refresh toggle, which
+// flips with every DRAM refresh request; BIOSes count its flips to time
+// delays without depending on the CPU's speed. Port 92h is the PS/2's
+// "system control port A", which AT-class boards took up for its fast A20
+// gate and CPU reset.
+use crate::hardware::reset::{Reset, ResetKind};
+
+const SPEAKER_GATE: u8 = 0x01;
+const SPEAKER_DATA: u8 = 0x02;
+/// The checks are enabled when these bits are clear. Setting one also
+/// clears its error.
+const PARITY_CHECK_DISABLE: u8 = 0x04;
+const IO_CHECK_DISABLE: u8 = 0x08;
+const REFRESH_TOGGLE: u8 = 0x10;
+const TIMER_2_OUT: u8 = 0x20;
+const IO_CHECK: u8 = 0x40;
+const PARITY_ERROR: u8 = 0x80;
+
+const FAST_RESET: u8 = 0x01;
+const FAST_A20: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SystemControl {
+    /// The low four bits last written to port 61h.
+    pub port_b: u8,
+    pub refresh_toggle: bool,
+    pub parity_error: bool,
+    pub io_channel_check: bool,
+    /// What was last written to port 92h.
+    pub port_a: u8,
+}
+
+impl SystemControl {
+    pub fn new() -> SystemControl {
+        SystemControl::default()
+    }
+
+    pub fn speaker_gate(&self) -> bool {
+        (self.port_b & SPEAKER_GATE) != 0
+    }
+
+    pub fn speaker_data(&self) -> bool {
+        (self.port_b & SPEAKER_DATA) != 0
+    }
+
+    pub fn fast_a20(&self) -> bool {
+        (self.port_a & FAST_A20) != 0
+    }
+
+    /// Counts refresh requests, from PIT channel 1.
+    pub fn refresh(&mut self, requests: usize) {
+        self.refresh_toggle ^= (requests & 1) != 0;
+    }
+
+    /// A parity error from memory. Nothing emulated makes one, but a
+    /// debugger can.
+    pub fn raise_parity_error(&mut self) {
+        if (self.port_b & PARITY_CHECK_DISABLE) == 0 {
+            self.parity_error = true;
+        }
+    }
+
+    /// An expansion card pulling -I/O CH CK low.
+    pub fn raise_io_channel_check(&mut self) {
+        if (self.port_b & IO_CHECK_DISABLE) == 0 {
+            self.io_channel_check = true;
+        }
+    }
+
+    /// Whether either check asks for an NMI. Port 70h can still mask it.
+    pub fn nmi(&self) -> bool {
+        self.parity_error || self.io_channel_check
+    }
+
+    pub fn read_port_b(&self, timer_2_out: bool) -> u8 {
+        let mut value = self.port_b;
+        if self.refresh_toggle {
+            value |= REFRESH_TOGGLE;
+        }
+        if timer_2_out {
+            value |= TIMER_2_OUT;
+        }
+        if self.io_channel_check {
+            value |= IO_CHECK;
+        }
+        if self.parity_error {
+            value |= PARITY_ERROR;
+        }
+        value
+    }
+
+    pub fn write_port_b(&mut self, value: u8) {
+        self.port_b = value & 0x0f;
+        if (value & PARITY_CHECK_DISABLE) != 0 {
+            self.parity_error = false;
+        }
+        if (value & IO_CHECK_DISABLE) != 0 {
+            self.io_channel_check = false;
+        }
+    }
+
+    /// Bit 0 always reads back clear, as the reset it asks for has
+    /// already happened.
+    pub fn read_port_a(&self) -> u8 {
+        self.port_a & !FAST_RESET
+    }
+
+    /// Writes port 92h, and returns whether the CPU should be reset: bit 0
+    /// going from 0 to 1 pulses its reset line.
+    pub fn write_port_a(&mut self, value: u8) -> bool {
+        let reset = (value & !self.port_a & FAST_RESET) != 0;
+        self.port_a = value;
+        reset
+    }
+}
+
+/// Both ports are on the system reset line, so a CPU-only reset leaves
+/// them alone, including port 92h's own.
+impl Reset for SystemControl {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind != ResetKind::Warm {
+            *self = SystemControl::new();
+        }
+    }
+}
+
+#[test]
+fn test_system_control_ports() {
+    let mut control = SystemControl::new();
+    control.write_port_b(0x03);
+    assert!(control.speaker_gate() && control.speaker_data());
+    assert_eq!(control.read_port_b(true), 0x23);
+
+    control.refresh(3);
+    assert_eq!(control.read_port_b(false), 0x13);
+    control.refresh(2);
+    assert_eq!(control.read_port_b(false), 0x13);
+
+    // Errors latch while their checks are enabled, and setting the
+    // disable bit clears them.
+    control.raise_io_channel_check();
+    control.raise_parity_error();
+    assert!(control.nmi());
+    assert_eq!(control.read_port_b(false), 0xd3);
+    control.write_port_b(0x0b);
+    assert_eq!(control.read_port_b(false), 0x9b);
+    control.raise_io_channel_check();
+    assert!(!control.io_channel_check);
+    control.write_port_b(0x0f);
+    assert!(!control.nmi());
+
+    // Only setting bit 0 resets; holding it doesn't reset again.
+    assert!(control.write_port_a(0x03));
+    assert!(control.fast_a20());
+    assert_eq!(control.read_port_a(), 0x02);
+    assert!(!control.write_port_a(0x03));
+    assert!(!control.write_port_a(0x02));
+    assert!(control.write_port_a(0x01));
+}
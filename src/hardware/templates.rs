@@ -0,0 +1,286 @@
+// Ready-made configurations of well known machines, selectable by name.
+// Video and sound cards are recorded here so the devices can be attached
+// once they exist; so far the CGA, Hercules and EGA cards and the Sound
+// Blaster are.
+use crate::hardware::builder::MachineBuilder;
+use crate::hardware::floppy::DriveType;
+use crate::hardware::machine::{IsaCard, Machine};
+use crate::hardware::reset::ResetKind;
+use crate::hardware::rom::BiosImage;
+use crate::hardware::rtc::RTC;
+use crate::hardware::soundblaster::SbModel;
+use log::warn;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Board {
+    Ibm5150,
+    Ibm5160,
+    /// A turbo XT clone: the 5160's switches, with the CPU's clock up to
+    /// 10 MHz.
+    GenericXt,
+    Ibm5170,
+    Generic286,
+}
+
+impl Board {
+    pub fn cpu(self) -> &'static str {
+        match self {
+            Board::Ibm5150 | Board::Ibm5160 | Board::GenericXt => "8088",
+            Board::Ibm5170 | Board::Generic286 => "80286",
+        }
+    }
+
+    /// The width of the expansion bus, and of the CPU's.
+    pub fn bus_bits(self) -> u8 {
+        match self {
+            Board::Ibm5150 | Board::Ibm5160 | Board::GenericXt => 8,
+            Board::Ibm5170 | Board::Generic286 => 16,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VideoCard {
+    Cga,
+    Hercules,
+    Ega,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SoundCard {
+    Speaker,
+    /// A Sound Blaster 2.0.
+    SoundBlaster,
+}
+
+/// What the AT setup program would have stored in CMOS for this machine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CmosDefaults {
+    pub base_memory_kb: u16,
+    pub extended_memory_kb: u16,
+    pub floppy_drives: [Option<DriveType>; 2],
+    /// Index into the BIOS hard disk type table, 0 for none.
+    pub hard_disk_type: u8,
+}
+
+impl CmosDefaults {
+    /// Writes the configuration bytes and their checksum the way the setup
+    /// program would. The clock itself is left alone.
+    pub fn apply(&self, rtc: &mut RTC, video: VideoCard) {
+        let drive_type = |drive: Option<DriveType>| drive.map_or(0, DriveType::cmos_type);
+        let drives = self
+            .floppy_drives
+            .iter()
+            .filter(|drive| drive.is_some())
+            .count() as u8;
+        let display = match video {
+            VideoCard::Cga => 0x20,
+            VideoCard::Hercules => 0x30,
+            VideoCard::Ega => 0x00,
+        };
+        rtc.ram[0x0e] = 0;
+        rtc.ram[0x10] = drive_type(self.floppy_drives[0]) << 4 | drive_type(self.floppy_drives[1]);
+        // Types 15 and up don't fit in a nibble and go in 19h instead.
+        if self.hard_disk_type < 15 {
+            rtc.ram[0x12] = self.hard_disk_type << 4;
+        } else {
+            rtc.ram[0x12] = 0xf0;
+            rtc.ram[0x19] = self.hard_disk_type;
+        }
+        rtc.ram[0x14] = if drives > 0 {
+            (drives - 1) << 6 | 0x01
+        } else {
+            0
+        } | display;
+        rtc.set_memory_sizes(self.base_memory_kb, self.extended_memory_kb);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MachineTemplate {
+    /// Short name used on the command line.
+    pub id: &'static str,
+    /// Other names it answers to, like `ibm_xt`.
+    pub aliases: &'static [&'static str],
+    pub name: &'static str,
+    pub board: Board,
+    /// The BIOS the machine shipped with, as `--bios` takes it. `None`
+    /// keeps the one the board loads itself.
+    pub bios: Option<&'static str>,
+    pub cpu_clock_hz: u32,
+    /// The CPU clock with the turbo switch off, on boards that have one.
+    pub slow_clock_hz: Option<u32>,
+    pub ram_kb: usize,
+    pub video: VideoCard,
+    pub sound: SoundCard,
+    /// Cards fitted as standard, beyond video and sound.
+    pub cards: &'static [IsaCard],
+    pub cmos: Option<CmosDefaults>,
+}
+
+pub const TEMPLATES: [MachineTemplate; 5] = [
+    MachineTemplate {
+        id: "ibm5150",
+        aliases: &["ibm_pc", "pc"],
+        name: "IBM 5150 64KB+CGA",
+        board: Board::Ibm5150,
+        bios: None,
+        cpu_clock_hz: 4_772_727,
+        slow_clock_hz: None,
+        ram_kb: 64,
+        video: VideoCard::Cga,
+        sound: SoundCard::Speaker,
+        cards: &[],
+        cmos: None,
+    },
+    MachineTemplate {
+        id: "ibm5160",
+        aliases: &["ibm_xt", "xt"],
+        name: "IBM 5160 640KB+Hercules",
+        board: Board::Ibm5160,
+        bios: Some("roms/machines/ibmxt/xt.rom"),
+        cpu_clock_hz: 4_772_727,
+        slow_clock_hz: None,
+        ram_kb: 640,
+        video: VideoCard::Hercules,
+        sound: SoundCard::Speaker,
+        cards: &[],
+        cmos: None,
+    },
+    MachineTemplate {
+        id: "turboxt",
+        aliases: &["turbo_xt", "xt_clone"],
+        name: "Turbo XT 10MHz 640KB+CGA",
+        board: Board::GenericXt,
+        bios: Some("roms/machines/genxt/pcxt.rom"),
+        cpu_clock_hz: 10_000_000,
+        slow_clock_hz: Some(4_772_727),
+        ram_kb: 640,
+        video: VideoCard::Cga,
+        sound: SoundCard::Speaker,
+        // On the multi-I/O card, with the serial and parallel ports.
+        cards: &[IsaCard::GamePort],
+        cmos: None,
+    },
+    MachineTemplate {
+        id: "ibm5170",
+        aliases: &["ibm_at", "at"],
+        name: "IBM 5170 6MHz+EGA",
+        board: Board::Ibm5170,
+        bios: Some("roms/machines/ibmat/62x0820.u27,roms/machines/ibmat/62x0821.u47"),
+        cpu_clock_hz: 6_000_000,
+        slow_clock_hz: None,
+        ram_kb: 512,
+        video: VideoCard::Ega,
+        sound: SoundCard::Speaker,
+        cards: &[],
+        cmos: Some(CmosDefaults {
+            base_memory_kb: 512,
+            extended_memory_kb: 0,
+            floppy_drives: [Some(DriveType::Drive1200K), Some(DriveType::Drive360K)],
+            hard_disk_type: 2,
+        }),
+    },
+    MachineTemplate {
+        id: "generic286",
+        aliases: &["at_clone", "286"],
+        name: "Generic 286-12 EGA+SB",
+        board: Board::Generic286,
+        bios: None,
+        cpu_clock_hz: 12_000_000,
+        slow_clock_hz: Some(8_000_000),
+        ram_kb: 640,
+        video: VideoCard::Ega,
+        sound: SoundCard::SoundBlaster,
+        cards: &[],
+        cmos: Some(CmosDefaults {
+            base_memory_kb: 640,
+            extended_memory_kb: 384,
+            floppy_drives: [Some(DriveType::Drive1440K), Some(DriveType::Drive1200K)],
+            hard_disk_type: 47,
+        }),
+    },
+];
+
+/// Looks a template up by its id, one of its aliases or its full name,
+/// ignoring case.
+pub fn find_template(name: &str) -> Option<&'static MachineTemplate> {
+    TEMPLATES.iter().find(|t| {
+        t.id.eq_ignore_ascii_case(name)
+            || t.name.eq_ignore_ascii_case(name)
+            || t.aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+    })
+}
+
+impl fmt::Display for MachineTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<12} {} ({}, {}-bit bus)",
+            self.id,
+            self.name,
+            self.board.cpu(),
+            self.board.bus_bits()
+        )
+    }
+}
+
+impl MachineTemplate {
+    pub fn build(&self) -> Result<Machine, String> {
+        let mut builder = MachineBuilder::new(self.board)
+            .ram_kb(self.ram_kb)
+            .clock_hz(self.cpu_clock_hz)
+            .video(self.video);
+        if let Some(hz) = self.slow_clock_hz {
+            builder = builder.slow_clock_hz(hz);
+        }
+        if self.sound == SoundCard::SoundBlaster {
+            builder = builder.card(IsaCard::SoundBlaster(SbModel::Sb2));
+        }
+        for &card in self.cards {
+            builder = builder.card(card);
+        }
+        if let Some(cmos) = self.cmos {
+            builder = builder
+                .floppy_drives(cmos.floppy_drives)
+                .extended_memory_kb(cmos.extended_memory_kb as usize);
+        }
+        let mut machine = builder.build()?;
+        if let (Some(cmos), Machine::At(at)) = (self.cmos, &mut machine) {
+            cmos.apply(&mut at.hardware.rtc, self.video);
+        }
+        // Without the ROM the machine shipped with, it gets the board's
+        // own, which boots the same way if not quite as the original did.
+        if let Some(spec) = self.bios {
+            let result = BiosImage::parse(spec)
+                .load()
+                .and_then(|bios| machine.load_bios(bios));
+            match result {
+                Ok(()) => machine.reset(ResetKind::Cold),
+                Err(err) => warn!("{}: {}, keeping the board's BIOS", self.name, err),
+            }
+        }
+        Ok(machine)
+    }
+}
+
+#[test]
+fn test_machine_templates() {
+    assert_eq!(find_template("IBM5160").unwrap().ram_kb, 640);
+    let template = find_template("generic 286-12 ega+sb").unwrap();
+    assert_eq!(template.video, VideoCard::Ega);
+    assert!(find_template("amiga").is_none());
+    assert_eq!(find_template("IBM_XT").unwrap().id, "ibm5160");
+    assert_eq!(find_template("at").unwrap().board.bus_bits(), 16);
+    for template in TEMPLATES.iter() {
+        let machine = template.build().unwrap();
+        assert!(machine.frame().is_some(), "{} has no display", template.id);
+        assert_eq!(machine.ram_size(), template.ram_kb);
+        assert_eq!(machine.clock_hz(), template.cpu_clock_hz);
+        let extended_kb = template.cmos.map_or(0, |cmos| cmos.extended_memory_kb);
+        assert_eq!(machine.extended_memory(), extended_kb as usize);
+    }
+}
@@ -0,0 +1,585 @@
+// The serial ports: an 8250, 16450 or 16550 UART at each of COM1-COM4.
+// Characters take as long to shift in and out as they would on the wire at
+// the programmed baud rate, so programs that time transmission see the
+// same thing as on a real port. Bytes go to and come from a
+// `SerialBackend`; with none connected the port is a socket with nothing
+// plugged in. The 16550's FIFOs work, and the 8250 lacks the scratch
+// register, which is how programs tell the three apart.
+use crate::hardware::passthrough::{LineSettings, ModemStatus, Parity, SerialBackend};
+use crate::hardware::reset::{Reset, ResetKind};
+use crate::input::InputEvent;
+use log::trace;
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Bit times per second at a divisor of 1: the 1.8432 MHz crystal over
+/// the 16 clocks each bit takes.
+pub const UART_CLOCK_HZ: u32 = 115_200;
+
+/// The base port and IRQ of COM1-COM4.
+pub const COM_PORTS: [(u16, u8); 4] = [(0x3f8, 4), (0x2f8, 3), (0x3e8, 4), (0x2e8, 3)];
+
+const FIFO_SIZE: usize = 16;
+const TRIGGER_LEVELS: [usize; 4] = [1, 4, 8, 14];
+
+/// Character times without a read or a new byte before a 16550 with bytes
+/// in its receive FIFO raises the timeout interrupt.
+const TIMEOUT_CHARACTERS: u32 = 4;
+
+/// Interrupt enable register bits.
+const IER_RECEIVED: u8 = 0x01;
+const IER_THRE: u8 = 0x02;
+const IER_LINE_STATUS: u8 = 0x04;
+const IER_MODEM_STATUS: u8 = 0x08;
+
+/// Interrupt identification values, highest priority first.
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_RECEIVED: u8 = 0x04;
+const IIR_TIMEOUT: u8 = 0x0c;
+const IIR_THRE: u8 = 0x02;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_NONE: u8 = 0x01;
+const IIR_FIFOS: u8 = 0xc0;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT1: u8 = 0x04;
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOPBACK: u8 = 0x10;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_OVERRUN: u8 = 0x02;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+const MSR_CTS: u8 = 0x10;
+const MSR_DSR: u8 = 0x20;
+const MSR_RI: u8 = 0x40;
+const MSR_DCD: u8 = 0x80;
+/// The trailing edge of RI, which is its only delta bit.
+const MSR_TERI: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UartModel {
+    Ins8250,
+    Ns16450,
+    Ns16550,
+}
+
+/// The backend a port is plugged into. A copy of the machine comes up
+/// with nothing plugged in, as two ports can't share one host device.
+#[derive(Default)]
+pub struct SerialConnection(Option<Box<dyn SerialBackend>>);
+
+impl Clone for SerialConnection {
+    fn clone(&self) -> SerialConnection {
+        SerialConnection(None)
+    }
+}
+
+impl fmt::Debug for SerialConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "SerialConnection(connected)"),
+            None => write!(f, "SerialConnection(none)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UART {
+    pub model: UartModel,
+    pub base: u16,
+    pub irq: u8,
+    backend: SerialConnection,
+    divisor: u16,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    /// The overrun bit, until LSR is read.
+    line_errors: u8,
+    msr: u8,
+    scratch: u8,
+    fifo_enabled: bool,
+    trigger: usize,
+    /// The receive buffer register, or the 16550's receive FIFO, and the
+    /// last byte read for when it's empty.
+    rx: VecDeque<u8>,
+    rbr: u8,
+    /// The transmit holding register, or FIFO, and the shift register.
+    tx: VecDeque<u8>,
+    shifting: Option<u8>,
+    /// Bit times until the character being shifted out is gone, and until
+    /// the next character time, when a byte can come in.
+    tx_countdown: u32,
+    rx_countdown: u32,
+    /// Character times since the receive FIFO was last read or filled.
+    idle: u32,
+    thre_pending: bool,
+    timeout_pending: bool,
+}
+
+impl UART {
+    pub fn new(model: UartModel, base: u16, irq: u8) -> UART {
+        let mut uart = UART {
+            model,
+            base,
+            irq,
+            backend: SerialConnection::default(),
+            divisor: 12,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            line_errors: 0,
+            msr: 0,
+            scratch: 0,
+            fifo_enabled: false,
+            trigger: 1,
+            rx: VecDeque::new(),
+            rbr: 0,
+            tx: VecDeque::new(),
+            shifting: None,
+            tx_countdown: 0,
+            rx_countdown: 0,
+            idle: 0,
+            thre_pending: false,
+            timeout_pending: false,
+        };
+        uart.rx_countdown = uart.character_time();
+        uart
+    }
+
+    /// COM1-COM4, counting from 0.
+    pub fn com(port: usize, model: UartModel) -> UART {
+        let (base, irq) = COM_PORTS[port];
+        UART::new(model, base, irq)
+    }
+
+    /// Plugs the port into `backend`, handing it the current line settings
+    /// and modem control lines.
+    pub fn connect(&mut self, mut backend: Box<dyn SerialBackend>) {
+        backend.configure(&self.line_settings());
+        backend.set_modem_control((self.mcr & MCR_DTR) != 0, (self.mcr & MCR_RTS) != 0);
+        self.backend = SerialConnection(Some(backend));
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialBackend>> {
+        self.backend.0.take()
+    }
+
+    /// Passes host input on to the backend.
+    pub fn input(&mut self, event: InputEvent) {
+        if let Some(backend) = &mut self.backend.0 {
+            backend.input(event);
+        }
+    }
+
+    pub fn claims(&self, addr: u16) -> bool {
+        addr.wrapping_sub(self.base) < 8
+    }
+
+    pub fn irq(&self) -> bool {
+        (self.mcr & MCR_OUT2) != 0 && self.interrupt().is_some()
+    }
+
+    pub fn line_settings(&self) -> LineSettings {
+        let parity = match (self.lcr >> 3) & 0x07 {
+            0x01 => Parity::Odd,
+            0x03 => Parity::Even,
+            0x05 => Parity::Mark,
+            0x07 => Parity::Space,
+            _ => Parity::None,
+        };
+        LineSettings {
+            baud: UART_CLOCK_HZ / self.divisor.max(1) as u32,
+            data_bits: 5 + (self.lcr & 0x03),
+            parity,
+            stop_bits: 1 + ((self.lcr >> 2) & 0x01),
+        }
+    }
+
+    /// Bit times a character takes: a start bit, the data, any parity and
+    /// the stop bits.
+    fn character_time(&self) -> u32 {
+        let settings = self.line_settings();
+        let parity = (settings.parity != Parity::None) as u32;
+        let bits = 1 + settings.data_bits as u32 + parity + settings.stop_bits as u32;
+        bits * self.divisor.max(1) as u32
+    }
+
+    fn capacity(&self) -> usize {
+        match self.fifo_enabled {
+            true => FIFO_SIZE,
+            false => 1,
+        }
+    }
+
+    fn loopback(&self) -> bool {
+        (self.mcr & MCR_LOOPBACK) != 0
+    }
+
+    /// The interrupt to report, highest priority first.
+    fn interrupt(&self) -> Option<u8> {
+        let received = match self.fifo_enabled {
+            true => self.rx.len() >= self.trigger,
+            false => !self.rx.is_empty(),
+        };
+        if (self.ier & IER_LINE_STATUS) != 0 && self.line_errors != 0 {
+            Some(IIR_LINE_STATUS)
+        } else if (self.ier & IER_RECEIVED) != 0 && received {
+            Some(IIR_RECEIVED)
+        } else if (self.ier & IER_RECEIVED) != 0 && self.timeout_pending {
+            Some(IIR_TIMEOUT)
+        } else if (self.ier & IER_THRE) != 0 && self.thre_pending {
+            Some(IIR_THRE)
+        } else if (self.ier & IER_MODEM_STATUS) != 0 && (self.msr & 0x0f) != 0 {
+            Some(IIR_MODEM_STATUS)
+        } else {
+            None
+        }
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        let dlab = (self.lcr & LCR_DLAB) != 0;
+        match (addr.wrapping_sub(self.base), dlab) {
+            (0, false) => {
+                if let Some(data) = self.rx.pop_front() {
+                    self.rbr = data;
+                }
+                self.idle = 0;
+                self.timeout_pending = false;
+                self.rbr
+            }
+            (0, true) => self.divisor as u8,
+            (1, false) => self.ier,
+            (1, true) => (self.divisor >> 8) as u8,
+            (2, _) => {
+                let interrupt = self.interrupt();
+                if interrupt == Some(IIR_THRE) {
+                    self.thre_pending = false;
+                }
+                let fifos = match self.fifo_enabled {
+                    true => IIR_FIFOS,
+                    false => 0,
+                };
+                interrupt.unwrap_or(IIR_NONE) | fifos
+            }
+            (3, _) => self.lcr,
+            (4, _) => self.mcr,
+            (5, _) => {
+                let mut lsr = std::mem::take(&mut self.line_errors);
+                if !self.rx.is_empty() {
+                    lsr |= LSR_DATA_READY;
+                }
+                if self.tx.is_empty() {
+                    lsr |= LSR_THRE;
+                    if self.shifting.is_none() {
+                        lsr |= LSR_TEMT;
+                    }
+                }
+                lsr
+            }
+            (6, _) => {
+                let msr = self.msr;
+                self.msr &= 0xf0;
+                msr
+            }
+            (7, _) if self.model == UartModel::Ins8250 => 0xff,
+            (7, _) => self.scratch,
+            _ => 0xff,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        let dlab = (self.lcr & LCR_DLAB) != 0;
+        match (addr.wrapping_sub(self.base), dlab) {
+            (0, false) => self.write_thr(data),
+            (0, true) => self.divisor = (self.divisor & 0xff00) | data as u16,
+            (1, false) => {
+                // Enabling the THRE interrupt with the register empty
+                // raises it straight away.
+                if (data & !self.ier & IER_THRE) != 0 && self.tx.is_empty() {
+                    self.thre_pending = true;
+                }
+                self.ier = data & 0x0f;
+            }
+            (1, true) => self.divisor = (self.divisor & 0x00ff) | (data as u16) << 8,
+            (2, _) if self.model == UartModel::Ns16550 => self.write_fcr(data),
+            (3, _) => {
+                self.lcr = data;
+                // The receiver starts timing characters in the new format.
+                self.rx_countdown = self.character_time();
+                if !dlab || (data & LCR_DLAB) == 0 {
+                    let settings = self.line_settings();
+                    trace!(target: "io", "UART {:#05x} set to {:?}", self.base, settings);
+                    if let Some(backend) = &mut self.backend.0 {
+                        backend.configure(&settings);
+                    }
+                }
+            }
+            (4, _) => self.write_mcr(data & 0x1f),
+            (7, _) => self.scratch = data,
+            _ => {}
+        }
+    }
+
+    fn write_thr(&mut self, data: u8) {
+        self.thre_pending = false;
+        if self.shifting.is_none() && self.tx.is_empty() {
+            // Straight through to the shift register, which leaves the
+            // holding register empty again.
+            self.shifting = Some(data);
+            self.tx_countdown = self.character_time();
+            self.thre_pending = true;
+        } else if self.tx.len() < self.capacity() {
+            self.tx.push_back(data);
+        }
+    }
+
+    fn write_fcr(&mut self, data: u8) {
+        let enabled = (data & 0x01) != 0;
+        if enabled != self.fifo_enabled {
+            self.rx.clear();
+            self.tx.clear();
+        }
+        self.fifo_enabled = enabled;
+        if (data & 0x02) != 0 {
+            self.rx.clear();
+            self.timeout_pending = false;
+        }
+        if (data & 0x04) != 0 {
+            self.tx.clear();
+        }
+        self.trigger = TRIGGER_LEVELS[(data >> 6) as usize];
+    }
+
+    fn write_mcr(&mut self, data: u8) {
+        self.mcr = data;
+        // In loopback the outputs go inactive and come back in on the
+        // inputs instead.
+        let (dtr, rts) = match self.loopback() {
+            true => (false, false),
+            false => ((data & MCR_DTR) != 0, (data & MCR_RTS) != 0),
+        };
+        if let Some(backend) = &mut self.backend.0 {
+            backend.set_modem_control(dtr, rts);
+        }
+        if self.loopback() {
+            self.set_modem_status(ModemStatus {
+                cts: (data & MCR_RTS) != 0,
+                dsr: (data & MCR_DTR) != 0,
+                ri: (data & MCR_OUT1) != 0,
+                dcd: (data & MCR_OUT2) != 0,
+            });
+        }
+    }
+
+    /// Takes new levels on the modem status lines, noting which changed.
+    fn set_modem_status(&mut self, status: ModemStatus) {
+        let mut lines = 0;
+        for (on, bit) in [
+            (status.cts, MSR_CTS),
+            (status.dsr, MSR_DSR),
+            (status.ri, MSR_RI),
+            (status.dcd, MSR_DCD),
+        ] {
+            if on {
+                lines |= bit;
+            }
+        }
+        let changed = (self.msr ^ lines) & 0xf0;
+        let mut deltas = (changed >> 4) & !MSR_TERI;
+        if (self.msr & !lines & MSR_RI) != 0 {
+            deltas |= MSR_TERI;
+        }
+        self.msr = lines | (self.msr & 0x0f) | deltas;
+    }
+
+    fn receive(&mut self, data: u8) {
+        self.idle = 0;
+        if self.rx.len() < self.capacity() {
+            self.rx.push_back(data);
+            return;
+        }
+        // Without FIFOs the new byte overwrites the old one; with them
+        // it's lost.
+        self.line_errors |= LSR_OVERRUN;
+        if !self.fifo_enabled {
+            self.rx[0] = data;
+        }
+    }
+
+    /// Runs the port for `ticks` bit times at a divisor of 1.
+    pub fn tick(&mut self, ticks: usize) {
+        let mut ticks = ticks as u32;
+        while ticks > 0 {
+            let mut step = ticks.min(self.rx_countdown);
+            if self.shifting.is_some() {
+                step = step.min(self.tx_countdown);
+            }
+            ticks -= step;
+            self.rx_countdown -= step;
+            if self.shifting.is_some() {
+                self.tx_countdown -= step;
+                if self.tx_countdown == 0 {
+                    self.finish_transmit();
+                }
+            }
+            if self.rx_countdown == 0 {
+                self.rx_countdown = self.character_time();
+                self.character_period();
+            }
+        }
+    }
+
+    /// Bit times until `tick` next has something to do: a character
+    /// shifted out, or the next character time.
+    pub fn ticks_until_event(&self) -> usize {
+        match self.shifting {
+            Some(_) => self.rx_countdown.min(self.tx_countdown) as usize,
+            None => self.rx_countdown as usize,
+        }
+    }
+
+    fn finish_transmit(&mut self) {
+        if let Some(data) = self.shifting.take() {
+            match (self.loopback(), &mut self.backend.0) {
+                (true, _) => self.receive(data),
+                (false, Some(backend)) => backend.write_byte(data),
+                (false, None) => {}
+            }
+        }
+        if let Some(next) = self.tx.pop_front() {
+            self.shifting = Some(next);
+            self.tx_countdown = self.character_time();
+            if self.tx.is_empty() {
+                self.thre_pending = true;
+            }
+        }
+    }
+
+    /// Once a character time: lets a byte in from the backend, follows its
+    /// modem status lines, and counts towards the FIFO timeout.
+    fn character_period(&mut self) {
+        if !self.loopback() {
+            let status = match &mut self.backend.0 {
+                Some(backend) => backend.modem_status(),
+                None => ModemStatus::default(),
+            };
+            self.set_modem_status(status);
+            if self.rx.len() < self.capacity() {
+                if let Some(data) = self.backend.0.as_mut().and_then(|b| b.read_byte()) {
+                    self.receive(data);
+                    return;
+                }
+            }
+        }
+        self.idle += 1;
+        if self.fifo_enabled && !self.rx.is_empty() && self.idle >= TIMEOUT_CHARACTERS {
+            self.timeout_pending = true;
+        }
+    }
+}
+
+impl Reset for UART {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        let backend = self.disconnect();
+        *self = UART::new(self.model, self.base, self.irq);
+        if let Some(backend) = backend {
+            self.connect(backend);
+        }
+    }
+}
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn test_uart_transmit_receive_and_fifo() {
+    struct Wire(Arc<Mutex<Vec<u8>>>, VecDeque<u8>);
+    impl SerialBackend for Wire {
+        fn write_byte(&mut self, value: u8) {
+            self.0.lock().unwrap().push(value);
+        }
+        fn read_byte(&mut self) -> Option<u8> {
+            self.1.pop_front()
+        }
+    }
+    let sent = Arc::new(Mutex::new(vec![]));
+    let mut uart = UART::com(0, UartModel::Ns16450);
+    uart.connect(Box::new(Wire(
+        sent.clone(),
+        VecDeque::from(vec![0x41, 0x42]),
+    )));
+
+    // 9600 baud 8N1 is ten bit times of divisor 12 a character.
+    uart.wb(0x3fb, 0x80);
+    uart.wb(0x3f8, 12);
+    uart.wb(0x3f9, 0);
+    uart.wb(0x3fb, 0x03);
+    assert_eq!(uart.line_settings().baud, 9600);
+    uart.wb(0x3fc, MCR_OUT2);
+    uart.wb(0x3f9, IER_RECEIVED | IER_THRE);
+    assert!(uart.irq());
+    assert_eq!(uart.rb(0x3fa), IIR_THRE);
+    assert!(!uart.irq());
+
+    // A byte goes straight to the shift register, the next one waits.
+    uart.wb(0x3f8, b'h');
+    uart.wb(0x3f8, b'i');
+    assert_eq!(uart.rb(0x3fd) & (LSR_THRE | LSR_TEMT), 0);
+    uart.tick(119);
+    assert!(sent.lock().unwrap().is_empty());
+    uart.tick(1);
+    assert_eq!(*sent.lock().unwrap(), b"h");
+    uart.tick(120);
+    assert_eq!(*sent.lock().unwrap(), b"hi");
+    assert_eq!(uart.rb(0x3fd) & (LSR_THRE | LSR_TEMT), LSR_THRE | LSR_TEMT);
+
+    // Received bytes come in once a character time, with the received
+    // data interrupt ahead of THRE.
+    uart.tick(120);
+    assert_eq!(uart.rb(0x3fa), IIR_RECEIVED);
+    assert_eq!(uart.rb(0x3f8), 0x41);
+    uart.tick(120);
+    assert_eq!(uart.rb(0x3fd) & LSR_DATA_READY, LSR_DATA_READY);
+    assert_eq!(uart.rb(0x3f8), 0x42);
+
+    // Loopback turns the outputs round, and overruns without a FIFO.
+    uart.rb(0x3fe);
+    uart.wb(0x3fc, MCR_LOOPBACK | MCR_RTS | MCR_OUT2);
+    assert_eq!(uart.rb(0x3fe), MSR_CTS | MSR_DCD | 0x02);
+    for data in [1, 2, 3] {
+        uart.wb(0x3f8, data);
+        uart.tick(120);
+    }
+    assert_eq!(uart.rb(0x3fd) & LSR_OVERRUN, LSR_OVERRUN);
+    assert_eq!(uart.rb(0x3f8), 3);
+
+    // The 16550's FIFO waits for its trigger level, or times out.
+    let mut uart = UART::com(1, UartModel::Ns16550);
+    uart.wb(0x2fa, 0x41);
+    uart.wb(0x2fc, MCR_LOOPBACK | MCR_OUT2);
+    uart.wb(0x2f9, IER_RECEIVED);
+    assert_eq!(uart.rb(0x2fa), IIR_NONE | IIR_FIFOS);
+    for data in 0..4 {
+        uart.wb(0x2f8, data);
+    }
+    uart.tick(uart.character_time() as usize * 4);
+    assert_eq!(uart.rb(0x2fa), IIR_RECEIVED | IIR_FIFOS);
+    assert_eq!(uart.rb(0x2f8), 0);
+    uart.tick(uart.character_time() as usize * TIMEOUT_CHARACTERS as usize);
+    assert_eq!(uart.rb(0x2fa), IIR_TIMEOUT | IIR_FIFOS);
+
+    // And only the 8250 has no scratch register.
+    uart.wb(0x2ff, 0x5a);
+    assert_eq!(uart.rb(0x2ff), 0x5a);
+    let mut uart = UART::com(0, UartModel::Ins8250);
+    uart.wb(0x3ff, 0x5a);
+    assert_eq!(uart.rb(0x3ff), 0xff);
+}
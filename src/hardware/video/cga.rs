@@ -0,0 +1,615 @@
+use crate::hardware::reset::{Reset, ResetKind};
+use crate::hardware::video::crtc::{Crtc6845, CrtcEvent};
+use crate::hardware::video::RGBI_PALETTE;
+use crate::renderer::Frame;
+use log::{trace, warn};
+use std::fs;
+use std::mem;
+
+/// The display area every BIOS mode uses, in hdots and lines. 40 column
+/// and 320 pixel modes draw each pixel two hdots wide.
+pub const CGA_WIDTH: u32 = 640;
+pub const CGA_HEIGHT: u32 = 200;
+pub const CGA_VRAM_SIZE: usize = 0x4000;
+
+/// Mode control register (3D8h) bits.
+const MODE_HIRES_TEXT: u8 = 0x01;
+const MODE_GRAPHICS: u8 = 0x02;
+const MODE_BW: u8 = 0x04;
+const MODE_ENABLE: u8 = 0x08;
+const MODE_HIRES_GRAPHICS: u8 = 0x10;
+const MODE_BLINK: u8 = 0x20;
+
+/// What an NTSC monitor makes of each 4-hdot pattern in 640x200 mode,
+/// leftmost hdot in bit 3. Each group is one cycle of the colour
+/// subcarrier, so the pattern's phase decides the hue.
+const COMPOSITE_PALETTE: [u32; 16] = [
+    0x000000, 0x006e31, 0x3109ff, 0x008aff, 0xa70031, 0x767676, 0xec11ff, 0xbb92ff, 0x315a00,
+    0x00db00, 0x767676, 0x45f7bb, 0xec6300, 0xbbe400, 0xff7fbb, 0xffffff,
+];
+
+/// The IBM Color Graphics Adapter: a 6845, 16K of video memory at B8000h
+/// (mirrored up to BFFFFh) and the mode and colour select registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CGA {
+    pub crtc: Crtc6845,
+    pub vram: Vec<u8>,
+    pub mode: u8,
+    pub color_select: u8,
+    /// Whether CPU accesses during 80 column text put snow on the screen,
+    /// as they did on the real card.
+    pub snow: bool,
+    /// Whether to show 640x200 graphics in the artifact colours of a
+    /// composite monitor rather than black and white.
+    pub composite: bool,
+    /// The 8x8 character set from the card's ROM.
+    font: Vec<u8>,
+    /// Hdots of the master clock left over from the last character.
+    hdots: usize,
+    /// The last complete frame, and the one being drawn.
+    framebuffer: Vec<u32>,
+    back_buffer: Vec<u32>,
+    next_line: usize,
+    /// The bytes the CPU got to instead of the card on the line being
+    /// displayed, by column.
+    snow_bytes: Vec<(usize, u8)>,
+}
+
+impl CGA {
+    pub fn new() -> CGA {
+        let rom = fs::read("roms/video/cga/cga.rom").unwrap_or_else(|err| {
+            warn!("Couldn't load the CGA character ROM: {}", err);
+            vec![]
+        });
+        // The ROM holds the MDA's font, then the CGA's thin and normal
+        // 8x8 fonts. A jumper picks the normal one.
+        let font = match rom.len() {
+            0x2000 => rom[0x1800..].to_vec(),
+            _ => vec![0; 0x800],
+        };
+        CGA {
+            crtc: Crtc6845::new(),
+            vram: vec![0; CGA_VRAM_SIZE],
+            mode: 0,
+            color_select: 0,
+            snow: false,
+            composite: false,
+            font,
+            hdots: 0,
+            framebuffer: vec![0; (CGA_WIDTH * CGA_HEIGHT) as usize],
+            back_buffer: vec![0; (CGA_WIDTH * CGA_HEIGHT) as usize],
+            next_line: 0,
+            snow_bytes: vec![],
+        }
+    }
+
+    /// The last frame the card finished drawing.
+    pub fn frame(&self) -> Frame<'_> {
+        Frame::new(CGA_WIDTH, CGA_HEIGHT, &self.framebuffer)
+    }
+
+    pub fn read_vram(&mut self, addr: u32) -> u8 {
+        let value = self.fetch(addr as usize);
+        self.contend(value);
+        value
+    }
+
+    pub fn write_vram(&mut self, addr: u32, value: u8) {
+        self.vram[addr as usize & (CGA_VRAM_SIZE - 1)] = value;
+        self.contend(value);
+    }
+
+    fn fetch(&self, offset: usize) -> u8 {
+        self.vram[offset & (CGA_VRAM_SIZE - 1)]
+    }
+
+    /// In 80 column text the card needs every memory cycle, so a CPU
+    /// access while the beam is displaying steals one: the card shows the
+    /// CPU's byte as both the character and attribute of the character
+    /// it was fetching.
+    fn contend(&mut self, value: u8) {
+        if !self.snow
+            || (self.mode & (MODE_HIRES_TEXT | MODE_GRAPHICS)) != MODE_HIRES_TEXT
+            || !self.crtc.display_enable()
+        {
+            return;
+        }
+        let column = self.crtc.address() - self.crtc.row_start();
+        self.snow_bytes.push((column, value));
+    }
+
+    /// A character is 8 hdots in 80 column text and 16 in everything
+    /// else.
+    fn char_width(&self) -> usize {
+        if (self.mode & MODE_HIRES_TEXT) != 0 {
+            8
+        } else {
+            16
+        }
+    }
+
+    /// Clocks the 6845 through `hdots`. Each line is drawn as the beam
+    /// leaves its display area, with the registers as they are then, and
+    /// the finished frame is shown when the beam gets back to the top.
+    pub fn tick(&mut self, hdots: usize) {
+        self.hdots += hdots;
+        let char_width = self.char_width();
+        while self.hdots >= char_width {
+            self.hdots -= char_width;
+            match self.crtc.tick() {
+                CrtcEvent::DisplayEnd => self.render_line(),
+                CrtcEvent::FrameStart if self.crtc.programmed() => self.finish_frame(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Hdots until the card shows its next frame, or `None` while the
+    /// 6845 hasn't been programmed and there's nothing to show.
+    pub fn hdots_until_frame(&self) -> Option<usize> {
+        if !self.crtc.programmed() {
+            return None;
+        }
+        let hdots = self.crtc.chars_until_frame_start() * self.char_width();
+        Some(hdots.saturating_sub(self.hdots).max(1))
+    }
+
+    /// Bit 0 is set while the beam is outside the display area, which is
+    /// when memory can be written without snow. Bit 3 is vertical sync.
+    fn status(&self) -> u8 {
+        0xf0 | !self.crtc.display_enable() as u8 | (self.crtc.vsync() as u8) << 3
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr & 0x0f {
+            0x0..=0x7 => self.crtc.rb(addr),
+            0xa => self.status(),
+            _ => 0xff,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr & 0x0f {
+            0x0..=0x7 => self.crtc.wb(addr, data),
+            0x8 => {
+                trace!(target: "video", "CGA mode {:#04x}", data);
+                self.mode = data & 0x3f;
+            }
+            0x9 => self.color_select = data & 0x3f,
+            _ => {}
+        }
+    }
+
+    /// In 640x200 graphics the colour select register picks the
+    /// foreground instead, and the border stays black.
+    fn border(&self) -> u32 {
+        if (self.mode & (MODE_ENABLE | MODE_HIRES_GRAPHICS)) == MODE_ENABLE {
+            RGBI_PALETTE[(self.color_select & 0x0f) as usize]
+        } else {
+            0
+        }
+    }
+
+    fn render_line(&mut self) {
+        let y = self.crtc.line();
+        if y >= CGA_HEIGHT as usize {
+            return;
+        }
+        let border = self.border();
+        let mut back_buffer = mem::take(&mut self.back_buffer);
+        let pixels = &mut back_buffer[y * CGA_WIDTH as usize..][..CGA_WIDTH as usize];
+        pixels.iter_mut().for_each(|pixel| *pixel = border);
+        if (self.mode & MODE_ENABLE) != 0 {
+            let char_width = self.char_width();
+            let columns = self
+                .crtc
+                .horizontal_displayed()
+                .min(CGA_WIDTH as usize / char_width);
+            let pixels = &mut pixels[..columns * char_width];
+            let (address, line) = (self.crtc.row_start(), self.crtc.row_address());
+            if (self.mode & MODE_GRAPHICS) != 0 {
+                self.render_graphics(pixels, address, line);
+            } else {
+                self.render_text(pixels, address, line, char_width);
+            }
+        }
+        self.back_buffer = back_buffer;
+        self.next_line = y + 1;
+        self.snow_bytes.clear();
+    }
+
+    /// Lines the 6845 didn't display are all border.
+    fn finish_frame(&mut self) {
+        let border = self.border();
+        let start = self.next_line.min(CGA_HEIGHT as usize) * CGA_WIDTH as usize;
+        self.back_buffer[start..]
+            .iter_mut()
+            .for_each(|pixel| *pixel = border);
+        mem::swap(&mut self.framebuffer, &mut self.back_buffer);
+        self.next_line = 0;
+    }
+
+    fn render_text(&self, pixels: &mut [u32], address: usize, line: usize, char_width: usize) {
+        // Characters blink every 16 frames, and the card blinks the cursor
+        // itself twice as fast whatever the 6845's blink bits say.
+        let blink = (self.crtc.frames() & 0x10) != 0;
+        let cursor_blink = (self.crtc.frames() & 0x08) != 0;
+        for (column, pixels) in pixels.chunks_mut(char_width).enumerate() {
+            let offset = (address + column) * 2;
+            let (character, attribute) = match self.snow_bytes.iter().find(|s| s.0 == column) {
+                Some(&(_, value)) => (value, value),
+                None => (self.fetch(offset), self.fetch(offset + 1)),
+            };
+            let mut foreground = attribute & 0x0f;
+            let mut background = attribute >> 4;
+            if (self.mode & MODE_BLINK) != 0 {
+                background &= 0x07;
+                if (attribute & 0x80) != 0 && blink {
+                    foreground = background;
+                }
+            }
+            let mut bits = self.font[character as usize * 8 + (line & 7)];
+            if cursor_blink && self.crtc.cursor(address + column, line) {
+                bits = 0xff;
+            }
+            for (x, pixel) in pixels.iter_mut().enumerate() {
+                let lit = (bits << (x * 8 / char_width)) & 0x80 != 0;
+                let color = if lit { foreground } else { background };
+                *pixel = RGBI_PALETTE[color as usize];
+            }
+        }
+    }
+
+    /// Each character's worth of a graphics mode is a word of memory: 8
+    /// pixels of 2 bits, or 16 of 1. Odd scanlines come from the second
+    /// 8K.
+    fn render_graphics(&self, pixels: &mut [u32], address: usize, line: usize) {
+        let bank = (line & 1) * 0x2000;
+        let colors = if (self.mode & MODE_HIRES_GRAPHICS) != 0 {
+            [0, self.color_select & 0x0f, 0, 0]
+        } else {
+            let intensity = (self.color_select & 0x10) >> 1;
+            let palette = match self.mode & MODE_BW {
+                0 if (self.color_select & 0x20) != 0 => [3, 5, 7],
+                0 => [2, 4, 6],
+                _ => [3, 4, 7],
+            };
+            [
+                self.color_select & 0x0f,
+                palette[0] | intensity,
+                palette[1] | intensity,
+                palette[2] | intensity,
+            ]
+        };
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let byte = self.fetch(address * 2 + bank + i / 8);
+            let color = if (self.mode & MODE_HIRES_GRAPHICS) != 0 {
+                (byte >> (7 - i % 8)) & 1
+            } else {
+                (byte >> (6 - (i / 2 % 4) * 2)) & 3
+            };
+            *pixel = RGBI_PALETTE[colors[color as usize] as usize];
+        }
+        if (self.mode & (MODE_HIRES_GRAPHICS | MODE_BW)) == MODE_HIRES_GRAPHICS && self.composite {
+            self.render_composite(pixels);
+        }
+    }
+
+    /// Recolours 640x200 graphics the way a composite monitor sees it.
+    /// The foreground colour doesn't matter to the pattern, only whether
+    /// each hdot is lit.
+    fn render_composite(&self, pixels: &mut [u32]) {
+        for group in pixels.chunks_mut(4) {
+            let pattern = group
+                .iter()
+                .fold(0, |pattern, &pixel| pattern << 1 | (pixel != 0) as usize);
+            let color = COMPOSITE_PALETTE[pattern << (4 - group.len())];
+            group.iter_mut().for_each(|pixel| *pixel = color);
+        }
+    }
+}
+
+impl Default for CGA {
+    fn default() -> CGA {
+        CGA::new()
+    }
+}
+
+// RESET DRV clears the mode register, which blanks the display until the
+// BIOS sets a mode. The 6845 has no reset of its own on this card.
+impl Reset for CGA {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        self.mode = 0;
+        self.color_select = 0;
+        if kind == ResetKind::Cold {
+            self.vram.iter_mut().for_each(|byte| *byte = 0);
+        }
+    }
+}
+
+#[test]
+fn test_cga_modes_and_status() {
+    let frame = 912 * 262;
+    let mut cga = CGA::new();
+    cga.font[0x41 * 8..0x42 * 8].copy_from_slice(&[0x81; 8]);
+    // 80x25 text with the cursor on the second character.
+    let text = [
+        0x71, 0x50, 0x5a, 0x0a, 0x1f, 0x06, 0x19, 0x1c, 0x02, 0x07, 0x06, 0x07, 0x00, 0x00, 0x00,
+        0x01,
+    ];
+    for (index, value) in text.iter().enumerate() {
+        cga.wb(0x3d4, index as u8);
+        cga.wb(0x3d5, *value);
+    }
+    cga.wb(0x3d8, 0x29);
+    cga.wb(0x3d9, 0x01);
+    assert_eq!(cga.rb(0x3d5), 1);
+    cga.write_vram(0xb_8000, 0x41);
+    cga.write_vram(0xb_8001, 0x1e);
+    cga.write_vram(0xb_8003, 0x07);
+    cga.tick(frame);
+    assert_eq!(&cga.framebuffer[..3], &[0xffff55, 0x0000aa, 0x0000aa]);
+    assert_eq!(cga.framebuffer[7], 0xffff55);
+    assert_eq!(cga.framebuffer[CGA_WIDTH as usize * 6 + 8], 0);
+    // The cursor shows from the ninth frame on.
+    cga.tick(frame * 8);
+    assert_eq!(cga.framebuffer[CGA_WIDTH as usize * 6 + 8], 0xaaaaaa);
+    // Without blinking, bit 7 gives bright backgrounds. Memory is
+    // mirrored every 16K.
+    cga.wb(0x3d8, 0x09);
+    cga.write_vram(0xb_c001, 0x9e);
+    cga.tick(frame * 8);
+    assert_eq!(&cga.framebuffer[..2], &[0xffff55, 0x5555ff]);
+    cga.wb(0x3d8, 0x29);
+    cga.tick(frame);
+    assert_eq!(&cga.framebuffer[..2], &[0x0000aa, 0x0000aa]);
+
+    // 320x200 with the cyan, magenta and white palette. Odd lines come
+    // from B8000h + 2000h.
+    let graphics = [0x38, 0x28, 0x2d, 0x0a, 0x7f, 0x06, 0x64, 0x70, 0x02, 0x01];
+    for (index, value) in graphics.iter().enumerate() {
+        cga.wb(0x3d4, index as u8);
+        cga.wb(0x3d5, *value);
+    }
+    cga.wb(0x3d8, 0x0a);
+    cga.wb(0x3d9, 0x30);
+    cga.write_vram(0xb_a000, 0x1b);
+    cga.tick(frame);
+    let line = &cga.framebuffer[CGA_WIDTH as usize..][..8];
+    assert_eq!(
+        line,
+        &[0, 0, 0x55ffff, 0x55ffff, 0xff55ff, 0xff55ff, 0xffffff, 0xffffff]
+    );
+    cga.wb(0x3d8, 0x1a);
+    cga.wb(0x3d9, 0x0f);
+    cga.tick(frame);
+    assert_eq!(cga.framebuffer[CGA_WIDTH as usize + 2], 0x000000);
+    assert_eq!(cga.framebuffer[CGA_WIDTH as usize + 3], 0xffffff);
+
+    // A background colour changed in the blanking after line 99 shows
+    // from line 100 down.
+    cga.wb(0x3d8, 0x0a);
+    cga.wb(0x3d9, 0x01);
+    cga.tick(912 * 99 + 656);
+    cga.wb(0x3d9, 0x04);
+    cga.tick(frame - 912 * 99 - 656);
+    assert_eq!(cga.framebuffer[CGA_WIDTH as usize * 99 + 8], 0x0000aa);
+    assert_eq!(cga.framebuffer[CGA_WIDTH as usize * 100 + 8], 0xaa0000);
+
+    assert_eq!(cga.rb(0x3da), 0xf0);
+    cga.tick(CGA_WIDTH as usize);
+    assert_eq!(cga.rb(0x3da), 0xf1);
+    cga.tick(912 * 230);
+    assert_eq!(cga.rb(0x3da), 0xf9);
+
+    // With snow on, a write while the 6845 is fetching the eleventh
+    // character shows there for a frame as both character and attribute.
+    let mut cga = CGA::new();
+    cga.font[0x41 * 8..0x42 * 8].copy_from_slice(&[0x81; 8]);
+    for (index, value) in text.iter().enumerate() {
+        cga.wb(0x3d4, index as u8);
+        cga.wb(0x3d5, *value);
+    }
+    cga.wb(0x3d8, 0x09);
+    cga.snow = true;
+    cga.tick(8 * 10);
+    cga.write_vram(0xb_8000, 0x41);
+    cga.tick(frame);
+    assert_eq!(&cga.framebuffer[80..82], &[0x0000aa, 0xaa0000]);
+    cga.tick(frame);
+    assert_eq!(cga.framebuffer[80], 0);
+
+    // Composite colours go by 4-hdot patterns in 640x200.
+    for (index, value) in graphics.iter().enumerate() {
+        cga.wb(0x3d4, index as u8);
+        cga.wb(0x3d5, *value);
+    }
+    cga.wb(0x3d8, 0x1a);
+    cga.wb(0x3d9, 0x0f);
+    cga.composite = true;
+    cga.write_vram(0xb_8000, 0x5c);
+    cga.tick(frame * 2);
+    assert_eq!(
+        &cga.framebuffer[2..6],
+        &[0x767676, 0x767676, 0xec6300, 0xec6300]
+    );
+}
+
+#[cfg(test)]
+fn program_crtc(cga: &mut CGA, regs: &[u8]) {
+    for (index, value) in regs.iter().enumerate() {
+        cga.wb(0x3d4, index as u8);
+        cga.wb(0x3d5, *value);
+    }
+}
+
+#[cfg(test)]
+const TEXT_80X25: [u8; 12] = [
+    0x71, 0x50, 0x5a, 0x0a, 0x1f, 0x06, 0x19, 0x1c, 0x02, 0x07, 0x06, 0x07,
+];
+
+#[cfg(test)]
+const GRAPHICS_320X200: [u8; 10] = [0x38, 0x28, 0x2d, 0x0a, 0x7f, 0x06, 0x64, 0x70, 0x02, 0x01];
+
+#[test]
+fn test_status_edges() {
+    // In 80 column text a character is 8 hdots, and 80 of the 114 are
+    // displayed.
+    let mut cga = CGA::new();
+    program_crtc(&mut cga, &TEXT_80X25);
+    cga.wb(0x3d8, 0x09);
+    cga.tick(8 * 79);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x00);
+    cga.tick(8);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x01);
+    cga.tick(8 * 33);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x01);
+    cga.tick(8);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x00);
+    // Past the last of the 200 displayed lines, bit 0 stays set.
+    cga.tick(912 * 198);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x00);
+    cga.tick(8 * 80);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x01);
+    cga.tick(912 - 8 * 80);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x01);
+    // Vertical sync runs from line 224 to 239.
+    cga.tick(912 * 24 - 8);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x01);
+    cga.tick(8);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x09);
+    cga.tick(912 * 16 - 8);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x09);
+    cga.tick(8);
+    assert_eq!(cga.rb(0x3da) & 0x09, 0x01);
+
+    // Everything else has 16 hdot characters, 40 of them displayed.
+    let mut cga = CGA::new();
+    program_crtc(&mut cga, &GRAPHICS_320X200);
+    cga.wb(0x3d8, 0x0a);
+    cga.tick(16 * 39);
+    assert_eq!(cga.rb(0x3da) & 0x01, 0x00);
+    cga.tick(16);
+    assert_eq!(cga.rb(0x3da) & 0x01, 0x01);
+    cga.tick(16 * 17);
+    assert_eq!(cga.rb(0x3da) & 0x01, 0x00);
+}
+
+#[test]
+fn test_palette_change_mid_frame() {
+    let frame = 912 * 262;
+    let mut cga = CGA::new();
+    program_crtc(&mut cga, &GRAPHICS_320X200);
+    // Every pixel colour 1, from the green, red and brown palette.
+    cga.vram.iter_mut().for_each(|byte| *byte = 0x55);
+    cga.wb(0x3d8, 0x0a);
+    cga.wb(0x3d9, 0x00);
+    cga.tick(frame);
+    // Partway through line 50, after it's been drawn up to the
+    // beam, switch to cyan, magenta and white.
+    cga.tick(912 * 50 + 16 * 20);
+    cga.wb(0x3d9, 0x20);
+    // The frame on show is still all green.
+    assert!(cga.framebuffer.iter().all(|&pixel| pixel == 0x00aa00));
+    cga.tick(frame - 912 * 50 - 16 * 20);
+    let line = |cga: &CGA, y: usize| {
+        cga.framebuffer[y * CGA_WIDTH as usize..][..CGA_WIDTH as usize].to_vec()
+    };
+    for y in 0..50 {
+        assert!(
+            line(&cga, y).iter().all(|&pixel| pixel == 0x00aa00),
+            "line {}",
+            y
+        );
+    }
+    // Each line is drawn as the beam leaves it, so the line the write came
+    // in is new all the way across.
+    for y in 50..CGA_HEIGHT as usize {
+        assert!(
+            line(&cga, y).iter().all(|&pixel| pixel == 0x00aaaa),
+            "line {}",
+            y
+        );
+    }
+    cga.tick(frame);
+    assert!(cga.framebuffer.iter().all(|&pixel| pixel == 0x00aaaa));
+}
+
+#[test]
+fn test_snow() {
+    let frame = 912 * 262;
+    let mut cga = CGA::new();
+    assert!(!cga.snow);
+    program_crtc(&mut cga, &TEXT_80X25);
+    cga.wb(0x3d8, 0x09);
+    // Off, a write during the display makes no snow.
+    cga.tick(8 * 10);
+    cga.write_vram(0xb_8000, 0x44);
+    assert!(cga.snow_bytes.is_empty());
+    cga.write_vram(0xb_8000, 0x00);
+
+    cga.snow = true;
+    cga.tick(frame - 8 * 10);
+    // Reads snow as well as writes, each on the column being fetched.
+    cga.tick(8 * 10);
+    cga.read_vram(0xb_8000);
+    cga.tick(8 * 5);
+    cga.write_vram(0xb_8002, 0x44);
+    assert_eq!(cga.snow_bytes, [(10, 0x00), (15, 0x44)]);
+    // Nothing while the beam is in the border or the blanking.
+    cga.tick(8 * 70);
+    cga.write_vram(0xb_8004, 0x44);
+    assert!(cga.snow_bytes.is_empty());
+    cga.tick(912 * 205);
+    cga.write_vram(0xb_8004, 0x44);
+    assert!(cga.snow_bytes.is_empty());
+    // The snow shows in the frame it fell in, in red on red.
+    cga.tick(frame - 912 * 205 - 8 * 85);
+    let row = &cga.framebuffer[8 * 15..8 * 16];
+    assert!(row.iter().all(|&pixel| pixel == 0xaa0000));
+
+    // 40 column text and graphics leave the card time to spare.
+    cga.tick(8 * 10);
+    cga.wb(0x3d8, 0x08);
+    cga.write_vram(0xb_8000, 0x44);
+    cga.wb(0x3d8, 0x0a);
+    cga.write_vram(0xb_8000, 0x44);
+    assert!(cga.snow_bytes.is_empty());
+}
+
+#[test]
+fn test_composite_artifact_colours() {
+    let frame = 912 * 262;
+    let mut cga = CGA::new();
+    program_crtc(&mut cga, &GRAPHICS_320X200);
+    // 640x200, white on black: 0001 0010, 0100 1000, 1111 0000.
+    cga.wb(0x3d8, 0x1a);
+    cga.wb(0x3d9, 0x0f);
+    cga.vram[..3].copy_from_slice(&[0x12, 0x48, 0xf0]);
+    cga.tick(frame);
+    let mono: Vec<u32> = [0x12u8, 0x48, 0xf0]
+        .iter()
+        .flat_map(|&byte| (0..8).map(move |bit| (byte << bit) & 0x80 != 0))
+        .map(|lit| if lit { 0xffffff } else { 0 })
+        .collect();
+    assert_eq!(&cga.framebuffer[..24], &mono[..]);
+
+    cga.composite = true;
+    cga.tick(frame);
+    let colours = [0x006e31, 0x3109ff, 0xa70031, 0x315a00, 0xffffff, 0x000000];
+    let expected: Vec<u32> = colours.iter().flat_map(|&colour| [colour; 4]).collect();
+    assert_eq!(&cga.framebuffer[..24], &expected[..]);
+    // The foreground colour makes no difference to the hue.
+    cga.wb(0x3d9, 0x01);
+    cga.tick(frame);
+    assert_eq!(&cga.framebuffer[..24], &expected[..]);
+
+    // 320x200 has no artifacts to show.
+    cga.wb(0x3d8, 0x0a);
+    cga.wb(0x3d9, 0x30);
+    cga.tick(frame);
+    assert_eq!(&cga.framebuffer[..4], &[0, 0, 0x55ffff, 0x55ffff]);
+}
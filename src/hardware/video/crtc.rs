@@ -0,0 +1,359 @@
+use log::trace;
+
+/// The bits of each register that exist.
+const MASKS: [u8; 16] = [
+    0xff, 0xff, 0xff, 0x0f, 0x7f, 0x1f, 0x7f, 0x7f, 0x03, 0x1f, 0x7f, 0x1f, 0x3f, 0xff, 0x3f, 0xff,
+];
+
+/// Vertical sync always lasts 16 lines on the 6845.
+const VSYNC_LINES: u8 = 16;
+
+/// What a character clock did besides moving the beam on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrtcEvent {
+    None,
+    /// The beam has just left the display area of a line, which can now
+    /// be drawn.
+    DisplayEnd,
+    /// The frame is over and the beam is back at the top.
+    FrameStart,
+}
+
+/// The Motorola 6845 CRT controller the MDA, CGA and Hercules cards are
+/// built around. The card clocks it once per character; it counts out the
+/// frame and says where the beam is, which memory address to fetch and
+/// whether the cursor is there.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Crtc6845 {
+    pub index: u8,
+    pub regs: [u8; 18],
+    /// The character in the line, the scanline in the character row and
+    /// the character row.
+    h: u8,
+    ra: u8,
+    row: u8,
+    /// Lines into the vertical total adjust at the bottom of the frame,
+    /// once the rows have run out.
+    adjust: Option<u8>,
+    vsync_left: u8,
+    /// The address of the current character and of the row's first.
+    ma: usize,
+    row_start: usize,
+    /// Scanlines since the start of the frame.
+    line: usize,
+    frames: u32,
+}
+
+impl Crtc6845 {
+    pub fn new() -> Crtc6845 {
+        Crtc6845::default()
+    }
+
+    pub fn horizontal_displayed(&self) -> usize {
+        self.regs[1] as usize
+    }
+
+    pub fn vertical_displayed(&self) -> usize {
+        self.regs[6] as usize
+    }
+
+    /// Scanlines per character row.
+    pub fn char_height(&self) -> usize {
+        self.regs[9] as usize + 1
+    }
+
+    /// In characters, which are words of video memory on these cards.
+    pub fn start_address(&self) -> usize {
+        (self.regs[12] as usize) << 8 | self.regs[13] as usize
+    }
+
+    pub fn cursor_address(&self) -> usize {
+        (self.regs[14] as usize) << 8 | self.regs[15] as usize
+    }
+
+    /// The first and last scanline of the cursor, or `None` if it's
+    /// turned off.
+    pub fn cursor_lines(&self) -> Option<(usize, usize)> {
+        if (self.regs[10] & 0x60) == 0x20 {
+            return None;
+        }
+        Some(((self.regs[10] & 0x1f) as usize, self.regs[11] as usize))
+    }
+
+    /// Whether the registers describe a picture at all. Until the BIOS
+    /// programs them the 6845 runs through tiny frames that aren't worth
+    /// drawing.
+    pub fn programmed(&self) -> bool {
+        self.regs[0] > 0 && self.regs[1] > 0 && self.regs[6] > 0
+    }
+
+    pub fn display_enable(&self) -> bool {
+        self.adjust.is_none()
+            && (self.h as usize) < self.horizontal_displayed()
+            && (self.row as usize) < self.vertical_displayed()
+    }
+
+    pub fn hsync(&self) -> bool {
+        let start = self.regs[2];
+        let width = self.regs[3] & 0x0f;
+        self.h >= start && (self.h as u16) < start as u16 + width as u16
+    }
+
+    pub fn vsync(&self) -> bool {
+        self.vsync_left > 0
+    }
+
+    /// The memory address the 6845 is putting out.
+    pub fn address(&self) -> usize {
+        self.ma
+    }
+
+    /// The address of the first character in the row.
+    pub fn row_start(&self) -> usize {
+        self.row_start
+    }
+
+    /// The scanline within the character row.
+    pub fn row_address(&self) -> usize {
+        self.ra as usize
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Counts frames, for the cards that blink things.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Whether the cursor is at this character and scanline. The cards
+    /// blink it themselves.
+    pub fn cursor(&self, address: usize, line: usize) -> bool {
+        self.cursor_lines()
+            .is_some_and(|(start, end)| start <= line && line <= end)
+            && address == self.cursor_address()
+    }
+
+    /// Characters until the beam gets back to the top, if the registers
+    /// stay as they are.
+    pub fn chars_until_frame_start(&self) -> usize {
+        let line_end = match self.regs[0].checked_sub(self.h) {
+            Some(left) => left as usize + 1,
+            None => 1,
+        };
+        let lines = match self.adjust {
+            Some(lines) => (self.regs[5] as usize).saturating_sub(lines as usize + 1),
+            None => {
+                let char_height = self.char_height();
+                let rows = (self.regs[4] as usize).saturating_sub(self.row as usize);
+                let ra = (self.regs[9] as usize).saturating_sub(self.ra as usize);
+                ra + rows * char_height + self.regs[5] as usize
+            }
+        };
+        line_end + lines * (self.regs[0] as usize + 1)
+    }
+
+    /// Moves on one character.
+    pub fn tick(&mut self) -> CrtcEvent {
+        self.h = self.h.wrapping_add(1);
+        self.ma += 1;
+        if self.h <= self.regs[0] {
+            let display_end = self.h == self.regs[1]
+                && self.adjust.is_none()
+                && (self.row as usize) < self.vertical_displayed();
+            return if display_end {
+                CrtcEvent::DisplayEnd
+            } else {
+                CrtcEvent::None
+            };
+        }
+        self.h = 0;
+        self.line += 1;
+        self.vsync_left = self.vsync_left.saturating_sub(1);
+        if let Some(lines) = self.adjust {
+            if lines + 1 >= self.regs[5] {
+                return self.start_frame();
+            }
+            self.adjust = Some(lines + 1);
+        } else if self.ra >= self.regs[9] {
+            self.ra = 0;
+            self.row = self.row.wrapping_add(1);
+            self.row_start += self.horizontal_displayed();
+            if self.row > self.regs[4] {
+                if self.regs[5] == 0 {
+                    return self.start_frame();
+                }
+                self.adjust = Some(0);
+            }
+            if self.row == self.regs[7] {
+                self.vsync_left = VSYNC_LINES;
+            }
+        } else {
+            self.ra += 1;
+        }
+        self.ma = self.row_start;
+        CrtcEvent::None
+    }
+
+    fn start_frame(&mut self) -> CrtcEvent {
+        self.ra = 0;
+        self.row = 0;
+        self.adjust = None;
+        self.line = 0;
+        self.row_start = self.start_address();
+        self.ma = self.row_start;
+        self.frames = self.frames.wrapping_add(1);
+        if self.regs[7] == 0 {
+            self.vsync_left = VSYNC_LINES;
+        }
+        CrtcEvent::FrameStart
+    }
+
+    /// Even ports select a register, odd ports access it. Only the cursor
+    /// and light pen registers can be read back.
+    pub fn rb(&self, addr: u16) -> u8 {
+        match self.index {
+            14..=17 if (addr & 1) != 0 => self.regs[self.index as usize],
+            _ => 0,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        if (addr & 1) == 0 {
+            self.index = data & 0x1f;
+        } else if let Some(mask) = MASKS.get(self.index as usize) {
+            trace!(target: "video", "CRTC R{} <- {:#04x}", self.index, data);
+            self.regs[self.index as usize] = data & mask;
+        }
+    }
+}
+
+#[test]
+fn test_crtc_timing() {
+    let mut crtc = Crtc6845::new();
+    // The CGA's 80x25 text mode, starting 80 characters in.
+    let regs = [
+        0x71, 0x50, 0x5a, 0x0a, 0x1f, 0x06, 0x19, 0x1c, 0x02, 0x07, 0x06, 0x07, 0x00, 0x50,
+    ];
+    for (index, value) in regs.iter().enumerate() {
+        crtc.wb(0x3d4, index as u8);
+        crtc.wb(0x3d5, *value);
+    }
+    assert!(crtc.programmed());
+    let mut chars = 0;
+    let mut lines = 0;
+    loop {
+        chars += 1;
+        match crtc.tick() {
+            CrtcEvent::DisplayEnd => lines += 1,
+            CrtcEvent::FrameStart => break,
+            CrtcEvent::None => {}
+        }
+    }
+    // 262 lines of 114 characters, 200 of them displayed.
+    assert_eq!((chars, lines), (262 * 114, 200));
+    assert_eq!(crtc.address(), 80);
+    assert!(crtc.display_enable() && !crtc.hsync());
+    for _ in 0..0x5a {
+        crtc.tick();
+    }
+    assert!(!crtc.display_enable() && crtc.hsync());
+    assert_eq!(crtc.address(), 80 + 0x5a);
+    for _ in 0..114 * 8 - 0x5a {
+        crtc.tick();
+    }
+    assert_eq!(
+        (crtc.line(), crtc.row_address(), crtc.address()),
+        (8, 0, 160)
+    );
+    // Vertical sync runs from row 28 for 16 lines.
+    for _ in 0..114 * (28 * 8 - 8) {
+        crtc.tick();
+    }
+    assert!(crtc.vsync() && !crtc.display_enable());
+    for _ in 0..114 * 16 {
+        crtc.tick();
+    }
+    assert!(!crtc.vsync());
+    assert_eq!(crtc.frames(), 1);
+}
+
+#[test]
+fn test_display_and_sync_edges() {
+    let mut crtc = Crtc6845::new();
+    // 80x25 text: 114 characters a line, 80 displayed, horizontal sync
+    // from character 90 for 10.
+    let regs = [
+        0x71, 0x50, 0x5a, 0x0a, 0x1f, 0x06, 0x19, 0x1c, 0x02, 0x07, 0x06, 0x07,
+    ];
+    for (index, value) in regs.iter().enumerate() {
+        crtc.wb(0x3d4, index as u8);
+        crtc.wb(0x3d5, *value);
+    }
+    for line in 0..2 {
+        for h in 0..114 {
+            assert_eq!(crtc.display_enable(), h < 80, "line {} char {}", line, h);
+            assert_eq!(
+                crtc.hsync(),
+                (90..100).contains(&h),
+                "line {} char {}",
+                line,
+                h
+            );
+            let event = crtc.tick();
+            assert_eq!(event == CrtcEvent::DisplayEnd, h == 79);
+        }
+    }
+    // The last displayed line, then the first of the border below.
+    for _ in 0..114 * 197 {
+        crtc.tick();
+    }
+    assert_eq!(crtc.line(), 199);
+    assert!(crtc.display_enable());
+    for _ in 0..114 {
+        crtc.tick();
+    }
+    assert!(!crtc.display_enable());
+    // Vertical sync starts with row 28 and lasts 16 lines.
+    for _ in 0..114 * 23 + 113 {
+        crtc.tick();
+    }
+    assert_eq!(crtc.line(), 223);
+    assert!(!crtc.vsync());
+    crtc.tick();
+    assert!(crtc.vsync());
+    for _ in 0..114 * 16 - 1 {
+        crtc.tick();
+    }
+    assert_eq!(crtc.line(), 239);
+    assert!(crtc.vsync());
+    crtc.tick();
+    assert!(!crtc.vsync());
+}
+
+#[test]
+fn test_chars_until_frame_start() {
+    let mut crtc = Crtc6845::new();
+    // 80x25 text again. The count holds from anywhere in the frame, the
+    // vertical total adjust at the bottom included.
+    let regs = [
+        0x71, 0x50, 0x5a, 0x0a, 0x1f, 0x06, 0x19, 0x1c, 0x02, 0x07, 0x06, 0x07,
+    ];
+    for (index, value) in regs.iter().enumerate() {
+        crtc.wb(0x3d4, index as u8);
+        crtc.wb(0x3d5, *value);
+    }
+    assert_eq!(crtc.chars_until_frame_start(), 262 * 114);
+    for skip in [0, 1, 113, 114 * 100 + 57, 114 * 256 + 3, 114 * 261 + 113] {
+        for _ in 0..skip {
+            crtc.tick();
+        }
+        let chars = crtc.chars_until_frame_start();
+        assert_eq!(chars, 262 * 114 - skip);
+        for _ in 1..chars {
+            assert_ne!(crtc.tick(), CrtcEvent::FrameStart);
+        }
+        assert_eq!(crtc.tick(), CrtcEvent::FrameStart);
+    }
+}
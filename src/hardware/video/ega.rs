@@ -0,0 +1,648 @@
+use crate::hardware::clock::DeviceClock;
+use crate::hardware::reset::{Reset, ResetKind};
+use crate::hardware::video::mda::MDA_CLOCK_HZ;
+use crate::hardware::video::RGBI_PALETTE;
+use crate::hardware::MASTER_CLOCK_HZ;
+use crate::renderer::Frame;
+use log::{debug, trace, warn};
+use std::fs;
+use std::mem;
+
+/// The biggest picture the EGA draws, in its 350 line modes.
+pub const EGA_MAX_WIDTH: u32 = 640;
+pub const EGA_MAX_HEIGHT: u32 = 350;
+pub const EGA_PLANE_SIZE: usize = 0x10000;
+pub const EGA_ROM_SIZE: usize = 0x4000;
+
+/// The switches on the card's back bracket, set for an Enhanced Color
+/// Display in its 350 line mode.
+const DEFAULT_SWITCHES: u8 = 0x09;
+
+/// Miscellaneous output register (3C2h) bits.
+const MISC_COLOR_IO: u8 = 0x01;
+const MISC_RAM_ENABLE: u8 = 0x02;
+/// Set for negative vertical sync, which tells the monitor to sync to 350
+/// lines.
+const MISC_350_LINES: u8 = 0x80;
+
+/// The sequencer, graphics controller, attribute controller and CRTC
+/// register indexes that matter here.
+const SEQ_CLOCKING: usize = 1;
+const SEQ_MAP_MASK: usize = 2;
+const SEQ_CHAR_MAP: usize = 3;
+const SEQ_MEMORY_MODE: usize = 4;
+const GC_SET_RESET: usize = 0;
+const GC_ENABLE_SET_RESET: usize = 1;
+const GC_COLOR_COMPARE: usize = 2;
+const GC_DATA_ROTATE: usize = 3;
+const GC_READ_MAP: usize = 4;
+const GC_MODE: usize = 5;
+const GC_MISC: usize = 6;
+const GC_COLOR_DONT_CARE: usize = 7;
+const GC_BIT_MASK: usize = 8;
+const ATTR_MODE: usize = 0x10;
+const ATTR_PLANE_ENABLE: usize = 0x12;
+
+/// The IBM Enhanced Graphics Adapter with all 256K fitted: four planes of
+/// 64K behind the sequencer and graphics controller, the attribute
+/// controller's palette, its own CRTC and a BIOS in an option ROM at
+/// C0000h.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EGA {
+    pub planes: [Vec<u8>; 4],
+    pub misc: u8,
+    pub seq_index: u8,
+    pub seq: [u8; 5],
+    pub gc_index: u8,
+    pub gc: [u8; 9],
+    pub attr_index: u8,
+    pub attr: [u8; 0x14],
+    pub crtc_index: u8,
+    pub crtc: [u8; 0x19],
+    pub switches: u8,
+    /// 3C0h takes an index and a data byte in turn. Reading input status 1
+    /// goes back to the index.
+    attr_data_next: bool,
+    latches: [u8; 4],
+    rom: Vec<u8>,
+    /// Turns master clock hdots into dots of the 16.257 MHz crystal.
+    fast_clock: DeviceClock,
+    /// Dots since the top left of the display area.
+    position: usize,
+    frames: u32,
+    /// The last complete frame and its size, and the one being drawn.
+    width: u32,
+    height: u32,
+    framebuffer: Vec<u32>,
+    back_buffer: Vec<u32>,
+    next_line: usize,
+}
+
+impl EGA {
+    pub fn new() -> EGA {
+        let rom =
+            fs::read("roms/video/ega/ibm_6277356_ega_card_u44_27128.bin").unwrap_or_else(|err| {
+                warn!("Couldn't load the EGA BIOS ROM: {}", err);
+                vec![]
+            });
+        EGA {
+            planes: [
+                vec![0; EGA_PLANE_SIZE],
+                vec![0; EGA_PLANE_SIZE],
+                vec![0; EGA_PLANE_SIZE],
+                vec![0; EGA_PLANE_SIZE],
+            ],
+            misc: 0,
+            seq_index: 0,
+            seq: [0; 5],
+            gc_index: 0,
+            gc: [0; 9],
+            attr_index: 0,
+            attr: [0; 0x14],
+            crtc_index: 0,
+            crtc: [0; 0x19],
+            switches: DEFAULT_SWITCHES,
+            attr_data_next: false,
+            latches: [0; 4],
+            rom,
+            fast_clock: DeviceClock::new(MASTER_CLOCK_HZ as u32, MDA_CLOCK_HZ),
+            position: 0,
+            frames: 0,
+            width: EGA_MAX_WIDTH,
+            height: EGA_MAX_HEIGHT,
+            framebuffer: vec![0; (EGA_MAX_WIDTH * EGA_MAX_HEIGHT) as usize],
+            back_buffer: vec![0; (EGA_MAX_WIDTH * EGA_MAX_HEIGHT) as usize],
+            next_line: 0,
+        }
+    }
+
+    pub fn frame(&self) -> Frame<'_> {
+        let pixels = &self.framebuffer[..(self.width * self.height) as usize];
+        Frame::new(self.width, self.height, pixels)
+    }
+
+    pub fn read_rom(&self, addr: u32) -> u8 {
+        let offset = addr as usize & (EGA_ROM_SIZE - 1);
+        self.rom.get(offset).copied().unwrap_or(0xff)
+    }
+
+    /// The window onto video memory the graphics controller has been set
+    /// to, as a base address and size.
+    fn memory_window(&self) -> (u32, u32) {
+        match (self.gc[GC_MISC] >> 2) & 3 {
+            0 => (0xa_0000, 0x2_0000),
+            1 => (0xa_0000, 0x1_0000),
+            2 => (0xb_0000, 0x8000),
+            _ => (0xb_8000, 0x8000),
+        }
+    }
+
+    pub fn decodes(&self, addr: u32) -> bool {
+        let (base, size) = self.memory_window();
+        (self.misc & MISC_RAM_ENABLE) != 0 && addr >= base && addr < base + size
+    }
+
+    /// The CRTC and input status 1 move between 3Bxh and 3Dxh with the
+    /// monitor type.
+    fn io_base(&self) -> u16 {
+        if (self.misc & MISC_COLOR_IO) != 0 {
+            0x3d0
+        } else {
+            0x3b0
+        }
+    }
+
+    /// Whether a port belongs to the card, given where its CRTC is.
+    pub fn claims(&self, addr: u16) -> bool {
+        matches!(addr, 0x3c0..=0x3cf) || (addr & 0xfff0) == self.io_base()
+    }
+
+    /// In odd/even mode even addresses go to planes 0 and 2 and odd ones
+    /// to 1 and 3, which is how text mode keeps characters and attributes
+    /// apart.
+    fn odd_even(&self) -> bool {
+        (self.seq[SEQ_MEMORY_MODE] & 0x04) == 0
+    }
+
+    pub fn read_vram(&mut self, addr: u32) -> u8 {
+        let mut offset = (addr - self.memory_window().0) as usize & (EGA_PLANE_SIZE - 1);
+        let mut plane = (self.gc[GC_READ_MAP] & 3) as usize;
+        if (self.gc[GC_MODE] & 0x10) != 0 {
+            plane = (plane & 2) | (offset & 1);
+            offset &= !1;
+        }
+        for (latch, data) in self.latches.iter_mut().zip(self.planes.iter()) {
+            *latch = data[offset];
+        }
+        if (self.gc[GC_MODE] & 0x08) == 0 {
+            return self.latches[plane];
+        }
+        // Read mode 1: a bit is set where every plane that isn't a don't
+        // care matches the colour compare register.
+        let mut result = 0xff;
+        for (i, latch) in self.latches.iter().enumerate() {
+            if (self.gc[GC_COLOR_DONT_CARE] >> i) & 1 != 0 {
+                let compare = if (self.gc[GC_COLOR_COMPARE] >> i) & 1 != 0 {
+                    0xff
+                } else {
+                    0x00
+                };
+                result &= !(latch ^ compare);
+            }
+        }
+        result
+    }
+
+    pub fn write_vram(&mut self, addr: u32, value: u8) {
+        let mut offset = (addr - self.memory_window().0) as usize & (EGA_PLANE_SIZE - 1);
+        let mut map_mask = self.seq[SEQ_MAP_MASK] & 0x0f;
+        if self.odd_even() {
+            map_mask &= if (offset & 1) != 0 { 0x0a } else { 0x05 };
+            offset &= !1;
+        }
+        let rotated = value.rotate_right((self.gc[GC_DATA_ROTATE] & 7) as u32);
+        let bit_mask = self.gc[GC_BIT_MASK];
+        for plane in 0..4 {
+            if (map_mask >> plane) & 1 == 0 {
+                continue;
+            }
+            let latch = self.latches[plane];
+            let set_reset = if (self.gc[GC_SET_RESET] >> plane) & 1 != 0 {
+                0xff
+            } else {
+                0x00
+            };
+            let data = match self.gc[GC_MODE] & 3 {
+                // Write mode 1 copies the latches, as loaded by the last
+                // read.
+                1 => {
+                    self.planes[plane][offset] = latch;
+                    continue;
+                }
+                2 if (value >> plane) & 1 != 0 => 0xff,
+                2 => 0x00,
+                _ if (self.gc[GC_ENABLE_SET_RESET] >> plane) & 1 != 0 => set_reset,
+                _ => rotated,
+            };
+            let data = match (self.gc[GC_DATA_ROTATE] >> 3) & 3 {
+                0 => data,
+                1 => data & latch,
+                2 => data | latch,
+                _ => data ^ latch,
+            };
+            self.planes[plane][offset] = (data & bit_mask) | (latch & !bit_mask);
+        }
+    }
+
+    /// The horizontal and vertical totals, and the display area, in dots
+    /// and lines. Totals the BIOS hasn't programmed yet fall back to the
+    /// CGA's, so an idle card doesn't draw a frame every few dots.
+    fn timing(&self) -> (usize, usize, usize, usize) {
+        let dot_width = if (self.seq[SEQ_CLOCKING] & 0x08) != 0 {
+            16
+        } else {
+            8
+        };
+        let overflow = self.crtc[0x07] as usize;
+        let h_total = (self.crtc[0x00] as usize + 2) * dot_width;
+        let v_total = (self.crtc[0x06] as usize | (overflow & 0x01) << 8) + 1;
+        let h_display = (self.crtc[0x01] as usize + 1) * dot_width;
+        let v_display = (self.crtc[0x12] as usize | (overflow & 0x02) << 7) + 1;
+        if h_total < 256 || v_total < 200 {
+            return (912, 262, 640, 200);
+        }
+        (h_total, v_total, h_display, v_display)
+    }
+
+    pub fn tick(&mut self, hdots: usize) {
+        let dots = match (self.misc >> 2) & 3 {
+            1 => self.fast_clock.ticks(hdots),
+            _ => hdots,
+        };
+        self.position += dots;
+        // Each line is drawn as the beam leaves its display area, so
+        // changes made in the blanking intervals show from the next line.
+        loop {
+            let (h_total, v_total, h_display, v_display) = self.timing();
+            let display_end = self.next_line * h_total + h_display;
+            let height = v_display.min(EGA_MAX_HEIGHT as usize);
+            if self.next_line < height && self.position >= display_end {
+                self.render_line(self.next_line);
+                self.next_line += 1;
+            } else if self.position >= h_total * v_total {
+                self.position -= h_total * v_total;
+                self.finish_frame();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Hdots of the master clock until the card shows its next frame.
+    pub fn hdots_until_frame(&self) -> usize {
+        let (h_total, v_total, _, _) = self.timing();
+        let dots = (h_total * v_total).saturating_sub(self.position).max(1);
+        match (self.misc >> 2) & 3 {
+            1 => self.fast_clock.cycles_until(dots),
+            _ => dots,
+        }
+    }
+
+    /// Input status 1. Bit 0 is set outside the display area and bit 3
+    /// during vertical sync.
+    fn status(&self) -> u8 {
+        let (h_total, _, h_display, v_display) = self.timing();
+        let line = self.position / h_total;
+        let dot = self.position % h_total;
+        let vsync_start = self.crtc[0x10] as usize | (self.crtc[0x07] as usize & 0x04) << 6;
+        // The end register only holds the low four bits of the line count.
+        let vsync_lines = match (self.crtc[0x11] as usize).wrapping_sub(vsync_start) & 0x0f {
+            0 => 16,
+            lines => lines,
+        };
+        let display = line < v_display && dot < h_display;
+        let vsync = line >= vsync_start && line < vsync_start + vsync_lines;
+        !display as u8 | (vsync as u8) << 3
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr {
+            // Input status 0 reads one of the switches, picked by the
+            // clock select bits.
+            0x3c2 => {
+                let switch = (self.misc >> 2) & 3;
+                ((self.switches & (8 >> switch)) != 0) as u8 * 0x10
+            }
+            _ if addr == self.io_base() + 0x0a => {
+                self.attr_data_next = false;
+                self.status()
+            }
+            // Only the cursor and light pen registers can be read back.
+            _ if (addr & 0xfff9) == self.io_base() + 0x01 => match self.crtc_index {
+                0x0c..=0x11 => self.crtc[self.crtc_index as usize],
+                _ => 0xff,
+            },
+            _ => 0xff,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x3c0 if self.attr_data_next => {
+                self.attr_data_next = false;
+                let index = (self.attr_index & 0x1f) as usize;
+                if let Some(register) = self.attr.get_mut(index) {
+                    *register = data;
+                }
+            }
+            0x3c0 => {
+                self.attr_data_next = true;
+                self.attr_index = data & 0x3f;
+            }
+            0x3c2 => {
+                trace!(target: "video", "EGA misc output {:#04x}", data);
+                self.misc = data;
+            }
+            0x3c4 => self.seq_index = data & 0x07,
+            0x3c5 => {
+                if let Some(register) = self.seq.get_mut(self.seq_index as usize) {
+                    *register = data;
+                }
+            }
+            0x3ce => self.gc_index = data & 0x0f,
+            0x3cf => {
+                if let Some(register) = self.gc.get_mut(self.gc_index as usize) {
+                    *register = data;
+                }
+            }
+            _ if (addr & 0xfff9) == self.io_base() => self.crtc_index = data & 0x1f,
+            _ if (addr & 0xfff9) == self.io_base() + 0x01 => {
+                trace!(target: "video", "EGA CRTC R{:#04x} <- {:#04x}", self.crtc_index, data);
+                if let Some(register) = self.crtc.get_mut(self.crtc_index as usize) {
+                    *register = data;
+                }
+            }
+            _ => debug!(target: "video", "Unknown EGA write {:#06x} <- {:#04x}", addr, data),
+        }
+    }
+
+    /// Looks a pixel's value up in the attribute controller's palette and
+    /// turns the 6 bit colour into RGB. In the 200 line modes the monitor
+    /// is a 5153, or an ECD pretending to be one, which takes bit 4 as
+    /// intensity.
+    fn color(&self, value: u8) -> u32 {
+        let color = self.attr[(value & self.attr[ATTR_PLANE_ENABLE] & 0x0f) as usize];
+        if (self.misc & MISC_350_LINES) == 0 {
+            return RGBI_PALETTE[((color & 0x07) | (color & 0x10) >> 1) as usize];
+        }
+        let channel = |primary: u8, secondary: u8| {
+            (((color >> primary) & 1) as u32 * 0xaa) + (((color >> secondary) & 1) as u32 * 0x55)
+        };
+        channel(2, 5) << 16 | channel(1, 4) << 8 | channel(0, 3)
+    }
+
+    /// Turns a CRTC address into an offset into the planes. In word mode
+    /// the CRTC counts words, and in the CGA compatible modes the row scan
+    /// counter's bit 0 stands in for address bit 13, as on the CGA.
+    fn plane_offset(&self, address: usize, line: usize) -> usize {
+        let mode = self.crtc[0x17];
+        let mut offset = if (mode & 0x40) != 0 {
+            address
+        } else {
+            address << 1
+        };
+        if (mode & 0x01) == 0 {
+            offset = (offset & !0x2000) | (line & 1) << 13;
+        }
+        offset & (EGA_PLANE_SIZE - 1)
+    }
+
+    /// Lines go into the back buffer at the widest stride, whatever the
+    /// mode, and the front buffer is packed once the frame is done.
+    fn render_line(&mut self, y: usize) {
+        let (_, _, h_display, _) = self.timing();
+        let width = h_display.min(EGA_MAX_WIDTH as usize);
+        let mut back_buffer = mem::take(&mut self.back_buffer);
+        let pixels = &mut back_buffer[y * EGA_MAX_WIDTH as usize..][..EGA_MAX_WIDTH as usize];
+        pixels[width..].iter_mut().for_each(|pixel| *pixel = 0);
+        let char_width = if (self.seq[SEQ_CLOCKING] & 0x08) != 0 {
+            16
+        } else {
+            8
+        };
+        let char_height = (self.crtc[0x09] & 0x1f) as usize + 1;
+        let start = (self.crtc[0x0c] as usize) << 8 | self.crtc[0x0d] as usize;
+        let stride = self.crtc[0x13] as usize * 2;
+        let (row, line) = (y / char_height, y % char_height);
+        for (column, pixels) in pixels[..width].chunks_mut(char_width).enumerate() {
+            let address = start + row * stride + column;
+            if (self.attr[ATTR_MODE] & 0x01) != 0 {
+                self.render_graphics(pixels, address, line);
+            } else {
+                self.render_text(pixels, address, line);
+            }
+        }
+        self.back_buffer = back_buffer;
+    }
+
+    fn finish_frame(&mut self) {
+        let (_, _, h_display, v_display) = self.timing();
+        self.width = (h_display as u32).min(EGA_MAX_WIDTH);
+        self.height = (v_display as u32).min(EGA_MAX_HEIGHT);
+        let width = self.width as usize;
+        for y in 0..self.height as usize {
+            let line = &self.back_buffer[y * EGA_MAX_WIDTH as usize..][..width];
+            if y < self.next_line {
+                self.framebuffer[y * width..][..width].copy_from_slice(line);
+            } else {
+                self.framebuffer[y * width..][..width]
+                    .iter_mut()
+                    .for_each(|pixel| *pixel = 0);
+            }
+        }
+        self.next_line = 0;
+        self.frames = self.frames.wrapping_add(1);
+    }
+
+    fn render_text(&self, pixels: &mut [u32], address: usize, line: usize) {
+        let offset = self.plane_offset(address, line);
+        let character = self.planes[0][offset] as usize;
+        let attribute = self.planes[1][offset];
+        let mut foreground = attribute & 0x0f;
+        let mut background = attribute >> 4;
+        if (self.attr[ATTR_MODE] & 0x08) != 0 {
+            background &= 0x07;
+            if (attribute & 0x80) != 0 && (self.frames & 0x10) != 0 {
+                foreground = background;
+            }
+        }
+        // Attribute bit 3 picks between two of the four fonts in plane 2.
+        let char_map = self.seq[SEQ_CHAR_MAP];
+        let map = if (attribute & 0x08) != 0 {
+            char_map & 3
+        } else {
+            (char_map >> 2) & 3
+        } as usize;
+        let mut bits = self.planes[2][map * 0x4000 + character * 32 + (line & 0x1f)];
+        let cursor = (self.crtc[0x0e] as usize) << 8 | self.crtc[0x0f] as usize;
+        let cursor_lines = (self.crtc[0x0a] & 0x1f) as usize..(self.crtc[0x0b] & 0x1f) as usize;
+        if address == cursor && cursor_lines.contains(&line) && (self.frames & 0x08) != 0 {
+            bits = 0xff;
+        }
+        let scale = pixels.len() / 8;
+        for (x, pixel) in pixels.iter_mut().enumerate() {
+            let lit = (bits << (x / scale)) & 0x80 != 0;
+            *pixel = self.color(if lit { foreground } else { background });
+        }
+    }
+
+    /// Planar graphics take a bit from each plane for each pixel. The CGA
+    /// compatible modes instead shift pairs of bits out of planes 0 and 1
+    /// in turn.
+    fn render_graphics(&self, pixels: &mut [u32], address: usize, line: usize) {
+        let offset = self.plane_offset(address, line);
+        let scale = pixels.len() / 8;
+        for (x, pixel) in pixels.iter_mut().enumerate() {
+            let x = x / scale;
+            let value = if (self.gc[GC_MODE] & 0x20) != 0 {
+                let byte = self.planes[x / 4][offset];
+                (byte >> (6 - (x % 4) * 2)) & 3
+            } else {
+                (0..4).fold(0, |value, plane| {
+                    value | ((self.planes[plane][offset] >> (7 - x)) & 1) << plane
+                })
+            };
+            *pixel = self.color(value);
+        }
+    }
+}
+
+impl Default for EGA {
+    fn default() -> EGA {
+        EGA::new()
+    }
+}
+
+// RESET DRV leaves the registers blank for the card's BIOS to program.
+impl Reset for EGA {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        let planes = mem::take(&mut self.planes);
+        let rom = mem::take(&mut self.rom);
+        *self = EGA {
+            planes,
+            rom,
+            switches: self.switches,
+            ..EGA::new()
+        };
+        if kind == ResetKind::Cold {
+            for plane in self.planes.iter_mut() {
+                plane.iter_mut().for_each(|byte| *byte = 0);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_ega_planes_and_modes() {
+    let mut ega = EGA::new();
+    // Mode 10h's essentials: 640x350 planar graphics at A0000h.
+    ega.wb(0x3c2, 0xa7);
+    for (index, value) in [(1, 0x01), (2, 0x0f), (4, 0x06)].iter() {
+        ega.wb(0x3c4, *index);
+        ega.wb(0x3c5, *value);
+    }
+    for (index, value) in [(5, 0x00), (6, 0x05), (8, 0xff)].iter() {
+        ega.wb(0x3ce, *index);
+        ega.wb(0x3cf, *value);
+    }
+    for (index, value) in [
+        (0x00, 0x5b),
+        (0x01, 0x4f),
+        (0x06, 0x6c),
+        (0x07, 0x1f),
+        (0x10, 0x5e),
+        (0x11, 0x2b),
+        (0x12, 0x5d),
+        (0x13, 0x28),
+        (0x17, 0xe3),
+    ]
+    .iter()
+    {
+        ega.wb(0x3d4, *index);
+        ega.wb(0x3d5, *value);
+    }
+    ega.rb(0x3da);
+    for index in 0..16 {
+        ega.wb(0x3c0, index);
+        ega.wb(0x3c0, index);
+    }
+    ega.wb(0x3c0, 0x10);
+    ega.wb(0x3c0, 0x01);
+    ega.wb(0x3c0, 0x12);
+    ega.wb(0x3c0, 0x0f);
+    assert!(ega.decodes(0xa_0000) && !ega.decodes(0xb_8000));
+
+    // Write mode 0 with set/reset on planes 0 and 2, masked to one pixel.
+    ega.wb(0x3ce, 0);
+    ega.wb(0x3cf, 0x05);
+    ega.wb(0x3ce, 1);
+    ega.wb(0x3cf, 0x0f);
+    ega.wb(0x3ce, 8);
+    ega.wb(0x3cf, 0x80);
+    ega.write_vram(0xa_0000, 0xff);
+    assert_eq!(
+        [ega.planes[0][0], ega.planes[1][0], ega.planes[2][0]],
+        [0x80, 0x00, 0x80]
+    );
+    // Write mode 2 ORed onto the latches for the next pixel.
+    ega.wb(0x3ce, 3);
+    ega.wb(0x3cf, 0x10);
+    ega.wb(0x3ce, 5);
+    ega.wb(0x3cf, 0x02);
+    ega.wb(0x3ce, 8);
+    ega.wb(0x3cf, 0x40);
+    ega.read_vram(0xa_0000);
+    ega.write_vram(0xa_0000, 0x0a);
+    assert_eq!(
+        [ega.planes[0][0], ega.planes[1][0], ega.planes[3][0]],
+        [0x80, 0x40, 0x40]
+    );
+    // Write mode 1 copies a byte through the latches.
+    ega.wb(0x3cf, 0x01);
+    ega.read_vram(0xa_0000);
+    ega.write_vram(0xa_0050, 0);
+    assert_eq!(ega.planes[2][0x50], 0x80);
+    // Read mode 1 finds colour 5 in the first pixel.
+    ega.wb(0x3cf, 0x08);
+    ega.wb(0x3ce, 2);
+    ega.wb(0x3cf, 0x05);
+    ega.wb(0x3ce, 7);
+    ega.wb(0x3cf, 0x0f);
+    assert_eq!(ega.read_vram(0xa_0000), 0x80);
+    ega.wb(0x3ce, 4);
+    ega.wb(0x3cf, 1);
+    ega.wb(0x3ce, 5);
+    ega.wb(0x3cf, 0x00);
+    assert_eq!(ega.read_vram(0xa_0000), 0x40);
+
+    ega.tick(912 * 262);
+    ega.tick(912 * 262);
+    let frame = ega.frame();
+    assert_eq!((frame.width, frame.height), (640, 350));
+    assert_eq!(&frame.pixels[..3], &[0xaa00aa, 0x00aa55, 0x000000]);
+    assert_eq!(frame.pixels[640], 0xaa00aa);
+
+    // 80x25 text: characters in plane 0, attributes in plane 1 and the
+    // font in plane 2.
+    ega.wb(0x3c0, 0x10);
+    ega.wb(0x3c0, 0x00);
+    ega.wb(0x3c0, 0x0e);
+    ega.wb(0x3c0, 0x3e);
+    for (index, value) in [(1, 0x00), (3, 0x00), (8, 0xff)].iter() {
+        ega.wb(0x3ce, *index);
+        ega.wb(0x3cf, *value);
+    }
+    ega.wb(0x3ce, 6);
+    ega.wb(0x3cf, 0x0e);
+    ega.wb(0x3c4, 4);
+    ega.wb(0x3c5, 0x02);
+    ega.wb(0x3ce, 5);
+    ega.wb(0x3cf, 0x10);
+    ega.wb(0x3d4, 0x09);
+    ega.wb(0x3d5, 0x0d);
+    ega.wb(0x3d4, 0x17);
+    ega.wb(0x3d5, 0xa3);
+    ega.write_vram(0xb_8000, 0x41);
+    ega.write_vram(0xb_8001, 0x1e);
+    ega.planes[2][0x41 * 32 + 3] = 0x81;
+    ega.tick(912 * 262);
+    ega.tick(912 * 262);
+    let frame = ega.frame();
+    assert_eq!(&frame.pixels[640 * 3..][..2], &[0xffff55, 0x0000aa]);
+    assert_eq!(ega.rb(0x3c2), 0x00);
+    ega.wb(0x3c2, 0x23);
+    assert_eq!(ega.rb(0x3c2), 0x10);
+}
@@ -0,0 +1,350 @@
+use crate::hardware::reset::{Reset, ResetKind};
+use crate::hardware::video::crtc::{Crtc6845, CrtcEvent};
+use crate::renderer::Frame;
+use log::{trace, warn};
+use std::fs;
+use std::mem;
+
+/// The MDA's 16.257 MHz dot clock, which the Hercules card shares.
+pub const MDA_CLOCK_HZ: u32 = 16_257_000;
+/// 80x25 characters of 9x14 dots. Hercules graphics use the top 348
+/// lines.
+pub const MDA_WIDTH: u32 = 720;
+pub const MDA_HEIGHT: u32 = 350;
+
+/// The shades of the 5151's green phosphor.
+const MONO_OFF: u32 = 0x000000;
+const MONO_NORMAL: u32 = 0x00aa00;
+const MONO_BRIGHT: u32 = 0x55ff55;
+
+/// Mode control register (3B8h) bits. The Hercules card adds graphics
+/// and the choice of display page.
+const MODE_GRAPHICS: u8 = 0x02;
+const MODE_ENABLE: u8 = 0x08;
+const MODE_BLINK: u8 = 0x20;
+const MODE_PAGE1: u8 = 0x80;
+
+/// Hercules configuration switch (3BFh) bits.
+const CONFIG_ALLOW_GRAPHICS: u8 = 0x01;
+const CONFIG_UPPER_PAGE: u8 = 0x02;
+
+/// The IBM Monochrome Display Adapter, or the Hercules Graphics Card that
+/// adds a 720x348 graphics mode and 64K of memory to it. Both sit at
+/// B0000h and ports 3B0h-3BFh, out of the CGA's way, so a machine can
+/// have one of each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MDA {
+    pub crtc: Crtc6845,
+    pub vram: Vec<u8>,
+    pub mode: u8,
+    pub hercules: bool,
+    pub config: u8,
+    /// The 9x14 character set, rows 0-7 of every character followed by
+    /// rows 8-13.
+    font: Vec<u8>,
+    /// Dots left over from the last character.
+    dots: usize,
+    /// The last complete frame, and the one being drawn.
+    framebuffer: Vec<u32>,
+    back_buffer: Vec<u32>,
+    next_line: usize,
+}
+
+impl MDA {
+    pub fn new() -> MDA {
+        let rom = fs::read("roms/video/mda/mda.rom").unwrap_or_else(|err| {
+            warn!("Couldn't load the MDA character ROM: {}", err);
+            vec![]
+        });
+        let font = match rom.len() {
+            0x2000 => rom[..0x1000].to_vec(),
+            _ => vec![0; 0x1000],
+        };
+        MDA {
+            crtc: Crtc6845::new(),
+            vram: vec![0; 0x1000],
+            mode: 0,
+            hercules: false,
+            config: 0,
+            font,
+            dots: 0,
+            framebuffer: vec![0; (MDA_WIDTH * MDA_HEIGHT) as usize],
+            back_buffer: vec![0; (MDA_WIDTH * MDA_HEIGHT) as usize],
+            next_line: 0,
+        }
+    }
+
+    pub fn hercules() -> MDA {
+        MDA {
+            vram: vec![0; 0x10000],
+            hercules: true,
+            ..MDA::new()
+        }
+    }
+
+    pub fn frame(&self) -> Frame<'_> {
+        Frame::new(MDA_WIDTH, MDA_HEIGHT, &self.framebuffer)
+    }
+
+    /// The MDA's 4K repeats through B0000h-B7FFFh. The Hercules card's
+    /// second page at B8000h is only there once it's been switched on, so
+    /// that it doesn't clash with a CGA.
+    pub fn decodes(&self, addr: u32) -> bool {
+        match addr {
+            0xb_0000..=0xb_7fff => true,
+            0xb_8000..=0xb_ffff => self.hercules && (self.config & CONFIG_UPPER_PAGE) != 0,
+            _ => false,
+        }
+    }
+
+    pub fn read_vram(&self, addr: u32) -> u8 {
+        self.vram[addr as usize & (self.vram.len() - 1)]
+    }
+
+    pub fn write_vram(&mut self, addr: u32, value: u8) {
+        let mask = self.vram.len() - 1;
+        self.vram[addr as usize & mask] = value;
+    }
+
+    pub fn tick(&mut self, dots: usize) {
+        self.dots += dots;
+        let char_width = self.char_width();
+        while self.dots >= char_width {
+            self.dots -= char_width;
+            match self.crtc.tick() {
+                CrtcEvent::DisplayEnd => self.render_line(),
+                CrtcEvent::FrameStart if self.crtc.programmed() => self.finish_frame(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Dots until the card shows its next frame, or `None` while the 6845
+    /// hasn't been programmed and there's nothing to show.
+    pub fn dots_until_frame(&self) -> Option<usize> {
+        if !self.crtc.programmed() {
+            return None;
+        }
+        let dots = self.crtc.chars_until_frame_start() * self.char_width();
+        Some(dots.saturating_sub(self.dots).max(1))
+    }
+
+    fn graphics(&self) -> bool {
+        self.hercules && (self.mode & MODE_GRAPHICS) != 0
+    }
+
+    /// Characters are 9 dots wide, and a word of Hercules graphics is 16.
+    fn char_width(&self) -> usize {
+        if self.graphics() {
+            16
+        } else {
+            9
+        }
+    }
+
+    /// Bit 0 is horizontal sync. Bit 7 is the Hercules card's vertical
+    /// sync, low during the retrace, which is how software tells it apart
+    /// from an MDA.
+    fn status(&self) -> u8 {
+        let vsync = self.hercules && self.crtc.vsync();
+        0xf0 & !((vsync as u8) << 7) | self.crtc.hsync() as u8
+    }
+
+    pub fn rb(&mut self, addr: u16) -> u8 {
+        match addr & 0x0f {
+            0x0..=0x7 => self.crtc.rb(addr),
+            0xa => self.status(),
+            _ => 0xff,
+        }
+    }
+
+    pub fn wb(&mut self, addr: u16, data: u8) {
+        match addr & 0x0f {
+            0x0..=0x7 => self.crtc.wb(addr, data),
+            0x8 => {
+                trace!(target: "video", "MDA mode {:#04x}", data);
+                self.mode = match self.hercules {
+                    // Graphics and page 1 need the configuration switch to
+                    // allow them.
+                    true if (self.config & CONFIG_ALLOW_GRAPHICS) == 0 => data & 0x2d,
+                    true if (self.config & CONFIG_UPPER_PAGE) == 0 => data & 0x2f,
+                    true => data & 0xaf,
+                    false => data & 0x2d,
+                };
+            }
+            0xf if self.hercules => self.config = data & 0x03,
+            _ => {}
+        }
+    }
+
+    fn render_line(&mut self) {
+        let y = self.crtc.line();
+        if y >= MDA_HEIGHT as usize {
+            return;
+        }
+        let mut back_buffer = mem::take(&mut self.back_buffer);
+        let pixels = &mut back_buffer[y * MDA_WIDTH as usize..][..MDA_WIDTH as usize];
+        pixels.iter_mut().for_each(|pixel| *pixel = MONO_OFF);
+        if (self.mode & MODE_ENABLE) != 0 {
+            let char_width = self.char_width();
+            let columns = self
+                .crtc
+                .horizontal_displayed()
+                .min(MDA_WIDTH as usize / char_width);
+            let pixels = &mut pixels[..columns * char_width];
+            let (address, line) = (self.crtc.row_start(), self.crtc.row_address());
+            if self.graphics() {
+                self.render_graphics(pixels, address, line);
+            } else {
+                self.render_text(pixels, address, line);
+            }
+        }
+        self.back_buffer = back_buffer;
+        self.next_line = y + 1;
+    }
+
+    fn finish_frame(&mut self) {
+        let start = self.next_line.min(MDA_HEIGHT as usize) * MDA_WIDTH as usize;
+        self.back_buffer[start..]
+            .iter_mut()
+            .for_each(|pixel| *pixel = MONO_OFF);
+        mem::swap(&mut self.framebuffer, &mut self.back_buffer);
+        self.next_line = 0;
+    }
+
+    fn render_text(&self, pixels: &mut [u32], address: usize, line: usize) {
+        let blink = (self.crtc.frames() & 0x10) != 0;
+        let cursor_blink = (self.crtc.frames() & 0x08) != 0;
+        for (column, pixels) in pixels.chunks_mut(9).enumerate() {
+            let offset = (address + column) * 2;
+            let character = self.read_vram(offset as u32) as usize;
+            let attribute = self.read_vram(offset as u32 + 1);
+            // There are no colours, only a few combinations that mean
+            // something: nothing, reverse video, underline and bright.
+            let bright_background = (self.mode & MODE_BLINK) == 0 && (attribute & 0x80) != 0;
+            let (mut on, off) = match attribute & 0x77 {
+                0x00 => (MONO_OFF, MONO_OFF),
+                0x70 if bright_background => (MONO_OFF, MONO_BRIGHT),
+                0x70 => (MONO_OFF, MONO_NORMAL),
+                _ if (attribute & 0x08) != 0 => (MONO_BRIGHT, MONO_OFF),
+                _ => (MONO_NORMAL, MONO_OFF),
+            };
+            if (self.mode & MODE_BLINK) != 0 && (attribute & 0x80) != 0 && blink {
+                on = off;
+            }
+            let bits = match line {
+                0..=7 => self.font[character * 8 + line],
+                8..=13 => self.font[0x800 + character * 8 + line - 8],
+                _ => 0,
+            };
+            // Line drawing characters carry their last column on into the
+            // ninth so boxes join up.
+            let mut bits = (bits as u16) << 1;
+            if (0xc0..=0xdf).contains(&character) {
+                bits |= bits >> 1 & 1;
+            }
+            if (attribute & 0x77) == 0x01 && line == 12 {
+                bits = 0x1ff;
+            }
+            if cursor_blink && self.crtc.cursor(address + column, line) {
+                bits = 0x1ff;
+            }
+            for (x, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = if (bits << x) & 0x100 != 0 { on } else { off };
+            }
+        }
+    }
+
+    /// Hercules graphics interleave four banks of 8K, one for each
+    /// scanline of a character row, and each word is 16 pixels.
+    fn render_graphics(&self, pixels: &mut [u32], address: usize, line: usize) {
+        let page = if (self.mode & MODE_PAGE1) != 0 {
+            0x8000
+        } else {
+            0
+        };
+        let base = page + (line & 3) * 0x2000 + address * 2;
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let byte = self.read_vram((base + i / 8) as u32);
+            *pixel = if (byte << (i % 8)) & 0x80 != 0 {
+                MONO_NORMAL
+            } else {
+                MONO_OFF
+            };
+        }
+    }
+}
+
+impl Default for MDA {
+    fn default() -> MDA {
+        MDA::new()
+    }
+}
+
+impl Reset for MDA {
+    fn reset(&mut self, kind: ResetKind) {
+        if kind == ResetKind::Warm {
+            return;
+        }
+        self.mode = 0;
+        self.config = 0;
+        if kind == ResetKind::Cold {
+            self.vram.iter_mut().for_each(|byte| *byte = 0);
+        }
+    }
+}
+
+#[test]
+fn test_mda_and_hercules() {
+    let text = [
+        0x61, 0x50, 0x52, 0x0f, 0x19, 0x06, 0x19, 0x19, 0x02, 0x0d, 0x20, 0x0c,
+    ];
+    let graphics = [0x35, 0x2d, 0x2e, 0x07, 0x5b, 0x02, 0x57, 0x57, 0x02, 0x03];
+    let mut mda = MDA::new();
+    mda.font[0xc4 * 8..0xc5 * 8].copy_from_slice(&[0x81; 8]);
+    for (index, value) in text.iter().enumerate() {
+        mda.wb(0x3b4, index as u8);
+        mda.wb(0x3b5, *value);
+    }
+    mda.wb(0x3b8, 0x29);
+    assert!(!mda.decodes(0xb_8000));
+    // 4K, repeated.
+    mda.write_vram(0xb_1000, 0xc4);
+    mda.write_vram(0xb_0001, 0x0f);
+    mda.write_vram(0xb_0003, 0x70);
+    mda.write_vram(0xb_0005, 0x01);
+    mda.tick(882 * 370);
+    let row = &mda.framebuffer[..27];
+    assert_eq!(&row[..2], &[MONO_BRIGHT, MONO_OFF]);
+    assert_eq!(&row[7..9], &[MONO_BRIGHT, MONO_BRIGHT]);
+    assert_eq!(row[9], MONO_NORMAL);
+    assert_eq!(row[18], MONO_OFF);
+    assert_eq!(mda.framebuffer[MDA_WIDTH as usize * 12 + 18], MONO_NORMAL);
+    // Horizontal sync starts at character 52h. There's no Hercules, so
+    // nothing happens to bit 7 in the vertical retrace.
+    assert_eq!(mda.rb(0x3ba), 0xf0);
+    mda.tick(9 * 0x52);
+    assert_eq!(mda.rb(0x3ba), 0xf1);
+    mda.tick(882 * 350);
+    assert_eq!(mda.rb(0x3ba), 0xf1);
+
+    let mut hercules = MDA::hercules();
+    hercules.wb(0x3b8, 0x8a);
+    assert_eq!(hercules.mode, 0x08);
+    hercules.wb(0x3bf, 0x03);
+    assert!(hercules.decodes(0xb_8000));
+    for (index, value) in graphics.iter().enumerate() {
+        hercules.wb(0x3b4, index as u8);
+        hercules.wb(0x3b5, *value);
+    }
+    hercules.wb(0x3b8, 0x8a);
+    hercules.write_vram(0xb_e05a, 0x40);
+    hercules.tick(864 * 370);
+    assert_eq!(
+        hercules.framebuffer[MDA_WIDTH as usize * 7 + 1],
+        MONO_NORMAL
+    );
+    assert_eq!(hercules.rb(0x3ba), 0xf0);
+    hercules.tick(864 * 348);
+    assert_eq!(hercules.rb(0x3ba), 0x70);
+}
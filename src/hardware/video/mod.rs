@@ -0,0 +1,13 @@
+// Video adapters. Each card renders into a framebuffer of its own, which
+// the frontend shows through `renderer::Frame`.
+pub mod cga;
+pub mod crtc;
+pub mod ega;
+pub mod mda;
+
+/// The 16 colours of the IBM 5153 RGBI monitor, as 0x00RRGGBB. The
+/// monitor turns dark yellow into brown.
+pub const RGBI_PALETTE: [u32; 16] = [
+    0x000000, 0x0000aa, 0x00aa00, 0x00aaaa, 0xaa0000, 0xaa00aa, 0xaa5500, 0xaaaaaa, 0x555555,
+    0x5555ff, 0x55ff55, 0x55ffff, 0xff5555, 0xff55ff, 0xffff55, 0xffffff,
+];
@@ -0,0 +1,254 @@
+// Running with no display until something says the run is over, for
+// automated testing of BIOSes and operating systems coming up. Each
+// condition is one of
+//
+//     instructions=N      N instructions have run
+//     at=SSSS:OOOO        the CPU is about to run the instruction at CS:IP
+//     port=PPPP[:VV]      software writes to a port, or writes VV to it
+//     text=STRING         STRING is somewhere in the text memory
+//
+// with the addresses and values in hex, optionally after the exit status
+// the run should end with and a colon, which is 0 otherwise. So
+//
+//     --exit-when port=e9:00 --exit-when 1:port=e9:ff --exit-when 2:instructions=100000000
+//
+// is a test program that passes or fails by writing to port E9h, with a
+// time limit. Whichever condition is met first ends the run, and `dump`
+// shows the state the machine was left in.
+use crate::hardware::breakpoints::Breakpoint;
+use crate::hardware::machine::Machine;
+use crate::hardware::StopReason;
+use std::fmt;
+
+/// How close to an instruction limit the run goes a frame at a time. A
+/// frame never has more instructions than this, so closer than this they
+/// run one by one up to the limit.
+const FRAME_INSTRUCTIONS: u64 = 1_000_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Instructions(u64),
+    Address(u16, u16),
+    PortWrite(u16, Option<u8>),
+    Text(String),
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Instructions(count) => write!(f, "{} instructions run", count),
+            Condition::Address(cs, ip) => write!(f, "reached {:04X}:{:04X}", cs, ip),
+            Condition::PortWrite(port, None) => write!(f, "port {:X}h written", port),
+            Condition::PortWrite(port, Some(value)) => {
+                write!(f, "{:02X}h written to port {:X}h", value, port)
+            }
+            Condition::Text(text) => write!(f, "\"{}\" on the screen", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExitCondition {
+    pub condition: Condition,
+    /// What the process exits with when it's met.
+    pub status: i32,
+}
+
+impl ExitCondition {
+    /// Parses a condition as the command line has it, e.g. `1:at=f000:e05b`.
+    pub fn parse(spec: &str) -> Result<ExitCondition, String> {
+        let (status, spec) = match spec.split_once(':') {
+            Some((status, rest)) if !status.contains('=') => {
+                let status = status
+                    .parse()
+                    .map_err(|_| format!("bad exit status '{}'", status))?;
+                (status, rest)
+            }
+            _ => (0, spec),
+        };
+        let (kind, value) = spec
+            .split_once('=')
+            .ok_or_else(|| "expected instructions=, at=, port= or text=".to_string())?;
+        let hex = |value: &str| u16::from_str_radix(value.trim_start_matches("0x"), 16).ok();
+        let condition = match kind {
+            "instructions" => Condition::Instructions(
+                value
+                    .parse()
+                    .map_err(|_| format!("bad instruction count '{}'", value))?,
+            ),
+            "at" => match value.split_once(':').map(|(cs, ip)| (hex(cs), hex(ip))) {
+                Some((Some(cs), Some(ip))) => Condition::Address(cs, ip),
+                _ => {
+                    return Err(format!(
+                        "expected an address like f000:e05b, not '{}'",
+                        value
+                    ))
+                }
+            },
+            "port" => {
+                let (port, data) = match value.split_once(':') {
+                    Some((port, data)) => (port, Some(data)),
+                    None => (value, None),
+                };
+                let data = data.map(|data| hex(data).filter(|&data| data <= 0xff));
+                match (hex(port), data) {
+                    (Some(port), None) => Condition::PortWrite(port, None),
+                    (Some(port), Some(Some(data))) => Condition::PortWrite(port, Some(data as u8)),
+                    _ => return Err(format!("expected a port like e9 or e9:42, not '{}'", value)),
+                }
+            }
+            "text" if !value.is_empty() => Condition::Text(value.to_string()),
+            _ => return Err(format!("unknown condition '{}'", spec)),
+        };
+        Ok(ExitCondition { condition, status })
+    }
+}
+
+/// Runs `machine` until one of `conditions` is met, returning it. Without
+/// any, runs for ever.
+pub fn run<'a>(machine: &mut Machine, conditions: &'a [ExitCondition]) -> &'a ExitCondition {
+    for exit in conditions {
+        match exit.condition {
+            Condition::Address(cs, ip) => {
+                machine.breakpoints_mut().add(Breakpoint::Address(cs, ip));
+            }
+            Condition::PortWrite(port, value) => machine.port_watch_mut().watch(port, value),
+            _ => {}
+        }
+    }
+    let limit = conditions
+        .iter()
+        .filter_map(|exit| match exit.condition {
+            Condition::Instructions(count) => Some(count),
+            _ => None,
+        })
+        .min();
+    loop {
+        let left = limit.map(|limit| limit.saturating_sub(machine.instructions()));
+        let reason = match left {
+            Some(left) if left <= FRAME_INSTRUCTIONS => machine.run_instructions(left as usize),
+            _ => machine.run_frame(),
+        };
+        if let Some(exit) = conditions.iter().find(|exit| met(machine, exit, reason)) {
+            return exit;
+        }
+    }
+}
+
+fn met(machine: &mut Machine, exit: &ExitCondition, reason: StopReason) -> bool {
+    match &exit.condition {
+        Condition::Instructions(count) => machine.instructions() >= *count,
+        Condition::Address(cs, ip) => {
+            let state = machine.cpu_state();
+            reason == StopReason::Breakpoint && (state.cs(), state.ip) == (*cs, *ip)
+        }
+        Condition::PortWrite(port, value) => {
+            reason == StopReason::PortWritten
+                && matches!(machine.port_watch_mut().last(), Some((written, data))
+                    if written == *port && value.is_none_or(|value| value == data))
+        }
+        Condition::Text(text) => {
+            let chars = machine.text_chars();
+            chars
+                .windows(text.len())
+                .any(|window| window == text.as_bytes())
+        }
+    }
+}
+
+/// The state the machine was left in, for the log of a run: why it
+/// stopped, the registers, the last POST code and the first text page.
+pub fn dump(machine: &Machine, exit: &ExitCondition) -> String {
+    let mut out = format!(
+        "Stopped after {} instructions: {}\n",
+        machine.instructions(),
+        exit.condition
+    );
+    out += &format!("{}\n", machine.cpu_state());
+    if let Some(code) = machine.last_post_code() {
+        out += &format!("POST code {:02X}h\n", code);
+    }
+    let chars = machine.text_chars();
+    let page = &chars[..chars.len().min(80 * 25)];
+    for row in page.chunks(80) {
+        let line: String = row
+            .iter()
+            .map(|&c| match c {
+                0x20..=0x7e => c as char,
+                _ => ' ',
+            })
+            .collect();
+        let line = line.trim_end();
+        if !line.is_empty() {
+            out += &format!("| {}\n", line);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+use crate::hardware::IbmPc5150Machine;
+
+#[test]
+fn test_parse_exit_condition() {
+    let parse = |spec| ExitCondition::parse(spec).map(|exit| (exit.status, exit.condition));
+    assert_eq!(
+        parse("instructions=1000"),
+        Ok((0, Condition::Instructions(1000)))
+    );
+    assert_eq!(
+        parse("3:at=f000:e05b"),
+        Ok((3, Condition::Address(0xf000, 0xe05b)))
+    );
+    assert_eq!(parse("port=e9"), Ok((0, Condition::PortWrite(0xe9, None))));
+    assert_eq!(
+        parse("1:port=0x402:ff"),
+        Ok((1, Condition::PortWrite(0x402, Some(0xff))))
+    );
+    assert_eq!(
+        parse("text=C:\\>"),
+        Ok((0, Condition::Text("C:\\>".to_string())))
+    );
+    assert!(parse("port=e9:100").is_err());
+    assert!(parse("x:text=A").is_err());
+    assert!(parse("at=f000").is_err());
+    assert!(parse("text=").is_err());
+    assert!(parse("halt").is_err());
+}
+
+#[test]
+fn test_headless_run() {
+    let mut pc = IbmPc5150Machine::new();
+    pc.cpu.regs.seg_regs[1] = 0;
+    pc.cpu.regs.ip = 0x100;
+    // mov al,42h; out 0e9h,al; jmp $
+    pc.hardware.memory.ram[0x100..0x106].copy_from_slice(&[0xb0, 0x42, 0xe6, 0xe9, 0xeb, 0xfe]);
+    let mut machine = Machine::Pc(Box::new(pc));
+    let backup = machine.clone();
+
+    let conditions = [
+        ExitCondition::parse("1:port=e9:41").unwrap(),
+        ExitCondition::parse("2:port=e9:42").unwrap(),
+    ];
+    assert_eq!(run(&mut machine, &conditions).status, 2);
+    assert_eq!(machine.instructions(), 2);
+    assert_eq!(machine.cpu_state().ip, 0x104);
+
+    let mut machine = backup.clone();
+    let conditions = [
+        ExitCondition::parse("at=0000:0104").unwrap(),
+        ExitCondition::parse("5:instructions=1").unwrap(),
+    ];
+    assert_eq!(run(&mut machine, &conditions).status, 5);
+    assert_eq!(machine.instructions(), 1);
+    let exit = run(&mut machine, &conditions[..1]);
+    assert_eq!(machine.cpu_state().ip, 0x104);
+    let dump = dump(&machine, exit);
+    assert!(dump.starts_with("Stopped after 2 instructions: reached 0000:0104\n"));
+    assert!(dump.contains("AX=0042"));
+
+    let mut machine = backup;
+    let conditions = [ExitCondition::parse("instructions=12345").unwrap()];
+    run(&mut machine, &conditions);
+    assert_eq!(machine.instructions(), 12345);
+}
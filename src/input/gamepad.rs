@@ -0,0 +1,53 @@
+use crate::input::{InputEvent, InputSource};
+use gilrs::{Axis, Button, EventType, Gilrs};
+
+/// Host gamepads, through gilrs, as the game port's joysticks. The left
+/// stick is stick A and the right stick stick B, which is where flight
+/// sims look for the throttle and rudder; the four face buttons are the
+/// port's four buttons.
+pub struct Gamepads {
+    gilrs: Gilrs,
+}
+
+impl Gamepads {
+    pub fn new() -> Result<Gamepads, String> {
+        let gilrs = Gilrs::new().map_err(|err| err.to_string())?;
+        Ok(Gamepads { gilrs })
+    }
+}
+
+impl InputSource for Gamepads {
+    /// The events from every gamepad since the last call.
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let mut events = vec![];
+        while let Some(event) = self.gilrs.next_event() {
+            let event = match event.event {
+                EventType::AxisChanged(axis, value, _) => {
+                    let (stick, axis, value) = match axis {
+                        Axis::LeftStickX => (0, 0, value),
+                        Axis::LeftStickY => (0, 1, -value),
+                        Axis::RightStickX => (1, 0, value),
+                        Axis::RightStickY => (1, 1, -value),
+                        _ => continue,
+                    };
+                    let value = ((value.clamp(-1.0, 1.0) + 1.0) * 127.5) as u8;
+                    InputEvent::JoystickAxis { stick, axis, value }
+                }
+                EventType::ButtonPressed(button, _) | EventType::ButtonReleased(button, _) => {
+                    let pressed = matches!(event.event, EventType::ButtonPressed(..));
+                    let button = match button {
+                        Button::South => 0,
+                        Button::East => 1,
+                        Button::West => 2,
+                        Button::North => 3,
+                        _ => continue,
+                    };
+                    InputEvent::JoystickButton { button, pressed }
+                }
+                _ => continue,
+            };
+            events.push(event);
+        }
+        events
+    }
+}
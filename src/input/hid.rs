@@ -0,0 +1,240 @@
+use crate::input::{InputEvent, InputSource};
+use log::{debug, warn};
+use std::fs::File;
+use std::io::Read;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HidTarget {
+    Key(u8),
+    Button(u8),
+    Axis { stick: u8, axis: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HidSource {
+    Bit { byte: usize, mask: u8 },
+    Byte { byte: usize, invert: bool },
+    Word { byte: usize, invert: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HidRule {
+    pub source: HidSource,
+    pub target: HidTarget,
+}
+
+/// Maps raw HID report bytes onto guest input. Mappings are written as a
+/// small line-based script, one rule per line:
+///
+/// ```text
+/// # steering wheel: report byte 1 is the wheel, byte 2 the pedals
+/// axis 1 -> joy 0 x
+/// axis 2 invert -> joy 0 y
+/// axis16 4 -> joy 1 x
+/// bit 5 0x01 -> button 0
+/// bit 5 0x02 -> key 0x39
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HidMapping {
+    pub rules: Vec<HidRule>,
+}
+
+fn parse_number(text: &str, line: usize) -> Result<usize, String> {
+    let parsed = if let Some(hex) = text.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16)
+    } else {
+        text.parse::<usize>()
+    };
+    parsed.map_err(|_| format!("line {}: bad number '{}'", line, text))
+}
+
+fn parse_u8(text: &str, line: usize) -> Result<u8, String> {
+    let value = parse_number(text, line)?;
+    if value > 0xff {
+        return Err(format!("line {}: {} is out of range", line, text));
+    }
+    Ok(value as u8)
+}
+
+impl HidMapping {
+    pub fn parse(script: &str) -> Result<HidMapping, String> {
+        let mut rules = vec![];
+        for (index, line) in script.lines().enumerate() {
+            let line_number = index + 1;
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (source, target) = match line.find("->") {
+                Some(pos) => (&line[..pos], &line[pos + 2..]),
+                None => return Err(format!("line {}: missing '->'", line_number)),
+            };
+            let source: Vec<&str> = source.split_whitespace().collect();
+            let target: Vec<&str> = target.split_whitespace().collect();
+
+            let invert = source.get(2) == Some(&"invert");
+            let source = match source.as_slice() {
+                ["bit", byte, mask] => HidSource::Bit {
+                    byte: parse_number(byte, line_number)?,
+                    mask: parse_u8(mask, line_number)?,
+                },
+                ["axis", byte] | ["axis", byte, "invert"] => HidSource::Byte {
+                    byte: parse_number(byte, line_number)?,
+                    invert,
+                },
+                ["axis16", byte] | ["axis16", byte, "invert"] => HidSource::Word {
+                    byte: parse_number(byte, line_number)?,
+                    invert,
+                },
+                _ => return Err(format!("line {}: unknown source", line_number)),
+            };
+            let target = match target.as_slice() {
+                ["key", scancode] => HidTarget::Key(parse_u8(scancode, line_number)?),
+                ["button", button] => HidTarget::Button(parse_u8(button, line_number)?),
+                ["joy", stick, axis] => HidTarget::Axis {
+                    stick: parse_u8(stick, line_number)?,
+                    axis: match *axis {
+                        "x" => 0,
+                        "y" => 1,
+                        _ => return Err(format!("line {}: axis must be x or y", line_number)),
+                    },
+                },
+                _ => return Err(format!("line {}: unknown target", line_number)),
+            };
+            let is_bit = matches!(source, HidSource::Bit { .. });
+            let is_axis = matches!(target, HidTarget::Axis { .. });
+            if is_bit == is_axis {
+                return Err(format!(
+                    "line {}: bits map to keys or buttons, axes map to joystick axes",
+                    line_number
+                ));
+            }
+            rules.push(HidRule { source, target });
+        }
+        Ok(HidMapping { rules })
+    }
+
+    fn sample(source: HidSource, report: &[u8]) -> Option<u8> {
+        match source {
+            HidSource::Bit { byte, mask } => report.get(byte).map(|b| (b & mask != 0) as u8),
+            HidSource::Byte { byte, invert } => {
+                report.get(byte).map(|b| if invert { 255 - *b } else { *b })
+            }
+            HidSource::Word { byte, invert } => {
+                let lo = *report.get(byte)?;
+                let hi = *report.get(byte + 1)?;
+                let value = (u16::from_le_bytes([lo, hi]) >> 8) as u8;
+                Some(if invert { 255 - value } else { value })
+            }
+        }
+    }
+
+    /// Compares two consecutive reports and produces an event for every
+    /// mapped input that changed.
+    pub fn translate(&self, previous: &[u8], report: &[u8]) -> Vec<InputEvent> {
+        let mut events = vec![];
+        for rule in self.rules.iter() {
+            let value = match HidMapping::sample(rule.source, report) {
+                Some(value) => value,
+                None => continue,
+            };
+            if HidMapping::sample(rule.source, previous) == Some(value) {
+                continue;
+            }
+            events.push(match rule.target {
+                HidTarget::Key(scancode) => InputEvent::Key {
+                    scancode: scancode.into(),
+                    pressed: value != 0,
+                },
+                HidTarget::Button(button) => InputEvent::JoystickButton {
+                    button,
+                    pressed: value != 0,
+                },
+                HidTarget::Axis { stick, axis } => InputEvent::JoystickAxis { stick, axis, value },
+            });
+        }
+        events
+    }
+}
+
+/// Reads raw reports from a host HID device node (hidraw on Linux) on a
+/// background thread, so polling from the emulation loop never blocks.
+pub struct HidDevice {
+    mapping: HidMapping,
+    reports: Receiver<Vec<u8>>,
+    previous: Vec<u8>,
+}
+
+impl HidDevice {
+    pub fn open(path: &str, mapping: HidMapping) -> std::io::Result<HidDevice> {
+        let mut file = File::open(path)?;
+        let (sender, reports) = mpsc::channel();
+        let path = path.to_string();
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            loop {
+                match file.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        if sender.send(buf[..len].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        warn!("Reading HID device {} failed: {}", path, err);
+                        break;
+                    }
+                }
+            }
+            debug!("HID device {} closed", path);
+        });
+        Ok(HidDevice {
+            mapping,
+            reports,
+            previous: vec![],
+        })
+    }
+}
+
+impl InputSource for HidDevice {
+    /// Drains all reports received since the last call.
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let mut events = vec![];
+        while let Ok(report) = self.reports.try_recv() {
+            events.extend(self.mapping.translate(&self.previous, &report));
+            self.previous = report;
+        }
+        events
+    }
+}
+
+#[test]
+fn test_hid_mapping() {
+    let mapping = HidMapping::parse(
+        "# wheel\naxis 1 -> joy 0 x\naxis 2 invert -> joy 0 y\nbit 3 0x02 -> key 0x39\n",
+    )
+    .unwrap();
+    assert_eq!(mapping.rules.len(), 3);
+
+    let events = mapping.translate(&[0, 0x80, 0x00, 0x00], &[0, 0x80, 0x10, 0x02]);
+    assert_eq!(
+        events,
+        vec![
+            InputEvent::JoystickAxis {
+                stick: 0,
+                axis: 1,
+                value: 0xef
+            },
+            InputEvent::Key {
+                scancode: 0x39,
+                pressed: true
+            },
+        ]
+    );
+    assert!(HidMapping::parse("bit 1 0x01 -> joy 0 x").is_err());
+}
@@ -0,0 +1,145 @@
+use crate::input::InputEvent;
+
+/// What a key of the virtual joystick does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Control {
+    Up,
+    Down,
+    Left,
+    Right,
+    Button(u8),
+}
+
+/// A joystick worked from the keyboard, for hosts without a real one.
+/// Holding a direction pushes stick A all the way over, and letting go
+/// centres it again. The keys are set with a comma-separated list of
+/// `name=scancode` pairs, by set 1 make code with E0h in front for the
+/// keys that have it:
+///
+/// ```text
+/// up=0xe048,down=0xe050,left=0xe04b,right=0xe04d,fire1=0x1d,fire2=0x38
+/// ```
+///
+/// which is also what it starts with: the arrow keys, Ctrl and Alt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualJoystick {
+    keys: Vec<(u16, Control)>,
+    /// Directions held, as up, down, left, right.
+    held: [bool; 4],
+}
+
+impl VirtualJoystick {
+    pub fn new() -> VirtualJoystick {
+        VirtualJoystick::parse(
+            "up=0xe048,down=0xe050,left=0xe04b,right=0xe04d,fire1=0x1d,fire2=0x38",
+        )
+        .unwrap()
+    }
+
+    pub fn parse(spec: &str) -> Result<VirtualJoystick, String> {
+        let mut keys = vec![];
+        for pair in spec.split(',').filter(|pair| !pair.is_empty()) {
+            let (name, scancode) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=scancode, not '{}'", pair))?;
+            let parsed = match scancode.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => scancode.parse(),
+            };
+            let scancode = parsed.map_err(|_| format!("bad scancode '{}'", scancode))?;
+            let control = match name {
+                "up" => Control::Up,
+                "down" => Control::Down,
+                "left" => Control::Left,
+                "right" => Control::Right,
+                "fire1" => Control::Button(0),
+                "fire2" => Control::Button(1),
+                "fire3" => Control::Button(2),
+                "fire4" => Control::Button(3),
+                _ => return Err(format!("unknown control '{}'", name)),
+            };
+            keys.retain(|&(_, existing)| existing != control);
+            keys.push((scancode, control));
+        }
+        Ok(VirtualJoystick {
+            keys,
+            held: [false; 4],
+        })
+    }
+
+    /// The position of one axis from the two directions that move it.
+    fn axis(negative: bool, positive: bool) -> u8 {
+        match (negative, positive) {
+            (true, false) => 0,
+            (false, true) => 255,
+            _ => 128,
+        }
+    }
+
+    /// The joystick events a key makes, if it's one of the joystick's.
+    pub fn translate(&mut self, event: InputEvent) -> Vec<InputEvent> {
+        let (scancode, pressed) = match event {
+            InputEvent::Key { scancode, pressed } => (scancode, pressed),
+            _ => return vec![],
+        };
+        let controls: Vec<Control> = self
+            .keys
+            .iter()
+            .filter(|(key, _)| *key == scancode)
+            .map(|&(_, control)| control)
+            .collect();
+        let mut events = vec![];
+        for control in controls {
+            let (direction, axis) = match control {
+                Control::Up => (0, 1),
+                Control::Down => (1, 1),
+                Control::Left => (2, 0),
+                Control::Right => (3, 0),
+                Control::Button(button) => {
+                    events.push(InputEvent::JoystickButton { button, pressed });
+                    continue;
+                }
+            };
+            self.held[direction] = pressed;
+            let value = match axis {
+                0 => VirtualJoystick::axis(self.held[2], self.held[3]),
+                _ => VirtualJoystick::axis(self.held[0], self.held[1]),
+            };
+            events.push(InputEvent::JoystickAxis {
+                stick: 0,
+                axis,
+                value,
+            });
+        }
+        events
+    }
+}
+
+impl Default for VirtualJoystick {
+    fn default() -> VirtualJoystick {
+        VirtualJoystick::new()
+    }
+}
+
+#[test]
+fn test_virtual_joystick_keys() {
+    let mut joystick = VirtualJoystick::parse("left=0x1e,right=0x20,fire2=57").unwrap();
+    let key = |scancode, pressed| InputEvent::Key { scancode, pressed };
+    let x = |value| InputEvent::JoystickAxis {
+        stick: 0,
+        axis: 0,
+        value,
+    };
+    assert_eq!(joystick.translate(key(0x1e, true)), vec![x(0)]);
+    assert_eq!(joystick.translate(key(0x20, true)), vec![x(128)]);
+    assert_eq!(joystick.translate(key(0x1e, false)), vec![x(255)]);
+    assert_eq!(
+        joystick.translate(key(0x39, true)),
+        vec![InputEvent::JoystickButton {
+            button: 1,
+            pressed: true
+        }]
+    );
+    assert!(joystick.translate(key(0x48, true)).is_empty());
+    assert!(VirtualJoystick::parse("jump=0x39").is_err());
+}
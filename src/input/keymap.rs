@@ -0,0 +1,119 @@
+use crate::hardware::keyboard::{KEY_PAUSE, KEY_PRINT_SCREEN};
+use crate::input::InputEvent;
+
+/// Every key on a 104-key keyboard, the 102nd key and SysRq, by name,
+/// with their set 1 make codes.
+#[rustfmt::skip]
+const KEYS: [(&str, u16); 106] = [
+    ("esc", 0x01), ("1", 0x02), ("2", 0x03), ("3", 0x04), ("4", 0x05),
+    ("5", 0x06), ("6", 0x07), ("7", 0x08), ("8", 0x09), ("9", 0x0a),
+    ("0", 0x0b), ("minus", 0x0c), ("equals", 0x0d), ("backspace", 0x0e),
+    ("tab", 0x0f), ("q", 0x10), ("w", 0x11), ("e", 0x12), ("r", 0x13),
+    ("t", 0x14), ("y", 0x15), ("u", 0x16), ("i", 0x17), ("o", 0x18),
+    ("p", 0x19), ("leftbracket", 0x1a), ("rightbracket", 0x1b),
+    ("enter", 0x1c), ("leftctrl", 0x1d), ("a", 0x1e), ("s", 0x1f),
+    ("d", 0x20), ("f", 0x21), ("g", 0x22), ("h", 0x23), ("j", 0x24),
+    ("k", 0x25), ("l", 0x26), ("semicolon", 0x27), ("quote", 0x28),
+    ("backquote", 0x29), ("leftshift", 0x2a), ("backslash", 0x2b),
+    ("z", 0x2c), ("x", 0x2d), ("c", 0x2e), ("v", 0x2f), ("b", 0x30),
+    ("n", 0x31), ("m", 0x32), ("comma", 0x33), ("period", 0x34),
+    ("slash", 0x35), ("rightshift", 0x36), ("kpmultiply", 0x37),
+    ("leftalt", 0x38), ("space", 0x39), ("capslock", 0x3a), ("f1", 0x3b),
+    ("f2", 0x3c), ("f3", 0x3d), ("f4", 0x3e), ("f5", 0x3f), ("f6", 0x40),
+    ("f7", 0x41), ("f8", 0x42), ("f9", 0x43), ("f10", 0x44),
+    ("numlock", 0x45), ("scrolllock", 0x46), ("kp7", 0x47), ("kp8", 0x48),
+    ("kp9", 0x49), ("kpminus", 0x4a), ("kp4", 0x4b), ("kp5", 0x4c),
+    ("kp6", 0x4d), ("kpplus", 0x4e), ("kp1", 0x4f), ("kp2", 0x50),
+    ("kp3", 0x51), ("kp0", 0x52), ("kpperiod", 0x53), ("sysrq", 0x54),
+    ("intlbackslash", 0x56), ("f11", 0x57), ("f12", 0x58),
+    ("kpenter", 0xe01c), ("rightctrl", 0xe01d), ("kpdivide", 0xe035),
+    ("printscreen", KEY_PRINT_SCREEN), ("rightalt", 0xe038),
+    ("home", 0xe047), ("up", 0xe048), ("pageup", 0xe049), ("left", 0xe04b),
+    ("right", 0xe04d), ("end", 0xe04f), ("down", 0xe050),
+    ("pagedown", 0xe051), ("insert", 0xe052), ("delete", 0xe053),
+    ("leftwin", 0xe05b), ("rightwin", 0xe05c), ("menu", 0xe05d),
+    ("pause", KEY_PAUSE),
+];
+
+/// A key's set 1 code, by name or as a number.
+pub fn key_code(name: &str) -> Option<u16> {
+    let name = name.trim().to_ascii_lowercase();
+    if let Some(hex) = name.strip_prefix("0x") {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    KEYS.iter()
+        .find(|(key, _)| *key == name)
+        .map(|&(_, code)| code)
+}
+
+/// Sends some host keys to the machine as other keys, or not at all. The
+/// table is a comma-separated list of `from=to` pairs, by name or set 1
+/// code, with `none` to swallow a key:
+///
+/// ```text
+/// capslock=leftctrl,leftctrl=capslock,leftwin=none
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyMap {
+    keys: Vec<(u16, Option<u16>)>,
+}
+
+impl KeyMap {
+    pub fn parse(spec: &str) -> Result<KeyMap, String> {
+        let mut keys = vec![];
+        for pair in spec.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (from, to) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected from=to, not '{}'", pair))?;
+            let key = |name: &str| key_code(name).ok_or_else(|| format!("unknown key '{}'", name));
+            let from = key(from)?;
+            let to = match to.trim() {
+                "none" => None,
+                to => Some(key(to)?),
+            };
+            keys.retain(|&(existing, _)| existing != from);
+            keys.push((from, to));
+        }
+        Ok(KeyMap { keys })
+    }
+
+    /// The event with its key remapped, or `None` if the key is swallowed.
+    /// Anything other than a key passes through.
+    pub fn map(&self, event: InputEvent) -> Option<InputEvent> {
+        match event {
+            InputEvent::Key { scancode, pressed } => {
+                let scancode = match self.keys.iter().find(|&&(from, _)| from == scancode) {
+                    Some(&(_, to)) => to?,
+                    None => scancode,
+                };
+                Some(InputEvent::Key { scancode, pressed })
+            }
+            event => Some(event),
+        }
+    }
+}
+
+#[test]
+fn test_key_map() {
+    assert_eq!(key_code("F12"), Some(0x58));
+    assert_eq!(key_code("pause"), Some(KEY_PAUSE));
+    assert_eq!(key_code("0xe05b"), Some(0xe05b));
+    assert_eq!(key_code("hyper"), None);
+
+    let map = KeyMap::parse("capslock=leftctrl, leftctrl=capslock,leftwin=none").unwrap();
+    let key = |scancode| InputEvent::Key {
+        scancode,
+        pressed: true,
+    };
+    assert_eq!(map.map(key(0x3a)), Some(key(0x1d)));
+    assert_eq!(map.map(key(0x1d)), Some(key(0x3a)));
+    assert_eq!(map.map(key(0xe05b)), None);
+    assert_eq!(map.map(key(0x1e)), Some(key(0x1e)));
+    let button = InputEvent::MouseButton {
+        button: 0,
+        pressed: true,
+    };
+    assert_eq!(map.map(button), Some(button));
+    assert!(KeyMap::parse("capslock").is_err());
+    assert!(KeyMap::parse("capslock=hyper").is_err());
+}
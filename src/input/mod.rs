@@ -0,0 +1,29 @@
+#[cfg(feature = "gamepad")]
+pub mod gamepad;
+pub mod hid;
+pub mod joystick;
+pub mod keymap;
+pub mod mouse;
+
+/// Host input translated into something the emulated machine understands.
+/// Keys use XT (set 1) make codes, with E0h in the high byte for the keys
+/// that send it first and `KEY_PAUSE` and `KEY_PRINT_SCREEN` from
+/// `hardware::keyboard` for the two that send sequences of their own; the
+/// keyboard is responsible for any further translation. Joystick axes use
+/// the 0-255 range the game port's one-shot timers are scaled to. Mouse
+/// motion is in mickeys, right and down being positive, and its buttons are
+/// 0 for left, 1 for right and 2 for middle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    Key { scancode: u16, pressed: bool },
+    JoystickAxis { stick: u8, axis: u8, value: u8 },
+    JoystickButton { button: u8, pressed: bool },
+    MouseMove { dx: i32, dy: i32 },
+    MouseButton { button: u8, pressed: bool },
+}
+
+/// A host device whose input is polled from the emulation loop.
+pub trait InputSource {
+    /// The events since the last call.
+    fn poll(&mut self) -> Vec<InputEvent>;
+}
@@ -0,0 +1,59 @@
+use crate::input::InputEvent;
+
+/// Turns the host's relative mouse motion into mickeys for the emulated
+/// mouse, scaled by a sensitivity. What's left over after rounding is
+/// carried to the next move, so slow movement at a low sensitivity still
+/// gets somewhere.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MouseMotion {
+    sensitivity: f64,
+    remainder: (f64, f64),
+}
+
+impl MouseMotion {
+    pub fn new(sensitivity: f64) -> MouseMotion {
+        MouseMotion {
+            sensitivity,
+            remainder: (0.0, 0.0),
+        }
+    }
+
+    /// The move for the machine, if it adds up to a mickey yet.
+    pub fn motion(&mut self, dx: f64, dy: f64) -> Option<InputEvent> {
+        let x = self.remainder.0 + dx * self.sensitivity;
+        let y = self.remainder.1 + dy * self.sensitivity;
+        let (dx, dy) = (x.trunc(), y.trunc());
+        self.remainder = (x - dx, y - dy);
+        if dx == 0.0 && dy == 0.0 {
+            return None;
+        }
+        Some(InputEvent::MouseMove {
+            dx: dx as i32,
+            dy: dy as i32,
+        })
+    }
+
+    /// Forgets any motion carried over, as when the mouse is let go.
+    pub fn clear(&mut self) {
+        self.remainder = (0.0, 0.0);
+    }
+}
+
+#[test]
+fn test_mouse_motion() {
+    let mut motion = MouseMotion::new(2.0);
+    assert_eq!(
+        motion.motion(3.0, -1.0),
+        Some(InputEvent::MouseMove { dx: 6, dy: -2 })
+    );
+
+    let mut motion = MouseMotion::new(0.25);
+    assert_eq!(motion.motion(2.0, 0.0), None);
+    assert_eq!(
+        motion.motion(2.0, -4.0),
+        Some(InputEvent::MouseMove { dx: 1, dy: -1 })
+    );
+    motion.motion(3.0, 0.0);
+    motion.clear();
+    assert_eq!(motion.motion(1.0, 0.0), None);
+}
@@ -0,0 +1,120 @@
+// Host scheduling tweaks for latency-sensitive use. Pinning and priority
+// changes need the `realtime` feature and are only implemented on Linux;
+// everywhere else they are reported as unavailable and the emulator runs
+// normally.
+use log::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LatencySettings {
+    /// Host CPU to pin the emulation thread to.
+    pub pin_cpu: Option<usize>,
+    pub raise_priority: bool,
+    /// Use the smallest audio buffers that still play back cleanly.
+    pub low_latency: bool,
+}
+
+impl LatencySettings {
+    /// Parses a comma separated list such as `cpu=2,priority,low-latency`.
+    /// `low-latency` on its own also raises the thread priority.
+    pub fn parse(spec: &str) -> Result<LatencySettings, String> {
+        let mut settings = LatencySettings::default();
+        for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match item.split_once('=') {
+                Some(("cpu", cpu)) => {
+                    settings.pin_cpu = Some(
+                        cpu.parse()
+                            .map_err(|_| format!("bad CPU number '{}'", cpu))?,
+                    );
+                }
+                None if item == "priority" => settings.raise_priority = true,
+                None if item == "low-latency" => {
+                    settings.low_latency = true;
+                    settings.raise_priority = true;
+                }
+                _ => return Err(format!("unknown latency option '{}'", item)),
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Audio buffer size in sample frames, for the audio backend to use.
+    pub fn audio_buffer_frames(&self) -> usize {
+        if self.low_latency {
+            256
+        } else {
+            2048
+        }
+    }
+}
+
+#[cfg(all(feature = "realtime", target_os = "linux"))]
+fn pin_current_thread(cpu: usize) -> Result<(), String> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "realtime", target_os = "linux"))]
+fn raise_current_thread_priority() -> Result<(), String> {
+    unsafe {
+        let param = libc::sched_param { sched_priority: 10 };
+        if libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) == 0 {
+            return Ok(());
+        }
+        // Real-time scheduling usually needs privileges; a lower nice
+        // value is the next best thing.
+        if libc::setpriority(libc::PRIO_PROCESS, 0, -10) == 0 {
+            return Ok(());
+        }
+    }
+    Err(std::io::Error::last_os_error().to_string())
+}
+
+#[cfg(not(all(feature = "realtime", target_os = "linux")))]
+fn pin_current_thread(_cpu: usize) -> Result<(), String> {
+    Err("not supported in this build".to_string())
+}
+
+#[cfg(not(all(feature = "realtime", target_os = "linux")))]
+fn raise_current_thread_priority() -> Result<(), String> {
+    Err("not supported in this build".to_string())
+}
+
+/// Applies the settings to the calling thread, which should be the one
+/// running the emulation loop. Anything that can't be done is logged and
+/// returned, but never stops the emulator from running.
+pub fn apply(settings: &LatencySettings) -> Vec<String> {
+    let mut problems = vec![];
+    if let Some(cpu) = settings.pin_cpu {
+        match pin_current_thread(cpu) {
+            Ok(()) => info!("Pinned emulation thread to CPU {}", cpu),
+            Err(err) => problems.push(format!("couldn't pin to CPU {}: {}", cpu, err)),
+        }
+    }
+    if settings.raise_priority {
+        match raise_current_thread_priority() {
+            Ok(()) => info!("Raised emulation thread priority"),
+            Err(err) => problems.push(format!("couldn't raise priority: {}", err)),
+        }
+    }
+    for problem in problems.iter() {
+        warn!("{}", problem);
+    }
+    problems
+}
+
+#[test]
+fn test_parse_latency_settings() {
+    let settings = LatencySettings::parse("cpu=3, low-latency").unwrap();
+    assert_eq!(settings.pin_cpu, Some(3));
+    assert!(settings.raise_priority);
+    assert_eq!(settings.audio_buffer_frames(), 256);
+    assert!(LatencySettings::parse("cpu=x").is_err());
+    assert!(LatencySettings::parse("turbo").is_err());
+}
@@ -0,0 +1,59 @@
+//! An IBM PC, XT and AT emulator core, for embedding in a frontend of
+//! your own.
+//!
+//! Machines come from a preset in [`TEMPLATES`], or part by part from a
+//! [`MachineBuilder`]. Either way the result is a [`Machine`], which the
+//! frontend runs a video frame at a time with [`Machine::run_frame`],
+//! feeding it host input with [`Machine::input`], drawing
+//! [`Machine::frame`] and playing [`Machine::render_audio`] in between.
+//!
+//! ```no_run
+//! use emupc_rs::{Board, IsaCard, MachineBuilder, VideoCard};
+//!
+//! let mut machine = MachineBuilder::new(Board::Ibm5160)
+//!     .video(VideoCard::Cga)
+//!     .card(IsaCard::Adlib)
+//!     .build()
+//!     .unwrap();
+//! let mut audio = vec![0; 735];
+//! loop {
+//!     machine.run_frame();
+//!     if let Some(frame) = machine.frame() {
+//!         // Draw it.
+//!     }
+//!     machine.render_audio(&mut audio);
+//! }
+//! ```
+//!
+//! Everything below the machine, the CPUs and each device, is public too,
+//! for debuggers and tests that need to get at it.
+extern crate bitflags;
+
+pub mod audio;
+pub mod compat;
+pub mod cpu;
+pub mod cpu286;
+pub mod cpu386;
+pub mod cpu8086;
+pub mod debugger;
+pub mod disasm;
+pub mod hardware;
+pub mod headless;
+pub mod input;
+pub mod latency;
+pub mod logging;
+pub mod recording;
+pub mod renderer;
+pub mod savestate;
+pub mod trace;
+
+pub use crate::hardware::builder::MachineBuilder;
+pub use crate::hardware::machine::{DriveLights, IsaCard, Machine};
+pub use crate::hardware::reset::ResetKind;
+pub use crate::hardware::runcontrol::RunControl;
+pub use crate::hardware::templates::{
+    find_template, Board, MachineTemplate, SoundCard, VideoCard, TEMPLATES,
+};
+pub use crate::hardware::{RunEvent, StopReason};
+pub use crate::input::{InputEvent, InputSource};
+pub use crate::renderer::Frame;
@@ -0,0 +1,136 @@
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Every log call in the emulator uses one of these as its target, e.g.
+// `trace!(target: "io", ...)`. Anything else falls back to the default level.
+pub const TARGETS: [&str; 9] = [
+    "cpu", "io", "pic", "pit", "dma", "video", "fdc", "disk", "post",
+];
+
+const OFF: usize = 0;
+
+static DEFAULT_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Warn as usize);
+static TARGET_LEVELS: [AtomicUsize; 9] = [
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+    AtomicUsize::new(usize::MAX),
+];
+
+static LOGGER: EmuLogger = EmuLogger;
+
+struct EmuLogger;
+
+fn filter_from_usize(value: usize) -> LevelFilter {
+    match value {
+        OFF => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+fn target_index(target: &str) -> Option<usize> {
+    TARGETS.iter().position(|t| *t == target)
+}
+
+pub fn level_for(target: &str) -> LevelFilter {
+    if let Some(index) = target_index(target) {
+        let level = TARGET_LEVELS[index].load(Ordering::Relaxed);
+        if level != usize::MAX {
+            return filter_from_usize(level);
+        }
+    }
+    filter_from_usize(DEFAULT_LEVEL.load(Ordering::Relaxed))
+}
+
+fn update_max_level() {
+    let mut max = DEFAULT_LEVEL.load(Ordering::Relaxed);
+    for level in TARGET_LEVELS.iter() {
+        let level = level.load(Ordering::Relaxed);
+        if level != usize::MAX && level > max {
+            max = level;
+        }
+    }
+    log::set_max_level(filter_from_usize(max));
+}
+
+/// Sets the level of a single component. Unknown targets are rejected so
+/// that a typo in a filter spec doesn't silently log nothing.
+pub fn set_level(target: &str, level: LevelFilter) -> Result<(), String> {
+    match target_index(target) {
+        Some(index) => {
+            TARGET_LEVELS[index].store(level as usize, Ordering::Relaxed);
+            update_max_level();
+            Ok(())
+        }
+        None => Err(format!("unknown log target '{}'", target)),
+    }
+}
+
+pub fn set_default_level(level: LevelFilter) {
+    DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level
+        .parse::<LevelFilter>()
+        .map_err(|_| format!("unknown log level '{}'", level))
+}
+
+/// Applies a filter spec such as `warn,io=trace,pit=debug`. A bare level
+/// sets the default for every target without an explicit entry.
+pub fn apply_spec(spec: &str) -> Result<(), String> {
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.find('=') {
+            Some(pos) => set_level(&entry[..pos], parse_level(&entry[pos + 1..])?)?,
+            None => set_default_level(parse_level(entry)?),
+        }
+    }
+    Ok(())
+}
+
+pub fn init() -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    update_max_level();
+    Ok(())
+}
+
+impl Log for EmuLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        eprintln!("[{} {}] {}", level, record.target(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+#[test]
+fn test_apply_spec() {
+    apply_spec("error,io=trace").unwrap();
+    assert_eq!(level_for("io"), LevelFilter::Trace);
+    assert_eq!(level_for("cpu"), LevelFilter::Error);
+    assert!(apply_spec("bogus=trace").is_err());
+    assert!(apply_spec("cpu=loud").is_err());
+}
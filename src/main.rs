@@ -1,39 +1,776 @@
-extern crate bitflags;
+mod cli;
+mod config;
+#[cfg(feature = "window")]
+mod window;
 
-use crate::cpu8086::*;
-use crate::hardware::*;
+use clap::Parser;
+use cli::Args;
+#[cfg(feature = "midi")]
+use emupc_rs::audio::midi::HostMidiOut;
+use emupc_rs::audio::midi::{MidiDump, MidiOut};
+use emupc_rs::audio::output::AudioOut;
+#[cfg(feature = "audio")]
+use emupc_rs::audio::output::HostAudio;
+use emupc_rs::debugger::Debugger;
+use emupc_rs::hardware::cdrom::CdImage;
+use emupc_rs::hardware::floppy::{FloppyMedia, MountMode};
+use emupc_rs::hardware::harddisk::HardDisk;
+use emupc_rs::hardware::lpt::LoopbackPlug;
+use emupc_rs::hardware::ne2000::{DEFAULT_MAC, NE2000_BASE, NE2000_IRQ};
+#[cfg(all(feature = "passthrough", unix))]
+use emupc_rs::hardware::passthrough::HostPty;
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+use emupc_rs::hardware::passthrough::{HostPacketSocket, HostParallelPort, HostTap};
+#[cfg(feature = "passthrough")]
+use emupc_rs::hardware::passthrough::{HostPrinterDevice, HostSerialPort};
+use emupc_rs::hardware::passthrough::{NetworkBackend, ParallelBackend, SerialBackend, TcpSerial};
+use emupc_rs::hardware::printer::{PrintFormat, Printer};
+use emupc_rs::hardware::serialmouse::{MouseProtocol, SerialMouse};
+use emupc_rs::hardware::slirp::Slirp;
+use emupc_rs::hardware::*;
+#[cfg(feature = "gamepad")]
+use emupc_rs::input::gamepad::Gamepads;
+use emupc_rs::input::joystick::VirtualJoystick;
+use emupc_rs::input::keymap::KeyMap;
+use emupc_rs::input::{InputEvent, InputSource};
+use emupc_rs::recording::Recorder;
+#[cfg(feature = "window")]
+use emupc_rs::renderer;
+use emupc_rs::trace::Tracer;
+use emupc_rs::{compat, headless, latency, logging};
+use log::info;
+use std::env;
 use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
 
-pub mod cpu286;
-pub mod cpu8086;
-pub mod hardware;
+/// Warns about known problems with the boot disk, using the database named
+/// by `EMUPC_COMPAT_DB`.
+#[cfg_attr(not(feature = "compat-db"), allow(unused_variables))]
+fn check_compatibility(template: &templates::MachineTemplate, image: &[u8]) {
+    let fingerprint = match compat::Fingerprint::of(image) {
+        Some(fingerprint) => fingerprint,
+        None => return,
+    };
+    info!("Boot disk: {}", fingerprint);
+    #[cfg(feature = "compat-db")]
+    if let Ok(path) = env::var("EMUPC_COMPAT_DB") {
+        match compat::CompatDatabase::load(&path) {
+            Ok(db) => {
+                if let Some(entry) = db.lookup(&fingerprint) {
+                    info!("Recognised {}", entry.name);
+                    for suggestion in entry.suggestions(template) {
+                        log::warn!("{}", suggestion);
+                    }
+                }
+            }
+            Err(err) => eprintln!("EMUPC_COMPAT_DB: {}", err),
+        }
+    }
+}
+
+/// The host MIDI output named by `--midi`, or the file named by
+/// `--midi-dump`, for the MPU-401.
+fn open_midi_out(args: &Args) -> Option<Box<dyn MidiOut>> {
+    let result: Result<Box<dyn MidiOut>, String> = if let Some(path) = &args.midi_dump {
+        MidiDump::create(path).map(|dump| Box::new(dump) as Box<dyn MidiOut>)
+    } else if let Some(name) = &args.midi {
+        open_host_midi(name)
+    } else {
+        return None;
+    };
+    match result {
+        Ok(midi_out) => Some(midi_out),
+        Err(err) => cli::fail("MIDI output", err),
+    }
+}
+
+#[cfg(feature = "midi")]
+fn open_host_midi(name: &str) -> Result<Box<dyn MidiOut>, String> {
+    Ok(Box::new(HostMidiOut::open(name)?))
+}
+
+#[cfg(not(feature = "midi"))]
+fn open_host_midi(_name: &str) -> Result<Box<dyn MidiOut>, String> {
+    Err("built without the midi feature".to_string())
+}
+
+/// The host's sound output, unless there's no one to hear it. A machine
+/// with no sound is no reason not to run, so failing to open one is only
+/// a warning.
+fn open_audio(args: &Args) -> Option<Box<dyn AudioOut>> {
+    if args.headless || args.no_audio {
+        return None;
+    }
+    match open_host_audio(args.audio_latency) {
+        Ok(audio) => Some(audio),
+        Err(err) => {
+            log::warn!("No sound: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(feature = "audio")]
+fn open_host_audio(latency_ms: u32) -> Result<Box<dyn AudioOut>, String> {
+    Ok(Box::new(HostAudio::open(latency_ms)?))
+}
+
+#[cfg(not(feature = "audio"))]
+fn open_host_audio(_latency_ms: u32) -> Result<Box<dyn AudioOut>, String> {
+    Err("built without the audio feature".to_string())
+}
+
+/// Opens the backend a `--com1` to `--com4` option names:
+/// `tcp-listen:[ADDRESS:]PORT`, `tcp:HOST:PORT`, `pty`, `serial:PATH`, or
+/// `mouse` or `mouse-systems` for a serial mouse.
+fn open_serial(spec: &str) -> Result<Box<dyn SerialBackend>, String> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind {
+        "tcp-listen" => {
+            let address = match arg.contains(':') {
+                true => arg.to_string(),
+                false => format!("127.0.0.1:{}", arg),
+            };
+            let backend =
+                TcpSerial::listen(&address).map_err(|err| format!("{}: {}", address, err))?;
+            println!("Waiting for a serial connection on {}", address);
+            Ok(Box::new(backend))
+        }
+        "tcp" => {
+            let backend = TcpSerial::connect(arg).map_err(|err| format!("{}: {}", arg, err))?;
+            Ok(Box::new(backend))
+        }
+        "mouse" => Ok(Box::new(SerialMouse::new(MouseProtocol::Microsoft))),
+        "mouse-systems" => Ok(Box::new(SerialMouse::new(MouseProtocol::MouseSystems))),
+        "pty" | "serial" => open_host_serial(kind, arg),
+        _ => Err(format!("unknown serial backend {}", spec)),
+    }
+}
+
+#[cfg(all(feature = "passthrough", unix))]
+fn open_host_serial(kind: &str, path: &str) -> Result<Box<dyn SerialBackend>, String> {
+    if kind == "pty" {
+        let pty = HostPty::open().map_err(|err| err.to_string())?;
+        println!("Serial port on {}", pty.path);
+        return Ok(Box::new(pty));
+    }
+    let port = HostSerialPort::open(path).map_err(|err| format!("{}: {}", path, err))?;
+    Ok(Box::new(port))
+}
+
+#[cfg(all(feature = "passthrough", not(unix)))]
+fn open_host_serial(kind: &str, path: &str) -> Result<Box<dyn SerialBackend>, String> {
+    if kind == "pty" {
+        return Err("pseudo-terminals need a Unix host".to_string());
+    }
+    let port = HostSerialPort::open(path).map_err(|err| format!("{}: {}", path, err))?;
+    Ok(Box::new(port))
+}
+
+#[cfg(not(feature = "passthrough"))]
+fn open_host_serial(kind: &str, _path: &str) -> Result<Box<dyn SerialBackend>, String> {
+    Err(format!("{} needs the passthrough feature", kind))
+}
+
+/// Opens the backend an `--lpt1` to `--lpt3` option names: `file:PATH` to
+/// capture what's printed as is, `text:PATH` for just the text,
+/// `loopback` for a diagnostics wrap plug, `printer:PATH` for a host
+/// printer device or `parport:PATH` for a host parallel port.
+fn open_parallel(spec: &str) -> Result<Box<dyn ParallelBackend>, String> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind {
+        "file" => Ok(Box::new(Printer::create(arg, PrintFormat::Raw)?)),
+        "text" => Ok(Box::new(Printer::create(arg, PrintFormat::Text)?)),
+        "loopback" => Ok(Box::new(LoopbackPlug::new())),
+        "printer" | "parport" => open_host_parallel(kind, arg),
+        _ => Err(format!("unknown parallel backend {}", spec)),
+    }
+}
+
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+fn open_host_parallel(kind: &str, path: &str) -> Result<Box<dyn ParallelBackend>, String> {
+    let error = |err| format!("{}: {}", path, err);
+    match kind {
+        "parport" => Ok(Box::new(HostParallelPort::open(path).map_err(error)?)),
+        _ => Ok(Box::new(HostPrinterDevice::open(path).map_err(error)?)),
+    }
+}
+
+#[cfg(all(feature = "passthrough", not(target_os = "linux")))]
+fn open_host_parallel(kind: &str, path: &str) -> Result<Box<dyn ParallelBackend>, String> {
+    if kind == "parport" {
+        return Err("host parallel ports need a Linux host".to_string());
+    }
+    let printer = HostPrinterDevice::open(path).map_err(|err| format!("{}: {}", path, err))?;
+    Ok(Box::new(printer))
+}
+
+#[cfg(not(feature = "passthrough"))]
+fn open_host_parallel(kind: &str, _path: &str) -> Result<Box<dyn ParallelBackend>, String> {
+    Err(format!("{} needs the passthrough feature", kind))
+}
+
+/// Opens the backend a `--nic` option names: `slirp` for a user-mode
+/// network behind the host's own connection, `tap:NAME` for a host TAP
+/// interface or `pcap:INTERFACE` to put frames straight onto a host
+/// interface.
+fn open_network(spec: &str) -> Result<Box<dyn NetworkBackend>, String> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind {
+        "slirp" => Ok(Box::new(Slirp::new())),
+        "tap" | "pcap" => open_host_network(kind, arg),
+        _ => Err(format!("unknown network backend {}", spec)),
+    }
+}
+
+#[cfg(all(feature = "passthrough", target_os = "linux"))]
+fn open_host_network(kind: &str, name: &str) -> Result<Box<dyn NetworkBackend>, String> {
+    let error = |err| format!("{}: {}", name, err);
+    match kind {
+        "tap" => Ok(Box::new(HostTap::open(name).map_err(error)?)),
+        _ => Ok(Box::new(HostPacketSocket::open(name).map_err(error)?)),
+    }
+}
+
+#[cfg(all(feature = "passthrough", not(target_os = "linux")))]
+fn open_host_network(kind: &str, _name: &str) -> Result<Box<dyn NetworkBackend>, String> {
+    Err(format!("{} needs a Linux host", kind))
+}
+
+#[cfg(not(feature = "passthrough"))]
+fn open_host_network(kind: &str, _name: &str) -> Result<Box<dyn NetworkBackend>, String> {
+    Err(format!("{} needs the passthrough feature", kind))
+}
+
+#[cfg(feature = "gamepad")]
+fn open_gamepads() -> Result<Box<dyn InputSource>, String> {
+    Ok(Box::new(Gamepads::new()?))
+}
+
+#[cfg(not(feature = "gamepad"))]
+fn open_gamepads() -> Result<Box<dyn InputSource>, String> {
+    Err("built without the gamepad feature".to_string())
+}
+
+/// Images a hotkey changes a drive's disk to, one after another and round
+/// again after the last.
+struct MediaSet {
+    images: Vec<String>,
+    next: usize,
+}
+
+impl MediaSet {
+    /// The set of `first`, the image the drive starts with if any, then
+    /// `rest`. The first change is to the one after what's in the drive.
+    fn new(first: Option<&String>, rest: &[String]) -> MediaSet {
+        MediaSet {
+            images: first.into_iter().chain(rest).cloned().collect(),
+            next: first.is_some() as usize,
+        }
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let image = self.images.get(self.next % self.images.len().max(1))?;
+        self.next = (self.next + 1) % self.images.len();
+        Some(image.clone())
+    }
+}
+
+/// The machine and what feeds it and listens to it, run a frame at a time
+/// by whichever frontend shows it.
+pub struct Session {
+    pub machine: machine::Machine,
+    pub control: runcontrol::RunControl,
+    input_sources: Vec<Box<dyn InputSource>>,
+    keymap: KeyMap,
+    midi_out: Option<Box<dyn MidiOut>>,
+    audio: Option<Box<dyn AudioOut>>,
+    recorder: Option<Recorder>,
+    floppy_mode: MountMode,
+    disk_set: MediaSet,
+    cd_set: MediaSet,
+    /// Once there's been a debugger, breakpoints and watchpoints stop into
+    /// it.
+    debugger: Option<Debugger>,
+}
+
+impl Session {
+    /// Hands host input to the machine, through the key remapping table.
+    pub fn input(&mut self, event: InputEvent) {
+        if let Some(event) = self.keymap.map(event) {
+            self.machine.input(event);
+        }
+    }
+
+    /// Runs a frame's worth of the machine, returning whether it ran at
+    /// all, which it doesn't while paused.
+    pub fn run_frame(&mut self) -> bool {
+        let mut events = vec![];
+        for source in self.input_sources.iter_mut() {
+            events.extend(source.poll());
+        }
+        for event in events {
+            self.input(event);
+        }
+        let reason = self.control.run(&mut self.machine);
+        let ran = reason.is_some();
+        if self.debugger.is_some() && reason.is_some_and(StopReason::is_breakpoint) {
+            self.debug();
+        }
+        if let Some(midi_out) = &mut self.midi_out {
+            midi_out.send(&self.machine.take_midi());
+        }
+        if self.audio.is_none() && self.recorder.is_none() {
+            return ran;
+        }
+        let samples = self.machine.take_audio();
+        if let Some(audio) = &mut self.audio {
+            // Unthrottled, there's far too much to play; let it run dry.
+            if !self.control.unthrottled() {
+                audio.push(&samples, self.control.speed());
+            }
+        }
+        if let (Some(recorder), true) = (&mut self.recorder, ran) {
+            let frame = self.machine.frame();
+            let result = recorder
+                .audio(&samples)
+                .and_then(|()| frame.map_or(Ok(()), |frame| recorder.frame(&frame)));
+            if let Err(err) = result {
+                log::warn!("Recording: {}", err);
+                self.stop_recording();
+            }
+        }
+        ran
+    }
+
+    /// Stops in the debugger on the terminal until one of its commands
+    /// runs the machine again. Quitting it quits the emulator.
+    pub fn debug(&mut self) {
+        let debugger = self.debugger.get_or_insert_with(Debugger::new);
+        let stdin = io::stdin();
+        match debugger.repl(&mut self.machine, &mut stdin.lock(), &mut io::stdout()) {
+            Ok(false) => self.control.resume(),
+            _ => {
+                self.finish();
+                process::exit(0);
+            }
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Records every frame and all the sound into `dir` from now on.
+    pub fn start_recording(&mut self, dir: &Path) -> Result<(), String> {
+        self.stop_recording();
+        self.recorder = Some(Recorder::start(dir, self.machine.sample_rate())?);
+        info!("Recording to {}", dir.display());
+        Ok(())
+    }
+
+    /// Changes the disk in drive A: to the next of the --disk-set images,
+    /// returning the image now in it.
+    pub fn next_floppy(&mut self) -> Result<String, String> {
+        let path = self.disk_set.next().ok_or("no --disk-set images")?;
+        let media = FloppyMedia::open(&path, self.floppy_mode)?;
+        self.machine.change_floppy(0, media)?;
+        info!("Changed A: to {}", path);
+        Ok(path)
+    }
 
-#[allow(dead_code)]
+    /// Changes the disc in the CD-ROM drive to the next of the --cd-set
+    /// images, returning the image now in it.
+    pub fn next_cdrom(&mut self) -> Result<String, String> {
+        let path = self.cd_set.next().ok_or("no --cd-set images")?;
+        self.machine.change_cdrom(CdImage::open(&path)?)?;
+        info!("Changed the CD-ROM to {}", path);
+        Ok(path)
+    }
+
+    /// Mounts an image in the first drive it suits, as when it's dropped on
+    /// the window: discs in the CD-ROM drive, going by the name, and
+    /// floppies in A: or, failing that, B:. Returns the drive's name.
+    pub fn mount(&mut self, path: &str) -> Result<&'static str, String> {
+        let lower = path.to_ascii_lowercase();
+        if lower.ends_with(".iso") || lower.ends_with(".cue") {
+            self.machine.change_cdrom(CdImage::open(path)?)?;
+            info!("Changed the CD-ROM to {}", path);
+            return Ok("CD");
+        }
+        let media = FloppyMedia::open(path, self.floppy_mode)?;
+        let drive = match self.machine.change_floppy(0, media.clone()) {
+            Ok(_) => "A:",
+            Err(err) => match self.machine.change_floppy(1, media) {
+                Ok(_) => "B:",
+                Err(_) => return Err(format!("{}: no drive takes it ({})", path, err)),
+            },
+        };
+        info!("Changed {} to {}", drive, path);
+        Ok(drive)
+    }
+
+    /// Stops recording and finishes the trace, before the emulator exits.
+    pub fn finish(&mut self) {
+        self.stop_recording();
+        match self.machine.stop_trace() {
+            Ok(Some(count)) => info!("Traced {} instructions", count),
+            Ok(None) => {}
+            Err(err) => log::warn!("Trace: {}", err),
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            let (dir, frames) = (recorder.dir().to_path_buf(), recorder.frames());
+            match recorder.finish() {
+                Ok(()) => info!("Recorded {} frames to {}", frames, dir.display()),
+                Err(err) => log::warn!("Recording: {}", err),
+            }
+        }
+    }
+}
 
 fn main() {
-    let mut machine = IbmPc5150Machine::new();
-    //let mut cpu_thread = SchedulerThread::new(4_772_727);
-    //let mut pit_thread = SchedulerThread::new(1_193_182);
-    //let mut scheduler: Scheduler<IbmPc5150Machine> = Scheduler::new();
+    let mut args = Args::parse();
+    // A profile's options go before the command line's, which win.
+    if let Some(path) = &args.config {
+        match config::load(path, args.profile.as_deref()) {
+            Ok(profile) => {
+                let mut argv = env::args_os();
+                let program = argv.next().into_iter();
+                args = Args::parse_from(
+                    program
+                        .chain(profile.into_iter().map(Into::into))
+                        .chain(argv),
+                );
+            }
+            Err(err) => cli::fail("--config", err),
+        }
+    }
+    logging::init().unwrap();
+    if let Some(spec) = &args.log {
+        if let Err(err) = logging::apply_spec(spec) {
+            cli::fail("--log", err);
+        }
+    }
+    if let Some(spec) = &args.latency {
+        match latency::LatencySettings::parse(spec) {
+            Ok(settings) => {
+                latency::apply(&settings);
+            }
+            Err(err) => cli::fail("--latency", err),
+        }
+    }
 
-    //scheduler.threads.push(&mut cpu_thread);
-    //scheduler.threads.push(&mut pit_thread);
-    //let pit_func = IbmPc5150Machine::tick;
-    //let cpu_func = Cpu8086::tick;
-    //scheduler.threads[1].schedule(4, pit_func, &mut machine);
-    //scheduler.threads[0].schedule(1, cpu_func, &mut machine.cpu);
+    if args.machine == "list" {
+        for template in templates::TEMPLATES.iter() {
+            println!("{}", template);
+        }
+        return;
+    }
+    let mut template = match templates::find_template(&args.machine) {
+        Some(template) => *template,
+        None => cli::fail(
+            "--machine",
+            format!("unknown machine '{}', try --machine list", args.machine),
+        ),
+    };
+    if let Some(kb) = args.ram {
+        template.ram_kb = kb;
+        if let Some(cmos) = &mut template.cmos {
+            cmos.base_memory_kb = kb as u16;
+        }
+    }
+    if let Some(video) = args.video {
+        template.video = video;
+    }
+    let mut machine = template.build().unwrap_or_else(|err| {
+        eprintln!("{}: {}", template.name, err);
+        process::exit(1);
+    });
+    if args.perf_counter {
+        machine.attach_perf_counter();
+    }
+    if let Some(hz) = args.clock {
+        if let Err(err) = machine.set_clock_hz(hz) {
+            cli::fail("--clock", err);
+        }
+    }
+    if let Some(hz) = args.slow_clock {
+        if let Err(err) = machine.set_slow_clock_hz(Some(hz)) {
+            cli::fail("--slow-clock", err);
+        }
+    }
+    if let Some(on) = args.turbo {
+        machine.set_turbo(on);
+    }
+    if args.mda {
+        if let Err(err) = machine.attach_mda() {
+            cli::fail("--mda", err);
+        }
+    }
+    if let Some(image) = &args.bios {
+        if let Err(err) = image.load().and_then(|bios| machine.load_bios(bios)) {
+            cli::fail("--bios", err);
+        }
+    }
+    for (path, addr) in &args.option_roms {
+        let result = rom::OptionRom::load(path).and_then(|rom| machine.add_option_rom(rom, *addr));
+        if let Err(err) = result {
+            cli::fail("--option-rom", err);
+        }
+    }
+    if let Some(kb) = args.ext_mem {
+        match machine.set_extended_memory(kb) {
+            Ok(()) => machine.reset(reset::ResetKind::Cold),
+            Err(err) => cli::fail("--ext-mem", err),
+        }
+    }
+    for (option, ranges, kind) in [
+        ("--shadow", &args.shadow, memory::UpperMemory::Shadow),
+        ("--umb", &args.umb, memory::UpperMemory::Umb),
+    ] {
+        for &(start, end) in ranges {
+            if let Err(err) = machine.set_upper_memory(start, end, kind) {
+                cli::fail(option, err);
+            }
+        }
+    }
+    if let Some(path) = &args.cmos {
+        let result = match &mut machine {
+            machine::Machine::At(at) => at.hardware.rtc.attach_nvram(path),
+            machine::Machine::Pc(_) => Err("this machine has no CMOS".to_string()),
+        };
+        if let Err(err) = result {
+            cli::fail("--cmos", err);
+        }
+    }
+    let mode = match args.floppy_read_only {
+        true => MountMode::ReadOnly,
+        false => MountMode::WriteBack,
+    };
+    for (drive, option, path) in [(0, "--fda", &args.fda), (1, "--fdb", &args.fdb)] {
+        if let Some(path) = path {
+            let result = FloppyMedia::open(path, mode)
+                .and_then(|media| machine.change_floppy(drive, media).map(drop));
+            if let Err(err) = result {
+                cli::fail(option, err);
+            }
+        }
+    }
+    for (drive, option, path) in [(0, "--hda", &args.hda), (1, "--hdb", &args.hdb)] {
+        if let Some(path) = path {
+            let result = HardDisk::open(path).and_then(|mut disk| {
+                if args.snapshot {
+                    disk.start_snapshot(&format!("{}.snapshot", path))?;
+                }
+                Ok(disk)
+            });
+            let result = result.and_then(|disk| match &mut machine {
+                machine::Machine::At(at) => {
+                    at.hardware.attach_hard_disk(drive, disk);
+                    Ok(())
+                }
+                machine::Machine::Pc(pc) => pc.hardware.attach_hard_disk(drive, disk),
+            });
+            if let Err(err) = result {
+                cli::fail(option, err);
+            }
+        }
+    }
+    if let Some(path) = &args.cdrom {
+        let result = CdImage::open(path).and_then(|image| match &mut machine {
+            machine::Machine::At(at) => {
+                at.hardware.attach_cdrom(Some(image));
+                Ok(())
+            }
+            machine::Machine::Pc(_) => Err("this machine has no IDE channel".to_string()),
+        });
+        if let Err(err) = result {
+            cli::fail("--cdrom", err);
+        }
+    }
+    if args.adlib {
+        machine.attach_adlib();
+    }
+    if let Some(model) = args.sb {
+        machine.attach_sound_blaster(model);
+    }
+    if let Some(size_kb) = args.ems {
+        let config = ems::EmsConfig {
+            base: args.ems_port.unwrap_or(ems::EMS_BASE),
+            frame: args.ems_frame.unwrap_or(ems::PageFrame::D000),
+            size_kb,
+        };
+        if let Err(err) = machine.add_card(machine::IsaCard::Ems(config)) {
+            cli::fail("--ems", err);
+        }
+    }
+    let midi_out = open_midi_out(&args);
+    if midi_out.is_some() {
+        machine.attach_mpu401();
+    }
+    let com = [&args.com1, &args.com2, &args.com3, &args.com4];
+    for (port, spec) in com.iter().enumerate() {
+        if let Some(spec) = spec {
+            let result = open_serial(spec).and_then(|backend| match machine.serial_mut(port) {
+                Some(uart) => {
+                    uart.connect(backend);
+                    Ok(())
+                }
+                None => Err(format!("this machine has no COM{}", port + 1)),
+            });
+            if let Err(err) = result {
+                cli::fail(&format!("--com{}", port + 1), err);
+            }
+        }
+    }
+    let lpt = [&args.lpt1, &args.lpt2, &args.lpt3];
+    for (port, spec) in lpt.iter().enumerate() {
+        if let Some(spec) = spec {
+            match open_parallel(spec) {
+                Ok(backend) => machine.lpt_mut(port).connect(backend),
+                Err(err) => cli::fail(&format!("--lpt{}", port + 1), err),
+            }
+        }
+    }
+    if let Some(spec) = &args.nic {
+        match open_network(spec) {
+            Ok(backend) => machine.attach_ne2000(
+                args.nic_port.unwrap_or(NE2000_BASE),
+                args.nic_irq.unwrap_or(NE2000_IRQ),
+                DEFAULT_MAC,
+                backend,
+            ),
+            Err(err) => cli::fail("--nic", err),
+        }
+    }
+    let mut input_sources: Vec<Box<dyn InputSource>> = vec![];
+    if let Some(spec) = &args.joystick {
+        let result = match spec.split_once(':').unwrap_or((spec, "")) {
+            ("gamepad", _) => open_gamepads().map(|gamepads| {
+                input_sources.push(gamepads);
+                None
+            }),
+            ("keys", "") => Ok(Some(VirtualJoystick::new())),
+            ("keys", map) => VirtualJoystick::parse(map).map(Some),
+            _ => Err("expected gamepad or keys[:MAP]".to_string()),
+        };
+        match result {
+            Ok(virtual_joystick) => machine.attach_game_port(virtual_joystick),
+            Err(err) => cli::fail("--joystick", err),
+        }
+    }
+    if args.ps2_mouse {
+        if let Err(err) = machine.attach_ps2_mouse() {
+            cli::fail("--ps2-mouse", err);
+        }
+    }
+    if args.cga_snow || args.composite {
+        match machine.cga_mut() {
+            Some(cga) => {
+                cga.snow = args.cga_snow;
+                cga.composite = args.composite;
+            }
+            None => eprintln!("--cga-snow and --composite need a CGA, ignoring them"),
+        }
+    }
 
-    let bootsector: Vec<u8> = fs::read("pcdos10.img").unwrap();
-    for i in 0..=511 {
-        machine.hardware.ram[i + 0x7c00] = bootsector[i];
+    if let Some(path) = &args.fda {
+        let image = fs::read(path).unwrap_or_default();
+        check_compatibility(&template, &image);
+        if args.direct_boot {
+            match &mut machine {
+                machine::Machine::Pc(pc) if image.len() >= 512 => {
+                    pc.hardware.memory.ram[0x7c00..0x7e00].copy_from_slice(&image[..512]);
+                    pc.cpu.floppy = image;
+                    pc.cpu.regs.ip = 0;
+                    pc.cpu.regs.seg_regs[1] = 0x7c0;
+                }
+                machine::Machine::Pc(_) => cli::fail("--direct-boot", "no boot sector on A:"),
+                machine::Machine::At(_) => {
+                    cli::fail("--direct-boot", "only the PC can boot without POST")
+                }
+            }
+        }
     }
-    machine.cpu.floppy = bootsector.clone();
 
-    machine.cpu.regs.ip = 0;
-    machine.cpu.regs.seg_regs[1] = 0x7c0;
+    if let Some(path) = &args.trace {
+        let filter = args.trace_filter.clone().unwrap_or_default();
+        let result = Tracer::create(path, machine.isa(), filter).and_then(|tracer| {
+            let tracer = match args.trace_ring {
+                Some(capacity) => tracer.ring_buffer(capacity as usize),
+                None => tracer,
+            };
+            machine.start_trace(tracer)
+        });
+        if let Err(err) = result {
+            cli::fail("--trace", err);
+        }
+    }
 
+    let mut control = runcontrol::RunControl::new();
+    if let Err(err) = control.set_speed(args.speed) {
+        cli::fail("--speed", err);
+    }
+    control.set_unthrottled(args.unthrottled);
+    let audio = open_audio(&args);
+    if let Some(audio) = &audio {
+        machine.set_sample_rate(audio.sample_rate());
+    }
+    let mut session = Session {
+        machine,
+        control,
+        input_sources,
+        keymap: args.keymap.clone().unwrap_or_default(),
+        midi_out,
+        audio,
+        recorder: None,
+        floppy_mode: mode,
+        disk_set: MediaSet::new(args.fda.as_ref(), &args.disk_set),
+        cd_set: MediaSet::new(args.cdrom.as_ref(), &args.cd_set),
+        debugger: None,
+    };
+    if let Some(dir) = &args.record {
+        if let Err(err) = session.start_recording(dir) {
+            cli::fail("--record", err);
+        }
+    }
+    if args.debug {
+        session.debug();
+    }
+    #[cfg(feature = "window")]
+    if !args.headless {
+        let options = window::Options {
+            scale: args.scale,
+            scaling: match args.integer_scale {
+                true => renderer::Scaling::Integer,
+                false => renderer::Scaling::Fit,
+            },
+            mouse_sensitivity: args.mouse_sensitivity,
+            screenshot_dir: args.screenshot_dir.clone(),
+            status_bar: args.status_bar,
+        };
+        if let Err(err) = window::run(session, options) {
+            eprintln!("Window: {}, try --headless", err);
+            process::exit(1);
+        }
+        return;
+    }
+    if !args.exit_when.is_empty() {
+        let exit = headless::run(&mut session.machine, &args.exit_when);
+        print!("{}", headless::dump(&session.machine, exit));
+        session.finish();
+        process::exit(exit.status);
+    }
+    let mut pacer = runcontrol::FramePacer::new();
     loop {
-        let cycles: usize = machine.cpu.tick(&mut machine.hardware);
-        machine.tick(cycles);
+        session.run_frame();
+        if !args.headless {
+            pacer.wait(session.control.frame_duration());
+        }
     }
 }
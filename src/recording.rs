@@ -0,0 +1,168 @@
+// Recording what the machine shows and plays, into a directory: every
+// emulated frame as a numbered PNG and the mixed audio as one WAV. That's
+// no use for watching, but anything can make a video of it, e.g.
+//
+//     ffmpeg -framerate 59.92 -i frame-%06d.png -i audio.wav video.mp4
+//
+// Every frame is written, changed or not and however fast the machine is
+// running, so the pictures and the sound stay in step with each other.
+use crate::renderer::screenshot::encode_png;
+use crate::renderer::Frame;
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The size of a WAV file's header, before the samples.
+const WAV_HEADER_LEN: u32 = 44;
+
+/// Writes 16-bit mono PCM to a WAV file. The sizes in the header aren't
+/// known until the end, so `finish` goes back and fills them in.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> std::io::Result<WavWriter<W>> {
+        let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&1u16.to_le_bytes()); // mono
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        out.write_all(&header)?;
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        let data: Vec<u8> = samples
+            .iter()
+            .flat_map(|&sample| ((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+            .collect();
+        self.out.write_all(&data)?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Fills in the sizes, returning the writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let data_len = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(WAV_HEADER_LEN - 8 + data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_len.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// A recording in progress.
+pub struct Recorder {
+    dir: PathBuf,
+    frames: u64,
+    wav: WavWriter<BufWriter<File>>,
+}
+
+impl Recorder {
+    /// Starts recording into `dir`, which is made if it isn't there, with
+    /// audio at `sample_rate`.
+    pub fn start(dir: &Path, sample_rate: u32) -> Result<Recorder, String> {
+        let error = |err: std::io::Error| format!("{}: {}", dir.display(), err);
+        fs::create_dir_all(dir).map_err(error)?;
+        let file = File::create(dir.join("audio.wav")).map_err(error)?;
+        let wav = WavWriter::new(BufWriter::new(file), sample_rate).map_err(error)?;
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            frames: 0,
+            wav,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Frames recorded so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn frame(&mut self, frame: &Frame) -> Result<(), String> {
+        frame.check()?;
+        self.frames += 1;
+        let path = self.dir.join(format!("frame-{:06}.png", self.frames));
+        let file = File::create(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        encode_png(
+            frame.width,
+            frame.height,
+            frame.pixels,
+            BufWriter::new(file),
+        )
+        .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn audio(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.wav
+            .write(samples)
+            .map_err(|err| format!("{}: {}", self.dir.display(), err))
+    }
+
+    /// Stops recording, leaving the WAV file complete.
+    pub fn finish(self) -> Result<(), String> {
+        let dir = self.dir;
+        self.wav
+            .finish()
+            .map(drop)
+            .map_err(|err| format!("{}: {}", dir.display(), err))
+    }
+}
+
+/// The first of `emupc-rec-0001`, `emupc-rec-0002` and so on in `dir`
+/// that doesn't exist yet.
+pub fn next_dir(dir: &Path) -> PathBuf {
+    (1..)
+        .map(|n| dir.join(format!("emupc-rec-{:04}", n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+#[test]
+fn test_wav_writer() {
+    let mut wav = WavWriter::new(std::io::Cursor::new(vec![]), 48_000).unwrap();
+    wav.write(&[0.0, 1.0]).unwrap();
+    wav.write(&[-2.0]).unwrap();
+    let data = wav.finish().unwrap().into_inner();
+    assert_eq!(data.len(), 44 + 6);
+    assert_eq!(&data[..4], b"RIFF");
+    assert_eq!(data[4..8], 42u32.to_le_bytes());
+    assert_eq!(data[24..28], 48_000u32.to_le_bytes());
+    assert_eq!(data[40..44], 6u32.to_le_bytes());
+    assert_eq!(data[44..], [0x00, 0x00, 0xff, 0x7f, 0x01, 0x80]);
+}
+
+#[test]
+fn test_recorder() {
+    let dir = std::env::temp_dir().join("emupc-recording-test");
+    let _ = fs::remove_dir_all(&dir);
+    let mut recorder = Recorder::start(&dir, 44_100).unwrap();
+    let pixels = [0x00ffffff; 4];
+    recorder.frame(&Frame::new(2, 2, &pixels)).unwrap();
+    recorder.audio(&[0.5; 735]).unwrap();
+    recorder.frame(&Frame::new(2, 2, &pixels)).unwrap();
+    assert_eq!(recorder.frames(), 2);
+    recorder.finish().unwrap();
+    assert!(dir.join("frame-000002.png").exists());
+    assert_eq!(
+        fs::metadata(dir.join("audio.wav")).unwrap().len(),
+        44 + 1470
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
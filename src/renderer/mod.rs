@@ -0,0 +1,161 @@
+// Turns the framebuffer the emulated video card produces into something a
+// frontend can show. Frontends only talk to the `Renderer` trait, so the
+// desktop window, libretro and WASM builds can pick whichever
+// implementation their platform supports.
+pub mod font;
+pub mod osd;
+pub mod screenshot;
+pub mod software;
+#[cfg(feature = "wgpu")]
+pub mod wgpu;
+
+/// A rectangle in framebuffer pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Clips the rectangle to a `width` x `height` area.
+    pub fn clip(&self, width: u32, height: u32) -> Rect {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        Rect {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        }
+    }
+}
+
+/// One emulated video frame. Pixels are 0x00RRGGBB, row by row.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: &'a [u32],
+    /// The parts of the frame that changed since the last one, or `None`
+    /// if all of it may have.
+    pub dirty: Option<&'a [Rect]>,
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(width: u32, height: u32, pixels: &'a [u32]) -> Frame<'a> {
+        Frame {
+            width,
+            height,
+            pixels,
+            dirty: None,
+        }
+    }
+
+    pub fn with_dirty(self, dirty: &'a [Rect]) -> Frame<'a> {
+        Frame {
+            dirty: Some(dirty),
+            ..self
+        }
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.pixels.len() != (self.width * self.height) as usize {
+            return Err(format!(
+                "{}x{} frame has {} pixels",
+                self.width,
+                self.height,
+                self.pixels.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub trait Renderer {
+    fn name(&self) -> &'static str;
+    /// Sets the size of the output in host pixels. Frames are scaled to
+    /// fill it.
+    fn resize(&mut self, width: u32, height: u32);
+    fn render(&mut self, frame: &Frame) -> Result<(), String>;
+}
+
+/// How frames are fitted to the output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scaling {
+    /// As large as fits, at the 4:3 of the monitors the cards drove.
+    Fit,
+    /// Whole multiples of the frame's size across and down, so every
+    /// pixel is drawn the same size: the largest that comes within about
+    /// 20% of 4:3, or `Fit` when none does.
+    Integer,
+}
+
+/// How far off 4:3 integer scaling may be, as the log of the ratio.
+const MAX_ASPECT_ERROR: f64 = 0.2;
+
+/// Where a `frame_width` x `frame_height` frame goes in the output,
+/// centred.
+pub fn viewport(
+    frame_width: u32,
+    frame_height: u32,
+    width: u32,
+    height: u32,
+    scaling: Scaling,
+) -> Rect {
+    let centred = |w: u32, h: u32| Rect::new((width - w) / 2, (height - h) / 2, w, h);
+    if scaling == Scaling::Integer && frame_width > 0 && frame_height > 0 {
+        let best = (1..=width / frame_width)
+            .flat_map(|x| {
+                (1..=height / frame_height).map(move |y| (x * frame_width, y * frame_height))
+            })
+            .filter(|&(w, h)| (w as f64 / h as f64 * 3.0 / 4.0).ln().abs() <= MAX_ASPECT_ERROR)
+            .max_by_key(|&(w, h)| w * h);
+        if let Some((w, h)) = best {
+            return centred(w, h);
+        }
+    }
+    let w = width.min(height * 4 / 3);
+    centred(w, w * 3 / 4)
+}
+
+#[test]
+fn test_viewport() {
+    // Letterboxed and pillarboxed to 4:3.
+    assert_eq!(
+        viewport(640, 200, 1000, 600, Scaling::Fit),
+        Rect::new(100, 0, 800, 600)
+    );
+    assert_eq!(
+        viewport(640, 200, 800, 900, Scaling::Fit),
+        Rect::new(0, 150, 800, 600)
+    );
+    // CGA's 640x200 doubles its lines, MDA's 720x350 stays as it is.
+    assert_eq!(
+        viewport(640, 200, 800, 600, Scaling::Integer),
+        Rect::new(80, 100, 640, 400)
+    );
+    assert_eq!(
+        viewport(640, 200, 1920, 1080, Scaling::Integer),
+        Rect::new(320, 40, 1280, 1000)
+    );
+    // MDA needs more room before its pixels can all be the same size.
+    assert_eq!(
+        viewport(720, 350, 800, 600, Scaling::Integer),
+        Rect::new(0, 0, 800, 600)
+    );
+    assert_eq!(
+        viewport(720, 350, 1920, 1080, Scaling::Integer),
+        Rect::new(240, 15, 1440, 1050)
+    );
+}
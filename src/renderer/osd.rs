@@ -0,0 +1,186 @@
+// The on-screen display: short messages that fade out after a few seconds,
+// a drive activity indicator and an optional status line, drawn over
+// whatever a frontend is about to show. It's drawn into the frontend's own
+// output rather than the machine's frame, so screenshots and recordings
+// never have it in them.
+use crate::renderer::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How long a message stays up.
+pub const MESSAGE_DURATION: Duration = Duration::from_secs(3);
+
+/// The most messages up at once. A new one pushes the oldest off.
+const MAX_MESSAGES: usize = 4;
+
+const TEXT_COLOR: u32 = 0x00ffffff;
+const ACTIVITY_COLOR: u32 = 0x0040ff40;
+
+/// Space around text, in unscaled pixels.
+const MARGIN: u32 = 2;
+
+#[derive(Clone, Debug, Default)]
+pub struct Osd {
+    messages: VecDeque<(String, Instant)>,
+    activity: Option<String>,
+    status: Option<String>,
+}
+
+impl Osd {
+    pub fn new() -> Osd {
+        Osd::default()
+    }
+
+    /// Puts up a message, from `now` until `MESSAGE_DURATION` later.
+    pub fn message(&mut self, text: impl Into<String>, now: Instant) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages
+            .push_back((text.into(), now + MESSAGE_DURATION));
+    }
+
+    /// Takes down the messages that have had their time, returning whether
+    /// any did.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        let before = self.messages.len();
+        self.messages.retain(|(_, until)| *until > now);
+        self.messages.len() != before
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &str> {
+        self.messages.iter().map(|(text, _)| text.as_str())
+    }
+
+    /// Sets the indicator in the top right corner, e.g. the drives that are
+    /// busy, or clears it with `None`.
+    pub fn set_activity(&mut self, activity: Option<String>) {
+        self.activity = activity;
+    }
+
+    /// Sets the line along the bottom, or clears it with `None`.
+    pub fn set_status(&mut self, status: Option<String>) {
+        self.status = status;
+    }
+
+    /// Whether there's anything to draw.
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty() && self.activity.is_none() && self.status.is_none()
+    }
+
+    /// Draws everything over `pixels`, a `width` x `height` picture. The
+    /// text is scaled up on bigger pictures so it stays readable.
+    pub fn draw(&self, pixels: &mut [u32], width: u32, height: u32) {
+        let scale = (height / 480).max(1);
+        let line = (GLYPH_HEIGHT + MARGIN * 2) * scale;
+        let mut canvas = Canvas {
+            pixels,
+            width,
+            height,
+            scale,
+        };
+        for (row, (text, _)) in (0..).zip(&self.messages) {
+            canvas.label(0, row * line, text, TEXT_COLOR);
+        }
+        if let Some(activity) = &self.activity {
+            let x = width.saturating_sub(canvas.text_width(activity));
+            canvas.label(x, 0, activity, ACTIVITY_COLOR);
+        }
+        if let Some(status) = &self.status {
+            let y = height.saturating_sub(line);
+            canvas.shade(0, y, width, line);
+            canvas.text(0, y, status, TEXT_COLOR);
+        }
+    }
+}
+
+struct Canvas<'a> {
+    pixels: &'a mut [u32],
+    width: u32,
+    height: u32,
+    scale: u32,
+}
+
+impl Canvas<'_> {
+    fn text_width(&self, text: &str) -> u32 {
+        (text.chars().count() as u32 * GLYPH_WIDTH + MARGIN * 2) * self.scale
+    }
+
+    /// Darkens a rectangle to half brightness, for text to stand out on.
+    fn shade(&mut self, x: u32, y: u32, width: u32, height: u32) {
+        let right = (x + width).min(self.width);
+        for y in y..(y + height).min(self.height) {
+            let row = (y * self.width) as usize;
+            for pixel in &mut self.pixels[row + x.min(right) as usize..row + right as usize] {
+                *pixel = (*pixel >> 1) & 0x007f7f7f;
+            }
+        }
+    }
+
+    /// Draws `text` on a shaded box whose top left corner is at `x`, `y`.
+    fn label(&mut self, x: u32, y: u32, text: &str, color: u32) {
+        let height = (GLYPH_HEIGHT + MARGIN * 2) * self.scale;
+        self.shade(x, y, self.text_width(text), height);
+        self.text(x, y, text, color);
+    }
+
+    /// Draws `text` inside the margin from `x`, `y`, cut off at the edges.
+    fn text(&mut self, x: u32, y: u32, text: &str, color: u32) {
+        let scale = self.scale;
+        let (x, y) = (x + MARGIN * scale, y + MARGIN * scale);
+        for (n, c) in (0..).zip(text.chars()) {
+            let left = x + n * GLYPH_WIDTH * scale;
+            for (row, bits) in (0..).zip(font::glyph(c)) {
+                for column in (0..GLYPH_WIDTH).filter(|column| bits & (1 << column) != 0) {
+                    self.fill(left + column * scale, y + row * scale, scale, color);
+                }
+            }
+        }
+    }
+
+    fn fill(&mut self, x: u32, y: u32, size: u32, color: u32) {
+        for y in y..(y + size).min(self.height) {
+            for x in x..(x + size).min(self.width) {
+                self.pixels[(y * self.width + x) as usize] = color;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_osd() {
+    let start = Instant::now();
+    let mut osd = Osd::new();
+    assert!(osd.is_empty());
+    for n in 0..5 {
+        osd.message(format!("message {}", n), start + Duration::from_secs(n));
+    }
+    assert_eq!(osd.messages().next(), Some("message 1"));
+    assert!(!osd.tick(start + Duration::from_secs(3)));
+    assert!(osd.tick(start + Duration::from_secs(5)));
+    assert_eq!(
+        osd.messages().collect::<Vec<_>>(),
+        ["message 3", "message 4"]
+    );
+
+    // "!" on white: its top row is bits 3 and 4, on a box shaded grey
+    // from the corner.
+    let mut osd = Osd::new();
+    osd.message("!", start);
+    let mut pixels = vec![0x00ffffff; 40 * 20];
+    osd.draw(&mut pixels, 40, 20);
+    let row = &pixels[MARGIN as usize * 40..];
+    assert_eq!(row[..5], [0x007f7f7f; 5]);
+    assert_eq!(row[5..7], [TEXT_COLOR; 2]);
+    assert_eq!(row[7..12], [0x007f7f7f; 5]);
+    assert_eq!(row[12], 0x00ffffff);
+
+    // The status line runs the width of the bottom, and nothing is drawn
+    // off the edges of a picture too small for it.
+    osd.set_status(Some("a status line far wider than the picture".into()));
+    osd.set_activity(Some("HD".into()));
+    let mut pixels = vec![0x00ffffff; 40 * 20];
+    osd.draw(&mut pixels, 40, 20);
+    assert_eq!(pixels[19 * 40 + 39], 0x007f7f7f);
+    assert_eq!(pixels[39], 0x007f7f7f);
+}
@@ -0,0 +1,84 @@
+// Screenshots, as PNG. The plain kind is the frame exactly as the video
+// card made it, one pixel for each of its pixels, which is what regression
+// comparisons want. The aspect-corrected kind is stretched to the 4:3 the
+// monitor showed it at, which is what a bug report wants: 640x200 comes
+// out as 640x480 rather than squashed.
+use crate::renderer::software::SoftwareRenderer;
+use crate::renderer::{Frame, Renderer};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The size a `width` x `height` frame has at 4:3, keeping whichever side
+/// is longer for the ratio so no detail is lost.
+pub fn aspect_size(width: u32, height: u32) -> (u32, u32) {
+    if width * 3 >= height * 4 {
+        (width, width * 3 / 4)
+    } else {
+        (height * 4 / 3, height)
+    }
+}
+
+/// Writes 0x00RRGGBB pixels out as an RGB PNG.
+pub fn encode_png(width: u32, height: u32, pixels: &[u32], out: impl Write) -> Result<(), String> {
+    let mut encoder = png::Encoder::new(out, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+    let data: Vec<u8> = pixels
+        .iter()
+        .flat_map(|&pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8])
+        .collect();
+    writer
+        .write_image_data(&data)
+        .map_err(|err| err.to_string())?;
+    writer.finish().map_err(|err| err.to_string())
+}
+
+/// Saves `frame` to `path`, stretched to 4:3 if `aspect_correct`.
+pub fn save(frame: &Frame, path: &Path, aspect_correct: bool) -> Result<(), String> {
+    frame.check()?;
+    let file = File::create(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let out = BufWriter::new(file);
+    let result = match aspect_correct {
+        false => encode_png(frame.width, frame.height, frame.pixels, out),
+        true => {
+            let (width, height) = aspect_size(frame.width, frame.height);
+            let mut renderer = SoftwareRenderer::new(width, height);
+            renderer.render(frame)?;
+            encode_png(width, height, renderer.output(), out)
+        }
+    };
+    result.map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// The first of `emupc-0001.png`, `emupc-0002.png` and so on in `dir`
+/// that doesn't exist yet.
+pub fn next_path(dir: &Path) -> PathBuf {
+    (1..)
+        .map(|n| dir.join(format!("emupc-{:04}.png", n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+#[test]
+fn test_screenshot() {
+    assert_eq!(aspect_size(640, 200), (640, 480));
+    assert_eq!(aspect_size(320, 200), (320, 240));
+    assert_eq!(aspect_size(720, 350), (720, 540));
+    assert_eq!(aspect_size(640, 480), (640, 480));
+    assert_eq!(aspect_size(200, 300), (400, 300));
+
+    let pixels = [0x00ff0000, 0x0000ff00, 0x000000ff, 0x00123456];
+    let mut png = vec![];
+    encode_png(2, 2, &pixels, &mut png).unwrap();
+    let decoder = png::Decoder::new(&png[..]);
+    let mut reader = decoder.read_info().unwrap();
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).unwrap();
+    assert_eq!((info.width, info.height), (2, 2));
+    assert_eq!(
+        &data[..info.buffer_size()],
+        [255, 0, 0, 0, 255, 0, 0, 0, 255, 0x12, 0x34, 0x56]
+    );
+}
@@ -0,0 +1,109 @@
+use crate::renderer::{Frame, Rect, Renderer};
+
+/// Scales frames into a plain pixel buffer with nearest neighbour
+/// sampling. Works everywhere, and is what libretro and WASM builds hand
+/// to their hosts.
+#[derive(Clone, Debug, Default)]
+pub struct SoftwareRenderer {
+    width: u32,
+    height: u32,
+    output: Vec<u32>,
+    /// Size of the last frame, so a size change forces a full redraw.
+    source_size: (u32, u32),
+}
+
+impl SoftwareRenderer {
+    pub fn new(width: u32, height: u32) -> SoftwareRenderer {
+        SoftwareRenderer {
+            width,
+            height,
+            output: vec![0; (width * height) as usize],
+            source_size: (0, 0),
+        }
+    }
+
+    /// The scaled image, 0x00RRGGBB, row by row.
+    pub fn output(&self) -> &[u32] {
+        &self.output
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Redraws the output pixels covering `rect` of the frame.
+    fn blit(&mut self, frame: &Frame, rect: Rect) {
+        let rect = rect.clip(frame.width, frame.height);
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        let (sw, sh) = (frame.width as u64, frame.height as u64);
+        let (dw, dh) = (self.width as u64, self.height as u64);
+        // The output pixels whose nearest source pixel lies inside `rect`.
+        let x0 = (rect.x as u64 * dw).div_ceil(sw);
+        let x1 = ((rect.x + rect.width) as u64 * dw).div_ceil(sw);
+        let y0 = (rect.y as u64 * dh).div_ceil(sh);
+        let y1 = ((rect.y + rect.height) as u64 * dh).div_ceil(sh);
+        for y in y0..y1 {
+            let src_row = (y * sh / dh) as usize * frame.width as usize;
+            let dst_row = y as usize * self.width as usize;
+            for x in x0..x1 {
+                let src_x = (x * sw / dw) as usize;
+                self.output[dst_row + x as usize] = frame.pixels[src_row + src_x];
+            }
+        }
+    }
+}
+
+impl Renderer for SoftwareRenderer {
+    fn name(&self) -> &'static str {
+        "software"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.output = vec![0; (width * height) as usize];
+        self.source_size = (0, 0);
+    }
+
+    fn render(&mut self, frame: &Frame) -> Result<(), String> {
+        frame.check()?;
+        if frame.width == 0 || frame.height == 0 {
+            return Ok(());
+        }
+        let whole = Rect::new(0, 0, frame.width, frame.height);
+        match frame.dirty {
+            Some(dirty) if self.source_size == (frame.width, frame.height) => {
+                for rect in dirty {
+                    self.blit(frame, *rect);
+                }
+            }
+            _ => self.blit(frame, whole),
+        }
+        self.source_size = (frame.width, frame.height);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_software_renderer_scales_dirty_rects() {
+    let mut renderer = SoftwareRenderer::new(4, 4);
+    let mut pixels = vec![1, 2, 3, 4];
+    renderer.render(&Frame::new(2, 2, &pixels)).unwrap();
+    assert_eq!(
+        renderer.output(),
+        &[1, 1, 2, 2, 1, 1, 2, 2, 3, 3, 4, 4, 3, 3, 4, 4]
+    );
+
+    // Only the dirty pixel is redrawn, even though the others changed too.
+    pixels = vec![9, 9, 9, 5];
+    let dirty = [Rect::new(1, 1, 1, 1)];
+    renderer
+        .render(&Frame::new(2, 2, &pixels).with_dirty(&dirty))
+        .unwrap();
+    assert_eq!(&renderer.output()[8..], &[3, 3, 5, 5, 3, 3, 5, 5]);
+    assert_eq!(&renderer.output()[..4], &[1, 1, 2, 2]);
+
+    assert!(renderer.render(&Frame::new(3, 2, &pixels)).is_err());
+}
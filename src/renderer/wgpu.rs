@@ -0,0 +1,286 @@
+use crate::renderer::{Frame, Rect, Renderer};
+use std::borrow::Cow;
+
+const SHADER: &str = r#"
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// One triangle covering the whole target.
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+@group(0) @binding(0) var frame: texture_2d<f32>;
+@group(0) @binding(1) var frame_sampler: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(textureSample(frame, frame_sampler, in.uv).rgb, 1.0);
+}
+"#;
+
+/// Uploads frames to a texture and scales them on the GPU. Only the dirty
+/// parts of a frame are uploaded.
+///
+/// The frontend owns the surface: before each `render` it hands over the
+/// view of the texture to draw into with `set_target`.
+pub struct WgpuRenderer {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    /// The frame texture, its bind group and its size.
+    texture: Option<(wgpu::Texture, wgpu::BindGroup, u32, u32)>,
+    target: Option<wgpu::TextureView>,
+    width: u32,
+    height: u32,
+    staging: Vec<u8>,
+}
+
+impl WgpuRenderer {
+    /// Creates a renderer drawing into targets of `format`.
+    pub fn new(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        format: wgpu::TextureFormat,
+    ) -> WgpuRenderer {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("frame shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(SHADER)),
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("frame bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("frame pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("frame pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                compilation_options: Default::default(),
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                compilation_options: Default::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+        // Nearest filtering keeps text crisp at integer scales.
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("frame sampler"),
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        WgpuRenderer {
+            device,
+            queue,
+            pipeline,
+            bind_group_layout,
+            sampler,
+            texture: None,
+            target: None,
+            width: 0,
+            height: 0,
+            staging: vec![],
+        }
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.queue
+    }
+
+    pub fn set_target(&mut self, view: wgpu::TextureView) {
+        self.target = Some(view);
+    }
+
+    /// Makes sure the frame texture matches the frame size. Returns true if
+    /// it had to be recreated, which means the whole frame must be uploaded.
+    fn prepare_texture(&mut self, width: u32, height: u32) -> bool {
+        if let Some((_, _, w, h)) = self.texture {
+            if (w, h) == (width, height) {
+                return false;
+            }
+        }
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("frame"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            // 0x00RRGGBB words are B, G, R, X in memory.
+            format: wgpu::TextureFormat::Bgra8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("frame bind group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        });
+        self.texture = Some((texture, bind_group, width, height));
+        true
+    }
+
+    fn upload(&mut self, frame: &Frame, rect: Rect) {
+        let rect = rect.clip(frame.width, frame.height);
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        self.staging.clear();
+        for y in rect.y..rect.y + rect.height {
+            let start = (y * frame.width + rect.x) as usize;
+            for pixel in &frame.pixels[start..start + rect.width as usize] {
+                self.staging.extend_from_slice(&pixel.to_le_bytes());
+            }
+        }
+        let texture = match &self.texture {
+            Some((texture, ..)) => texture,
+            None => return,
+        };
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: rect.x,
+                    y: rect.y,
+                    z: 0,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            &self.staging,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(rect.width * 4),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: rect.width,
+                height: rect.height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}
+
+impl Renderer for WgpuRenderer {
+    fn name(&self) -> &'static str {
+        "wgpu"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        // The target view already has the right size; this only matters
+        // to frontends that ask the renderer for it.
+        self.width = width;
+        self.height = height;
+    }
+
+    fn render(&mut self, frame: &Frame) -> Result<(), String> {
+        frame.check()?;
+        if frame.width == 0 || frame.height == 0 {
+            return Ok(());
+        }
+        let recreated = self.prepare_texture(frame.width, frame.height);
+        match frame.dirty {
+            Some(dirty) if !recreated => {
+                for rect in dirty {
+                    self.upload(frame, *rect);
+                }
+            }
+            _ => self.upload(frame, Rect::new(0, 0, frame.width, frame.height)),
+        }
+
+        let target = self.target.as_ref().ok_or("no render target set")?;
+        let bind_group = match &self.texture {
+            Some((_, bind_group, ..)) => bind_group,
+            None => return Ok(()),
+        };
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("frame encoder"),
+            });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("frame pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        self.queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+}
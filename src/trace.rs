@@ -0,0 +1,350 @@
+// An instruction trace: a line for every instruction the CPU runs, with
+// where it is, its bytes and disassembly, and the registers it changed,
+//
+//     FFFF:0000  EA5BE000F0    jmp 0xf000:0xe05b                  CS=F000
+//     F000:E05B  FA            cli
+//     F000:E05C  B080          mov al, 0x80                       AX=0080
+//
+// IP isn't among them, as the next line shows where it went. Lines go to a
+// file as the machine runs or, in ring buffer mode, only the last so many
+// are kept and written when the trace stops, which is what finding out how
+// a program crashed wants. Either way the disassembly is left until a line
+// is written. Filters keep a trace of millions of instructions to the code
+// of interest, by physical address and by CS.
+use crate::cpu::CpuState;
+use crate::disasm::{self, Isa};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Where the changed registers start on a line.
+const CHANGES_COLUMN: usize = 60;
+
+/// The registers a line shows the changes of, as `CpuState` has them and
+/// in its order, flags last.
+const REGISTERS: [&str; 13] = [
+    "AX", "CX", "DX", "BX", "SP", "BP", "SI", "DI", "ES", "CS", "SS", "DS", "FL",
+];
+
+/// Which instructions go in a trace: those at a physical address in one of
+/// the ranges, if there are any, and with CS one of the segments, if there
+/// are any.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceFilter {
+    /// The first and last address of each range.
+    pub ranges: Vec<(u32, u32)>,
+    pub segments: Vec<u16>,
+}
+
+impl TraceFilter {
+    /// Parses filters as the command line has them, comma separated and in
+    /// hex, e.g. `cs=f000,range=fe000-fffff`.
+    pub fn parse(spec: &str) -> Result<TraceFilter, String> {
+        let hex = |value: &str| u32::from_str_radix(value.trim_start_matches("0x"), 16).ok();
+        let mut filter = TraceFilter::default();
+        for part in spec.split(',') {
+            match part.split_once('=') {
+                Some(("cs", seg)) => match hex(seg).filter(|&seg| seg <= 0xffff) {
+                    Some(seg) => filter.segments.push(seg as u16),
+                    None => return Err(format!("expected a segment like f000, not '{}'", seg)),
+                },
+                Some(("range", range)) => {
+                    match range
+                        .split_once('-')
+                        .map(|(start, end)| (hex(start), hex(end)))
+                    {
+                        Some((Some(start), Some(end))) if start <= end => {
+                            filter.ranges.push((start, end))
+                        }
+                        _ => {
+                            return Err(format!(
+                                "expected a range like fe000-fffff, not '{}'",
+                                range
+                            ))
+                        }
+                    }
+                }
+                _ => return Err(format!("expected cs= or range=, not '{}'", part)),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether the instruction at `cs`, at `physical`, goes in the trace.
+    pub fn passes(&self, cs: u16, physical: u32) -> bool {
+        (self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|&(start, end)| (start..=end).contains(&physical)))
+            && (self.segments.is_empty() || self.segments.contains(&cs))
+    }
+}
+
+/// An instruction as it ran, as small as it'll go, for ring buffers of
+/// millions of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Record {
+    cs: u16,
+    ip: u16,
+    bytes: [u8; disasm::MAX_LENGTH],
+    /// Which of `REGISTERS` changed, a bit each.
+    changed: u16,
+    values: [u16; 13],
+}
+
+impl Record {
+    fn new(before: &CpuState, bytes: [u8; disasm::MAX_LENGTH], after: &CpuState) -> Record {
+        let registers = |state: &CpuState| {
+            let mut values = [0; 13];
+            values[..8].copy_from_slice(&state.gprs);
+            values[8..12].copy_from_slice(&state.seg_regs);
+            values[12] = state.flags;
+            values
+        };
+        let (old, values) = (registers(before), registers(after));
+        let changed = (0..13)
+            .filter(|&n| old[n] != values[n])
+            .fold(0, |changed, n| changed | 1 << n);
+        Record {
+            cs: before.cs(),
+            ip: before.ip,
+            bytes,
+            changed,
+            values,
+        }
+    }
+
+    fn line(&self, isa: Isa) -> String {
+        let instruction = disasm::decode(&self.bytes, self.cs, self.ip, isa);
+        let changes: Vec<String> = (0..13)
+            .filter(|&n| self.changed & 1 << n != 0)
+            .map(|n| format!("{}={:04X}", REGISTERS[n], self.values[n]))
+            .collect();
+        let line = format!(
+            "{:<width$}{}",
+            instruction.to_string(),
+            changes.join(" "),
+            width = CHANGES_COLUMN
+        );
+        line.trim_end().to_string()
+    }
+}
+
+/// Writes an instruction trace. The machine hands it every instruction
+/// `wants`, before and after it runs.
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    isa: Isa,
+    filter: TraceFilter,
+    /// In ring buffer mode, the last records and how many to keep.
+    ring: Option<(VecDeque<Record>, usize)>,
+    /// Instructions traced, including any a ring buffer has dropped.
+    count: u64,
+}
+
+impl Tracer {
+    /// A trace of `isa` code into `out`.
+    pub fn new(out: Box<dyn Write + Send>, isa: Isa, filter: TraceFilter) -> Tracer {
+        Tracer {
+            out,
+            isa,
+            filter,
+            ring: None,
+            count: 0,
+        }
+    }
+
+    /// A trace into a new file at `path`.
+    pub fn create(path: &Path, isa: Isa, filter: TraceFilter) -> Result<Tracer, String> {
+        let file = File::create(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file)), isa, filter))
+    }
+
+    /// Keeps only the last `capacity` instructions, to write when the
+    /// trace finishes.
+    pub fn ring_buffer(mut self, capacity: usize) -> Tracer {
+        self.ring = Some((VecDeque::new(), capacity.max(1)));
+        self
+    }
+
+    /// Whether the instruction at `cs`, at `physical`, goes in the trace.
+    pub fn wants(&self, cs: u16, physical: u32) -> bool {
+        self.filter.passes(cs, physical)
+    }
+
+    /// Traces an instruction, from the registers before and after it ran
+    /// and the bytes it was run from.
+    pub fn record(
+        &mut self,
+        before: &CpuState,
+        bytes: [u8; disasm::MAX_LENGTH],
+        after: &CpuState,
+    ) -> Result<(), String> {
+        let record = Record::new(before, bytes, after);
+        self.count += 1;
+        match &mut self.ring {
+            Some((ring, capacity)) => {
+                if ring.len() == *capacity {
+                    ring.pop_front();
+                }
+                ring.push_back(record);
+                Ok(())
+            }
+            None => writeln!(self.out, "{}", record.line(self.isa)).map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Writes out whatever's left to write, returning how many
+    /// instructions were traced.
+    pub fn finish(mut self) -> Result<u64, String> {
+        if let Some((ring, _)) = self.ring.take() {
+            for record in ring {
+                writeln!(self.out, "{}", record.line(self.isa)).map_err(|err| err.to_string())?;
+            }
+        }
+        self.out.flush().map_err(|err| err.to_string())?;
+        Ok(self.count)
+    }
+}
+
+/// Where a machine keeps its tracer. A trace isn't part of the machine's
+/// state, so a clone of the machine isn't traced, the way a clone of a CPU
+/// has no hooks.
+#[derive(Default)]
+pub struct TraceSlot(pub Option<Tracer>);
+
+impl Clone for TraceSlot {
+    fn clone(&self) -> TraceSlot {
+        TraceSlot(None)
+    }
+}
+
+impl fmt::Debug for TraceSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("TraceSlot").field(&self.0.is_some()).finish()
+    }
+}
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// A trace's output, shared with the test looking at it.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl SharedBuffer {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[test]
+fn test_parse_trace_filter() {
+    assert_eq!(
+        TraceFilter::parse("cs=f000,range=fe000-fffff,cs=0x70"),
+        Ok(TraceFilter {
+            ranges: vec![(0xfe000, 0xfffff)],
+            segments: vec![0xf000, 0x70],
+        })
+    );
+    assert!(TraceFilter::parse("cs=10000").is_err());
+    assert!(TraceFilter::parse("range=fffff-fe000").is_err());
+    assert!(TraceFilter::parse("range=fe000").is_err());
+    assert!(TraceFilter::parse("ip=100").is_err());
+
+    let filter = TraceFilter::parse("cs=f000,range=fe000-fffff").unwrap();
+    assert!(filter.passes(0xf000, 0xfe05b));
+    assert!(!filter.passes(0xfe00, 0xfe05b));
+    assert!(!filter.passes(0xf000, 0xf0000));
+    assert!(TraceFilter::default().passes(0x1234, 0x12340));
+}
+
+#[test]
+fn test_tracer() {
+    let before = CpuState {
+        seg_regs: [0, 0, 0, 0],
+        ip: 0x100,
+        flags: 0xf002,
+        ..CpuState::default()
+    };
+    let mut bytes = [0; disasm::MAX_LENGTH];
+    bytes[..2].copy_from_slice(&[0xb0, 0x42]);
+    let after = CpuState {
+        gprs: [0x42, 0, 0, 0, 0, 0, 0, 0],
+        ip: 0x102,
+        ..before
+    };
+
+    let out = SharedBuffer::default();
+    let mut tracer = Tracer::new(Box::new(out.clone()), Isa::I8086, TraceFilter::default());
+    tracer.record(&before, bytes, &after).unwrap();
+    tracer
+        .record(&after, [0xf8; disasm::MAX_LENGTH], &after)
+        .unwrap();
+    assert_eq!(tracer.finish(), Ok(2));
+    assert_eq!(
+        out.text(),
+        format!(
+            "0000:0100  B042          mov al, 0x42{:23}AX=0042\n0000:0102  F8            clc\n",
+            ""
+        )
+    );
+
+    // A ring buffer writes only the last records, when it finishes.
+    let out = SharedBuffer::default();
+    let tracer = Tracer::new(Box::new(out.clone()), Isa::I8086, TraceFilter::default());
+    let mut tracer = tracer.ring_buffer(1);
+    tracer.record(&before, bytes, &after).unwrap();
+    tracer
+        .record(&after, [0xf8; disasm::MAX_LENGTH], &after)
+        .unwrap();
+    assert_eq!(out.text(), "");
+    assert_eq!(tracer.finish(), Ok(2));
+    assert_eq!(out.text(), "0000:0102  F8            clc\n");
+}
+
+#[test]
+fn test_machine_trace() {
+    use crate::hardware::machine::Machine;
+    use crate::hardware::IbmPc5150Machine;
+
+    let mut pc = IbmPc5150Machine::new();
+    pc.cpu.regs.seg_regs[1] = 0;
+    pc.cpu.regs.ip = 0x100;
+    // mov al, 0x42; call 0x0107; hlt; clc; ret
+    let code = [0xb0, 0x42, 0xe8, 0x02, 0x00, 0xf4, 0x00, 0xf8, 0xc3];
+    pc.hardware.memory.ram[0x100..0x109].copy_from_slice(&code);
+    pc.cpu.regs.gprs[4] = 0x1000;
+    let mut machine = Machine::Pc(Box::new(pc));
+
+    // Only the subroutine, and nothing once the CPU has halted.
+    let out = SharedBuffer::default();
+    let filter = TraceFilter::parse("range=107-108").unwrap();
+    let tracer = Tracer::new(Box::new(out.clone()), machine.isa(), filter);
+    machine.start_trace(tracer).unwrap();
+    machine.run_instructions(10);
+    assert_eq!(machine.stop_trace(), Ok(Some(2)));
+    let text = out.text();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("0000:0107  F8            clc"));
+    assert!(lines[1].starts_with("0000:0108  C3            ret"));
+    assert!(lines[1].ends_with("SP=1000"));
+    assert_eq!(machine.stop_trace(), Ok(None));
+}
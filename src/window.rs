@@ -0,0 +1,667 @@
+// The desktop window. The machine runs a frame whenever the emulated
+// display's vertical refresh comes round, as `FramePacer` counts it, so
+// the picture keeps time even when the host is late now and again. Each
+// frame is scaled on the CPU and handed to the window system as it is.
+//
+// Clicking in the window captures the mouse: the pointer is hidden and
+// held, and its motion goes to the machine's mouse until Ctrl+F10 or
+// switching away lets it go.
+//
+// A machine with a second monitor, an MDA beside the colour card, has both
+// displays side by side, the primary on the left. Screenshots and
+// recordings are of the primary only.
+//
+// A disk image dropped on the window goes in the first drive it suits.
+//
+// Messages about what the hotkeys did, and the drives' activity, show over
+// the picture for a moment, and a status line along the bottom can be
+// kept up too. They're drawn into the window only, never the machine's
+// frame, so screenshots and recordings don't have them.
+//
+// The rest of the Ctrl+function keys run the machine, and the machine never
+// sees the function key:
+//
+//     Ctrl+F1         changes A:'s disk to the next --disk-set image, or
+//                     ejects it with Shift
+//     Ctrl+F2         the same for the CD-ROM and --cd-set
+//     Ctrl+F3         stops in the debugger on the terminal
+//     Ctrl+F4         shows or hides the status line
+//     Ctrl+F5         saves a screenshot, stretched to 4:3 with Shift
+//     Ctrl+F6         starts or stops recording
+//     Ctrl+F7         runs unthrottled, or back at the set speed
+//     Ctrl+F8         steps one frame, or one instruction with Shift
+//     Ctrl+F9         pauses or resumes
+//     Ctrl+F11/F12    halves or doubles the speed, from 0.1x to 10x
+use crate::Session;
+use emupc_rs::hardware::runcontrol::{FramePacer, FRAME_DURATION, SPEED_RANGE};
+use emupc_rs::input::keymap::key_code;
+use emupc_rs::input::mouse::MouseMotion;
+use emupc_rs::input::InputEvent;
+use emupc_rs::recording;
+use emupc_rs::renderer::osd::Osd;
+use emupc_rs::renderer::screenshot;
+use emupc_rs::renderer::software::SoftwareRenderer;
+use emupc_rs::renderer::{self, Renderer, Scaling};
+use log::{info, warn};
+use softbuffer::{Context, Surface};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use winit::application::ApplicationHandler;
+use winit::dpi::LogicalSize;
+use winit::event::{DeviceEvent, DeviceId, ElementState, MouseButton, WindowEvent};
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
+use winit::keyboard::{KeyCode, ModifiersState, PhysicalKey};
+use winit::window::{CursorGrabMode, Window, WindowId};
+
+const TITLE: &str = "emupc-rs";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    /// The window starts at this many times 640x480.
+    pub scale: u32,
+    pub scaling: Scaling,
+    /// Mickeys for each pixel the host's mouse moves.
+    pub mouse_sensitivity: f64,
+    /// Where screenshots go.
+    pub screenshot_dir: PathBuf,
+    /// Starts with the status line showing.
+    pub status_bar: bool,
+}
+
+struct Display {
+    window: Rc<Window>,
+    surface: Surface<Rc<Window>, Rc<Window>>,
+}
+
+struct App {
+    session: Session,
+    options: Options,
+    display: Option<Display>,
+    /// One for each of the machine's displays.
+    renderers: Vec<SoftwareRenderer>,
+    pacer: FramePacer,
+    next_frame: Instant,
+    /// When the window was last redrawn. Faster than real time, most
+    /// frames are never shown.
+    last_redraw: Instant,
+    error: Option<String>,
+    mouse: MouseMotion,
+    captured: bool,
+    modifiers: ModifiersState,
+    /// Hotkeys still down, whose keys the machine mustn't see.
+    hotkeys_held: Vec<KeyCode>,
+    /// The machine's mouse buttons held down, so letting the mouse go can
+    /// let them go too.
+    buttons: [bool; 3],
+    osd: Osd,
+    status_bar: bool,
+    /// Frames run and shown since `stats_since`, for the status line.
+    frames_run: u32,
+    redraws: u32,
+    stats_since: Instant,
+    /// The frame rate and clock for the status line, worked out once a
+    /// second.
+    stats: String,
+}
+
+impl App {
+    fn open(&self, event_loop: &ActiveEventLoop) -> Result<Display, String> {
+        let displays = self.session.machine.frames().len().max(1) as u32;
+        let size = LogicalSize::new(
+            640 * self.options.scale * displays,
+            480 * self.options.scale,
+        );
+        let attributes = Window::default_attributes()
+            .with_title(TITLE)
+            .with_inner_size(size);
+        let window = Rc::new(
+            event_loop
+                .create_window(attributes)
+                .map_err(|err| err.to_string())?,
+        );
+        let context = Context::new(window.clone()).map_err(|err| err.to_string())?;
+        let surface = Surface::new(&context, window.clone()).map_err(|err| err.to_string())?;
+        Ok(Display { window, surface })
+    }
+
+    /// Draws the machine's displays into the window side by side, each in
+    /// its own share of the width, black around them.
+    fn present(&mut self) -> Result<(), String> {
+        let display = match &mut self.display {
+            Some(display) => display,
+            None => return Ok(()),
+        };
+        let size = display.window.inner_size();
+        let (width, height) = match (NonZeroU32::new(size.width), NonZeroU32::new(size.height)) {
+            (Some(width), Some(height)) => (width, height),
+            _ => return Ok(()),
+        };
+        display
+            .surface
+            .resize(width, height)
+            .map_err(|err| err.to_string())?;
+        let mut buffer = display
+            .surface
+            .buffer_mut()
+            .map_err(|err| err.to_string())?;
+        buffer.fill(0);
+        let frames = self.session.machine.frames();
+        let share = size.width / frames.len().max(1) as u32;
+        self.renderers
+            .resize_with(frames.len(), || SoftwareRenderer::new(0, 0));
+        for ((left, frame), renderer) in (0..)
+            .map(|n| n * share)
+            .zip(&frames)
+            .zip(&mut self.renderers)
+        {
+            let view = renderer::viewport(
+                frame.width,
+                frame.height,
+                share,
+                size.height,
+                self.options.scaling,
+            );
+            if view.width > 0 && view.height > 0 {
+                if renderer.size() != (view.width, view.height) {
+                    renderer.resize(view.width, view.height);
+                }
+                renderer.render(frame)?;
+                let rows = renderer.output().chunks(view.width as usize);
+                for (y, row) in (view.y..).zip(rows) {
+                    let start = (y * size.width + left + view.x) as usize;
+                    buffer[start..start + row.len()].copy_from_slice(row);
+                }
+            }
+        }
+        self.osd.tick(Instant::now());
+        self.osd.draw(&mut buffer, size.width, size.height);
+        self.redraws += 1;
+        buffer.present().map_err(|err| err.to_string())
+    }
+
+    fn capture(&mut self, captured: bool) {
+        let window = match &self.display {
+            Some(display) => display.window.clone(),
+            None => return,
+        };
+        if captured {
+            // Locked keeps the pointer where it is, where the platform can;
+            // confined to the window is the next best thing.
+            let grab = window
+                .set_cursor_grab(CursorGrabMode::Locked)
+                .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined));
+            if let Err(err) = grab {
+                warn!("Couldn't capture the mouse: {}", err);
+                return;
+            }
+        } else {
+            let _ = window.set_cursor_grab(CursorGrabMode::None);
+            for button in 0..3 {
+                if std::mem::take(&mut self.buttons[button]) {
+                    self.session.input(InputEvent::MouseButton {
+                        button: button as u8,
+                        pressed: false,
+                    });
+                }
+            }
+            self.mouse.clear();
+        }
+        window.set_cursor_visible(!captured);
+        self.captured = captured;
+        self.update_title();
+    }
+
+    /// Runs a Ctrl+function key hotkey, returning whether it was one.
+    fn hotkey(&mut self, code: KeyCode) -> bool {
+        let recording = self.session.recording();
+        let control = &mut self.session.control;
+        let message = match code {
+            KeyCode::F1 | KeyCode::F2 => {
+                let (drive, result) = match (code, self.modifiers.shift_key()) {
+                    (KeyCode::F1, true) => {
+                        self.session.machine.eject_floppy(0);
+                        ("A:", Ok(None))
+                    }
+                    (KeyCode::F1, false) => ("A:", self.session.next_floppy().map(Some)),
+                    (_, true) => {
+                        self.session.machine.eject_cdrom();
+                        ("CD", Ok(None))
+                    }
+                    (_, false) => ("CD", self.session.next_cdrom().map(Some)),
+                };
+                Some(match result {
+                    Ok(Some(path)) => format!("{} {}", drive, file_name(&path)),
+                    Ok(None) => format!("{} ejected", drive),
+                    Err(err) => {
+                        warn!("{} {}", drive, err);
+                        format!("{} {}", drive, err)
+                    }
+                })
+            }
+            KeyCode::F3 => {
+                self.capture(false);
+                self.session.debug();
+                None
+            }
+            KeyCode::F4 => {
+                self.status_bar = !self.status_bar;
+                self.update_status();
+                None
+            }
+            KeyCode::F5 => {
+                let path = screenshot::next_path(&self.options.screenshot_dir);
+                let aspect_correct = self.modifiers.shift_key();
+                match self.session.machine.screenshot(&path, aspect_correct) {
+                    Ok(()) => {
+                        info!("Saved {}", path.display());
+                        Some(format!("Saved {}", path.display()))
+                    }
+                    Err(err) => {
+                        warn!("Screenshot: {}", err);
+                        Some("Screenshot failed".to_string())
+                    }
+                }
+            }
+            KeyCode::F6 if recording => {
+                self.session.stop_recording();
+                Some("Recording stopped".to_string())
+            }
+            KeyCode::F6 => {
+                let dir = recording::next_dir(&self.options.screenshot_dir);
+                match self.session.start_recording(&dir) {
+                    Ok(()) => Some(format!("Recording to {}", dir.display())),
+                    Err(err) => {
+                        warn!("Recording: {}", err);
+                        Some("Recording failed".to_string())
+                    }
+                }
+            }
+            KeyCode::F7 => {
+                control.set_unthrottled(!control.unthrottled());
+                Some(speed_message(control.unthrottled(), control.speed()))
+            }
+            KeyCode::F8 if self.modifiers.shift_key() => {
+                control.step_instructions(1);
+                None
+            }
+            KeyCode::F8 => {
+                control.step_frame();
+                None
+            }
+            KeyCode::F9 if control.paused() => {
+                control.resume();
+                Some("Resumed".to_string())
+            }
+            KeyCode::F9 => {
+                control.pause();
+                Some("Paused".to_string())
+            }
+            KeyCode::F10 if self.captured => {
+                self.capture(false);
+                None
+            }
+            KeyCode::F11 | KeyCode::F12 => {
+                let speed = match code {
+                    KeyCode::F11 => control.speed() / 2.0,
+                    _ => control.speed() * 2.0,
+                };
+                let speed = speed.clamp(SPEED_RANGE.0, SPEED_RANGE.1);
+                control.set_speed(speed).unwrap();
+                Some(speed_message(control.unthrottled(), speed))
+            }
+            _ => return false,
+        };
+        if let Some(message) = message {
+            self.osd.message(message, Instant::now());
+        }
+        self.update_title();
+        true
+    }
+
+    /// Brings the drive lights and, if it's showing, the status line up to
+    /// date. The lights are taken at each redraw, so a sector read any time
+    /// since the last one still shows.
+    fn update_status(&mut self) {
+        let lights = self.session.machine.drive_lights();
+        let activity: Vec<&str> = [
+            (lights.floppy[0], "A:"),
+            (lights.floppy[1], "B:"),
+            (lights.hard_disk, "HD"),
+        ]
+        .iter()
+        .filter(|(lit, _)| *lit)
+        .map(|&(_, name)| name)
+        .collect();
+        self.osd
+            .set_activity(Some(activity.join(" ")).filter(|text| !text.is_empty()));
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.stats_since);
+        if elapsed >= Duration::from_secs(1) {
+            let seconds = elapsed.as_secs_f64();
+            // Each frame run is a frame's worth of the CPU's clock, or
+            // less when stepping, so this is a little high then.
+            let mhz = self.frames_run as f64
+                * FRAME_DURATION.as_secs_f64()
+                * self.session.machine.clock_hz() as f64
+                / seconds
+                / 1e6;
+            self.stats = format!("{:.0} fps  {:.2} MHz  ", self.redraws as f64 / seconds, mhz);
+            self.frames_run = 0;
+            self.redraws = 0;
+            self.stats_since = now;
+        }
+        let light = |lit| if lit { '*' } else { '.' };
+        let status = format!(
+            "{}A:{} B:{} HD:{}",
+            self.stats,
+            light(lights.floppy[0]),
+            light(lights.floppy[1]),
+            light(lights.hard_disk)
+        );
+        self.osd
+            .set_status(Some(status).filter(|_| self.status_bar));
+    }
+
+    fn update_title(&self) {
+        let control = &self.session.control;
+        let mut title = TITLE.to_string();
+        if control.paused() {
+            title += " - paused";
+        }
+        if control.unthrottled() {
+            title += " - unthrottled";
+        } else if control.speed() != 1.0 {
+            title += &format!(" - {}x", control.speed());
+        }
+        if self.session.recording() {
+            title += " - recording";
+        }
+        if self.captured {
+            title += " - Ctrl+F10 releases the mouse";
+        }
+        if let Some(display) = &self.display {
+            display.window.set_title(&title);
+        }
+    }
+
+    fn fail(&mut self, event_loop: &ActiveEventLoop, err: String) {
+        self.error = Some(err);
+        event_loop.exit();
+    }
+}
+
+impl ApplicationHandler for App {
+    fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+        if self.display.is_some() {
+            return;
+        }
+        match self.open(event_loop) {
+            Ok(display) => self.display = Some(display),
+            Err(err) => self.fail(event_loop, err),
+        }
+        self.update_title();
+        self.next_frame = Instant::now();
+    }
+
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
+        match event {
+            WindowEvent::CloseRequested => event_loop.exit(),
+            // By where the key is rather than what it says, as the
+            // machine has a keyboard layout of its own. Held keys repeat
+            // as the host repeats them, as typematic would.
+            WindowEvent::KeyboardInput { event, .. } => {
+                let pressed = event.state == ElementState::Pressed;
+                if let PhysicalKey::Code(code) = event.physical_key {
+                    // A hotkey's repeats and release go wherever its press
+                    // went, whatever's happened to Ctrl since.
+                    if self.hotkeys_held.contains(&code) {
+                        if !pressed {
+                            self.hotkeys_held.retain(|&held| held != code);
+                        }
+                        return;
+                    }
+                    if pressed && self.modifiers.control_key() && self.hotkey(code) {
+                        self.hotkeys_held.push(code);
+                        return;
+                    }
+                    if let Some(scancode) = pc_key(code).and_then(key_code) {
+                        self.session.input(InputEvent::Key { scancode, pressed });
+                    }
+                }
+            }
+            WindowEvent::ModifiersChanged(modifiers) => self.modifiers = modifiers.state(),
+            // The click that captures the mouse isn't the machine's.
+            WindowEvent::MouseInput { state, button, .. } => {
+                let pressed = state == ElementState::Pressed;
+                if !self.captured {
+                    if pressed && button == MouseButton::Left {
+                        self.capture(true);
+                    }
+                    return;
+                }
+                let button = match button {
+                    MouseButton::Left => 0,
+                    MouseButton::Right => 1,
+                    MouseButton::Middle => 2,
+                    _ => return,
+                };
+                self.buttons[button] = pressed;
+                self.session.input(InputEvent::MouseButton {
+                    button: button as u8,
+                    pressed,
+                });
+            }
+            WindowEvent::Focused(false) if self.captured => self.capture(false),
+            WindowEvent::DroppedFile(path) => {
+                let path = path.to_string_lossy();
+                let message = match self.session.mount(&path) {
+                    Ok(drive) => format!("{} {}", drive, file_name(&path)),
+                    Err(err) => {
+                        warn!("{}", err);
+                        format!("Can't mount {}", file_name(&path))
+                    }
+                };
+                self.osd.message(message, Instant::now());
+            }
+            WindowEvent::RedrawRequested => {
+                if let Err(err) = self.present() {
+                    self.fail(event_loop, err);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Raw motion rather than the pointer's position, which stops at the
+    // edge of the window or doesn't move at all while it's locked.
+    fn device_event(&mut self, _event_loop: &ActiveEventLoop, _id: DeviceId, event: DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta: (dx, dy) } = event {
+            if self.captured {
+                if let Some(event) = self.mouse.motion(dx, dy) {
+                    self.session.input(event);
+                }
+            }
+        }
+    }
+
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        let now = Instant::now();
+        if now >= self.next_frame {
+            if self.session.run_frame() {
+                self.frames_run += 1;
+            }
+            let duration = self.session.control.frame_duration();
+            self.next_frame = now + self.pacer.advance(now, duration);
+            if now.duration_since(self.last_redraw) >= FRAME_DURATION {
+                self.update_status();
+                if let Some(display) = &self.display {
+                    display.window.request_redraw();
+                }
+                self.last_redraw = now;
+            }
+        }
+        event_loop.set_control_flow(ControlFlow::WaitUntil(self.next_frame));
+    }
+}
+
+/// Just the file's name, for a message that has to fit on the screen.
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(path.into(), |name| name.to_string_lossy().into_owned())
+}
+
+/// The message for a change of speed.
+fn speed_message(unthrottled: bool, speed: f64) -> String {
+    match unthrottled {
+        true => "Unthrottled".to_string(),
+        false => format!("Speed {}x", speed),
+    }
+}
+
+/// The PC key in the same place as a host key, by name.
+fn pc_key(code: KeyCode) -> Option<&'static str> {
+    use KeyCode::*;
+    Some(match code {
+        Escape => "esc",
+        Digit1 => "1",
+        Digit2 => "2",
+        Digit3 => "3",
+        Digit4 => "4",
+        Digit5 => "5",
+        Digit6 => "6",
+        Digit7 => "7",
+        Digit8 => "8",
+        Digit9 => "9",
+        Digit0 => "0",
+        Minus => "minus",
+        Equal => "equals",
+        Backspace => "backspace",
+        Tab => "tab",
+        KeyQ => "q",
+        KeyW => "w",
+        KeyE => "e",
+        KeyR => "r",
+        KeyT => "t",
+        KeyY => "y",
+        KeyU => "u",
+        KeyI => "i",
+        KeyO => "o",
+        KeyP => "p",
+        BracketLeft => "leftbracket",
+        BracketRight => "rightbracket",
+        Enter => "enter",
+        ControlLeft => "leftctrl",
+        KeyA => "a",
+        KeyS => "s",
+        KeyD => "d",
+        KeyF => "f",
+        KeyG => "g",
+        KeyH => "h",
+        KeyJ => "j",
+        KeyK => "k",
+        KeyL => "l",
+        Semicolon => "semicolon",
+        Quote => "quote",
+        Backquote => "backquote",
+        ShiftLeft => "leftshift",
+        Backslash => "backslash",
+        KeyZ => "z",
+        KeyX => "x",
+        KeyC => "c",
+        KeyV => "v",
+        KeyB => "b",
+        KeyN => "n",
+        KeyM => "m",
+        Comma => "comma",
+        Period => "period",
+        Slash => "slash",
+        ShiftRight => "rightshift",
+        NumpadMultiply => "kpmultiply",
+        AltLeft => "leftalt",
+        Space => "space",
+        CapsLock => "capslock",
+        F1 => "f1",
+        F2 => "f2",
+        F3 => "f3",
+        F4 => "f4",
+        F5 => "f5",
+        F6 => "f6",
+        F7 => "f7",
+        F8 => "f8",
+        F9 => "f9",
+        F10 => "f10",
+        NumLock => "numlock",
+        ScrollLock => "scrolllock",
+        Numpad7 => "kp7",
+        Numpad8 => "kp8",
+        Numpad9 => "kp9",
+        NumpadSubtract => "kpminus",
+        Numpad4 => "kp4",
+        Numpad5 => "kp5",
+        Numpad6 => "kp6",
+        NumpadAdd => "kpplus",
+        Numpad1 => "kp1",
+        Numpad2 => "kp2",
+        Numpad3 => "kp3",
+        Numpad0 => "kp0",
+        NumpadDecimal => "kpperiod",
+        IntlBackslash => "intlbackslash",
+        F11 => "f11",
+        F12 => "f12",
+        NumpadEnter => "kpenter",
+        ControlRight => "rightctrl",
+        NumpadDivide => "kpdivide",
+        PrintScreen => "printscreen",
+        AltRight => "rightalt",
+        Home => "home",
+        ArrowUp => "up",
+        PageUp => "pageup",
+        ArrowLeft => "left",
+        ArrowRight => "right",
+        End => "end",
+        ArrowDown => "down",
+        PageDown => "pagedown",
+        Insert => "insert",
+        Delete => "delete",
+        SuperLeft => "leftwin",
+        SuperRight => "rightwin",
+        ContextMenu => "menu",
+        Pause => "pause",
+        _ => return None,
+    })
+}
+
+/// Runs `session` in a window until it's closed.
+pub fn run(session: Session, options: Options) -> Result<(), String> {
+    let event_loop = EventLoop::new().map_err(|err| err.to_string())?;
+    let mut app = App {
+        session,
+        mouse: MouseMotion::new(options.mouse_sensitivity),
+        status_bar: options.status_bar,
+        options,
+        display: None,
+        renderers: vec![],
+        pacer: FramePacer::new(),
+        next_frame: Instant::now(),
+        last_redraw: Instant::now(),
+        error: None,
+        captured: false,
+        modifiers: ModifiersState::empty(),
+        hotkeys_held: vec![],
+        buttons: [false; 3],
+        osd: Osd::new(),
+        frames_run: 0,
+        redraws: 0,
+        stats_since: Instant::now(),
+        stats: String::new(),
+    };
+    event_loop
+        .run_app(&mut app)
+        .map_err(|err| err.to_string())?;
+    app.session.finish();
+    app.error.map_or(Ok(()), Err)
+}